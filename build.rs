@@ -0,0 +1,14 @@
+//! Generates the Rust types of `proto/hardwave.proto` with the `proto`
+//! feature; does nothing without it
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    #[cfg(feature = "proto")]
+    {
+        println!("cargo:rerun-if-changed=proto/hardwave.proto");
+        // A protoc of our own, so building doesn't depend on one installed
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("no vendored protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        prost_build::compile_protos(&["proto/hardwave.proto"], &["proto/"]).expect("proto/hardwave.proto doesn't compile");
+    }
+}
@@ -0,0 +1,132 @@
+//! How much each coarse band is moving
+//!
+//! A resonance or a held pad sits at the same level frame after frame; a
+//! drum bus or a vocal doesn't. Activity tells the two apart: the mean
+//! absolute frame-to-frame change of each coarse band's level over the last
+//! `ACTIVITY_FRAMES` frames (2 s at the 20 Hz frame rate), scaled so that
+//! `FULL_SCALE_DB` of change per frame reads 1. Both channels count as one,
+//! by the summed power of the band, so a pan move isn't activity.
+//!
+//! The changes are kept in a fixed ring with a running sum per band, so a
+//! frame costs the same handful of operations per band however long the
+//! window, and nothing is allocated after the engine is made.
+
+use crate::bands::MAX_COARSE_BANDS;
+use crate::units::{db_to_linear, power_to_db};
+
+/// Frames the activity is averaged over: 2 s at 20 Hz
+pub const ACTIVITY_FRAMES: usize = 40;
+
+/// Mean change per frame, in dB, that reads as full activity. Static tones
+/// stay well under 0.1 dB; a kick or a tremolo moves its bands several dB
+/// a frame.
+pub const FULL_SCALE_DB: f32 = 6.0;
+
+const MAX_BANDS: usize = MAX_COARSE_BANDS as usize;
+
+/// Running activity of up to `MAX_COARSE_BANDS` bands
+#[derive(Debug, Clone)]
+pub struct BandActivity {
+    /// |change| per band, in dB; row `next` is the oldest
+    deltas: [[f32; MAX_BANDS]; ACTIVITY_FRAMES],
+    /// Sum of each band's column of `deltas`. f64, so adding and taking away
+    /// for hours doesn't drift.
+    sums: [f64; MAX_BANDS],
+    /// Levels of the previous frame
+    last: [f32; MAX_BANDS],
+    /// Bands being tracked; 0 until the first frame
+    bands: usize,
+    next: usize,
+    /// Rows of `deltas` in use
+    filled: usize,
+}
+
+impl Default for BandActivity {
+    fn default() -> Self {
+        Self {
+            deltas: [[0.0; MAX_BANDS]; ACTIVITY_FRAMES],
+            sums: [0.0; MAX_BANDS],
+            last: [0.0; MAX_BANDS],
+            bands: 0,
+            next: 0,
+            filled: 0,
+        }
+    }
+}
+
+impl BandActivity {
+    /// Forget the history, as after a seek or with bands of a new layout
+    pub fn clear(&mut self) {
+        self.sums = [0.0; MAX_BANDS];
+        self.bands = 0;
+        self.next = 0;
+        self.filled = 0;
+    }
+
+    /// Take one frame's coarse bands, in dB, and return each band's activity
+    /// in 0..=1. A change in the number of bands starts over; so does the
+    /// first frame, which reads as no activity.
+    pub fn update(&mut self, left_db: &[f32], right_db: &[f32]) -> Vec<f32> {
+        let bands = left_db.len().min(right_db.len()).min(MAX_BANDS);
+        let level = |band: usize| {
+            power_to_db(db_to_linear(left_db[band]).powi(2) + db_to_linear(right_db[band]).powi(2))
+        };
+        if bands != self.bands {
+            self.clear();
+            self.bands = bands;
+            for band in 0..bands {
+                self.last[band] = level(band);
+            }
+            return vec![0.0; bands];
+        }
+
+        let oldest = self.filled == ACTIVITY_FRAMES;
+        let row = &mut self.deltas[self.next];
+        for (band, slot) in row.iter_mut().enumerate().take(bands) {
+            let db = level(band);
+            let delta = (db - self.last[band]).abs();
+            self.last[band] = db;
+            if oldest {
+                self.sums[band] -= f64::from(*slot);
+            }
+            *slot = delta;
+            self.sums[band] += f64::from(delta);
+        }
+        self.next = (self.next + 1) % ACTIVITY_FRAMES;
+        self.filled = (self.filled + 1).min(ACTIVITY_FRAMES);
+
+        let frames = self.filled as f64;
+        self.sums[..bands]
+            .iter()
+            .map(|&sum| ((sum.max(0.0) / frames) as f32 / FULL_SCALE_DB).min(1.0))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_forgets_old_movement() {
+        let mut activity = BandActivity::default();
+        // Band 0 jumps 6 dB every frame, band 1 holds still
+        for frame in 0..ACTIVITY_FRAMES + 1 {
+            let db = if frame % 2 == 0 { -20.0 } else { -26.0 };
+            let shown = activity.update(&[db, -40.0], &[db, -40.0]);
+            if frame > 0 {
+                assert!((shown[0] - 1.0).abs() < 0.01, "frame {}: {}", frame, shown[0]);
+                assert!(shown[1] < 1e-6);
+            }
+        }
+        // A full window of stillness later, nothing is left of it
+        let mut shown = Vec::new();
+        for _ in 0..ACTIVITY_FRAMES {
+            shown = activity.update(&[-20.0, -40.0], &[-20.0, -40.0]);
+        }
+        assert!(shown[0] < 1e-4, "{}", shown[0]);
+
+        // Another layout starts over
+        assert_eq!(activity.update(&[-3.0; 7], &[-3.0; 7]), vec![0.0; 7]);
+    }
+}
@@ -0,0 +1,722 @@
+//! Analysis state and the worker that rebuilds it off the audio thread
+//!
+//! A new sample rate, bandwidth mode, zero padding, window or eco mode needs
+//! new decimators, ring buffers, FFT plans and window tables, and so do
+//! turning the analysis of the sidechain on or off, a new channel count and
+//! a new update rate. None of that may be allocated or freed on the audio
+//! thread, so `process` only notices the change and asks the config worker
+//! for it.
+//! The worker builds an `AnalysisState` and hands it over; the audio thread
+//! swaps it in at the start of the next block, which discards at most the
+//! analysis frame in progress, and sends the old state back to the worker to
+//! be dropped there. Until the new state arrives analysis carries on with the
+//! old one.
+//!
+//! The audio side only uses `try_send`, `try_recv` and `Thread::unpark`, and
+//! the worker never waits inside a channel, so the channels' internal locks
+//! are never taken on the audio thread.
+
+use crossbeam_channel::{bounded, Receiver, Sender};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::decimator::{self, Decimator};
+use crate::fft::{FftProcessor, RingBuffer, ECO_FFT_SIZE, FFT_SIZE};
+use crate::memory::{self, Buffer, MemoryGauges};
+use crate::panics::{self, Region};
+use crate::reference::ReferenceState;
+use crate::params::{WindowType, ZeroPad};
+use crate::remote::{DEFAULT_UPDATE_RATE_HZ, MAX_UPDATE_RATE_HZ};
+use crate::resampler::{self, Resampler};
+use crate::surround::{SurroundState, MAX_CHANNELS};
+use crate::threads::{self, Priority};
+
+/// How long the worker sleeps between checks when nobody wakes it
+const WORKER_POLL: Duration = Duration::from_millis(50);
+
+/// Highest host sample rate the buffer caps are sized for
+pub const MAX_SAMPLE_RATE: f32 = 768_000.0;
+
+/// `history_len` at `MAX_SAMPLE_RATE` with full bandwidth, the longest any
+/// config needs
+pub const MAX_HISTORY_LEN: usize =
+    FFT_SIZE + (MAX_SAMPLE_RATE as usize / DEFAULT_UPDATE_RATE_HZ as usize / 2).saturating_sub(FFT_SIZE / 2);
+
+/// Settings that need a rebuilt `AnalysisState` when they change
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AnalysisConfig {
+    pub sample_rate: f32,
+    pub full_bandwidth: bool,
+    pub zero_pad: ZeroPad,
+    pub window: WindowType,
+    /// Resample to `resampler::NORMALIZED_RATE`; takes precedence over
+    /// `full_bandwidth`
+    pub normalized_rate: bool,
+    /// Half the decimated rate and an `ECO_FFT_SIZE` window; takes
+    /// precedence over both of the above
+    pub eco_mode: bool,
+    /// Analyse the sidechain as well, see `reference`
+    pub reference: bool,
+    /// Channels of the main input; more than two are analysed one by one
+    /// as well, see `surround`
+    pub channels: usize,
+    /// Frames a second, `DEFAULT_UPDATE_RATE_HZ` to `MAX_UPDATE_RATE_HZ`,
+    /// see `remote`
+    pub update_rate: u32,
+}
+
+impl Default for AnalysisConfig {
+    fn default() -> Self {
+        Self {
+            sample_rate: 48000.0,
+            full_bandwidth: false,
+            zero_pad: ZeroPad::Off,
+            window: WindowType::Hann,
+            normalized_rate: false,
+            eco_mode: false,
+            reference: false,
+            channels: 2,
+            update_rate: DEFAULT_UPDATE_RATE_HZ,
+        }
+    }
+}
+
+/// Everything the audio thread analyses with for one configuration
+pub struct AnalysisState {
+    pub config: AnalysisConfig,
+
+    pub fft_left: FftProcessor,
+    pub fft_right: FftProcessor,
+
+    /// Summed power spectra of the interval's hops, one sum per FFT bin
+    pub power_left: Vec<f32>,
+    pub power_right: Vec<f32>,
+
+    pub decimator_left: Decimator,
+    pub decimator_right: Decimator,
+
+    /// After the decimators; passes the feed through unless the rate is
+    /// normalised
+    pub resampler: Resampler,
+
+    /// The last `history_len` analysis-rate samples
+    pub buffer_left: RingBuffer,
+    pub buffer_right: RingBuffer,
+
+    /// Samples the FFT windows, `FFT_SIZE` or `ECO_FFT_SIZE`
+    pub fft_size: usize,
+
+    /// Samples kept per channel: one FFT window plus the deepest lag the
+    /// centred alignment needs
+    pub history_len: usize,
+
+    /// Sample rate the FFT sees after decimation and resampling
+    pub analysis_rate: f32,
+
+    /// Samples between FFT sends, for the config's update rate
+    pub samples_per_send: usize,
+
+    /// The same again for the sidechain, with Reference Analysis on
+    pub reference: Option<ReferenceState>,
+
+    /// Every channel of a surround input, unpadded
+    pub surround: Option<SurroundState>,
+}
+
+impl AnalysisState {
+    /// Pick the decimation factor and resampling for the config and allocate
+    /// everything it needs. Call this off the audio thread.
+    pub fn build(config: AnalysisConfig) -> Self {
+        let stages = if config.eco_mode {
+            decimator::stages_for_rate(config.sample_rate) + 1
+        } else if config.normalized_rate {
+            decimator::stages_above_rate(config.sample_rate)
+        } else if config.full_bandwidth {
+            0
+        } else {
+            decimator::stages_for_rate(config.sample_rate)
+        };
+        let decimator = || {
+            let mut decimator = Decimator::new();
+            decimator.set_stages(stages);
+            decimator
+        };
+        let decimator_left = decimator();
+        let decimator_right = decimator();
+
+        let decimated_rate = config.sample_rate / decimator_left.factor() as f32;
+        let new_resampler = || {
+            if config.normalized_rate && !config.eco_mode {
+                Resampler::new(decimated_rate)
+            } else {
+                Resampler::passthrough()
+            }
+        };
+        let resampler = new_resampler();
+        let analysis_rate = if resampler.is_active() {
+            resampler::NORMALIZED_RATE
+        } else {
+            decimated_rate
+        };
+        // Never slower than the caps are sized for
+        let update_rate = config.update_rate.clamp(DEFAULT_UPDATE_RATE_HZ, MAX_UPDATE_RATE_HZ);
+        let samples_per_send = (analysis_rate / update_rate as f32) as usize;
+        let fft_size = if config.eco_mode { ECO_FFT_SIZE } else { FFT_SIZE };
+        let history_len = fft_size + (samples_per_send / 2).saturating_sub(fft_size / 2);
+
+        let zero_pad = config.zero_pad.factor();
+        let fft = || FftProcessor::with_window_type(fft_size, zero_pad, config.window);
+        let fft_left = fft();
+        let fft_right = fft();
+        let bins = fft_left.num_bins();
+        let reference = config.reference.then(|| ReferenceState {
+            fft_left: fft(),
+            fft_right: fft(),
+            decimator_left: decimator(),
+            decimator_right: decimator(),
+            resampler: new_resampler(),
+            buffer_left: RingBuffer::new(history_len),
+            buffer_right: RingBuffer::new(history_len),
+        });
+        let surround = (config.channels > 2).then(|| {
+            let channels = config.channels.min(MAX_CHANNELS);
+            SurroundState {
+                ffts: (0..channels).map(|_| FftProcessor::with_window_type(fft_size, 1, config.window)).collect(),
+                decimators: (0..channels).map(|_| decimator()).collect(),
+                resamplers: (0..channels / 2).map(|_| new_resampler()).collect(),
+                buffers: (0..channels).map(|_| RingBuffer::new(history_len)).collect(),
+            }
+        });
+        Self {
+            config,
+            fft_left,
+            fft_right,
+            power_left: vec![0.0; bins],
+            power_right: vec![0.0; bins],
+            decimator_left,
+            decimator_right,
+            resampler,
+            buffer_left: RingBuffer::new(history_len),
+            buffer_right: RingBuffer::new(history_len),
+            fft_size,
+            history_len,
+            analysis_rate,
+            samples_per_send,
+            reference,
+            surround,
+        }
+    }
+
+    /// Append one analysis-rate sample per channel, dropping the oldest once
+    /// `history_len` are buffered.
+    pub fn buffer(&mut self, left: f32, right: f32) {
+        self.buffer_left.push(left);
+        self.buffer_right.push(right);
+    }
+
+    /// Drop buffered audio, the sidechain's and the surround channels' too
+    pub fn clear(&mut self) {
+        self.buffer_left.clear();
+        self.buffer_right.clear();
+        if let Some(reference) = &mut self.reference {
+            reference.clear();
+        }
+        if let Some(surround) = &mut self.surround {
+            surround.clear();
+        }
+    }
+
+    /// Update the gauges of the buffers this state owns. Never allocates.
+    pub fn report_memory(&self, gauges: &MemoryGauges) {
+        gauges.set(
+            Buffer::SampleRings,
+            self.buffer_left.heap_bytes() + self.buffer_right.heap_bytes(),
+        );
+        gauges.set(Buffer::FftBuffers, self.fft_left.heap_bytes() + self.fft_right.heap_bytes());
+        gauges.set(
+            Buffer::HopPower,
+            memory::vec_bytes(&self.power_left) + memory::vec_bytes(&self.power_right),
+        );
+        gauges.set(
+            Buffer::Decimators,
+            self.decimator_left.heap_bytes() + self.decimator_right.heap_bytes() + self.resampler.heap_bytes(),
+        );
+        gauges.set(Buffer::Reference, self.reference.as_ref().map_or(0, ReferenceState::heap_bytes));
+        gauges.set(Buffer::Surround, self.surround.as_ref().map_or(0, SurroundState::heap_bytes));
+    }
+}
+
+/// Audio-thread end of the config worker
+pub struct ConfigPipeline {
+    build: fn(AnalysisConfig) -> AnalysisState,
+    worker: Option<Worker>,
+
+    /// Config asked of the worker and not yet swapped in
+    requested: Option<AnalysisConfig>,
+
+    /// An old state the retire channel had no room for; sent on a later block
+    unretired: Option<Box<AnalysisState>>,
+
+    /// The current state is to be replaced even though its config is right
+    rebuild: bool,
+}
+
+struct Worker {
+    requests: Sender<AnalysisConfig>,
+    ready: Receiver<Box<AnalysisState>>,
+    retired: Sender<Box<AnalysisState>>,
+    shutdown: Arc<AtomicBool>,
+    handle: JoinHandle<()>,
+}
+
+impl ConfigPipeline {
+    pub fn new() -> Self {
+        Self::with_builder(AnalysisState::build)
+    }
+
+    fn with_builder(build: fn(AnalysisConfig) -> AnalysisState) -> Self {
+        Self {
+            build,
+            worker: None,
+            requested: None,
+            unretired: None,
+            rebuild: false,
+        }
+    }
+
+    /// Start the worker thread if it isn't running. Deferred from `new` like
+    /// the WebSocket client, so plugin scans don't spawn threads.
+    pub fn start(&mut self) {
+        if self.worker.is_some() {
+            return;
+        }
+        let (requests, request_rx) = bounded::<AnalysisConfig>(4);
+        let (ready_tx, ready) = bounded::<Box<AnalysisState>>(2);
+        let (retired, retired_rx) = bounded::<Box<AnalysisState>>(4);
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let build = self.build;
+        let stop = shutdown.clone();
+        // A request whose build panicked is built again after the restart
+        let mut pending = None;
+        let spawned = threads::spawn_restarting("hardwave-config", Priority::Streaming, Region::ConfigBuilder, move || {
+            worker_loop(build, &request_rx, &ready_tx, &retired_rx, &stop, &mut pending)
+        });
+        if let Ok(handle) = spawned {
+            self.worker = Some(Worker {
+                requests,
+                ready,
+                retired,
+                shutdown,
+                handle,
+            });
+        }
+    }
+
+    /// Whether a config has been asked for and not yet swapped in
+    #[cfg(test)]
+    pub fn is_pending(&self) -> bool {
+        self.requested.is_some()
+    }
+
+    /// Audio thread: have `sync` replace the current state with a freshly
+    /// built one for the same config, as after a failed self-check
+    pub fn rebuild(&mut self) {
+        self.rebuild = true;
+        self.requested = None;
+    }
+
+    /// Audio thread, once per block: ask for `wanted` if `current` was built
+    /// for something else, and swap in a finished state for it. Returns true
+    /// when `current` was replaced. Never blocks, allocates or frees.
+    pub fn sync(&mut self, current: &mut Box<AnalysisState>, wanted: AnalysisConfig) -> bool {
+        let Some(worker) = &self.worker else {
+            return false;
+        };
+
+        if let Some(old) = self.unretired.take() {
+            self.unretired = worker.retired.try_send(old).err().map(|e| e.into_inner());
+        }
+
+        if current.config == wanted && !self.rebuild {
+            self.requested = None;
+        } else if self.requested != Some(wanted) && worker.requests.try_send(wanted).is_ok() {
+            // A full queue is retried on the next block
+            self.requested = Some(wanted);
+            worker.handle.thread().unpark();
+        }
+
+        // Swap only while still behind; a state for an overtaken config, or
+        // a second one for the same config, goes straight back. Nothing more
+        // is collected while an old state is waiting to be retired, so none
+        // is ever dropped here.
+        let mut behind = current.config != wanted || self.rebuild;
+        let mut swapped = false;
+        while self.unretired.is_none() {
+            let Ok(state) = worker.ready.try_recv() else {
+                break;
+            };
+            let old = if behind && state.config == wanted {
+                behind = false;
+                swapped = true;
+                std::mem::replace(current, state)
+            } else {
+                state
+            };
+            self.unretired = worker.retired.try_send(old).err().map(|e| e.into_inner());
+        }
+        if swapped {
+            self.requested = None;
+            self.rebuild = false;
+            worker.handle.thread().unpark();
+        }
+        swapped
+    }
+}
+
+impl Default for ConfigPipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for ConfigPipeline {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            worker.shutdown.store(true, Ordering::Relaxed);
+            worker.handle.thread().unpark();
+            let _ = worker.handle.join();
+        }
+    }
+}
+
+fn worker_loop(
+    build: fn(AnalysisConfig) -> AnalysisState,
+    requests: &Receiver<AnalysisConfig>,
+    ready: &Sender<Box<AnalysisState>>,
+    retired: &Receiver<Box<AnalysisState>>,
+    shutdown: &AtomicBool,
+    pending: &mut Option<AnalysisConfig>,
+) {
+    let mut built: Option<Box<AnalysisState>> = None;
+    while !shutdown.load(Ordering::Relaxed) {
+        panics::checkpoint(Region::ConfigBuilder);
+
+        // Old states are dropped here, off the audio thread
+        retired.try_iter().for_each(drop);
+
+        // Only the newest request matters
+        while let Ok(newer) = requests.try_recv() {
+            *pending = Some(newer);
+        }
+        if let Some(config) = *pending {
+            if built.as_ref().map(|s| s.config) != Some(config) {
+                built = Some(Box::new(build(config)));
+            }
+            *pending = None;
+        }
+
+        if let Some(state) = built.take() {
+            built = ready.try_send(state).err().map(|e| e.into_inner());
+            if built.is_some() {
+                // The audio thread hasn't collected the last one yet
+                thread::park_timeout(Duration::from_millis(5));
+                continue;
+            }
+        }
+        thread::park_timeout(WORKER_POLL);
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::protocol::NUM_BINS;
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::time::Instant;
+
+    /// Counts heap operations made by threads inside `guarded`
+    struct GuardedAllocator;
+
+    thread_local! {
+        static GUARDED: Cell<bool> = const { Cell::new(false) };
+        static HEAP_OPS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    fn note_heap_op() {
+        let _ = GUARDED.try_with(|guarded| {
+            if guarded.get() {
+                let _ = HEAP_OPS.try_with(|ops| ops.set(ops.get() + 1));
+            }
+        });
+    }
+
+    unsafe impl GlobalAlloc for GuardedAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            note_heap_op();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            note_heap_op();
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            note_heap_op();
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: GuardedAllocator = GuardedAllocator;
+
+    /// Run `f` and return how many allocations, reallocations and frees it
+    /// made on this thread
+    pub(crate) fn heap_ops_in<R>(f: impl FnOnce() -> R) -> (R, usize) {
+        HEAP_OPS.with(|ops| ops.set(0));
+        GUARDED.with(|guarded| guarded.set(true));
+        let result = f();
+        GUARDED.with(|guarded| guarded.set(false));
+        (result, HEAP_OPS.with(|ops| ops.get()))
+    }
+
+    fn config(sample_rate: f32, full_bandwidth: bool) -> AnalysisConfig {
+        AnalysisConfig {
+            sample_rate,
+            full_bandwidth,
+            ..AnalysisConfig::default()
+        }
+    }
+
+    /// Feed one block through `state` the way `process` does
+    fn simulate_block(state: &mut AnalysisState, block: usize) {
+        for i in 0..block {
+            let x = (i as f32 * 0.01).sin();
+            let (Some(left), Some(right)) =
+                (state.decimator_left.process(x), state.decimator_right.process(x))
+            else {
+                continue;
+            };
+            state.buffer(left, right);
+        }
+    }
+
+    fn wait_for_swap(pipeline: &mut ConfigPipeline, state: &mut Box<AnalysisState>, wanted: AnalysisConfig) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !pipeline.sync(state, wanted) {
+            assert!(Instant::now() < deadline, "config {:?} never arrived", wanted);
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_build_matches_config() {
+        let state = AnalysisState::build(config(192000.0, false));
+        assert_eq!(state.analysis_rate, 48000.0);
+        assert_eq!(state.samples_per_send, 2400);
+        assert_eq!(state.history_len, FFT_SIZE);
+
+        let full = AnalysisState::build(config(192000.0, true));
+        assert_eq!(full.analysis_rate, 192000.0);
+        assert_eq!(full.samples_per_send, 9600);
+        assert!(full.history_len > FFT_SIZE);
+        assert_eq!(full.buffer_left.capacity(), full.history_len);
+
+        let padded = AnalysisState::build(AnalysisConfig {
+            zero_pad: ZeroPad::X4,
+            ..config(48000.0, false)
+        });
+        assert_eq!(padded.fft_left.num_bins(), 4 * NUM_BINS);
+        assert_eq!(padded.power_left.len(), 4 * NUM_BINS);
+        // Padding interpolates; it doesn't lengthen the window
+        assert_eq!(padded.history_len, FFT_SIZE);
+        assert_eq!(padded.fft_right.window_type(), WindowType::Hann);
+
+        let flat_top = AnalysisState::build(AnalysisConfig {
+            window: WindowType::FlatTop,
+            ..config(48000.0, false)
+        });
+        assert_eq!(flat_top.fft_left.window_type(), WindowType::FlatTop);
+        assert_eq!(flat_top.fft_right.window_type(), WindowType::FlatTop);
+
+        // Every project rate analyses at 48 kHz, full bandwidth or not
+        for rate in [44100.0, 88200.0, 96000.0, 176400.0] {
+            let normalized = AnalysisState::build(AnalysisConfig {
+                normalized_rate: true,
+                ..config(rate, true)
+            });
+            assert_eq!(normalized.analysis_rate, 48000.0, "at {} Hz", rate);
+            assert_eq!(normalized.samples_per_send, 2400);
+            assert_eq!(normalized.resampler.is_active(), rate != 96000.0);
+        }
+
+        // Eco mode halves the rate the plain decimation gives, whatever
+        // else is asked for, and the window with it
+        for (rate, eco_rate) in [(44100.0, 22050.0), (48000.0, 24000.0), (192000.0, 24000.0), (768000.0, 48000.0)] {
+            let eco = AnalysisState::build(AnalysisConfig {
+                eco_mode: true,
+                normalized_rate: true,
+                ..config(rate, true)
+            });
+            assert_eq!(eco.analysis_rate, eco_rate, "at {} Hz", rate);
+            assert!(!eco.resampler.is_active());
+            assert_eq!(eco.fft_size, ECO_FFT_SIZE);
+            assert_eq!(eco.fft_left.num_bins(), NUM_BINS / 2);
+            assert_eq!(eco.power_left.len(), NUM_BINS / 2);
+            assert!(eco.history_len >= ECO_FFT_SIZE && eco.history_len < FFT_SIZE);
+        }
+
+        // The sidechain gets the main input's analysis, only when asked for
+        assert!(AnalysisState::build(config(96000.0, false)).reference.is_none());
+        let state = AnalysisState::build(AnalysisConfig {
+            reference: true,
+            zero_pad: ZeroPad::X2,
+            normalized_rate: true,
+            ..config(44100.0, false)
+        });
+        let reference = state.reference.as_ref().unwrap();
+        assert_eq!(reference.fft_left.num_bins(), state.fft_left.num_bins());
+        assert_eq!(reference.fft_right.window_type(), state.fft_right.window_type());
+        assert_eq!(reference.buffer_right.capacity(), state.history_len);
+        assert_eq!(reference.decimator_left.factor(), state.decimator_left.factor());
+        assert_eq!(reference.resampler.is_active(), state.resampler.is_active());
+
+        // A surround input gets every channel unpadded, and a resampler a pair
+        assert!(state.surround.is_none());
+        let state = AnalysisState::build(AnalysisConfig {
+            channels: 6,
+            zero_pad: ZeroPad::X2,
+            ..config(96000.0, false)
+        });
+        let surround = state.surround.as_ref().unwrap();
+        assert_eq!((surround.channels(), surround.resamplers.len()), (6, 3));
+        assert_eq!(surround.ffts[5].num_bins(), state.fft_left.num_bins() / 2);
+        assert_eq!(surround.buffers[0].capacity(), state.history_len);
+        assert_eq!(surround.decimators[4].factor(), state.decimator_left.factor());
+
+        // A faster update rate sends more often over the same window, and
+        // a slower one than the caps allow is the default
+        let fast = AnalysisState::build(AnalysisConfig { update_rate: 40, ..config(48000.0, false) });
+        assert_eq!((fast.samples_per_send, fast.history_len), (1200, FFT_SIZE));
+        let slow = AnalysisState::build(AnalysisConfig { update_rate: 5, ..config(192000.0, true) });
+        assert_eq!((slow.samples_per_send, slow.history_len), (full.samples_per_send, full.history_len));
+    }
+
+    #[test]
+    fn test_swaps_in_requested_config() {
+        let mut pipeline = ConfigPipeline::new();
+        pipeline.start();
+        let mut state = Box::new(AnalysisState::build(AnalysisConfig::default()));
+
+        assert!(!pipeline.sync(&mut state, AnalysisConfig::default()));
+        assert!(!pipeline.is_pending());
+
+        let wanted = config(96000.0, true);
+        wait_for_swap(&mut pipeline, &mut state, wanted);
+        assert_eq!(state.config, wanted);
+        assert_eq!(state.analysis_rate, 96000.0);
+        assert!(!pipeline.is_pending());
+    }
+
+    #[test]
+    fn test_rapid_toggles_never_touch_the_heap_on_the_audio_thread() {
+        let mut pipeline = ConfigPipeline::new();
+        pipeline.start();
+        let mut state = Box::new(AnalysisState::build(AnalysisConfig::default()));
+        let configs = [
+            config(48000.0, false),
+            config(48000.0, true),
+            config(96000.0, false),
+            config(192000.0, true),
+            config(44100.0, false),
+        ];
+
+        let (swaps, heap_ops) = heap_ops_in(|| {
+            let mut swaps = 0;
+            for block in 0..5000 {
+                // Change the wanted config every few blocks, faster than the
+                // worker can keep up with
+                let wanted = configs[(block / 3) % configs.len()];
+                if pipeline.sync(&mut state, wanted) {
+                    swaps += 1;
+                }
+                simulate_block(&mut state, 64);
+                if block % 50 == 0 {
+                    thread::yield_now();
+                }
+            }
+            swaps
+        });
+        assert_eq!(heap_ops, 0, "audio thread allocated or freed {} times", heap_ops);
+        assert!(swaps > 0);
+        assert!(state.buffer_left.len() <= state.history_len);
+
+        // Settles on the last config once the toggling stops
+        let last = config(96000.0, true);
+        wait_for_swap(&mut pipeline, &mut state, last);
+        assert_eq!(state.config, last);
+    }
+
+    #[test]
+    fn test_audio_side_never_waits_for_a_stalled_worker() {
+        fn slow_build(config: AnalysisConfig) -> AnalysisState {
+            thread::sleep(Duration::from_millis(200));
+            AnalysisState::build(config)
+        }
+        let mut pipeline = ConfigPipeline::with_builder(slow_build);
+        pipeline.start();
+        let mut state = Box::new(AnalysisState::build(AnalysisConfig::default()));
+        let wanted = config(96000.0, false);
+
+        // While the worker is busy building, every block keeps running on the
+        // old state and returns at once
+        let started = Instant::now();
+        let mut slowest = Duration::ZERO;
+        while started.elapsed() < Duration::from_millis(100) {
+            let block = Instant::now();
+            assert!(!pipeline.sync(&mut state, wanted));
+            slowest = slowest.max(block.elapsed());
+            simulate_block(&mut state, 64);
+        }
+        assert!(pipeline.is_pending());
+        assert_eq!(state.config, AnalysisConfig::default());
+        assert!(slowest < Duration::from_millis(20), "sync took {:?}", slowest);
+
+        wait_for_swap(&mut pipeline, &mut state, wanted);
+    }
+
+    #[test]
+    fn test_worker_restarts_after_a_panic() {
+        use crate::panics::hooks;
+
+        let mut pipeline = ConfigPipeline::new();
+        pipeline.start();
+        let mut state = Box::new(AnalysisState::build(AnalysisConfig::default()));
+        let restarts = panics::restarts(Region::ConfigBuilder);
+        let worker = pipeline.worker.as_ref().unwrap().handle.thread().id();
+        hooks::inject(Region::ConfigBuilder, Some(worker));
+
+        // The request made while it was down is still built
+        let wanted = config(96000.0, false);
+        wait_for_swap(&mut pipeline, &mut state, wanted);
+        assert_eq!(state.config, wanted);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while panics::restarts(Region::ConfigBuilder) == restarts {
+            assert!(Instant::now() < deadline, "worker never restarted");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_drop_stops_worker() {
+        let mut pipeline = ConfigPipeline::new();
+        pipeline.start();
+        let started = Instant::now();
+        drop(pipeline);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+}
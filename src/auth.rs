@@ -1,28 +1,624 @@
 //! Token persistence for the VST webview editor.
 //!
 //! Stores the user's JWT at `~/.hardwave/vst-token` so they don't have to
-//! log in every time the plugin window is opened.
+//! log in every time the plugin window is opened. When the home directory is
+//! not writable (roaming profiles, sandboxed hosts) the token falls back to
+//! the Hardwave data-local directory. A fallback save can't always remove
+//! the copy it replaces, so loading takes the newest of the files.
+//!
+//! Only the editor saves or deletes tokens; headless builds just read them.
+//! The editor and the connection thread share the token in memory
+//! (`AccountToken`). A token the editor couldn't save stands there until it
+//! saves or clears another; otherwise the file is read again only once it
+//! changed, so a login in another instance is picked up.
+//!
+//! The connection thread sends the account token in the handshake, as
+//! `Authorization: Bearer <token>`, so the Suite can tell who is streaming.
+//! When it carries the `stream_exchange` claim, the connection thread also
+//! trades it with the Suite for a short-lived, stream-only token
+//! (`StreamAuth`). That one lives in memory only and authenticates the
+//! reconnect handshakes in the account token's place.
+//!
+//! A Suite that answers the handshake with 401 or 403 leaves the connection
+//! `Unauthorized`. It tries again after `UNAUTHORIZED_RETRY`, not at the
+//! usual pace, or at once when the editor saves or clears the token
+//! (`AccountToken`); a live connection reconnects with the new one.
 
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::fmt;
 use std::fs;
-use std::path::PathBuf;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::base64;
+
+/// Claim in the account token saying the Suite will exchange it for a stream
+/// token: `true`, or the name of its exchange endpoint
+const EXCHANGE_CLAIM: &str = "stream_exchange";
+
+/// How long the connection thread waits for the Suite to answer an exchange
+pub const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// How long after a failed exchange connections go without trying again
+pub const EXCHANGE_RETRY: Duration = Duration::from_secs(60);
+
+/// A stream token this close to expiry is exchanged for a new one instead of
+/// being used
+pub const RENEW_BEFORE: Duration = Duration::from_secs(30);
+
+/// How long after the Suite turned the account token down the next
+/// connection is tried, unless a token is saved before
+pub const UNAUTHORIZED_RETRY: Duration = Duration::from_secs(30);
+
+/// Errors from reading or writing the token file, or exchanging the token.
+#[derive(Debug)]
+pub enum AuthError {
+    /// Neither a home directory nor a data-local directory could be found.
+    #[cfg(any(feature = "gui", test))]
+    NoHomeDir,
+    /// The token location exists but we are not allowed to write to it.
+    NotWritable(PathBuf),
+    /// Any other I/O failure.
+    Io(io::Error),
+    /// The Suite refused or didn't answer a token exchange.
+    Exchange(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(any(feature = "gui", test))]
+            AuthError::NoHomeDir => write!(f, "no home directory to store the token in"),
+            AuthError::NotWritable(path) => write!(f, "{} is not writable", path.display()),
+            AuthError::Io(e) => write!(f, "token file I/O error: {}", e),
+            AuthError::Exchange(why) => write!(f, "token exchange failed: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for AuthError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AuthError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl AuthError {
+    fn from_io(path: &Path, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem => {
+                AuthError::NotWritable(path.to_path_buf())
+            }
+            _ => AuthError::Io(e),
+        }
+    }
+}
+
+/// Candidate token file locations, in order of preference.
+fn token_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(home) = dirs::home_dir() {
+        paths.push(home.join(".hardwave").join("vst-token"));
+    }
+    if let Some(data) = dirs::data_local_dir() {
+        paths.push(data.join("Hardwave").join("vst-token"));
+    }
+    paths
+}
+
+/// The claims of a JWT. The signature isn't checked; that is the Suite's job.
+fn jwt_claims(jwt: &str) -> Option<serde_json::Value> {
+    let payload = jwt.split('.').nth(1)?;
+    serde_json::from_slice(&base64::decode(payload)?).ok()
+}
+
+/// Whether the Suite will exchange this account token for a stream token
+pub fn offers_exchange(account_token: &str) -> bool {
+    match jwt_claims(account_token).as_ref().and_then(|claims| claims.get(EXCHANGE_CLAIM)) {
+        Some(serde_json::Value::Bool(offered)) => *offered,
+        Some(serde_json::Value::String(endpoint)) => !endpoint.is_empty(),
+        _ => false,
+    }
+}
+
+/// A short-lived token that only lets this plugin stream, never written to
+/// disk
+#[derive(Clone, PartialEq, Eq)]
+pub struct StreamToken {
+    token: String,
+    expires_at: Instant,
+}
+
+impl StreamToken {
+    pub fn as_str(&self) -> &str {
+        &self.token
+    }
+
+    /// Whether it is worth using rather than renewing at `now`
+    pub fn is_fresh(&self, now: Instant) -> bool {
+        now + RENEW_BEFORE < self.expires_at
+    }
+}
+
+impl fmt::Debug for StreamToken {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamToken")
+            .field("token", &"<redacted>")
+            .field("expires_at", &self.expires_at)
+            .finish()
+    }
+}
+
+/// Text message asking the Suite to exchange `account_token`
+pub fn exchange_request(account_token: &str) -> String {
+    serde_json::json!({
+        "type": "token_exchange",
+        "scope": "stream",
+        "token": account_token,
+    })
+    .to_string()
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ExchangeReply {
+    StreamToken { token: String, expires_in: u64 },
+    TokenExchangeError { reason: String },
+}
+
+/// The Suite's answer to `exchange_request`, received at `now`, or `None` if
+/// `text` is some other message
+pub fn parse_exchange_reply(text: &str, now: Instant) -> Option<Result<StreamToken, AuthError>> {
+    Some(match serde_json::from_str::<ExchangeReply>(text).ok()? {
+        ExchangeReply::StreamToken { token, .. } if token.trim().is_empty() => {
+            Err(AuthError::Exchange("empty stream token".to_string()))
+        }
+        ExchangeReply::StreamToken { token, expires_in } => Ok(StreamToken {
+            token,
+            expires_at: now + Duration::from_secs(expires_in),
+        }),
+        ExchangeReply::TokenExchangeError { reason } => Err(AuthError::Exchange(reason)),
+    })
+}
+
+/// Where the connection thread reads the account token
+type AccountTokenSource = Box<dyn Fn() -> Result<Option<String>, AuthError> + Send>;
+
+/// The connection thread's stream token, and when to exchange for one
+pub struct StreamAuth {
+    load_account_token: AccountTokenSource,
+    stream: Option<StreamToken>,
+    /// No exchange before this, after one failed
+    retry_at: Option<Instant>,
+}
+
+impl StreamAuth {
+    /// Handshakes carry `account`'s token, as the editor last set it
+    pub fn new(account: Arc<AccountToken>) -> Self {
+        Self::with_loader(move || account.current())
+    }
+
+    /// Read the account token with `load` instead
+    pub fn with_loader(load: impl Fn() -> Result<Option<String>, AuthError> + Send + 'static) -> Self {
+        Self {
+            load_account_token: Box::new(load),
+            stream: None,
+            retry_at: None,
+        }
+    }
 
-/// Get the path to the token file.
-fn token_path() -> Option<PathBuf> {
-    dirs::home_dir().map(|h| h.join(".hardwave").join("vst-token"))
+    /// The stream token to open a connection with, unless it is due for
+    /// renewal
+    pub fn bearer(&self, now: Instant) -> Option<String> {
+        self.stream
+            .as_ref()
+            .filter(|stream| stream.is_fresh(now))
+            .map(|stream| stream.as_str().to_string())
+    }
+
+    /// The account token, to open a connection with when there is no
+    /// fresh stream token
+    pub fn account_token(&self) -> Result<Option<String>, AuthError> {
+        (self.load_account_token)()
+    }
+
+    /// The account token to exchange on a new connection, if one is due: no
+    /// fresh stream token, an account token that offers the exchange, and no
+    /// recent failure
+    pub fn exchange_due(&self, now: Instant) -> Result<Option<String>, AuthError> {
+        if self.bearer(now).is_some() || self.retry_at.is_some_and(|at| now < at) {
+            return Ok(None);
+        }
+        Ok((self.load_account_token)()?.filter(|token| offers_exchange(token)))
+    }
+
+    pub fn exchanged(&mut self, stream: StreamToken) {
+        self.stream = Some(stream);
+        self.retry_at = None;
+    }
+
+    /// Stream without a token until `EXCHANGE_RETRY` has passed
+    pub fn exchange_failed(&mut self, now: Instant) {
+        self.stream = None;
+        self.retry_at = Some(now + EXCHANGE_RETRY);
+    }
+
+    /// The Suite turned the stream token down; the next connection exchanges
+    /// again
+    pub fn forget(&mut self) {
+        self.stream = None;
+    }
 }
 
-/// Load a previously-saved JWT token from disk.
-pub fn load_token() -> Option<String> {
-    let path = token_path()?;
-    fs::read_to_string(path).ok().map(|s| s.trim().to_string()).filter(|s| !s.is_empty())
+/// The account token, shared by the editor and the connection thread, and a
+/// count of the editor saving or clearing it, so the connection thread knows
+/// when its handshake carried an old one
+#[derive(Debug)]
+pub struct AccountToken {
+    paths: Vec<PathBuf>,
+    state: Mutex<TokenState>,
+    generation: AtomicU32,
 }
 
-/// Save a JWT token to disk.
-pub fn save_token(token: &str) {
-    if let Some(path) = token_path() {
-        if let Some(parent) = path.parent() {
-            let _ = fs::create_dir_all(parent);
+#[derive(Debug, Default)]
+struct TokenState {
+    token: Option<String>,
+    /// The newest file's time and token when last read, to tell when it
+    /// changes; `None` before the first read
+    on_disk: Option<Option<(SystemTime, String)>>,
+    /// Set or cleared by the editor, but not on disk
+    unsaved: bool,
+}
+
+impl AccountToken {
+    /// The token in the usual locations, read when first asked for
+    pub fn new() -> Self {
+        Self::at(token_paths())
+    }
+
+    pub fn at(paths: Vec<PathBuf>) -> Self {
+        Self {
+            paths,
+            state: Mutex::new(TokenState::default()),
+            generation: AtomicU32::new(0),
+        }
+    }
+
+    /// The token, after taking the one on disk if the file changed since it
+    /// was last read and the editor has nothing unsaved.
+    ///
+    /// An error when a token file exists but cannot be read; the token in
+    /// memory stands, and is returned once the file is readable or an
+    /// unsaved one stands in for it.
+    pub fn current(&self) -> Result<Option<String>, AuthError> {
+        let mut state = self.state.lock();
+        if state.unsaved {
+            return Ok(state.token.clone());
         }
-        let _ = fs::write(path, token);
+        let on_disk = newest_on_disk(&self.paths)?;
+        if state.on_disk.as_ref() != Some(&on_disk) {
+            state.token = on_disk.as_ref().map(|(_, token)| token.clone());
+            state.on_disk = Some(on_disk);
+        }
+        Ok(state.token.clone())
+    }
+
+    /// The token in memory, without looking at the file
+    #[cfg(any(feature = "gui", test))]
+    pub fn cached(&self) -> Option<String> {
+        self.state.lock().token.clone()
+    }
+
+    /// Editor: use `token` from now on and save it, returning the path it was
+    /// written to. It is used even if it can't be saved.
+    #[cfg(any(feature = "gui", test))]
+    pub fn save(&self, token: String) -> Result<PathBuf, AuthError> {
+        let mut state = self.state.lock();
+        let saved = save_to(&self.paths, &token);
+        state.token = Some(token);
+        self.written(&mut state, saved.is_ok());
+        saved
+    }
+
+    /// Editor: log out, removing the saved token from every location. Nothing
+    /// is used from now on, even if a file can't be removed.
+    #[cfg(any(feature = "gui", test))]
+    pub fn clear(&self) -> Result<(), AuthError> {
+        let mut state = self.state.lock();
+        let deleted = delete_from(&self.paths);
+        state.token = None;
+        self.written(&mut state, deleted.is_ok());
+        deleted
+    }
+
+    /// After the editor set the token: the file as it is now, so only a
+    /// later change is taken from it
+    #[cfg(any(feature = "gui", test))]
+    fn written(&self, state: &mut TokenState, on_disk: bool) {
+        state.unsaved = !on_disk;
+        state.on_disk = newest_on_disk(&self.paths).ok();
+        self.changed();
+    }
+
+    /// The token changed; a connection made with the old one reconnects
+    #[cfg(any(feature = "gui", test))]
+    pub fn changed(&self) {
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for AccountToken {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+fn load_from(paths: &[PathBuf]) -> Result<Option<String>, AuthError> {
+    Ok(newest_on_disk(paths)?.map(|(_, token)| token))
+}
+
+/// The newest non-empty token in `paths`, and when it was written
+fn newest_on_disk(paths: &[PathBuf]) -> Result<Option<(SystemTime, String)>, AuthError> {
+    let mut first_err = None;
+    let mut newest: Option<(SystemTime, String)> = None;
+    for path in paths {
+        match fs::read_to_string(path) {
+            Ok(s) => {
+                let token = s.trim();
+                if token.is_empty() {
+                    continue;
+                }
+                // A save that fell back may have left an older token in a
+                // preferred location; ties go to the preferred one
+                let modified = fs::metadata(path).and_then(|meta| meta.modified()).unwrap_or(UNIX_EPOCH);
+                if newest.as_ref().is_none_or(|(at, _)| modified > *at) {
+                    newest = Some((modified, token.to_string()));
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => {
+                first_err.get_or_insert(AuthError::from_io(path, e));
+            }
+        }
+    }
+    match (newest, first_err) {
+        (Some(newest), _) => Ok(Some(newest)),
+        (None, Some(e)) => Err(e),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+fn save_to(paths: &[PathBuf], token: &str) -> Result<PathBuf, AuthError> {
+    let mut first_err = None;
+    for path in paths {
+        let result = match path.parent() {
+            Some(parent) => fs::create_dir_all(parent).and_then(|_| fs::write(path, token)),
+            None => fs::write(path, token),
+        };
+        match result {
+            Ok(()) => return Ok(path.clone()),
+            Err(e) => {
+                first_err.get_or_insert(AuthError::from_io(path, e));
+            }
+        }
+    }
+    Err(first_err.unwrap_or(AuthError::NoHomeDir))
+}
+
+#[cfg(any(feature = "gui", test))]
+fn delete_from(paths: &[PathBuf]) -> Result<(), AuthError> {
+    if paths.is_empty() {
+        return Err(AuthError::NoHomeDir);
+    }
+    for path in paths {
+        match fs::remove_file(path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(AuthError::from_io(path, e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hardwave-auth-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_missing_token_is_none() {
+        let dir = scratch_dir("missing");
+        let paths = vec![dir.join("a").join("vst-token")];
+        assert!(matches!(load_from(&paths), Ok(None)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_unreadable_token_is_error() {
+        let dir = scratch_dir("unreadable");
+        // A directory where the file should be cannot be read as a string.
+        let path = dir.join("vst-token");
+        fs::create_dir_all(&path).unwrap();
+        assert!(load_from(&[path]).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_load_after_a_fallback_save_takes_the_new_token() {
+        let dir = scratch_dir("fallback-load");
+        let primary = dir.join("home").join("vst-token");
+        let fallback = dir.join("data").join("vst-token");
+        save_to(std::slice::from_ref(&primary), "old").unwrap();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        fs::File::options().write(true).open(&primary).unwrap().set_modified(an_hour_ago).unwrap();
+
+        // The primary couldn't be written this time, so the save fell back
+        save_to(std::slice::from_ref(&fallback), "new").unwrap();
+        let paths = [primary.clone(), fallback];
+        assert_eq!(load_from(&paths).unwrap().as_deref(), Some("new"));
+
+        // And a later save to the primary wins again
+        save_to(&paths, "newer").unwrap();
+        assert_eq!(load_from(&paths).unwrap().as_deref(), Some("newer"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_no_locations_is_no_home_dir() {
+        assert!(matches!(save_to(&[], "t"), Err(AuthError::NoHomeDir)));
+        assert!(matches!(delete_from(&[]), Err(AuthError::NoHomeDir)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_read_only_dir_falls_back() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("readonly");
+        let locked = dir.join("locked");
+        let fallback = dir.join("fallback");
+        fs::create_dir_all(&locked).unwrap();
+        fs::set_permissions(&locked, fs::Permissions::from_mode(0o555)).unwrap();
+
+        // Root ignores directory permissions, so there is nothing to test.
+        if fs::write(locked.join("probe"), "x").is_ok() {
+            let _ = fs::set_permissions(&locked, fs::Permissions::from_mode(0o755));
+            let _ = fs::remove_dir_all(&dir);
+            return;
+        }
+
+        let primary = locked.join("vst-token");
+        match save_to(std::slice::from_ref(&primary), "abc") {
+            Err(AuthError::NotWritable(p)) => assert_eq!(p, primary),
+            other => panic!("expected NotWritable, got {:?}", other),
+        }
+
+        let secondary = fallback.join("vst-token");
+        let written = save_to(&[primary.clone(), secondary.clone()], "abc").unwrap();
+        assert_eq!(written, secondary);
+        assert_eq!(load_from(&[primary, secondary]).unwrap().as_deref(), Some("abc"));
+
+        let _ = fs::set_permissions(&locked, fs::Permissions::from_mode(0o755));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// Account token with `"stream_exchange": true`
+    const EXCHANGE_JWT: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJ1c2VyLTEiLCJzdHJlYW1fZXhjaGFuZ2UiOnRydWV9.sig";
+
+    #[test]
+    fn test_exchange_claim() {
+        assert!(offers_exchange(EXCHANGE_JWT));
+        // An endpoint name counts too
+        assert!(offers_exchange("h.eyJzdWIiOiJ1c2VyLTEiLCJzdHJlYW1fZXhjaGFuZ2UiOiJ3c3MifQ.s"));
+        assert!(!offers_exchange("eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJ1c2VyLTEifQ.sig"));
+        assert!(!offers_exchange("not a jwt"));
+        assert!(!offers_exchange("a.!!!.c"));
+    }
+
+    #[test]
+    fn test_exchange_replies() {
+        let now = Instant::now();
+        let token = parse_exchange_reply(r#"{"type":"stream_token","token":"s-1","expires_in":300}"#, now)
+            .unwrap()
+            .unwrap();
+        assert_eq!(token.as_str(), "s-1");
+        assert!(token.is_fresh(now));
+        assert!(!token.is_fresh(now + Duration::from_secs(300) - RENEW_BEFORE));
+        assert!(!format!("{:?}", token).contains("s-1"));
+
+        let refused = parse_exchange_reply(r#"{"type":"token_exchange_error","reason":"no scopes"}"#, now);
+        assert!(matches!(refused, Some(Err(AuthError::Exchange(why))) if why == "no scopes"));
+        let empty = parse_exchange_reply(r#"{"type":"stream_token","token":" ","expires_in":300}"#, now);
+        assert!(matches!(empty, Some(Err(AuthError::Exchange(_)))));
+        assert!(parse_exchange_reply(r#"{"type":"hello"}"#, now).is_none());
+    }
+
+    #[test]
+    fn test_stream_auth_renews_and_backs_off() {
+        let mut auth = StreamAuth::with_loader(|| Ok(Some(EXCHANGE_JWT.to_string())));
+        let now = Instant::now();
+        assert_eq!(auth.bearer(now), None);
+        assert_eq!(auth.exchange_due(now).unwrap().as_deref(), Some(EXCHANGE_JWT));
+
+        auth.exchanged(parse_exchange_reply(r#"{"type":"stream_token","token":"s-1","expires_in":300}"#, now).unwrap().unwrap());
+        assert_eq!(auth.bearer(now).as_deref(), Some("s-1"));
+        assert_eq!(auth.exchange_due(now).unwrap(), None);
+
+        // Close to expiry it is exchanged again rather than used
+        let later = now + Duration::from_secs(280);
+        assert_eq!(auth.bearer(later), None);
+        assert!(auth.exchange_due(later).unwrap().is_some());
+
+        // A failure waits before trying again
+        auth.exchange_failed(later);
+        assert_eq!(auth.exchange_due(later + Duration::from_secs(1)).unwrap(), None);
+        assert!(auth.exchange_due(later + EXCHANGE_RETRY).unwrap().is_some());
+
+        // Without the claim there is nothing to exchange
+        let plain = StreamAuth::with_loader(|| Ok(Some("a.eyJzdWIiOiJ1c2VyLTEifQ.c".to_string())));
+        assert_eq!(plain.exchange_due(now).unwrap(), None);
+        assert_eq!(plain.account_token().unwrap().as_deref(), Some("a.eyJzdWIiOiJ1c2VyLTEifQ.c"));
+    }
+
+    #[test]
+    fn test_account_token_follows_the_file_only_when_it_changes() {
+        let dir = scratch_dir("shared");
+        let paths = vec![dir.join("vst-token")];
+        let account = AccountToken::at(paths.clone());
+        assert_eq!(account.current().unwrap(), None);
+
+        // Another instance logs in
+        save_to(&paths, "elsewhere").unwrap();
+        assert_eq!(account.current().unwrap().as_deref(), Some("elsewhere"));
+
+        // Saved here, read back as is
+        let generation = account.generation();
+        account.save("here".to_string()).unwrap();
+        assert_eq!(account.generation(), generation + 1);
+        assert_eq!(account.current().unwrap().as_deref(), Some("here"));
+
+        // A token that can't be saved isn't undone by reading the file it
+        // couldn't replace
+        let unwritable = AccountToken::at(vec![dir.join("vst-token").join("under-a-file")]);
+        assert!(unwritable.save("unsaved".to_string()).is_err());
+        assert_eq!(unwritable.cached().as_deref(), Some("unsaved"));
+        assert_eq!(unwritable.current().unwrap().as_deref(), Some("unsaved"));
+
+        // Logging out, until someone logs in again
+        account.clear().unwrap();
+        assert_eq!(account.current().unwrap(), None);
+        save_to(&paths, "again").unwrap();
+        assert_eq!(account.current().unwrap().as_deref(), Some("again"));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_save_load_delete_roundtrip() {
+        let dir = scratch_dir("roundtrip");
+        let paths = vec![dir.join("vst-token")];
+        save_to(&paths, "token-123").unwrap();
+        assert_eq!(load_from(&paths).unwrap().as_deref(), Some("token-123"));
+        delete_from(&paths).unwrap();
+        assert!(matches!(load_from(&paths), Ok(None)));
+        let _ = fs::remove_dir_all(&dir);
     }
 }
@@ -0,0 +1,119 @@
+//! Attack and release on the coarse bands
+//!
+//! At 20 frames a second the raw bands jump about too much to drive lights
+//! or a bar graph directly. The smoother follows each band's level in dB
+//! with a one-pole of its own: a rising band moves towards the new level
+//! with the attack time constant, a falling one with the release, both over
+//! the time since the previous frame, so an irregular send interval smooths
+//! the same. A time constant of zero follows the band at once, which is the
+//! default for both, so packets carry raw bands unless asked otherwise.
+//!
+//! Activity is worked out before, from the raw bands; smoothing would hide
+//! the movement it measures. State is fixed-size and per band, and starts
+//! over with a new band layout or when the engine clears its history.
+
+use crate::bands::MAX_COARSE_BANDS;
+
+const MAX_BANDS: usize = MAX_COARSE_BANDS as usize;
+
+/// Smoothed levels of up to `MAX_COARSE_BANDS` bands per channel
+#[derive(Debug, Clone)]
+pub struct BandSmoother {
+    left: [f32; MAX_BANDS],
+    right: [f32; MAX_BANDS],
+    /// Bands being smoothed; 0 until the first frame
+    bands: usize,
+}
+
+impl Default for BandSmoother {
+    fn default() -> Self {
+        Self { left: [0.0; MAX_BANDS], right: [0.0; MAX_BANDS], bands: 0 }
+    }
+}
+
+impl BandSmoother {
+    /// Forget the levels, so the next frame is taken as it is
+    pub fn clear(&mut self) {
+        self.bands = 0;
+    }
+
+    /// Smooth one frame's bands, in dB, in place, `dt` seconds after the
+    /// previous one. The first frame, and one with a different number of
+    /// bands, is left as it is and starts over. Non-finite levels pass
+    /// through without moving the state.
+    pub fn update(&mut self, left_db: &mut [f32], right_db: &mut [f32], dt: f32, attack_s: f32, release_s: f32) {
+        let bands = left_db.len().min(right_db.len()).min(MAX_BANDS);
+        if bands != self.bands || !dt.is_finite() {
+            self.bands = bands;
+            self.left[..bands].copy_from_slice(&left_db[..bands]);
+            self.right[..bands].copy_from_slice(&right_db[..bands]);
+            return;
+        }
+        let coefficient = |tau: f32| if tau > 0.0 { 1.0 - (-dt / tau).exp() } else { 1.0 };
+        let (attack, release) = (coefficient(attack_s), coefficient(release_s));
+        for (state, levels) in [(&mut self.left, &mut *left_db), (&mut self.right, &mut *right_db)] {
+            for (smoothed, level) in state.iter_mut().zip(levels.iter_mut()).take(bands) {
+                if !level.is_finite() {
+                    continue;
+                }
+                let coefficient = if *level > *smoothed { attack } else { release };
+                *smoothed += (*level - *smoothed) * coefficient;
+                *level = *smoothed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Frames of `dt` seconds with `db` in band 0 of both channels,
+    /// returning the smoothed band 0 of the left
+    fn feed(smoother: &mut BandSmoother, db: f32, frames: usize, dt: f32, attack_s: f32, release_s: f32) -> f32 {
+        let mut last = db;
+        for _ in 0..frames {
+            let (mut left, mut right) = ([db, -50.0], [db, -50.0]);
+            smoother.update(&mut left, &mut right, dt, attack_s, release_s);
+            assert_eq!(left, right);
+            assert_eq!(left[1], -50.0);
+            last = left[0];
+        }
+        last
+    }
+
+    #[test]
+    fn test_step_reaches_63_percent_after_a_time_constant() {
+        let (attack, release) = (0.25, 1.0);
+        for dt in [0.05, 0.025, 0.01] {
+            let mut smoother = BandSmoother::default();
+            feed(&mut smoother, -60.0, 1, dt, attack, release);
+            // A 60 dB rise, for one attack time constant
+            let risen = feed(&mut smoother, 0.0, (attack / dt).round() as usize, dt, attack, release);
+            assert!((risen - (-60.0 + 0.632 * 60.0)).abs() < 1.0, "at dt {}: {} dB", dt, risen);
+
+            // Settled, then a 60 dB fall for one release time constant
+            feed(&mut smoother, 0.0, 200, dt, attack, release);
+            let fallen = feed(&mut smoother, -60.0, (release / dt).round() as usize, dt, attack, release);
+            assert!((fallen - (-0.632 * 60.0)).abs() < 1.0, "at dt {}: {} dB", dt, fallen);
+        }
+    }
+
+    #[test]
+    fn test_zero_follows_at_once_and_clear_starts_over() {
+        let mut smoother = BandSmoother::default();
+        feed(&mut smoother, -60.0, 1, 0.05, 0.0, 0.0);
+        assert_eq!(feed(&mut smoother, -3.0, 1, 0.05, 0.0, 0.0), -3.0);
+        assert_eq!(feed(&mut smoother, -40.0, 1, 0.05, 0.0, 0.0), -40.0);
+
+        // Slow, but a cleared smoother takes the next frame as it is
+        assert!(feed(&mut smoother, 0.0, 1, 0.05, 1.0, 1.0) < -30.0);
+        smoother.clear();
+        assert_eq!(feed(&mut smoother, 0.0, 1, 0.05, 1.0, 1.0), 0.0);
+
+        // So does a new band layout
+        let (mut left, mut right) = ([-80.0; 5], [-80.0; 5]);
+        smoother.update(&mut left, &mut right, 0.05, 1.0, 1.0);
+        assert_eq!(left, [-80.0; 5]);
+    }
+}
@@ -0,0 +1,186 @@
+//! Coarse bands for LED strips and other hardware visualisers
+//!
+//! Each coarse band is the summed power of the FFT bins whose centre
+//! frequency falls inside it, so the total energy across the coarse bands
+//! equals that of the fine bins they cover. Readings are dB of that sum and
+//! can exceed 0 dB for broadband content. A zero-padded spectrum has
+//! several overlapping bins per true bin; its sum is divided by the padding
+//! factor so the readings don't depend on it.
+
+use crate::params::CoarseMapping;
+use crate::units::{db_to_linear, power_to_db};
+
+/// Allowed range of the coarse band count
+pub const MIN_COARSE_BANDS: i32 = 4;
+pub const MAX_COARSE_BANDS: i32 = 16;
+
+/// Lowest and highest frequency covered by the coarse bands, in Hz
+pub const LOW_HZ: f32 = 20.0;
+pub const HIGH_HZ: f32 = 20_000.0;
+
+/// Edges of the perceptual groups: sub, bass, low-mid, mid, high-mid,
+/// presence, air
+pub const PERCEPTUAL_EDGES_HZ: [f32; 8] = [20.0, 60.0, 250.0, 500.0, 2_000.0, 4_000.0, 6_000.0, 20_000.0];
+
+/// Band edges in Hz, one more than the number of bands, from exactly
+/// `LOW_HZ` to exactly `HIGH_HZ`. Packets carry these, so receivers never
+/// derive the grid themselves. The perceptual mapping always has seven bands
+/// and ignores `count`.
+pub fn edges(mapping: CoarseMapping, count: usize) -> Vec<f32> {
+    match mapping {
+        CoarseMapping::EqualLogWidth => {
+            let count = count.clamp(MIN_COARSE_BANDS as usize, MAX_COARSE_BANDS as usize);
+            let ratio = HIGH_HZ / LOW_HZ;
+            // The top edge is pinned rather than left to rounding
+            (0..=count)
+                .map(|i| if i == count { HIGH_HZ } else { LOW_HZ * ratio.powf(i as f32 / count as f32) })
+                .collect()
+        }
+        CoarseMapping::Perceptual => PERCEPTUAL_EDGES_HZ.to_vec(),
+    }
+}
+
+/// Bands `edges` makes, without building them
+pub fn band_count(mapping: CoarseMapping, count: usize) -> usize {
+    match mapping {
+        CoarseMapping::EqualLogWidth => count.clamp(MIN_COARSE_BANDS as usize, MAX_COARSE_BANDS as usize),
+        CoarseMapping::Perceptual => PERCEPTUAL_EDGES_HZ.len() - 1,
+    }
+}
+
+/// Sum the power of `bins_db` (bin `k` centred on `k * bin_hz`, `zero_pad`
+/// bins per unpadded bin) into the bands between consecutive `edges`. Bands
+/// are half-open except the last, which includes its upper edge.
+pub fn coarse_power(bins_db: &[f32], bin_hz: f32, zero_pad: usize, edges: &[f32]) -> Vec<f32> {
+    let bands = edges.len().saturating_sub(1);
+    let mut power = vec![0.0_f32; bands];
+    if bands == 0 {
+        return power;
+    }
+    let low = edges[0];
+    let high = edges[bands];
+
+    let mut band = 0;
+    for (k, &db) in bins_db.iter().enumerate() {
+        let hz = k as f32 * bin_hz;
+        if hz < low {
+            continue;
+        }
+        if hz > high {
+            break;
+        }
+        while band + 1 < bands && hz >= edges[band + 1] {
+            band += 1;
+        }
+        let amplitude = db_to_linear(db);
+        power[band] += amplitude * amplitude;
+    }
+    let zero_pad = zero_pad.max(1) as f32;
+    power.iter_mut().for_each(|p| *p /= zero_pad);
+    power
+}
+
+/// `coarse_power` in dB
+pub fn coarse_db(bins_db: &[f32], bin_hz: f32, zero_pad: usize, edges: &[f32]) -> Vec<f32> {
+    coarse_power(bins_db, bin_hz, zero_pad, edges)
+        .into_iter()
+        .map(power_to_db)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BIN_HZ: f32 = 48_000.0 / 4096.0;
+
+    /// Deterministic pseudo-random bins between -100 and 0 dB
+    fn noise_bins() -> Vec<f32> {
+        let mut state = 0x2545_f491_u32;
+        (0..2048)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                -100.0 * (state % 1000) as f32 / 1000.0
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_energy_is_conserved() {
+        let bins = noise_bins();
+        for (mapping, count) in [
+            (CoarseMapping::EqualLogWidth, 4),
+            (CoarseMapping::EqualLogWidth, 11),
+            (CoarseMapping::EqualLogWidth, 16),
+            (CoarseMapping::Perceptual, 8),
+        ] {
+            let edges = edges(mapping, count);
+            let coarse: f32 = coarse_power(&bins, BIN_HZ, 1, &edges).iter().sum();
+            let fine: f32 = bins
+                .iter()
+                .enumerate()
+                .filter(|(k, _)| (LOW_HZ..=HIGH_HZ).contains(&(*k as f32 * BIN_HZ)))
+                .map(|(_, &db)| db_to_linear(db).powi(2))
+                .sum();
+            assert!((coarse - fine).abs() <= fine * 1e-4, "{:?}/{}: {} vs {}", mapping, count, coarse, fine);
+        }
+    }
+
+    #[test]
+    fn test_edges() {
+        let perceptual = edges(CoarseMapping::Perceptual, 12);
+        assert_eq!(perceptual, vec![20.0, 60.0, 250.0, 500.0, 2_000.0, 4_000.0, 6_000.0, 20_000.0]);
+
+        let log = edges(CoarseMapping::EqualLogWidth, 10);
+        assert_eq!(log.len(), 11);
+        assert_eq!((log[0], log[10]), (LOW_HZ, HIGH_HZ));
+        let ratios: Vec<f32> = log.windows(2).map(|w| w[1] / w[0]).collect();
+        assert!(ratios.iter().all(|r| (r - ratios[0]).abs() < 1e-3));
+
+        // Counts outside the parameter range are clamped
+        assert_eq!(edges(CoarseMapping::EqualLogWidth, 1).len(), MIN_COARSE_BANDS as usize + 1);
+        assert_eq!(edges(CoarseMapping::EqualLogWidth, 64).len(), MAX_COARSE_BANDS as usize + 1);
+        assert_eq!(band_count(CoarseMapping::EqualLogWidth, 1), MIN_COARSE_BANDS as usize);
+        assert_eq!(band_count(CoarseMapping::EqualLogWidth, 10), log.len() - 1);
+        assert_eq!(band_count(CoarseMapping::Perceptual, 12), perceptual.len() - 1);
+    }
+
+    #[test]
+    fn test_tone_lands_in_its_group() {
+        // A lone bin at ~1 kHz belongs to the mid group
+        let mut bins = vec![-100.0; 2048];
+        bins[(1_000.0 / BIN_HZ) as usize] = -6.0;
+        let coarse = coarse_db(&bins, BIN_HZ, 1, &PERCEPTUAL_EDGES_HZ);
+        assert!((coarse[3] - (-6.0)).abs() < 0.01);
+        assert!(coarse.iter().enumerate().all(|(i, &db)| i == 3 || db == -100.0));
+    }
+
+    #[test]
+    fn test_zero_pad_leaves_readings_alone() {
+        use crate::fft::{FftProcessor, FFT_SIZE};
+
+        // Tones in the middle of four of the eight bands, each off its bin
+        let samples: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| {
+                [73.0, 411.0, 2_309.0, 13_000.0]
+                    .iter()
+                    .map(|hz| 0.2 * (2.0 * std::f32::consts::PI * hz * i as f32 / 48_000.0).sin())
+                    .sum()
+            })
+            .collect();
+        let edges = edges(CoarseMapping::EqualLogWidth, 8);
+        let unpadded = coarse_db(&FftProcessor::new().process(&samples, 48_000.0), BIN_HZ, 1, &edges);
+        for factor in [2, 4] {
+            let bins = FftProcessor::with_zero_pad(factor).process(&samples, 48_000.0);
+            let padded = coarse_db(&bins, BIN_HZ / factor as f32, factor, &edges);
+            for (band, (a, b)) in unpadded.iter().zip(&padded).enumerate() {
+                // Bands holding only leakage from a neighbour's tone vary more
+                if *a > -30.0 {
+                    assert!((a - b).abs() < 1.0, "{}x band {}: {} vs {} dB", factor, band, b, a);
+                }
+            }
+        }
+    }
+}
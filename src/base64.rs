@@ -0,0 +1,45 @@
+//! Base64 decoding, for the keys and tokens users paste
+//!
+//! Account tokens are JWTs, whose parts are unpadded base64url; packet keys
+//! come in whichever alphabet the tool that made them used. One decoder
+//! reads both alphabets, padded or not.
+
+/// Decode standard or URL-safe base64, padded or not
+pub fn decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    for c in text.trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' | b'-' => 62,
+            b'/' | b'_' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_both_alphabets_padded_or_not() {
+        assert_eq!(decode("aGk").unwrap(), b"hi");
+        assert_eq!(decode("aGk=").unwrap(), b"hi");
+        assert_eq!(decode("+/8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode("-_8").unwrap(), [0xfb, 0xff]);
+        assert_eq!(decode("").unwrap(), b"");
+        assert!(decode("a.b").is_none());
+    }
+}
@@ -0,0 +1,202 @@
+//! Beat and bar phase from the host transport, for visuals in time
+//!
+//! VJ and lighting software syncs to the analyser stream, and without the
+//! beat it can only guess. Each FFT frame says where in the beat and the bar
+//! the end of its analysis window fell, from the host's tempo and position,
+//! in the spirit of Ableton Link's phase: 0 on the beat, rising to just
+//! under 1 before the next. Beats are quarter notes, as hosts count them; a
+//! bar is `numerator * 4 / denominator` of them.
+//!
+//! The host reports the transport once per block, at its start. The window
+//! ends at a sample within the block (its end, unless the interval is longer
+//! than the window), and the position there is the block start's plus that
+//! many samples at the block start's tempo. A tempo ramp inside the block is
+//! off by at most the ramp over one block, which is gone by the next block.
+//! The wall clock is never used, so the phase follows the audio rather than
+//! when the block happened to be processed.
+//!
+//! Without a position or tempo from the host, or while it is stopped, the
+//! phase is invalid and both values read 0.
+
+use nih_plug::prelude::Transport;
+
+/// Bars are four beats when the host doesn't say otherwise
+const DEFAULT_BEATS_PER_BAR: f64 = 4.0;
+
+/// The host transport at the start of a block
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct TransportSnapshot {
+    pub playing: bool,
+    pub recording: bool,
+    pub sample_rate: f32,
+    /// Samples from the project start
+    pub pos_samples: Option<i64>,
+    /// Quarter notes from the project start
+    pub pos_beats: Option<f64>,
+    /// Where the bar the block starts in began, in quarter notes
+    pub bar_start_pos_beats: Option<f64>,
+    /// BPM
+    pub tempo: Option<f64>,
+    pub time_sig: Option<(i32, i32)>,
+}
+
+impl TransportSnapshot {
+    pub fn of(transport: &Transport) -> Self {
+        Self {
+            playing: transport.playing,
+            recording: transport.recording,
+            sample_rate: transport.sample_rate,
+            pos_samples: transport.pos_samples(),
+            pos_beats: transport.pos_beats(),
+            bar_start_pos_beats: transport.bar_start_pos_beats(),
+            tempo: transport.tempo,
+            time_sig: transport.time_sig_numerator.zip(transport.time_sig_denominator),
+        }
+    }
+
+    /// Quarter notes per bar, from the time signature
+    fn beats_per_bar(&self) -> f64 {
+        match self.time_sig {
+            Some((numerator, denominator)) if numerator > 0 && denominator > 0 => {
+                f64::from(numerator) * 4.0 / f64::from(denominator)
+            }
+            _ => DEFAULT_BEATS_PER_BAR,
+        }
+    }
+
+    /// The phase `offset` host samples after the block start. `None` while
+    /// stopped or without a tempo or position.
+    pub fn phase_at(&self, offset: i64) -> Option<BeatPhase> {
+        if !self.playing || self.sample_rate <= 0.0 {
+            return None;
+        }
+        let tempo = self.tempo.filter(|tempo| tempo.is_finite() && *tempo > 0.0)?;
+        let start = self.pos_beats.filter(|beats| beats.is_finite())?;
+        let beats = start + offset as f64 / f64::from(self.sample_rate) * tempo / 60.0;
+
+        let per_bar = self.beats_per_bar();
+        // Bars count from the host's bar start where it reports one, so a
+        // change of time signature earlier in the project doesn't shift them
+        let bar_start = self.bar_start_pos_beats.filter(|beats| beats.is_finite()).unwrap_or(0.0);
+        Some(BeatPhase {
+            beat: unit(beats),
+            bar: unit((beats - bar_start) / per_bar),
+        })
+    }
+}
+
+/// `value` wrapped into 0..1
+fn unit(value: f64) -> f32 {
+    // Rounding can land an f32 on 1.0 just before a beat
+    let phase = value.rem_euclid(1.0) as f32;
+    if phase >= 1.0 {
+        0.0
+    } else {
+        phase
+    }
+}
+
+/// Where in the beat and the bar a frame's window ended, 0..1 each
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BeatPhase {
+    pub beat: f32,
+    pub bar: f32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48_000.0;
+    const BLOCK: i64 = 480;
+
+    /// A transport at 120 BPM, `block` blocks in
+    fn at_120(block: i64) -> TransportSnapshot {
+        let pos_samples = block * BLOCK;
+        let pos_beats = pos_samples as f64 / f64::from(RATE) * 2.0;
+        TransportSnapshot {
+            playing: true,
+            recording: false,
+            sample_rate: RATE,
+            pos_samples: Some(pos_samples),
+            pos_beats: Some(pos_beats),
+            bar_start_pos_beats: Some((pos_beats / 4.0).floor() * 4.0),
+            tempo: Some(120.0),
+            time_sig: Some((4, 4)),
+        }
+    }
+
+    #[test]
+    fn test_phase_advances_and_wraps() {
+        // 120 BPM at 48 kHz: 24000 samples, 50 blocks, per beat. A frame
+        // every 5 blocks, its window ending at the block's end, moves a
+        // tenth of a beat and a fortieth of a bar.
+        let phases: Vec<BeatPhase> = (0..60).step_by(5).map(|block| at_120(block).phase_at(BLOCK).unwrap()).collect();
+        for pair in phases.windows(2) {
+            let step = (pair[1].beat - pair[0].beat).rem_euclid(1.0);
+            assert!((step - 0.1).abs() < 1e-4, "beat moved {} from {:?}", step, pair[0]);
+            let step = (pair[1].bar - pair[0].bar).rem_euclid(1.0);
+            assert!((step - 0.025).abs() < 1e-4, "bar moved {}", step);
+        }
+        // The frame after block 49 ends exactly on beat 2
+        let on_beat = at_120(49).phase_at(BLOCK).unwrap();
+        assert_eq!(on_beat.beat, 0.0);
+        assert!((on_beat.bar - 0.25).abs() < 1e-6);
+        // and just before it the phase is nearly 1
+        let before = at_120(49).phase_at(BLOCK - 1).unwrap();
+        assert!(before.beat > 0.999 && before.beat < 1.0);
+
+        // Four beats on, the bar wraps too
+        let on_bar = at_120(199).phase_at(BLOCK).unwrap();
+        assert_eq!(on_bar, BeatPhase { beat: 0.0, bar: 0.0 });
+    }
+
+    #[test]
+    fn test_block_start_tempo_and_time_signature() {
+        // Only the block start's tempo counts, however far into the block
+        let quarter = at_120(0).phase_at(6000).unwrap();
+        assert!((quarter.beat - 0.25).abs() < 1e-6);
+        let slower = TransportSnapshot { tempo: Some(60.0), ..at_120(0) };
+        assert!((slower.phase_at(6000).unwrap().beat - 0.125).abs() < 1e-6);
+
+        // 6/8 is three quarter notes to the bar
+        let waltz = TransportSnapshot { time_sig: Some((6, 8)), bar_start_pos_beats: None, ..at_120(0) };
+        let phase = waltz.phase_at(36_000).unwrap();
+        assert!((phase.beat - 0.5).abs() < 1e-6);
+        assert!((phase.bar - 0.5).abs() < 1e-6, "{:?}", phase);
+    }
+
+    #[test]
+    fn test_frames_follow_the_transport() {
+        use crate::analysis::AnalysisConfig;
+        use crate::engine::AnalysisEngine;
+
+        // The plugin's path: a frame's phase is its block's transport,
+        // moved on to where the window ended
+        let mut engine = AnalysisEngine::new(AnalysisConfig::default());
+        let mut phases = Vec::new();
+        for block in 0..60 {
+            let transport = TransportSnapshot { playing: block < 40, ..at_120(block) };
+            engine.push_samples(&[0.0; BLOCK as usize], &[0.0; BLOCK as usize]);
+            if let Some(frame) = engine.poll_frame() {
+                phases.push((block, transport.phase_at(BLOCK - frame.window_lag as i64)));
+            }
+        }
+        let playing: Vec<BeatPhase> = phases.iter().filter(|(block, _)| *block < 40).map(|(_, p)| p.unwrap()).collect();
+        assert!(playing.len() >= 5);
+        for pair in playing.windows(2) {
+            let step = (pair[1].beat - pair[0].beat).rem_euclid(1.0);
+            assert!((step - 0.1).abs() < 1e-4, "beat moved {}", step);
+        }
+        // Stopped, every frame says so
+        assert!(phases.iter().filter(|(block, _)| *block >= 40).all(|(_, phase)| phase.is_none()));
+    }
+
+    #[test]
+    fn test_stopped_or_unknown_transport_is_invalid() {
+        assert_eq!(TransportSnapshot { playing: false, ..at_120(10) }.phase_at(BLOCK), None);
+        assert_eq!(TransportSnapshot { tempo: None, ..at_120(10) }.phase_at(BLOCK), None);
+        assert_eq!(TransportSnapshot { pos_beats: None, ..at_120(10) }.phase_at(BLOCK), None);
+        assert_eq!(TransportSnapshot::default().phase_at(BLOCK), None);
+    }
+}
@@ -0,0 +1,450 @@
+//! One queue out of the audio thread, one per consumer after it
+//!
+//! Frames used to be sent to each consumer from the audio thread, a clone
+//! and a `try_send` apiece. Now they are published once, as an `Arc`, onto
+//! the bus's ingress queue, and the bus's own thread hands the same `Arc` to
+//! every subscription. A subscription has its own bounded queue and says
+//! what happens when that is full: drop the newest message, as the Suite's
+//! connection does, or make room by dropping the oldest, as the plugin
+//! window does, which only ever shows the latest frame. A slow consumer
+//! loses its own messages and nobody else's, and each sees what it accepts
+//! in the order it was published.
+//!
+//! Consumers subscribe when they start and leave by dropping their
+//! `Subscription`: the connection thread for as long as it runs, the plugin
+//! window while it is open. With nobody subscribed, publishing does nothing.
+//! The thread starts with the first subscription, so a bus that is never
+//! used, as in a plugin scan, costs no thread. Once the bus is dropped,
+//! subscriptions read as disconnected.
+
+use crossbeam_channel::{bounded, Receiver, Sender, TrySendError};
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+
+use crate::error::TransportError;
+use crate::panics::{self, Region};
+use crate::protocol::AudioPacket;
+use crate::threads::{self, Priority};
+
+/// Messages the audio thread can get ahead of the bus's thread
+pub const INGRESS_LEN: usize = 64;
+
+/// The bus the plugin's packets go out on
+pub type PacketBus = Bus<AudioPacket>;
+
+/// What a full subscription does with the next message
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Drop the message that doesn't fit
+    DropNewest,
+    /// Drop the oldest queued message to make room for it
+    DropOldest,
+}
+
+/// What became of one message for one subscription
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Delivery {
+    /// A message was dropped for it: this one or, with `DropOldest`, the
+    /// oldest queued
+    pub dropped: bool,
+    /// Messages queued for the subscription afterwards
+    pub queued: usize,
+}
+
+type Accepts<T> = Box<dyn Fn(&T) -> bool + Send + Sync>;
+type Observer<T> = Box<dyn Fn(&T, Delivery) + Send + Sync>;
+
+/// How a consumer wants its messages
+pub struct Route<T> {
+    name: &'static str,
+    capacity: usize,
+    overflow: Overflow,
+    accepts: Option<Accepts<T>>,
+    observer: Option<Observer<T>>,
+}
+
+impl<T> Route<T> {
+    pub fn new(name: &'static str, capacity: usize, overflow: Overflow) -> Self {
+        Self {
+            name,
+            capacity,
+            overflow,
+            accepts: None,
+            observer: None,
+        }
+    }
+
+    /// Only take the messages `accepts` is true for
+    pub fn accepting(mut self, accepts: impl Fn(&T) -> bool + Send + Sync + 'static) -> Self {
+        self.accepts = Some(Box::new(accepts));
+        self
+    }
+
+    /// Call `observer` on the bus's thread after each message is queued or
+    /// dropped, for counts and memory gauges
+    pub fn observed(mut self, observer: impl Fn(&T, Delivery) + Send + Sync + 'static) -> Self {
+        self.observer = Some(Box::new(observer));
+        self
+    }
+}
+
+/// Messages one subscription got and lost
+#[derive(Debug, Default)]
+pub struct RouteStats {
+    delivered: AtomicU64,
+    dropped: AtomicU64,
+    /// Until the subscription is dropped
+    open: AtomicBool,
+}
+
+impl RouteStats {
+    #[cfg(test)]
+    pub fn delivered(&self) -> u64 {
+        self.delivered.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Whether the subscription still takes messages
+    pub fn is_open(&self) -> bool {
+        self.open.load(Ordering::Relaxed)
+    }
+}
+
+/// A subscription as the bus's thread sees it
+struct Registered<T> {
+    id: u64,
+    route: Route<T>,
+    sender: Sender<Arc<T>>,
+    /// Where `DropOldest` takes the oldest from
+    receiver: Receiver<Arc<T>>,
+    stats: Arc<RouteStats>,
+}
+
+impl<T> Registered<T> {
+    fn deliver(&self, message: &Arc<T>) {
+        if self.route.accepts.as_ref().is_some_and(|accepts| !accepts(message)) {
+            return;
+        }
+        let dropped = match self.sender.try_send(Arc::clone(message)) {
+            Ok(()) => false,
+            Err(TrySendError::Full(message)) if self.route.overflow == Overflow::DropOldest => {
+                // Unless the consumer took one meanwhile
+                let made_room = self.receiver.try_recv().is_ok();
+                let _ = self.sender.try_send(message);
+                made_room
+            }
+            Err(TrySendError::Full(_)) => true,
+            // The route holds a receiver itself
+            Err(TrySendError::Disconnected(_)) => true,
+        };
+        let counter = if dropped { &self.stats.dropped } else { &self.stats.delivered };
+        counter.fetch_add(1, Ordering::Relaxed);
+        if let Some(observer) = &self.route.observer {
+            observer(message, Delivery { dropped, queued: self.sender.len() });
+        }
+    }
+}
+
+/// The subscriptions, shared by the bus and its thread
+struct Hub<T> {
+    routes: Mutex<Vec<Registered<T>>>,
+    /// `routes.len()`, for the audio thread to read without the lock
+    count: AtomicUsize,
+}
+
+impl<T> Hub<T> {
+    fn dispatch(&self, message: Arc<T>) {
+        for route in self.routes.lock().iter() {
+            route.deliver(&message);
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        let mut routes = self.routes.lock();
+        routes.retain(|route| route.id != id);
+        self.count.store(routes.len(), Ordering::Relaxed);
+    }
+}
+
+/// Publishes messages to every subscription, from any thread
+pub struct Bus<T> {
+    /// Taken in `drop` so the thread sees the bus go
+    ingress: Option<Sender<Arc<T>>>,
+    /// The thread's end, until it starts
+    pending: Mutex<Option<Receiver<Arc<T>>>>,
+    hub: Arc<Hub<T>>,
+    next_id: AtomicU64,
+    thread: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl<T: Send + Sync + 'static> Bus<T> {
+    pub fn new() -> Self {
+        let (ingress, pending) = bounded(INGRESS_LEN);
+        Self {
+            ingress: Some(ingress),
+            pending: Mutex::new(Some(pending)),
+            hub: Arc::new(Hub {
+                routes: Mutex::new(Vec::new()),
+                count: AtomicUsize::new(0),
+            }),
+            next_id: AtomicU64::new(1),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Audio thread: hand `message` to every subscription. One push; never
+    /// blocks or allocates. `QueueFull` if the bus's thread has fallen
+    /// `INGRESS_LEN` messages behind, which drops the message for everyone.
+    pub fn publish(&self, message: Arc<T>) -> Result<(), TransportError> {
+        if self.hub.count.load(Ordering::Relaxed) == 0 {
+            return Ok(());
+        }
+        let Some(ingress) = &self.ingress else {
+            return Err(TransportError::Closed);
+        };
+        ingress.try_send(message).map_err(|e| match e {
+            TrySendError::Full(_) => TransportError::QueueFull,
+            TrySendError::Disconnected(_) => TransportError::Closed,
+        })
+    }
+
+    /// Off the audio thread: start taking messages for `route`. Starts the
+    /// bus's thread on first use; if that fails, the subscription stays
+    /// empty.
+    pub fn subscribe(&self, route: Route<T>) -> Subscription<T> {
+        let name = route.name;
+        let (sender, receiver) = bounded(route.capacity.max(1));
+        let stats = Arc::new(RouteStats {
+            open: AtomicBool::new(true),
+            ..RouteStats::default()
+        });
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        {
+            let mut routes = self.hub.routes.lock();
+            routes.push(Registered {
+                id,
+                route,
+                sender,
+                receiver: receiver.clone(),
+                stats: Arc::clone(&stats),
+            });
+            self.hub.count.store(routes.len(), Ordering::Relaxed);
+        }
+        crate::runtime::debug_log(&format!("[bus] {} subscribed", name));
+        self.start();
+        Subscription {
+            id,
+            receiver,
+            hub: Arc::downgrade(&self.hub),
+            stats,
+        }
+    }
+
+    /// Names of the current subscriptions, in the order they subscribed
+    #[cfg(test)]
+    pub fn routes(&self) -> Vec<&'static str> {
+        self.hub.routes.lock().iter().map(|route| route.route.name).collect()
+    }
+
+    fn start(&self) {
+        let Some(ingress) = self.pending.lock().take() else {
+            return;
+        };
+        let hub = Arc::clone(&self.hub);
+        // Joined in Drop. After a panic the loop goes on with the next
+        // message.
+        let spawned = threads::spawn_restarting("hardwave-bus", Priority::Streaming, Region::Bus, move || {
+            while let Ok(message) = ingress.recv() {
+                panics::checkpoint(Region::Bus);
+                hub.dispatch(message);
+            }
+        });
+        match spawned {
+            Ok(handle) => *self.thread.lock() = Some(handle),
+            Err(e) => crate::runtime::debug_log(&format!("[bus] thread not started: {}", e)),
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Default for Bus<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for Bus<T> {
+    fn drop(&mut self) {
+        // The thread drains what was published and stops
+        self.ingress.take();
+        if let Some(handle) = self.thread.lock().take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// One consumer's queue. Messages are the publisher's `Arc`s, shared with
+/// every other consumer. Dropping it leaves the bus.
+pub struct Subscription<T> {
+    id: u64,
+    receiver: Receiver<Arc<T>>,
+    hub: Weak<Hub<T>>,
+    stats: Arc<RouteStats>,
+}
+
+impl<T> Subscription<T> {
+    /// The queue; clones read from it too, for as long as the subscription
+    /// lives. It disconnects when the subscription or the bus is dropped.
+    pub fn receiver(&self) -> &Receiver<Arc<T>> {
+        &self.receiver
+    }
+
+    pub fn stats(&self) -> Arc<RouteStats> {
+        Arc::clone(&self.stats)
+    }
+}
+
+impl<T> Drop for Subscription<T> {
+    fn drop(&mut self) {
+        self.stats.open.store(false, Ordering::Relaxed);
+        if let Some(hub) = self.hub.upgrade() {
+            hub.remove(self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossbeam_channel::TryRecvError;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    /// Wait until `done`, failing after a second
+    fn wait_for(mut done: impl FnMut() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while !done() {
+            assert!(Instant::now() < deadline, "timed out");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn drain(subscription: &Subscription<u32>) -> Vec<u32> {
+        subscription.receiver().try_iter().map(|message| *message).collect()
+    }
+
+    #[test]
+    fn test_subscriptions_come_and_go() {
+        let bus = Bus::<u32>::new();
+        // Nobody listening: nothing to do, and no thread either
+        assert!(bus.publish(Arc::new(0)).is_ok());
+        assert!(bus.thread.lock().is_none());
+
+        let first = bus.subscribe(Route::new("first", 8, Overflow::DropNewest));
+        bus.publish(Arc::new(1)).unwrap();
+        // Handed out on the bus's thread, so a subscription made before
+        // then would get it too
+        wait_for(|| first.stats().delivered() == 1);
+        let second = bus.subscribe(Route::new("second", 8, Overflow::DropNewest));
+        assert_eq!(bus.routes(), ["first", "second"]);
+        bus.publish(Arc::new(2)).unwrap();
+        wait_for(|| first.stats().delivered() == 2 && second.stats().delivered() == 1);
+        assert_eq!(drain(&first), [1, 2]);
+        assert_eq!(drain(&second), [2], "only what was published after it subscribed");
+
+        // A dropped subscription is off the bus and its clones disconnect
+        let reader = first.receiver().clone();
+        let stats = first.stats();
+        drop(first);
+        assert!(!stats.is_open());
+        assert_eq!(bus.routes(), ["second"]);
+        bus.publish(Arc::new(3)).unwrap();
+        wait_for(|| second.stats().delivered() == 2);
+        assert_eq!(reader.try_recv(), Err(TryRecvError::Disconnected));
+        assert_eq!(stats.delivered(), 2);
+
+        // As do the ones left when the bus goes
+        let reader = second.receiver().clone();
+        drop(bus);
+        assert_eq!(reader.try_recv().map(|message| *message), Ok(3));
+        assert_eq!(reader.try_recv(), Err(TryRecvError::Disconnected));
+        drop(second);
+    }
+
+    #[test]
+    fn test_a_slow_consumer_only_loses_its_own() {
+        let bus = Bus::<u32>::new();
+        let slow = bus.subscribe(Route::new("slow", 4, Overflow::DropNewest));
+        let latest = bus.subscribe(Route::new("latest", 4, Overflow::DropOldest));
+        let fast = bus.subscribe(Route::new("fast", 4, Overflow::DropNewest));
+
+        // `fast` keeps up, the other two never read
+        let mut seen = Vec::new();
+        for i in 0..20 {
+            bus.publish(Arc::new(i)).unwrap();
+            wait_for(|| fast.stats().delivered() == u64::from(i) + 1);
+            seen.extend(drain(&fast));
+        }
+        assert_eq!(seen, (0..20).collect::<Vec<_>>());
+        assert_eq!(fast.stats().dropped(), 0);
+
+        wait_for(|| slow.stats().dropped() + slow.stats().delivered() == 20);
+        assert_eq!(drain(&slow), [0, 1, 2, 3], "the newest didn't fit");
+        assert_eq!(slow.stats().dropped(), 16);
+
+        wait_for(|| latest.stats().dropped() + latest.stats().delivered() == 20);
+        assert_eq!(drain(&latest), [16, 17, 18, 19], "the oldest made room");
+        assert_eq!(latest.stats().dropped(), 16);
+    }
+
+    #[test]
+    fn test_each_consumer_sees_its_messages_in_order() {
+        let bus = Arc::new(Bus::<u32>::new());
+        let even = bus.subscribe(Route::new("even", 1024, Overflow::DropNewest).accepting(|n| n % 2 == 0));
+        let all = bus.subscribe(Route::new("all", 1024, Overflow::DropNewest));
+        let observed = Arc::new(AtomicU64::new(0));
+        let seen = Arc::clone(&observed);
+        let watched = bus.subscribe(
+            Route::new("watched", 2, Overflow::DropOldest).observed(move |_, delivery| {
+                assert!(delivery.queued <= 2);
+                seen.fetch_add(1, Ordering::Relaxed);
+            }),
+        );
+
+        // Published from another thread, as the audio thread would
+        let publisher = {
+            let bus = Arc::clone(&bus);
+            thread::spawn(move || {
+                for i in 0..500 {
+                    while matches!(bus.publish(Arc::new(i)), Err(TransportError::QueueFull)) {
+                        thread::yield_now();
+                    }
+                }
+            })
+        };
+        publisher.join().unwrap();
+        wait_for(|| all.stats().delivered() == 500 && observed.load(Ordering::Relaxed) == 500);
+        assert_eq!(drain(&all), (0..500).collect::<Vec<_>>());
+        assert_eq!(drain(&even), (0..500).step_by(2).collect::<Vec<_>>());
+        assert_eq!(drain(&watched), [498, 499]);
+    }
+
+    #[test]
+    fn test_every_consumer_shares_the_published_message() {
+        let bus = Bus::<u32>::new();
+        let (a, b) = (
+            bus.subscribe(Route::new("a", 4, Overflow::DropNewest)),
+            bus.subscribe(Route::new("b", 4, Overflow::DropNewest)),
+        );
+        let message = Arc::new(7);
+        bus.publish(Arc::clone(&message)).unwrap();
+        wait_for(|| a.stats().delivered() == 1 && b.stats().delivered() == 1);
+        let (from_a, from_b) = (a.receiver().recv().unwrap(), b.receiver().recv().unwrap());
+        assert!(Arc::ptr_eq(&from_a, &message) && Arc::ptr_eq(&from_b, &message), "no copies");
+    }
+}
@@ -0,0 +1,366 @@
+//! Clicks and discontinuities in the input, clipped or not
+//!
+//! A buffer underrun or a bad edit leaves a jump between two samples that is
+//! usually well under 0 dBFS. The second difference `x[n] - 2x[n-1] +
+//! x[n-2]` is a differentiator with a 12 dB/octave high pass: it is small
+//! for anything band-limited and spikes on a jump. Each sample of it is
+//! compared with the RMS of the `HALF_WINDOW` samples either side, leaving
+//! out a few around it; a click stands `THRESHOLD_DB` above that. Looking
+//! ahead as well as back is what keeps drums out: a hit is loud from its
+//! onset on, so the samples after it are as loud as the onset, while a
+//! click is over as soon as it started. Detection is `HALF_WINDOW` samples
+//! late, which the reported positions account for.
+//!
+//! The engine runs one detector per input channel, at the host rate, and
+//! keeps a `ClickTally`; the plugin moves it into the shared `ClickStats`
+//! once per block. Heartbeats carry the clicks since the previous one, and
+//! the plugin window's `clicks` command (and `/clicks` on the Windows packet
+//! server) reports the totals.
+
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+/// Samples of context either side of the one being judged
+pub const HALF_WINDOW: usize = 32;
+
+/// How far above its surroundings a click's second difference stands
+pub const THRESHOLD_DB: f32 = 20.0;
+
+/// Samples either side of the judged one left out of its surroundings, since
+/// a jump spikes the second difference for three samples
+const GUARD: usize = 2;
+
+const WINDOW: usize = 2 * HALF_WINDOW + 1;
+
+/// Second differences below this (-60 dBFS) are never clicks, so noise in
+/// near silence isn't counted
+const FLOOR: f32 = 1e-3;
+
+/// One click in one channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Click {
+    /// Index of the first sample after the jump, counted from the
+    /// detector's start
+    pub sample: u64,
+    /// How far the second difference stood above its surroundings, dB
+    pub severity_db: f32,
+}
+
+/// The detector of one channel. Audio thread only; never allocates.
+#[derive(Debug, Clone)]
+pub struct ClickDetector {
+    /// The previous two input samples
+    x1: f32,
+    x2: f32,
+    /// The last `WINDOW` second differences; the judged one is in the middle
+    ring: [f32; WINDOW],
+    /// Where the next second difference goes, also the oldest
+    next: usize,
+    /// Sum of squares of `ring`, recomputed every time round
+    sum_sq: f64,
+    /// Input samples seen
+    samples: u64,
+    /// Samples left in which a click just found isn't found again
+    holdoff: usize,
+}
+
+impl ClickDetector {
+    pub fn new() -> Self {
+        Self {
+            x1: 0.0,
+            x2: 0.0,
+            ring: [0.0; WINDOW],
+            next: 0,
+            sum_sq: 0.0,
+            samples: 0,
+            holdoff: 0,
+        }
+    }
+
+    /// Input samples seen; what `Click::sample` counts in
+    pub fn samples(&self) -> u64 {
+        self.samples
+    }
+
+    /// Forget the signal, as after a transport jump, keeping the count
+    pub fn reset(&mut self) {
+        *self = Self { samples: self.samples, ..Self::new() };
+    }
+
+    /// Take one sample; returns a click found `HALF_WINDOW` samples ago
+    pub fn process(&mut self, x: f32) -> Option<Click> {
+        let diff = x - 2.0 * self.x1 + self.x2;
+        self.x2 = self.x1;
+        self.x1 = x;
+
+        let old = std::mem::replace(&mut self.ring[self.next], diff);
+        self.sum_sq += f64::from(diff) * f64::from(diff) - f64::from(old) * f64::from(old);
+        self.next = (self.next + 1) % WINDOW;
+        if self.next == 0 {
+            // Keeps rounding from piling up
+            self.sum_sq = self.ring.iter().map(|&d| f64::from(d) * f64::from(d)).sum();
+        }
+        self.samples += 1;
+        if self.samples < WINDOW as u64 + 2 {
+            return None;
+        }
+        if self.holdoff > 0 {
+            self.holdoff -= 1;
+            return None;
+        }
+
+        let at = |offset: isize| self.ring[(self.next as isize + HALF_WINDOW as isize + offset).rem_euclid(WINDOW as isize) as usize];
+        let peak = at(0).abs();
+        if peak <= FLOOR {
+            return None;
+        }
+        let guard = -(GUARD as isize)..=GUARD as isize;
+        let guard_sq: f64 = guard.clone().map(|offset| f64::from(at(offset)) * f64::from(at(offset))).sum();
+        let surroundings = ((self.sum_sq - guard_sq).max(0.0) / (WINDOW - 2 * GUARD - 1) as f64).sqrt() as f32;
+        let threshold = 10f32.powf(THRESHOLD_DB / 20.0);
+        if peak <= threshold * surroundings {
+            return None;
+        }
+
+        // The biggest of the spike's samples says where and how bad
+        let (offset, worst) = guard
+            .map(|offset| (offset, at(offset).abs()))
+            .fold((0, 0.0), |best, candidate| if candidate.1 > best.1 { candidate } else { best });
+        self.holdoff = HALF_WINDOW;
+        // The second difference just taken is centred on the sample before
+        let sample = (self.samples as i64 - 2 - HALF_WINDOW as i64 + offset as i64) as u64;
+        Some(Click {
+            sample,
+            severity_db: 20.0 * (worst / surroundings.max(f32::MIN_POSITIVE)).log10(),
+        })
+    }
+}
+
+impl Default for ClickDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What heartbeats and the stats report of the clicks over some time
+#[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct ClickReport {
+    pub count: u32,
+    /// When the worst one was, in ms on the clock of the packets'
+    /// `timestamp_ms`; 0 without clicks
+    pub worst_timestamp_ms: u64,
+    /// How far the worst one stood above its surroundings, dB; 0 without
+    /// clicks
+    pub worst_severity_db: f32,
+}
+
+/// The engine's clicks since the plugin last took them. Both channels feed
+/// it, so a click in both counts once.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClickTally {
+    count: u32,
+    worst: Option<Click>,
+    /// Where the last click was, taken or not
+    last_sample: Option<u64>,
+}
+
+impl ClickTally {
+    pub fn add(&mut self, click: Click) {
+        let repeat = self.last_sample.is_some_and(|last| click.sample.abs_diff(last) <= HALF_WINDOW as u64);
+        self.last_sample = Some(click.sample);
+        if !repeat {
+            self.count += 1;
+        }
+        if self.worst.is_none_or(|worst| click.severity_db > worst.severity_db) {
+            self.worst = Some(click);
+        }
+    }
+
+    /// The clicks so far and the worst of them, clearing both
+    pub fn take(&mut self) -> Option<(u32, Click)> {
+        let worst = self.worst.take()?;
+        Some((std::mem::take(&mut self.count), worst))
+    }
+}
+
+/// Clicks since the last heartbeat and since the plugin started, shared by
+/// the plugin, the connection thread and the editor. The worst click's
+/// severity and time are packed into one atomic, severity on top, so the
+/// two always belong together.
+#[derive(Debug, Default)]
+pub struct ClickStats {
+    interval_count: AtomicU32,
+    interval_worst: AtomicU64,
+    total_count: AtomicU32,
+    total_worst: AtomicU64,
+}
+
+const TIMESTAMP_BITS: u32 = 48;
+
+fn pack(report: &ClickReport) -> u64 {
+    let centi_db = (report.worst_severity_db * 100.0).round().clamp(0.0, f32::from(u16::MAX)) as u64;
+    (centi_db << TIMESTAMP_BITS) | (report.worst_timestamp_ms & ((1 << TIMESTAMP_BITS) - 1))
+}
+
+fn unpack(count: u32, packed: u64) -> ClickReport {
+    if count == 0 {
+        return ClickReport::default();
+    }
+    ClickReport {
+        count,
+        worst_timestamp_ms: packed & ((1 << TIMESTAMP_BITS) - 1),
+        worst_severity_db: (packed >> TIMESTAMP_BITS) as f32 / 100.0,
+    }
+}
+
+impl ClickStats {
+    /// Audio thread, with what the engine found since the last call
+    pub fn record(&self, report: ClickReport) {
+        let packed = pack(&report);
+        self.interval_count.fetch_add(report.count, Ordering::Relaxed);
+        self.interval_worst.fetch_max(packed, Ordering::Relaxed);
+        self.total_count.fetch_add(report.count, Ordering::Relaxed);
+        self.total_worst.fetch_max(packed, Ordering::Relaxed);
+    }
+
+    /// Connection thread, once per heartbeat: the clicks since the previous
+    /// one, if there were any. A click recorded while this runs may be
+    /// counted in the next interval.
+    pub fn take_interval(&self) -> Option<ClickReport> {
+        let count = self.interval_count.swap(0, Ordering::Relaxed);
+        let worst = self.interval_worst.swap(0, Ordering::Relaxed);
+        (count > 0).then(|| unpack(count, worst))
+    }
+
+    /// The clicks since the plugin started
+    #[cfg(any(feature = "gui", test))]
+    pub fn report(&self) -> ClickReport {
+        unpack(self.total_count.load(Ordering::Relaxed), self.total_worst.load(Ordering::Relaxed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 48000.0;
+
+    /// Same xorshift32 as the test vectors, in -1..1
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self) -> f32 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            self.0 = x;
+            (x as f64 / 4_294_967_296.0 * 2.0 - 1.0) as f32
+        }
+    }
+
+    /// Two bars at 120 bpm: a swept kick on 1 and 3, a noisy snare on 2 and
+    /// 4, and hats on every eighth, each starting at full level on its
+    /// first sample
+    fn drum_loop() -> Vec<f32> {
+        let seconds = 4.0;
+        let len = (RATE * seconds) as usize;
+        let beat = RATE as usize / 2;
+        let mut out = vec![0.0f32; len];
+        let mut noise = Noise(0x2545_f491);
+        fn add(out: &mut [f32], start: usize, mut samples: impl FnMut(f32) -> f32, seconds: f32) {
+            for i in 0..(seconds * RATE) as usize {
+                if let Some(sample) = out.get_mut(start + i) {
+                    *sample += samples(i as f32 / RATE);
+                }
+            }
+        }
+        for b in 0..8 {
+            if b % 2 == 0 {
+                let mut phase = 0.0f32;
+                let kick = |t: f32| {
+                    phase += 2.0 * PI * (50.0 + 100.0 * (-t / 0.03).exp()) / RATE;
+                    0.8 * (-t / 0.15).exp() * phase.sin()
+                };
+                add(&mut out, b * beat, kick, 0.6);
+            } else {
+                let snare = |t: f32| {
+                    0.5 * noise.next() * (-t / 0.06).exp() + 0.3 * (2.0 * PI * 180.0 * t).sin() * (-t / 0.08).exp()
+                };
+                add(&mut out, b * beat, snare, 0.4);
+            }
+        }
+        for h in 0..16 {
+            let mut previous = 0.0;
+            let hat = |t: f32| {
+                let white = noise.next();
+                let high = (white - previous) * 0.5;
+                previous = white;
+                0.3 * high * (-t / 0.02).exp()
+            };
+            add(&mut out, h * beat / 2, hat, 0.15);
+        }
+        out
+    }
+
+    fn detect(samples: &[f32]) -> Vec<Click> {
+        let mut detector = ClickDetector::new();
+        samples.iter().filter_map(|&x| detector.process(x)).collect()
+    }
+
+    #[test]
+    fn test_drums_are_not_clicks() {
+        let drums = drum_loop();
+        assert!(drums.iter().any(|x| x.abs() > 0.5), "the loop is loud");
+        assert_eq!(detect(&drums), []);
+    }
+
+    #[test]
+    fn test_three_discontinuities_are_three_clicks() {
+        let mut audio = drum_loop();
+        // A dropped sample in a kick's tail, a one-sample spike and a splice
+        // that loses 200 samples, all between hits
+        let dropout = (0.375 * RATE) as usize + audio[18_000..].iter().position(|x| x.abs() > 0.05).unwrap();
+        audio[dropout] = 0.0;
+        let spike = (2.375 * RATE) as usize;
+        audio[spike] += 0.1;
+        let splice = (3.375 * RATE) as usize;
+        audio.drain(splice..splice + 200);
+
+        let clicks = detect(&audio);
+        let found: Vec<u64> = clicks.iter().map(|click| click.sample).collect();
+        assert_eq!(found.len(), 3, "{:?}", clicks);
+        for (found, inserted) in found.iter().zip([dropout, spike, splice]) {
+            assert!(found.abs_diff(inserted as u64) <= 1, "click at {} for one at {}", found, inserted);
+        }
+        assert!(clicks.iter().all(|click| click.severity_db > THRESHOLD_DB));
+    }
+
+    #[test]
+    fn test_a_click_in_both_channels_counts_once() {
+        let mut tally = ClickTally::default();
+        tally.add(Click { sample: 1000, severity_db: 30.0 });
+        tally.add(Click { sample: 1001, severity_db: 36.0 });
+        tally.add(Click { sample: 5000, severity_db: 25.0 });
+        assert_eq!(tally.take(), Some((2, Click { sample: 1001, severity_db: 36.0 })));
+        assert_eq!(tally.take(), None);
+    }
+
+    #[test]
+    fn test_stats_report_the_worst_click_per_interval() {
+        let stats = ClickStats::default();
+        assert_eq!(stats.take_interval(), None);
+        stats.record(ClickReport { count: 2, worst_timestamp_ms: 1500, worst_severity_db: 31.5 });
+        stats.record(ClickReport { count: 1, worst_timestamp_ms: 1900, worst_severity_db: 24.0 });
+        assert_eq!(
+            stats.take_interval(),
+            Some(ClickReport { count: 3, worst_timestamp_ms: 1500, worst_severity_db: 31.5 })
+        );
+        assert_eq!(stats.take_interval(), None);
+
+        stats.record(ClickReport { count: 1, worst_timestamp_ms: 9000, worst_severity_db: 40.0 });
+        assert_eq!(stats.take_interval().map(|report| report.count), Some(1));
+        assert_eq!(stats.report(), ClickReport { count: 4, worst_timestamp_ms: 9000, worst_severity_db: 40.0 });
+    }
+}
@@ -0,0 +1,85 @@
+//! Time for the connection thread's schedules
+//!
+//! Heartbeats, timing reports and metrics blobs go out on intervals the
+//! connection thread measures with a `Clock`. In the plugin that is the
+//! system's. Tests step a `Clock::manual()` themselves, so a cadence is
+//! checked by what goes out after each step rather than by counting what
+//! arrived in a wall-clock window, which a loaded machine stretches.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Default)]
+pub struct Clock {
+    manual: Option<Arc<Manual>>,
+}
+
+#[derive(Debug)]
+struct Manual {
+    start: Instant,
+    elapsed_us: AtomicU64,
+    /// How often `now` was asked, so a test can tell the connection thread
+    /// has seen a step
+    reads: AtomicU64,
+}
+
+impl Clock {
+    pub fn system() -> Self {
+        Self::default()
+    }
+
+    /// A clock that stands still until `advance`d
+    #[cfg(test)]
+    pub fn manual() -> Self {
+        Self {
+            manual: Some(Arc::new(Manual {
+                start: Instant::now(),
+                elapsed_us: AtomicU64::new(0),
+                reads: AtomicU64::new(0),
+            })),
+        }
+    }
+
+    pub fn now(&self) -> Instant {
+        match &self.manual {
+            Some(manual) => {
+                manual.reads.fetch_add(1, Ordering::SeqCst);
+                manual.start + Duration::from_micros(manual.elapsed_us.load(Ordering::SeqCst))
+            }
+            None => Instant::now(),
+        }
+    }
+
+    /// Step a manual clock; the system clock can't be
+    #[cfg(test)]
+    pub fn advance(&self, by: Duration) {
+        let manual = self.manual.as_ref().expect("only a manual clock can be advanced");
+        manual.elapsed_us.fetch_add(by.as_micros() as u64, Ordering::SeqCst);
+    }
+
+    /// How often a manual clock was read; 0 for the system clock
+    #[cfg(test)]
+    pub fn reads(&self) -> u64 {
+        self.manual.as_ref().map_or(0, |manual| manual.reads.load(Ordering::SeqCst))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manual_clock_moves_only_when_advanced() {
+        let clock = Clock::manual();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_millis(1500));
+        assert_eq!(clock.now() - start, Duration::from_millis(1500));
+        assert_eq!(clock.reads(), 3);
+
+        let system = Clock::system();
+        assert!(system.now() <= Instant::now());
+        assert_eq!(system.reads(), 0);
+    }
+}
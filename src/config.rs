@@ -0,0 +1,165 @@
+//! Machine-wide settings shared by every plugin instance on this computer.
+//!
+//! Stored as JSON at `~/.hardwave/config.json`. A missing file and missing
+//! fields fall back to defaults, so the file only needs the keys a user
+//! actually wants to change. A file that can't be read or parsed is an error,
+//! which callers report and then carry on with the defaults.
+
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::{ConfigError, HardwaveError};
+use crate::udp::Transport;
+use crate::wire_format::WireFormat;
+
+/// Settings read from the machine config file
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MachineConfig {
+    /// Preferred WebView2 user data directory
+    pub webview_data_dir: Option<PathBuf>,
+
+    /// Record every outgoing packet from startup (see `recorder`)
+    pub record_packets: bool,
+
+    /// Check after every block that the left and right analysis state agree
+    /// (see `self_check`); debug builds always do
+    pub paranoid: bool,
+
+    /// An instance on this machine has connected to the Suite, so the
+    /// plugin window's first-run setup is over (see `onboarding`)
+    pub onboarded: bool,
+
+    /// Encoding to ask the Suite for on the packet stream, `"bincode"` or,
+    /// in builds with the `proto` feature, `"proto"` (see `wire_format`)
+    pub stream_format: WireFormat,
+
+    /// Connect to the Suite over TLS, in builds with the `tls` feature (see
+    /// `tls`)
+    pub use_tls: bool,
+
+    /// Browse for the Suite over mDNS rather than connect to the Port
+    /// parameter on this machine, in builds with the `mdns` feature (see
+    /// `discovery`)
+    pub discover_suite: bool,
+
+    /// Host the Suite runs on, at the Port parameter; wins over
+    /// `discover_suite`
+    pub suite_host: Option<String>,
+
+    /// How packets get to the Suite, `"websocket"` or `"udp"` (see `udp`)
+    pub transport: Transport,
+}
+
+/// Path to the machine config file.
+pub fn config_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".hardwave").join("config.json"))
+}
+
+/// Load the machine config. No file, or no home directory to look in, means
+/// the defaults; a file that can't be read or parsed is an error.
+pub fn load() -> Result<MachineConfig, HardwaveError> {
+    config_path().map_or(Ok(MachineConfig::default()), |p| load_from(&p))
+}
+
+pub fn load_from(path: &Path) -> Result<MachineConfig, HardwaveError> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(MachineConfig::default()),
+        Err(e) => return Err(ConfigError::Io(e).into()),
+    };
+    Ok(serde_json::from_str(&text).map_err(ConfigError::Json)?)
+}
+
+/// Set `onboarded` in the config file at `path`, creating it if need be.
+/// Every other key, known or not, is kept as it was.
+pub fn mark_onboarded(path: &Path) -> Result<(), HardwaveError> {
+    let mut config = match fs::read_to_string(path) {
+        Ok(text) => serde_json::from_str::<serde_json::Value>(&text).map_err(ConfigError::Json)?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => serde_json::json!({}),
+        Err(e) => return Err(ConfigError::Io(e).into()),
+    };
+    let Some(fields) = config.as_object_mut() else {
+        return Err(ConfigError::Io(io::Error::new(io::ErrorKind::InvalidData, "config is not an object")).into());
+    };
+    fields.insert("onboarded".to_string(), serde_json::Value::Bool(true));
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(ConfigError::Io)?;
+    }
+    let text = serde_json::to_string_pretty(&config).map_err(ConfigError::Json)?;
+    fs::write(path, text).map_err(ConfigError::Io)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_fields_default() {
+        let config: MachineConfig = serde_json::from_str("{}").unwrap();
+        assert_eq!(config, MachineConfig::default());
+
+        let config: MachineConfig =
+            serde_json::from_str(r#"{"webview_data_dir": "/tmp/wv", "unknown": 1}"#).unwrap();
+        assert_eq!(config.webview_data_dir, Some(PathBuf::from("/tmp/wv")));
+        assert!(!config.record_packets);
+        assert!(!config.paranoid);
+        assert!(!config.onboarded);
+        assert_eq!(config.stream_format, WireFormat::Canonical);
+        assert!(!config.use_tls);
+        assert!(!config.discover_suite);
+        assert_eq!(config.suite_host, None);
+        assert_eq!(config.transport, Transport::WebSocket);
+
+        let config: MachineConfig = serde_json::from_str(r#"{"stream_format": "proto", "use_tls": true}"#).unwrap();
+        assert_eq!(config.stream_format, WireFormat::Proto);
+        assert!(config.use_tls);
+
+        let config: MachineConfig =
+            serde_json::from_str(r#"{"discover_suite": true, "suite_host": "studio.local"}"#).unwrap();
+        assert!(config.discover_suite);
+        assert_eq!(config.suite_host.as_deref(), Some("studio.local"));
+
+        let config: MachineConfig = serde_json::from_str(r#"{"transport": "udp"}"#).unwrap();
+        assert_eq!(config.transport, Transport::Udp);
+    }
+
+    #[test]
+    fn test_mark_onboarded_keeps_other_keys() {
+        let dir = std::env::temp_dir().join(format!("hardwave-onboarded-{}", std::process::id()));
+        let path = dir.join("nested").join("config.json");
+        mark_onboarded(&path).unwrap();
+        assert!(load_from(&path).unwrap().onboarded);
+
+        fs::write(&path, r#"{"paranoid": true, "future": [1]}"#).unwrap();
+        mark_onboarded(&path).unwrap();
+        let config = load_from(&path).unwrap();
+        assert!(config.onboarded && config.paranoid);
+        let raw: serde_json::Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(raw["future"], serde_json::json!([1]));
+
+        fs::write(&path, "[]").unwrap();
+        assert!(mark_onboarded(&path).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_missing_file_defaults_and_bad_file_errors() {
+        let dir = std::env::temp_dir().join(format!("hardwave-config-{}", std::process::id()));
+        let _ = fs::create_dir_all(&dir);
+        let path = dir.join("config.json");
+        assert_eq!(load_from(&path).unwrap(), MachineConfig::default());
+
+        fs::write(&path, "not json").unwrap();
+        assert!(matches!(load_from(&path), Err(HardwaveError::Config(ConfigError::Json(_)))));
+
+        // A directory where the file should be can't be read
+        fs::remove_file(&path).unwrap();
+        fs::create_dir_all(&path).unwrap();
+        assert!(matches!(load_from(&path), Err(HardwaveError::Config(ConfigError::Io(_)))));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
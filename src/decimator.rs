@@ -0,0 +1,277 @@
+//! Half-band decimation of the analysis feed
+//!
+//! At 96 kHz and above most FFT bins sit above the audible range. Unless the
+//! user asks for full bandwidth, the analysis feed is decimated by powers of two
+//! down to at most 48 kHz before it reaches the FFT, which keeps the cost at
+//! 192 kHz equal to 48 kHz and improves low-frequency resolution.
+
+use std::f32::consts::PI;
+use std::mem::size_of;
+
+use crate::memory;
+
+/// Highest sample rate the FFT sees in decimation mode.
+pub const MAX_ANALYSIS_RATE: f32 = 48_000.0;
+
+/// Maximum number of half-band stages the rate choices use (384 kHz → 48 kHz).
+const MAX_RATE_STAGES: usize = 3;
+
+/// Stages a decimator holds: the rate choices plus the one eco mode adds.
+const MAX_STAGES: usize = MAX_RATE_STAGES + 1;
+
+/// Number of taps in each half-band FIR (odd, symmetric).
+const NUM_TAPS: usize = 63;
+
+/// Kaiser window beta for ~80 dB stopband attenuation.
+const KAISER_BETA: f32 = 7.86;
+
+/// Number of decimate-by-two stages needed to bring `sample_rate` to at most
+/// `MAX_ANALYSIS_RATE`.
+pub fn stages_for_rate(sample_rate: f32) -> usize {
+    let mut stages = 0;
+    let mut rate = sample_rate;
+    while rate > MAX_ANALYSIS_RATE + 1.0 && stages < MAX_RATE_STAGES {
+        rate /= 2.0;
+        stages += 1;
+    }
+    stages
+}
+
+/// Number of decimate-by-two stages that bring `sample_rate` as close to
+/// `MAX_ANALYSIS_RATE` as halving can without going below it, for the
+/// resampler to take the rest of the way.
+pub fn stages_above_rate(sample_rate: f32) -> usize {
+    let mut stages = 0;
+    let mut rate = sample_rate;
+    while rate / 2.0 > MAX_ANALYSIS_RATE - 1.0 && stages < MAX_RATE_STAGES {
+        rate /= 2.0;
+        stages += 1;
+    }
+    stages
+}
+
+/// Zeroth-order modified Bessel function of the first kind (series expansion).
+pub fn bessel_i0(x: f32) -> f32 {
+    let mut sum = 1.0_f32;
+    let mut term = 1.0_f32;
+    let half = x / 2.0;
+    for k in 1..32 {
+        term *= half / k as f32;
+        sum += term * term;
+    }
+    sum
+}
+
+/// Kaiser-windowed half-band lowpass coefficients, normalised to unity DC gain.
+fn half_band_coefficients() -> Vec<f32> {
+    let m = (NUM_TAPS - 1) as f32 / 2.0;
+    let norm = bessel_i0(KAISER_BETA);
+    let mut coeffs: Vec<f32> = (0..NUM_TAPS)
+        .map(|n| {
+            let x = n as f32 - m;
+            let sinc = if x == 0.0 { 0.5 } else { (PI * x / 2.0).sin() / (PI * x) };
+            let r = x / m;
+            let window = bessel_i0(KAISER_BETA * (1.0 - r * r).max(0.0).sqrt()) / norm;
+            sinc * window
+        })
+        .collect();
+    let sum: f32 = coeffs.iter().sum();
+    for c in &mut coeffs {
+        *c /= sum;
+    }
+    coeffs
+}
+
+/// A single decimate-by-two stage.
+struct HalfBandStage {
+    coeffs: Vec<f32>,
+    /// History stored twice so the newest `NUM_TAPS` samples are always contiguous
+    history: Vec<f32>,
+    pos: usize,
+    odd: bool,
+}
+
+impl HalfBandStage {
+    fn new() -> Self {
+        Self {
+            coeffs: half_band_coefficients(),
+            history: vec![0.0; NUM_TAPS * 2],
+            pos: 0,
+            odd: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.history.iter_mut().for_each(|s| *s = 0.0);
+        self.pos = 0;
+        self.odd = false;
+    }
+
+    /// Push one input sample; returns an output sample every second call.
+    fn process(&mut self, x: f32) -> Option<f32> {
+        self.history[self.pos] = x;
+        self.history[self.pos + NUM_TAPS] = x;
+        self.pos = (self.pos + 1) % NUM_TAPS;
+
+        self.odd = !self.odd;
+        if self.odd {
+            return None;
+        }
+
+        let window = &self.history[self.pos..self.pos + NUM_TAPS];
+        Some(window.iter().zip(&self.coeffs).map(|(s, c)| s * c).sum())
+    }
+}
+
+/// Cascade of half-band stages for one channel.
+pub struct Decimator {
+    stages: Vec<HalfBandStage>,
+    active: usize,
+}
+
+impl Decimator {
+    /// What `heap_bytes` reports: every stage's coefficients and doubled
+    /// history, whether active or not
+    pub const MAX_HEAP_BYTES: usize =
+        MAX_STAGES * (size_of::<HalfBandStage>() + 3 * NUM_TAPS * size_of::<f32>());
+
+    pub fn new() -> Self {
+        Self {
+            stages: (0..MAX_STAGES).map(|_| HalfBandStage::new()).collect(),
+            active: 0,
+        }
+    }
+
+    /// Set the number of active stages (clamped to the supported maximum) and
+    /// clear the filter state.
+    pub fn set_stages(&mut self, stages: usize) {
+        self.active = stages.min(MAX_STAGES);
+        self.reset();
+    }
+
+    /// Heap bytes of the filter state
+    pub fn heap_bytes(&self) -> usize {
+        memory::vec_bytes(&self.stages)
+            + self
+                .stages
+                .iter()
+                .map(|stage| memory::vec_bytes(&stage.coeffs) + memory::vec_bytes(&stage.history))
+                .sum::<usize>()
+    }
+
+    /// Overall decimation factor.
+    pub fn factor(&self) -> usize {
+        1 << self.active
+    }
+
+    pub fn reset(&mut self) {
+        for stage in &mut self.stages {
+            stage.reset();
+        }
+    }
+
+    /// Push one input sample; returns a sample at the decimated rate when one
+    /// is ready.
+    pub fn process(&mut self, x: f32) -> Option<f32> {
+        let mut sample = x;
+        for stage in &mut self.stages[..self.active] {
+            sample = stage.process(sample)?;
+        }
+        Some(sample)
+    }
+}
+
+impl Default for Decimator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::{FftProcessor, FFT_SIZE};
+
+    const SAMPLE_RATE: f32 = 96_000.0;
+
+    /// Analyse a 30 kHz tone at 96 kHz with the given number of stages and
+    /// return (analysis_rate, bins).
+    fn analyse_tone(stages: usize) -> (f32, Vec<f32>) {
+        let mut decimator = Decimator::new();
+        decimator.set_stages(stages);
+        let analysis_rate = SAMPLE_RATE / decimator.factor() as f32;
+
+        let mut samples = Vec::with_capacity(FFT_SIZE);
+        let mut i = 0;
+        while samples.len() < FFT_SIZE + NUM_TAPS {
+            let x = (2.0 * PI * 30_000.0 * i as f32 / SAMPLE_RATE).sin();
+            if let Some(y) = decimator.process(x) {
+                samples.push(y);
+            }
+            i += 1;
+        }
+        // Skip the filter warm-up.
+        let window = &samples[samples.len() - FFT_SIZE..];
+        (analysis_rate, FftProcessor::new().process(window, analysis_rate))
+    }
+
+    fn bin_for(freq: f32, rate: f32) -> usize {
+        (freq / (rate / FFT_SIZE as f32)).round() as usize
+    }
+
+    #[test]
+    fn test_stages_for_rate() {
+        assert_eq!(stages_for_rate(44_100.0), 0);
+        assert_eq!(stages_for_rate(48_000.0), 0);
+        assert_eq!(stages_for_rate(88_200.0), 1);
+        assert_eq!(stages_for_rate(96_000.0), 1);
+        assert_eq!(stages_for_rate(192_000.0), 2);
+        assert_eq!(stages_for_rate(384_000.0), 3);
+    }
+
+    #[test]
+    fn test_stages_above_rate() {
+        assert_eq!(stages_above_rate(44_100.0), 0);
+        assert_eq!(stages_above_rate(88_200.0), 0);
+        assert_eq!(stages_above_rate(96_000.0), 1);
+        assert_eq!(stages_above_rate(176_400.0), 1);
+        assert_eq!(stages_above_rate(768_000.0), 3);
+    }
+
+    #[test]
+    fn test_full_bandwidth_shows_ultrasonic_tone() {
+        let (rate, bins) = analyse_tone(0);
+        assert_eq!(rate, SAMPLE_RATE);
+        let bin = bin_for(30_000.0, rate);
+        assert!(bins[bin] > -3.0, "30 kHz tone reads {} dB", bins[bin]);
+    }
+
+    #[test]
+    fn test_decimation_rejects_ultrasonic_tone() {
+        let (rate, bins) = analyse_tone(1);
+        assert_eq!(rate, 48_000.0);
+        // Without filtering, 30 kHz would alias to 18 kHz at 48 kHz.
+        let alias = bin_for(18_000.0, rate);
+        let loudest = bins.iter().cloned().fold(-100.0_f32, f32::max);
+        assert!(bins[alias] < -60.0, "alias reads {} dB", bins[alias]);
+        assert!(loudest < -60.0, "loudest bin reads {} dB", loudest);
+    }
+
+    #[test]
+    fn test_decimation_passes_audible_tone() {
+        let mut decimator = Decimator::new();
+        decimator.set_stages(1);
+        let mut samples = Vec::new();
+        let mut i = 0;
+        while samples.len() < FFT_SIZE + NUM_TAPS {
+            let x = (2.0 * PI * 1_000.0 * i as f32 / SAMPLE_RATE).sin();
+            if let Some(y) = decimator.process(x) {
+                samples.push(y);
+            }
+            i += 1;
+        }
+        let bins = FftProcessor::new().process(&samples[samples.len() - FFT_SIZE..], 48_000.0);
+        let bin = bin_for(1_000.0, 48_000.0);
+        assert!(bins[bin] > -1.0, "1 kHz tone reads {} dB", bins[bin]);
+    }
+}
@@ -0,0 +1,94 @@
+//! More Suites than the one at the Port parameter
+//!
+//! The plugin streams to the Suite at the Port parameter, wherever
+//! `discovery` finds it. A project can list more destinations, a host and a
+//! port each: a second Suite on the studio's shared display machine, say.
+//! The list is saved with the project (see `params`) and set from the
+//! plugin window. Every enabled destination gets a connection of its own,
+//! with its own thread, state, backoff and queue on the bus, so one that is
+//! slow or not there never holds up the others.
+//!
+//! Every connection gets the same packets, numbered the same. What a
+//! connection makes itself, heartbeats and the rest, is numbered from the
+//! instance's one counter as well, so each Suite sees a gap where another's
+//! heartbeat went; it counts them as packets it didn't get, see `sequence`.
+//!
+//! The Suite at the Port parameter is the one in charge. What it
+//! subscribes to decides what is computed, so another Suite gets what it
+//! asked for only where that was computed anyway; the commands from the
+//! plugin window go to it alone, and only its update rate and names apply.
+//! The others never share a connection, so each is a stream of its own even
+//! on this machine.
+
+use serde::{Deserialize, Serialize};
+
+/// Destinations a project can list besides the Port parameter's
+pub const MAX_DESTINATIONS: usize = 4;
+
+/// Another Suite to stream to
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Destination {
+    pub host: String,
+    pub port: u16,
+    /// Listed but not streamed to when off
+    #[serde(default = "enabled")]
+    pub enabled: bool,
+}
+
+fn enabled() -> bool {
+    true
+}
+
+/// `destinations` as the plugin keeps them: hosts trimmed, without the ones
+/// with no host or port 0, the second of the same host and port, and any
+/// beyond `MAX_DESTINATIONS`
+pub fn clean(destinations: &[Destination]) -> Vec<Destination> {
+    let mut kept: Vec<Destination> = Vec::with_capacity(MAX_DESTINATIONS);
+    for destination in destinations {
+        let host = destination.host.trim();
+        if host.is_empty() || destination.port == 0 {
+            continue;
+        }
+        if kept.iter().any(|other| other.host == host && other.port == destination.port) {
+            continue;
+        }
+        if kept.len() == MAX_DESTINATIONS {
+            break;
+        }
+        kept.push(Destination { host: host.to_string(), ..destination.clone() });
+    }
+    kept
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn destination(host: &str, port: u16) -> Destination {
+        Destination { host: host.to_string(), port, enabled: true }
+    }
+
+    #[test]
+    fn test_clean() {
+        let listed = vec![
+            destination(" studio.local ", 9847),
+            destination("", 9847),
+            destination("studio.local", 0),
+            destination("studio.local", 9847),
+            Destination { enabled: false, ..destination("10.0.0.7", 9848) },
+        ];
+        assert_eq!(
+            clean(&listed),
+            vec![destination("studio.local", 9847), Destination { enabled: false, ..destination("10.0.0.7", 9848) }]
+        );
+
+        let many: Vec<Destination> = (1..=6).map(|port| destination("studio.local", port)).collect();
+        assert_eq!(clean(&many), many[..MAX_DESTINATIONS]);
+    }
+
+    #[test]
+    fn test_enabled_unless_saved_off() {
+        let loaded: Vec<Destination> = serde_json::from_str(r#"[{"host":"studio.local","port":9847}]"#).unwrap();
+        assert_eq!(loaded, vec![destination("studio.local", 9847)]);
+    }
+}
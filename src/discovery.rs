@@ -0,0 +1,192 @@
+//! Finding the Suite over mDNS instead of at the Port parameter
+//!
+//! The Port parameter is the setting users get wrong most. With
+//! `"discover_suite": true` in the machine config (see `config`), builds
+//! with the `mdns` feature browse for the Suite's `_hardwave._tcp.local.`
+//! service before each connection and connect to the host and port it
+//! advertises. The connection reads `Discovering` meanwhile. No answer
+//! within `BROWSE_TIMEOUT` (3 s) and the plugin connects to the Port
+//! parameter on this machine, as without discovery. A lost or failed
+//! connection browses again, so a Suite that moved is found where it went.
+//!
+//! A `"suite_host"` in the machine config wins over browsing: the plugin
+//! connects to that host at the Port parameter. A build without the feature
+//! that is asked to discover records `TransportError::Discovery` once and
+//! connects as if it wasn't. A connection to a Suite on another host is
+//! never the shared one, see `multiplex`.
+
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+
+use crate::config::MachineConfig;
+use crate::error::TransportError;
+
+/// The service the Suite advertises
+pub const SERVICE_TYPE: &str = "_hardwave._tcp.local.";
+
+/// How long browsing waits for the Suite before falling back to the Port
+/// parameter
+pub const BROWSE_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Where the Suite runs unless discovery or the config says otherwise
+pub const LOCAL_HOST: &str = "127.0.0.1";
+
+/// How the connection finds the Suite, from the machine config
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Discovery {
+    /// On this machine, at the Port parameter
+    #[default]
+    Local,
+    /// On this host, at the Port parameter
+    Host(String),
+    /// Wherever it advertises itself, see `browse`
+    Browse,
+    /// Browsing, answered at once with this address rather than over the
+    /// network, for tests
+    #[cfg(test)]
+    Advertised(SuiteAddress),
+}
+
+impl Discovery {
+    /// The host set wins over browsing. A build without the `mdns` feature
+    /// never browses, and says so.
+    pub fn from_config(config: &MachineConfig) -> (Self, Option<TransportError>) {
+        match config.suite_host.as_deref().map(str::trim) {
+            Some(host) if !host.is_empty() => (Discovery::Host(host.to_string()), None),
+            _ if config.discover_suite && cfg!(feature = "mdns") => (Discovery::Browse, None),
+            _ if config.discover_suite => {
+                (Discovery::Local, Some(TransportError::Discovery("this build has no mDNS".to_string())))
+            }
+            _ => (Discovery::Local, None),
+        }
+    }
+}
+
+/// A host and port to connect to the Suite at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SuiteAddress {
+    pub host: String,
+    pub port: u16,
+}
+
+impl SuiteAddress {
+    /// The Suite on this machine at `port`
+    pub fn local(port: u16) -> Self {
+        Self { host: LOCAL_HOST.to_string(), port }
+    }
+
+    pub fn is_local(&self) -> bool {
+        self.host == LOCAL_HOST
+    }
+
+    /// The socket address to connect to. A name is looked up afresh every
+    /// time, so one that moved is followed.
+    pub fn resolve(&self) -> Result<SocketAddr, TransportError> {
+        (self.host.as_str(), self.port)
+            .to_socket_addrs()
+            .map_err(TransportError::Connect)?
+            .next()
+            .ok_or_else(|| TransportError::Connect(io::Error::new(io::ErrorKind::NotFound, "no address")))
+    }
+}
+
+/// `host:port`, for the handshake's `Host` header; IPv6 addresses in
+/// brackets
+impl fmt::Display for SuiteAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.host.contains(':') {
+            write!(f, "[{}]:{}", self.host, self.port)
+        } else {
+            write!(f, "{}:{}", self.host, self.port)
+        }
+    }
+}
+
+/// Browse for `service_type` until one resolves, `timeout` passes or
+/// `shutdown` is set. The first address of the first Suite to answer, IPv4
+/// where it has one.
+#[cfg(feature = "mdns")]
+pub fn browse(
+    service_type: &str,
+    timeout: Duration,
+    shutdown: &AtomicBool,
+) -> Result<Option<SuiteAddress>, TransportError> {
+    use mdns_sd::{ServiceDaemon, ServiceEvent};
+    use std::sync::atomic::Ordering;
+    use std::time::Instant;
+
+    // Short enough for dropping the client not to wait on it
+    const POLL: Duration = Duration::from_millis(100);
+
+    let failed = |e: mdns_sd::Error| TransportError::Discovery(e.to_string());
+    let daemon = ServiceDaemon::new().map_err(failed)?;
+    let events = daemon.browse(service_type).map_err(failed)?;
+    let deadline = Instant::now() + timeout;
+    let mut found = None;
+    while found.is_none() && !shutdown.load(Ordering::Relaxed) {
+        let Some(left) = deadline.checked_duration_since(Instant::now()).filter(|left| !left.is_zero()) else {
+            break;
+        };
+        match events.recv_timeout(left.min(POLL)) {
+            Ok(ServiceEvent::ServiceResolved(info)) => {
+                let addresses = info.get_addresses();
+                let ip = addresses.iter().find(|ip| ip.is_ipv4()).or_else(|| addresses.iter().next());
+                found = ip.map(|ip| SuiteAddress { host: ip.to_string(), port: info.get_port() });
+            }
+            Err(_) if events.is_disconnected() => break,
+            Ok(_) | Err(_) => {}
+        }
+    }
+    // Its thread goes on its own; nothing waits for it
+    let _ = daemon.shutdown();
+    Ok(found)
+}
+
+/// Without the feature nothing browses; `Discovery::from_config` never
+/// asks to
+#[cfg(not(feature = "mdns"))]
+pub fn browse(
+    _service_type: &str,
+    _timeout: Duration,
+    _shutdown: &AtomicBool,
+) -> Result<Option<SuiteAddress>, TransportError> {
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_the_host_set_wins() {
+        let config = |discover_suite, suite_host: Option<&str>| MachineConfig {
+            discover_suite,
+            suite_host: suite_host.map(str::to_string),
+            ..MachineConfig::default()
+        };
+        let studio = Discovery::Host("studio.local".to_string());
+        assert_eq!(Discovery::from_config(&config(false, None)).0, Discovery::Local);
+        assert_eq!(Discovery::from_config(&config(false, Some("studio.local"))).0, studio);
+        assert_eq!(Discovery::from_config(&config(true, Some(" studio.local "))).0, studio);
+
+        let (discovery, error) = Discovery::from_config(&config(true, Some("")));
+        if cfg!(feature = "mdns") {
+            assert_eq!((discovery, error.is_none()), (Discovery::Browse, true));
+        } else {
+            assert_eq!(discovery, Discovery::Local);
+            assert!(matches!(error, Some(TransportError::Discovery(_))));
+        }
+    }
+
+    #[test]
+    fn test_address_display() {
+        assert_eq!(SuiteAddress::local(9847).to_string(), "127.0.0.1:9847");
+        assert!(SuiteAddress::local(9847).is_local());
+        let remote = SuiteAddress { host: "fe80::1".to_string(), port: 9847 };
+        assert_eq!(remote.to_string(), "[fe80::1]:9847");
+        assert!(!remote.is_local());
+    }
+}
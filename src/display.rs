@@ -0,0 +1,231 @@
+//! Per-instance display hints for the Suite
+//!
+//! Each instance carries a label, a colour and a display order that the Suite
+//! uses to tell streams apart. All three are saved with the project. Label
+//! and colour default to the host's track name and colour where the host
+//! reports them (see `track_info`). Otherwise an instance nobody has
+//! configured gets a colour derived from its instance ID and a label ending
+//! in the start of it, "Hardwave Analyser #3f2b8c1e", so several fresh
+//! instances still look different, and the same instance looks the same on
+//! every machine the project is opened on.
+//!
+//! Every packet the instance sends carries `instance_tag`, 32 bits of a
+//! hash of its ID, so a Suite can tell interleaved streams apart without
+//! the ID itself in every frame; the hello and heartbeats carry the ID the
+//! tag stands for. 32 bits read exactly as a JavaScript number, and a few
+//! dozen instances share one by chance about once in ten million.
+
+use serde::Serialize;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Highest display order; lower values sort first
+pub const MAX_DISPLAY_ORDER: u8 = 99;
+
+/// Longest label, in characters
+pub const MAX_LABEL_CHARS: usize = 64;
+
+/// Saturation and lightness of derived colours, chosen to read on both the
+/// dark and light Suite themes
+const DEFAULT_SATURATION: f32 = 0.65;
+const DEFAULT_LIGHTNESS: f32 = 0.55;
+
+/// What the Suite is told about how to show this instance
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct DisplayHints {
+    pub instance_id: String,
+    /// `default_label` if neither the user nor the host named the instance
+    pub label: String,
+    /// `#rrggbb`
+    pub color: String,
+    pub order: u8,
+    /// The user's names for the two analysis streams; `None` while they
+    /// follow the channel mode
+    pub channel_labels: Option<[String; 2]>,
+}
+
+/// A fresh random instance ID in UUID v4 form
+pub fn new_instance_id() -> String {
+    // std's RandomState is seeded randomly per process and differs per call
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    let word = |salt: u64| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u128(nanos);
+        hasher.write_u64(salt);
+        hasher.finish()
+    };
+    // Version 4 and the RFC 4122 variant
+    let high = (word(1) & !0xf000) | 0x4000;
+    let low = (word(2) & !(0xc << 60)) | (0x8 << 60);
+    format!(
+        "{:08x}-{:04x}-{:04x}-{:04x}-{:012x}",
+        high >> 32,
+        (high >> 16) & 0xffff,
+        high & 0xffff,
+        low >> 48,
+        low & 0xffff_ffff_ffff
+    )
+}
+
+/// Parse a user colour: `#rrggbb` or `#rgb`, the `#` optional, any case.
+/// Returns it normalised to lower-case `#rrggbb`, or `None` if it isn't one.
+pub fn parse_color(input: &str) -> Option<String> {
+    let hex = input.trim();
+    let hex = hex.strip_prefix('#').unwrap_or(hex);
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let hex = hex.to_ascii_lowercase();
+    match hex.len() {
+        6 => Some(format!("#{}", hex)),
+        3 => Some(hex.chars().fold(String::from("#"), |mut out, c| {
+            out.push(c);
+            out.push(c);
+            out
+        })),
+        _ => None,
+    }
+}
+
+/// Clean up a label: surrounding whitespace and control characters removed,
+/// cut to `MAX_LABEL_CHARS`. `None` if nothing is left.
+pub fn clean_label(input: &str) -> Option<String> {
+    let label: String = input
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_LABEL_CHARS)
+        .collect();
+    let label = label.trim_end();
+    (!label.is_empty()).then(|| label.to_string())
+}
+
+/// FNV-1a of an instance ID, rather than std's hashers, which may change
+/// between Rust releases
+fn id_hash(instance_id: &str) -> u64 {
+    instance_id.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Colour for an instance that has none set: a hue from a hash of its ID.
+pub fn default_color(instance_id: &str) -> String {
+    let hash = id_hash(instance_id);
+    let hue = (hash % 360) as f32;
+    let (r, g, b) = hsl_to_rgb(hue, DEFAULT_SATURATION, DEFAULT_LIGHTNESS);
+    format!("#{:02x}{:02x}{:02x}", r, g, b)
+}
+
+/// Label for an instance nobody named: the product and the first group of
+/// its ID
+pub fn default_label(instance_id: &str) -> String {
+    let short: String = instance_id.chars().take(8).collect();
+    format!("Hardwave Analyser #{}", short)
+}
+
+/// The `instance_tag` of the instance with ID `instance_id`: both halves of
+/// its hash folded together
+pub fn instance_tag(instance_id: &str) -> u32 {
+    let hash = id_hash(instance_id);
+    (hash ^ (hash >> 32)) as u32
+}
+
+/// `hue` in degrees, `saturation` and `lightness` in 0..=1
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> (u8, u8, u8) {
+    let chroma = (1.0 - (2.0 * lightness - 1.0).abs()) * saturation;
+    let sector = hue / 60.0;
+    let x = chroma * (1.0 - (sector % 2.0 - 1.0).abs());
+    let (r, g, b) = match sector as u32 {
+        0 => (chroma, x, 0.0),
+        1 => (x, chroma, 0.0),
+        2 => (0.0, chroma, x),
+        3 => (0.0, x, chroma),
+        4 => (x, 0.0, chroma),
+        _ => (chroma, 0.0, x),
+    };
+    let m = lightness - chroma / 2.0;
+    let channel = |v: f32| ((v + m) * 255.0).round().clamp(0.0, 255.0) as u8;
+    (channel(r), channel(g), channel(b))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_color() {
+        assert_eq!(parse_color("#FF8800").as_deref(), Some("#ff8800"));
+        assert_eq!(parse_color("  12abEF \n").as_deref(), Some("#12abef"));
+        assert_eq!(parse_color("#f80").as_deref(), Some("#ff8800"));
+
+        for bad in ["", "#", "#ff88", "#ff88001", "#gg0000", "red", "#ff 800", "rgb(1,2,3)"] {
+            assert_eq!(parse_color(bad), None, "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_clean_label() {
+        assert_eq!(clean_label("  Kick \n").as_deref(), Some("Kick"));
+        assert_eq!(clean_label("Bass\u{0}\u{1b}[31m").as_deref(), Some("Bass[31m"));
+        assert_eq!(clean_label(" \t "), None);
+        let long = "é".repeat(100);
+        assert_eq!(clean_label(&long).unwrap().chars().count(), MAX_LABEL_CHARS);
+    }
+
+    #[test]
+    fn test_default_color_is_stable_per_instance() {
+        let id = "3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34";
+        assert_eq!(default_color(id), default_color(id));
+        assert_eq!(parse_color(&default_color(id)), Some(default_color(id)));
+
+        // FNV-1a of the ID picks the hue, so this never changes between builds
+        assert_eq!(default_color(""), "#add742");
+
+        // Fresh instances spread over the colour wheel
+        let colors: std::collections::HashSet<String> =
+            (0..20).map(|_| default_color(&new_instance_id())).collect();
+        assert!(colors.len() >= 15, "only {} distinct colours", colors.len());
+    }
+
+    #[test]
+    fn test_default_label() {
+        assert_eq!(default_label("3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34"), "Hardwave Analyser #3f2b8c1e");
+        // A hand-edited ID is cut by characters, not bytes
+        assert_eq!(default_label("éééééééééé"), "Hardwave Analyser #éééééééé");
+        assert_eq!(clean_label(&default_label(&new_instance_id())).map(|label| label.len()), Some(27));
+    }
+
+    #[test]
+    fn test_instance_id_format() {
+        let id = new_instance_id();
+        let groups: Vec<&str> = id.split('-').collect();
+        assert_eq!(groups.iter().map(|g| g.len()).collect::<Vec<_>>(), vec![8, 4, 4, 4, 12]);
+        assert!(groups[2].starts_with('4'));
+        assert!(matches!(groups[3].as_bytes()[0], b'8' | b'9' | b'a' | b'b'));
+        assert_ne!(new_instance_id(), id);
+    }
+
+    #[test]
+    fn test_instance_tag_is_stable_per_instance() {
+        // Like the colour, the same on every build and machine
+        assert_eq!(instance_tag("3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34"), 0x8577_0bfd);
+        assert_eq!(instance_tag(""), 0x4fd0_bfc1);
+
+        let tags: std::collections::HashSet<u32> = (0..100).map(|_| instance_tag(&new_instance_id())).collect();
+        assert_eq!(tags.len(), 100);
+    }
+
+    #[test]
+    fn test_hsl_to_rgb() {
+        assert_eq!(hsl_to_rgb(0.0, 1.0, 0.5), (255, 0, 0));
+        assert_eq!(hsl_to_rgb(120.0, 1.0, 0.5), (0, 255, 0));
+        assert_eq!(hsl_to_rgb(240.0, 1.0, 0.5), (0, 0, 255));
+        assert_eq!(hsl_to_rgb(200.0, 0.0, 0.5), (128, 128, 128));
+    }
+}
@@ -6,42 +6,66 @@
 //! threading restriction on ICoreWebView2::ExecuteScript and the wry
 //! custom-protocol interception issues in wry 0.46.
 
-use crossbeam_channel::Receiver;
+use atomic_float::AtomicF32;
+#[cfg(any(target_os = "windows", test))]
+use crossbeam_channel::{Receiver, TryRecvError};
 use nih_plug::prelude::*;
 use parking_lot::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+#[cfg(any(target_os = "windows", test))]
+use std::sync::Weak;
 use std::thread;
 use std::time::Duration;
 use wry::raw_window_handle as rwh06;
 
-use crate::auth;
-use crate::protocol::AudioPacket;
+use crate::auth::{self, AccountToken};
+use crate::bus::{Overflow, PacketBus, Route, Subscription};
+use crate::clicks::ClickStats;
+use crate::destinations::Destination;
+use crate::display::DisplayHints;
+#[cfg(any(target_os = "windows", test))]
+use crate::display;
+use crate::editor_payload::{EditorFeed, UpdateGate};
+use crate::error::{EditorError, ErrorStats};
+#[cfg(any(target_os = "windows", test))]
+use crate::error::{HardwaveError, TransportError};
+use crate::history::{HistoryCommand, HistoryControl};
+use crate::memory::{Buffer, MemoryGauges};
+use crate::onboarding::{self, Onboarding, SetupEffects, SetupState};
+use crate::packet_crypto::{self, PacketEncryption, PacketKey};
+use crate::panics::{self, Region};
+use crate::webview_commands::{self, WebViewCommand};
+use crate::webview_dir;
+use crate::params::HardwaveAnalyserParams;
+use crate::ports::{self, PortWarning};
+use crate::presets::{self, Preset};
+use crate::protocol::{AudioPacket, PACKET_TYPE_FFT, PACKET_TYPE_WAVEFORM};
+#[cfg(any(target_os = "windows", test))]
+use crate::resume::ResumeDetector;
+use crate::shared::EditorLifecycle;
+use crate::slots;
+use crate::spectrogram::SpectrogramHistory;
+use crate::suite_commands::SuiteCommands;
+use crate::theme::{self, HardwaveEnv};
+use crate::threads::{self, Priority};
+use crate::tls::TlsControl;
+use crate::watchdog::ProcessWatchdog;
+use crate::websocket::{ConnectionState, ConnectionStats, ConnectionStatus, Destinations, PACKET_QUEUE_LEN};
 
 /// Write a debug line to %TEMP%\hardwave-debug.log (Windows) or /tmp/hardwave-debug.log.
 #[allow(unused)]
 fn debug_log(msg: &str) {
-    use std::io::Write;
-    let path = {
-        let mut p = std::env::temp_dir();
-        p.push("hardwave-debug.log");
-        p
-    };
-    if let Ok(mut f) = std::fs::OpenOptions::new().create(true).append(true).open(&path) {
-        let now = std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_secs();
-        let _ = writeln!(f, "[{}] {}", now, msg);
-    }
+    crate::runtime::debug_log(msg);
 }
 
 // ---------------------------------------------------------------------------
 // WebView2 auto-install (Windows only)
 // ---------------------------------------------------------------------------
 
+/// Install the WebView2 runtime if neither the machine nor the user has it.
 #[cfg(target_os = "windows")]
-fn ensure_webview2() {
+fn ensure_webview2() -> Result<(), HardwaveError> {
     use std::process::Command;
 
     let installed = Command::new("reg")
@@ -54,7 +78,7 @@ fn ensure_webview2() {
         .map(|o| o.status.success())
         .unwrap_or(false);
 
-    if installed { return; }
+    if installed { return Ok(()); }
 
     let installed_user = Command::new("reg")
         .args([
@@ -66,7 +90,7 @@ fn ensure_webview2() {
         .map(|o| o.status.success())
         .unwrap_or(false);
 
-    if installed_user { return; }
+    if installed_user { return Ok(()); }
 
     nih_log!("WebView2 Runtime not found — downloading bootstrapper...");
 
@@ -84,17 +108,24 @@ fn ensure_webview2() {
         ])
         .output();
 
+    let exit_status = |status: std::process::ExitStatus| {
+        std::io::Error::other(format!("exit status {}", status))
+    };
     match download {
-        Ok(output) if output.status.success() => {
-            nih_log!("Installing WebView2 Runtime silently...");
-            let _ = Command::new(&bootstrapper_path)
-                .args(["/silent", "/install"])
-                .output();
-            let _ = std::fs::remove_file(&bootstrapper_path);
-        }
-        _ => {
-            nih_log!("Failed to download WebView2 bootstrapper");
-        }
+        Ok(output) if output.status.success() => {}
+        Ok(output) => return Err(EditorError::WebView2Download(exit_status(output.status)).into()),
+        Err(e) => return Err(EditorError::WebView2Download(e).into()),
+    }
+
+    nih_log!("Installing WebView2 Runtime silently...");
+    let install = Command::new(&bootstrapper_path)
+        .args(["/silent", "/install"])
+        .output();
+    let _ = std::fs::remove_file(&bootstrapper_path);
+    match install {
+        Ok(output) if output.status.success() => Ok(()),
+        Ok(output) => Err(EditorError::WebView2Install(exit_status(output.status)).into()),
+        Err(e) => Err(EditorError::WebView2Install(e).into()),
     }
 }
 
@@ -102,6 +133,9 @@ fn ensure_webview2() {
 const EDITOR_WIDTH: u32 = 1100;
 const EDITOR_HEIGHT: u32 = 700;
 
+/// How often the OS theme is re-read while the editor is open.
+const THEME_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 /// Base URL for the analyser page.
 const ANALYSER_URL: &str = "https://hardwavestudios.com/vst/analyser";
 
@@ -181,114 +215,1014 @@ enum ParentData {
 
 unsafe impl Send for ParentData {}
 
-/// Wrapper to make wry::WebView sendable across threads.
-/// SAFETY: On Windows, we create the webview on the DAW's UI thread and only
-/// access it from a background thread for evaluate_script calls, which WebView2
-/// marshals to the UI thread internally.
-struct SendWebView(wry::WebView);
-unsafe impl Send for SendWebView {}
+/// A Windows webview and its context, kept on the host's UI thread that
+/// created them
+#[cfg(target_os = "windows")]
+struct OwnedWebView {
+    id: u64,
+    webview: wry::WebView,
+    /// Must outlive the webview, so it is declared (and dropped) after it
+    _web_context: wry::WebContext,
+}
+
+#[cfg(target_os = "windows")]
+thread_local! {
+    /// Webviews created on this thread. They aren't Send, so the
+    /// `EditorHandle` only holds an `OwnedWebViewId` for its entry.
+    static WEBVIEWS: std::cell::RefCell<Vec<OwnedWebView>> = const { std::cell::RefCell::new(Vec::new()) };
+}
+
+/// Which `WEBVIEWS` entry belongs to an editor, and on which thread
+#[cfg(target_os = "windows")]
+struct OwnedWebViewId {
+    id: u64,
+    owner: thread::ThreadId,
+}
+
+#[cfg(target_os = "windows")]
+impl OwnedWebViewId {
+    /// Store a freshly built webview on the current thread
+    fn store(webview: wry::WebView, web_context: wry::WebContext) -> Self {
+        static NEXT_ID: std::sync::atomic::AtomicU64 = std::sync::atomic::AtomicU64::new(0);
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        WEBVIEWS.with(|webviews| {
+            webviews.borrow_mut().push(OwnedWebView {
+                id,
+                webview,
+                _web_context: web_context,
+            })
+        });
+        Self {
+            id,
+            owner: thread::current().id(),
+        }
+    }
+
+    /// Run `f` with the webview, if it is still open and this is its thread.
+    /// Skipped while the list is being changed, e.g. a webview being
+    /// destroyed dispatching one last IPC message.
+    fn with(id: u64, f: impl FnOnce(&wry::WebView)) {
+        WEBVIEWS.with(|webviews| {
+            if let Ok(webviews) = webviews.try_borrow() {
+                if let Some(entry) = webviews.iter().find(|entry| entry.id == id) {
+                    f(&entry.webview);
+                }
+            }
+        });
+    }
+
+    /// Destroy the webview. Hosts close editors on the thread that opened
+    /// them; anywhere else the webview can't be touched and is left for the
+    /// UI thread to clean up when it exits.
+    fn close(self) {
+        if thread::current().id() != self.owner {
+            debug_log("Editor closed off the UI thread; leaving the webview to it");
+            return;
+        }
+        // Taken out first so the RefCell isn't borrowed while it drops
+        let entry = WEBVIEWS.with(|webviews| {
+            let mut webviews = webviews.borrow_mut();
+            let index = webviews.iter().position(|entry| entry.id == self.id)?;
+            Some(webviews.swap_remove(index))
+        });
+        drop(entry);
+    }
+}
+
+/// What the editor shares with the plugin and the connection thread
+#[derive(Clone)]
+pub struct EditorShared {
+    /// Frames are subscribed to while the window is open
+    pub bus: Arc<PacketBus>,
+    /// Shared with the connection thread, which reconnects when it is saved
+    /// or cleared
+    pub auth_token: Arc<AccountToken>,
+    pub lifecycle: Arc<EditorLifecycle>,
+    pub recording: Arc<AtomicBool>,
+    /// Display hints the connection thread sends to the Suite
+    pub display: Arc<Mutex<DisplayHints>>,
+    /// Per-buffer memory use, reported on request
+    pub memory: Arc<MemoryGauges>,
+    /// Errors of this instance, added to by the editor and reported on
+    /// request
+    pub errors: Arc<ErrorStats>,
+    /// Clicks in the input, reported on request
+    pub clicks: Arc<ClickStats>,
+    /// When the host last processed audio, for the status line
+    pub watchdog: Arc<ProcessWatchdog>,
+    /// Commands relayed to the Suite, and its answers
+    pub suite_commands: Arc<SuiteCommands>,
+    /// The key packets to the Suite are encrypted with
+    pub encryption: Arc<PacketEncryption>,
+    /// The connection to the Suite, for the `status` of editor payloads
+    pub connection: ConnectionStats,
+    /// The first-run flag and reconnect requests, for the setup state
+    pub onboarding: Arc<Onboarding>,
+    /// TLS to the Suite, and the certificate the user may accept
+    pub tls: Arc<TlsControl>,
+    /// Connections to the Suites besides the Port parameter's
+    pub destinations: Arc<Destinations>,
+    /// Freeze and scrub requests for the plugin's frame history
+    pub history: Arc<HistoryControl>,
+}
 
 pub struct HardwaveAnalyserEditor {
-    packet_rx: Receiver<AudioPacket>,
-    auth_token: Arc<Mutex<Option<String>>>,
+    shared: EditorShared,
+    params: Arc<HardwaveAnalyserParams>,
+    /// Recent spectrum, filled while the window is open
+    spectrogram: Arc<Mutex<SpectrogramHistory>>,
+    /// Host UI scale, from `set_scale_factor`
+    scale: Arc<AtomicF32>,
     size: (u32, u32),
 }
 
 impl HardwaveAnalyserEditor {
-    pub fn new(packet_rx: Receiver<AudioPacket>) -> Self {
-        let token = auth::load_token();
+    pub fn new(params: Arc<HardwaveAnalyserParams>, shared: EditorShared) -> Self {
+        if let Err(e) = shared.auth_token.current() {
+            debug_log(&format!("load_token failed: {}", e));
+        }
+        let spectrogram = SpectrogramHistory::new();
+        shared.memory.set(Buffer::Spectrogram, spectrogram.heap_bytes());
         Self {
-            packet_rx,
-            auth_token: Arc::new(Mutex::new(token)),
+            shared,
+            params,
+            spectrogram: Arc::new(Mutex::new(spectrogram)),
+            scale: Arc::new(AtomicF32::new(1.0)),
             size: (EDITOR_WIDTH, EDITOR_HEIGHT),
         }
     }
 
     fn build_url(&self) -> String {
-        let token = self.auth_token.lock();
-        match token.as_deref() {
-            Some(t) => format!("{}?token={}", ANALYSER_URL, t),
-            None => ANALYSER_URL.to_string(),
+        analyser_url(self.shared.auth_token.cached().as_deref())
+    }
+}
+
+/// URL for the analyser page, with the token when logged in.
+fn analyser_url(token: Option<&str>) -> String {
+    match token {
+        Some(t) => format!("{}?token={}", ANALYSER_URL, t),
+        None => ANALYSER_URL.to_string(),
+    }
+}
+
+/// Decide whether the webview has to navigate for the current token. Returns
+/// the new URL when it differs from the one already loaded.
+fn navigation_target(loaded_url: &str, token: Option<&str>) -> Option<String> {
+    let wanted = analyser_url(token);
+    (wanted != loaded_url).then_some(wanted)
+}
+
+/// Pick up a token written to disk by another instance or a login elsewhere.
+/// One the editor couldn't save stays, and so does the token when the file
+/// can't be read.
+fn refresh_token_from_disk(auth_token: &AccountToken) {
+    let _ = auth_token.current();
+}
+
+/// JS that publishes the current channel labels to the page.
+fn channel_labels_script(params: &HardwaveAnalyserParams) -> String {
+    let labels = serde_json::to_string(&params.channel_labels()).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "window.__hardwaveChannelLabels = {0}; \
+         window.__hardwave && window.__hardwave.onChannelLabels && window.__hardwave.onChannelLabels({0})",
+        labels
+    )
+}
+
+/// Webview bounds for the default editor size at the host's UI scale.
+#[cfg(not(target_os = "windows"))]
+fn editor_bounds(scale: f32) -> wry::Rect {
+    let scale = scale as f64;
+    wry::Rect {
+        position: wry::dpi::LogicalPosition::new(0, 0).into(),
+        size: wry::dpi::PhysicalSize::new(
+            (EDITOR_WIDTH as f64 * scale).round(),
+            (EDITOR_HEIGHT as f64 * scale).round(),
+        )
+        .into(),
+    }
+}
+
+/// JS that publishes the theme and UI scale to the page.
+fn env_script(env: &HardwaveEnv) -> String {
+    let json = serde_json::to_string(env).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "window.__hardwaveEnv = {0}; \
+         window.__hardwave && window.__hardwave.onEnv && window.__hardwave.onEnv({0})",
+        json
+    )
+}
+
+/// JS that updates the env hints. Returns `None` when they haven't changed
+/// since `last`.
+fn env_update(env: HardwaveEnv, last: &mut HardwaveEnv) -> Option<String> {
+    if *last == env {
+        return None;
+    }
+    *last = env;
+    Some(env_script(&env))
+}
+
+/// JS that shows or clears the port warning banner. Returns `None` when the
+/// warning hasn't changed since `last`.
+fn port_warning_update(
+    params: &HardwaveAnalyserParams,
+    last: &mut Option<Option<PortWarning>>,
+) -> Option<String> {
+    let port = params.network.port.value();
+    let warning = ports::check(port);
+    if *last == Some(warning) {
+        return None;
+    }
+    *last = Some(warning);
+    let text = warning.map(|w| format!("Port {}: {}", port, w));
+    let json = serde_json::to_string(&text).unwrap_or_else(|_| "null".to_string());
+    Some(format!(
+        "window.__hardwavePortWarning = {0}; \
+         window.__hardwave && window.__hardwave.onPortWarning && window.__hardwave.onPortWarning({0})",
+        json
+    ))
+}
+
+/// JS that shows or clears the "no audio from host" status. Returns `None`
+/// when the host hasn't started or stopped processing since `last`.
+fn host_audio_update(stale_ms: u32, last: &mut Option<bool>) -> Option<String> {
+    let stale = stale_ms > 0;
+    if *last == Some(stale) {
+        return None;
+    }
+    *last = Some(stale);
+    let text = stale.then_some("no audio from host");
+    let json = serde_json::to_string(&text).unwrap_or_else(|_| "null".to_string());
+    Some(format!(
+        "window.__hardwaveHostAudio = {0}; \
+         window.__hardwave && window.__hardwave.onHostAudio && window.__hardwave.onHostAudio({0})",
+        json
+    ))
+}
+
+/// JS that tells the page whether eco mode is on. Returns `None` when it
+/// hasn't changed since `last`.
+fn eco_mode_update(params: &HardwaveAnalyserParams, last: &mut Option<bool>) -> Option<String> {
+    let on = params.analysis.eco_mode.value();
+    if *last == Some(on) {
+        return None;
+    }
+    *last = Some(on);
+    Some(format!(
+        "window.__hardwaveEcoMode = {0}; \
+         window.__hardwave && window.__hardwave.onEcoMode && window.__hardwave.onEcoMode({0})",
+        on
+    ))
+}
+
+/// What the setup state is put together from, besides the account token
+struct SetupSources {
+    onboarding: Arc<Onboarding>,
+    tls: Arc<TlsControl>,
+    destinations: Arc<Destinations>,
+    connection: ConnectionStats,
+    /// Whether the window gets packets from the plugin
+    transport_ok: bool,
+}
+
+impl SetupSources {
+    fn state(&self, auth_token: &AccountToken) -> SetupState {
+        SetupState::compose(
+            self.connection.status().connection == ConnectionState::Connected,
+            auth_token.cached().is_some(),
+            self.transport_ok,
+            self.onboarding.first_run(),
+        )
+    }
+}
+
+/// JS that publishes the setup state to the page, and why the last setup
+/// action failed, if it did.
+fn setup_state_script(state: &SetupState, error: Option<&str>) -> String {
+    let mut json = serde_json::to_value(state).unwrap_or(serde_json::Value::Null);
+    if let Some(fields) = json.as_object_mut() {
+        fields.insert("error".to_string(), serde_json::json!(error));
+    }
+    format!(
+        "window.__hardwaveSetup = {0}; \
+         window.__hardwave && window.__hardwave.onSetupState && window.__hardwave.onSetupState({0})",
+        json
+    )
+}
+
+/// JS that updates the setup state. Returns `None` when it hasn't changed
+/// since `last`.
+fn setup_update(state: SetupState, last: &mut Option<SetupState>) -> Option<String> {
+    if *last == Some(state) {
+        return None;
+    }
+    *last = Some(state);
+    Some(setup_state_script(&state, None))
+}
+
+/// The setup actions as the editor carries them out
+struct EditorSetupEffects<'a> {
+    onboarding: &'a Onboarding,
+    params: &'a HardwaveAnalyserParams,
+    context: &'a dyn GuiContext,
+}
+
+impl SetupEffects for EditorSetupEffects<'_> {
+    fn retry_connect(&self) {
+        self.onboarding.request_retry();
+    }
+
+    fn open_external(&self, url: &str) -> std::io::Result<()> {
+        onboarding::open_external(url)
+    }
+
+    /// One automation/undo step, like a preset
+    fn set_port(&self, port: u16) {
+        let setter = ParamSetter::new(self.context);
+        setter.begin_set_parameter(&self.params.network.port);
+        setter.set_parameter(&self.params.network.port, i32::from(port));
+        setter.end_set_parameter(&self.params.network.port);
+    }
+}
+
+/// Handle an IPC message from the page. Returns a script to evaluate in reply,
+/// if any. A panic while handling it answers nothing and is counted in
+/// `errors`, rather than unwinding into the host.
+#[allow(clippy::too_many_arguments)]
+fn handle_ipc(
+    msg: &str,
+    auth_token: &AccountToken,
+    params: &HardwaveAnalyserParams,
+    context: &dyn GuiContext,
+    recording: &AtomicBool,
+    display: &Mutex<DisplayHints>,
+    memory: &MemoryGauges,
+    errors: &ErrorStats,
+    clicks: &ClickStats,
+    suite_commands: &SuiteCommands,
+    encryption: &PacketEncryption,
+    spectrogram: &Mutex<SpectrogramHistory>,
+    history: &HistoryControl,
+    setup: &SetupSources,
+) -> Option<String> {
+    reply_contained(errors, || {
+        dispatch_ipc(
+            msg, auth_token, params, context, recording, display, memory, errors, clicks, suite_commands, encryption,
+            spectrogram, history, setup,
+        )
+    })
+}
+
+/// The reply `dispatch` makes, or none if it panics
+fn reply_contained(errors: &ErrorStats, dispatch: impl FnOnce() -> Option<String>) -> Option<String> {
+    panics::contain(Region::Ipc, dispatch).unwrap_or_else(|panicked| {
+        errors.record(&panicked.into());
+        None
+    })
+}
+
+#[allow(clippy::too_many_arguments)]
+fn dispatch_ipc(
+    msg: &str,
+    auth_token: &AccountToken,
+    params: &HardwaveAnalyserParams,
+    context: &dyn GuiContext,
+    recording: &AtomicBool,
+    display: &Mutex<DisplayHints>,
+    memory: &MemoryGauges,
+    errors: &ErrorStats,
+    clicks: &ClickStats,
+    suite_commands: &SuiteCommands,
+    encryption: &PacketEncryption,
+    spectrogram: &Mutex<SpectrogramHistory>,
+    history: &HistoryControl,
+    setup: &SetupSources,
+) -> Option<String> {
+    if let Some(token) = msg.strip_prefix("saveToken:") {
+        // Used even if it can't be saved; the connection reconnects with
+        // it, a refused one included
+        let result = auth_token.save(token.trim().to_string());
+        setup.onboarding.request_retry();
+        match result {
+            Ok(path) => {
+                debug_log(&format!("Token saved to {}", path.display()));
+                None
+            }
+            Err(e) => {
+                debug_log(&format!("save_token failed: {}", e));
+                let script = token_error_script(&e);
+                errors.record(&e.into());
+                Some(script)
+            }
+        }
+    } else if let Some(json) = msg.strip_prefix("setChannelLabels:") {
+        // ["first", "second"]; two empty strings restore the mode defaults
+        match serde_json::from_str::<[String; 2]>(json) {
+            Ok([first, second]) => {
+                let mode = params.analysis.channel_mode.value();
+                if let Ok(mut labels) = params.channel_labels.write() {
+                    labels.set(&first, &second, mode);
+                }
+                *display.lock() = params.display_hints();
+            }
+            Err(e) => debug_log(&format!("setChannelLabels: bad payload: {}", e)),
+        }
+        Some(channel_labels_script(params))
+    } else if let Some(name) = msg.strip_prefix("applyPreset:") {
+        let dir = presets::user_dir();
+        match presets::find(dir.as_deref(), name) {
+            Ok(preset) => {
+                preset.apply(params, &ParamSetter::new(context));
+                *display.lock() = params.display_hints();
+                Some(channel_labels_script(params))
+            }
+            Err(e) => Some(preset_error_script(&e)),
+        }
+    } else if let Some(name) = msg.strip_prefix("savePreset:") {
+        // Overwrites an existing user preset; the page asks for confirmation
+        let dir = presets::user_dir();
+        match presets::save(dir.as_deref(), name, &Preset::capture(params)) {
+            Ok(_) => Some(presets_script()),
+            Err(e) => Some(preset_error_script(&e)),
+        }
+    } else if msg == "listPresets" {
+        Some(presets_script())
+    } else if let Some(state) = msg.strip_prefix("setRecording:") {
+        recording.store(state == "on", Ordering::Relaxed);
+        Some(recording_script(recording.load(Ordering::Relaxed)))
+    } else if let Some(state) = msg.strip_prefix("setMetricsOptIn:") {
+        // Metrics only ever go to the connected Suite, in heartbeats
+        params.metrics_opt_in.store(state == "on", Ordering::Relaxed);
+        Some(metrics_opt_in_script(params))
+    } else if let Some(color) = msg.strip_prefix("setDisplayColor:") {
+        // "#rrggbb" or "#rgb"; empty returns to the colour derived from the
+        // instance ID
+        if !params.set_display_color(color) {
+            debug_log(&format!("setDisplayColor: not a colour: {:?}", color));
+        }
+        *display.lock() = params.display_hints();
+        Some(display_hints_script(params))
+    } else if let Some(label) = msg.strip_prefix("setDisplayLabel:") {
+        // Empty returns to the host's track name, or the derived label
+        params.set_display_label(label);
+        *display.lock() = params.display_hints();
+        Some(display_hints_script(params))
+    } else if let Some(order) = msg.strip_prefix("setDisplayOrder:") {
+        match order.trim().parse::<i64>() {
+            Ok(order) => params.set_display_order(order),
+            Err(e) => debug_log(&format!("setDisplayOrder: bad payload: {}", e)),
+        }
+        *display.lock() = params.display_hints();
+        Some(display_hints_script(params))
+    } else if let Some(request) = msg.strip_prefix("copySlot:") {
+        // "A>B" copies slot A's settings into B; the parameters are untouched
+        match slots::parse_copy(request) {
+            Some((from, to)) => {
+                if let Ok(mut slots) = params.analysis_slots.write() {
+                    slots.copy(from, to, params.engine_settings());
+                }
+            }
+            None => debug_log(&format!("copySlot: bad payload: {:?}", request)),
         }
+        None
+    } else if let Some(json) = msg.strip_prefix("suiteCommand:") {
+        // {"command":"startCapture","args":{...}}; the Suite's answer
+        // follows later through onSuiteCommand
+        Some(match suite_commands.submit(json) {
+            Ok(seq) => suite_command_script(Some(seq), "queued", None),
+            Err(e) => {
+                debug_log(&format!("suiteCommand: {}", e));
+                suite_command_script(None, "rejected", Some(&e.to_string()))
+            }
+        })
+    } else if let Some(text) = msg.strip_prefix("setPacketKey:") {
+        // Hex or base64; a key that doesn't parse changes nothing
+        Some(match PacketKey::parse(text) {
+            Ok(key) => {
+                let saved = packet_crypto::save_key(&key);
+                encryption.set(Some(key));
+                match saved {
+                    Ok(_) => packet_key_script(encryption, None),
+                    Err(e) => {
+                        debug_log(&format!("save_key failed: {}", e));
+                        let script = packet_key_script(encryption, Some(&format!("in use, but not saved: {}", e)));
+                        errors.record(&e.into());
+                        script
+                    }
+                }
+            }
+            Err(e) => packet_key_script(encryption, Some(&e.to_string())),
+        })
+    } else if msg == "clearPacketKey" {
+        encryption.set(None);
+        Some(match packet_crypto::delete_key() {
+            Ok(()) => packet_key_script(encryption, None),
+            Err(e) => {
+                debug_log(&format!("delete_key failed: {}", e));
+                let script = packet_key_script(encryption, Some(&e.to_string()));
+                errors.record(&e.into());
+                script
+            }
+        })
+    } else if msg == "packetKey" {
+        Some(packet_key_script(encryption, None))
+    } else if msg == "memory" {
+        Some(memory_script(memory))
+    } else if msg == "errors" {
+        Some(errors_script(errors))
+    } else if msg == "clicks" {
+        Some(clicks_script(clicks))
+    } else if let Some(path) = msg.strip_prefix("saveSpectrogram:") {
+        // Rendered from a copy so the packet drain isn't held up
+        let snapshot = spectrogram.lock().clone();
+        let path = path.trim();
+        Some(match snapshot.save_png(std::path::Path::new(path)) {
+            Ok(()) => spectrogram_saved_script(path),
+            Err(e) => {
+                debug_log(&format!("saveSpectrogram: {}: {}", path, e));
+                spectrogram_error_script(&e)
+            }
+        })
+    } else if let Some(command) = HistoryCommand::parse_ipc(msg) {
+        // history.freeze, history.scrub:0.25, history.resume; frames from
+        // the history come back through the usual payloads
+        history.apply(command);
+        None
+    } else if msg == "setupState" {
+        Some(setup_state_script(&setup.state(auth_token), None))
+    } else if let Some(name) = msg.strip_prefix("setupAction:") {
+        // Only the actions in onboarding::SetupAction; the page never picks
+        // the URL or the port
+        let effects = EditorSetupEffects {
+            onboarding: &setup.onboarding,
+            params,
+            context,
+        };
+        let error = onboarding::relay(name, &effects).err().map(|e| {
+            debug_log(&format!("setupAction: {}", e));
+            e.to_string()
+        });
+        Some(setup_state_script(&setup.state(auth_token), error.as_deref()))
+    } else if msg == "tlsCertificate" {
+        Some(tls_script(&setup.tls, None))
+    } else if let Some(fingerprint) = msg.strip_prefix("trustTlsCertificate:") {
+        // The fingerprint the page showed, so a certificate offered since
+        // isn't the one trusted; the connection retries at once
+        let error = match setup.tls.trust(fingerprint) {
+            Ok(()) => None,
+            Err(e) => {
+                debug_log(&format!("trustTlsCertificate: {}", e));
+                Some(e.to_string())
+            }
+        };
+        setup.onboarding.request_retry();
+        Some(tls_script(&setup.tls, error.as_deref()))
+    } else if msg == "destinations" {
+        Some(destinations_script(&setup.destinations, None))
+    } else if let Some(json) = msg.strip_prefix("setDestinations:") {
+        // [{"host":"studio.local","port":9847,"enabled":true}, ...]; saved
+        // with the project as kept, and connected to from now on
+        let error = match serde_json::from_str::<Vec<Destination>>(json) {
+            Ok(listed) => {
+                setup.destinations.set(&params.set_destinations(&listed));
+                None
+            }
+            Err(e) => {
+                debug_log(&format!("setDestinations: bad payload: {}", e));
+                Some(e.to_string())
+            }
+        };
+        Some(destinations_script(&setup.destinations, error.as_deref()))
+    } else if msg == "tick" {
+        // Periodic UI-thread callback from the page (Windows).
+        refresh_token_from_disk(auth_token);
+        None
+    } else if msg == "clearToken" {
+        match auth_token.clear() {
+            Ok(()) => None,
+            Err(e) => {
+                debug_log(&format!("delete_token failed: {}", e));
+                let script = token_error_script(&e);
+                errors.record(&e.into());
+                Some(script)
+            }
+        }
+    } else {
+        if let Some(info) = msg.strip_prefix("debug:") {
+            debug_log(&format!("[js] {}", info));
+        }
+        None
     }
 }
 
+/// JS that publishes the available preset names to the page.
+fn presets_script() -> String {
+    let names = presets::list(presets::user_dir().as_deref());
+    let json = serde_json::to_string(&names).unwrap_or_else(|_| "[]".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onPresets && window.__hardwave.onPresets({})",
+        json
+    )
+}
+
+/// JS that reports a preset failure to the page.
+fn preset_error_script(err: &presets::PresetError) -> String {
+    debug_log(&format!("preset: {}", err));
+    let msg = serde_json::to_string(&err.to_string()).unwrap_or_else(|_| "\"\"".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onPresetError && window.__hardwave.onPresetError({})",
+        msg
+    )
+}
+
+/// JS that tells the page whether packets are being recorded.
+fn recording_script(on: bool) -> String {
+    format!(
+        "window.__hardwave && window.__hardwave.onRecording && window.__hardwave.onRecording({})",
+        on
+    )
+}
+
+/// JS that tells the page whether usage metrics are shared with the Suite.
+fn metrics_opt_in_script(params: &HardwaveAnalyserParams) -> String {
+    format!(
+        "window.__hardwave && window.__hardwave.onMetricsOptIn && window.__hardwave.onMetricsOptIn({})",
+        params.metrics_opt_in.load(Ordering::Relaxed)
+    )
+}
+
+/// JS that publishes this instance's display colour and order to the page.
+fn display_hints_script(params: &HardwaveAnalyserParams) -> String {
+    let json = serde_json::to_string(&params.display_hints()).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "window.__hardwaveDisplay = {0}; \
+         window.__hardwave && window.__hardwave.onDisplayHints && window.__hardwave.onDisplayHints({0})",
+        json
+    )
+}
+
+/// JS that tells the page whether packets are encrypted and with which key,
+/// and why the last key change failed, if it did. The key itself never
+/// leaves the plugin.
+fn packet_key_script(encryption: &PacketEncryption, error: Option<&str>) -> String {
+    let status = encryption.status();
+    let json = serde_json::json!({
+        "enabled": status.enabled,
+        "key_id": status.key_id.map(|id| format!("{:02x}", id)),
+        "error": error,
+    });
+    format!(
+        "window.__hardwave && window.__hardwave.onPacketKey && window.__hardwave.onPacketKey({})",
+        json
+    )
+}
+
+/// JS that tells the page whether the connection uses TLS and the
+/// fingerprint of the certificate last turned down, for the user to accept,
+/// and why accepting it failed, if it did
+fn tls_script(tls: &TlsControl, error: Option<&str>) -> String {
+    let json = serde_json::json!({
+        "enabled": tls.enabled(),
+        "untrusted": tls.untrusted(),
+        "error": error,
+    });
+    format!("window.__hardwave && window.__hardwave.onTls && window.__hardwave.onTls({})", json)
+}
+
+/// JS that publishes the extra destinations and their connection states to
+/// the page, and why the last change failed, if it did
+fn destinations_script(destinations: &Destinations, error: Option<&str>) -> String {
+    let json = serde_json::json!({
+        "destinations": destinations.statuses(),
+        "error": error,
+    });
+    format!(
+        "window.__hardwave && window.__hardwave.onDestinations && window.__hardwave.onDestinations({})",
+        json
+    )
+}
+
+/// The connection's state and latency, with what it sent and lost and the
+/// state changes before, as JSON
+#[cfg(any(target_os = "windows", test))]
+fn status_json(connection: &ConnectionStats) -> String {
+    let mut status = serde_json::to_value(connection.status()).unwrap_or(serde_json::Value::Null);
+    if let Some(fields) = status.as_object_mut() {
+        fields.insert(
+            "link".to_string(),
+            serde_json::to_value(connection.link()).unwrap_or(serde_json::Value::Null),
+        );
+        // How it got here, oldest first, each change timed from now
+        let now = std::time::Instant::now();
+        let history: Vec<serde_json::Value> = connection
+            .history()
+            .iter()
+            .map(|t| {
+                serde_json::json!({
+                    "from": t.from,
+                    "to": t.to,
+                    "reason": t.reason,
+                    "ms_ago": now.saturating_duration_since(t.at).as_millis() as u64,
+                })
+            })
+            .collect();
+        fields.insert("state_history".to_string(), serde_json::Value::Array(history));
+    }
+    status.to_string()
+}
+
+/// This instance's error counts, with the panics caught anywhere in the
+/// process, as JSON
+fn errors_json(errors: &ErrorStats) -> String {
+    let mut report = serde_json::to_value(errors.report()).unwrap_or(serde_json::Value::Null);
+    if let Some(fields) = report.as_object_mut() {
+        fields.insert("panics".to_string(), serde_json::json!(panics::report()));
+    }
+    report.to_string()
+}
+
+/// JS that reports this instance's error counts to the page.
+fn errors_script(errors: &ErrorStats) -> String {
+    let json = errors_json(errors);
+    format!(
+        "window.__hardwave && window.__hardwave.onErrors && window.__hardwave.onErrors({})",
+        json
+    )
+}
+
+/// JS that reports the clicks found in the input to the page.
+fn clicks_script(clicks: &ClickStats) -> String {
+    let json = serde_json::to_string(&clicks.report()).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onClicks && window.__hardwave.onClicks({})",
+        json
+    )
+}
+
+/// JS that tells the page how a Suite command fared: `"queued"` with its
+/// sequence number, then `"ok"` or `"failed"` once the Suite answers, or
+/// `"rejected"` without a number if it never left the plugin.
+fn suite_command_script(seq: Option<u32>, state: &str, error: Option<&str>) -> String {
+    let json = serde_json::json!({ "seq": seq, "state": state, "error": error });
+    format!(
+        "window.__hardwave && window.__hardwave.onSuiteCommand && window.__hardwave.onSuiteCommand({})",
+        json
+    )
+}
+
+/// JS that passes the Suite's answers to commands on to the page. Returns
+/// `None` when there are none.
+fn suite_command_acks_update(suite_commands: &SuiteCommands) -> Option<String> {
+    let scripts: Vec<String> = suite_commands
+        .take_acks()
+        .iter()
+        .map(|ack| suite_command_script(Some(ack.seq), if ack.ok { "ok" } else { "failed" }, ack.error.as_deref()))
+        .collect();
+    (!scripts.is_empty()).then(|| scripts.join("; "))
+}
+
+/// JS that reports this instance's memory use to the page.
+/// Hand an editor payload to the page, or its frame to a page from before
+/// the envelope
+#[cfg(any(not(target_os = "windows"), test))]
+fn editor_payload_script(payload_json: &str) -> String {
+    format!(
+        "(function(p) {{ if (window.__onEditorPayload) window.__onEditorPayload(p); \
+         else if (p.type === 'fft' && window.__onAudioPacket) window.__onAudioPacket(p.data); }})({})",
+        payload_json
+    )
+}
+
+/// What `bridge_update` compares of the connection: its state, address,
+/// last error and port warning
+type BridgeKey = (ConnectionState, Option<String>, Option<String>, Option<String>);
+
+/// JS that hands the connection to the Suite to the page's
+/// `__onBridgeStatus`, for a connected badge. Returns `None` when its
+/// state, address, last error and port warning haven't changed since
+/// `last`; the latency moving is no change. The Windows poller does the
+/// same with each envelope's `status`.
+#[cfg(any(not(target_os = "windows"), test))]
+fn bridge_update(status: ConnectionStatus, last: &mut Option<BridgeKey>) -> Option<String> {
+    let key = (status.connection, status.address.clone(), status.last_error.clone(), status.port_warning.clone());
+    if last.as_ref() == Some(&key) {
+        return None;
+    }
+    *last = Some(key);
+    let json = serde_json::to_string(&status).unwrap_or_else(|_| "null".to_string());
+    Some(format!(
+        "window.__hardwaveBridge = {0}; \
+         window.__onBridgeStatus && window.__onBridgeStatus({0})",
+        json
+    ))
+}
+
+/// Hand the newest waveform packet, as `GET /waveform` serves it, to a page
+/// that draws the scope
+#[cfg(any(not(target_os = "windows"), test))]
+fn editor_waveform_script(waveform_json: &str) -> String {
+    format!("window.__onEditorWaveform && window.__onEditorWaveform({})", waveform_json)
+}
+
+fn memory_script(memory: &MemoryGauges) -> String {
+    let json = serde_json::to_string(&memory.report()).unwrap_or_else(|_| "null".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onMemory && window.__hardwave.onMemory({})",
+        json
+    )
+}
+
+/// JS that tells the page where the spectrogram was saved.
+fn spectrogram_saved_script(path: &str) -> String {
+    let path = serde_json::to_string(path).unwrap_or_else(|_| "\"\"".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onSpectrogramSaved && window.__hardwave.onSpectrogramSaved({})",
+        path
+    )
+}
+
+/// JS that reports a failed spectrogram save to the page.
+fn spectrogram_error_script(err: &std::io::Error) -> String {
+    let msg = serde_json::to_string(&err.to_string()).unwrap_or_else(|_| "\"\"".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onSpectrogramError && window.__hardwave.onSpectrogramError({})",
+        msg
+    )
+}
+
+/// JS that reports a token storage failure to the page.
+fn token_error_script(err: &auth::AuthError) -> String {
+    let msg = serde_json::to_string(&err.to_string()).unwrap_or_else(|_| "\"\"".to_string());
+    format!(
+        "window.__hardwave && window.__hardwave.onTokenSaveError && window.__hardwave.onTokenSaveError({})",
+        msg
+    )
+}
+
 // ---------------------------------------------------------------------------
-// Local HTTP packet server (Windows only)
+// Local HTTP packet server (used on Windows only)
 // ---------------------------------------------------------------------------
 
+/// How long the packet server goes without a poll before it checks whether
+/// its editor is still around
+#[cfg(any(target_os = "windows", test))]
+const POLL_IDLE_TIMEOUT: Duration = Duration::from_secs(2 * 60);
+
 /// Spawn a tiny HTTP server on a random loopback port that serves the latest
-/// FFT packet as JSON. JS fetches `http://127.0.0.1:{port}/` at ~60 fps.
+/// FFT packet as JSON. JS fetches `http://127.0.0.1:{port}/packet` at ~60 fps
+/// and gets an `editor_payload` envelope with `connection` merged in; `GET
+/// /packet?v=1` and `GET /` serve the packet flat, as before the envelope.
+/// `GET /waveform` serves the newest waveform packet for the scope.
+/// `GET /memory` returns the instance's memory report instead, `GET /errors`
+/// its error counts, `GET /status` its connection's state, address, last
+/// error, recent state changes and what it sent and lost, `GET /clicks` the
+/// clicks found in its input, `GET /spectrogram.png` the last half minute
+/// of spectrum as an image, `GET /config` the display hints the Suite is
+/// sent, channel labels included, and `GET /instance` the instance ID and
+/// process ID, so a port can be traced back to the instance holding it. The drainer also feeds every packet into
+/// `spectrogram`.
 ///
 /// The server runs until `running` is set to false (EditorHandle dropped).
-#[cfg(target_os = "windows")]
+/// If the host loses the handle without dropping it, for instance while
+/// crashing, the server stops once nobody has polled it for `idle_timeout`
+/// and nothing but the server still holds `running`. Returns the port and
+/// the server thread; dropping the handle detaches it.
+#[cfg(any(target_os = "windows", test))]
+#[allow(clippy::too_many_arguments)]
 fn start_packet_server(
-    packet_rx: Receiver<crate::protocol::AudioPacket>,
+    packet_rx: Receiver<Arc<AudioPacket>>,
     running: Arc<AtomicBool>,
-) -> u16 {
+    memory: Arc<MemoryGauges>,
+    errors: Arc<ErrorStats>,
+    clicks: Arc<ClickStats>,
+    spectrogram: Arc<Mutex<SpectrogramHistory>>,
+    display: Arc<Mutex<DisplayHints>>,
+    connection: ConnectionStats,
+    idle_timeout: Duration,
+) -> Result<(u16, thread::JoinHandle<()>), HardwaveError> {
     use std::io::{Read, Write};
-    use std::net::TcpListener;
 
-    let listener = match TcpListener::bind("127.0.0.1:0") {
-        Ok(l) => l,
-        Err(e) => {
-            debug_log(&format!("start_packet_server: bind failed: {}", e));
-            return 0;
-        }
-    };
+    let listener = bind_loopback(0)?;
     let port = listener.local_addr().map(|a| a.port()).unwrap_or(0);
+    ports::register_packet_server(port);
 
-    thread::spawn(move || {
-        // Shared storage for the latest packet.
-        let latest: Arc<Mutex<Option<crate::protocol::AudioPacket>>> =
-            Arc::new(Mutex::new(None));
+    // Exits within a few ms of `running` going false, and nothing it touches
+    // outlives the Arcs it holds
+    let server = threads::spawn("hardwave-pktserv", Priority::Streaming, move || {
+        // Shared storage for the latest packet, numbered.
+        let latest: Arc<Mutex<EditorFeed>> = Arc::new(Mutex::new(EditorFeed::default()));
 
         // Drainer thread: keeps `latest` current from the crossbeam channel.
-        {
+        // Holds `running` weakly so it doesn't count as an owner. Joined
+        // when the server stops.
+        let drainer = {
             let latest_w = Arc::clone(&latest);
-            let running_d = Arc::clone(&running);
-            thread::spawn(move || {
-                while running_d.load(Ordering::Relaxed) {
-                    while let Ok(p) = packet_rx.try_recv() {
-                        *latest_w.lock() = Some(p);
-                    }
-                    thread::sleep(Duration::from_millis(4));
-                }
-            });
-        }
+            let running_d = Arc::downgrade(&running);
+            let spectrogram_w = Arc::clone(&spectrogram);
+            threads::spawn_restarting("hardwave-drain", Priority::Streaming, Region::Drainer, move || {
+                drain_packets(&packet_rx, &latest_w, &spectrogram_w, &running_d);
+            })
+        };
 
         // HTTP accept loop (non-blocking so we can check `running`).
+        let mut listener = listener;
         listener.set_nonblocking(true).ok();
-        while running.load(Ordering::Relaxed) {
+        let mut last_poll = std::time::Instant::now();
+        let mut resume = ResumeDetector::new();
+        while !packet_server_should_stop(&running, last_poll.elapsed(), idle_timeout) {
+            if resume.check().is_some() {
+                // Bound before the machine slept; the page polls this port,
+                // so it is bound again rather than swapped for a free one
+                debug_log("Resumed from sleep, binding the packet server again");
+                drop(listener);
+                match rebind_loopback(port, &running) {
+                    Some(fresh) => listener = fresh,
+                    None => break,
+                }
+                last_poll = std::time::Instant::now();
+            }
             match listener.accept() {
                 Ok((mut stream, _)) => {
-                    let body = {
-                        let guard = latest.lock();
-                        match guard.as_ref() {
-                            Some(p) => serde_json::to_string(p)
+                    last_poll = std::time::Instant::now();
+                    // Only the request line matters
+                    stream.set_read_timeout(Some(Duration::from_millis(10))).ok();
+                    let mut buf = [0u8; 1024];
+                    let n = stream.read(&mut buf).unwrap_or(0);
+                    let request = String::from_utf8_lossy(&buf[..n]);
+                    let target = request.split_whitespace().nth(1).unwrap_or("/");
+                    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+                    let json = |body: String| ("application/json", body.into_bytes());
+                    let response = panics::contain(Region::PacketServer, || match path {
+                        "/memory" => json(
+                            serde_json::to_string(&memory.report())
+                                .unwrap_or_else(|_| "null".to_string()),
+                        ),
+                        "/errors" => json(errors_json(&errors)),
+                        "/status" => json(status_json(&connection)),
+                        "/config" => json(
+                            serde_json::to_string(&*display.lock())
                                 .unwrap_or_else(|_| "null".to_string()),
-                            None => "null".to_string(),
+                        ),
+                        "/waveform" => json(latest.lock().waveform_json()),
+                        "/clicks" => json(
+                            serde_json::to_string(&clicks.report())
+                                .unwrap_or_else(|_| "null".to_string()),
+                        ),
+                        "/spectrogram.png" => {
+                            let snapshot = spectrogram.lock().clone();
+                            match snapshot.to_png() {
+                                Ok(png) => ("image/png", png),
+                                Err(e) => {
+                                    debug_log(&format!("spectrogram.png: {}", e));
+                                    json("null".to_string())
+                                }
+                            }
+                        }
+                        "/instance" => {
+                            let instance_id = display.lock().instance_id.clone();
+                            json(
+                                serde_json::json!({
+                                    "instance_tag": display::instance_tag(&instance_id),
+                                    "instance_id": instance_id,
+                                    "pid": std::process::id(),
+                                })
+                                .to_string(),
+                            )
+                        }
+                        "/packet" if !query.split('&').any(|q| q == "v=1") => {
+                            let status = connection.status();
+                            json(latest.lock().payload_json(status))
+                        }
+                        _ => json(latest.lock().legacy_json()),
+                    });
+                    let (status, (content_type, body)) = match response {
+                        Ok(response) => ("200 OK", response),
+                        Err(panicked) => {
+                            errors.record(&panicked.into());
+                            ("500 Internal Server Error", json("null".to_string()))
                         }
                     };
-                    // Drain the incoming HTTP request bytes (ignore them).
-                    stream.set_read_timeout(Some(Duration::from_millis(10))).ok();
-                    let mut buf = [0u8; 1024];
-                    let _ = stream.read(&mut buf);
                     // Write minimal HTTP response.
-                    let resp = format!(
-                        "HTTP/1.1 200 OK\r\n\
-                         Content-Type: application/json\r\n\
+                    let header = format!(
+                        "HTTP/1.1 {}\r\n\
+                         Content-Type: {}\r\n\
                          Access-Control-Allow-Origin: *\r\n\
                          Cache-Control: no-store\r\n\
                          Connection: close\r\n\
                          Content-Length: {}\r\n\
-                         \r\n\
-                         {}",
-                        body.len(),
-                        body
+                         \r\n",
+                        status,
+                        content_type,
+                        body.len()
                     );
+                    // Accepted sockets inherit non-blocking from the listener,
+                    // and an image is more than one write
+                    stream.set_nonblocking(false).ok();
                     stream.set_write_timeout(Some(Duration::from_millis(100))).ok();
-                    let _ = stream.write_all(resp.as_bytes());
+                    let _ = stream
+                        .write_all(header.as_bytes())
+                        .and_then(|_| stream.write_all(&body));
                 }
                 Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                     thread::sleep(Duration::from_millis(5));
@@ -296,10 +1230,93 @@ fn start_packet_server(
                 Err(_) => break,
             }
         }
+        // Stops the drainer too if the server was orphaned
+        drop(running);
+        if let Ok(drainer) = drainer {
+            let _ = drainer.join();
+        }
+        ports::unregister_packet_server(port);
         debug_log("Packet server stopped");
     });
+    match server {
+        Ok(thread) => Ok((port, thread)),
+        Err(e) => {
+            ports::unregister_packet_server(port);
+            Err(TransportError::Spawn(e).into())
+        }
+    }
+}
 
-    port
+/// Listen on `port` of the loopback interface, or on a free one for 0
+#[cfg(any(target_os = "windows", test))]
+fn bind_loopback(port: u16) -> Result<std::net::TcpListener, HardwaveError> {
+    std::net::TcpListener::bind(("127.0.0.1", port)).map_err(|e| TransportError::Bind(e).into())
+}
+
+/// Bind `port` again after a resume, non-blocking, retrying while the
+/// network stack comes back. `None` if it stays taken for
+/// `REBIND_ATTEMPTS` tries or the editor closes meanwhile.
+#[cfg(any(target_os = "windows", test))]
+fn rebind_loopback(port: u16, running: &AtomicBool) -> Option<std::net::TcpListener> {
+    for attempt in 1..=REBIND_ATTEMPTS {
+        match bind_loopback(port) {
+            Ok(listener) => {
+                listener.set_nonblocking(true).ok();
+                return Some(listener);
+            }
+            Err(e) if attempt == REBIND_ATTEMPTS => debug_log(&format!("Packet server stopped: {}", e)),
+            Err(_) => {}
+        }
+        if !running.load(Ordering::Relaxed) {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    None
+}
+
+/// Tries, 100 ms apart, at binding the packet server's port after a resume
+#[cfg(any(target_os = "windows", test))]
+const REBIND_ATTEMPTS: u32 = 50;
+
+/// Whether the packet server should shut down: its editor closed it, or
+/// there have been no polls for `idle_timeout` and the server holds the
+/// last reference to `running`.
+#[cfg(any(target_os = "windows", test))]
+fn packet_server_should_stop(running: &Arc<AtomicBool>, since_poll: Duration, idle_timeout: Duration) -> bool {
+    !running.load(Ordering::Relaxed) || (since_poll >= idle_timeout && Arc::strong_count(running) == 1)
+}
+
+/// Keep `latest` and `spectrogram` current from the editor channel until the
+/// server stops or the plugin, which owns the sender, is gone. `latest`
+/// starts a heartbeat whenever the channel has been quiet an interval.
+#[cfg(any(target_os = "windows", test))]
+fn drain_packets(
+    packet_rx: &Receiver<Arc<AudioPacket>>,
+    latest: &Mutex<EditorFeed>,
+    spectrogram: &Mutex<SpectrogramHistory>,
+    running: &Weak<AtomicBool>,
+) {
+    while running.upgrade().is_some_and(|r| r.load(Ordering::Relaxed)) {
+        panics::checkpoint(Region::Drainer);
+        loop {
+            match packet_rx.try_recv() {
+                Ok(p) => {
+                    spectrogram.lock().push(&p);
+                    latest.lock().push(p, std::time::Instant::now());
+                }
+                Err(TryRecvError::Empty) => {
+                    latest.lock().tick(std::time::Instant::now());
+                    break;
+                }
+                Err(TryRecvError::Disconnected) => {
+                    debug_log("Packet drainer: plugin is gone, stopping");
+                    return;
+                }
+            }
+        }
+        thread::sleep(Duration::from_millis(4));
+    }
 }
 
 // ---------------------------------------------------------------------------
@@ -308,12 +1325,57 @@ impl Editor for HardwaveAnalyserEditor {
     fn spawn(
         &self,
         parent: ParentWindowHandle,
-        _context: Arc<dyn GuiContext>,
+        context: Arc<dyn GuiContext>,
     ) -> Box<dyn std::any::Any + Send> {
-        let packet_rx = self.packet_rx.clone();
+        // Only frames and waveforms are shown. A window that can't keep up
+        // drops its oldest, to show the newest sooner; nothing else waits on
+        // it.
+        let memory_gauge = Arc::clone(&self.shared.memory);
+        let route = Route::new("editor", PACKET_QUEUE_LEN, Overflow::DropOldest)
+            .accepting(|packet: &AudioPacket| matches!(packet.packet_type, PACKET_TYPE_FFT | PACKET_TYPE_WAVEFORM))
+            .observed(move |packet: &AudioPacket, delivery| {
+                memory_gauge.set(Buffer::EditorQueue, delivery.queued * packet.heap_bytes());
+            });
+        // Held by the handle, so the bus stops queueing once the window closes
+        let subscription = self.shared.bus.subscribe(route);
+        let packet_rx = subscription.receiver().clone();
         let running = Arc::new(AtomicBool::new(true));
-        let auth_token = Arc::clone(&self.auth_token);
+        let EditorShared {
+            auth_token,
+            lifecycle,
+            recording,
+            display,
+            memory,
+            errors,
+            clicks,
+            watchdog,
+            suite_commands,
+            encryption,
+            connection,
+            onboarding,
+            tls,
+            destinations,
+            history,
+            ..
+        } = self.shared.clone();
+        let params = Arc::clone(&self.params);
         let url = self.build_url();
+        let spectrogram = Arc::clone(&self.spectrogram);
+        let scale = Arc::clone(&self.scale);
+        lifecycle.opened();
+
+        // Detached: best-effort cleanup of old per-instance WebView2 temp
+        // directories, finishes on its own.
+        let _ = threads::spawn("hardwave-cleanup", Priority::Background, || {
+            let removed = webview_dir::cleanup_stale(
+                &std::env::temp_dir(),
+                webview_dir::STALE_TEMP_DIR_AGE,
+                std::process::id(),
+            );
+            if removed > 0 {
+                debug_log(&format!("Removed {} stale WebView2 temp dirs", removed));
+            }
+        });
 
         // ---------------------------------------------------------------
         // Windows: create webview on the DAW's UI thread using build()
@@ -324,7 +1386,7 @@ impl Editor for HardwaveAnalyserEditor {
         // position → ghosting artifacts.
         //
         // FFT data is delivered via a local TCP server (start_packet_server).
-        // JS fetches http://127.0.0.1:{port}/ at ~60fps. Chrome permits
+        // JS fetches http://127.0.0.1:{port}/packet at ~60fps. Chrome permits
         // HTTPS pages fetching from 127.0.0.1 (localhost is "potentially
         // trustworthy" per the W3C spec), so no --disable-web-security needed.
         // ---------------------------------------------------------------
@@ -336,42 +1398,196 @@ impl Editor for HardwaveAnalyserEditor {
             };
             debug_log(&format!("spawn() called, parent HWND = 0x{:X}", parent_hwnd));
 
-            ensure_webview2();
+            if let Err(e) = ensure_webview2() {
+                debug_log(&format!("WebView2 runtime: {}", e));
+                errors.record(&e);
+            }
 
             // Use a writable data directory for WebView2. The default is the
             // executable's folder (FL Studio's Program Files) which is not
             // writable → E_ACCESSDENIED.
-            let data_dir = dirs::data_local_dir()
-                .unwrap_or_else(std::env::temp_dir)
-                .join("Hardwave")
-                .join("WebView2");
-            debug_log(&format!("WebView2 data dir = {:?}", data_dir));
-            let _ = std::fs::create_dir_all(&data_dir);
-            let mut web_context = wry::WebContext::new(Some(data_dir));
+            let candidates = webview_dir::candidates(
+                std::env::var_os(webview_dir::DATA_DIR_ENV).map(std::path::PathBuf::from),
+                crate::config::load()
+                    .unwrap_or_else(|e| {
+                        debug_log(&format!("Machine config ignored: {}", e));
+                        errors.record(&e);
+                        Default::default()
+                    })
+                    .webview_data_dir,
+                dirs::data_local_dir(),
+                &std::env::temp_dir(),
+                std::process::id(),
+            );
+            let data_dir = webview_dir::choose(&candidates)
+                .map_err(|e| errors.record(&e))
+                .ok();
+            debug_log(&format!(
+                "WebView2 data dir = {:?} (candidates {:?})",
+                data_dir, candidates
+            ));
+            let mut web_context = wry::WebContext::new(data_dir);
 
             let parent_wrapper = RwhWrapper(parent);
             let ipc_auth_token = Arc::clone(&auth_token);
+            let ipc_params = Arc::clone(&params);
+            let ipc_context = Arc::clone(&context);
+            let ipc_recording = Arc::clone(&recording);
+            let ipc_display = Arc::clone(&display);
+            let ipc_memory = Arc::clone(&memory);
+            let ipc_errors = Arc::clone(&errors);
+            let ipc_clicks = Arc::clone(&clicks);
+            let ipc_suite_commands = Arc::clone(&suite_commands);
+            let ipc_encryption = Arc::clone(&encryption);
+            let ipc_spectrogram = Arc::clone(&spectrogram);
+            let ipc_history = Arc::clone(&history);
+            let ipc_scale = Arc::clone(&scale);
+            let labels_script = channel_labels_script(&params);
+            let display_script = display_hints_script(&params);
+
+            // The IPC handler runs on this (UI) thread and owns the pump: the
+            // page posts a message every frame, and each one applies whatever
+            // has been queued for the webview since.
+            let (ipc_queue, pump) = webview_commands::channel();
+            let webview_id: Arc<Mutex<Option<u64>>> = Arc::new(Mutex::new(None));
+            let ipc_webview_id = Arc::clone(&webview_id);
+            let loaded_url = Mutex::new(url.clone());
+            let port_warning = Mutex::new(None);
+            let host_audio = Mutex::new(None);
+            let eco_mode = Mutex::new(None);
+            let setup_state = Mutex::new(None);
+            let setup_connection = connection.clone();
+            let env = HardwaveEnv {
+                theme: theme::detect(),
+                scale: scale.load(Ordering::Relaxed),
+            };
+            let env_script_init = env_script(&env);
+            let last_env = Mutex::new(env);
 
             debug_log(&format!("URL = {}", url));
 
             // Start the local HTTP server that serves FFT packets as JSON.
-            // JS polls http://127.0.0.1:{port}/ at ~60fps.
-            let server_port = start_packet_server(packet_rx.clone(), Arc::clone(&running));
+            // JS polls http://127.0.0.1:{port}/packet at ~60fps.
+            // Detached: it stops by itself, see start_packet_server
+            let server_port = start_packet_server(
+                packet_rx.clone(),
+                Arc::clone(&running),
+                Arc::clone(&memory),
+                Arc::clone(&errors),
+                Arc::clone(&clicks),
+                Arc::clone(&spectrogram),
+                Arc::clone(&display),
+                connection,
+                POLL_IDLE_TIMEOUT,
+            )
+            .map_or_else(
+                |e| {
+                    debug_log(&format!("Packet server not started: {}", e));
+                    errors.record(&e);
+                    0
+                },
+                |(port, _server)| port,
+            );
             debug_log(&format!("Packet server listening on port {}", server_port));
+            let ipc_setup = SetupSources {
+                onboarding,
+                tls,
+                destinations,
+                connection: setup_connection,
+                transport_ok: server_port != 0,
+            };
 
             let init_script = format!(
                 r#"
                 window.__HARDWAVE_VST = true;
+                window.__hardwavePort = {port};
                 window.__hardwave = {{
                     saveToken: function(token) {{
                         window.ipc.postMessage('saveToken:' + token);
-                    }}
+                    }},
+                    clearToken: function() {{
+                        window.ipc.postMessage('clearToken');
+                    }},
+                    setChannelLabels: function(first, second) {{
+                        window.ipc.postMessage('setChannelLabels:' + JSON.stringify([first, second]));
+                    }},
+                    listPresets: function() {{
+                        window.ipc.postMessage('listPresets');
+                    }},
+                    applyPreset: function(name) {{
+                        window.ipc.postMessage('applyPreset:' + name);
+                    }},
+                    savePreset: function(name) {{
+                        window.ipc.postMessage('savePreset:' + name);
+                    }},
+                    setRecording: function(on) {{
+                        window.ipc.postMessage('setRecording:' + (on ? 'on' : 'off'));
+                    }},
+                    setMetricsOptIn: function(on) {{
+                        window.ipc.postMessage('setMetricsOptIn:' + (on ? 'on' : 'off'));
+                    }},
+                    setDisplayColor: function(color) {{
+                        window.ipc.postMessage('setDisplayColor:' + color);
+                    }},
+                    setDisplayLabel: function(label) {{
+                        window.ipc.postMessage('setDisplayLabel:' + label);
+                    }},
+                    setDisplayOrder: function(order) {{
+                        window.ipc.postMessage('setDisplayOrder:' + order);
+                    }},
+                    copySlot: function(from, to) {{
+                        window.ipc.postMessage('copySlot:' + from + '>' + to);
+                    }},
+                    memory: function() {{
+                        window.ipc.postMessage('memory');
+                    }},
+                    errors: function() {{
+                        window.ipc.postMessage('errors');
+                    }},
+                    clicks: function() {{
+                        window.ipc.postMessage('clicks');
+                    }},
+                    suiteCommand: function(command, args) {{
+                        window.ipc.postMessage('suiteCommand:' + JSON.stringify({{ command: command, args: args }}));
+                    }},
+                    saveSpectrogram: function(path) {{
+                        window.ipc.postMessage('saveSpectrogram:' + path);
+                    }},
+                    setupState: function() {{
+                        window.ipc.postMessage('setupState');
+                    }},
+                    setupAction: function(name) {{
+                        window.ipc.postMessage('setupAction:' + name);
+                    }},
+                    openDevtools: function() {{
+                        window.ipc.postMessage('openDevtools');
+                    }},
+                    onTokenSaveError: null,
+                    onChannelLabels: null,
+                    onPortWarning: null,
+                    onHostAudio: null,
+                    onEcoMode: null,
+                    onPacketKey: null,
+                    onTls: null,
+                    onPresets: null,
+                    onPresetError: null,
+                    onRecording: null,
+                    onMetricsOptIn: null,
+                    onDisplayHints: null,
+                    onMemory: null,
+                    onErrors: null,
+                    onClicks: null,
+                    onSuiteCommand: null,
+                    onSpectrogramSaved: null,
+                    onSpectrogramError: null,
+                    onSetupState: null,
+                    onEnv: null
                 }};
 
                 // Poll for FFT data from the local TCP packet server.
                 // On Windows, evaluate_script from a Rust background thread
                 // fails silently (ICoreWebView2 is STA-bound). Instead, JS
-                // fetches http://127.0.0.1:{port}/ at ~60fps from the real
+                // fetches http://127.0.0.1:{port}/packet at ~60fps from the real
                 // TCP server. Chrome permits HTTPS→http://127.0.0.1 because
                 // loopback is considered potentially trustworthy.
                 (function() {{
@@ -380,6 +1596,7 @@ impl Editor for HardwaveAnalyserEditor {
                     var _fetchNull = 0;
                     var _fetchErr = 0;
                     var _packetsSent = 0;
+                    var _bridge = null;
 
                     function dbg(msg) {{
                         try {{ window.ipc.postMessage('debug:' + msg); }} catch(e) {{}}
@@ -388,25 +1605,37 @@ impl Editor for HardwaveAnalyserEditor {
                     function startPolling() {{
                         if (_polling) return;
                         _polling = true;
-                        dbg('polling started on ' + window.location.href + ' port={port}');
+                        dbg('polling started on ' + window.location.href + ' port=' + window.__hardwavePort);
 
                         (function poll() {{
-                            fetch('http://127.0.0.1:{port}/')
+                            fetch('http://127.0.0.1:' + window.__hardwavePort + '/packet')
                                 .then(function(r) {{
                                     _fetchOk++;
                                     return r.json();
                                 }})
-                                .then(function(data) {{
-                                    if (data !== null) {{
-                                        if (typeof window.__onAudioPacket === 'function') {{
-                                            window.__onAudioPacket(data);
-                                            _packetsSent++;
-                                            if (_packetsSent <= 3) {{
-                                                dbg('packet delivered #' + _packetsSent +
-                                                    ' peak=' + data.left_peak);
-                                            }}
-                                        }} else {{
-                                            _fetchNull++;
+                                .then(function(payload) {{
+                                    // The connection to the Suite, once
+                                    // per change of state, address or error
+                                    var s = payload.status;
+                                    var bridge = s && JSON.stringify([s.connection, s.address, s.last_error]);
+                                    if (bridge && bridge !== _bridge) {{
+                                        window.__hardwaveBridge = s;
+                                        if (typeof window.__onBridgeStatus === 'function') {{
+                                            window.__onBridgeStatus(s);
+                                            _bridge = bridge;
+                                        }}
+                                    }}
+                                    if (typeof window.__onEditorPayload === 'function') {{
+                                        window.__onEditorPayload(payload);
+                                        _packetsSent++;
+                                    }} else if (payload.type === 'fft' &&
+                                               typeof window.__onAudioPacket === 'function') {{
+                                        // A page from before the envelope
+                                        window.__onAudioPacket(payload.data);
+                                        _packetsSent++;
+                                        if (_packetsSent <= 3) {{
+                                            dbg('packet delivered #' + _packetsSent +
+                                                ' peak=' + payload.data.left_peak);
                                         }}
                                     }} else {{
                                         _fetchNull++;
@@ -425,7 +1654,14 @@ impl Editor for HardwaveAnalyserEditor {
                                         dbg('fetch error #' + _fetchErr + ': ' + e);
                                     }}
                                 }})
-                                .finally(function() {{ requestAnimationFrame(poll); }});
+                                .finally(function() {{
+                                    // Eco mode: 30 fps instead of every frame
+                                    if (window.__hardwaveEcoMode) {{
+                                        setTimeout(poll, {eco_interval_ms});
+                                    }} else {{
+                                        requestAnimationFrame(poll);
+                                    }}
+                                }});
                         }})();
                     }}
 
@@ -434,9 +1670,16 @@ impl Editor for HardwaveAnalyserEditor {
                     }} else {{
                         startPolling();
                     }}
+
+                    // Give the plugin a UI-thread callback to check for token
+                    // changes and navigate without tearing down the webview.
+                    setInterval(function() {{
+                        try {{ window.ipc.postMessage('tick'); }} catch(e) {{}}
+                    }}, 1000);
                 }})();
                 "#,
-                port = server_port
+                port = server_port,
+                eco_interval_ms = crate::editor_payload::ECO_UPDATE_INTERVAL.as_millis()
             );
 
             #[allow(unused_imports)]
@@ -447,42 +1690,89 @@ impl Editor for HardwaveAnalyserEditor {
                     "--disable-features=msWebOOUI,msPdfOOUI,msSmartScreenProtection \
                      --allow-insecure-localhost"
                 )
-                .with_devtools(false)
+                .with_devtools(cfg!(debug_assertions))
                 .with_transparent(false)
                 .with_background_color((10, 10, 11, 255))
                 .with_visible(true)
                 .with_focused(true)
-                .with_url(&url)
+                .with_url(&theme::with_env(&url, &env))
                 .with_ipc_handler(move |req: wry::http::Request<String>| {
-                    let msg = req.body().as_str();
-                    if let Some(token) = msg.strip_prefix("saveToken:") {
-                        let token = token.trim().to_string();
-                        auth::save_token(&token);
-                        *ipc_auth_token.lock() = Some(token);
-                    } else if let Some(info) = msg.strip_prefix("debug:") {
-                        debug_log(&format!("[js] {}", info));
+                    if req.body() == "openDevtools" {
+                        ipc_queue.push(WebViewCommand::OpenDevtools);
+                    }
+                    let reply = handle_ipc(req.body(), &ipc_auth_token, &ipc_params, &*ipc_context, &ipc_recording, &ipc_display, &ipc_memory, &ipc_errors, &ipc_clicks, &ipc_suite_commands, &ipc_encryption, &ipc_spectrogram, &ipc_history, &ipc_setup);
+                    if let Some(js) = reply {
+                        ipc_queue.eval(js);
+                    }
+                    if let Some(js) = port_warning_update(&ipc_params, &mut port_warning.lock()) {
+                        ipc_queue.eval(js);
+                    }
+                    if let Some(js) = host_audio_update(watchdog.stale_ms(), &mut host_audio.lock()) {
+                        ipc_queue.eval(js);
+                    }
+                    if let Some(js) = eco_mode_update(&ipc_params, &mut eco_mode.lock()) {
+                        ipc_queue.eval(js);
+                    }
+                    if let Some(js) = suite_command_acks_update(&suite_commands) {
+                        ipc_queue.eval(js);
+                    }
+                    if let Some(js) = setup_update(ipc_setup.state(&ipc_auth_token), &mut setup_state.lock()) {
+                        ipc_queue.eval(js);
+                    }
+                    // The registry read is cheap enough for every message
+                    let env = HardwaveEnv {
+                        theme: theme::detect(),
+                        scale: ipc_scale.load(Ordering::Relaxed),
+                    };
+                    let mut last_env = last_env.lock();
+                    if let Some(js) = env_update(env, &mut last_env) {
+                        ipc_queue.eval(js);
+                    }
+
+                    let token = ipc_auth_token.cached();
+                    let mut loaded = loaded_url.lock();
+                    if let Some(target) = navigation_target(&loaded, token.as_deref()) {
+                        debug_log("Token changed, navigating webview");
+                        ipc_queue.push(WebViewCommand::Navigate(theme::with_env(&target, &last_env)));
+                        *loaded = target;
+                    }
+
+                    // Before build() returns there is no webview yet; the
+                    // commands wait for the next message
+                    if let Some(id) = *ipc_webview_id.lock() {
+                        OwnedWebViewId::with(id, |webview| {
+                            pump.pump(|command| webview_commands::apply(webview, command));
+                        });
                     }
                 })
                 .with_initialization_script(&init_script)
+                .with_initialization_script(&labels_script)
+                .with_initialization_script(&display_script)
+                .with_initialization_script(&env_script_init)
                 .build(&parent_wrapper);
 
             match webview {
                 Ok(wv) => {
                     debug_log("WebView created successfully (TCP packet server active)!");
+                    let owned = OwnedWebViewId::store(wv, web_context);
+                    *webview_id.lock() = Some(owned.id);
                     Box::new(EditorHandle {
-                        _thread: None,
-                        _webview: Some(Arc::new(Mutex::new(SendWebView(wv)))),
-                        _web_context: Some(SendWebContext(web_context)),
+                        thread: None,
+                        webview: Some(owned),
                         running,
+                        lifecycle,
+                        _subscription: subscription,
                     })
                 }
                 Err(e) => {
                     debug_log(&format!("FAILED to create webview: {}", e));
+                    errors.record(&EditorError::WebView(e).into());
                     Box::new(EditorHandle {
-                        _thread: None,
-                        _webview: None,
-                        _web_context: None,
+                        thread: None,
+                        webview: None,
                         running,
+                        lifecycle,
+                        _subscription: subscription,
                     })
                 }
             }
@@ -500,7 +1790,11 @@ impl Editor for HardwaveAnalyserEditor {
                 ParentWindowHandle::Win32Hwnd(h) => ParentData::Win32(h as usize),
             };
 
-            let handle = thread::spawn(move || {
+            let spawn_errors = Arc::clone(&errors);
+
+            // Joined when the EditorHandle is dropped, so the webview is gone
+            // before the host can unload the plugin
+            let handle = threads::spawn("hardwave-editor", Priority::Streaming, move || {
                 #[cfg(all(target_os = "linux", feature = "gtk"))]
                 {
                     let _ = gtk::init();
@@ -518,22 +1812,50 @@ impl Editor for HardwaveAnalyserEditor {
                 let parent_wrapper = RwhWrapper(reconstructed);
 
                 let ipc_auth_token = Arc::clone(&auth_token);
+                let ipc_params = Arc::clone(&params);
+                let ipc_context = Arc::clone(&context);
+                let ipc_recording = Arc::clone(&recording);
+                let ipc_display = Arc::clone(&display);
+                let ipc_memory = Arc::clone(&memory);
+                let ipc_errors = Arc::clone(&errors);
+                let ipc_clicks = Arc::clone(&clicks);
+                let ipc_suite_commands = Arc::clone(&suite_commands);
+                let ipc_encryption = Arc::clone(&encryption);
+                let ipc_spectrogram = Arc::clone(&spectrogram);
+                let ipc_history = Arc::clone(&history);
+                // The window gets packets on this thread, so its transport
+                // is always up
+                let setup = Arc::new(SetupSources {
+                    onboarding,
+                    tls,
+                    destinations,
+                    connection: connection.clone(),
+                    transport_ok: true,
+                });
+                let ipc_setup = Arc::clone(&setup);
+                let labels_script = channel_labels_script(&params);
+                let display_script = display_hints_script(&params);
+                let mut env = HardwaveEnv {
+                    theme: theme::detect(),
+                    scale: scale.load(Ordering::Relaxed),
+                };
+                // This thread owns the webview; the IPC handler and the
+                // loop below queue commands, which the loop applies.
+                let (queue, pump) = webview_commands::channel();
+                let ipc_queue = queue.clone();
                 let webview = wry::WebViewBuilder::new()
-                    .with_bounds(wry::Rect {
-                        position: wry::dpi::LogicalPosition::new(0, 0).into(),
-                        size: wry::dpi::LogicalSize::new(EDITOR_WIDTH, EDITOR_HEIGHT).into(),
-                    })
+                    .with_bounds(editor_bounds(env.scale))
                     .with_transparent(false)
                     .with_background_color((10, 10, 11, 255))
                     .with_visible(true)
                     .with_focused(true)
-                    .with_url(&url)
+                    .with_url(theme::with_env(&url, &env))
                     .with_ipc_handler(move |req: wry::http::Request<String>| {
-                        let msg = req.body().as_str();
-                        if let Some(token) = msg.strip_prefix("saveToken:") {
-                            let token = token.trim().to_string();
-                            auth::save_token(&token);
-                            *ipc_auth_token.lock() = Some(token);
+                        if req.body() == "openDevtools" {
+                            ipc_queue.push(WebViewCommand::OpenDevtools);
+                        }
+                        if let Some(js) = handle_ipc(req.body(), &ipc_auth_token, &ipc_params, &*ipc_context, &ipc_recording, &ipc_display, &ipc_memory, &ipc_errors, &ipc_clicks, &ipc_suite_commands, &ipc_encryption, &ipc_spectrogram, &ipc_history, &ipc_setup) {
+                            ipc_queue.eval(js);
                         }
                     })
                     .with_initialization_script(
@@ -542,29 +1864,172 @@ impl Editor for HardwaveAnalyserEditor {
                         window.__hardwave = {
                             saveToken: function(token) {
                                 window.ipc.postMessage('saveToken:' + token);
-                            }
+                            },
+                            clearToken: function() {
+                                window.ipc.postMessage('clearToken');
+                            },
+                            setChannelLabels: function(first, second) {
+                                window.ipc.postMessage('setChannelLabels:' + JSON.stringify([first, second]));
+                            },
+                            listPresets: function() {
+                                window.ipc.postMessage('listPresets');
+                            },
+                            applyPreset: function(name) {
+                                window.ipc.postMessage('applyPreset:' + name);
+                            },
+                            savePreset: function(name) {
+                                window.ipc.postMessage('savePreset:' + name);
+                            },
+                            setRecording: function(on) {
+                                window.ipc.postMessage('setRecording:' + (on ? 'on' : 'off'));
+                            },
+                            setMetricsOptIn: function(on) {
+                                window.ipc.postMessage('setMetricsOptIn:' + (on ? 'on' : 'off'));
+                            },
+                            setDisplayColor: function(color) {
+                                window.ipc.postMessage('setDisplayColor:' + color);
+                            },
+                            setDisplayLabel: function(label) {
+                                window.ipc.postMessage('setDisplayLabel:' + label);
+                            },
+                            setDisplayOrder: function(order) {
+                                window.ipc.postMessage('setDisplayOrder:' + order);
+                            },
+                            copySlot: function(from, to) {
+                                window.ipc.postMessage('copySlot:' + from + '>' + to);
+                            },
+                            memory: function() {
+                                window.ipc.postMessage('memory');
+                            },
+                            errors: function() {
+                                window.ipc.postMessage('errors');
+                            },
+                            clicks: function() {
+                                window.ipc.postMessage('clicks');
+                            },
+                            suiteCommand: function(command, args) {
+                                window.ipc.postMessage('suiteCommand:' + JSON.stringify({ command: command, args: args }));
+                            },
+                            saveSpectrogram: function(path) {
+                                window.ipc.postMessage('saveSpectrogram:' + path);
+                            },
+                            setupState: function() {
+                                window.ipc.postMessage('setupState');
+                            },
+                            setupAction: function(name) {
+                                window.ipc.postMessage('setupAction:' + name);
+                            },
+                            openDevtools: function() {
+                                window.ipc.postMessage('openDevtools');
+                            },
+                            onTokenSaveError: null,
+                            onChannelLabels: null,
+                            onPortWarning: null,
+                            onHostAudio: null,
+                            onEcoMode: null,
+                            onPacketKey: null,
+                            onTls: null,
+                            onPresets: null,
+                            onPresetError: null,
+                            onRecording: null,
+                            onMetricsOptIn: null,
+                            onDisplayHints: null,
+                            onMemory: null,
+                            onErrors: null,
+                            onClicks: null,
+                            onSuiteCommand: null,
+                            onSpectrogramSaved: null,
+                            onSpectrogramError: null,
+                            onSetupState: null,
+                            onEnv: null
                         };
                         "#,
                     )
+                    .with_initialization_script(&labels_script)
+                    .with_initialization_script(&display_script)
+                    .with_initialization_script(&env_script(&env))
                     .build_as_child(&parent_wrapper);
 
                 match webview {
                     Ok(webview) => {
+                        let mut loaded_url = url.clone();
+                        let mut last_disk_check = std::time::Instant::now();
+                        let mut port_warning = None;
+                        let mut host_audio = None;
+                        let mut bridge = None;
+                        let mut eco_mode = None;
+                        let mut setup_state = None;
+                        let mut updates = UpdateGate::default();
+                        let mut os_theme = env.theme;
+                        let mut last_theme_check = std::time::Instant::now();
+                        let mut feed = EditorFeed::default();
+                        let mut last_seq = feed.seq();
+                        let mut last_waveform_seq = feed.waveform_seq();
                         while running_clone.load(Ordering::Relaxed) {
-                            let mut latest: Option<AudioPacket> = None;
                             while let Ok(packet) = packet_rx.try_recv() {
-                                latest = Some(packet);
+                                spectrogram.lock().push(&packet);
+                                feed.push(packet, std::time::Instant::now());
+                            }
+                            feed.tick(std::time::Instant::now());
+
+                            let now = std::time::Instant::now();
+                            if feed.seq() != last_seq && updates.admit(now, params.analysis.eco_mode.value()) {
+                                last_seq = feed.seq();
+                                queue.eval(editor_payload_script(&feed.payload_json(connection.status())));
+                            }
+                            if feed.waveform_seq() != last_waveform_seq {
+                                last_waveform_seq = feed.waveform_seq();
+                                queue.eval(editor_waveform_script(&feed.waveform_json()));
+                            }
+                            // Every pass, so the badge never lags the connection
+                            if let Some(js) = bridge_update(connection.status(), &mut bridge) {
+                                queue.eval(js);
                             }
 
-                            if let Some(packet) = latest {
-                                let json = serde_json::to_string(&packet).unwrap_or_default();
-                                let js = format!(
-                                    "window.__onAudioPacket && window.__onAudioPacket({})",
-                                    json
-                                );
-                                let _ = webview.evaluate_script(&js);
+                            if let Some(js) = suite_command_acks_update(&suite_commands) {
+                                queue.eval(js);
+                            }
+
+                            // Follow token changes (IPC every frame, disk ~1 s)
+                            if last_disk_check.elapsed() >= Duration::from_secs(1) {
+                                refresh_token_from_disk(&auth_token);
+                                if let Some(js) = port_warning_update(&params, &mut port_warning) {
+                                    queue.eval(js);
+                                }
+                                if let Some(js) = host_audio_update(watchdog.stale_ms(), &mut host_audio) {
+                                    queue.eval(js);
+                                }
+                                if let Some(js) = eco_mode_update(&params, &mut eco_mode) {
+                                    queue.eval(js);
+                                }
+                                if let Some(js) = setup_update(setup.state(&auth_token), &mut setup_state) {
+                                    queue.eval(js);
+                                }
+                                if last_theme_check.elapsed() >= THEME_POLL_INTERVAL {
+                                    os_theme = theme::detect();
+                                    last_theme_check = std::time::Instant::now();
+                                }
+                                let current = HardwaveEnv {
+                                    theme: os_theme,
+                                    scale: scale.load(Ordering::Relaxed),
+                                };
+                                let rescaled = current.scale != env.scale;
+                                if let Some(js) = env_update(current, &mut env) {
+                                    queue.eval(js);
+                                    if rescaled {
+                                        queue.push(WebViewCommand::SetBounds(editor_bounds(env.scale)));
+                                    }
+                                }
+                                last_disk_check = std::time::Instant::now();
+                            }
+                            let token = auth_token.cached();
+                            if let Some(target) = navigation_target(&loaded_url, token.as_deref()) {
+                                queue.push(WebViewCommand::Navigate(theme::with_env(&target, &env)));
+                                loaded_url = target;
                             }
 
+                            pump.pump(|command| webview_commands::apply(&webview, command));
+
                             #[cfg(all(target_os = "linux", feature = "gtk"))]
                             {
                                 while gtk::events_pending() {
@@ -577,15 +2042,22 @@ impl Editor for HardwaveAnalyserEditor {
                     }
                     Err(e) => {
                         nih_log!("Failed to create webview: {}", e);
+                        errors.record(&EditorError::WebView(e).into());
                     }
                 }
             });
+            let thread = handle
+                .map_err(|e| {
+                    debug_log(&format!("Failed to spawn editor thread: {}", e));
+                    spawn_errors.record(&EditorError::Spawn(e).into());
+                })
+                .ok();
 
             Box::new(EditorHandle {
-                _thread: Some(handle),
-                _webview: None,
-                _web_context: None,
+                thread,
                 running,
+                lifecycle,
+                _subscription: subscription,
             })
         }
     }
@@ -594,7 +2066,9 @@ impl Editor for HardwaveAnalyserEditor {
         self.size
     }
 
-    fn set_scale_factor(&self, _factor: f32) -> bool {
+    fn set_scale_factor(&self, factor: f32) -> bool {
+        // Picked up by the open editor within a second, and by the next spawn
+        self.scale.store(factor, Ordering::Relaxed);
         true
     }
 
@@ -603,22 +2077,465 @@ impl Editor for HardwaveAnalyserEditor {
     fn param_values_changed(&self) {}
 }
 
-/// Wrapper to make wry::WebContext sendable across threads.
-struct SendWebContext(wry::WebContext);
-unsafe impl Send for SendWebContext {}
-
 /// Handle returned from `spawn()`. When dropped, the editor closes.
 struct EditorHandle {
-    _thread: Option<thread::JoinHandle<()>>,
-    _webview: Option<Arc<Mutex<SendWebView>>>,
-    /// Must outlive the webview.
-    _web_context: Option<SendWebContext>,
+    /// Editor thread (Linux/macOS), joined on drop
+    thread: Option<thread::JoinHandle<()>>,
+    /// Webview on the host's UI thread (Windows), destroyed on drop
+    #[cfg(target_os = "windows")]
+    webview: Option<OwnedWebViewId>,
     running: Arc<AtomicBool>,
+    lifecycle: Arc<EditorLifecycle>,
+    /// The editor's route on the bus; dropping it disconnects the drainer
+    _subscription: Subscription<AudioPacket>,
 }
 
 impl Drop for EditorHandle {
     fn drop(&mut self) {
         debug_log("EditorHandle dropped, closing editor");
         self.running.store(false, Ordering::Relaxed);
+        #[cfg(target_os = "windows")]
+        if let Some(webview) = self.webview.take() {
+            webview.close();
+        }
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        self.lifecycle.closed();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::suite_commands::CommandAck;
+    use crate::websocket::WebSocketClient;
+
+    #[test]
+    fn test_analyser_url() {
+        assert_eq!(analyser_url(None), ANALYSER_URL);
+        assert_eq!(analyser_url(Some("abc")), format!("{}?token=abc", ANALYSER_URL));
+    }
+
+    #[test]
+    fn test_navigation_target() {
+        let logged_out = analyser_url(None);
+        let logged_in = analyser_url(Some("abc"));
+
+        // Same state: stay on the current document.
+        assert_eq!(navigation_target(&logged_out, None), None);
+        assert_eq!(navigation_target(&logged_in, Some("abc")), None);
+
+        // Login, token rotation and logout all navigate.
+        assert_eq!(navigation_target(&logged_out, Some("abc")), Some(logged_in.clone()));
+        assert_eq!(
+            navigation_target(&logged_in, Some("def")),
+            Some(analyser_url(Some("def")))
+        );
+        assert_eq!(navigation_target(&logged_in, None), Some(logged_out));
+    }
+
+    #[test]
+    fn test_host_audio_update() {
+        let mut last = None;
+        // The first poll always publishes, then only changes do
+        assert!(host_audio_update(0, &mut last).unwrap().contains("onHostAudio(null)"));
+        assert_eq!(host_audio_update(0, &mut last), None);
+
+        let stale = host_audio_update(800, &mut last).unwrap();
+        assert!(stale.contains(r#"onHostAudio("no audio from host")"#), "{}", stale);
+        assert_eq!(host_audio_update(1800, &mut last), None, "rising staleness is no change");
+
+        assert!(host_audio_update(0, &mut last).unwrap().contains("onHostAudio(null)"));
+    }
+
+    #[test]
+    fn test_editor_payload_script() {
+        let js = editor_payload_script(r#"{"type":"status","seq":0,"data":null}"#);
+        assert!(js.ends_with(r#"({"type":"status","seq":0,"data":null})"#), "{}", js);
+        assert!(js.contains("window.__onEditorPayload(p)") && js.contains("window.__onAudioPacket(p.data)"));
+
+        let js = editor_waveform_script(r#"{"seq":0,"data":null}"#);
+        assert_eq!(js, r#"window.__onEditorWaveform && window.__onEditorWaveform({"seq":0,"data":null})"#);
+    }
+
+    #[test]
+    fn test_bridge_update() {
+        let mut last = None;
+        let js = bridge_update(ConnectionStatus::default(), &mut last).unwrap();
+        assert!(js.contains("window.__onBridgeStatus("), "{}", js);
+        assert!(js.contains(r#""connection":"disconnected","address":null,"last_error":null"#), "{}", js);
+
+        let connected = ConnectionStatus {
+            connection: ConnectionState::Connected,
+            address: Some("127.0.0.1:9847".to_string()),
+            ..Default::default()
+        };
+        let js = bridge_update(connected.clone(), &mut last).unwrap();
+        assert!(js.contains(r#""connection":"connected","address":"127.0.0.1:9847""#), "{}", js);
+        let slower = ConnectionStatus { round_trip_us: Some(900), ..connected.clone() };
+        assert_eq!(bridge_update(slower, &mut last), None, "the latency moving is no change");
+
+        let failed = ConnectionStatus { last_error: Some("send failed".to_string()), ..connected };
+        assert!(bridge_update(failed.clone(), &mut last).unwrap().contains(r#""last_error":"send failed""#));
+        let warned = ConnectionStatus { port_warning: Some("port is used".to_string()), ..failed };
+        assert!(bridge_update(warned, &mut last).unwrap().contains(r#""port_warning":"port is used""#));
+    }
+
+    #[test]
+    fn test_eco_mode_update() {
+        let params = HardwaveAnalyserParams::default();
+        let mut last = None;
+        assert!(eco_mode_update(&params, &mut last).unwrap().contains("onEcoMode(false)"));
+        assert_eq!(eco_mode_update(&params, &mut last), None);
+    }
+
+    #[test]
+    fn test_setup_update() {
+        let mut last = None;
+        let fresh = SetupState::compose(false, false, true, true);
+        let js = setup_update(fresh, &mut last).unwrap();
+        assert!(js.contains(r#""step":"start_suite""#), "{}", js);
+        assert!(js.contains(r#""error":null"#), "{}", js);
+        assert_eq!(setup_update(fresh, &mut last), None);
+
+        let connected = SetupState::compose(true, false, true, false);
+        assert!(setup_update(connected, &mut last).unwrap().contains(r#""step":"log_in""#));
+    }
+
+    #[test]
+    fn test_packet_key_script() {
+        let encryption = PacketEncryption::default();
+        let js = packet_key_script(&encryption, Some("no key entered"));
+        assert!(js.ends_with(r#"onPacketKey({"enabled":false,"error":"no key entered","key_id":null})"#), "{}", js);
+
+        let key = PacketKey::parse(&"2a".repeat(32)).unwrap();
+        let id = format!("{:02x}", key.id());
+        encryption.set(Some(key));
+        let js = packet_key_script(&encryption, None);
+        assert!(js.contains(&format!(r#""key_id":"{}""#, id)) && js.contains(r#""enabled":true"#), "{}", js);
+        assert!(!js.contains(&"2a".repeat(4)), "the key leaked: {}", js);
+    }
+
+    #[test]
+    fn test_tls_script() {
+        use crate::tls::TlsError;
+
+        let tls = TlsControl::default();
+        tls.set_enabled(true);
+        let fingerprint = "ab".repeat(32);
+        tls.attempted(Some(&TlsError::Untrusted { host: "127.0.0.1".to_string(), fingerprint: fingerprint.clone() }));
+        let js = tls_script(&tls, None);
+        assert!(
+            js.ends_with(&format!(r#"onTls({{"enabled":true,"error":null,"untrusted":"{}"}})"#, fingerprint)),
+            "{}",
+            js
+        );
+    }
+
+    #[test]
+    fn test_suite_command_acks_update() {
+        let suite_commands = SuiteCommands::default();
+        assert_eq!(suite_command_acks_update(&suite_commands), None);
+
+        suite_commands.acknowledged(CommandAck { seq: 1, ok: true, error: None });
+        suite_commands.acknowledged(CommandAck { seq: 2, ok: false, error: Some("busy".to_string()) });
+        let js = suite_command_acks_update(&suite_commands).unwrap();
+        let calls: Vec<&str> = js.split("; ").collect();
+        assert_eq!(calls.len(), 2, "{}", js);
+        assert!(calls[0].contains(r#""seq":1"#) && calls[0].contains(r#""state":"ok""#), "{}", js);
+        assert!(calls[1].contains(r#""state":"failed""#) && calls[1].contains(r#""error":"busy""#), "{}", js);
+        assert_eq!(suite_command_acks_update(&suite_commands), None, "each answer is passed on once");
+
+        let rejected = suite_command_script(None, "rejected", Some("unknown command"));
+        assert!(rejected.contains(r#""seq":null"#), "{}", rejected);
+    }
+
+    /// Wait up to `within` for `thread` to finish
+    fn finishes(thread: &thread::JoinHandle<()>, within: Duration) -> bool {
+        let deadline = std::time::Instant::now() + within;
+        while !thread.is_finished() && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        thread.is_finished()
+    }
+
+    fn packet_server(running: &Arc<AtomicBool>, idle_timeout: Duration) -> (u16, thread::JoinHandle<()>) {
+        let (_packet_tx, packet_rx) = crossbeam_channel::bounded::<Arc<AudioPacket>>(4);
+        packet_server_fed_by(packet_rx, running, idle_timeout)
+    }
+
+    fn packet_server_fed_by(
+        packet_rx: Receiver<Arc<AudioPacket>>,
+        running: &Arc<AtomicBool>,
+        idle_timeout: Duration,
+    ) -> (u16, thread::JoinHandle<()>) {
+        let display = DisplayHints {
+            instance_id: "3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34".to_string(),
+            channel_labels: Some(["Kick".to_string(), "Snare".to_string()]),
+            ..Default::default()
+        };
+        start_packet_server(
+            packet_rx,
+            Arc::clone(running),
+            Arc::new(MemoryGauges::default()),
+            Arc::new(ErrorStats::default()),
+            Arc::new(ClickStats::default()),
+            Arc::new(Mutex::new(SpectrogramHistory::new())),
+            Arc::new(Mutex::new(display)),
+            WebSocketClient::new().connection_stats(),
+            idle_timeout,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_taken_port_is_a_bind_error() {
+        let taken = bind_loopback(0).unwrap();
+        let port = taken.local_addr().unwrap().port();
+        assert!(matches!(
+            bind_loopback(port),
+            Err(HardwaveError::Transport(TransportError::Bind(_)))
+        ));
+    }
+
+    #[test]
+    fn test_rebind_keeps_the_port() {
+        let before = bind_loopback(0).unwrap();
+        let port = before.local_addr().unwrap().port();
+        drop(before);
+        let running = AtomicBool::new(true);
+        let after = rebind_loopback(port, &running).unwrap();
+        assert_eq!(after.local_addr().unwrap().port(), port);
+
+        // Taken by someone else, and the editor closes while waiting
+        running.store(false, Ordering::Relaxed);
+        assert!(rebind_loopback(port, &running).is_none());
+    }
+
+    #[test]
+    fn test_drainer_stops_when_plugin_is_gone() {
+        let (packet_tx, packet_rx) = crossbeam_channel::bounded::<Arc<AudioPacket>>(4);
+        let latest = Arc::new(Mutex::new(EditorFeed::default()));
+        let spectrogram = Arc::new(Mutex::new(SpectrogramHistory::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let drainer = {
+            let (latest, spectrogram, running) =
+                (Arc::clone(&latest), Arc::clone(&spectrogram), Arc::downgrade(&running));
+            thread::spawn(move || drain_packets(&packet_rx, &latest, &spectrogram, &running))
+        };
+
+        packet_tx.send(Arc::new(AudioPacket::fft(48000, 7))).unwrap();
+        drop(packet_tx);
+
+        // `running` is still set; only the sender went away
+        assert!(finishes(&drainer, Duration::from_secs(2)));
+        assert!(running.load(Ordering::Relaxed));
+        assert_eq!(latest.lock().latest().map(|p| p.timestamp_ms), Some(7));
+    }
+
+    #[test]
+    fn test_ipc_panic_answers_nothing() {
+        use crate::error::Layer;
+        use crate::panics::hooks;
+
+        let errors = ErrorStats::default();
+        hooks::inject(Region::Ipc, Some(thread::current().id()));
+        assert_eq!(reply_contained(&errors, || Some(errors_script(&errors))), None);
+        assert_eq!(errors.count(Layer::Panic), 1);
+
+        // The next message is handled as usual, and reports the panic
+        let reply = reply_contained(&errors, || Some(errors_script(&errors))).unwrap();
+        assert!(reply.contains(r#""panic":1"#) && reply.contains(r#""region":"ipc""#), "{}", reply);
+    }
+
+    #[test]
+    fn test_drainer_restarts_after_a_panic() {
+        use crate::panics::hooks;
+
+        let (packet_tx, packet_rx) = crossbeam_channel::bounded::<Arc<AudioPacket>>(4);
+        let latest = Arc::new(Mutex::new(EditorFeed::default()));
+        let spectrogram = Arc::new(Mutex::new(SpectrogramHistory::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let drainer = {
+            let (latest, spectrogram, running) =
+                (Arc::clone(&latest), Arc::clone(&spectrogram), Arc::downgrade(&running));
+            threads::spawn_restarting("hardwave-drain", Priority::Streaming, Region::Drainer, move || {
+                drain_packets(&packet_rx, &latest, &spectrogram, &running)
+            })
+            .unwrap()
+        };
+        let restarts = panics::restarts(Region::Drainer);
+        hooks::inject(Region::Drainer, Some(drainer.thread().id()));
+
+        // The meters keep moving after the restart
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while panics::restarts(Region::Drainer) == restarts {
+            assert!(std::time::Instant::now() < deadline, "drainer never restarted");
+            thread::sleep(Duration::from_millis(2));
+        }
+        packet_tx.send(Arc::new(AudioPacket::fft(48000, 9))).unwrap();
+        while latest.lock().latest().map(|p| p.timestamp_ms) != Some(9) {
+            assert!(std::time::Instant::now() < deadline, "nothing drained after the restart");
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        running.store(false, Ordering::Relaxed);
+        assert!(finishes(&drainer, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_packet_server_answers_after_a_panic() {
+        use crate::panics::hooks;
+        use std::io::{Read, Write};
+
+        let get = |port: u16, target: &str| {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let running = Arc::new(AtomicBool::new(true));
+        let (port, server) = packet_server(&running, POLL_IDLE_TIMEOUT);
+        hooks::inject(Region::PacketServer, Some(server.thread().id()));
+        assert!(get(port, "/packet").starts_with("HTTP/1.1 500"));
+
+        // The server goes on, and the panic is in the error counts
+        let errors = get(port, "/errors");
+        assert!(errors.starts_with("HTTP/1.1 200"), "{}", errors);
+        let body: serde_json::Value = serde_json::from_str(errors.split_once("\r\n\r\n").unwrap().1).unwrap();
+        assert_eq!(body["panic"], 1);
+        assert!(body["last"].as_str().unwrap().contains("injected panic in packet_server"));
+        assert!(body["panics"].as_array().unwrap().iter().any(|count| count["region"] == "packet_server"));
+
+        running.store(false, Ordering::Relaxed);
+        assert!(finishes(&server, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_seq_increases_across_drainer_updates() {
+        let (packet_tx, packet_rx) = crossbeam_channel::bounded::<Arc<AudioPacket>>(4);
+        let latest = Arc::new(Mutex::new(EditorFeed::default()));
+        let spectrogram = Arc::new(Mutex::new(SpectrogramHistory::new()));
+        let running = Arc::new(AtomicBool::new(true));
+        let drainer = {
+            let (latest, spectrogram, running) =
+                (Arc::clone(&latest), Arc::clone(&spectrogram), Arc::downgrade(&running));
+            thread::spawn(move || drain_packets(&packet_rx, &latest, &spectrogram, &running))
+        };
+
+        let mut seqs = vec![latest.lock().seq()];
+        for timestamp_ms in 1..=20 {
+            packet_tx.send(Arc::new(AudioPacket::fft(48000, timestamp_ms))).unwrap();
+            thread::sleep(Duration::from_millis(2));
+            seqs.push(latest.lock().seq());
+        }
+        // Drained, it stays put until a heartbeat is due
+        while !packet_tx.is_empty() {
+            thread::sleep(Duration::from_millis(2));
+        }
+        thread::sleep(Duration::from_millis(20));
+        seqs.push(latest.lock().seq());
+        assert_eq!(latest.lock().seq(), 20);
+        assert!(seqs.windows(2).all(|w| w[0] <= w[1]), "{:?}", seqs);
+
+        running.store(false, Ordering::Relaxed);
+        assert!(finishes(&drainer, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_packet_route_versions() {
+        use std::io::{Read, Write};
+
+        let get = |port: u16, target: &str| {
+            let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+            stream.write_all(format!("GET {} HTTP/1.1\r\n\r\n", target).as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            let body = response.split_once("\r\n\r\n").unwrap().1.to_string();
+            serde_json::from_str::<serde_json::Value>(&body).unwrap()
+        };
+
+        let (packet_tx, packet_rx) = crossbeam_channel::bounded::<Arc<AudioPacket>>(4);
+        let running = Arc::new(AtomicBool::new(true));
+        let (port, server) = packet_server_fed_by(packet_rx, &running, POLL_IDLE_TIMEOUT);
+
+        assert_eq!(get(port, "/packet")["type"], "status");
+        assert_eq!(get(port, "/packet?v=1"), serde_json::Value::Null);
+
+        packet_tx.send(Arc::new(AudioPacket::fft(48000, 7))).unwrap();
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while get(port, "/packet")["type"] != "fft" && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(5));
+        }
+        let envelope = get(port, "/packet");
+        assert_eq!(envelope["seq"], 1);
+        assert_eq!(envelope["status"]["connection"], "disconnected");
+        let status = get(port, "/status");
+        assert_eq!(status["connection"], "disconnected");
+        assert_eq!(status["link"]["packets_sent"], 0);
+        assert_eq!(status["link"]["last_error"], serde_json::Value::Null);
+        assert_eq!(status["address"], serde_json::Value::Null, "nothing tried yet");
+        assert_eq!(status["last_error"], serde_json::Value::Null);
+        assert_eq!(envelope["status"]["address"], serde_json::Value::Null);
+        let config = get(port, "/config");
+        assert_eq!(config["instance_id"], "3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34");
+        assert_eq!(config["channel_labels"], serde_json::json!(["Kick", "Snare"]));
+        let legacy = get(port, "/packet?v=1");
+        assert_eq!(legacy["timestamp_ms"], 7);
+        assert_eq!(envelope["data"], legacy);
+        assert_eq!(get(port, "/"), legacy, "the old route still serves the flat shape");
+
+        running.store(false, Ordering::Relaxed);
+        assert!(finishes(&server, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_orphaned_packet_server_stops_when_idle() {
+        use std::io::{Read, Write};
+
+        let running = Arc::new(AtomicBool::new(true));
+        let (port, server) = packet_server(&running, Duration::from_millis(200));
+
+        // A poll is answered, and the instance ID identifies the owner
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).unwrap();
+        stream.write_all(b"GET /instance HTTP/1.1\r\n\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.contains(r#""instance_id":"3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34""#));
+        assert!(response.contains(r#""instance_tag":2239171581"#), "the tag its packets carry");
+
+        // The owner disappears without closing the server, as in a crash
+        drop(running);
+        assert!(finishes(&server, Duration::from_secs(2)));
+        assert!(std::net::TcpStream::connect(("127.0.0.1", port)).is_err());
+    }
+
+    #[test]
+    fn test_owned_packet_server_outlives_idle_timeout() {
+        let running = Arc::new(AtomicBool::new(true));
+        let (_, server) = packet_server(&running, Duration::from_millis(20));
+
+        // Nobody polls, but the editor still holds the switch
+        thread::sleep(Duration::from_millis(200));
+        assert!(!server.is_finished());
+
+        running.store(false, Ordering::Relaxed);
+        assert!(finishes(&server, Duration::from_secs(2)));
+    }
+
+    #[test]
+    fn test_packet_server_should_stop() {
+        let running = Arc::new(AtomicBool::new(true));
+        let timeout = Duration::from_secs(60);
+        assert!(!packet_server_should_stop(&running, Duration::ZERO, timeout));
+        assert!(packet_server_should_stop(&running, timeout, timeout));
+
+        let owner = Arc::clone(&running);
+        assert!(!packet_server_should_stop(&running, timeout, timeout));
+        owner.store(false, Ordering::Relaxed);
+        assert!(packet_server_should_stop(&running, Duration::ZERO, timeout));
     }
 }
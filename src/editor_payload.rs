@@ -0,0 +1,403 @@
+//! What the editor is sent of the analysis and the connection
+//!
+//! The packet server's `GET /packet`, and the Linux webview's
+//! `window.__onEditorPayload`, get one envelope:
+//!
+//! ```json
+//! { "type": "fft", "seq": 42, "data": { ...AudioPacket... },
+//!   "status": { "connection": "connected", "address": "127.0.0.1:9847", "last_error": null,
+//!               "latency": { ... }, "round_trip_us": 250, "port_warning": null } }
+//! ```
+//!
+//! - `fft`: `data` is the newest FFT packet, exactly as the Suite gets it.
+//! - `heartbeat`: no frame for `HEARTBEAT_INTERVAL` or longer, e.g. with the
+//!   transport stopped. `data` is `{"sample_rate", "timestamp_ms"}`, the
+//!   last frame's rate and its timestamp moved on by one interval per
+//!   heartbeat, so it reads on the same clock as the frames.
+//! - `status`: no frame yet; `data` is null.
+//!
+//! `seq` goes up by one for every frame and every heartbeat, and is what
+//! the page compares to tell a new payload from one it has already drawn.
+//! `status` is the connection to the Suite as of the request: where it
+//! connects, null before the first attempt, why the last connection failed
+//! or ended, if one did, and the latest ping's round trip, null before the
+//! first pong.
+//!
+//! In eco mode the page is updated at most every `ECO_UPDATE_INTERVAL`: the
+//! Windows poller slows down, and the Linux webview only gets the newest
+//! payload once the interval is up.
+//!
+//! `GET /packet?v=1`, and `GET /` as before, still serve the newest packet
+//! flat, or `null`, for pages from before the envelope. They go in the
+//! release after next.
+//!
+//! Waveform packets for the oscilloscope (see `envelope`) are kept apart,
+//! so a poll never misses a frame for them: `GET /waveform` serves
+//! `{"seq": 7, "data": { ...AudioPacket... }}`, the newest one numbered on
+//! its own counter, with `data` null before the first. The Linux webview
+//! gets the same through `window.__onEditorWaveform`. Each covers the input
+//! since the one before, so a page polling slower than the frames misses
+//! some; a scope scrolls on regardless.
+
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::protocol::{AudioPacket, PACKET_TYPE_FFT, PACKET_TYPE_WAVEFORM};
+use crate::websocket::{ConnectionStatus, HEARTBEAT_INTERVAL};
+
+/// Shortest gap between editor updates in eco mode, 30 fps
+pub const ECO_UPDATE_INTERVAL: Duration = Duration::from_millis(33);
+
+/// The `type` of an envelope
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PayloadType {
+    Fft,
+    Heartbeat,
+    Status,
+}
+
+/// `data` of a heartbeat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct HeartbeatData {
+    pub sample_rate: u32,
+    pub timestamp_ms: u64,
+}
+
+/// `data` of an envelope, null for `status`
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum PayloadData<'a> {
+    Fft(&'a AudioPacket),
+    Heartbeat(HeartbeatData),
+    None,
+}
+
+/// One `/packet` answer
+#[derive(Debug, Serialize)]
+pub struct EditorPayload<'a> {
+    #[serde(rename = "type")]
+    pub kind: PayloadType,
+    pub seq: u64,
+    pub data: PayloadData<'a>,
+    pub status: ConnectionStatus,
+}
+
+/// `GET /waveform`'s answer
+#[derive(Debug, Serialize)]
+pub struct WaveformPayload<'a> {
+    pub seq: u64,
+    pub data: Option<&'a AudioPacket>,
+}
+
+/// The newest frame, and the heartbeats since it, numbered, and the newest
+/// waveform packet. Fed by the packet drainer, read by the server.
+#[derive(Debug, Default)]
+pub struct EditorFeed {
+    seq: u64,
+    /// The newest FFT packet and when it arrived
+    latest: Option<(Arc<AudioPacket>, Instant)>,
+    /// Heartbeats since `latest`
+    heartbeats: u32,
+    /// Waveform packets so far, and the newest
+    waveform_seq: u64,
+    waveform: Option<Arc<AudioPacket>>,
+}
+
+impl EditorFeed {
+    /// Take a packet from the editor's subscription. Only FFT frames and
+    /// waveform packets are shown.
+    pub fn push(&mut self, packet: impl Into<Arc<AudioPacket>>, now: Instant) {
+        let packet = packet.into();
+        if packet.packet_type == PACKET_TYPE_WAVEFORM {
+            self.waveform_seq += 1;
+            self.waveform = Some(packet);
+            return;
+        }
+        if packet.packet_type != PACKET_TYPE_FFT {
+            return;
+        }
+        self.seq += 1;
+        self.latest = Some((packet, now));
+        self.heartbeats = 0;
+    }
+
+    /// Start a heartbeat if the frames stopped another interval ago. Called
+    /// whenever the channel is empty.
+    pub fn tick(&mut self, now: Instant) {
+        let Some((_, received)) = &self.latest else {
+            return;
+        };
+        let intervals = now.saturating_duration_since(*received).as_millis() / HEARTBEAT_INTERVAL.as_millis();
+        let intervals = intervals.min(u128::from(u32::MAX)) as u32;
+        if intervals > self.heartbeats {
+            self.seq += 1;
+            self.heartbeats = intervals;
+        }
+    }
+
+    /// The sequence number of the current payload
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// The newest frame as `/packet?v=1` serves it
+    #[cfg(any(target_os = "windows", test))]
+    pub fn latest(&self) -> Option<&AudioPacket> {
+        self.latest.as_ref().map(|(packet, _)| &**packet)
+    }
+
+    /// The current payload, with `status` merged in
+    pub fn payload(&self, status: ConnectionStatus) -> EditorPayload<'_> {
+        let (kind, data) = match &self.latest {
+            None => (PayloadType::Status, PayloadData::None),
+            Some((packet, _)) if self.heartbeats == 0 => (PayloadType::Fft, PayloadData::Fft(packet)),
+            Some((packet, _)) => (
+                PayloadType::Heartbeat,
+                PayloadData::Heartbeat(HeartbeatData {
+                    sample_rate: packet.sample_rate,
+                    timestamp_ms: packet.timestamp_ms
+                        + u64::from(self.heartbeats) * HEARTBEAT_INTERVAL.as_millis() as u64,
+                }),
+            ),
+        };
+        EditorPayload {
+            kind,
+            seq: self.seq,
+            data,
+            status,
+        }
+    }
+
+    /// The current payload as JSON
+    pub fn payload_json(&self, status: ConnectionStatus) -> String {
+        serde_json::to_string(&self.payload(status)).unwrap_or_else(|_| "null".to_string())
+    }
+
+    /// The newest frame as JSON in the legacy flat shape, or `null`
+    #[cfg(any(target_os = "windows", test))]
+    pub fn legacy_json(&self) -> String {
+        self.latest()
+            .and_then(|packet| serde_json::to_string(packet).ok())
+            .unwrap_or_else(|| "null".to_string())
+    }
+
+    /// The sequence number of the newest waveform packet
+    pub fn waveform_seq(&self) -> u64 {
+        self.waveform_seq
+    }
+
+    /// The newest waveform packet as `GET /waveform` serves it
+    pub fn waveform_json(&self) -> String {
+        let payload = WaveformPayload { seq: self.waveform_seq, data: self.waveform.as_deref() };
+        serde_json::to_string(&payload).unwrap_or_else(|_| "null".to_string())
+    }
+}
+
+/// Coalesces editor updates to one per `ECO_UPDATE_INTERVAL` in eco mode.
+/// The feed keeps only the newest payload, so one held back is replaced,
+/// never queued.
+#[derive(Debug, Default)]
+pub struct UpdateGate {
+    last: Option<Instant>,
+}
+
+impl UpdateGate {
+    /// Whether an update may go out at `now`; counts it if so
+    pub fn admit(&mut self, now: Instant, eco_mode: bool) -> bool {
+        let too_soon = self
+            .last
+            .is_some_and(|last| now.saturating_duration_since(last) < ECO_UPDATE_INTERVAL);
+        if eco_mode && too_soon {
+            return false;
+        }
+        self.last = Some(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::latency::LatencyStats;
+    use crate::websocket::ConnectionState;
+    use serde_json::{json, Value};
+
+    fn status() -> ConnectionStatus {
+        ConnectionStatus {
+            connection: ConnectionState::Connected,
+            address: Some("127.0.0.1:9847".to_string()),
+            last_error: Some("send failed".to_string()),
+            latency: LatencyStats { count: 12, p50_us: 180, p95_us: 420, max_us: 900 },
+            round_trip_us: Some(250),
+            port_warning: None,
+        }
+    }
+
+    fn parse(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_status_and_heartbeat_envelopes() {
+        let start = Instant::now();
+        let mut feed = EditorFeed::default();
+        assert_eq!(
+            parse(&feed.payload_json(status())),
+            json!({
+                "type": "status",
+                "seq": 0,
+                "data": null,
+                "status": {
+                    "connection": "connected",
+                    "address": "127.0.0.1:9847",
+                    "last_error": "send failed",
+                    "latency": { "count": 12, "p50_us": 180, "p95_us": 420, "max_us": 900 },
+                    "round_trip_us": 250,
+                    "port_warning": null
+                }
+            })
+        );
+        assert_eq!(feed.legacy_json(), "null");
+
+        feed.push(AudioPacket::fft(44100, 5000), start);
+        feed.tick(start + HEARTBEAT_INTERVAL * 2);
+        assert_eq!(
+            parse(&feed.payload_json(ConnectionStatus::default())),
+            json!({
+                "type": "heartbeat",
+                "seq": 2,
+                "data": { "sample_rate": 44100, "timestamp_ms": 7000 },
+                "status": {
+                    "connection": "disconnected",
+                    "address": null,
+                    "last_error": null,
+                    "latency": { "count": 0, "p50_us": 0, "p95_us": 0, "max_us": 0 },
+                    "round_trip_us": null,
+                    "port_warning": null
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_fft_envelope_wraps_the_legacy_shape() {
+        let mut feed = EditorFeed::default();
+        feed.push(AudioPacket::fft(48000, 7).with_bins(vec![-12.5; 4], vec![-30.0; 4]), Instant::now());
+
+        let envelope = parse(&feed.payload_json(status()));
+        let legacy = parse(&feed.legacy_json());
+        assert_eq!(envelope["type"], "fft");
+        assert_eq!(envelope["seq"], 1);
+        assert_eq!(envelope["data"], legacy);
+        assert_eq!(envelope["status"]["connection"], "connected");
+
+        let mut keys: Vec<&str> = envelope.as_object().unwrap().keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["data", "seq", "status", "type"]);
+
+        // The flat shape pages from before the envelope read
+        let mut fields: Vec<&str> = legacy.as_object().unwrap().keys().map(String::as_str).collect();
+        fields.sort_unstable();
+        assert_eq!(
+            fields,
+            [
+                "analysis_alignment", "analysis_slot", "band_activity", "band_count", "bar_phase", "bar_start_beats",
+                "beat_phase", "beat_phase_valid", "captured_us", "channel_bins", "channel_labels", "channel_mode",
+                "channel_peaks", "channel_rms", "clicks", "coarse_edges_hz", "command", "command_seq", "compact_levels",
+                "correlation", "disabled", "display_color", "display_label", "display_order", "eco_mode", "editor_open",
+                "editor_seq", "envelope_samples", "fft_size", "historical", "instance_id", "instance_tag", "left_bins",
+                "left_coarse", "left_envelope", "left_peak", "left_peak_bands", "left_peak_meter", "left_rms",
+                "left_rms_meter", "left_spectrum_raw", "left_true_peak", "left_wave", "link", "loop_end_samples",
+                "loop_policy", "loop_start_samples", "loop_wraps", "looping", "metrics", "metrics_opt_in",
+                "momentary_lufs", "non_finite_samples", "packet_type", "playing", "plugin_version", "pos_beats",
+                "pos_samples", "protocol_version", "recording", "rejected_transitions", "resumed_after_ms",
+                "right_bins", "right_coarse", "right_envelope", "right_peak", "right_peak_bands", "right_peak_meter",
+                "right_rms", "right_rms_meter", "right_spectrum_raw", "right_true_peak", "right_wave", "sample_rate",
+                "sequence", "short_term_lufs", "simulated", "stale_ms", "state_transitions", "stereo_warnings",
+                "streaming_suspended", "supported_version", "tempo_bpm", "time_sig_denominator", "time_sig_numerator",
+                "timestamp_ms", "timing", "zero_pad",
+            ]
+        );
+        assert_eq!(legacy["sample_rate"], 48000);
+        assert_eq!(legacy["timestamp_ms"], 7);
+        assert_eq!(legacy["left_bins"], json!([-12.5, -12.5, -12.5, -12.5]));
+    }
+
+    #[test]
+    fn test_seq_moves_on_once_per_frame_and_heartbeat() {
+        let start = Instant::now();
+        let mut feed = EditorFeed::default();
+        feed.tick(start + HEARTBEAT_INTERVAL * 3);
+        assert_eq!(feed.seq(), 0, "no heartbeats before the first frame");
+
+        feed.push(AudioPacket::fft(48000, 0), start);
+        feed.tick(start + Duration::from_millis(4));
+        assert_eq!(feed.seq(), 1);
+
+        // Only FFT frames count
+        feed.push(AudioPacket::new_heartbeat(0, 0), start);
+        assert_eq!(feed.seq(), 1);
+
+        feed.tick(start + HEARTBEAT_INTERVAL);
+        feed.tick(start + HEARTBEAT_INTERVAL + Duration::from_millis(4));
+        assert_eq!(feed.seq(), 2, "one heartbeat per interval");
+        feed.tick(start + HEARTBEAT_INTERVAL * 5);
+        assert_eq!(feed.seq(), 3, "a late tick is still one heartbeat");
+        assert!(matches!(
+            feed.payload(status()).data,
+            PayloadData::Heartbeat(HeartbeatData { timestamp_ms: 5000, .. })
+        ));
+
+        let resumed = start + HEARTBEAT_INTERVAL * 6;
+        feed.push(AudioPacket::fft(48000, 6000), resumed);
+        assert_eq!(feed.seq(), 4);
+        assert_eq!(feed.payload(status()).kind, PayloadType::Fft);
+    }
+
+    #[test]
+    fn test_waveform_packets_are_kept_apart() {
+        let start = Instant::now();
+        let mut feed = EditorFeed::default();
+        assert_eq!(parse(&feed.waveform_json()), json!({ "seq": 0, "data": null }));
+
+        feed.push(AudioPacket::fft(48000, 0), start);
+        let mut waveform = AudioPacket::new_waveform(48000, 0);
+        waveform.left_envelope = vec![-0.5, 0.5];
+        waveform.envelope_samples = 10;
+        feed.push(waveform, start);
+
+        // The frame is still the payload, and the waveform has its own count
+        assert_eq!((feed.seq(), feed.waveform_seq()), (1, 1));
+        assert_eq!(feed.payload(status()).kind, PayloadType::Fft);
+        let served = parse(&feed.waveform_json());
+        assert_eq!(served["seq"], 1);
+        assert_eq!(served["data"]["packet_type"], 8);
+        assert_eq!(served["data"]["left_envelope"], json!([-0.5, 0.5]));
+        assert_eq!(served["data"]["envelope_samples"], 10);
+    }
+
+    #[test]
+    fn test_eco_mode_coalesces_updates_to_30_fps() {
+        // A second of 60 fps polls, each with a new payload
+        let updates = |eco_mode: bool| {
+            let start = Instant::now();
+            let (mut feed, mut gate) = (EditorFeed::default(), UpdateGate::default());
+            let mut seqs = Vec::new();
+            for poll in 0..60u32 {
+                let now = start + Duration::from_micros(16_667) * poll;
+                feed.push(AudioPacket::fft(48000, u64::from(poll) * 17), now);
+                if gate.admit(now, eco_mode) {
+                    seqs.push(feed.seq());
+                }
+            }
+            seqs
+        };
+        assert_eq!(updates(false).len(), 60);
+        let eco = updates(true);
+        assert_eq!(eco.len(), 30);
+        // Each update is the newest payload; the ones between are skipped
+        assert_eq!(eco[..3], [1, 3, 5]);
+    }
+}
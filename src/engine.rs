@@ -0,0 +1,1747 @@
+//! The analysis, independent of the plugin API
+//!
+//! `AnalysisEngine` holds everything between the host's sample buffers and a
+//! finished frame: decimation and resampling, the ring buffers, both FFTs, levels, meter
+//! ballistics, the send cadence, the coarse bands, their activity and
+//! smoothing, and the stereo checks. The plugin feeds it one
+//! block at a time and turns each frame into packets; anything else that has
+//! audio (tests, the standalone tool, an offline analyser) can do the same
+//! without a host.
+//!
+//! Per block on the audio thread: `set_settings`, `resume_after`,
+//! `push_samples`, `push_reference`, `push_channels`, then `poll_frame`. At
+//! most one frame comes out per block, once `samples_per_send` samples have
+//! gone in since the last one and the history is full. None of these
+//! allocate except for the frame itself.
+
+use serde::{Deserialize, Serialize};
+
+use crate::activity::BandActivity;
+use crate::analysis::{AnalysisConfig, AnalysisState, ConfigPipeline};
+use crate::band_smoothing::BandSmoother;
+use crate::bands;
+use crate::clicks::{ClickDetector, ClickReport, ClickTally};
+use crate::envelope::{Envelope, EnvelopeMeter};
+use crate::fft::FftProcessor;
+use crate::latency;
+use crate::loudness::LoudnessMeter;
+use crate::memory::{Buffer, MemoryGauges};
+use crate::metadata::{self, Metadata};
+use crate::meter::{BandPeaks, ChannelMeter};
+use crate::params::{AnalysisAlignment, ChannelMode, CoarseMapping, RmsIntegration, Weighting, WindowType, ZeroPad};
+use crate::polarity::{self, StereoReport, StereoWatch};
+use crate::protocol::{AudioPacket, ChannelLevels, WAVE_SIZE};
+use crate::reference::ReferenceFrame;
+use crate::self_check::{Inconsistency, SelfCheck};
+use crate::simulate::InputSimulator;
+use crate::subscriptions::{Category, CategorySet};
+use crate::surround::{SurroundFrame, MAX_CHANNELS};
+use crate::true_peak::TruePeakMeter;
+use crate::units::SampleGuard;
+use crate::weighting::WeightingFilter;
+
+/// How the engine analyses, read from the parameters every block. Missing
+/// fields in a saved slot read as the defaults.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EngineSettings {
+    pub full_bandwidth: bool,
+    pub zero_pad: ZeroPad,
+    pub window: WindowType,
+    /// Analyse at `resampler::NORMALIZED_RATE` whatever the host rate
+    pub normalized_rate: bool,
+    /// Half the analysis rate and a 2048-point FFT. Not saved with a slot:
+    /// it follows the parameter whichever slot is active.
+    #[serde(skip)]
+    pub eco_mode: bool,
+    pub channel_mode: ChannelMode,
+    /// Applied to the analysis feed only, see `weighting`
+    pub weighting: Weighting,
+    pub alignment: AnalysisAlignment,
+    pub coarse_mapping: CoarseMapping,
+    pub coarse_bands: usize,
+    /// Work out how much each coarse band is moving, see `activity`
+    pub band_activity: bool,
+    /// Look for one channel being a delayed copy of the other, see
+    /// `polarity`
+    pub delay_check: bool,
+    /// Time constants of the coarse bands' smoothing, ms; 0 is raw, see
+    /// `band_smoothing`
+    pub band_attack: f32,
+    pub band_release: f32,
+    /// Seconds and dB/s of the coarse bands' peak hold, see `meter`
+    pub band_peak_hold: f32,
+    pub band_peak_decay: f32,
+    /// Seconds
+    pub peak_hold: f32,
+    /// dB/s
+    pub peak_release: f32,
+    pub rms_integration: RmsIntegration,
+}
+
+impl Default for EngineSettings {
+    /// The parameters' defaults
+    fn default() -> Self {
+        Self {
+            full_bandwidth: false,
+            zero_pad: ZeroPad::Off,
+            window: WindowType::Hann,
+            normalized_rate: false,
+            eco_mode: false,
+            channel_mode: ChannelMode::LeftRight,
+            weighting: Weighting::Z,
+            alignment: AnalysisAlignment::WindowEndsAtSend,
+            coarse_mapping: CoarseMapping::EqualLogWidth,
+            coarse_bands: 8,
+            band_activity: false,
+            delay_check: false,
+            band_attack: 0.0,
+            band_release: 0.0,
+            band_peak_hold: 1.5,
+            band_peak_decay: 20.0,
+            peak_hold: 1.5,
+            peak_release: 20.0,
+            rms_integration: RmsIntegration::Vu,
+        }
+    }
+}
+
+/// One analysed window, everything an FFT packet carries except its timestamp
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnalysisFrame {
+    /// Sample rate the FFT saw, after decimation and resampling
+    pub analysis_rate: f32,
+    /// Bins per unpadded bin; bin `i` is `i * analysis_rate / (fft_size *
+    /// zero_pad)` Hz
+    pub zero_pad: usize,
+    /// Samples the FFT windowed, smaller in eco mode
+    pub fft_size: usize,
+    pub eco_mode: bool,
+    pub left_bins: Vec<f32>,
+    pub right_bins: Vec<f32>,
+    /// Raw peak and RMS of the window
+    pub left_levels: ChannelLevels,
+    pub right_levels: ChannelLevels,
+    /// The same after meter ballistics
+    pub left_meters: ChannelLevels,
+    pub right_meters: ChannelLevels,
+    pub left_wave: Vec<f32>,
+    pub right_wave: Vec<f32>,
+    pub left_coarse: Vec<f32>,
+    pub right_coarse: Vec<f32>,
+    pub coarse_edges: Vec<f32>,
+    /// Held peaks of the raw coarse bands
+    pub left_peak_bands: Vec<f32>,
+    pub right_peak_bands: Vec<f32>,
+    /// Activity of each coarse band, 0..=1; empty unless `band_activity`
+    pub band_activity: Vec<f32>,
+    pub alignment: AnalysisAlignment,
+    /// How long before the frame was made the interval it describes ended
+    pub delay_ms: u64,
+    /// Host samples from the window's last sample to the end of the block
+    /// the frame came out after; 0 unless the interval is longer than the
+    /// window
+    pub window_lag: usize,
+    /// `latency::now_us` when the window's last sample was buffered
+    pub captured_us: u64,
+    /// NaN/Inf input samples replaced with silence since the last frame
+    pub non_finite_samples: u32,
+    /// Correlation over the interval and any warning it raised, see
+    /// `polarity`
+    pub stereo: StereoReport,
+    /// Correlation of the left and right window, -1..=1; +1 for a mono
+    /// input
+    pub correlation: f32,
+    /// BS.1770 loudness of the input over the last 400 ms and 3 s, LUFS
+    pub momentary_lufs: f32,
+    pub short_term_lufs: f32,
+    /// Largest true peak of each channel since the last frame, dBTP
+    pub left_true_peak: f32,
+    pub right_true_peak: f32,
+    /// Min/max envelope of the input since the last frame, for its waveform
+    /// packet; not part of the FFT packet
+    pub envelope: Envelope,
+    /// Some of the window was synthesised, see `simulate`
+    pub simulated: bool,
+    /// The sidechain's spectrum over the same window, for its reference
+    /// packet; `None` unless Reference Analysis is on and the spectrum is
+    /// wanted. Not part of the FFT packet.
+    pub reference: Option<ReferenceFrame>,
+    /// Every channel of a surround input over the same window, for its
+    /// channels packet; `None` for mono and stereo, and when neither the
+    /// spectrum nor the levels are wanted. Not part of the FFT packet.
+    pub surround: Option<SurroundFrame>,
+}
+
+impl AnalysisFrame {
+    /// The FFT packet for this frame. The caller picks the clock; the plugin
+    /// stamps frames with when their interval ended.
+    pub fn into_packet(self, timestamp_ms: u64) -> AudioPacket {
+        let mut packet = AudioPacket::fft(self.analysis_rate as u32, timestamp_ms)
+            .with_bins(self.left_bins, self.right_bins)
+            .with_levels(self.left_levels, self.right_levels)
+            .with_meters(self.left_meters, self.right_meters)
+            .with_wave(self.left_wave, self.right_wave)
+            .with_coarse(self.left_coarse, self.right_coarse, self.coarse_edges)
+            .with_peak_bands(self.left_peak_bands, self.right_peak_bands);
+        packet.captured_us = self.captured_us;
+        packet.analysis_alignment = self.alignment.wire_id();
+        packet.non_finite_samples = self.non_finite_samples;
+        packet.zero_pad = self.zero_pad as u8;
+        packet.eco_mode = self.eco_mode;
+        packet.band_activity = self.band_activity;
+        packet.simulated = self.simulated;
+        packet.correlation = self.correlation;
+        packet.momentary_lufs = self.momentary_lufs;
+        packet.short_term_lufs = self.short_term_lufs;
+        packet.left_true_peak = self.left_true_peak;
+        packet.right_true_peak = self.right_true_peak;
+        packet
+    }
+}
+
+/// Turns blocks of stereo samples into analysis frames
+pub struct AnalysisEngine {
+    /// FFTs, decimators and sample buffers for the current configuration
+    analysis: Box<AnalysisState>,
+
+    /// Rebuilds `analysis` off the audio thread when the bandwidth or
+    /// padding changes
+    config_pipeline: ConfigPipeline,
+
+    /// Host sample rate
+    sample_rate: f32,
+
+    settings: EngineSettings,
+
+    /// Meter ballistics for left channel
+    meter_left: ChannelMeter,
+
+    /// Meter ballistics for right channel
+    meter_right: ChannelMeter,
+
+    /// Replaces NaN/Inf input before it reaches any analysis state
+    sample_guard: SampleGuard,
+
+    /// The weighting on each channel of the analysis feed, at the host rate
+    weighting_left: WeightingFilter,
+    weighting_right: WeightingFilter,
+
+    /// Analyse the sidechain, see `reference`
+    reference: bool,
+    /// The guard and weighting of the sidechain; its replaced samples
+    /// aren't reported
+    reference_guard: SampleGuard,
+    reference_weighting_left: WeightingFilter,
+    reference_weighting_right: WeightingFilter,
+
+    /// Frames a second, see `set_update_rate`
+    update_rate: u32,
+
+    /// Channels of the main input, see `set_channels`
+    channels: usize,
+    /// The guard and weighting of each channel of a surround input; empty
+    /// for mono and stereo
+    channel_guard: SampleGuard,
+    channel_weighting: Vec<WeightingFilter>,
+
+    /// Hops accumulated this interval
+    hops_in_interval: usize,
+
+    /// Samples since the last hop FFT
+    samples_since_hop: usize,
+
+    /// Samples since the last frame
+    samples_since_send: usize,
+
+    /// Left/right consistency checks, with the `paranoid` setting on
+    self_check: Option<Box<SelfCheck>>,
+
+    /// Click detectors on the input, at the host rate
+    clicks_left: ClickDetector,
+    clicks_right: ClickDetector,
+
+    /// Clicks since the last `take_clicks`
+    click_tally: ClickTally,
+
+    /// Polarity and delayed-copy checks on the input, at the host rate
+    stereo: StereoWatch,
+
+    /// Loudness, true peaks and envelope of the input, at the host rate
+    loudness: LoudnessMeter,
+    true_peak: TruePeakMeter,
+    envelope: EnvelopeMeter,
+
+    /// How much the coarse bands moved over the last frames
+    activity: BandActivity,
+    /// Attack and release on the coarse bands the frames carry
+    band_smoothing: BandSmoother,
+    /// Peak hold on the coarse bands
+    band_peaks: BandPeaks,
+
+    /// What frames carry; the rest isn't computed
+    outputs: CategorySet,
+
+    /// How often the costly parts of a frame ran
+    work: WorkCounts,
+
+    /// Synthesises input after silence, if the developer setting is on
+    simulator: Option<InputSimulator>,
+
+    /// Analysis samples buffered since the last synthesised one; `MAX`
+    /// when none is
+    since_synthetic: usize,
+
+    /// The last block was one channel passed as both
+    mono_input: bool,
+}
+
+/// Frames whose costly parts were computed, since the engine was made
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WorkCounts {
+    /// With FFTs and coarse bands
+    pub spectra: u64,
+    /// With the oscilloscope waveform copied out
+    pub waveforms: u64,
+}
+
+impl AnalysisEngine {
+    /// Allocates everything; call this off the audio thread
+    pub fn new(config: AnalysisConfig) -> Self {
+        Self {
+            analysis: Box::new(AnalysisState::build(config)),
+            config_pipeline: ConfigPipeline::new(),
+            sample_rate: config.sample_rate,
+            settings: EngineSettings {
+                full_bandwidth: config.full_bandwidth,
+                zero_pad: config.zero_pad,
+                normalized_rate: config.normalized_rate,
+                eco_mode: config.eco_mode,
+                ..EngineSettings::default()
+            },
+            meter_left: ChannelMeter::default(),
+            meter_right: ChannelMeter::default(),
+            sample_guard: SampleGuard::default(),
+            weighting_left: WeightingFilter::new(Weighting::Z, config.sample_rate),
+            weighting_right: WeightingFilter::new(Weighting::Z, config.sample_rate),
+            reference: config.reference,
+            reference_guard: SampleGuard::default(),
+            reference_weighting_left: WeightingFilter::new(Weighting::Z, config.sample_rate),
+            reference_weighting_right: WeightingFilter::new(Weighting::Z, config.sample_rate),
+            update_rate: config.update_rate,
+            channels: config.channels,
+            channel_guard: SampleGuard::default(),
+            channel_weighting: channel_weighting(config.channels, Weighting::Z, config.sample_rate),
+            hops_in_interval: 0,
+            samples_since_hop: 0,
+            samples_since_send: 0,
+            self_check: None,
+            clicks_left: ClickDetector::new(),
+            clicks_right: ClickDetector::new(),
+            click_tally: ClickTally::default(),
+            stereo: StereoWatch::new(),
+            loudness: LoudnessMeter::new(config.sample_rate),
+            true_peak: TruePeakMeter::default(),
+            envelope: EnvelopeMeter::new(config.sample_rate),
+            activity: BandActivity::default(),
+            band_smoothing: BandSmoother::default(),
+            band_peaks: BandPeaks::default(),
+            outputs: CategorySet::ALL,
+            work: WorkCounts::default(),
+            simulator: None,
+            since_synthetic: usize::MAX,
+            mono_input: false,
+        }
+    }
+
+    /// Start the worker that rebuilds the analysis state for bandwidth and
+    /// padding changes. Until it runs, `set_settings` keeps the current ones.
+    pub fn start(&mut self) {
+        self.config_pipeline.start();
+    }
+
+    /// Turn the left/right consistency checks on or off. Allocates; call
+    /// this off the audio thread.
+    pub fn set_paranoid(&mut self, paranoid: bool) {
+        if paranoid != self.self_check.is_some() {
+            self.self_check = paranoid.then(|| Box::new(SelfCheck::new()));
+        }
+    }
+
+    /// Audio thread, after `push_samples`, with the `paranoid` setting on:
+    /// check that both channels' state still agrees. A mismatch resets the
+    /// analysis, asks the config worker for a fresh state and is returned
+    /// for logging. Off, this does nothing. Never allocates.
+    pub fn check_consistency(&mut self) -> Result<(), Inconsistency> {
+        let Some(check) = &mut self.self_check else {
+            return Ok(());
+        };
+        let result = check.after_block(&mut self.analysis);
+        if result.is_err() {
+            self.reset();
+            self.config_pipeline.rebuild();
+        }
+        result
+    }
+
+    /// Audio thread, once per block: what frames should carry, from the
+    /// subscriptions. Without `Fft` the bins and coarse bands come out
+    /// empty and no FFT runs, with hops accumulated so far dropped; without
+    /// `Waveform` so does the waveform. Levels and meters always run, since
+    /// their ballistics need every frame. Returns true when the outputs
+    /// changed.
+    pub fn set_outputs(&mut self, outputs: CategorySet) -> bool {
+        if outputs == self.outputs {
+            return false;
+        }
+        if !outputs.contains(Category::Fft) && self.outputs.contains(Category::Fft) {
+            self.clear_hops();
+        }
+        self.outputs = outputs;
+        true
+    }
+
+    /// Audio thread, once per block before `set_settings`: analyse the
+    /// sidechain as well. Turning it on or off rebuilds the analysis state
+    /// like a bandwidth change; until the new state is in, frames carry
+    /// what the old one had.
+    pub fn set_reference(&mut self, on: bool) {
+        self.reference = on;
+    }
+
+    /// Audio thread, once per block before `set_settings`: frames a second,
+    /// from `remote::DEFAULT_UPDATE_RATE_HZ` to `remote::MAX_UPDATE_RATE_HZ`.
+    /// A change rebuilds the analysis state like a bandwidth change.
+    pub fn set_update_rate(&mut self, hz: u32) {
+        self.update_rate = hz;
+    }
+
+    /// The main input's channel count, from the negotiated layout; more
+    /// than two are analysed one by one too, see `push_channels`. Allocates;
+    /// call this off the audio thread, before `set_sample_rate`.
+    pub fn set_channels(&mut self, channels: usize) {
+        self.channels = channels.min(MAX_CHANNELS);
+        self.channel_weighting = channel_weighting(self.channels, self.settings.weighting, self.sample_rate);
+    }
+
+    /// Analyse synthesised input, seeded with `seed`, whenever the input
+    /// has been silent for a while; `None` never does (see `simulate`)
+    pub fn set_simulated_input(&mut self, seed: Option<u32>) {
+        self.simulator = seed.map(InputSimulator::new);
+    }
+
+    /// The last block was synthesised
+    #[cfg(test)]
+    pub fn simulating(&self) -> bool {
+        self.simulator.as_ref().is_some_and(InputSimulator::engaged)
+    }
+
+    pub fn work_counts(&self) -> WorkCounts {
+        self.work
+    }
+
+    /// The configuration the analysis state was built for
+    pub fn config(&self) -> AnalysisConfig {
+        self.analysis.config
+    }
+
+    /// What the hello packet says about the analysis, as it is now
+    pub fn metadata(&self) -> Metadata {
+        Metadata {
+            sample_rate: self.analysis.analysis_rate as u32,
+            fft_size: self.analysis.fft_size as u32,
+            zero_pad: self.analysis.config.zero_pad.factor() as u8,
+            band_count: bands::band_count(self.settings.coarse_mapping, self.settings.coarse_bands) as u8,
+            channel_mode: metadata::channel_mode(self.settings.channel_mode),
+        }
+    }
+
+    /// Rebuild for a new host sample rate, with the bandwidth and padding of
+    /// the last settings, so apply those first. Allocates; call this off the audio
+    /// thread, e.g. from the plugin's `initialize`.
+    pub fn set_sample_rate(&mut self, sample_rate: f32) {
+        self.sample_rate = sample_rate;
+        self.design_weighting();
+        self.loudness = LoudnessMeter::new(sample_rate);
+        self.true_peak.reset();
+        self.envelope = EnvelopeMeter::new(sample_rate);
+        *self.analysis = AnalysisState::build(self.wanted_config());
+        self.clear();
+    }
+
+    /// Audio thread, once per block before `push_samples`. A bandwidth or
+    /// padding change is built by the config worker and the frame in progress is dropped
+    /// once the new state is swapped in; so is the frame in progress when the
+    /// channel mode or weighting changes, so one window never mixes them.
+    /// Returns true when a new state was swapped in and its memory needs
+    /// reporting.
+    pub fn set_settings(&mut self, settings: EngineSettings) -> bool {
+        let mode_changed = settings.channel_mode != self.settings.channel_mode;
+        let weighting_changed = settings.weighting != self.settings.weighting;
+        self.settings = settings;
+        if weighting_changed {
+            self.design_weighting();
+        }
+
+        let wanted = self.wanted_config();
+        let swapped = self.config_pipeline.sync(&mut self.analysis, wanted);
+        if swapped || mode_changed || weighting_changed {
+            self.clear();
+        }
+        swapped
+    }
+
+    /// Audio thread, before `push_samples`, with the wall-clock ms since the
+    /// previous block. Whatever of the gap this block's `block_len` samples
+    /// don't cover is audio the host never delivered; if that is longer than
+    /// a window, the history spans the gap, so it is reset and warm-up starts
+    /// over. Returns true when it was.
+    pub fn resume_after(&mut self, gap_ms: u64, block_len: usize) -> bool {
+        let block_ms = block_len as f32 / self.sample_rate * 1000.0;
+        let window_ms = self.analysis.fft_size as f32 / self.analysis.analysis_rate * 1000.0;
+        let missing_ms = gap_ms as f32 - block_ms;
+        if missing_ms <= window_ms {
+            return false;
+        }
+        self.reset();
+        true
+    }
+
+    /// Feed one block. A mono input passes the same slice for both channels,
+    /// which counts its bad samples once. Long enough silence is replaced
+    /// with synthesised input when that is on.
+    pub fn push_samples(&mut self, left: &[f32], right: &[f32]) {
+        let mono = std::ptr::eq(left, right);
+        self.mono_input = mono;
+        let delay_check = self.settings.delay_check;
+        // Hops are only taken for a spectrum somebody wants
+        let average =
+            self.settings.alignment == AnalysisAlignment::AverageOfInterval && self.outputs.contains(Category::Fft);
+
+        if let Some(simulator) = &mut self.simulator {
+            if simulator.observe(left, right, self.sample_rate) {
+                self.mono_input = false;
+                for _ in 0..left.len() {
+                    let Some(simulator) = &mut self.simulator else { break };
+                    let (left, right) = simulator.next(self.sample_rate);
+                    self.loudness.push(left, right, false);
+                    self.true_peak.push(left, right, false);
+                    self.envelope.push(left, right, false);
+                    self.push_host_sample(left, right, average, true);
+                }
+                return;
+            }
+        }
+
+        for (&left, &right) in left.iter().zip(right) {
+            let left = self.sample_guard.clean(left);
+            let right = if mono { left } else { self.sample_guard.clean(right) };
+
+            if let Some(click) = self.clicks_left.process(left) {
+                self.click_tally.add(click);
+            }
+            if !mono {
+                if let Some(click) = self.clicks_right.process(right) {
+                    self.click_tally.add(click);
+                }
+                self.stereo.push(left, right, delay_check);
+            }
+            self.loudness.push(left, right, mono);
+            self.true_peak.push(left, right, mono);
+            self.envelope.push(left, right, mono);
+            self.push_host_sample(left, right, average, false);
+        }
+    }
+
+    /// Feed the sidechain's block, after `push_samples`; `None` when
+    /// nothing is routed to it, which empties its history. A mono sidechain
+    /// passes the same slice for both channels. Does nothing with Reference
+    /// Analysis off.
+    pub fn push_reference(&mut self, reference: Option<(&[f32], &[f32])>) {
+        let Some(state) = &mut self.analysis.reference else {
+            return;
+        };
+        let Some((left, right)) = reference else {
+            state.clear();
+            return;
+        };
+        let mono = std::ptr::eq(left, right);
+        let mode = self.settings.channel_mode;
+        for (&left, &right) in left.iter().zip(right) {
+            let left = self.reference_guard.clean(left);
+            let right = if mono { left } else { self.reference_guard.clean(right) };
+            let left = self.reference_weighting_left.process(left);
+            let right = self.reference_weighting_right.process(right);
+            let (Some(left), Some(right)) = (state.decimator_left.process(left), state.decimator_right.process(right))
+            else {
+                continue;
+            };
+            for (left, right) in state.resampler.process(left, right) {
+                let (left, right) = channels(mode, left, right);
+                state.buffer(left, right);
+            }
+        }
+        self.reference_guard.take();
+    }
+
+    /// Feed every channel of a surround block, after `push_samples` has had
+    /// their downmix. Does nothing for mono and stereo.
+    pub fn push_channels<C: AsRef<[f32]>>(&mut self, channels: &[C]) {
+        let Some(state) = &mut self.analysis.surround else {
+            return;
+        };
+        let count = state.channels();
+        if channels.len() < count {
+            return;
+        }
+        let len = channels[..count].iter().map(|channel| channel.as_ref().len()).min().unwrap_or(0);
+        let mut samples = [0.0; MAX_CHANNELS];
+        for i in 0..len {
+            for ((sample, channel), weighting) in samples.iter_mut().zip(channels).zip(&mut self.channel_weighting) {
+                *sample = weighting.process(self.channel_guard.clean(channel.as_ref()[i]));
+            }
+            state.push(&samples[..count]);
+        }
+        self.channel_guard.take();
+    }
+
+    /// Take one host-rate sample per channel towards the ring
+    fn push_host_sample(&mut self, left: f32, right: f32, average: bool, synthetic: bool) {
+        let (left, right) = (self.weighting_left.process(left), self.weighting_right.process(right));
+
+        // Decimate to the analysis rate (both channels produce in lockstep)
+        let (Some(left), Some(right)) = (
+            self.analysis.decimator_left.process(left),
+            self.analysis.decimator_right.process(right),
+        ) else {
+            return;
+        };
+
+        // Then resample to it, if the rate is normalised
+        for (left, right) in self.analysis.resampler.process(left, right) {
+            self.push_analysis_sample(left, right, average, synthetic);
+        }
+    }
+
+    /// Take one analysis-rate sample per channel into the ring
+    fn push_analysis_sample(&mut self, left: f32, right: f32, average: bool, synthetic: bool) {
+        let (left, right) = channels(self.settings.channel_mode, left, right);
+
+        // Add to buffers, keeping history_len samples
+        self.analysis.buffer(left, right);
+        self.since_synthetic = if synthetic { 0 } else { self.since_synthetic.saturating_add(1) };
+
+        self.samples_since_send += 1;
+
+        if average {
+            self.samples_since_hop += 1;
+            let fft_size = self.analysis.fft_size;
+            if self.samples_since_hop >= fft_size / 4 && self.analysis.buffer_left.len() >= fft_size {
+                self.accumulate_hop();
+            }
+        }
+    }
+
+    /// The next frame, if one is due: at the update rate, 20 Hz unless the
+    /// Suite asks for more, once the history is full
+    pub fn poll_frame(&mut self) -> Option<AnalysisFrame> {
+        if self.samples_since_send < self.analysis.samples_per_send
+            || self.analysis.buffer_left.len() < self.analysis.history_len
+        {
+            return None;
+        }
+        let frame = self.analyse();
+        self.samples_since_send = 0;
+        Some(frame)
+    }
+
+    /// Forget all audio, as after a transport jump: buffers, the interval in
+    /// progress, filter histories and meters
+    pub fn reset(&mut self) {
+        self.clear();
+        self.analysis.decimator_left.reset();
+        self.analysis.decimator_right.reset();
+        self.analysis.resampler.reset();
+        self.meter_left.reset();
+        self.meter_right.reset();
+        self.weighting_left.reset();
+        self.weighting_right.reset();
+        if let Some(reference) = &mut self.analysis.reference {
+            reference.reset();
+        }
+        self.reference_weighting_left.reset();
+        self.reference_weighting_right.reset();
+        if let Some(surround) = &mut self.analysis.surround {
+            surround.reset();
+        }
+        self.channel_weighting.iter_mut().for_each(WeightingFilter::reset);
+        // A seek is a jump in the audio but not a click in it
+        self.clicks_left.reset();
+        self.clicks_right.reset();
+        self.stereo.reset();
+        self.loudness.reset();
+        self.true_peak.reset();
+        self.envelope.reset();
+    }
+
+    /// The stereo warnings up now, `polarity` bits
+    pub fn stereo_warnings(&self) -> u8 {
+        self.stereo.warnings()
+    }
+
+    /// Audio thread, when the Suite dismisses the stereo warnings
+    pub fn dismiss_stereo_warnings(&mut self) {
+        self.stereo.dismiss();
+    }
+
+    /// Audio thread, after `push_samples`, with the wall-clock ms of the
+    /// block's end: the clicks since the last call, the worst one's time
+    /// counted back from `now_ms`. `None` without any.
+    pub fn take_clicks(&mut self, now_ms: u64) -> Option<ClickReport> {
+        let (count, worst) = self.click_tally.take()?;
+        let ago_samples = self.clicks_left.samples().saturating_sub(worst.sample);
+        let ago_ms = (ago_samples as f64 / f64::from(self.sample_rate) * 1000.0) as u64;
+        Some(ClickReport {
+            count,
+            worst_timestamp_ms: now_ms.saturating_sub(ago_ms),
+            worst_severity_db: worst.severity_db,
+        })
+    }
+
+    /// Update the gauges of the buffers the engine owns. Never allocates.
+    pub fn report_memory(&self, gauges: &MemoryGauges) {
+        self.analysis.report_memory(gauges);
+        gauges.set(Buffer::SelfCheck, self.self_check.as_ref().map_or(0, |check| check.heap_bytes()));
+    }
+
+    /// The configuration the analysis should run with right now
+    fn wanted_config(&self) -> AnalysisConfig {
+        AnalysisConfig {
+            sample_rate: self.sample_rate,
+            full_bandwidth: self.settings.full_bandwidth,
+            zero_pad: self.settings.zero_pad,
+            window: self.settings.window,
+            normalized_rate: self.settings.normalized_rate,
+            eco_mode: self.settings.eco_mode,
+            reference: self.reference,
+            channels: self.channels,
+            update_rate: self.update_rate,
+        }
+    }
+
+    /// Drop buffered audio and any partly accumulated interval
+    fn clear(&mut self) {
+        self.analysis.clear();
+        self.samples_since_send = 0;
+        self.since_synthetic = usize::MAX;
+        self.clear_hops();
+        self.activity.clear();
+        self.band_smoothing.clear();
+        self.band_peaks.clear();
+    }
+
+    /// Weighting filters for the current rate and setting, from silence
+    fn design_weighting(&mut self) {
+        self.weighting_left = WeightingFilter::new(self.settings.weighting, self.sample_rate);
+        self.weighting_right = WeightingFilter::new(self.settings.weighting, self.sample_rate);
+        self.reference_weighting_left = WeightingFilter::new(self.settings.weighting, self.sample_rate);
+        self.reference_weighting_right = WeightingFilter::new(self.settings.weighting, self.sample_rate);
+        for weighting in &mut self.channel_weighting {
+            *weighting = WeightingFilter::new(self.settings.weighting, self.sample_rate);
+        }
+    }
+
+    fn clear_hops(&mut self) {
+        self.analysis.power_left.fill(0.0);
+        self.analysis.power_right.fill(0.0);
+        self.hops_in_interval = 0;
+        self.samples_since_hop = 0;
+    }
+
+    /// Add the newest window's power spectrum to the interval average
+    fn accumulate_hop(&mut self) {
+        let start = self.analysis.buffer_left.len() - self.analysis.fft_size;
+        let analysis = &mut *self.analysis;
+        analysis.fft_left.accumulate_power(&analysis.buffer_left.as_slice()[start..], &mut analysis.power_left);
+        analysis.fft_right.accumulate_power(&analysis.buffer_right.as_slice()[start..], &mut analysis.power_right);
+        self.hops_in_interval += 1;
+        self.samples_since_hop = 0;
+    }
+
+    /// (window lag, interval delay) in samples for the current alignment.
+    ///
+    /// Centring a 4096-sample (or eco 2048) window on a shorter interval needs audio from
+    /// after the send, so the frame describes the interval that ended
+    /// `delay` samples ago instead. On intervals longer than the window (full
+    /// bandwidth at high rates) the window ends `lag` samples before the send.
+    fn alignment_offsets(&self, alignment: AnalysisAlignment) -> (usize, usize) {
+        let (samples_per_send, fft_size) = (self.analysis.samples_per_send, self.analysis.fft_size);
+        match alignment {
+            AnalysisAlignment::WindowCentredOnInterval => (
+                (samples_per_send / 2).saturating_sub(fft_size / 2),
+                (fft_size / 2).saturating_sub(samples_per_send / 2),
+            ),
+            _ => (0, 0),
+        }
+    }
+
+    /// Analyse the buffered window into a frame
+    fn analyse(&mut self) -> AnalysisFrame {
+        // The window's last sample was just buffered
+        let captured_us = latency::now_us();
+
+        let settings = self.settings;
+        let alignment = settings.alignment;
+        let (lag, delay) = self.alignment_offsets(alignment);
+        let end = self.analysis.buffer_left.len() - lag;
+        let fft_size = self.analysis.fft_size;
+        let window = end - fft_size..end;
+
+        let spectrum = self.outputs.contains(Category::Fft);
+
+        // The window ending at the send closes the interval's average
+        if spectrum && alignment == AnalysisAlignment::AverageOfInterval && self.samples_since_hop > 0 {
+            self.accumulate_hop();
+        }
+
+        // Process FFT for both channels → raw magnitude bins in dB
+        let (left_bins, right_bins) =
+            if !spectrum {
+                (Vec::new(), Vec::new())
+            } else if alignment == AnalysisAlignment::AverageOfInterval && self.hops_in_interval > 0 {
+                (
+                    FftProcessor::mean_power_db(&self.analysis.power_left, self.hops_in_interval),
+                    FftProcessor::mean_power_db(&self.analysis.power_right, self.hops_in_interval),
+                )
+            } else {
+                let analysis = &mut *self.analysis;
+                let rate = analysis.analysis_rate;
+                (
+                    analysis.fft_left.process(&analysis.buffer_left.as_slice()[window.clone()], rate),
+                    analysis.fft_right.process(&analysis.buffer_right.as_slice()[window.clone()], rate),
+                )
+            };
+        self.clear_hops();
+
+        // Coarse bands for hardware visualisers
+        let zero_pad = self.analysis.config.zero_pad.factor();
+        let (edges, mut left_coarse, mut right_coarse) = if spectrum {
+            let edges = bands::edges(settings.coarse_mapping, settings.coarse_bands);
+            let bin_hz = self.analysis.analysis_rate / (fft_size * zero_pad) as f32;
+            let left_coarse = bands::coarse_db(&left_bins, bin_hz, zero_pad, &edges);
+            let right_coarse = bands::coarse_db(&right_bins, bin_hz, zero_pad, &edges);
+            self.work.spectra += 1;
+            (edges, left_coarse, right_coarse)
+        } else {
+            (Vec::new(), Vec::new(), Vec::new())
+        };
+
+        // The sidechain over the same window, unsmoothed
+        let rate = self.analysis.analysis_rate;
+        let reference = match &mut self.analysis.reference {
+            Some(state) if spectrum => Some(state.frame(window.start, window.end, rate, zero_pad, &edges)),
+            _ => None,
+        };
+        // and every channel of a surround input, unpadded
+        let surround = match &mut self.analysis.surround {
+            Some(state) if spectrum || self.outputs.contains(Category::Levels) => {
+                Some(state.frame(window.start, window.end))
+            }
+            _ => None,
+        };
+
+        // Frames without the bands leave a gap the activity would span
+        let band_activity = if spectrum && settings.band_activity {
+            self.activity.update(&left_coarse, &right_coarse)
+        } else {
+            self.activity.clear();
+            Vec::new()
+        };
+
+        // Ballistics and smoothing advance by the time since the last frame;
+        // the peaks hold the raw bands
+        let dt = self.samples_since_send as f32 / self.analysis.analysis_rate;
+        let (left_peak_bands, right_peak_bands) = if spectrum {
+            let (hold, decay) = (settings.band_peak_hold, settings.band_peak_decay);
+            let peaks = self.band_peaks.update(&left_coarse, &right_coarse, dt, hold, decay);
+            let (attack_s, release_s) = (settings.band_attack / 1000.0, settings.band_release / 1000.0);
+            self.band_smoothing.update(&mut left_coarse, &mut right_coarse, dt, attack_s, release_s);
+            peaks
+        } else {
+            self.band_peaks.clear();
+            self.band_smoothing.clear();
+            (Vec::new(), Vec::new())
+        };
+
+        let stereo = self.stereo.finish_interval(self.sample_rate, settings.delay_check);
+        let (left_true_peak, right_true_peak) = self.true_peak.take();
+
+        // Calculate levels
+        let (left, right) = (self.analysis.buffer_left.as_slice(), self.analysis.buffer_right.as_slice());
+        let (left_peak, left_rms) = FftProcessor::calculate_levels(&left[window.clone()]);
+        let (right_peak, right_rms) = FftProcessor::calculate_levels(&right[window.clone()]);
+        let correlation = if self.mono_input {
+            1.0
+        } else {
+            let mid_side = settings.channel_mode == ChannelMode::MidSide;
+            polarity::window_correlation(&left[window.clone()], &right[window], mid_side)
+        };
+
+        // Meter ballistics
+        let (hold, release) = (settings.peak_hold, settings.peak_release);
+        let (attack_s, release_s) = settings.rms_integration.time_constants();
+        let left_peak_meter = self.meter_left.peak.update(left_peak, dt, hold, release);
+        let right_peak_meter = self.meter_right.peak.update(right_peak, dt, hold, release);
+        let left_rms_meter = self.meter_left.rms.update(left_rms, dt, attack_s, release_s);
+        let right_rms_meter = self.meter_right.rms.update(right_rms, dt, attack_s, release_s);
+
+        // Oscilloscope waveform: the last WAVE_SIZE samples of the ring
+        let waveform = self.outputs.contains(Category::Waveform);
+        if waveform {
+            self.work.waveforms += 1;
+        }
+        let wave = |buffer: &[f32]| {
+            if !waveform {
+                Vec::new()
+            } else if buffer.len() >= WAVE_SIZE {
+                buffer[buffer.len() - WAVE_SIZE..].to_vec()
+            } else {
+                vec![0.0_f32; WAVE_SIZE]
+            }
+        };
+
+        AnalysisFrame {
+            analysis_rate: self.analysis.analysis_rate,
+            zero_pad,
+            fft_size,
+            eco_mode: self.analysis.config.eco_mode,
+            left_bins,
+            right_bins,
+            left_levels: ChannelLevels::new(left_peak, left_rms),
+            right_levels: ChannelLevels::new(right_peak, right_rms),
+            left_meters: ChannelLevels::new(left_peak_meter, left_rms_meter),
+            right_meters: ChannelLevels::new(right_peak_meter, right_rms_meter),
+            left_wave: wave(self.analysis.buffer_left.as_slice()),
+            right_wave: wave(self.analysis.buffer_right.as_slice()),
+            left_coarse,
+            right_coarse,
+            coarse_edges: edges,
+            left_peak_bands,
+            right_peak_bands,
+            band_activity,
+            alignment,
+            delay_ms: (delay as f32 / self.analysis.analysis_rate * 1000.0) as u64,
+            window_lag: (lag as f64 * f64::from(self.sample_rate) / f64::from(self.analysis.analysis_rate)).round()
+                as usize,
+            captured_us,
+            non_finite_samples: self.sample_guard.take(),
+            stereo,
+            correlation,
+            momentary_lufs: self.loudness.momentary(),
+            short_term_lufs: self.loudness.short_term(),
+            left_true_peak,
+            right_true_peak,
+            envelope: self.envelope.take(),
+            simulated: self.since_synthetic < self.analysis.history_len,
+            reference,
+            surround,
+        }
+    }
+}
+
+/// A weighting filter per channel of a surround input, none for mono and
+/// stereo
+fn channel_weighting(channels: usize, weighting: Weighting, sample_rate: f32) -> Vec<WeightingFilter> {
+    if channels <= 2 {
+        return Vec::new();
+    }
+    (0..channels).map(|_| WeightingFilter::new(weighting, sample_rate)).collect()
+}
+
+/// One analysis-rate sample per channel as `mode` analyses it
+fn channels(mode: ChannelMode, left: f32, right: f32) -> (f32, f32) {
+    match mode {
+        ChannelMode::LeftRight => (left, right),
+        ChannelMode::MidSide => ((left + right) * 0.5, (left - right) * 0.5),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fft::{ECO_FFT_SIZE, FFT_SIZE};
+    use crate::protocol::Db;
+    use crate::self_check::FFT_CHECK_BLOCKS;
+    use crate::units::FLOOR_DB;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 48_000.0;
+    const BLOCK: usize = 480;
+
+    fn engine() -> AnalysisEngine {
+        AnalysisEngine::new(AnalysisConfig::default())
+    }
+
+    /// `len` samples of a sine on FFT bin `bin` at 48 kHz, starting at sample `from`
+    fn sine(bin: usize, amplitude: f32, from: usize, len: usize) -> Vec<f32> {
+        let hz = bin as f32 * RATE / FFT_SIZE as f32;
+        (from..from + len)
+            .map(|i| amplitude * (2.0 * PI * hz * i as f32 / RATE).sin())
+            .collect()
+    }
+
+    /// Push `blocks` blocks of `signal` and return the frames, with the index
+    /// of the block each came out after
+    fn run(engine: &mut AnalysisEngine, blocks: usize, signal: impl Fn(usize) -> (Vec<f32>, Vec<f32>)) -> Vec<(usize, AnalysisFrame)> {
+        let mut frames = Vec::new();
+        for block in 0..blocks {
+            let (left, right) = signal(block * BLOCK);
+            engine.push_samples(&left, &right);
+            if let Some(frame) = engine.poll_frame() {
+                frames.push((block, frame));
+            }
+        }
+        frames
+    }
+
+    fn silence(_: usize) -> (Vec<f32>, Vec<f32>) {
+        (vec![0.0; BLOCK], vec![0.0; BLOCK])
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut engine = engine();
+        // 48 kHz needs no decimation: 2400 samples per frame, 4096 of history
+        assert_eq!(engine.config(), AnalysisConfig::default());
+
+        // Nothing until a whole window is buffered, even though a frame
+        // interval has long passed
+        let mut pushed = 0;
+        while pushed + BLOCK < FFT_SIZE {
+            engine.push_samples(&[0.0; BLOCK], &[0.0; BLOCK]);
+            pushed += BLOCK;
+            assert!(engine.poll_frame().is_none(), "frame after {} samples", pushed);
+        }
+        engine.push_samples(&[0.0; BLOCK], &[0.0; BLOCK]);
+        assert!(engine.poll_frame().is_some());
+
+        // Reset starts the warm-up over
+        engine.reset();
+        engine.push_samples(&[0.0; 2400], &[0.0; 2400]);
+        assert!(engine.poll_frame().is_none());
+    }
+
+    #[test]
+    fn test_resume_after_a_gap_restarts_warm_up() {
+        let mut engine = engine();
+        run(&mut engine, 10, silence);
+        // 10 ms blocks arriving every 10 ms, or late by less than a window
+        // (85 ms at 48 kHz), keep the history
+        assert!(!engine.resume_after(10, BLOCK));
+        assert!(!engine.resume_after(80, BLOCK));
+        // Big blocks take as long to arrive as they last
+        assert!(!engine.resume_after(170, 8192));
+        // The next frame is on time: four blocks on, as in `test_cadence`
+        assert_eq!(run(&mut engine, 4, silence).len(), 1);
+
+        // A frozen track resumes after a second
+        assert!(engine.resume_after(1000, BLOCK));
+        assert!(run(&mut engine, 8, silence).is_empty(), "frame from audio before the gap");
+        let frames = run(&mut engine, 1, silence);
+        assert_eq!(frames.len(), 1, "warm-up never finished");
+    }
+
+    #[test]
+    fn test_cadence() {
+        let mut engine = engine();
+        let frames = run(&mut engine, 60, silence);
+        let blocks: Vec<usize> = frames.iter().map(|(block, _)| *block).collect();
+        // First after 4320 samples (the first block past 4096), then every
+        // 2400 samples: five blocks
+        assert_eq!(blocks, [8, 13, 18, 23, 28, 33, 38, 43, 48, 53, 58]);
+
+        // One frame per block at most, however long the block
+        engine.push_samples(&[0.0; 3 * 2400], &[0.0; 3 * 2400]);
+        assert!(engine.poll_frame().is_some());
+        assert!(engine.poll_frame().is_none());
+    }
+
+    #[test]
+    fn test_average_of_interval_takes_every_hop() {
+        use crate::fft::HOP_SIZE;
+
+        let mut engine = engine();
+        engine.set_settings(EngineSettings { alignment: AnalysisAlignment::AverageOfInterval, ..EngineSettings::default() });
+        // A tone that swells, so every window of an interval differs
+        let signal: Vec<f32> = sine(100, 1.0, 0, 16 * HOP_SIZE)
+            .iter()
+            .enumerate()
+            .map(|(i, x)| x * i as f32 / (16 * HOP_SIZE) as f32)
+            .collect();
+
+        // One hop per block: the first frame once a window is buffered, then
+        // every third block, the first interval past the 2400 samples
+        let mut frames = Vec::new();
+        for (block, samples) in signal.chunks(HOP_SIZE).enumerate() {
+            engine.push_samples(samples, samples);
+            let hops = engine.hops_in_interval;
+            if let Some(frame) = engine.poll_frame() {
+                frames.push((block, hops, frame));
+            }
+        }
+        let emitted: Vec<(usize, usize)> = frames.iter().map(|(block, hops, _)| (*block, *hops)).collect();
+        assert_eq!(emitted, [(3, 1), (6, 3), (9, 3), (12, 3), (15, 3)]);
+
+        // Each frame is the power average of the windows ending on its hops
+        let mut processor = FftProcessor::new();
+        for (block, hops, frame) in &frames {
+            let mut acc = vec![0.0; crate::protocol::NUM_BINS];
+            for hop in 0..*hops {
+                let end = (block + 1 - hop) * HOP_SIZE;
+                processor.accumulate_power(&signal[end - FFT_SIZE..end], &mut acc);
+            }
+            let expected = FftProcessor::mean_power_db(&acc, *hops);
+            let error = frame.left_bins.iter().zip(&expected).map(|(a, b)| (a - b).abs()).fold(0.0, f32::max);
+            assert!(error < 0.01, "frame after block {} is {} dB off its average", block, error);
+        }
+    }
+
+    #[test]
+    fn test_calibration_tone() {
+        let mut engine = engine();
+        // Full scale left, half (-6.02 dB) right, on bin 100
+        let frames = run(&mut engine, 10, |from| {
+            (sine(100, 1.0, from, BLOCK), sine(100, 0.5, from, BLOCK))
+        });
+        let (_, frame) = frames.last().unwrap();
+
+        assert_eq!(frame.analysis_rate, RATE);
+        assert!(frame.left_bins[100].abs() < 0.1, "left bin reads {} dB", frame.left_bins[100]);
+        assert!((frame.right_bins[100] + 6.02).abs() < 0.1, "right bin reads {} dB", frame.right_bins[100]);
+        assert!(frame.left_bins[200] < -60.0);
+        assert!(frame.left_levels.peak.get().abs() < 0.01);
+        assert!((frame.left_levels.rms.get() - std::f32::consts::FRAC_1_SQRT_2).abs() < 0.01);
+        assert_eq!(frame.non_finite_samples, 0);
+
+        let packet = frame.clone().into_packet(1234);
+        assert_eq!(packet.timestamp_ms, 1234);
+        assert_eq!(packet.left_bins, frame.left_bins);
+    }
+
+    #[test]
+    fn test_zero_pad_keeps_calibration() {
+        let tone = |from| (sine(100, 1.0, from, BLOCK), sine(100, 0.5, from, BLOCK));
+        let (_, unpadded) = run(&mut engine(), 10, tone).pop().unwrap();
+        let mut padded = AnalysisEngine::new(AnalysisConfig {
+            zero_pad: ZeroPad::X4,
+            ..AnalysisConfig::default()
+        });
+        let (_, frame) = run(&mut padded, 10, tone).pop().unwrap();
+
+        assert_eq!(frame.left_bins.len(), 4 * crate::protocol::NUM_BINS);
+        assert!(frame.left_bins[400].abs() < 0.1, "reads {} dB", frame.left_bins[400]);
+        for (a, b) in unpadded.left_coarse.iter().zip(&frame.left_coarse) {
+            assert!(*a < -30.0 || (a - b).abs() < 0.5, "coarse {} vs {} dB", b, a);
+        }
+        assert_eq!(frame.into_packet(0).zero_pad, 4);
+    }
+
+    /// One tone in each of the default coarse bands, at `rate`
+    fn band_tones(rate: f32, from: usize, len: usize) -> Vec<f32> {
+        const TONES_HZ: [f32; 8] = [30.0, 73.0, 173.0, 411.0, 975.0, 2_300.0, 5_500.0, 13_000.0];
+        (from..from + len)
+            .map(|i| {
+                let t = i as f64 / f64::from(rate);
+                TONES_HZ
+                    .iter()
+                    .map(|&hz| 0.1 * (2.0 * std::f64::consts::PI * f64::from(hz) * t).sin() as f32)
+                    .sum()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_normalized_rate_reads_the_same_at_every_project_rate() {
+        let coarse_at = |rate: f32| {
+            let mut engine = AnalysisEngine::new(AnalysisConfig {
+                sample_rate: rate,
+                normalized_rate: true,
+                ..AnalysisConfig::default()
+            });
+            // 10 ms blocks for a second
+            let block = (rate / 100.0) as usize;
+            let mut last = None;
+            for n in 0..100 {
+                let tones = band_tones(rate, n * block, block);
+                engine.push_samples(&tones, &tones);
+                last = engine.poll_frame().or(last);
+            }
+            let frame: AnalysisFrame = last.unwrap();
+            // Packets say which rate the bins are at
+            assert_eq!(frame.clone().into_packet(0).sample_rate, 48_000);
+            frame.left_coarse
+        };
+        let cd = coarse_at(44_100.0);
+        let hi_res = coarse_at(96_000.0);
+        for (band, (a, b)) in cd.iter().zip(&hi_res).enumerate() {
+            assert!((a - b).abs() < 0.5, "band {}: {} dB at 44.1 kHz, {} dB at 96 kHz", band, a, b);
+        }
+    }
+
+    /// Apply `settings` and wait for the config worker to swap them in
+    fn switch_to(engine: &mut AnalysisEngine, settings: EngineSettings) {
+        let deadline = std::time::Instant::now() + std::time::Duration::from_secs(5);
+        while !engine.set_settings(settings) {
+            assert!(std::time::Instant::now() < deadline, "{:?} never arrived", settings);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_eco_mode_round_trip() {
+        let normal = EngineSettings {
+            zero_pad: ZeroPad::X2,
+            coarse_bands: 16,
+            ..EngineSettings::default()
+        };
+        let mut engine = AnalysisEngine::new(AnalysisConfig {
+            zero_pad: ZeroPad::X2,
+            ..AnalysisConfig::default()
+        });
+        engine.start();
+        assert!(!engine.set_settings(normal));
+        let before = engine.config();
+        let tone = |from| (sine(100, 1.0, from, BLOCK), sine(100, 0.5, from, BLOCK));
+        let (_, reference) = run(&mut engine, 20, tone).pop().unwrap();
+        assert!(!reference.clone().into_packet(0).eco_mode);
+
+        // One switch: half the rate, half the window, still calibrated
+        switch_to(&mut engine, EngineSettings { eco_mode: true, ..normal });
+        assert!(engine.config().eco_mode);
+        let frames = run(&mut engine, 40, tone);
+        let (_, eco) = frames.last().unwrap();
+        assert_eq!(eco.fft_size, ECO_FFT_SIZE);
+        assert_eq!(eco.analysis_rate, RATE / 2.0);
+        assert_eq!(eco.left_bins.len(), crate::protocol::NUM_BINS, "half the bins of the padded 4096");
+        // Bin 100 of 4096 at 48 kHz is bin 100 of 2048 at 24 kHz, 200 padded
+        assert!(eco.left_bins[200].abs() < 0.2, "reads {} dB", eco.left_bins[200]);
+        assert!((eco.right_bins[200] + 6.02).abs() < 0.2);
+        let packet = eco.clone().into_packet(0);
+        assert!(packet.eco_mode);
+        assert_eq!(packet.sample_rate, 24_000);
+        // The Suite still gets a frame every 2400 host samples
+        for pair in frames.windows(2) {
+            assert_eq!(pair[1].0 - pair[0].0, 5);
+        }
+
+        // Off again, everything is as it was
+        switch_to(&mut engine, normal);
+        assert_eq!(engine.config(), before);
+        let (_, restored) = run(&mut engine, 20, tone).pop().unwrap();
+        assert_eq!(restored.fft_size, FFT_SIZE);
+        assert_eq!(restored.left_bins, reference.left_bins);
+        assert_eq!(restored.right_bins, reference.right_bins);
+        assert_eq!(restored.left_coarse, reference.left_coarse);
+        assert_eq!(restored.analysis_rate, reference.analysis_rate);
+    }
+
+    #[test]
+    fn test_update_rate_changes_the_send_cadence() {
+        let mut engine = engine();
+        engine.start();
+        let tone = |from| (sine(100, 1.0, from, BLOCK), sine(100, 1.0, from, BLOCK));
+        fn gaps(frames: &[(usize, AnalysisFrame)]) -> Vec<usize> {
+            frames.windows(2).map(|pair| pair[1].0 - pair[0].0).collect()
+        }
+        let frames = run(&mut engine, 40, tone);
+        assert_eq!(gaps(&frames), vec![5; frames.len() - 1], "every 2400 samples at 20 Hz");
+
+        // 50 Hz: a frame every 960 samples, over the same window
+        engine.set_update_rate(50);
+        switch_to(&mut engine, EngineSettings::default());
+        assert_eq!(engine.config().update_rate, 50);
+        let frames = run(&mut engine, 40, tone);
+        assert!(frames.len() > 10, "{} frames", frames.len());
+        assert_eq!(gaps(&frames), vec![2; frames.len() - 1]);
+        let (_, frame) = frames.last().unwrap();
+        assert_eq!(frame.fft_size, FFT_SIZE);
+        assert!(frame.left_bins[100].abs() < 0.2, "still calibrated, reads {} dB", frame.left_bins[100]);
+    }
+
+    #[test]
+    fn test_self_check_recovers_a_desynchronised_state() {
+        let mut engine = engine();
+        engine.start();
+        engine.set_paranoid(true);
+        let tone = |from| (sine(64, 1.0, from, BLOCK), sine(64, 1.0, from, BLOCK));
+        run(&mut engine, 20, tone);
+        assert_eq!(engine.check_consistency(), Ok(()));
+
+        // Samples the right ring lost
+        engine.analysis.buffer_right.clear();
+        assert!(matches!(engine.check_consistency(), Err(Inconsistency::RingFill { .. })));
+        assert!(engine.analysis.buffer_left.is_empty() && engine.analysis.buffer_right.is_empty());
+        assert_eq!(engine.check_consistency(), Ok(()));
+
+        // An FFT planned for other padding can't be fixed in place; a fresh
+        // state is built for the config the engine already has
+        engine.analysis.fft_right = FftProcessor::with_zero_pad(2);
+        let err = (0..FFT_CHECK_BLOCKS).find_map(|_| engine.check_consistency().err());
+        assert!(matches!(err, Some(Inconsistency::FftShape { .. })));
+        switch_to(&mut engine, EngineSettings::default());
+        assert_eq!(engine.analysis.fft_right.num_bins(), engine.analysis.fft_left.num_bins());
+        assert_eq!(engine.config(), AnalysisConfig::default());
+        for _ in 0..FFT_CHECK_BLOCKS {
+            assert_eq!(engine.check_consistency(), Ok(()));
+        }
+
+        // And the analysis carries on as before
+        let frames = run(&mut engine, 20, tone);
+        let (_, frame) = frames.last().unwrap();
+        assert!(frame.left_bins[64] > -1.0 && frame.right_bins[64] > -1.0);
+    }
+
+    #[test]
+    fn test_self_check_off_checks_nothing() {
+        let mut engine = engine();
+        let gauges = MemoryGauges::default();
+        engine.report_memory(&gauges);
+        assert!(engine.self_check.is_none());
+        assert_eq!(gauges.get(Buffer::SelfCheck), 0);
+
+        // Even a broken state goes unnoticed and untouched
+        run(&mut engine, 20, silence);
+        engine.analysis.buffer_right.clear();
+        let fill = engine.analysis.buffer_left.len();
+        assert_eq!(engine.check_consistency(), Ok(()));
+        assert_eq!(engine.analysis.buffer_left.len(), fill);
+
+        engine.set_paranoid(true);
+        engine.report_memory(&gauges);
+        assert!(gauges.get(Buffer::SelfCheck) > 0);
+        engine.set_paranoid(false);
+        assert!(engine.self_check.is_none());
+    }
+
+    #[test]
+    fn test_mid_side() {
+        let mut engine = engine();
+        engine.set_settings(EngineSettings {
+            channel_mode: ChannelMode::MidSide,
+            ..EngineSettings::default()
+        });
+        // Identical channels: all mid, no side
+        let frames = run(&mut engine, 10, |from| {
+            let tone = sine(100, 1.0, from, BLOCK);
+            (tone.clone(), tone)
+        });
+        let (_, frame) = frames.last().unwrap();
+        assert!(frame.left_bins[100].abs() < 0.1);
+        assert!(frame.right_bins.iter().all(|&db| db < -90.0));
+    }
+
+    #[test]
+    fn test_reference_is_analysed_apart() {
+        let mut engine = AnalysisEngine::new(AnalysisConfig { reference: true, ..AnalysisConfig::default() });
+        engine.set_reference(true);
+        let mut last = None;
+        for block in 0..10 {
+            let main = sine(100, 1.0, block * BLOCK, BLOCK);
+            let reference = sine(300, 0.5, block * BLOCK, BLOCK);
+            engine.push_samples(&main, &main);
+            engine.push_reference(Some((&reference, &reference)));
+            last = engine.poll_frame().or(last);
+        }
+        let frame = last.unwrap();
+        let reference = frame.reference.clone().unwrap();
+        assert!(frame.left_bins[100].abs() < 0.1 && frame.left_bins[300] < -60.0);
+        assert!((reference.left_bins[300] + 6.02).abs() < 0.1, "reads {} dB", reference.left_bins[300]);
+        assert!(reference.right_bins[100] < -60.0, "none of the main input");
+        assert_eq!(reference.left_coarse.len(), frame.left_coarse.len());
+
+        // Nothing routed: the floor, not the tone from before
+        let main = sine(100, 1.0, 0, BLOCK);
+        let mut last = None;
+        for _ in 0..10 {
+            engine.push_samples(&main, &main);
+            engine.push_reference(None);
+            last = engine.poll_frame().or(last);
+        }
+        let reference = last.unwrap().reference.unwrap();
+        assert!(reference.left_bins.iter().chain(&reference.right_coarse).all(|&db| db == FLOOR_DB));
+
+        // Off, the frame has none
+        let frame = run(&mut AnalysisEngine::new(AnalysisConfig::default()), 10, silence).pop().unwrap().1;
+        assert!(frame.reference.is_none());
+    }
+
+    #[test]
+    fn test_surround_channels_are_analysed_one_by_one() {
+        let mut engine = AnalysisEngine::new(AnalysisConfig { channels: 6, ..AnalysisConfig::default() });
+        // L R C LFE Ls Rs, each on a bin of its own
+        let tones = [100, 150, 200, 40, 300, 350];
+        let mut last = None;
+        for block in 0..10 {
+            let channels: Vec<Vec<f32>> = tones.iter().map(|&bin| sine(bin, 0.5, block * BLOCK, BLOCK)).collect();
+            let (mut left, mut right) = (vec![0.0; BLOCK], vec![0.0; BLOCK]);
+            crate::surround::downmix(&channels, &mut left, &mut right);
+            engine.push_samples(&left, &right);
+            engine.push_channels(&channels);
+            last = engine.poll_frame().or(last);
+        }
+        let frame = last.unwrap();
+        let surround = frame.surround.clone().unwrap();
+        assert_eq!(surround.bins.len(), 6 * crate::protocol::NUM_BINS);
+        for (channel, bins) in surround.bins.chunks(crate::protocol::NUM_BINS).enumerate() {
+            for (other, &bin) in tones.iter().enumerate() {
+                if other == channel {
+                    assert!((bins[bin] + 6.02).abs() < 0.1, "channel {} reads {} dB", channel, bins[bin]);
+                } else {
+                    assert!(bins[bin] < -60.0, "channel {} has channel {}'s tone", channel, other);
+                }
+            }
+            assert!((surround.levels[channel].peak.get() + 6.02).abs() < 0.5);
+        }
+
+        // The FFT packet has the downmix: the centre in both sides, the LFE
+        // in neither
+        assert!((frame.left_bins[200] - frame.right_bins[200]).abs() < 0.1);
+        assert!(frame.left_bins[40] < -60.0 && frame.right_bins[40] < -60.0);
+        assert!(frame.left_bins[300] > -10.0 && frame.right_bins[300] < -60.0);
+
+        // Stereo has none
+        let frame = run(&mut AnalysisEngine::new(AnalysisConfig::default()), 10, silence).pop().unwrap().1;
+        assert!(frame.surround.is_none());
+    }
+
+    #[test]
+    fn test_channel_mode_change_restarts_warm_up() {
+        let mut engine = engine();
+        run(&mut engine, 8, silence);
+        engine.set_settings(EngineSettings {
+            channel_mode: ChannelMode::MidSide,
+            ..EngineSettings::default()
+        });
+        engine.push_samples(&[0.0; BLOCK], &[0.0; BLOCK]);
+        assert!(engine.poll_frame().is_none(), "window mixes channel modes");
+    }
+
+    #[test]
+    fn test_meters_smooth_across_frames() {
+        let mut engine = engine();
+        let frames = run(&mut engine, 40, |from| {
+            let tone = if from < 20 * BLOCK { sine(100, 1.0, from, BLOCK) } else { vec![0.0; BLOCK] };
+            (tone.clone(), tone)
+        });
+        // The tone stops: the raw peak drops at once, the meter holds then falls
+        let after: Vec<&AnalysisFrame> = frames.iter().filter(|(block, _)| *block >= 30).map(|(_, f)| f).collect();
+        let first = after[0];
+        assert!(first.left_levels.peak.get() < -90.0);
+        assert!(first.left_meters.peak.get() > -3.0, "meter dropped to {}", first.left_meters.peak.get());
+        assert!(first.left_meters.rms.get() > 0.1);
+    }
+
+    #[test]
+    fn test_bad_samples_are_counted_per_frame() {
+        let mut engine = engine();
+        let mut left = vec![0.0; BLOCK];
+        left[3] = f32::NAN;
+        left[4] = f32::INFINITY;
+        engine.push_samples(&left, &left);
+        let frames = run(&mut engine, 20, silence);
+        assert_eq!(frames[0].1.non_finite_samples, 2, "mono input counts each sample once");
+        assert_eq!(frames[1].1.non_finite_samples, 0);
+    }
+
+    #[test]
+    fn test_unsubscribed_data_is_not_computed() {
+        let mut engine = engine();
+        // One tone through all three runs, so a window spanning two of them
+        // sees no jump in phase
+        let next = std::cell::Cell::new(0);
+        let tone = |_| {
+            let from = next.replace(next.get() + BLOCK);
+            (sine(100, 0.5, from, BLOCK), sine(100, 0.5, from, BLOCK))
+        };
+        let all = run(&mut engine, 20, tone);
+        assert_eq!(engine.work_counts(), WorkCounts { spectra: all.len() as u64, waveforms: all.len() as u64 });
+
+        // Levels only, as for a Suite showing meters with the window closed
+        engine.set_outputs(CategorySet::NONE.with(Category::Levels));
+        let before = engine.work_counts();
+        let levels_only = run(&mut engine, 20, tone);
+        assert!(!levels_only.is_empty());
+        assert_eq!(engine.work_counts(), before, "no FFT or waveform ran");
+        for (_, frame) in &levels_only {
+            assert!(frame.left_bins.is_empty() && frame.left_coarse.is_empty() && frame.left_wave.is_empty());
+            assert!(frame.left_levels.peak.get() > -7.0);
+        }
+
+        // The window opens and wants the spectrum back; hops taken before
+        // don't leak into it
+        engine.set_outputs(CategorySet::NONE.with(Category::Levels).with(Category::Fft));
+        let spectrum = run(&mut engine, 20, tone);
+        assert_eq!(engine.work_counts().spectra, before.spectra + spectrum.len() as u64);
+        assert_eq!(engine.work_counts().waveforms, before.waveforms);
+        assert!((spectrum[0].1.left_bins[100] - all[0].1.left_bins[100]).abs() < 0.5);
+        assert!(spectrum[0].1.left_wave.is_empty());
+    }
+
+    #[test]
+    fn test_band_smoothing_follows_the_settings() {
+        // A tone after a second of silence, raw and with 500 ms attack and
+        // release
+        let tone = |from| (sine(100, 0.5, from, BLOCK), sine(100, 0.5, from, BLOCK));
+        let step = |from: usize| if from < 100 * BLOCK { silence(from) } else { tone(from) };
+        let hz = 100.0 * RATE / FFT_SIZE as f32;
+        let band_of = |frame: &AnalysisFrame| frame.coarse_edges.windows(2).position(|w| (w[0]..w[1]).contains(&hz));
+        let tone_band = |engine: &mut AnalysisEngine| -> Vec<f32> {
+            run(engine, 600, step).iter().map(|(_, frame)| frame.left_coarse[band_of(frame).unwrap()]).collect()
+        };
+        let raw = tone_band(&mut engine());
+        let mut smoothed_engine = engine();
+        let slow = EngineSettings { band_attack: 500.0, band_release: 500.0, ..EngineSettings::default() };
+        smoothed_engine.set_settings(slow);
+        let smoothed = tone_band(&mut smoothed_engine);
+
+        let onset = raw.iter().position(|&db| db > -20.0).unwrap();
+        assert!(smoothed[onset] < raw[onset] - 10.0, "{} against {} dB", smoothed[onset], raw[onset]);
+        let (last_raw, last_smoothed) = (raw[raw.len() - 1], smoothed[smoothed.len() - 1]);
+        assert!((last_smoothed - last_raw).abs() < 0.1, "{} against {} dB", last_smoothed, last_raw);
+
+        // After a reset the first frame is taken as it comes, not released
+        // from the tone
+        smoothed_engine.reset();
+        let (_, after_reset) = run(&mut smoothed_engine, 20, silence).remove(0);
+        let (_, raw_silence) = run(&mut engine(), 20, silence).remove(0);
+        assert_eq!(after_reset.left_coarse, raw_silence.left_coarse);
+    }
+
+    #[test]
+    fn test_band_peaks_outlast_the_band() {
+        // Two seconds of a tone, then silence
+        let tone = |from| (sine(100, 0.5, from, BLOCK), sine(100, 0.5, from, BLOCK));
+        let step = |from: usize| if from < 200 * BLOCK { tone(from) } else { silence(from) };
+        let mut engine = engine();
+        engine.set_settings(EngineSettings { band_peak_hold: 1.0, band_peak_decay: 20.0, ..EngineSettings::default() });
+        let frames = run(&mut engine, 400, step);
+
+        let hz = 100.0 * RATE / FFT_SIZE as f32;
+        let (_, first) = &frames[0];
+        let band = first.coarse_edges.windows(2).position(|w| (w[0]..w[1]).contains(&hz)).unwrap();
+        let held_db = |block: usize| {
+            let (_, frame) = frames.iter().rev().find(|(at, _)| *at <= block).unwrap();
+            assert_eq!(frame.left_peak_bands.len(), frame.left_coarse.len());
+            (frame.left_coarse[band], frame.left_peak_bands[band], frame.right_peak_bands[band])
+        };
+        let (live, peak, right) = held_db(199);
+        assert!((peak - live).abs() < 0.5 && (right - live).abs() < 0.5, "{} {} {}", live, peak, right);
+
+        // Half a second into the silence the band has gone and its peak holds
+        let (gone, still, _) = held_db(260);
+        assert!(gone < live - 40.0, "{} dB", gone);
+        assert!((still - live).abs() < 0.5, "{} dB", still);
+        // Past the hold it falls at 20 dB/s
+        let (_, fallen, _) = held_db(399);
+        assert!(fallen < live - 10.0 && fallen > live - 30.0, "{} dB from {}", fallen, live);
+
+        let packet = frames[frames.len() - 1].1.clone().into_packet(0);
+        assert_eq!(packet.left_peak_bands.len(), packet.left_coarse.len());
+    }
+
+    #[test]
+    fn test_weighting_shapes_the_spectrum_and_levels() {
+        let weighted = |weighting, bin| {
+            let mut engine = engine();
+            engine.set_settings(EngineSettings { weighting, ..EngineSettings::default() });
+            let (_, frame) = run(&mut engine, 100, |from| (sine(bin, 0.5, from, BLOCK), sine(bin, 0.5, from, BLOCK)))
+                .pop()
+                .unwrap();
+            (frame.left_bins[bin], frame.left_levels.rms.get())
+        };
+        // 35 Hz is A-weighted by -37.2 dB, 996 Hz by -0.01 dB
+        for (bin, nominal) in [(3, -37.2), (85, 0.0)] {
+            let (flat_db, flat_rms) = weighted(Weighting::Z, bin);
+            let (a_db, a_rms) = weighted(Weighting::A, bin);
+            assert!((a_db - flat_db - nominal).abs() < 0.5, "bin {}: {} dB", bin, a_db - flat_db);
+            let level_db = 20.0 * (a_rms / flat_rms).log10();
+            assert!((level_db - nominal).abs() < 0.5, "bin {}: {} dB", bin, level_db);
+        }
+    }
+
+    #[test]
+    fn test_frames_carry_loudness() {
+        // Three seconds of EBU Tech 3341 case 1, whatever the weighting
+        let amplitude = 10f32.powf(-23.0 / 20.0);
+        let tone = |from: usize| {
+            let tone: Vec<f32> =
+                (from..from + BLOCK).map(|n| amplitude * (2.0 * PI * 1000.0 * n as f32 / RATE).sin()).collect();
+            (tone.clone(), tone)
+        };
+        let mut engine = engine();
+        engine.set_settings(EngineSettings { weighting: Weighting::A, ..EngineSettings::default() });
+        let (_, frame) = run(&mut engine, 300, tone).pop().unwrap();
+        let packet = frame.into_packet(0);
+        assert!((packet.momentary_lufs + 23.0).abs() < 0.1, "{}", packet.momentary_lufs);
+        assert!((packet.short_term_lufs + 23.0).abs() < 0.1, "{}", packet.short_term_lufs);
+
+        engine.reset();
+        let (_, frame) = run(&mut engine, 20, silence).remove(0);
+        assert_eq!((frame.momentary_lufs, frame.short_term_lufs), (Db::FLOOR.get(), Db::FLOOR.get()));
+    }
+
+    #[test]
+    fn test_frames_carry_true_peaks() {
+        // A full-scale quarter-rate sine sampled 45° off its crests
+        let tone = |from: usize| {
+            let tone: Vec<f32> = (from..from + BLOCK).map(|n| (PI * 0.5 * n as f32 + PI / 4.0).sin()).collect();
+            (tone.clone(), tone.iter().map(|x| 0.5 * x).collect())
+        };
+        let mut engine = engine();
+        let frames = run(&mut engine, 40, tone);
+        let (_, frame) = frames.last().unwrap();
+        assert!((frame.left_levels.peak.get() + 3.01).abs() < 0.05, "{}", frame.left_levels.peak.get());
+        assert!(frame.left_true_peak.abs() < 0.3, "{} dBTP", frame.left_true_peak);
+        assert!((frame.right_true_peak + 6.02).abs() < 0.3, "{} dBTP", frame.right_true_peak);
+
+        let packet = frame.clone().into_packet(0);
+        assert_eq!(packet.left_peak, frame.left_levels.peak.get(), "sample peaks are still sent");
+        assert_eq!(packet.left_true_peak, frame.left_true_peak);
+    }
+
+    #[test]
+    fn test_frames_carry_the_envelope_since_the_last() {
+        // Full scale on the left, a quarter on the right, every other sample
+        let square = |from: usize| {
+            let left: Vec<f32> = (from..from + BLOCK).map(|n| if n % 2 == 0 { 1.0 } else { -1.0 }).collect();
+            let right = left.iter().map(|x| 0.25 * x).collect();
+            (left, right)
+        };
+        let mut engine = engine();
+        let frames = run(&mut engine, 40, square);
+        let (_, frame) = frames.last().unwrap();
+        let envelope = &frame.envelope;
+        assert_eq!((envelope.sample_rate, envelope.samples_per_point), (48_000, 10));
+        // A 50 ms interval, 2400 samples
+        assert_eq!(envelope.left.len() / 2 * 10, 2400);
+        assert!(envelope.left.chunks(2).all(|point| point == [-1.0, 1.0]));
+        assert!(envelope.right.chunks(2).all(|point| point == [-0.25, 0.25]));
+
+        let packet = frame.clone().envelope.into_packet(7).unwrap();
+        assert_eq!(packet.left_envelope, envelope.left);
+        assert!(frame.clone().into_packet(7).left_envelope.is_empty(), "not in the FFT packet");
+    }
+
+    #[test]
+    fn test_window_correlation_in_frames() {
+        let correlation_of = |channel_mode, signal: &dyn Fn(usize) -> (Vec<f32>, Vec<f32>)| {
+            let mut engine = engine();
+            engine.set_settings(EngineSettings { channel_mode, ..EngineSettings::default() });
+            let (_, frame) = run(&mut engine, 40, signal).pop().unwrap();
+            frame.into_packet(0).correlation
+        };
+        let tone = |from| sine(100, 0.5, from, BLOCK);
+        let in_phase = |from| (tone(from), tone(from));
+        let inverted = |from| (tone(from), tone(from).iter().map(|x| -x).collect());
+        for mode in [ChannelMode::LeftRight, ChannelMode::MidSide] {
+            assert!((correlation_of(mode, &in_phase) - 1.0).abs() < 1e-3, "{:?}", mode);
+            assert!((correlation_of(mode, &inverted) + 1.0).abs() < 1e-3, "{:?}", mode);
+            assert_eq!(correlation_of(mode, &silence), 0.0, "{:?}", mode);
+        }
+
+        // One channel passed as both reads +1, even silent
+        let mut engine = engine();
+        let mut frames = Vec::new();
+        for _ in 0..40 {
+            let block = [0.0; BLOCK];
+            engine.push_samples(&block, &block);
+            frames.extend(engine.poll_frame());
+        }
+        assert!(!frames.is_empty());
+        assert!(frames.iter().all(|frame| frame.correlation == 1.0));
+    }
+
+    #[test]
+    fn test_band_activity() {
+        use crate::activity::ACTIVITY_FRAMES;
+
+        let activity_of = |signal: &dyn Fn(usize) -> (Vec<f32>, Vec<f32>)| {
+            let mut engine = engine();
+            engine.set_settings(EngineSettings { band_activity: true, ..EngineSettings::default() });
+            // Warm-up, then a full window
+            let frames = run(&mut engine, 5 * (ACTIVITY_FRAMES + 10), signal);
+            let (_, frame) = frames.last().unwrap();
+            (frame.band_activity.clone(), frame.coarse_edges.clone())
+        };
+
+        // A held sine barely moves its band
+        let (activity, edges) = activity_of(&|from| (sine(100, 0.5, from, BLOCK), sine(100, 0.5, from, BLOCK)));
+        let hz = 100.0 * RATE / FFT_SIZE as f32;
+        let band = edges.windows(2).position(|w| (w[0]..w[1]).contains(&hz)).unwrap();
+        assert_eq!(activity.len(), edges.len() - 1);
+        assert!(activity[band] < 0.05, "sine band: {}", activity[band]);
+
+        // Noise with a 3 Hz tremolo moves every band
+        let tremolo = |from: usize| {
+            let mut state = 0x2545_f491_u32 ^ from as u32;
+            let samples: Vec<f32> = (from..from + BLOCK)
+                .map(|i| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    let noise = state as f32 / u32::MAX as f32 - 0.5;
+                    let gain = (1.0 + 0.9 * (2.0 * PI * 3.0 * i as f32 / RATE).sin()) / 1.9;
+                    noise * gain
+                })
+                .collect();
+            (samples.clone(), samples)
+        };
+        let (activity, _) = activity_of(&tremolo);
+        assert!(activity.iter().all(|&a| a > 0.5), "{:?}", activity);
+
+        // Off, nothing is computed or sent
+        let frames = run(&mut engine(), 20, silence);
+        assert!(frames.iter().all(|(_, frame)| frame.band_activity.is_empty()));
+    }
+
+    #[test]
+    fn test_simulated_input_after_silence() {
+        let simulated_run = |seed: u32| {
+            let mut engine = engine();
+            engine.set_simulated_input(Some(seed));
+            // 3 s of silence come out as silence
+            let silent = run(&mut engine, 300, silence);
+            assert!(silent.iter().all(|(_, frame)| !frame.simulated && frame.left_levels.peak == Db::FLOOR));
+            assert!(!engine.simulating());
+
+            // Then the engine makes its own signal, and says so
+            let frames = run(&mut engine, 50, silence);
+            assert!(engine.simulating());
+            assert_eq!(frames.len(), 10);
+            assert!(frames.iter().all(|(_, frame)| frame.simulated && frame.left_levels.peak.get() > -20.0));
+            assert_ne!(frames[0].1.left_bins, frames[1].1.left_bins);
+
+            // Real signal takes over in the same block; frames stay flagged
+            // until no synthesised sample is left in the window
+            let real = run(&mut engine, 20, |from| (sine(100, 0.5, from, BLOCK), sine(100, 0.5, from, BLOCK)));
+            assert!(!engine.simulating());
+            let flagged: Vec<(usize, bool)> = real.iter().map(|(block, frame)| (*block, frame.simulated)).collect();
+            assert_eq!(flagged, [(3, true), (8, false), (13, false), (18, false)]);
+
+            frames
+                .into_iter()
+                .map(|(_, mut frame)| {
+                    frame.captured_us = 0;
+                    frame
+                })
+                .collect::<Vec<_>>()
+        };
+        // A seed always makes the same frames
+        assert_eq!(simulated_run(7), simulated_run(7));
+        assert_ne!(simulated_run(7), simulated_run(8));
+
+        // Off, silence stays silence
+        let mut engine = engine();
+        assert!(run(&mut engine, 400, silence).iter().all(|(_, frame)| !frame.simulated));
+    }
+}
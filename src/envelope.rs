@@ -0,0 +1,216 @@
+//! Min/max waveform envelopes for a scrolling oscilloscope
+//!
+//! The 512 samples of `left_wave` and `right_wave` are the end of the
+//! analysis window, a snapshot; a scope that scrolls needs every sample in
+//! between. With Waveform Packets on, each frame is followed by a
+//! `PACKET_TYPE_WAVEFORM` packet summing up the input since the one before:
+//! up to `POINTS` (256) points per channel, each the lowest and the highest
+//! sample of `envelope_samples` host samples, interleaved in
+//! `left_envelope` and `right_envelope` as `[min, max, min, max, ...]`.
+//!
+//! The input is taken as it arrives, at the host rate and before the
+//! weighting or the channel mode, by a fixed-size meter costing a compare
+//! or two per sample. A point spans enough samples for a 20 Hz frame
+//! interval to fill at most `POINTS`; when more arrive before a frame, as
+//! with large host blocks, neighbouring points merge and each spans twice as
+//! many, so a packet never has more than `POINTS`. The last point may be
+//! partial. A mono input sends its one channel on both sides.
+//!
+//! Packets go to a Suite subscribed to `waveform` and to the plugin window,
+//! none while the history is frozen, and aren't coalesced: each covers its
+//! own stretch of samples.
+
+use crate::protocol::AudioPacket;
+
+/// Points per channel at most
+pub const POINTS: usize = 256;
+
+/// Frames per second the span of a point is sized for
+const FRAMES_PER_SECOND: f32 = 20.0;
+
+/// What a frame's waveform packet carries
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Envelope {
+    /// Interleaved minimum and maximum of each point
+    pub left: Vec<f32>,
+    pub right: Vec<f32>,
+    /// Host samples per point
+    pub samples_per_point: u32,
+    /// Host sample rate
+    pub sample_rate: u32,
+}
+
+impl Envelope {
+    /// The waveform packet of this envelope, stamped `timestamp_ms`; `None`
+    /// if it has no points
+    pub fn into_packet(self, timestamp_ms: u64) -> Option<AudioPacket> {
+        if self.left.is_empty() {
+            return None;
+        }
+        let mut packet = AudioPacket::new_waveform(self.sample_rate, timestamp_ms);
+        packet.left_envelope = self.left;
+        packet.right_envelope = self.right;
+        packet.envelope_samples = self.samples_per_point;
+        Some(packet)
+    }
+}
+
+/// Builds the envelope of a stereo or mono input, one sample at a time
+#[derive(Debug, Clone)]
+pub struct EnvelopeMeter {
+    /// Interleaved minimum and maximum of the points so far, the last one
+    /// in progress
+    left: [f32; 2 * POINTS],
+    right: [f32; 2 * POINTS],
+    /// Points begun
+    points: usize,
+    /// Samples in the last point
+    in_point: u32,
+    /// Samples per point now, and after a take
+    span: u32,
+    base_span: u32,
+    sample_rate: u32,
+    /// The input since the last take was mono
+    mono: bool,
+}
+
+impl EnvelopeMeter {
+    pub fn new(sample_rate: f32) -> Self {
+        let base_span = (sample_rate / FRAMES_PER_SECOND / POINTS as f32).ceil().max(1.0) as u32;
+        Self {
+            left: [0.0; 2 * POINTS],
+            right: [0.0; 2 * POINTS],
+            points: 0,
+            in_point: 0,
+            span: base_span,
+            base_span,
+            sample_rate: sample_rate as u32,
+            mono: false,
+        }
+    }
+
+    /// One host sample of each channel; `right` is ignored for a mono input
+    pub fn push(&mut self, left: f32, right: f32, mono: bool) {
+        if self.in_point == self.span || self.points == 0 {
+            if self.points == POINTS {
+                self.merge();
+            }
+            let at = 2 * self.points;
+            self.left[at..at + 2].fill(left);
+            self.right[at..at + 2].fill(right);
+            self.points += 1;
+            self.in_point = 0;
+        }
+        let at = 2 * (self.points - 1);
+        Self::widen(&mut self.left[at..at + 2], left);
+        if !mono {
+            Self::widen(&mut self.right[at..at + 2], right);
+        }
+        self.in_point += 1;
+        self.mono = mono;
+    }
+
+    /// The points since the last call
+    pub fn take(&mut self) -> Envelope {
+        let len = 2 * self.points;
+        let left = self.left[..len].to_vec();
+        let right = if self.mono { left.clone() } else { self.right[..len].to_vec() };
+        let envelope = Envelope { left, right, samples_per_point: self.span, sample_rate: self.sample_rate };
+        self.reset();
+        envelope
+    }
+
+    /// Back to no points
+    pub fn reset(&mut self) {
+        self.points = 0;
+        self.in_point = 0;
+        self.span = self.base_span;
+    }
+
+    fn widen(point: &mut [f32], sample: f32) {
+        point[0] = point[0].min(sample);
+        point[1] = point[1].max(sample);
+    }
+
+    /// Halve the points, each new one spanning two old ones
+    fn merge(&mut self) {
+        for channel in [&mut self.left, &mut self.right] {
+            for i in 0..POINTS / 2 {
+                channel[2 * i] = channel[4 * i].min(channel[4 * i + 2]);
+                channel[2 * i + 1] = channel[4 * i + 1].max(channel[4 * i + 3]);
+            }
+        }
+        self.points = POINTS / 2;
+        self.span *= 2;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PACKET_TYPE_WAVEFORM;
+
+    #[test]
+    fn test_points_hold_the_extremes() {
+        let mut meter = EnvelopeMeter::new(48_000.0);
+        // 2400 samples, a frame interval, at 10 per point
+        for n in 0..2400 {
+            let x = if n % 10 == 3 { 0.5 } else if n % 10 == 7 { -0.25 } else { 0.0 };
+            meter.push(x, -x, false);
+        }
+        let envelope = meter.take();
+        assert_eq!(envelope.samples_per_point, 10);
+        assert_eq!(envelope.left.len(), 2 * 240);
+        assert!(envelope.left.chunks(2).all(|point| point == [-0.25, 0.5]));
+        assert!(envelope.right.chunks(2).all(|point| point == [-0.5, 0.25]));
+
+        // Taken, the next starts empty
+        assert!(meter.take().left.is_empty());
+        meter.push(0.125, 0.0, true);
+        let mono = meter.take();
+        assert_eq!(mono.left, [0.125, 0.125]);
+        assert_eq!(mono.right, mono.left);
+    }
+
+    #[test]
+    fn test_long_intervals_merge_points() {
+        let mut meter = EnvelopeMeter::new(48_000.0);
+        // Three frame intervals' worth before a take: a ramp, so every
+        // point's extremes are its first and last samples
+        let len = 3 * 2400;
+        for n in 0..len {
+            meter.push(n as f32, 0.0, false);
+        }
+        let envelope = meter.take();
+        assert_eq!(envelope.samples_per_point, 40);
+        let points: Vec<&[f32]> = envelope.left.chunks(2).collect();
+        assert_eq!(points.len(), len / 40);
+        assert!(points.len() <= POINTS);
+        for (i, point) in points.iter().enumerate() {
+            assert_eq!(*point, [(i * 40) as f32, (i * 40 + 39) as f32]);
+        }
+        // Back to the short span
+        meter.push(0.0, 0.0, false);
+        assert_eq!(meter.take().samples_per_point, 10);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let mut meter = EnvelopeMeter::new(44_100.0);
+        for n in 0..2205 {
+            meter.push((n as f32 * 0.01).sin(), (n as f32 * 0.02).cos(), false);
+        }
+        let envelope = meter.take();
+        let packet = envelope.clone().into_packet(900).unwrap();
+        assert_eq!(packet.packet_type, PACKET_TYPE_WAVEFORM);
+        assert!(packet.left_bins.is_empty() && packet.left_wave.is_empty());
+
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.timestamp_ms), (44_100, 900));
+        assert_eq!(decoded.envelope_samples, envelope.samples_per_point);
+        assert_eq!(decoded.left_envelope, envelope.left);
+        assert_eq!(decoded.right_envelope, envelope.right);
+
+        assert!(Envelope::default().into_packet(0).is_none());
+    }
+}
@@ -0,0 +1,434 @@
+//! Errors for the whole crate
+//!
+//! Modules keep the error closest to their work (`AuthError`, `PresetError`,
+//! I/O errors); `HardwaveError` sorts them by layer, so a caller can tell a
+//! port that is taken from a Suite that isn't running without reading
+//! messages. Errors nobody up the stack can do anything about are counted in
+//! `ErrorStats` rather than dropped. The editor's `errors` IPC command and the
+//! packet server's `/errors` endpoint report the counts.
+
+use parking_lot::Mutex;
+#[cfg(any(feature = "gui", test))]
+use serde::Serialize;
+use std::fmt;
+use std::io;
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::auth::AuthError;
+use crate::packet_crypto::KeyError;
+use crate::tls::TlsError;
+use crate::panics::Panicked;
+
+/// Any error the plugin reports
+#[derive(Debug)]
+pub enum HardwaveError {
+    /// Streaming to the Suite or serving packets to the plugin window
+    Transport(TransportError),
+    /// A packet that would not encode or decode
+    Protocol(ProtocolError),
+    /// Creating or running the plugin window
+    #[cfg(any(feature = "gui", test))]
+    Editor(EditorError),
+    /// Reading or writing the login token
+    Auth(AuthError),
+    /// Reading the machine config
+    Config(ConfigError),
+    /// A panic caught before it reached the host, see `panics`
+    Panic(Panicked),
+}
+
+/// Errors moving packets between threads and over sockets
+#[derive(Debug)]
+pub enum TransportError {
+    /// `start` hasn't been called, so nothing takes packets yet
+    NotStarted,
+    /// The queue to the connection thread is full; the packet was dropped
+    QueueFull,
+    /// The thread at the other end of a channel is gone
+    Closed,
+    /// A listening socket couldn't be opened, e.g. because the port is taken
+    Bind(io::Error),
+    /// The Suite couldn't be reached
+    Connect(io::Error),
+    /// Something answered, but not with a WebSocket upgrade
+    Handshake(&'static str),
+    /// A connection that was up broke sending, reading or pinging; the
+    /// reason is the socket's
+    Lost(String),
+    /// The Suite answered the handshake with this status, 401 or 403: it
+    /// wants another token, see `auth`
+    Unauthorized(u16),
+    /// A key is set and the Suite didn't agree to encrypt with it
+    Encryption(&'static str),
+    /// TLS is on and the connection couldn't be secured, see `tls`
+    Tls(TlsError),
+    /// Browsing for the Suite failed, see `discovery`
+    Discovery(String),
+    /// A packet of this many bytes takes more datagrams than can be
+    /// counted, so it wasn't sent, see `udp`
+    Oversized(usize),
+    /// A packet of this many bytes couldn't be sealed with the packet key,
+    /// see `packet_crypto`
+    Seal(usize),
+    /// The port is one a plugin window in this process serves packets on,
+    /// not the Suite's, see `ports`
+    OwnPort(u16),
+    /// A thread couldn't be started
+    Spawn(io::Error),
+}
+
+/// Errors decoding a packet
+#[derive(Debug)]
+pub enum ProtocolError {
+    /// The bytes end before the named field, or it holds something no
+    /// packet does
+    #[cfg(test)]
+    Malformed(&'static str),
+    /// A legacy packet isn't bincode of one, see
+    /// `AudioPacket::from_bytes_legacy`
+    Codec(bincode::Error),
+    /// The packet says it has a layout this build can't read, see
+    /// `protocol::LAYOUT_VERSION`
+    #[cfg(test)]
+    UnknownVersion(u16),
+    /// The packet changed on the way: its checksum isn't that of its bytes,
+    /// see `checksum`
+    #[cfg(test)]
+    Checksum { sent: u32, computed: u32 },
+}
+
+/// Errors setting up the plugin window
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug)]
+pub enum EditorError {
+    /// The WebView2 runtime is missing and its installer couldn't be fetched
+    #[cfg(all(feature = "gui", target_os = "windows"))]
+    WebView2Download(io::Error),
+    /// The WebView2 installer failed
+    #[cfg(all(feature = "gui", target_os = "windows"))]
+    WebView2Install(io::Error),
+    /// None of these WebView2 data directories is writable
+    #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+    NoDataDir(Vec<PathBuf>),
+    /// wry couldn't build the webview
+    #[cfg(feature = "gui")]
+    WebView(wry::Error),
+    /// The editor thread couldn't be started
+    #[cfg(feature = "gui")]
+    Spawn(io::Error),
+}
+
+/// Errors reading `config::config_path`, or the packet key next to it. A
+/// missing file is not one.
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Json(serde_json::Error),
+    Key(KeyError),
+}
+
+impl HardwaveError {
+    /// Which layer the error comes from, as reported by `ErrorStats`
+    pub fn layer(&self) -> Layer {
+        match self {
+            HardwaveError::Transport(_) => Layer::Transport,
+            HardwaveError::Protocol(_) => Layer::Protocol,
+            #[cfg(any(feature = "gui", test))]
+            HardwaveError::Editor(_) => Layer::Editor,
+            HardwaveError::Auth(_) => Layer::Auth,
+            HardwaveError::Config(_) => Layer::Config,
+            HardwaveError::Panic(_) => Layer::Panic,
+        }
+    }
+}
+
+impl fmt::Display for HardwaveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HardwaveError::Transport(e) => write!(f, "transport: {}", e),
+            HardwaveError::Protocol(e) => write!(f, "protocol: {}", e),
+            #[cfg(any(feature = "gui", test))]
+            HardwaveError::Editor(e) => write!(f, "editor: {}", e),
+            HardwaveError::Auth(e) => write!(f, "auth: {}", e),
+            HardwaveError::Config(e) => write!(f, "config: {}", e),
+            HardwaveError::Panic(e) => write!(f, "panic: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for HardwaveError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            HardwaveError::Transport(e) => Some(e),
+            HardwaveError::Protocol(e) => Some(e),
+            #[cfg(any(feature = "gui", test))]
+            HardwaveError::Editor(e) => Some(e),
+            HardwaveError::Auth(e) => Some(e),
+            HardwaveError::Config(e) => Some(e),
+            HardwaveError::Panic(e) => Some(e),
+        }
+    }
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::NotStarted => write!(f, "not started"),
+            TransportError::QueueFull => write!(f, "packet queue full"),
+            TransportError::Closed => write!(f, "channel closed"),
+            TransportError::Bind(e) => write!(f, "bind failed: {}", e),
+            TransportError::Connect(e) => write!(f, "connect failed: {}", e),
+            TransportError::Handshake(why) => write!(f, "handshake failed: {}", why),
+            TransportError::Lost(why) => write!(f, "connection lost: {}", why),
+            TransportError::Unauthorized(status) => write!(f, "the Suite refused the token ({})", status),
+            TransportError::Encryption(why) => write!(f, "not streaming unencrypted: {}", why),
+            TransportError::Tls(e) => write!(f, "{}", e),
+            TransportError::Discovery(why) => write!(f, "mDNS discovery failed: {}", why),
+            TransportError::Oversized(len) => write!(f, "packet of {} bytes too large for datagrams", len),
+            TransportError::Seal(len) => write!(f, "packet of {} bytes could not be sealed", len),
+            TransportError::OwnPort(port) => write!(f, "port {} is the plugin window's packet server, not the Suite", port),
+            TransportError::Spawn(e) => write!(f, "thread spawn failed: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TransportError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TransportError::Bind(e) | TransportError::Connect(e) | TransportError::Spawn(e) => Some(e),
+            TransportError::Tls(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(test)]
+            ProtocolError::Malformed(field) => write!(f, "malformed packet: bad or missing {}", field),
+            ProtocolError::Codec(e) => write!(f, "malformed legacy packet: {}", e),
+            #[cfg(test)]
+            ProtocolError::UnknownVersion(version) => write!(f, "unknown packet layout version {}", version),
+            #[cfg(test)]
+            ProtocolError::Checksum { sent, computed } => {
+                write!(f, "packet checksum {:08x} doesn't match its bytes, {:08x}", sent, computed)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ProtocolError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ProtocolError::Codec(e) => Some(e),
+            #[cfg(test)]
+            ProtocolError::Malformed(_) | ProtocolError::UnknownVersion(_) | ProtocolError::Checksum { .. } => None,
+        }
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+impl fmt::Display for EditorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(all(feature = "gui", target_os = "windows"))]
+            EditorError::WebView2Download(e) => write!(f, "WebView2 download failed: {}", e),
+            #[cfg(all(feature = "gui", target_os = "windows"))]
+            EditorError::WebView2Install(e) => write!(f, "WebView2 install failed: {}", e),
+            #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+            EditorError::NoDataDir(tried) => write!(f, "no writable WebView2 data directory in {:?}", tried),
+            #[cfg(feature = "gui")]
+            EditorError::WebView(e) => write!(f, "webview creation failed: {}", e),
+            #[cfg(feature = "gui")]
+            EditorError::Spawn(e) => write!(f, "editor thread spawn failed: {}", e),
+        }
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+impl std::error::Error for EditorError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            #[cfg(all(feature = "gui", target_os = "windows"))]
+            EditorError::WebView2Download(e) | EditorError::WebView2Install(e) => Some(e),
+            #[cfg(feature = "gui")]
+            EditorError::Spawn(e) => Some(e),
+            #[cfg(feature = "gui")]
+            EditorError::WebView(e) => Some(e),
+            #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+            EditorError::NoDataDir(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "config file I/O error: {}", e),
+            ConfigError::Json(e) => write!(f, "malformed config file: {}", e),
+            ConfigError::Key(e) => write!(f, "packet key: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Json(e) => Some(e),
+            ConfigError::Key(e) => Some(e),
+        }
+    }
+}
+
+impl From<TransportError> for HardwaveError {
+    fn from(e: TransportError) -> Self {
+        HardwaveError::Transport(e)
+    }
+}
+
+impl From<ProtocolError> for HardwaveError {
+    fn from(e: ProtocolError) -> Self {
+        HardwaveError::Protocol(e)
+    }
+}
+
+impl From<bincode::Error> for HardwaveError {
+    fn from(e: bincode::Error) -> Self {
+        HardwaveError::Protocol(ProtocolError::Codec(e))
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+impl From<EditorError> for HardwaveError {
+    fn from(e: EditorError) -> Self {
+        HardwaveError::Editor(e)
+    }
+}
+
+impl From<AuthError> for HardwaveError {
+    fn from(e: AuthError) -> Self {
+        HardwaveError::Auth(e)
+    }
+}
+
+impl From<ConfigError> for HardwaveError {
+    fn from(e: ConfigError) -> Self {
+        HardwaveError::Config(e)
+    }
+}
+
+impl From<Panicked> for HardwaveError {
+    fn from(e: Panicked) -> Self {
+        HardwaveError::Panic(e)
+    }
+}
+
+impl From<KeyError> for HardwaveError {
+    fn from(e: KeyError) -> Self {
+        HardwaveError::Config(ConfigError::Key(e))
+    }
+}
+
+/// The variants of `HardwaveError`, without their details
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layer {
+    Transport,
+    Protocol,
+    Editor,
+    Auth,
+    Config,
+    Panic,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 6] =
+        [Layer::Transport, Layer::Protocol, Layer::Editor, Layer::Auth, Layer::Config, Layer::Panic];
+}
+
+/// Errors counted per layer since the instance was created, with the
+/// newest one's message. Shared by the connection thread and the editor.
+#[derive(Debug, Default)]
+pub struct ErrorStats {
+    counts: [AtomicU32; Layer::ALL.len()],
+    last: Mutex<Option<String>>,
+}
+
+impl ErrorStats {
+    /// Count `error` and keep its message. Formats and locks; not for the
+    /// audio thread.
+    pub fn record(&self, error: &HardwaveError) {
+        self.counts[error.layer() as usize].fetch_add(1, Ordering::Relaxed);
+        *self.last.lock() = Some(error.to_string());
+    }
+
+    #[cfg(any(feature = "gui", test))]
+    pub fn count(&self, layer: Layer) -> u32 {
+        self.counts[layer as usize].load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "gui", test))]
+    pub fn report(&self) -> ErrorReport {
+        ErrorReport {
+            transport: self.count(Layer::Transport),
+            protocol: self.count(Layer::Protocol),
+            editor: self.count(Layer::Editor),
+            auth: self.count(Layer::Auth),
+            config: self.count(Layer::Config),
+            panic: self.count(Layer::Panic),
+            last: self.last.lock().clone(),
+        }
+    }
+}
+
+/// What `ErrorStats` has seen, as JSON for the editor
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ErrorReport {
+    pub transport: u32,
+    pub protocol: u32,
+    pub editor: u32,
+    pub auth: u32,
+    pub config: u32,
+    pub panic: u32,
+    pub last: Option<String>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::error::Error;
+
+    #[test]
+    fn test_sources_are_kept() {
+        let refused = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let error = HardwaveError::from(TransportError::Connect(refused));
+        assert_eq!(error.layer(), Layer::Transport);
+        assert_eq!(error.to_string(), "transport: connect failed: refused");
+
+        let transport = error.source().unwrap();
+        let io = transport.source().unwrap().downcast_ref::<io::Error>().unwrap();
+        assert_eq!(io.kind(), io::ErrorKind::ConnectionRefused);
+
+        assert!(HardwaveError::from(TransportError::QueueFull).source().unwrap().source().is_none());
+    }
+
+    #[test]
+    fn test_stats_count_per_layer() {
+        let stats = ErrorStats::default();
+        assert_eq!(stats.report(), ErrorReport::default());
+
+        stats.record(&TransportError::Closed.into());
+        stats.record(&TransportError::NotStarted.into());
+        stats.record(&EditorError::NoDataDir(vec![PathBuf::from("/nope")]).into());
+        let report = stats.report();
+        assert_eq!((report.transport, report.editor, report.auth), (2, 1, 0));
+        assert_eq!(report.last.as_deref(), Some(r#"editor: no writable WebView2 data directory in ["/nope"]"#));
+
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.starts_with(r#"{"transport":2,"protocol":0,"editor":1,"#), "{}", json);
+    }
+}
@@ -1,66 +1,260 @@
 //! FFT processing for spectrum analysis
 //!
-//! Runs a 4096-point windowed FFT and returns all 2048 magnitude bins in dB.
-//! Frequency-to-display mapping and smoothing happen on the JS side.
+//! Runs a 4096-point windowed FFT and returns all 2048 magnitude bins in dB,
+//! or a 2048-point one (`ECO_FFT_SIZE`) in eco mode. Frequency-to-display
+//! mapping and bin smoothing happen on the JS side; the coarse bands get
+//! their attack and release smoothing in the plugin (`band_smoothing`).
+//!
+//! The input is real, so the transform is a real-to-complex one, half the
+//! work and memory of a complex FFT of the same length. It gives one bin
+//! more than is returned: Nyquist, which no packet carries.
+//!
+//! With zero padding the 4096 windowed samples are followed by zeros up to
+//! `FFT_SIZE * factor` before the transform. That interpolates the spectrum
+//! to `NUM_BINS * factor` bins without lengthening the window, so latency
+//! and true resolution stay those of 4096 samples.
+//!
+//! The window defaults to Hann; `WindowType` picks Blackman-Harris for tones
+//! close together, flat-top for amplitude accuracy or Kaiser instead. Each
+//! is scaled by its own coherent gain, so a full-scale sine on a bin reads
+//! 0 dB whichever it is. The table is built with the processor, off the
+//! audio thread like the rest of the analysis state.
 
-use rustfft::{num_complex::Complex, FftPlanner};
+use realfft::{num_complex::Complex, RealFftPlanner, RealToComplex};
 use std::f32::consts::PI;
+use std::mem::size_of;
+use std::sync::Arc;
 
+use crate::memory;
+use crate::params::{WindowType, ZeroPad};
 use crate::protocol::NUM_BINS;
+use crate::units::{self, FLOOR_DB};
 
 /// FFT size for analysis (NUM_BINS = FFT_SIZE / 2)
 pub const FFT_SIZE: usize = NUM_BINS * 2;
 
-/// FFT processor for a single channel
+/// FFT size in eco mode
+pub const ECO_FFT_SIZE: usize = FFT_SIZE / 2;
+
+/// Hop between FFTs when averaging over a send interval (75% overlap)
+#[cfg(test)]
+pub const HOP_SIZE: usize = FFT_SIZE / 4;
+
+/// Kaiser window shape, sidelobes about 60 dB down
+const KAISER_BETA: f32 = 8.6;
+
+/// Cosine-sum coefficients, `a0` first, which is also the coherent gain
+const BLACKMAN_HARRIS: [f32; 4] = [0.35875, 0.48829, 0.14128, 0.01168];
+const FLAT_TOP: [f32; 5] = [0.215_579, 0.416_632, 0.277_263, 0.083_579, 0.006_947];
+
+/// `window_len` samples of `kind`, symmetric, and its coherent gain
+fn window_table(kind: WindowType, window_len: usize) -> (Vec<f32>, f32) {
+    let phase = |i: usize| 2.0 * PI * i as f32 / (window_len - 1) as f32;
+    let cosine_sum = |coefficients: &[f32]| -> Vec<f32> {
+        (0..window_len)
+            .map(|i| {
+                coefficients
+                    .iter()
+                    .enumerate()
+                    .map(|(k, &a)| {
+                        let sign = if k % 2 == 0 { 1.0 } else { -1.0 };
+                        sign * a * (k as f32 * phase(i)).cos()
+                    })
+                    .sum()
+            })
+            .collect()
+    };
+    match kind {
+        // Exactly as before the window was selectable
+        WindowType::Hann => ((0..window_len).map(|i| 0.5 * (1.0 - phase(i).cos())).collect(), 0.5),
+        WindowType::BlackmanHarris => (cosine_sum(&BLACKMAN_HARRIS), BLACKMAN_HARRIS[0]),
+        WindowType::FlatTop => (cosine_sum(&FLAT_TOP), FLAT_TOP[0]),
+        WindowType::Kaiser => {
+            let half = (window_len - 1) as f32 / 2.0;
+            let table: Vec<f32> = (0..window_len)
+                .map(|i| {
+                    let x = (i as f32 - half) / half;
+                    bessel_i0(KAISER_BETA * (1.0 - x * x).max(0.0).sqrt()) / bessel_i0(KAISER_BETA)
+                })
+                .collect();
+            let gain = table.iter().sum::<f32>() / window_len as f32;
+            (table, gain)
+        }
+    }
+}
+
+/// Modified Bessel function of the first kind, order 0, by its series
+fn bessel_i0(x: f32) -> f32 {
+    let quarter_x2 = f64::from(x) * f64::from(x) / 4.0;
+    let (mut sum, mut term) = (1.0_f64, 1.0_f64);
+    for k in 1..50 {
+        term *= quarter_x2 / f64::from(k * k);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum as f32
+}
+
+/// FFT processor for a single channel. The plan, its scratch and the window
+/// are made once, when the processor is built, so `process_into` and
+/// `accumulate_power` never allocate; `process` only for the bins it returns.
 pub struct FftProcessor {
-    planner: FftPlanner<f32>,
-    fft_buffer: Vec<Complex<f32>>,
+    fft: Arc<dyn RealToComplex<f32>>,
+    /// Windowed and padded samples; the transform uses it as scratch too
+    input: Vec<f32>,
+    /// `input.len() / 2 + 1` bins, Nyquist last
+    spectrum: Vec<Complex<f32>>,
+    scratch: Vec<Complex<f32>>,
+    /// As long as the analysis window
     window: Vec<f32>,
+    #[cfg(test)]
+    window_type: WindowType,
+    /// Bin magnitude to amplitude: 2 / (window_len * coherent gain)
+    amplitude_scale: f32,
 }
 
 impl FftProcessor {
+    /// Most `heap_bytes` can report: the input at the largest padding, half as
+    /// many bins plus Nyquist, at most half as many for scratch, and the
+    /// window
+    pub const MAX_HEAP_BYTES: usize = FFT_SIZE
+        * (ZeroPad::MAX_FACTOR * (size_of::<f32>() + size_of::<Complex<f32>>()) + size_of::<f32>())
+        + size_of::<Complex<f32>>();
+
+    /// The same for a processor without padding
+    pub const UNPADDED_MAX_HEAP_BYTES: usize =
+        FFT_SIZE * (size_of::<f32>() + size_of::<Complex<f32>>() + size_of::<f32>()) + size_of::<Complex<f32>>();
+
     pub fn new() -> Self {
-        // Pre-compute Hann window
-        let window: Vec<f32> = (0..FFT_SIZE)
-            .map(|i| 0.5 * (1.0 - (2.0 * PI * i as f32 / (FFT_SIZE - 1) as f32).cos()))
-            .collect();
+        Self::with_zero_pad(1)
+    }
+
+    /// A processor transforming `FFT_SIZE * factor` points
+    pub fn with_zero_pad(factor: usize) -> Self {
+        Self::with_window(FFT_SIZE, factor)
+    }
+
+    /// A processor windowing `window_len` samples, at most `FFT_SIZE`, and
+    /// transforming `window_len * factor` points
+    pub fn with_window(window_len: usize, factor: usize) -> Self {
+        Self::with_window_type(window_len, factor, WindowType::Hann)
+    }
 
+    /// The same with a `kind` window instead of Hann
+    pub fn with_window_type(window_len: usize, factor: usize, kind: WindowType) -> Self {
+        let window_len = window_len.clamp(2, FFT_SIZE);
+        let size = window_len * factor.clamp(1, ZeroPad::MAX_FACTOR);
+        let (window, coherent_gain) = window_table(kind, window_len);
+
+        let fft = RealFftPlanner::new().plan_fft_forward(size);
         Self {
-            planner: FftPlanner::new(),
-            fft_buffer: vec![Complex::new(0.0, 0.0); FFT_SIZE],
+            input: fft.make_input_vec(),
+            spectrum: fft.make_output_vec(),
+            scratch: fft.make_scratch_vec(),
+            fft,
+            amplitude_scale: 2.0 / (window_len as f32 * coherent_gain),
             window,
+            #[cfg(test)]
+            window_type: kind,
         }
     }
 
-    /// Process audio samples and return NUM_BINS raw magnitude values in dB.
+    /// Heap bytes of the buffers, not counting the plan
+    pub fn heap_bytes(&self) -> usize {
+        memory::vec_bytes(&self.input)
+            + memory::vec_bytes(&self.spectrum)
+            + memory::vec_bytes(&self.scratch)
+            + memory::vec_bytes(&self.window)
+    }
+
+    /// Bins `process` returns: half the window length times the padding
+    /// factor, `NUM_BINS` times it for the full window
+    pub fn num_bins(&self) -> usize {
+        self.input.len() / 2
+    }
+
+    /// Samples each transform windows
+    pub fn window_len(&self) -> usize {
+        self.window.len()
+    }
+
+    #[cfg(test)]
+    pub fn window_type(&self) -> WindowType {
+        self.window_type
+    }
+
+    /// 2 / (window_len * coherent_gain), 4 / window_len for Hann's 0.5.
+    /// Without the gain a 0 dBFS sine reads −6 dB through Hann and more
+    /// through the others. It is the window length that counts, not the
+    /// transform length, so padding doesn't change it.
+    fn amplitude_scale(&self) -> f32 {
+        self.amplitude_scale
+    }
+
+    /// Window the first `window_len` samples, pad them and transform into
+    /// `spectrum`.
+    fn transform(&mut self, samples: &[f32]) {
+        // Apply the window and copy to the input buffer
+        let window_len = self.window.len();
+        for ((out, &s), &w) in self.input.iter_mut().zip(samples).zip(&self.window) {
+            *out = s * w;
+        }
+        self.input[window_len..].fill(0.0);
+
+        // Only fails for buffers of the wrong length, and these were made by
+        // the plan
+        let _ = self.fft.process_with_scratch(&mut self.input, &mut self.spectrum, &mut self.scratch);
+    }
+
+    /// Process audio samples and return `num_bins` raw magnitude values in dB.
     ///
-    /// Bin `i` corresponds to frequency `i * sample_rate / FFT_SIZE` Hz.
-    /// Bins above ~20 kHz are included but will be ignored by the JS renderer.
+    /// Bin `i` corresponds to frequency `i * sample_rate / (window_len *
+    /// factor)` Hz. Bins above ~20 kHz are included but will be ignored by
+    /// the JS renderer.
     pub fn process(&mut self, samples: &[f32], _sample_rate: f32) -> Vec<f32> {
-        if samples.len() < FFT_SIZE {
-            return vec![-100.0; NUM_BINS];
+        let mut bins = vec![FLOOR_DB; self.num_bins()];
+        self.process_into(samples, &mut bins);
+        bins
+    }
+
+    /// `process` into `bins`, which holds `num_bins` values, without
+    /// allocating. Too few samples leave it untouched.
+    pub fn process_into(&mut self, samples: &[f32], bins: &mut [f32]) {
+        if samples.len() < self.window_len() {
+            return;
         }
+        self.transform(samples);
 
-        // Apply Hann window and copy to FFT buffer
-        for i in 0..FFT_SIZE {
-            self.fft_buffer[i] = Complex::new(samples[i] * self.window[i], 0.0);
+        let scale = self.amplitude_scale();
+        for (out, bin) in bins.iter_mut().zip(&self.spectrum[..self.num_bins()]) {
+            *out = units::linear_to_db(bin.norm() * scale).min(0.0);
         }
+    }
 
-        // In-place forward FFT
-        let fft = self.planner.plan_fft_forward(FFT_SIZE);
-        fft.process(&mut self.fft_buffer);
+    /// Add the power spectrum of one window (scaled like `process`) to `acc`,
+    /// which holds `num_bins` sums.
+    pub fn accumulate_power(&mut self, samples: &[f32], acc: &mut [f32]) {
+        let bins = self.num_bins();
+        if samples.len() < self.window_len() || acc.len() < bins {
+            return;
+        }
+        self.transform(samples);
 
-        // Convert bins to dB.
-        // Hann window coherent gain = 0.5, so the correct amplitude scale is:
-        //   2 / (FFT_SIZE * coherent_gain) = 4 / FFT_SIZE
-        // Without this correction a 0 dBFS sine reads −6 dB.
-        let scale = 4.0 / FFT_SIZE as f32;
-        (0..NUM_BINS)
-            .map(|i| {
-                let mag = self.fft_buffer[i].norm() * scale;
-                let db = 20.0 * (mag + 1e-10).log10();
-                db.clamp(-100.0, 0.0)
-            })
+        let scale = self.amplitude_scale();
+        for (sum, bin) in acc.iter_mut().zip(&self.spectrum[..bins]) {
+            *sum += bin.norm_sqr() * scale * scale;
+        }
+    }
+
+    /// dB spectrum of the mean of `count` accumulated power spectra.
+    pub fn mean_power_db(acc: &[f32], count: usize) -> Vec<f32> {
+        if count == 0 {
+            return vec![FLOOR_DB; acc.len()];
+        }
+        acc.iter()
+            .map(|&sum| units::power_to_db(sum / count as f32).min(0.0))
             .collect()
     }
 
@@ -68,7 +262,7 @@ impl FftProcessor {
     /// Returns (peak_db, rms_linear).
     pub fn calculate_levels(samples: &[f32]) -> (f32, f32) {
         if samples.is_empty() {
-            return (-100.0, 0.0);
+            return (FLOOR_DB, 0.0);
         }
 
         let mut peak = 0.0_f32;
@@ -80,7 +274,7 @@ impl FftProcessor {
         }
 
         let rms = (sum_squares / samples.len() as f32).sqrt();
-        let peak_db = (20.0 * (peak + 1e-10).log10()).clamp(-100.0, 0.0);
+        let peak_db = units::linear_to_db(peak).min(0.0);
 
         (peak_db, rms)
     }
@@ -92,6 +286,64 @@ impl Default for FftProcessor {
     }
 }
 
+/// The last `capacity` samples of one channel. Each is written twice,
+/// `capacity` apart, so the buffered ones are always one contiguous slice,
+/// oldest first, for the FFT to window without copying; a push is O(1)
+/// however long the history. Allocated in `new`, never after.
+#[derive(Debug, Clone)]
+pub struct RingBuffer {
+    data: Vec<f32>,
+    capacity: usize,
+    /// Where the next sample goes, in `0..capacity`
+    next: usize,
+    len: usize,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        Self { data: vec![0.0; 2 * capacity], capacity, next: 0, len: 0 }
+    }
+
+    /// Append a sample, dropping the oldest once `capacity` are buffered
+    pub fn push(&mut self, sample: f32) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.data[self.next] = sample;
+        self.data[self.next + self.capacity] = sample;
+        self.next = (self.next + 1) % self.capacity;
+        self.len = (self.len + 1).min(self.capacity);
+    }
+
+    /// The buffered samples, oldest first
+    pub fn as_slice(&self) -> &[f32] {
+        let end = self.next + self.capacity;
+        &self.data[end - self.len..end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    pub fn clear(&mut self) {
+        self.next = 0;
+        self.len = 0;
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        memory::vec_bytes(&self.data)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -133,6 +385,373 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_real_fft_matches_the_complex_fft() {
+        let mut seed = 0x0bad_5eed_u32;
+        let mut next_noise = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as f32 / u32::MAX as f32 - 0.5
+        };
+        let noise: Vec<f32> = (0..FFT_SIZE).map(|_| next_noise()).collect();
+        // 20 Hz to 20 kHz over the window
+        let mut phase = 0.0_f32;
+        let sweep: Vec<f32> = (0..FFT_SIZE)
+            .map(|i| {
+                phase += 2.0 * PI * 20.0 * 1000.0_f32.powf(i as f32 / FFT_SIZE as f32) / 48000.0;
+                0.8 * phase.sin()
+            })
+            .collect();
+        let silence = vec![0.0; FFT_SIZE];
+        let signals = [("noise", &noise), ("sweep", &sweep), ("silence", &silence), ("sine", &sine(1000.0, 1.0))];
+
+        let db = |magnitude: f32| units::linear_to_db(magnitude).min(0.0);
+        for (name, samples) in signals {
+            for (window_len, factor, kind) in [
+                (FFT_SIZE, 1, WindowType::Hann),
+                (FFT_SIZE, 4, WindowType::Hann),
+                (ECO_FFT_SIZE, 2, WindowType::BlackmanHarris),
+                (FFT_SIZE, 1, WindowType::FlatTop),
+            ] {
+                let what = format!("{} through {:?}, {} x {}", name, kind, window_len, factor);
+                let mut processor = FftProcessor::with_window_type(window_len, factor, kind);
+                let expected = complex_fft_magnitudes(&processor, samples);
+                assert_same_db(&processor.process(samples, 48000.0), &expected, db, &what);
+
+                let mut power = vec![0.0; processor.num_bins()];
+                processor.accumulate_power(samples, &mut power);
+                let expected_power: Vec<f32> = expected.iter().map(|m| m * m).collect();
+                assert_same_db(
+                    &FftProcessor::mean_power_db(&power, 1),
+                    &expected_power,
+                    |power| units::power_to_db(power).min(0.0),
+                    &what,
+                );
+            }
+        }
+
+        assert!(FftProcessor::with_zero_pad(4).heap_bytes() <= FftProcessor::MAX_HEAP_BYTES);
+        assert!(FftProcessor::new().heap_bytes() <= FftProcessor::UNPADDED_MAX_HEAP_BYTES);
+    }
+
+    #[test]
+    fn test_repeated_transforms_match_and_never_allocate() {
+        let samples = sine(1000.0, 0.5);
+        let mut processor = FftProcessor::with_zero_pad(2);
+        let first = processor.process(&samples, 48000.0);
+        assert_eq!(processor.process(&samples, 48000.0), first);
+
+        let mut bins = vec![0.0; processor.num_bins()];
+        let mut power = vec![0.0; processor.num_bins()];
+        let ((), heap_ops) = crate::analysis::tests::heap_ops_in(|| {
+            for _ in 0..20 {
+                processor.process_into(&samples, &mut bins);
+                processor.accumulate_power(&samples, &mut power);
+            }
+        });
+        assert_eq!(heap_ops, 0, "transforming allocated or freed {} times", heap_ops);
+        assert_eq!(bins, first);
+    }
+
+    #[test]
+    fn test_full_scale_sine_reads_0_db_through_every_window() {
+        let peak_db = |kind: WindowType, sample_rate: f32| {
+            let samples: Vec<f32> =
+                (0..FFT_SIZE).map(|i| (2.0 * PI * 1000.0 * i as f32 / sample_rate).sin()).collect();
+            let mut processor = FftProcessor::with_window_type(FFT_SIZE, 1, kind);
+            assert_eq!(processor.window_type(), kind);
+            processor.process(&samples, sample_rate).into_iter().fold(FLOOR_DB, f32::max)
+        };
+        let kinds = [WindowType::Hann, WindowType::BlackmanHarris, WindowType::FlatTop, WindowType::Kaiser];
+        for kind in kinds {
+            // 1 kHz is bin 128 exactly at 32 kHz
+            let on_bin = peak_db(kind, 32000.0);
+            assert!(on_bin.abs() < 0.5, "{:?} reads {} dB", kind, on_bin);
+        }
+
+        // A third of a bin off, only flat-top still reads the amplitude
+        let flat_top = peak_db(WindowType::FlatTop, 48000.0);
+        assert!(flat_top.abs() < 0.1, "flat-top reads {} dB", flat_top);
+        assert!(peak_db(WindowType::Hann, 48000.0) < -0.3);
+
+        // Hann is still the default, and the same as ever
+        assert_eq!(FftProcessor::new().window_type(), WindowType::Hann);
+        assert_eq!(FftProcessor::new().amplitude_scale(), 4.0 / FFT_SIZE as f32);
+    }
+
+    fn sine(hz: f32, amplitude: f32) -> Vec<f32> {
+        (0..FFT_SIZE)
+            .map(|i| amplitude * (2.0 * PI * hz * i as f32 / 48000.0).sin())
+            .collect()
+    }
+
+    #[test]
+    fn test_zero_pad_keeps_calibration() {
+        // 0 dBFS on unpadded bin 100
+        let samples = sine(100.0 * 48000.0 / FFT_SIZE as f32, 1.0);
+        let unpadded = FftProcessor::new().process(&samples, 48000.0);
+        for factor in [1, 2, 4] {
+            let mut processor = FftProcessor::with_zero_pad(factor);
+            let bins = processor.process(&samples, 48000.0);
+            assert_eq!(bins.len(), NUM_BINS * factor);
+            assert_eq!(processor.num_bins(), bins.len());
+            assert!(bins[100 * factor].abs() < 0.01, "{}x reads {} dB", factor, bins[100 * factor]);
+
+            // Every factor-th bin is an unpadded one; the rest interpolate
+            for k in (0..NUM_BINS).step_by(97) {
+                assert!((bins[k * factor] - unpadded[k]).abs() < 0.01, "{}x bin {}", factor, k);
+            }
+        }
+    }
+
+    #[test]
+    fn test_eco_window_keeps_calibration() {
+        // 0 dBFS on bin 50 of the half-length window; only its first half
+        // of the samples is windowed
+        let samples = sine(50.0 * 48000.0 / ECO_FFT_SIZE as f32, 1.0);
+        let mut processor = FftProcessor::with_window(ECO_FFT_SIZE, 1);
+        assert_eq!(processor.window_len(), ECO_FFT_SIZE);
+        let bins = processor.process(&samples[..ECO_FFT_SIZE], 48000.0);
+        assert_eq!(bins.len(), NUM_BINS / 2);
+        assert!(bins[50].abs() < 0.01, "reads {} dB", bins[50]);
+        assert!(processor.heap_bytes() < FftProcessor::new().heap_bytes());
+
+        let mut padded = FftProcessor::with_window(ECO_FFT_SIZE, 2);
+        assert_eq!(padded.process(&samples, 48000.0).len(), NUM_BINS);
+    }
+
+    #[test]
+    fn test_zero_pad_finds_off_bin_peaks() {
+        // Halfway between two unpadded bins: the unpadded spectrum reads the
+        // Hann scalloping loss, the padded one is sampled at the peak
+        let samples = sine(100.5 * 48000.0 / FFT_SIZE as f32, 1.0);
+        let peak = |bins: Vec<f32>| bins.into_iter().fold(f32::MIN, f32::max);
+        let unpadded = peak(FftProcessor::new().process(&samples, 48000.0));
+        let padded = peak(FftProcessor::with_zero_pad(4).process(&samples, 48000.0));
+        assert!((unpadded - (-1.42)).abs() < 0.1, "unpadded peak {} dB", unpadded);
+        assert!(padded.abs() < 0.05, "padded peak {} dB", padded);
+    }
+
+    #[test]
+    fn test_zero_pad_separates_close_low_tones() {
+        // 50 and 72 Hz, under two unpadded bins (11.7 Hz) apart. That is as
+        // close as the Hann window can resolve at all; the unpadded bins
+        // sample the two lobes too coarsely to show the dip between them.
+        let samples: Vec<f32> = sine(50.0, 0.5).iter().zip(sine(72.0, 0.5)).map(|(a, b)| a + b).collect();
+        let peaks_below_100_hz = |factor: usize| {
+            let bins = FftProcessor::with_zero_pad(factor).process(&samples, 48000.0);
+            let top = (100.0 / (48000.0 / (FFT_SIZE * factor) as f32)) as usize;
+            (1..top).filter(|&k| bins[k] > bins[k - 1] && bins[k] >= bins[k + 1] && bins[k] > -20.0).count()
+        };
+        assert_eq!(peaks_below_100_hz(1), 1);
+        assert_eq!(peaks_below_100_hz(4), 2);
+    }
+
+    /// Deterministic sine plus noise fixture covering most of the dB range
+    fn fixture() -> Vec<f32> {
+        let mut seed = 0x1234_5678_u32;
+        (0..FFT_SIZE)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (seed >> 8) as f32 / (1u32 << 24) as f32 - 0.5;
+                0.7 * (2.0 * PI * 1000.0 * i as f32 / 48000.0).sin() + 1e-3 * noise
+            })
+            .collect()
+    }
+
+    /// The dB conversion used before `units` existed
+    fn legacy_db(x: f32) -> f32 {
+        (20.0 * (x + 1e-10).log10()).clamp(-100.0, 0.0)
+    }
+
+    /// The magnitudes `processor` should find in `samples`, by the complex
+    /// FFT it used before the real one
+    fn complex_fft_magnitudes(processor: &FftProcessor, samples: &[f32]) -> Vec<f32> {
+        let mut buffer = vec![Complex::new(0.0, 0.0); processor.input.len()];
+        for ((out, s), w) in buffer.iter_mut().zip(samples).zip(&processor.window) {
+            *out = Complex::new(s * w, 0.0);
+        }
+        rustfft::FftPlanner::new().plan_fft_forward(buffer.len()).process(&mut buffer);
+        buffer[..processor.num_bins()].iter().map(|c| c.norm() * processor.amplitude_scale()).collect()
+    }
+
+    /// Asserts `bins` are the dB of `magnitudes` up to rounding, which only
+    /// shows near the floor, where a bin is mostly rounding anyway
+    fn assert_same_db(bins: &[f32], magnitudes: &[f32], db: impl Fn(f32) -> f32, what: &str) {
+        assert_eq!(bins.len(), magnitudes.len(), "{}", what);
+        for (i, (&bin, &magnitude)) in bins.iter().zip(magnitudes).enumerate() {
+            let expected = db(magnitude);
+            let tolerance = if expected > -80.0 { 0.01 } else { 1.0 };
+            assert!(
+                (bin - expected).abs() <= tolerance,
+                "{}: bin {} reads {} dB, the complex FFT {} dB",
+                what,
+                i,
+                bin,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn test_units_refactor_matches_legacy_math() {
+        let samples = fixture();
+
+        // Same FFT as `process`, converted with the legacy formula
+        let processor = FftProcessor::new();
+        let expected = complex_fft_magnitudes(&processor, &samples);
+        let bins = FftProcessor::new().process(&samples, 48000.0);
+        assert_same_db(&bins, &expected, legacy_db, "fixture");
+
+        let (peak_db, rms) = FftProcessor::calculate_levels(&samples);
+        let legacy_peak = samples.iter().fold(0.0_f32, |m, s| m.max(s.abs()));
+        assert_eq!(peak_db, legacy_db(legacy_peak));
+        assert!((rms - 0.7 / 2.0_f32.sqrt()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_interval_average_matches_offline_stft() {
+        // Tone that fades in over the interval, on top of low noise
+        let hops = 3;
+        let len = FFT_SIZE + (hops - 1) * HOP_SIZE;
+        let mut seed = 42_u32;
+        let samples: Vec<f32> = (0..len)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let noise = (seed >> 8) as f32 / (1u32 << 24) as f32 - 0.5;
+                let fade = i as f32 / len as f32;
+                fade * (2.0 * PI * 3000.0 * i as f32 / 48000.0).sin() + 0.01 * noise
+            })
+            .collect();
+
+        let mut processor = FftProcessor::new();
+        let mut acc = vec![0.0; NUM_BINS];
+        for hop in 0..hops {
+            processor.accumulate_power(&samples[hop * HOP_SIZE..], &mut acc);
+        }
+        let averaged = FftProcessor::mean_power_db(&acc, hops);
+
+        // Offline reference: plain DFT of each Hann-windowed frame, powers averaged
+        let window = &processor.window;
+        for k in (0..NUM_BINS).step_by(37) {
+            let mut power = 0.0_f64;
+            for hop in 0..hops {
+                let frame = &samples[hop * HOP_SIZE..hop * HOP_SIZE + FFT_SIZE];
+                let (mut re, mut im) = (0.0_f64, 0.0_f64);
+                for (n, (&x, &w)) in frame.iter().zip(window).enumerate() {
+                    let phase = -2.0 * std::f64::consts::PI * (k * n) as f64 / FFT_SIZE as f64;
+                    re += (x * w) as f64 * phase.cos();
+                    im += (x * w) as f64 * phase.sin();
+                }
+                let scale = 4.0 / FFT_SIZE as f64;
+                power += (re * re + im * im) * scale * scale;
+            }
+            let reference = (10.0 * (power / hops as f64).log10()).clamp(-100.0, 0.0) as f32;
+            assert!(
+                (averaged[k] - reference).abs() < 1.0,
+                "bin {}: {} dB vs reference {} dB",
+                k,
+                averaged[k],
+                reference
+            );
+        }
+    }
+
+    #[test]
+    fn test_non_finite_input_is_contained() {
+        use crate::meter::ChannelMeter;
+        use crate::units::SampleGuard;
+
+        let mut guard = SampleGuard::default();
+        let mut processor = FftProcessor::new();
+        let mut meter = ChannelMeter::default();
+        let mut buffer = RingBuffer::new(FFT_SIZE);
+        let mut reported = Vec::new();
+
+        // Broken samples mid-stream, then a clean stretch
+        for frame in 0..4 {
+            for i in 0..FFT_SIZE {
+                let x = match (frame, i) {
+                    (1, 100) | (1, 2000) => f32::NAN,
+                    (1, 3000) => f32::INFINITY,
+                    _ => 0.5 * (2.0 * PI * 1000.0 * i as f32 / 48000.0).sin(),
+                };
+                buffer.push(guard.clean(x));
+            }
+
+            let bins = processor.process(buffer.as_slice(), 48000.0);
+            let (peak, rms) = FftProcessor::calculate_levels(buffer.as_slice());
+            let peak_meter = meter.peak.update(peak, 0.05, 1.5, 20.0);
+            let rms_meter = meter.rms.update(rms, 0.05, 0.3, 0.3);
+            assert!(bins.iter().all(|b| b.is_finite()), "frame {} has non-finite bins", frame);
+            assert!(peak.is_finite() && rms.is_finite());
+            assert!(peak_meter.is_finite() && rms_meter.is_finite());
+            reported.push(guard.take());
+        }
+        assert_eq!(reported, vec![0, 3, 0, 0]);
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_the_latest_window_in_order() {
+        let mut ring = RingBuffer::new(FFT_SIZE);
+        assert!(ring.is_empty());
+        for i in 0..100 {
+            ring.push(i as f32);
+        }
+        let expected: Vec<f32> = (0..100).map(|i| i as f32).collect();
+        assert_eq!(ring.as_slice(), &expected[..]);
+
+        // Any number of wraps later, the newest FFT_SIZE, oldest first
+        let total = 3 * FFT_SIZE + 123;
+        for i in 100..total {
+            ring.push(i as f32);
+        }
+        assert_eq!(ring.len(), FFT_SIZE);
+        let expected: Vec<f32> = (total - FFT_SIZE..total).map(|i| i as f32).collect();
+        assert_eq!(ring.as_slice(), &expected[..]);
+        assert_eq!(ring.heap_bytes(), 2 * FFT_SIZE * size_of::<f32>());
+
+        ring.clear();
+        ring.push(-1.0);
+        assert_eq!(ring.as_slice(), &[-1.0]);
+    }
+
+    #[test]
+    fn test_ring_buffer_throughput() {
+        // 0.5 s at 96 kHz into the longest history a config keeps, against
+        // shifting a Vec down by one per sample as the buffers once did
+        const SAMPLES: usize = 48_000;
+        let len = crate::analysis::MAX_HISTORY_LEN;
+        let mut ring = RingBuffer::new(len);
+        let mut shifted: Vec<f32> = vec![0.0; len];
+        for i in 0..len {
+            ring.push(i as f32);
+        }
+
+        let start = std::time::Instant::now();
+        for i in 0..SAMPLES {
+            ring.push(i as f32);
+        }
+        let ring_time = start.elapsed();
+
+        let start = std::time::Instant::now();
+        for i in 0..SAMPLES {
+            shifted.remove(0);
+            shifted.push(i as f32);
+        }
+        let shifted_time = start.elapsed();
+
+        assert_eq!(ring.as_slice(), &shifted[..]);
+        assert!(
+            ring_time * 10 < shifted_time,
+            "ring {:?}, shifted Vec {:?} for {} samples",
+            ring_time,
+            shifted_time,
+            SAMPLES
+        );
+    }
+
     #[test]
     fn test_calculate_levels() {
         let samples = vec![0.5f32, -0.5, 0.5, -0.5];
@@ -0,0 +1,609 @@
+//! The last few seconds of frames, frozen and scrubbed to inspect a transient
+//!
+//! A click or a transient is gone from the display before anyone can look
+//! at it. The plugin keeps the FFT frames it sent, up to `HISTORY_MS` (5 s)
+//! of them at the 20 Hz analysis rate and never more than `HISTORY_BYTES`,
+//! which at the larger zero-paddings is fewer seconds. The Suite or the
+//! plugin window can freeze them:
+//!
+//! - `history.freeze` stops recording at the newest frame. Analysis goes
+//!   on, but each frame it makes is replaced on the way out by the frozen
+//!   frame at the scrub position, which starts at the freeze point. Served
+//!   frames are flagged `historical` and keep their original timestamps.
+//! - `history.scrub` moves the position, from 0 (the oldest frame kept) to
+//!   1 (the freeze point); anything outside is clamped.
+//! - `history.resume` goes back to live frames, from analysis that never
+//!   stopped, and recording carries on from there.
+//!
+//! The Suite sends them as text messages, `{"type":"history.freeze"}`,
+//! `{"type":"history.scrub","position":0.25}` and
+//! `{"type":"history.resume"}`. The page posts `history.freeze`,
+//! `history.scrub:0.25` and `history.resume`. Either way they are requests,
+//! which the audio thread takes at its next frame.
+//!
+//! The Freeze parameter is a third way in, for holding the spectrum from
+//! the host: switching it on is a `history.freeze`, which serves the newest
+//! frame until something scrubs, and switching it off a `history.resume`.
+//! It acts only when it changes, so the Suite or the window can still
+//! resume or freeze in between.
+//!
+//! Frames are kept as the `Arc`s published on the bus, so recording one
+//! costs a pointer. Neither freeing a frame the history lets go of nor
+//! copying a frozen one happens on the audio thread: both are handed to
+//! the history's thread, which drops the first and sends back the second,
+//! flagged. The copy is made once per position and goes out as the same
+//! `Arc` until the position moves. Until the first one is back, a frame or
+//! so after the freeze, nothing goes out in place of the live frames.
+
+use atomic_float::AtomicF32;
+use crossbeam_channel::{bounded, Receiver, Sender};
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::protocol::AudioPacket;
+use crate::threads::{self, Priority};
+
+/// Span of frames kept, newest to oldest
+pub const HISTORY_MS: u64 = 5000;
+
+/// Frames kept at most: `HISTORY_MS` at 20 Hz. The ring never grows past it.
+pub const HISTORY_FRAMES: usize = 100;
+
+/// Heap the kept frames may hold; the oldest go first
+pub const HISTORY_BYTES: usize = 3 * 1024 * 1024;
+
+/// How often the history's thread looks for frames to drop and copy
+const HISTORY_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Frames the audio thread can let go of before the history's thread
+/// catches up: every kept frame, as a much larger frame can push them all
+/// out at once, and the copies
+const RETIRE_QUEUE_LEN: usize = HISTORY_FRAMES + 4;
+
+/// A request to the history
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HistoryCommand {
+    Freeze,
+    /// Serve the frozen frame at this position, 0 (oldest) to 1 (the freeze
+    /// point)
+    Scrub(f32),
+    Resume,
+}
+
+impl HistoryCommand {
+    /// `name` with an argument, as both the Suite and the page spell them
+    fn from_parts(name: &str, position: Option<f32>) -> Option<Self> {
+        match name {
+            "history.freeze" => Some(HistoryCommand::Freeze),
+            "history.scrub" => position.filter(|p| p.is_finite()).map(HistoryCommand::Scrub),
+            "history.resume" => Some(HistoryCommand::Resume),
+            _ => None,
+        }
+    }
+
+    /// The command in a message from the page, if it is one
+    #[cfg(any(feature = "gui", test))]
+    pub fn parse_ipc(msg: &str) -> Option<Self> {
+        match msg.split_once(':') {
+            Some((name, position)) => Self::from_parts(name, position.trim().parse().ok()),
+            None => Self::from_parts(msg, None),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct CommandMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    position: Option<f32>,
+}
+
+/// The history command in a text message from the Suite, if it is one
+pub fn parse_command(text: &str) -> Option<HistoryCommand> {
+    let message: CommandMessage = serde_json::from_str(text).ok()?;
+    HistoryCommand::from_parts(&message.kind, message.position)
+}
+
+/// What was last asked of the history; set by the connection thread and the
+/// editor, read by the plugin every frame
+#[derive(Debug)]
+pub struct HistoryControl {
+    frozen: AtomicBool,
+    position: AtomicF32,
+}
+
+impl Default for HistoryControl {
+    fn default() -> Self {
+        Self {
+            frozen: AtomicBool::new(false),
+            position: AtomicF32::new(1.0),
+        }
+    }
+}
+
+impl HistoryControl {
+    pub fn apply(&self, command: HistoryCommand) {
+        match command {
+            HistoryCommand::Freeze => {
+                // Freezing again keeps the frames and the position
+                if !self.frozen.swap(true, Ordering::Relaxed) {
+                    self.position.store(1.0, Ordering::Relaxed);
+                }
+            }
+            HistoryCommand::Scrub(position) => self.position.store(position.clamp(0.0, 1.0), Ordering::Relaxed),
+            HistoryCommand::Resume => self.frozen.store(false, Ordering::Relaxed),
+        }
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen.load(Ordering::Relaxed)
+    }
+
+    pub fn position(&self) -> f32 {
+        self.position.load(Ordering::Relaxed)
+    }
+}
+
+/// The recent frames, owned by the audio thread
+#[derive(Debug)]
+pub struct FrameHistory {
+    /// Oldest first
+    frames: VecDeque<Arc<AudioPacket>>,
+    /// Heap held by `frames`' packets
+    bytes: usize,
+    frozen: bool,
+    /// Counts freezes, so a copy asked for by an earlier one is told apart
+    freezes: u64,
+    /// The historical copy last served, for which freeze and index; served
+    /// again while the position stays on it
+    served: Option<(u64, usize, Arc<AudioPacket>)>,
+    /// Index whose copy was asked of the thread and isn't back yet
+    requested: Option<usize>,
+    worker: Option<Worker>,
+}
+
+/// A frame to be copied, flagged, for a freeze and index
+type CopyRequest = (u64, usize, Arc<AudioPacket>);
+
+#[derive(Debug)]
+struct Worker {
+    retired: Sender<Arc<AudioPacket>>,
+    requests: Sender<CopyRequest>,
+    copies: Receiver<CopyRequest>,
+    /// Dropping it stops the thread at once
+    stop: mpsc::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl FrameHistory {
+    /// Most `heap_bytes` can report
+    pub const MAX_HEAP_BYTES: usize = HISTORY_FRAMES * size_of::<Arc<AudioPacket>>() + HISTORY_BYTES;
+
+    pub fn new() -> Self {
+        Self {
+            frames: VecDeque::with_capacity(HISTORY_FRAMES),
+            bytes: 0,
+            frozen: false,
+            freezes: 0,
+            served: None,
+            requested: None,
+            worker: None,
+        }
+    }
+
+    /// Start the history's thread unless it runs. Deferred from `new` like
+    /// the config worker, so plugin scans don't spawn threads; until then
+    /// frames are dropped and copied by the caller.
+    pub fn start(&mut self) {
+        if self.worker.is_some() {
+            return;
+        }
+        let (retired, retired_rx) = bounded::<Arc<AudioPacket>>(RETIRE_QUEUE_LEN);
+        let (requests, request_rx) = bounded::<CopyRequest>(2);
+        let (copies_tx, copies) = bounded::<CopyRequest>(2);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let spawned = threads::spawn("hardwave-history", Priority::Background, move || loop {
+            // Frames the audio thread let go of are dropped here
+            retired_rx.try_iter().for_each(drop);
+            for (freeze, index, frame) in request_rx.try_iter() {
+                // One the audio thread doesn't collect is dropped here too
+                let _ = copies_tx.try_send((freeze, index, historical_copy(&frame)));
+            }
+            if !matches!(stopped.recv_timeout(HISTORY_POLL_INTERVAL), Err(mpsc::RecvTimeoutError::Timeout)) {
+                break;
+            }
+        });
+        if let Ok(handle) = spawned {
+            self.worker = Some(Worker {
+                retired,
+                requests,
+                copies,
+                stop,
+                handle,
+            });
+        }
+    }
+
+    /// Frames kept
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        self.frozen
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.frames.capacity() * size_of::<Arc<AudioPacket>>() + self.bytes
+    }
+
+    /// Whether the next `serve` sends the live frame, as it does unless
+    /// `control` has a history to freeze
+    pub fn serves_live(&self, control: &HistoryControl) -> bool {
+        !control.is_frozen() || self.frames.is_empty()
+    }
+
+    /// What goes out in place of a live `frame`: the frame itself, recorded,
+    /// or while `control` has the history frozen, the frozen frame at its
+    /// position. With nothing recorded to freeze, frames stay live. None
+    /// while the first copy of a freeze is on its way back from the
+    /// history's thread. Never blocks, and with the thread running never
+    /// allocates or frees.
+    pub fn serve(&mut self, frame: Arc<AudioPacket>, control: &HistoryControl) -> Option<Arc<AudioPacket>> {
+        let was_frozen = self.frozen;
+        self.frozen = !self.serves_live(control);
+        if !self.frozen {
+            if let Some((_, _, served)) = self.served.take() {
+                // Only a thread that stopped leaves no room, and then it is
+                // dropped here
+                let _ = self.retire(served);
+            }
+            self.requested = None;
+            self.record(Arc::clone(&frame));
+            return Some(frame);
+        }
+        if !was_frozen {
+            self.freezes += 1;
+        }
+        // The live frame is the caller's to let go of, having sent it
+        // nowhere
+        let index = ((self.frames.len() - 1) as f32 * control.position()).round() as usize;
+        self.copy(index);
+        self.served
+            .as_ref()
+            .filter(|(freeze, _, _)| *freeze == self.freezes)
+            .map(|(_, _, packet)| Arc::clone(packet))
+    }
+
+    /// Have the copy of the frame at `index` served from now on, or as soon
+    /// as the history's thread has made it
+    fn copy(&mut self, index: usize) {
+        let current = |served: &Option<(u64, usize, Arc<AudioPacket>)>, freezes| {
+            matches!(served, Some((freeze, served, _)) if *freeze == freezes && *served == index)
+        };
+        let Some(worker) = &self.worker else {
+            if !current(&self.served, self.freezes) {
+                self.served = Some((self.freezes, index, historical_copy(&self.frames[index])));
+            }
+            return;
+        };
+
+        // A copy replaces the one served, which has to be retired, so none
+        // is collected without room for it
+        while !worker.retired.is_full() {
+            let Ok((freeze, copied, packet)) = worker.copies.try_recv() else {
+                break;
+            };
+            let old = if freeze == self.freezes && Some(copied) == self.requested {
+                self.requested = None;
+                self.served.replace((freeze, copied, packet)).map(|(_, _, old)| old)
+            } else {
+                Some(packet)
+            };
+            if let Some(old) = old {
+                let _ = worker.retired.try_send(old);
+            }
+        }
+
+        // A full queue is retried on the next frame
+        if !current(&self.served, self.freezes)
+            && self.requested != Some(index)
+            && worker.requests.try_send((self.freezes, index, Arc::clone(&self.frames[index]))).is_ok()
+        {
+            self.requested = Some(index);
+        }
+    }
+
+    /// Keep `frame` as the newest, letting go of the oldest past any bound.
+    /// With the history's thread behind, the oldest stay and `frame` isn't
+    /// kept.
+    fn record(&mut self, frame: Arc<AudioPacket>) {
+        let bytes = frame.heap_bytes();
+        let newest = frame.timestamp_ms;
+        while let Some(oldest) = self.frames.front() {
+            let over = self.frames.len() == HISTORY_FRAMES
+                || self.bytes + bytes > HISTORY_BYTES
+                || newest.saturating_sub(oldest.timestamp_ms) > HISTORY_MS;
+            if !over {
+                break;
+            }
+            let oldest = self.frames.pop_front().expect("a front frame");
+            let oldest_bytes = oldest.heap_bytes();
+            if let Err(oldest) = self.retire(oldest) {
+                self.frames.push_front(oldest);
+                return;
+            }
+            self.bytes -= oldest_bytes;
+        }
+        self.bytes += bytes;
+        self.frames.push_back(frame);
+    }
+
+    /// Hand `frame` to the history's thread to be dropped; with its queue
+    /// full, give it back. Without the thread it is dropped here.
+    fn retire(&self, frame: Arc<AudioPacket>) -> Result<(), Arc<AudioPacket>> {
+        match &self.worker {
+            Some(worker) => worker.retired.try_send(frame).map_err(|e| e.into_inner()),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for FrameHistory {
+    fn drop(&mut self) {
+        if let Some(worker) = self.worker.take() {
+            drop(worker.stop);
+            let _ = worker.handle.join();
+        }
+    }
+}
+
+/// `frame` flagged as one from the history
+fn historical_copy(frame: &AudioPacket) -> Arc<AudioPacket> {
+    let mut packet = frame.clone();
+    packet.historical = true;
+    Arc::new(packet)
+}
+
+impl Default for FrameHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Turns the Freeze parameter into history commands; owned by the audio
+/// thread
+#[derive(Debug, Default)]
+pub struct FreezeSwitch {
+    engaged: bool,
+}
+
+impl FreezeSwitch {
+    /// Freeze or resume `control` if the parameter reads differently from
+    /// the last call
+    pub fn follow(&mut self, engaged: bool, control: &HistoryControl) {
+        if engaged != self.engaged {
+            control.apply(if engaged { HistoryCommand::Freeze } else { HistoryCommand::Resume });
+            self.engaged = engaged;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::NUM_BINS;
+
+    /// Frames every 50 ms, as at 20 Hz
+    fn frame(index: u64) -> Arc<AudioPacket> {
+        Arc::new(AudioPacket::fft(48000, index * 50).with_bins(vec![0.0; NUM_BINS], vec![0.0; NUM_BINS]))
+    }
+
+    #[test]
+    fn test_freeze_scrub_resume() {
+        let control = HistoryControl::default();
+        let mut history = FrameHistory::new();
+        for i in 0..40 {
+            let out = history.serve(frame(i), &control).unwrap();
+            assert_eq!(out.timestamp_ms, i * 50);
+            assert!(!out.historical);
+        }
+
+        // Frozen at frame 39: the freeze point comes out however far the
+        // analysis has got
+        control.apply(HistoryCommand::Freeze);
+        let out = history.serve(frame(40), &control).unwrap();
+        assert!(history.is_frozen());
+        assert_eq!(out.timestamp_ms, 39 * 50);
+        assert!(out.historical);
+
+        control.apply(HistoryCommand::Scrub(0.0));
+        assert_eq!(history.serve(frame(41), &control).unwrap().timestamp_ms, 0);
+        control.apply(HistoryCommand::Scrub(0.5));
+        let middle = history.serve(frame(42), &control).unwrap();
+        assert_eq!(middle.timestamp_ms, 20 * 50);
+        // The same position serves the same copy
+        assert!(Arc::ptr_eq(&middle, &history.serve(frame(43), &control).unwrap()));
+        control.apply(HistoryCommand::Scrub(7.0));
+        assert_eq!(history.serve(frame(44), &control).unwrap().timestamp_ms, 39 * 50);
+
+        // Nothing was recorded while frozen, and live frames carry on from
+        // the analysis
+        assert_eq!(history.len(), 40);
+        control.apply(HistoryCommand::Resume);
+        let out = history.serve(frame(45), &control).unwrap();
+        assert!(!history.is_frozen());
+        assert_eq!(out.timestamp_ms, 45 * 50);
+        assert!(!out.historical);
+        assert_eq!(history.len(), 41);
+
+        // A new freeze starts at its own freeze point
+        control.apply(HistoryCommand::Scrub(0.0));
+        control.apply(HistoryCommand::Freeze);
+        assert_eq!(history.serve(frame(46), &control).unwrap().timestamp_ms, 45 * 50);
+    }
+
+    #[test]
+    fn test_nothing_to_freeze_stays_live() {
+        let control = HistoryControl::default();
+        control.apply(HistoryCommand::Freeze);
+        let mut history = FrameHistory::new();
+        let out = history.serve(frame(0), &control).unwrap();
+        assert!(!out.historical);
+        // That frame was recorded, so the next one is frozen on it
+        assert!(history.serve(frame(1), &control).unwrap().historical);
+    }
+
+    #[test]
+    fn test_historical_flag_is_only_on_the_copy() {
+        let control = HistoryControl::default();
+        let mut history = FrameHistory::new();
+        let live = frame(3);
+        history.serve(Arc::clone(&live), &control).unwrap();
+        control.apply(HistoryCommand::Freeze);
+        let frozen = history.serve(frame(4), &control).unwrap();
+        assert!(frozen.historical && !live.historical);
+        assert_eq!(frozen.left_bins, live.left_bins);
+        assert_eq!(frozen.captured_us, live.captured_us);
+
+        // It goes out on the wire
+        let mut packet = AudioPacket::clone(&frozen);
+        packet.historical = false;
+        assert_ne!(frozen.to_bytes(), packet.to_bytes());
+    }
+
+    #[test]
+    fn test_memory_is_bounded() {
+        let control = HistoryControl::default();
+        let mut history = FrameHistory::new();
+
+        // 5 s of frames at 20 Hz
+        for i in 0..1000 {
+            history.serve(frame(i), &control).unwrap();
+        }
+        assert_eq!(history.len(), HISTORY_FRAMES);
+        assert!(history.heap_bytes() <= FrameHistory::MAX_HEAP_BYTES);
+
+        // Frames further apart hold 5 s of them
+        let mut history = FrameHistory::new();
+        for i in 0..100 {
+            history.serve(Arc::new(AudioPacket::fft(48000, i * 1000)), &control);
+        }
+        assert_eq!(history.len(), 6);
+
+        // Four times the bins hold fewer than 5 s
+        let mut history = FrameHistory::new();
+        for i in 0..1000 {
+            let bins = vec![0.0; 4 * NUM_BINS];
+            history.serve(Arc::new(AudioPacket::fft(48000, i * 50).with_bins(bins.clone(), bins)), &control);
+            assert!(history.heap_bytes() <= FrameHistory::MAX_HEAP_BYTES);
+        }
+        assert!(history.len() < HISTORY_FRAMES);
+        assert!(history.len() > 10);
+    }
+
+    #[test]
+    fn test_audio_thread_neither_frees_nor_copies() {
+        use crate::analysis::tests::heap_ops_in;
+        use std::time::Instant;
+
+        let control = HistoryControl::default();
+        let mut history = FrameHistory::new();
+        history.start();
+        // Made up front, so the history only ever lets go of them
+        let mut pending: VecDeque<Arc<AudioPacket>> = (0..HISTORY_FRAMES as u64 + 20).map(frame).collect();
+        let oldest = Arc::downgrade(&pending[0]);
+        let ((), heap_ops) = heap_ops_in(|| {
+            while let Some(frame) = pending.pop_front() {
+                history.serve(frame, &control);
+            }
+        });
+        assert_eq!(heap_ops, 0, "recording allocated or freed {} times", heap_ops);
+        // The 20 frames let go of are dropped by the history's thread
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while oldest.upgrade().is_some() {
+            assert!(Instant::now() < deadline, "the oldest frame was never dropped");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // Frozen halfway, at frame 70; nothing goes out until its copy is
+        // back, and then the same copy
+        control.apply(HistoryCommand::Freeze);
+        control.apply(HistoryCommand::Scrub(0.5));
+        let live = frame(200);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let copy = loop {
+            let (served, heap_ops) = heap_ops_in(|| history.serve(Arc::clone(&live), &control));
+            assert_eq!(heap_ops, 0, "serving allocated or freed {} times", heap_ops);
+            if let Some(copy) = served {
+                break copy;
+            }
+            assert!(Instant::now() < deadline, "the frozen frame was never copied");
+            std::thread::sleep(Duration::from_millis(1));
+        };
+        assert!(copy.historical);
+        assert_eq!(copy.timestamp_ms, 70 * 50);
+        assert!(Arc::ptr_eq(&copy, &history.serve(Arc::clone(&live), &control).unwrap()));
+    }
+
+    #[test]
+    fn test_freeze_param_holds_the_bands() {
+        let control = HistoryControl::default();
+        let mut history = FrameHistory::new();
+        let mut switch = FreezeSwitch::default();
+        // Every frame different, as from moving audio
+        let moving = |i: u64| {
+            let bins = vec![-(i as f32); NUM_BINS];
+            let mut packet = AudioPacket::fft(48000, i * 50).with_bins(bins.clone(), bins);
+            packet.left_coarse = vec![-(i as f32); 8];
+            Arc::new(packet)
+        };
+        for i in 0..10 {
+            switch.follow(false, &control);
+            history.serve(moving(i), &control).unwrap();
+        }
+
+        // Engaged, every send interval carries the same bands
+        switch.follow(true, &control);
+        let frozen: Vec<Arc<AudioPacket>> = (10..30)
+            .map(|i| {
+                switch.follow(true, &control);
+                history.serve(moving(i), &control).unwrap()
+            })
+            .collect();
+        assert!(frozen.iter().all(|p| p.historical && p.left_bins == moving(9).left_bins));
+        assert!(frozen.iter().all(|p| p.left_coarse == frozen[0].left_coarse && p.right_bins == frozen[0].right_bins));
+
+        // The Suite resumes; the parameter, still on, doesn't freeze again
+        control.apply(HistoryCommand::Resume);
+        switch.follow(true, &control);
+        assert!(!history.serve(moving(30), &control).unwrap().historical);
+
+        // Off and on again freezes afresh
+        switch.follow(false, &control);
+        switch.follow(true, &control);
+        assert_eq!(history.serve(moving(31), &control).unwrap().left_bins, moving(30).left_bins);
+    }
+
+    #[test]
+    fn test_commands_from_the_suite_and_the_page() {
+        assert_eq!(parse_command(r#"{"type":"history.freeze"}"#), Some(HistoryCommand::Freeze));
+        assert_eq!(parse_command(r#"{"type":"history.scrub","position":0.25}"#), Some(HistoryCommand::Scrub(0.25)));
+        assert_eq!(parse_command(r#"{"type":"history.resume"}"#), Some(HistoryCommand::Resume));
+        assert_eq!(parse_command(r#"{"type":"history.scrub"}"#), None);
+        assert_eq!(parse_command(r#"{"type":"clear_warnings"}"#), None);
+
+        assert_eq!(HistoryCommand::parse_ipc("history.freeze"), Some(HistoryCommand::Freeze));
+        assert_eq!(HistoryCommand::parse_ipc("history.scrub: 0.5"), Some(HistoryCommand::Scrub(0.5)));
+        assert_eq!(HistoryCommand::parse_ipc("history.scrub:NaN"), None);
+        assert_eq!(HistoryCommand::parse_ipc("history.scrub"), None);
+        assert_eq!(HistoryCommand::parse_ipc("history.resume"), Some(HistoryCommand::Resume));
+        assert_eq!(HistoryCommand::parse_ipc("copySlot:A>B"), None);
+    }
+}
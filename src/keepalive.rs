@@ -0,0 +1,164 @@
+//! Pings, to tell a Suite that is gone from one that is quiet
+//!
+//! A Suite that hangs, or a network stack that drops the connection without
+//! a reset, leaves the socket looking open: writes go into the kernel's
+//! buffer for minutes before one fails, and the plugin reads `Connected` all
+//! along. Every connection pings the Suite `PING_INTERVAL` (5 s) after the
+//! last pong, or after opening, and gives up on it once a ping has gone
+//! `PONG_TIMEOUT` (10 s) unanswered: the connection is dropped and opened
+//! again as after any other failure. The Suite's pings are answered when
+//! the plugin next reads, at most a millisecond or so later.
+//!
+//! A ping carries a counter, eight bytes little-endian, and only the pong
+//! with the same bytes answers it; pongs nobody asked for are allowed and
+//! ignored. The time between a ping and its pong is the round trip the
+//! plugin window reports, `round_trip_us` with the connection status. A
+//! shared connection pings once for every instance on it.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration, Instant};
+
+/// How long after the last pong the next ping goes out
+pub const PING_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a ping may go unanswered before the Suite counts as gone
+pub const PONG_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often a connection pings and how long it waits for the answer
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingTimes {
+    pub interval: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for PingTimes {
+    fn default() -> Self {
+        Self { interval: PING_INTERVAL, timeout: PONG_TIMEOUT }
+    }
+}
+
+/// The pings of one connection
+#[derive(Debug)]
+pub struct Keepalive {
+    times: PingTimes,
+    /// Payload of the next ping
+    next: u64,
+    /// The ping waiting for its pong, and when it went out
+    waiting: Option<(u64, Instant)>,
+    /// When the last pong came, or the connection opened
+    answered_at: Instant,
+    round_trip: Option<Duration>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Self::new(Instant::now(), PingTimes::default())
+    }
+}
+
+impl Keepalive {
+    /// For a connection opened at `now`
+    pub fn new(now: Instant, times: PingTimes) -> Self {
+        Self { times, next: 0, waiting: None, answered_at: now, round_trip: None }
+    }
+
+    /// The payload of a ping to send now, if one is due
+    pub fn ping_due(&mut self, now: Instant) -> Option<Vec<u8>> {
+        if self.waiting.is_some() || now.duration_since(self.answered_at) < self.times.interval {
+            return None;
+        }
+        let payload = self.next;
+        self.next = self.next.wrapping_add(1);
+        self.waiting = Some((payload, now));
+        Some(payload.to_le_bytes().to_vec())
+    }
+
+    /// A pong from the Suite, arrived at `now`. The round trip if it
+    /// answers the ping waiting.
+    pub fn pong(&mut self, payload: &[u8], now: Instant) -> Option<Duration> {
+        let (waiting, sent_at) = self.waiting?;
+        if payload != waiting.to_le_bytes() {
+            return None;
+        }
+        self.waiting = None;
+        self.answered_at = now;
+        let round_trip = now.duration_since(sent_at);
+        self.round_trip = Some(round_trip);
+        Some(round_trip)
+    }
+
+    /// Whether the ping waiting has gone unanswered too long
+    pub fn timed_out(&self, now: Instant) -> bool {
+        self.waiting.is_some_and(|(_, sent_at)| now.duration_since(sent_at) >= self.times.timeout)
+    }
+
+    /// The latest round trip measured on this connection
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.round_trip
+    }
+}
+
+/// The latest round trip of an instance's connection, for the plugin
+/// window; none while disconnected or before the first pong
+#[derive(Debug, Default)]
+pub struct RoundTrip {
+    /// Microseconds, 0 for none
+    us: AtomicU32,
+}
+
+impl RoundTrip {
+    pub fn set(&self, round_trip: Option<Duration>) {
+        // A round trip under a microsecond still counts as one
+        let us = round_trip.map_or(0, |round_trip| round_trip.as_micros().clamp(1, u32::MAX as u128) as u32);
+        self.us.store(us, Ordering::Relaxed);
+    }
+
+    /// Microseconds
+    #[cfg(any(feature = "gui", test))]
+    pub fn get(&self) -> Option<u32> {
+        Some(self.us.load(Ordering::Relaxed)).filter(|&us| us > 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pings_and_pongs() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut keepalive = Keepalive::new(start, PingTimes::default());
+        assert_eq!(keepalive.ping_due(at(4_999)), None);
+        let first = keepalive.ping_due(at(5_000)).unwrap();
+        assert_eq!(first, 0u64.to_le_bytes());
+        assert_eq!(keepalive.ping_due(at(6_000)), None, "one ping at a time");
+
+        // Only the pong with the ping's bytes answers it
+        assert_eq!(keepalive.pong(&1u64.to_le_bytes(), at(5_010)), None);
+        assert_eq!(keepalive.pong(b"", at(5_010)), None);
+        assert!(!keepalive.timed_out(at(14_999)));
+        assert_eq!(keepalive.pong(&first, at(5_020)), Some(Duration::from_millis(20)));
+        assert_eq!(keepalive.round_trip(), Some(Duration::from_millis(20)));
+        assert_eq!(keepalive.pong(&first, at(5_030)), None, "answered already");
+
+        // The next one is due after the interval from the pong
+        assert_eq!(keepalive.ping_due(at(10_000)), None);
+        assert_eq!(keepalive.ping_due(at(10_020)).unwrap(), 1u64.to_le_bytes());
+        assert!(!keepalive.timed_out(at(20_019)));
+        assert!(keepalive.timed_out(at(20_020)));
+        assert_eq!(keepalive.round_trip(), Some(Duration::from_millis(20)), "kept while waiting");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let round_trip = RoundTrip::default();
+        assert_eq!(round_trip.get(), None);
+        round_trip.set(Some(Duration::from_micros(250)));
+        assert_eq!(round_trip.get(), Some(250));
+        round_trip.set(Some(Duration::from_nanos(10)));
+        assert_eq!(round_trip.get(), Some(1));
+        round_trip.set(None);
+        assert_eq!(round_trip.get(), None);
+    }
+}
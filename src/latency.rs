@@ -0,0 +1,136 @@
+//! Latency instrumentation
+//!
+//! Frames are stamped on the audio thread when the last sample of their
+//! analysis window has been buffered, and measured again on the network
+//! thread once the packet has been handed to the TCP stack. Both ends read the
+//! same process-wide monotonic clock, so the difference is the in-process
+//! part of the display latency.
+
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use crate::memory;
+
+/// Number of recent frames the histogram covers (~50 s at 20 Hz)
+pub const HISTOGRAM_LEN: usize = 1024;
+
+/// How often a timing packet is sent when enabled
+pub const TIMING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Budget for audio thread → TCP stack at default settings, in µs. The
+/// network thread polls its queue every millisecond, so anything well above
+/// that means the send path is stalling.
+pub const IN_PROCESS_BUDGET_US: u64 = 5_000;
+
+static EPOCH: OnceLock<Instant> = OnceLock::new();
+
+/// Microseconds on the shared monotonic clock.
+pub fn now_us() -> u64 {
+    EPOCH.get_or_init(Instant::now).elapsed().as_micros() as u64
+}
+
+/// Percentiles over the recent window
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LatencyStats {
+    pub count: u32,
+    pub p50_us: u32,
+    pub p95_us: u32,
+    pub max_us: u32,
+}
+
+/// Rolling window of the most recent latency samples
+#[derive(Debug, Clone)]
+pub struct LatencyHistogram {
+    samples: VecDeque<u32>,
+}
+
+impl LatencyHistogram {
+    pub fn new() -> Self {
+        Self {
+            samples: VecDeque::with_capacity(HISTOGRAM_LEN),
+        }
+    }
+
+    /// Record the time between `captured_us` and `now_us`. Stamps from the
+    /// future (0 = not stamped) are ignored.
+    pub fn record(&mut self, captured_us: u64, now_us: u64) {
+        if captured_us == 0 || captured_us > now_us {
+            return;
+        }
+        if self.samples.len() == HISTOGRAM_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back((now_us - captured_us).min(u32::MAX as u64) as u32);
+    }
+
+    /// Heap bytes of the sample window
+    pub fn heap_bytes(&self) -> usize {
+        memory::deque_bytes(&self.samples)
+    }
+
+    pub fn stats(&self) -> LatencyStats {
+        if self.samples.is_empty() {
+            return LatencyStats::default();
+        }
+        let mut sorted: Vec<u32> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let at = |q: usize| sorted[(sorted.len() - 1) * q / 100];
+        LatencyStats {
+            count: sorted.len() as u32,
+            p50_us: at(50),
+            p95_us: at(95),
+            max_us: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+impl Default for LatencyHistogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_clock_is_monotonic() {
+        let a = now_us();
+        std::thread::sleep(Duration::from_millis(2));
+        assert!(now_us() >= a + 1_000);
+    }
+
+    #[test]
+    fn test_percentiles() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.stats(), LatencyStats::default());
+
+        for us in 1..=100 {
+            histogram.record(1_000, 1_000 + us);
+        }
+        // Unstamped and future stamps are ignored
+        histogram.record(0, 5_000);
+        histogram.record(9_000, 5_000);
+
+        let stats = histogram.stats();
+        assert_eq!(stats.count, 100);
+        assert_eq!(stats.p50_us, 50);
+        assert_eq!(stats.p95_us, 95);
+        assert_eq!(stats.max_us, 100);
+    }
+
+    #[test]
+    fn test_window_is_bounded() {
+        let mut histogram = LatencyHistogram::new();
+        histogram.record(1, 1_000_001);
+        for _ in 0..HISTOGRAM_LEN {
+            histogram.record(1, 11);
+        }
+        let stats = histogram.stats();
+        assert_eq!(stats.count as usize, HISTOGRAM_LEN);
+        assert_eq!(stats.max_us, 10, "old outlier should have aged out");
+    }
+}
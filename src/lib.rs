@@ -5,24 +5,126 @@
 //! When built with the `gui` feature, it also embeds a wry webview that loads
 //! the Hardwave Analyser from hardwave.studio inside the DAW plugin window.
 
+mod activity;
+mod analysis;
 mod auth;
+mod band_smoothing;
+mod bands;
+mod base64;
+mod beat_phase;
+mod bus;
+mod checksum;
+mod clicks;
+mod clock;
+mod config;
+mod decimator;
+mod destinations;
+mod discovery;
+mod display;
 #[cfg(feature = "gui")]
 mod editor;
+#[cfg(feature = "gui")]
+mod editor_payload;
+mod engine;
+mod envelope;
+mod error;
 mod fft;
+mod history;
+mod keepalive;
+mod latency;
+mod link_stats;
+mod loudness;
+mod memory;
+mod metadata;
+mod meter;
+mod metrics;
+mod multiplex;
+mod onboarding;
+mod packet_crypto;
+mod panics;
 mod params;
+mod polarity;
+mod ports;
+#[cfg(feature = "gui")]
+mod presets;
+#[cfg(feature = "proto")]
+pub mod proto;
 mod protocol;
+mod raw_spectrum;
+pub mod recorder;
+mod reference;
+mod remote;
+#[cfg(test)]
+mod replay;
+mod resampler;
+mod resume;
+mod runtime;
+mod self_check;
+mod sequence;
+mod shared;
+mod simulate;
+mod slots;
+#[cfg(feature = "gui")]
+mod spectrogram;
+mod state;
+mod subscriptions;
+mod surround;
+mod suite_commands;
+#[cfg(any(test, feature = "test-vectors"))]
+pub mod test_vectors;
+#[cfg(feature = "gui")]
+mod theme;
+mod threads;
+mod tls;
+mod track_info;
+mod transport_loop;
+mod transport_report;
+mod true_peak;
+mod udp;
+mod units;
+mod watchdog;
+#[cfg(feature = "gui")]
+mod webview_commands;
+#[cfg(feature = "gui")]
+mod webview_dir;
 mod websocket;
+mod weighting;
+mod wire_format;
 
-use crossbeam_channel::{bounded, Sender};
 use nih_plug::prelude::*;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Instant;
 
-use fft::{FftProcessor, FFT_SIZE};
-use params::HardwaveAnalyserParams;
+use analysis::AnalysisConfig;
+use beat_phase::{BeatPhase, TransportSnapshot};
+use clicks::ClickStats;
+use engine::{AnalysisEngine, AnalysisFrame, EngineSettings};
+use error::{HardwaveError, TransportError};
+use history::{FrameHistory, FreezeSwitch, HistoryControl};
+use memory::MemoryGauges;
+use panics::{Panicked, Region};
+use params::{AnalysisSlot, HardwaveAnalyserParams};
+use polarity::{StereoReport, StereoWarnings};
 use protocol::AudioPacket;
+use raw_spectrum::RawSpectrumPacer;
+use remote::RemoteControl;
+use runtime::RuntimeHandle;
+use sequence::PacketSequence;
+use slots::{AnalysisSlots, SlotTracker};
+use track_info::TrackInfoWatcher;
+use transport_loop::{LoopDetector, LoopEvent};
+use transport_report::TransportReporter;
+use watchdog::ProcessWatchdog;
 use websocket::WebSocketClient;
 
+/// Work `process` leaves to nih-plug's background thread
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Task {
+    /// Keep the name the Suite gave the instance, see `remote`
+    ApplyInstanceName,
+}
+
 /// Main plugin struct
 pub struct HardwaveAnalyser {
     params: Arc<HardwaveAnalyserParams>,
@@ -30,62 +132,143 @@ pub struct HardwaveAnalyser {
     /// WebSocket client for streaming to the desktop app
     ws_client: WebSocketClient,
 
-    /// Sender for the editor webview (gui feature)
-    editor_packet_tx: Sender<AudioPacket>,
-
     /// Editor instance (created once, reused)
     #[cfg(feature = "gui")]
     editor_instance: Option<editor::HardwaveAnalyserEditor>,
 
-    /// FFT processor for left channel
-    fft_left: FftProcessor,
+    /// Everything from samples to analysis frames
+    engine: AnalysisEngine,
+
+    /// Which analysis slot is live, and its settings
+    slots: SlotTracker,
+
+    /// Per-buffer memory use, shared with the WebSocket client and the editor
+    memory: Arc<MemoryGauges>,
 
-    /// FFT processor for right channel
-    fft_right: FftProcessor,
+    /// Host track name and colour, the defaults for the display hints
+    track_info: TrackInfoWatcher,
 
-    /// Sample buffer for left channel
-    buffer_left: Vec<f32>,
+    /// Stamped every block, so heartbeats and the editor can tell when the
+    /// host stops processing
+    watchdog: Arc<ProcessWatchdog>,
 
-    /// Sample buffer for right channel
-    buffer_right: Vec<f32>,
+    /// Watches the host's playhead for loops
+    loop_detector: LoopDetector,
 
-    /// Current sample rate
-    sample_rate: f32,
+    /// Decides which blocks tell the Suite the host's tempo and position
+    transport_reporter: TransportReporter,
 
-    /// Samples since last FFT send
-    samples_since_send: usize,
+    /// Clicks the engine found, shared with the WebSocket client and the
+    /// editor
+    clicks: Arc<ClickStats>,
 
-    /// Samples between FFT sends (for ~20Hz update rate)
-    samples_per_send: usize,
+    /// Polarity and delayed-copy warnings, shared with the WebSocket client
+    stereo: Arc<StereoWarnings>,
+
+    /// The last few seconds of frames, served in place of live ones while
+    /// frozen
+    history: FrameHistory,
+
+    /// Freeze and scrub requests from the Suite and the editor
+    history_control: Arc<HistoryControl>,
+
+    /// The Freeze parameter's requests
+    freeze: FreezeSwitch,
+
+    /// The update rate and instance name the Suite asked for
+    remote: Arc<RemoteControl>,
+
+    /// Non-finite samples reported in the previous frame
+    last_non_finite: u32,
+
+    /// The stereo downmix of a surround block, `max_buffer_size` long;
+    /// empty for mono and stereo
+    downmix: (Vec<f32>, Vec<f32>),
+
+    /// The previous frame couldn't be queued for the Suite
+    send_failing: bool,
+
+    /// Which frames take a raw spectrum packet
+    raw_spectrum: RawSpectrumPacer,
+
+    /// Numbers every packet, shared with the WebSocket client; starts over
+    /// on `initialize`
+    sequence: Arc<PacketSequence>,
 
     /// Plugin start time for timestamps
     start_time: Instant,
 
     /// Last port value (for detecting changes)
     last_port: i32,
+
+    /// The host is rendering offline; streaming to the Suite is suspended
+    offline: bool,
+
+    /// This instance's share of the process-wide resources; released last
+    runtime: RuntimeHandle,
 }
 
 impl Default for HardwaveAnalyser {
     fn default() -> Self {
-        let (editor_packet_tx, _editor_packet_rx) = bounded::<AudioPacket>(32);
+        let params = Arc::new(HardwaveAnalyserParams::default());
+        let ws_client = WebSocketClient::new();
+        let memory = ws_client.memory_gauges();
+        let watchdog = ws_client.process_watchdog();
+        let clicks = ws_client.click_stats();
+        let stereo = ws_client.stereo_warnings();
+        let history_control = ws_client.history_control();
+        let remote = ws_client.remote_control();
+        let sequence = ws_client.packet_sequence();
 
         Self {
-            params: Arc::new(HardwaveAnalyserParams::default()),
-            ws_client: WebSocketClient::new(),
-            editor_packet_tx,
             #[cfg(feature = "gui")]
             editor_instance: {
-                Some(editor::HardwaveAnalyserEditor::new(_editor_packet_rx))
+                Some(editor::HardwaveAnalyserEditor::new(
+                    params.clone(),
+                    editor::EditorShared {
+                        bus: ws_client.packet_bus(),
+                        auth_token: ws_client.account_token(),
+                        lifecycle: ws_client.editor_lifecycle(),
+                        recording: ws_client.recording_switch(),
+                        display: ws_client.display_hints(),
+                        memory: memory.clone(),
+                        errors: ws_client.error_stats(),
+                        clicks: clicks.clone(),
+                        watchdog: watchdog.clone(),
+                        suite_commands: ws_client.suite_commands(),
+                        encryption: ws_client.packet_encryption(),
+                        connection: ws_client.connection_stats(),
+                        onboarding: ws_client.onboarding(),
+                        tls: ws_client.tls_control(),
+                        destinations: ws_client.destinations(),
+                        history: history_control.clone(),
+                    },
+                ))
             },
-            fft_left: FftProcessor::new(),
-            fft_right: FftProcessor::new(),
-            buffer_left: Vec::with_capacity(FFT_SIZE),
-            buffer_right: Vec::with_capacity(FFT_SIZE),
-            sample_rate: 48000.0,
-            samples_since_send: 0,
-            samples_per_send: 2400, // 48000 / 20 = 2400 samples for 20Hz
+            params,
+            ws_client,
+            engine: AnalysisEngine::new(AnalysisConfig::default()),
+            slots: SlotTracker::new(AnalysisSlot::A, EngineSettings::default(), &AnalysisSlots::default()),
+            memory,
+            track_info: TrackInfoWatcher::default(),
+            watchdog,
+            loop_detector: LoopDetector::new(),
+            transport_reporter: TransportReporter::default(),
+            clicks,
+            stereo,
+            history: FrameHistory::new(),
+            history_control,
+            freeze: FreezeSwitch::default(),
+            remote,
+            last_non_finite: 0,
+            downmix: (Vec::new(), Vec::new()),
+            send_failing: false,
+            raw_spectrum: RawSpectrumPacer::default(),
+            sequence,
             start_time: Instant::now(),
             last_port: 9847,
+            offline: false,
+            runtime: RuntimeHandle::acquire(),
         }
     }
 }
@@ -98,16 +281,33 @@ impl Plugin for HardwaveAnalyser {
     const VERSION: &'static str = env!("CARGO_PKG_VERSION");
 
     const AUDIO_IO_LAYOUTS: &'static [AudioIOLayout] = &[
-        // Stereo
+        // Stereo, with a stereo sidechain for a reference track
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(2),
             main_output_channels: NonZeroU32::new(2),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames { aux_inputs: &["Reference"], ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
         // Mono (will be duplicated to stereo for analysis)
         AudioIOLayout {
             main_input_channels: NonZeroU32::new(1),
             main_output_channels: NonZeroU32::new(1),
+            aux_input_ports: &[new_nonzero_u32(2)],
+            names: PortNames { aux_inputs: &["Reference"], ..PortNames::const_default() },
+            ..AudioIOLayout::const_default()
+        },
+        // 5.1 and 7.1, analysed as a stereo downmix and channel by channel
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(6),
+            main_output_channels: NonZeroU32::new(6),
+            names: PortNames { layout: Some("5.1"), ..PortNames::const_default() },
+            ..AudioIOLayout::const_default()
+        },
+        AudioIOLayout {
+            main_input_channels: NonZeroU32::new(8),
+            main_output_channels: NonZeroU32::new(8),
+            names: PortNames { layout: Some("7.1"), ..PortNames::const_default() },
             ..AudioIOLayout::const_default()
         },
     ];
@@ -118,12 +318,38 @@ impl Plugin for HardwaveAnalyser {
     const SAMPLE_ACCURATE_AUTOMATION: bool = false;
 
     type SysExMessage = ();
-    type BackgroundTask = ();
+    type BackgroundTask = Task;
 
     fn params(&self) -> Arc<dyn Params> {
         self.params.clone()
     }
 
+    fn task_executor(&mut self) -> TaskExecutor<Self> {
+        let params = Arc::clone(&self.params);
+        let remote = Arc::clone(&self.remote);
+        let display = self.ws_client.display_hints();
+        Box::new(move |task| match task {
+            // Kept like one typed in the plugin window
+            Task::ApplyInstanceName => {
+                if let Some(name) = remote.take_instance_name() {
+                    params.set_display_label(&name);
+                    *display.lock() = params.display_hints();
+                }
+            }
+        })
+    }
+
+    fn filter_state(state: &mut PluginState) {
+        let saved = state::migrate(state);
+        if saved > state::STATE_VERSION {
+            Self::debug_log(&format!(
+                "Project saved by a newer plugin (state layout {}, this one knows {}); loading what it can",
+                saved,
+                state::STATE_VERSION
+            ));
+        }
+    }
+
     fn editor(&mut self, _async_executor: AsyncExecutor<Self>) -> Option<Box<dyn Editor>> {
         #[cfg(feature = "gui")]
         {
@@ -139,159 +365,393 @@ impl Plugin for HardwaveAnalyser {
 
     fn initialize(
         &mut self,
-        _audio_io_layout: &AudioIOLayout,
+        audio_io_layout: &AudioIOLayout,
         buffer_config: &BufferConfig,
-        _context: &mut impl InitContext<Self>,
+        context: &mut impl InitContext<Self>,
     ) -> bool {
-        self.sample_rate = buffer_config.sample_rate;
-        self.samples_per_send = (self.sample_rate / 20.0) as usize; // 20Hz update rate
+        // Packets are numbered from 0 again
+        self.sequence.reset();
+
+        // The project, if any, has been restored by now
+        self.slots = SlotTracker::new(
+            self.params.analysis.active_slot.value(),
+            self.params.engine_settings(),
+            &self.params.analysis_slots.read().map(|slots| *slots).unwrap_or_default(),
+        );
+
+        // Not the audio thread, so the new state can be built right here
+        let settings = self.engine_settings();
+        self.engine.set_settings(settings);
+        let channels = audio_io_layout.main_input_channels.map_or(2, |channels| channels.get() as usize);
+        self.engine.set_channels(channels);
+        let downmix_len = if channels > 2 { buffer_config.max_buffer_size as usize } else { 0 };
+        self.downmix = (vec![0.0; downmix_len], vec![0.0; downmix_len]);
+        self.engine.set_sample_rate(buffer_config.sample_rate);
+        self.engine.start();
+        self.history.start();
+        self.ws_client.set_metadata(self.engine.metadata());
+        // The audio thread's log lines only queue; this writes them
+        self.runtime.context().start_log();
+        // Read once per process and kept current, however many instances ask
+        let paranoid = self.runtime.context().config().is_ok_and(|config| config.paranoid);
+        self.engine.set_paranoid(paranoid || cfg!(debug_assertions));
+        // A developer setting, so only ever from the environment
+        let simulate = simulate::seed_from_env();
+        if let Some(seed) = simulate {
+            Self::debug_log(&format!(
+                "Simulating input after {} s of silence, seed {}",
+                simulate::ENGAGE_AFTER_S,
+                seed
+            ));
+        }
+        self.engine.set_simulated_input(simulate);
+        self.engine.report_memory(&self.memory);
+
+        // Hosts re-initialise when switching to and from offline rendering
+        self.offline = buffer_config.process_mode == ProcessMode::Offline;
+        self.ws_client.set_suspended(self.offline);
+        self.ws_client.set_plugin_api(context.plugin_api());
+        // nih-plug calls this again after restoring project state. Until it
+        // forwards the hosts' track-info change notifications, this is also
+        // where the track info is picked up.
+        self.track_info.refresh(&self.params);
+        self.ws_client.set_display_hints(self.params.display_hints());
 
-        // Clear buffers
-        self.buffer_left.clear();
-        self.buffer_right.clear();
+        Self::debug_log(&format!(
+            "Initialising; {} instances in this process",
+            self.runtime.context().instance_count()
+        ));
 
-        // Start WebSocket client (deferred from new() to avoid blocking DAW scans)
-        self.ws_client.start();
+        // Start WebSocket client (deferred from new() to avoid blocking DAW scans),
+        // over the connection all instances share where the Suite allows
+        self.ws_client.share_connection(self.runtime.context().suite_connection());
+        // As the project saved them; the editor changes them from here on
+        self.ws_client.set_destinations(&self.params.destinations());
+        if let Err(e) = self.ws_client.start() {
+            Self::debug_log(&format!("Not streaming to the Suite: {}", e));
+            self.ws_client.error_stats().record(&e);
+        }
 
         // Set initial port
-        self.ws_client.set_port(self.params.port.value());
-        self.last_port = self.params.port.value();
+        self.apply_port(self.params.network.port.value());
 
         true
     }
 
     fn reset(&mut self) {
-        self.buffer_left.clear();
-        self.buffer_right.clear();
-        self.samples_since_send = 0;
+        self.engine.reset();
+        self.loop_detector.reset();
+        self.transport_reporter.reset();
     }
 
     fn process(
         &mut self,
         buffer: &mut Buffer,
-        _aux: &mut AuxiliaryBuffers,
-        _context: &mut impl ProcessContext<Self>,
+        aux: &mut AuxiliaryBuffers,
+        context: &mut impl ProcessContext<Self>,
     ) -> ProcessStatus {
+        // The host is processing, whether or not analysis is enabled
+        let gap_ms = self.watchdog.beat();
+
         // Check if port changed
-        let current_port = self.params.port.value();
+        let current_port = self.params.network.port.value();
         if current_port != self.last_port {
-            self.ws_client.set_port(current_port);
-            self.last_port = current_port;
+            self.apply_port(current_port);
+        }
+
+        self.ws_client.set_timing_packets(self.params.network.timing_packets.value());
+        self.freeze.follow(self.params.display.freeze.value(), &self.history_control);
+        self.ws_client
+            .set_metrics_opt_in(self.params.metrics_opt_in.load(Ordering::Relaxed));
+
+        // A bandwidth change is built by the config worker, and a slot switch
+        // arrives as one change of everything
+        let settings = self.engine_settings();
+        self.engine.set_reference(self.params.analysis.reference_analysis.value());
+        self.engine.set_update_rate(self.remote.update_rate());
+        if self.engine.set_settings(settings) {
+            self.engine.report_memory(&self.memory);
+        }
+        self.ws_client.set_metadata(self.engine.metadata());
+        // Nothing nobody subscribed to gets computed. The work so far is
+        // logged when that changes, not per frame.
+        let outputs = self.ws_client.wanted_outputs();
+        if self.engine.set_outputs(outputs) {
+            Self::debug_log(&format!("outputs now {:?}; work so far: {:?}", outputs, self.engine.work_counts()));
+        }
+
+        // A name from the Suite is saved off the audio thread
+        if self.remote.take_naming() {
+            context.execute_background(Task::ApplyInstanceName);
         }
 
+        // Heartbeats go on either way, and say which
+        let enabled = self.params.network.enabled.value();
+        self.ws_client.set_session(enabled, self.now_ms());
+
         // Skip processing if disabled
-        if !self.params.enabled.value() {
+        if !enabled {
             return ProcessStatus::Normal;
         }
 
-        let num_channels = buffer.channels();
-        let num_samples = buffer.samples();
+        let transport = TransportSnapshot::of(context.transport());
 
-        // Process each sample
-        for sample_idx in 0..num_samples {
-            // Get samples (handle mono by duplicating)
-            let left = buffer.as_slice()[0][sample_idx];
-            let right = if num_channels > 1 {
-                buffer.as_slice()[1][sample_idx]
-            } else {
-                left
-            };
+        // Mono is analysed as two identical channels, and surround as its
+        // downmix as well as channel by channel
+        let channels = buffer.as_slice_immutable();
+        let mut downmix = std::mem::take(&mut self.downmix);
+        let (left, right): (&[f32], &[f32]) = if channels.len() > 2 {
+            let len = channels[0].len().min(downmix.0.len());
+            let (left, right) = (&mut downmix.0[..len], &mut downmix.1[..len]);
+            surround::downmix(channels, left, right);
+            (&*left, &*right)
+        } else {
+            let left: &[f32] = channels[0];
+            (left, channels.get(1).map_or(left, |right| &**right))
+        };
+        // and so is a mono sidechain; a host that routes nothing to it may
+        // leave out its channels
+        let reference = aux.inputs.first().and_then(|aux| {
+            let channels = aux.as_slice_immutable();
+            let left: &[f32] = channels.first()?;
+            Some((left, channels.get(1).map_or(left, |right| &**right)))
+        });
+        // A panic must not unwind into the host; the audio is untouched
+        // either way, and the analysis starts over
+        if self.analyse_contained(left, right, reference, channels, gap_ms, &transport).is_err() {
+            self.engine.reset();
+            self.loop_detector.reset();
+            self.transport_reporter.reset();
+        }
+        self.downmix = downmix;
 
-            // Add to buffers
-            self.buffer_left.push(left);
-            self.buffer_right.push(right);
+        // Pass through audio unchanged
+        ProcessStatus::Normal
+    }
+}
 
-            // Keep buffer at FFT_SIZE
-            if self.buffer_left.len() > FFT_SIZE {
-                self.buffer_left.remove(0);
-                self.buffer_right.remove(0);
+impl HardwaveAnalyser {
+    /// Analyse one block, catching any panic in it
+    fn analyse_contained(
+        &mut self,
+        left: &[f32],
+        right: &[f32],
+        reference: Option<(&[f32], &[f32])>,
+        surround: &[&mut [f32]],
+        gap_ms: u64,
+        transport: &TransportSnapshot,
+    ) -> Result<(), Panicked> {
+        panics::contain(Region::Process, || {
+            // A loop's repeats are marked for the Suite's history and
+            // integrated measurements
+            if let Some(event) = self.loop_detector.observe(transport.playing, transport.pos_samples, left.len()) {
+                self.send_loop_event(&event, transport.sample_rate);
+            }
+            // and the history view lines up with the bars
+            if self.transport_reporter.observe(transport, left.len()) {
+                self.send_transport(transport);
             }
+            self.analyse_block(left, right, reference, surround, gap_ms, transport);
+        })
+    }
 
-            self.samples_since_send += 1;
+    fn analyse_block(
+        &mut self,
+        left: &[f32],
+        right: &[f32],
+        reference: Option<(&[f32], &[f32])>,
+        surround: &[&mut [f32]],
+        gap_ms: u64,
+        transport: &TransportSnapshot,
+    ) {
+        // After a long enough gap the history is from before it
+        self.engine.resume_after(gap_ms, left.len());
+        if self.stereo.take_clear() {
+            self.engine.dismiss_stereo_warnings();
+        }
+        self.engine.push_samples(left, right);
+        self.engine.push_reference(reference);
+        self.engine.push_channels(surround);
+        if let Err(e) = self.engine.check_consistency() {
+            Self::debug_log(&format!(
+                "Self-check failed, analysis reset and rebuilt: {}; config {:?}",
+                e,
+                self.engine.config()
+            ));
+        }
+        let now_ms = self.now_ms();
+        if let Some(report) = self.engine.take_clicks(now_ms) {
+            self.clicks.record(report);
         }
 
-        // Send FFT data at ~20Hz
-        if self.samples_since_send >= self.samples_per_send && self.buffer_left.len() >= FFT_SIZE {
-            self.send_fft_data();
-            self.samples_since_send = 0;
+        // Send FFT data at ~20Hz, with the beat where its window ended
+        if let Some(frame) = self.engine.poll_frame() {
+            Self::log_stereo_warnings(&frame.stereo);
+            self.stereo.set(self.engine.stereo_warnings());
+            let phase = transport.phase_at(left.len() as i64 - frame.window_lag as i64);
+            self.send_fft_data(frame, phase);
         }
+    }
 
-        // Pass through audio unchanged
-        ProcessStatus::Normal
+    /// The settings of the active slot, with any parameter changes since the
+    /// last block taken into it
+    fn engine_settings(&mut self) -> EngineSettings {
+        let params = &self.params;
+        self.slots
+            .update(params.analysis.active_slot.value(), params.engine_settings(), &params.analysis_slots)
     }
-}
 
-impl HardwaveAnalyser {
-    /// Write a line to the same debug log as editor.rs
-    fn debug_log(msg: &str) {
-        use std::io::Write;
-        let path = { let mut p = std::env::temp_dir(); p.push("hardwave-debug.log"); p };
-        if let Ok(mut f) = std::fs::OpenOptions::new().create(true).append(true).open(&path) {
-            let now = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
-            let _ = writeln!(f, "[{}] [lib] {}", now, msg);
+    /// Log each stereo warning once, when it is raised
+    fn log_stereo_warnings(report: &StereoReport) {
+        if report.raised & polarity::POLARITY_FLIP != 0 {
+            Self::debug_log(&format!(
+                "Polarity warning: correlation {:.2} for {} s, one channel looks inverted",
+                report.correlation.unwrap_or(-1.0),
+                polarity::RAISE_AFTER_S
+            ));
+        }
+        if report.raised & polarity::DELAYED_COPY != 0 {
+            Self::debug_log(&format!(
+                "Delayed copy warning: the right channel matches the left {} host samples behind",
+                report.lag
+            ));
         }
     }
 
-    /// Process and send FFT data
-    fn send_fft_data(&mut self) {
-        // Process FFT for both channels → raw magnitude bins in dB
-        let left_bins = self.fft_left.process(&self.buffer_left, self.sample_rate);
-        let right_bins = self.fft_right.process(&self.buffer_right, self.sample_rate);
+    /// Point the client at a new port. The connection thread checks it and
+    /// reports what is wrong with it.
+    fn apply_port(&mut self, port: i32) {
+        self.ws_client.set_port(port);
+        self.last_port = port;
+    }
 
-        // Calculate levels
-        let (left_peak, left_rms) = FftProcessor::calculate_levels(&self.buffer_left);
-        let (right_peak, right_rms) = FftProcessor::calculate_levels(&self.buffer_right);
+    /// Write a line to the same debug log as editor.rs
+    fn debug_log(msg: &str) {
+        runtime::debug_log(&format!("[lib] {}", msg));
+    }
 
-        // Create and send packet
-        let timestamp_ms = self.start_time.elapsed().as_millis() as u64;
+    /// Packet timestamps: ms since the plugin started, leaving out any sleep
+    /// the monotonic clock counted so they go on where they were
+    fn now_ms(&self) -> u64 {
+        self.start_time.elapsed().saturating_sub(self.ws_client.slept()).as_millis() as u64
+    }
+
+    /// Send a frame to the Suite and the editor
+    fn send_fft_data(&mut self, mut frame: AnalysisFrame, phase: Option<BeatPhase>) {
+        // A delayed interval is stamped when it ended
+        let timestamp_ms = self.now_ms().saturating_sub(frame.delay_ms);
 
         // Log first 3 packets so we know FFT is running
         if timestamp_ms < 3000 || timestamp_ms % 10000 < 100 {
             Self::debug_log(&format!(
                 "send_fft_data: ts={}ms sr={} left_peak={:.1} bins={}",
-                timestamp_ms, self.sample_rate as u32, left_peak, left_bins.len()
+                timestamp_ms, frame.analysis_rate as u32, frame.left_levels.peak.get(), frame.left_bins.len()
             ));
         }
 
-        // Extract oscilloscope waveform: last WAVE_SIZE samples from ring buffer
-        use protocol::WAVE_SIZE;
-        let left_wave = if self.buffer_left.len() >= WAVE_SIZE {
-            self.buffer_left[self.buffer_left.len() - WAVE_SIZE..].to_vec()
-        } else {
-            vec![0.0_f32; WAVE_SIZE]
-        };
-        let right_wave = if self.buffer_right.len() >= WAVE_SIZE {
-            self.buffer_right[self.buffer_right.len() - WAVE_SIZE..].to_vec()
-        } else {
-            vec![0.0_f32; WAVE_SIZE]
+        let non_finite = frame.non_finite_samples;
+        if non_finite > 0 && self.last_non_finite == 0 {
+            Self::debug_log(&format!(
+                "replaced {} NaN/Inf input samples; check the plugins before this one",
+                non_finite
+            ));
+        }
+        self.last_non_finite = non_finite;
+
+        let envelope = std::mem::take(&mut frame.envelope);
+        let reference = frame.reference.take();
+        let surround = frame.surround.take();
+        let mut packet = frame.into_packet(timestamp_ms).with_beat_phase(phase);
+        packet.analysis_slot = self.slots.active().wire_id();
+        packet.compact_levels = self.params.network.compact_levels.value();
+        // A frame that goes out numbered; one held back for a frozen one
+        // takes no number
+        if self.history.serves_live(&self.history_control) {
+            self.sequence.stamp(&mut packet);
+        }
+        let raw = self.raw_spectrum_due(&packet).then(|| raw_spectrum::packet_for(&packet));
+        let waveform = (self.params.network.waveform_packets.value() && !self.history.is_frozen())
+            .then(|| envelope.into_packet(timestamp_ms))
+            .flatten();
+        let reference = reference.filter(|_| !self.history.is_frozen()).map(|r| r.into_packet(&packet));
+        let surround = surround.filter(|_| !self.history.is_frozen()).map(|s| s.into_packet(&packet));
+
+        // While the history is frozen, a frame from it goes out instead
+        let was_frozen = self.history.is_frozen();
+        let served = self.history.serve(Arc::new(packet), &self.history_control);
+        if self.history.is_frozen() != was_frozen {
+            if was_frozen {
+                Self::debug_log("History resumed, live frames again");
+            } else {
+                Self::debug_log(&format!("History frozen, scrubbing {} frames", self.history.len()));
+            }
+        }
+        self.memory.set(memory::Buffer::FrameHistory, self.history.heap_bytes());
+        // The first frozen frame is still being copied; it goes out next time
+        let Some(packet) = served else {
+            return;
         };
 
-        let packet = AudioPacket::new_fft(
-            self.sample_rate as u32,
-            timestamp_ms,
-            left_bins,
-            right_bins,
-            left_peak,
-            right_peak,
-            left_rms,
-            right_rms,
-            left_wave,
-            right_wave,
-        );
+        // Published once for the Suite and the editor. The Suite's route
+        // turns frames away while rendering offline; the editor's doesn't.
+        let queued = self.ws_client.send(packet);
 
-        // Send to WebSocket (desktop app)
-        self.ws_client.send(packet.clone());
+        // A full queue is the Suite falling behind, and already in the
+        // drop rate; anything else is worth a line, once
+        let failure = queued
+            .err()
+            .filter(|e| !self.offline && !matches!(e, HardwaveError::Transport(TransportError::QueueFull)));
+        if let Some(e) = &failure {
+            if !self.send_failing {
+                Self::debug_log(&format!("Not streaming to the Suite: {}", e));
+            }
+        }
+        self.send_failing = failure.is_some();
 
-        // Send to editor webview (non-blocking, drops if full)
-        match self.editor_packet_tx.try_send(packet) {
-            Ok(_) => {},
-            Err(crossbeam_channel::TrySendError::Full(_)) => {
-                Self::debug_log("editor channel FULL — dropping packet");
-            },
-            Err(crossbeam_channel::TrySendError::Disconnected(_)) => {
-                Self::debug_log("editor channel DISCONNECTED — no receivers");
-            },
+        // Queued behind their frame; a failure is the frame's
+        for mut packet in [raw, waveform, reference, surround].into_iter().flatten() {
+            self.sequence.stamp(&mut packet);
+            let _ = self.ws_client.send(packet);
+        }
+    }
+
+    /// Whether the live frame `packet` takes a raw spectrum packet. None
+    /// while the history is frozen, or without the spectrum.
+    fn raw_spectrum_due(&mut self, packet: &AudioPacket) -> bool {
+        let network = &self.params.network;
+        if !network.raw_spectrum.value() {
+            self.raw_spectrum.reset();
+            return false;
+        }
+        !self.history.is_frozen()
+            && !packet.left_bins.is_empty()
+            && self.raw_spectrum.due(packet.timestamp_ms, network.raw_spectrum_rate.value() as f32)
+    }
+
+    /// Tell the Suite a loop began, wrapped or ended
+    fn send_loop_event(&self, event: &LoopEvent, sample_rate: f32) {
+        if self.offline {
+            return;
+        }
+        let timestamp_ms = self.now_ms();
+        let policy = self.params.analysis.loop_policy.value().wire_id();
+        let mut packet = AudioPacket::new_loop(sample_rate as u32, timestamp_ms, event, policy);
+        self.sequence.stamp(&mut packet);
+        if let Err(e) = self.ws_client.send(packet) {
+            Self::debug_log(&format!("Loop event not sent to the Suite: {}; {:?}", e, event));
+        }
+    }
+
+    /// Tell the Suite the host's tempo, time signature, position and play
+    /// state
+    fn send_transport(&self, transport: &TransportSnapshot) {
+        if self.offline {
+            return;
+        }
+        let mut packet = AudioPacket::new_transport(self.now_ms(), transport);
+        self.sequence.stamp(&mut packet);
+        if let Err(e) = self.ws_client.send(packet) {
+            Self::debug_log(&format!("Transport not sent to the Suite: {}", e));
         }
     }
 }
@@ -320,3 +780,249 @@ impl Vst3Plugin for HardwaveAnalyser {
 
 nih_export_clap!(HardwaveAnalyser);
 nih_export_vst3!(HardwaveAnalyser);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::panics::hooks;
+    use crate::protocol::{PACKET_TYPE_FFT, PACKET_TYPE_HEARTBEAT};
+    use crossbeam_channel::Receiver;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::time::Duration;
+
+    struct TestInit;
+
+    impl InitContext<HardwaveAnalyser> for TestInit {
+        fn plugin_api(&self) -> PluginApi {
+            PluginApi::Clap
+        }
+
+        fn execute(&self, _task: Task) {}
+
+        fn set_latency_samples(&self, _samples: u32) {}
+
+        fn set_current_voice_capacity(&self, _capacity: u32) {}
+    }
+
+    /// Accept every connection and forward each packet with the index of
+    /// the connection it came on
+    fn mock_suite() -> (u16, Receiver<(usize, AudioPacket)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else { continue };
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let Ok(mut ws) = tungstenite::accept(stream) else { return };
+                    while let Ok(message) = ws.read() {
+                        if let tungstenite::Message::Binary(data) = message {
+                            if let Ok(packet) = AudioPacket::from_bytes(&data) {
+                                let _ = tx.send((connection, packet));
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    #[test]
+    fn test_initialize_starts_streaming_once() {
+        let _serial = runtime::tests::SERIAL.lock();
+        let (port, rx) = mock_suite();
+        let mut plugin = HardwaveAnalyser::default();
+        // Nothing streams before the host initialises the plugin, and
+        // sending says so
+        assert!(matches!(
+            plugin.ws_client.send(AudioPacket::new_heartbeat(48000, 0)),
+            Err(HardwaveError::Transport(TransportError::NotStarted))
+        ));
+
+        let config = BufferConfig {
+            sample_rate: 48000.0,
+            min_buffer_size: None,
+            max_buffer_size: 512,
+            process_mode: ProcessMode::Realtime,
+        };
+        // Hosts initialise again after restoring a project
+        for _ in 0..2 {
+            assert!(plugin.initialize(&HardwaveAnalyser::AUDIO_IO_LAYOUTS[0], &config, &mut TestInit));
+            plugin.apply_port(i32::from(port));
+        }
+
+        // Frames from the audio thread arrive, all over one connection and
+        // tagged with this instance
+        let tag = display::instance_tag(&plugin.params.display_hints().instance_id);
+        let block: Vec<f32> = (0..512).map(|i| 0.5 * (i as f32 * 0.13).sin()).collect();
+        let stopped = TransportSnapshot { sample_rate: 48000.0, ..TransportSnapshot::default() };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut connections = Vec::new();
+        let mut frames = 0;
+        while frames < 5 {
+            assert!(Instant::now() < deadline, "{} frames arrived after initialize", frames);
+            plugin.analyse_contained(&block, &block, None, &[], 0, &stopped).unwrap();
+            while let Ok((connection, packet)) = rx.try_recv() {
+                connections.push(connection);
+                assert_eq!(packet.instance_tag, tag, "packet type {}", packet.packet_type);
+                if packet.packet_type == PACKET_TYPE_FFT {
+                    frames += 1;
+                }
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        connections.sort_unstable();
+        connections.dedup();
+        assert_eq!(connections.len(), 1, "packets over connections {:?}", connections);
+    }
+
+    #[test]
+    fn test_heartbeats_follow_the_sample_rate() {
+        let _serial = runtime::tests::SERIAL.lock();
+        let (port, rx) = mock_suite();
+        let mut plugin = HardwaveAnalyser::default();
+        let mut heartbeat_at = |sample_rate: f32| {
+            let config = BufferConfig {
+                sample_rate,
+                min_buffer_size: None,
+                max_buffer_size: 512,
+                process_mode: ProcessMode::Realtime,
+            };
+            assert!(plugin.initialize(&HardwaveAnalyser::AUDIO_IO_LAYOUTS[0], &config, &mut TestInit));
+            plugin.apply_port(i32::from(port));
+            let expected = plugin.engine.metadata().sample_rate;
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                assert!(Instant::now() < deadline, "no heartbeat at {} Hz", expected);
+                let Ok((_, packet)) = rx.recv_timeout(Duration::from_millis(100)) else { continue };
+                if packet.packet_type == PACKET_TYPE_HEARTBEAT && packet.sample_rate == expected {
+                    return expected;
+                }
+            }
+        };
+        // Hosts initialise again when the sample rate changes
+        let before = heartbeat_at(44_100.0);
+        let after = heartbeat_at(96_000.0);
+        assert_ne!(before, after);
+    }
+
+    #[test]
+    fn test_suite_freezes_the_frames_it_gets() {
+        let _serial = runtime::tests::SERIAL.lock();
+        // Freezes the history with the third frame, and passes every frame on
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let mut frames = 0;
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { continue };
+                let Ok(mut ws) = tungstenite::accept(stream) else { continue };
+                while let Ok(message) = ws.read() {
+                    let tungstenite::Message::Binary(data) = message else { continue };
+                    let Ok(packet) = AudioPacket::from_bytes(&data) else { continue };
+                    if packet.packet_type != PACKET_TYPE_FFT {
+                        continue;
+                    }
+                    frames += 1;
+                    let freeze = tungstenite::Message::Text(r#"{"type":"freeze"}"#.to_string());
+                    if (frames == 3 && ws.send(freeze).is_err()) || tx.send(packet).is_err() {
+                        return;
+                    }
+                }
+            }
+        });
+
+        let mut plugin = HardwaveAnalyser::default();
+        let config = BufferConfig {
+            sample_rate: 48000.0,
+            min_buffer_size: None,
+            max_buffer_size: 512,
+            process_mode: ProcessMode::Realtime,
+        };
+        assert!(plugin.initialize(&HardwaveAnalyser::AUDIO_IO_LAYOUTS[0], &config, &mut TestInit));
+        plugin.apply_port(i32::from(port));
+
+        // Louder every block, so no two live frames are alike
+        let stopped = TransportSnapshot { sample_rate: 48000.0, ..TransportSnapshot::default() };
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let mut frames: Vec<AudioPacket> = Vec::new();
+        let mut blocks = 0;
+        while frames.iter().filter(|frame| frame.historical).count() < 5 {
+            assert!(Instant::now() < deadline, "{} frames arrived", frames.len());
+            let gain = 0.01 * (blocks % 100 + 1) as f32;
+            let block: Vec<f32> = (0..512).map(|i| gain * (i as f32 * 0.13).sin()).collect();
+            plugin.analyse_contained(&block, &block, None, &[], 0, &stopped).unwrap();
+            blocks += 1;
+            frames.extend(rx.try_iter());
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_ne!(frames[0].left_peak, frames[1].left_peak, "live frames change");
+        let first = frames.iter().position(|frame| frame.historical).unwrap();
+        assert!(first >= 3, "live until the Suite froze them");
+        let frozen = &frames[first..];
+        for frame in frozen {
+            assert!(frame.historical);
+            assert_eq!(frame.timestamp_ms, frozen[0].timestamp_ms);
+            assert_eq!(frame.left_peak, frozen[0].left_peak);
+            assert_eq!(frame.left_bins, frozen[0].left_bins);
+        }
+    }
+
+    #[test]
+    fn test_suite_names_are_saved_by_the_background_task() {
+        let _serial = runtime::tests::SERIAL.lock();
+        let mut plugin = HardwaveAnalyser::default();
+        let executor = plugin.task_executor();
+        plugin.remote.apply(remote::RemoteCommand::SetInstanceName("Drums".to_string()));
+
+        // The audio thread only learns there is one
+        assert!(plugin.remote.take_naming());
+        assert_ne!(plugin.params.display_hints().label, "Drums");
+        executor(Task::ApplyInstanceName);
+        assert_eq!(plugin.params.display_hints().label, "Drums");
+        assert_eq!(plugin.ws_client.display_hints().lock().label, "Drums", "announced");
+    }
+
+    #[test]
+    fn test_instances_tag_their_packets_apart() {
+        let _serial = runtime::tests::SERIAL.lock();
+        let instances = [HardwaveAnalyser::default(), HardwaveAnalyser::default()];
+        let tags: Vec<(String, u32)> = instances
+            .iter()
+            .map(|plugin| {
+                let hints = plugin.params.display_hints();
+                plugin.ws_client.set_display_hints(hints.clone());
+                let mut packet = AudioPacket::fft(48000, 0);
+                plugin.sequence.stamp(&mut packet);
+                assert_eq!(packet.instance_tag, display::instance_tag(&hints.instance_id));
+                (hints.instance_id, packet.instance_tag)
+            })
+            .collect();
+        assert_ne!(tags[0].0, tags[1].0, "a fresh ID each");
+        assert_ne!(tags[0].1, tags[1].1);
+    }
+
+    #[test]
+    fn test_process_panic_starts_the_analysis_over() {
+        let _serial = runtime::tests::SERIAL.lock();
+        let mut plugin = HardwaveAnalyser { offline: true, ..HardwaveAnalyser::default() };
+        plugin.engine.set_sample_rate(48000.0);
+        let block: Vec<f32> = (0..512).map(|i| 0.5 * (i as f32 * 0.13).sin()).collect();
+        let stopped = TransportSnapshot { sample_rate: 48000.0, ..TransportSnapshot::default() };
+
+        hooks::inject(Region::Process, Some(std::thread::current().id()));
+        assert!(plugin.analyse_contained(&block, &block, None, &[], 0, &stopped).is_err());
+
+        // The next blocks are analysed as usual
+        let before = plugin.engine.work_counts().spectra;
+        for _ in 0..40 {
+            plugin.analyse_contained(&block, &block, None, &[], 0, &stopped).unwrap();
+        }
+        assert!(plugin.engine.work_counts().spectra > before);
+    }
+}
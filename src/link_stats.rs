@@ -0,0 +1,140 @@
+//! Whether the packets are getting to the Suite
+//!
+//! Short of watching the Suite there was no telling whether the bridge
+//! delivers. Each connection counts as it goes: packets and bytes written
+//! to the link, packets its queue on the bus had no room for, reconnects,
+//! when a packet last went out and what last went wrong. `send` and the
+//! connection thread update the counts, and a `LinkReport` of them is what
+//! the Windows packet server's `/status` serves. Heartbeats carry a
+//! `LinkSummary` of the counts, so the Suite can show the link's health
+//! from its end too.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counts of one connection. Written by the bus's and the connection's
+/// threads, read by anyone.
+#[derive(Debug, Default)]
+pub struct LinkStats {
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    bytes: AtomicU64,
+    reconnects: AtomicU32,
+    /// Wall-clock ms of the last packet sent; 0 before the first
+    last_sent_ms: AtomicU64,
+    last_error: Mutex<Option<String>>,
+}
+
+impl LinkStats {
+    /// Count a packet of `bytes` written to the link
+    pub fn sent(&self, bytes: usize) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.last_sent_ms.store(wall_ms(), Ordering::Relaxed);
+    }
+
+    /// Count a packet there was no room for
+    pub fn dropped(&self) {
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Keep why a connection failed or ended. Formats and locks; not for
+    /// the audio thread. Returns whether it is another reason than the last.
+    pub fn failed(&self, error: &dyn Display) -> bool {
+        let error = error.to_string();
+        let mut last = self.last_error.lock();
+        let changed = last.as_deref() != Some(error.as_str());
+        *last = Some(error);
+        changed
+    }
+
+    /// Why the last connection failed or ended, if one did
+    #[cfg(any(feature = "gui", test))]
+    pub fn last_error(&self) -> Option<String> {
+        self.last_error.lock().clone()
+    }
+
+    #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+    pub fn report(&self) -> LinkReport {
+        LinkReport {
+            packets_sent: self.sent.load(Ordering::Relaxed),
+            packets_dropped: self.dropped.load(Ordering::Relaxed),
+            bytes_written: self.bytes.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            last_sent_ms: Some(self.last_sent_ms.load(Ordering::Relaxed)).filter(|&ms| ms != 0),
+            last_error: self.last_error(),
+        }
+    }
+
+    /// The counts for a heartbeat
+    pub fn summary(&self) -> LinkSummary {
+        LinkSummary {
+            packets_sent: self.sent.load(Ordering::Relaxed) as u32,
+            packets_dropped: self.dropped.load(Ordering::Relaxed) as u32,
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+            bytes_written: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// What `LinkStats` has counted, as JSON for the editor
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct LinkReport {
+    pub packets_sent: u64,
+    pub packets_dropped: u64,
+    pub bytes_written: u64,
+    pub reconnects: u32,
+    /// Wall-clock ms since the Unix epoch; `None` before the first
+    pub last_sent_ms: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+/// The counts a heartbeat carries; the packet counts wrap at `u32::MAX`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkSummary {
+    pub packets_sent: u32,
+    pub packets_dropped: u32,
+    pub reconnects: u32,
+    pub bytes_written: u64,
+}
+
+fn wall_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_counts_and_last_error() {
+        let stats = LinkStats::default();
+        assert_eq!(stats.report(), LinkReport::default());
+
+        stats.sent(100);
+        stats.sent(40);
+        stats.dropped();
+        stats.reconnected();
+        assert!(stats.failed(&"send failed"));
+        assert!(!stats.failed(&"send failed"), "the same again");
+        let report = stats.report();
+        assert_eq!((report.packets_sent, report.packets_dropped, report.bytes_written), (2, 1, 140));
+        assert_eq!(report.reconnects, 1);
+        assert!(report.last_sent_ms.is_some_and(|ms| ms > 0));
+        assert_eq!(report.last_error.as_deref(), Some("send failed"));
+        assert_eq!(
+            stats.summary(),
+            LinkSummary { packets_sent: 2, packets_dropped: 1, reconnects: 1, bytes_written: 140 }
+        );
+    }
+}
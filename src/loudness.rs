@@ -0,0 +1,158 @@
+//! Momentary and short-term loudness, ITU-R BS.1770
+//!
+//! The input is K-weighted at the host rate (see `weighting`), whatever the
+//! Weighting parameter and channel mode, and its squares summed over 100 ms
+//! blocks with both channels weighted 1.0. Momentary loudness is the mean
+//! over the last 4 blocks (400 ms), short-term over the last 30 (3 s);
+//! until that many have passed, over the blocks there are. Both are
+//! ungated, as the standard has them: its gates are for the integrated
+//! loudness over a programme. A mono input counts its one channel once, so
+//! a mono sine reads 3 LU under the same sine on both sides of a stereo
+//! one.
+//!
+//! Values are LUFS, `-0.691 + 10 log10` of the mean square, and bottom out
+//! at `FLOOR_DB` like every other reading. Frames carry the latest complete
+//! blocks, 100 ms resolution whatever the send interval. State is fixed
+//! size; a new rate or a reset starts from silence.
+
+use crate::params::Weighting;
+use crate::units::{power_to_db, FLOOR_DB};
+use crate::weighting::WeightingFilter;
+
+/// Blocks momentary loudness is the mean of
+pub const MOMENTARY_BLOCKS: usize = 4;
+
+/// Blocks short-term loudness is the mean of
+pub const SHORT_TERM_BLOCKS: usize = 30;
+
+/// Blocks per second
+const BLOCKS_PER_SECOND: f32 = 10.0;
+
+/// BS.1770's offset from the K-weighted mean square to LUFS, dB
+const LUFS_OFFSET: f32 = -0.691;
+
+/// Momentary and short-term loudness of a stereo or mono input
+#[derive(Debug, Clone)]
+pub struct LoudnessMeter {
+    left: WeightingFilter,
+    right: WeightingFilter,
+    /// Host samples per block
+    block_len: usize,
+    in_block: usize,
+    /// Weighted squares of the block so far, both channels
+    sum: f64,
+    /// Mean squares of the last blocks, a ring
+    blocks: [f64; SHORT_TERM_BLOCKS],
+    next: usize,
+    filled: usize,
+}
+
+impl LoudnessMeter {
+    pub fn new(sample_rate: f32) -> Self {
+        Self {
+            left: WeightingFilter::new(Weighting::K, sample_rate),
+            right: WeightingFilter::new(Weighting::K, sample_rate),
+            block_len: ((sample_rate / BLOCKS_PER_SECOND).round() as usize).max(1),
+            in_block: 0,
+            sum: 0.0,
+            blocks: [0.0; SHORT_TERM_BLOCKS],
+            next: 0,
+            filled: 0,
+        }
+    }
+
+    /// One host sample of each channel; `right` is ignored for a mono input
+    pub fn push(&mut self, left: f32, right: f32, mono: bool) {
+        let left = f64::from(self.left.process(left));
+        self.sum += left * left;
+        if !mono {
+            let right = f64::from(self.right.process(right));
+            self.sum += right * right;
+        }
+        self.in_block += 1;
+        if self.in_block == self.block_len {
+            self.blocks[self.next] = self.sum / self.block_len as f64;
+            self.next = (self.next + 1) % SHORT_TERM_BLOCKS;
+            self.filled = (self.filled + 1).min(SHORT_TERM_BLOCKS);
+            self.in_block = 0;
+            self.sum = 0.0;
+        }
+    }
+
+    /// Loudness over the last 400 ms, LUFS
+    pub fn momentary(&self) -> f32 {
+        self.loudness(MOMENTARY_BLOCKS)
+    }
+
+    /// Loudness over the last 3 s, LUFS
+    pub fn short_term(&self) -> f32 {
+        self.loudness(SHORT_TERM_BLOCKS)
+    }
+
+    /// Back to silence
+    pub fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+        self.in_block = 0;
+        self.sum = 0.0;
+        self.filled = 0;
+    }
+
+    fn loudness(&self, blocks: usize) -> f32 {
+        let blocks = blocks.min(self.filled);
+        if blocks == 0 {
+            return FLOOR_DB;
+        }
+        let sum: f64 =
+            (1..=blocks).map(|back| self.blocks[(self.next + SHORT_TERM_BLOCKS - back) % SHORT_TERM_BLOCKS]).sum();
+        (power_to_db((sum / blocks as f64) as f32) + LUFS_OFFSET).max(FLOOR_DB)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    /// `seconds` of a 1 kHz sine of peak `amplitude`, on both channels or
+    /// as a mono input
+    fn sine(meter: &mut LoudnessMeter, rate: f32, seconds: f32, amplitude: f32, mono: bool) {
+        for n in 0..(seconds * rate) as usize {
+            let x = amplitude * (2.0 * PI * 1000.0 * n as f32 / rate).sin();
+            meter.push(x, x, mono);
+        }
+    }
+
+    #[test]
+    fn test_ebu_3341_stereo_sine_reads_minus_23() {
+        // EBU Tech 3341 case 1: 1 kHz at -23 dBFS on both channels
+        for rate in [44_100.0, 48_000.0, 96_000.0] {
+            let mut meter = LoudnessMeter::new(rate);
+            sine(&mut meter, rate, 5.0, 10f32.powf(-23.0 / 20.0), false);
+            let (momentary, short_term) = (meter.momentary(), meter.short_term());
+            assert!((momentary + 23.0).abs() < 0.1, "at {}: momentary {}", rate, momentary);
+            assert!((short_term + 23.0).abs() < 0.1, "at {}: short-term {}", rate, short_term);
+        }
+    }
+
+    #[test]
+    fn test_windows_mono_silence_and_reset() {
+        let rate = 48_000.0;
+        let mut meter = LoudnessMeter::new(rate);
+        assert_eq!(meter.momentary(), FLOOR_DB);
+
+        // Mono counts once
+        sine(&mut meter, rate, 5.0, 10f32.powf(-23.0 / 20.0), true);
+        assert!((meter.momentary() + 26.01).abs() < 0.1, "{}", meter.momentary());
+
+        // A second of silence: gone from the momentary window but for the
+        // filters' tail, a third of the short-term one
+        sine(&mut meter, rate, 1.0, 0.0, false);
+        assert!(meter.momentary() < -70.0, "{}", meter.momentary());
+        let expected = -26.01 + 10.0 * (2.0f32 / 3.0).log10();
+        assert!((meter.short_term() - expected).abs() < 0.1, "{}", meter.short_term());
+
+        meter.reset();
+        assert_eq!(meter.short_term(), FLOOR_DB);
+    }
+}
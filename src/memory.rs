@@ -0,0 +1,311 @@
+//! Per-instance memory accounting
+//!
+//! Every buffer an instance keeps for its lifetime has a gauge here and a
+//! documented cap. The thread that owns a buffer sets its gauge whenever the
+//! size can have changed; gauges are plain atomics, so the audio thread can
+//! set them too. `MemoryGauges::report` lists them for the editor's `memory`
+//! IPC command and the packet server's `/memory` endpoint. Debug builds
+//! assert on every update that the buffer is within its cap.
+//!
+//! Sizes are heap bytes by capacity and leave out the FFT plans' twiddle
+//! tables and the short-lived second `AnalysisState` that exists while the
+//! config worker hands over a new one.
+
+#[cfg(any(feature = "gui", test))]
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::analysis::MAX_HISTORY_LEN;
+use crate::decimator::Decimator;
+use crate::fft::FftProcessor;
+use crate::history::FrameHistory;
+use crate::latency::HISTOGRAM_LEN;
+use crate::params::ZeroPad;
+use crate::protocol::{MAX_PACKET_HEAP_BYTES, NUM_BINS};
+use crate::recorder::RECORDER_BUFFER_BYTES;
+use crate::resampler::Resampler;
+use crate::self_check::SelfCheck;
+use crate::surround::MAX_CHANNELS;
+use crate::websocket::PACKET_QUEUE_LEN;
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+use crate::websocket::{StateTransition, STATE_HISTORY_LEN};
+
+/// What one instance may use in total; the sum of every cap with headroom
+#[cfg(any(feature = "gui", test))]
+pub const MEMORY_BUDGET_BYTES: usize = 12 * 1024 * 1024;
+
+/// The long-lived buffers of one instance
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Buffer {
+    /// Analysis-rate sample history, both channels
+    SampleRings,
+    /// FFT input, scratch and window, both channels
+    FftBuffers,
+    /// Half-band filter histories and coefficients, both channels
+    Decimators,
+    /// Power sums of the interval being averaged, both channels
+    HopPower,
+    /// Packets waiting for the connection thread
+    PacketQueue,
+    /// Packets waiting for the editor
+    EditorQueue,
+    /// Connection state transitions kept for diagnostics
+    StateHistory,
+    /// Recent frame latencies
+    LatencyHistogram,
+    /// Write buffer of an active packet recording
+    Recorder,
+    /// The editor's spectrogram of the last half minute
+    Spectrogram,
+    /// Synthetic frame and power sums of the `paranoid` self-check
+    SelfCheck,
+    /// The last 5 s of frames, for freezing and scrubbing
+    FrameHistory,
+    /// Sample history, FFTs and filters of the sidechain, with Reference
+    /// Analysis on
+    Reference,
+    /// Sample history, FFTs and filters of every channel of a surround
+    /// input
+    Surround,
+}
+
+impl Buffer {
+    pub const ALL: [Buffer; 14] = [
+        Buffer::SampleRings,
+        Buffer::FftBuffers,
+        Buffer::Decimators,
+        Buffer::HopPower,
+        Buffer::PacketQueue,
+        Buffer::EditorQueue,
+        Buffer::StateHistory,
+        Buffer::LatencyHistogram,
+        Buffer::Recorder,
+        Buffer::Spectrogram,
+        Buffer::SelfCheck,
+        Buffer::FrameHistory,
+        Buffer::Reference,
+        Buffer::Surround,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Buffer::SampleRings => "sample_rings",
+            Buffer::FftBuffers => "fft_buffers",
+            Buffer::Decimators => "decimators",
+            Buffer::HopPower => "hop_power",
+            Buffer::PacketQueue => "packet_queue",
+            Buffer::EditorQueue => "editor_queue",
+            Buffer::StateHistory => "state_history",
+            Buffer::LatencyHistogram => "latency_histogram",
+            Buffer::Recorder => "recorder_buffer",
+            Buffer::Spectrogram => "spectrogram",
+            Buffer::SelfCheck => "self_check",
+            Buffer::FrameHistory => "frame_history",
+            Buffer::Reference => "reference",
+            Buffer::Surround => "surround",
+        }
+    }
+
+    /// Most this buffer is allowed to hold, in bytes
+    pub fn cap_bytes(self) -> usize {
+        match self {
+            Buffer::SampleRings => 4 * MAX_HISTORY_LEN * size_of::<f32>(),
+            Buffer::FftBuffers => 2 * FftProcessor::MAX_HEAP_BYTES,
+            Buffer::Decimators => 2 * Decimator::MAX_HEAP_BYTES + Resampler::MAX_HEAP_BYTES,
+            Buffer::HopPower => 2 * NUM_BINS * ZeroPad::MAX_FACTOR * size_of::<f32>(),
+            Buffer::PacketQueue | Buffer::EditorQueue => PACKET_QUEUE_LEN * MAX_PACKET_HEAP_BYTES,
+            // Only the packet server on Windows reads it
+            #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+            Buffer::StateHistory => STATE_HISTORY_LEN * size_of::<StateTransition>(),
+            #[cfg(not(any(all(feature = "gui", target_os = "windows"), test)))]
+            Buffer::StateHistory => 0,
+            Buffer::LatencyHistogram => HISTOGRAM_LEN * size_of::<u32>(),
+            Buffer::Recorder => RECORDER_BUFFER_BYTES,
+            // Ring plus band edges; headless builds have no spectrogram
+            #[cfg(feature = "gui")]
+            Buffer::Spectrogram => {
+                crate::spectrogram::HISTORY_BYTES + (crate::spectrogram::BANDS + 1) * size_of::<f32>()
+            }
+            #[cfg(not(feature = "gui"))]
+            Buffer::Spectrogram => 0,
+            Buffer::SelfCheck => SelfCheck::HEAP_BYTES,
+            Buffer::FrameHistory => FrameHistory::MAX_HEAP_BYTES,
+            // The main input's rings, FFTs and filters once more
+            Buffer::Reference => {
+                Buffer::SampleRings.cap_bytes() + Buffer::FftBuffers.cap_bytes() + Buffer::Decimators.cap_bytes()
+            }
+            // A ring, mirrored like the main input's, an unpadded FFT and a
+            // decimator per channel of 7.1, and a resampler per pair
+            Buffer::Surround => {
+                MAX_CHANNELS
+                    * (2 * MAX_HISTORY_LEN * size_of::<f32>()
+                        + FftProcessor::UNPADDED_MAX_HEAP_BYTES
+                        + Decimator::MAX_HEAP_BYTES)
+                    + MAX_CHANNELS / 2 * Resampler::MAX_HEAP_BYTES
+            }
+        }
+    }
+}
+
+/// Heap bytes of a vector, by capacity
+pub fn vec_bytes<T>(v: &Vec<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+/// Heap bytes of a deque, by capacity
+pub fn deque_bytes<T>(v: &VecDeque<T>) -> usize {
+    v.capacity() * size_of::<T>()
+}
+
+/// Current size of every `Buffer`, shared by the threads that own them
+#[derive(Debug, Default)]
+pub struct MemoryGauges {
+    bytes: [AtomicUsize; Buffer::ALL.len()],
+}
+
+impl MemoryGauges {
+    /// Record the size of `buffer`. Never allocates.
+    pub fn set(&self, buffer: Buffer, bytes: usize) {
+        debug_assert!(
+            bytes <= buffer.cap_bytes(),
+            "{} uses {} bytes, over its {} byte cap",
+            buffer.name(),
+            bytes,
+            buffer.cap_bytes()
+        );
+        self.bytes[buffer as usize].store(bytes, Ordering::Relaxed);
+    }
+
+    #[cfg(any(feature = "gui", test))]
+    pub fn get(&self, buffer: Buffer) -> usize {
+        self.bytes[buffer as usize].load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "gui", test))]
+    pub fn report(&self) -> MemoryReport {
+        let buffers: Vec<BufferUsage> = Buffer::ALL
+            .iter()
+            .map(|&buffer| BufferUsage {
+                name: buffer.name(),
+                bytes: self.get(buffer),
+                cap_bytes: buffer.cap_bytes(),
+            })
+            .collect();
+        MemoryReport {
+            total_bytes: buffers.iter().map(|b| b.bytes).sum(),
+            budget_bytes: MEMORY_BUDGET_BYTES,
+            buffers,
+        }
+    }
+}
+
+/// One line of a `MemoryReport`
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BufferUsage {
+    pub name: &'static str,
+    pub bytes: usize,
+    pub cap_bytes: usize,
+}
+
+/// Buffer name → bytes for one instance
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct MemoryReport {
+    pub buffers: Vec<BufferUsage>,
+    pub total_bytes: usize,
+    pub budget_bytes: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::{AnalysisConfig, AnalysisState, MAX_SAMPLE_RATE};
+    use crate::params::WindowType;
+    use crate::protocol::{AudioPacket, WAVE_SIZE};
+    use crate::remote::DEFAULT_UPDATE_RATE_HZ;
+    use crate::websocket::WebSocketClient;
+
+    #[test]
+    fn test_caps_fit_the_budget() {
+        let caps: usize = Buffer::ALL.iter().map(|b| b.cap_bytes()).sum();
+        assert!(caps <= MEMORY_BUDGET_BYTES, "caps add up to {} bytes", caps);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "over its")]
+    fn test_over_cap_asserts() {
+        MemoryGauges::default().set(Buffer::HopPower, Buffer::HopPower.cap_bytes() + 1);
+    }
+
+    #[test]
+    fn test_report_lists_every_buffer() {
+        let gauges = MemoryGauges::default();
+        gauges.set(Buffer::HopPower, 100);
+        gauges.set(Buffer::LatencyHistogram, 50);
+        let report = gauges.report();
+        assert_eq!(report.buffers.len(), Buffer::ALL.len());
+        assert_eq!(report.total_bytes, 150);
+        let json = serde_json::to_string(&report).unwrap();
+        assert!(json.contains(r#"{"name":"hop_power","bytes":100,"cap_bytes":"#));
+    }
+
+    #[test]
+    fn test_long_session_stays_within_budget() {
+        // Nothing listens on port 1, so every packet piles up in the queue
+        let mut client = WebSocketClient::new();
+        client.set_port(1);
+        client.start().unwrap();
+        let gauges = client.memory_gauges();
+
+        // The most demanding analysis config, with its rings full
+        let mut state = AnalysisState::build(AnalysisConfig {
+            sample_rate: MAX_SAMPLE_RATE,
+            full_bandwidth: true,
+            zero_pad: ZeroPad::X4,
+            window: WindowType::Hann,
+            normalized_rate: false,
+            eco_mode: false,
+            reference: true,
+            channels: MAX_CHANNELS,
+            update_rate: DEFAULT_UPDATE_RATE_HZ,
+        });
+        for i in 0..state.history_len + 100 {
+            state.buffer(i as f32, -(i as f32));
+            if let Some(reference) = &mut state.reference {
+                reference.buffer(i as f32, -(i as f32));
+            }
+            if let Some(surround) = &mut state.surround {
+                surround.buffers.iter_mut().for_each(|buffer| buffer.push(i as f32));
+            }
+        }
+        state.report_memory(&gauges);
+
+        // Three hours of frames at 20 Hz, as fast as they can be queued
+        let frame = AudioPacket::fft(48000, 0)
+            .with_wave(vec![0.0; WAVE_SIZE], vec![0.0; WAVE_SIZE])
+            .with_coarse(vec![-50.0; 16], vec![-50.0; 16], vec![0.0; 17]);
+        let mut peak = 0;
+        for i in 0..3 * 60 * 60 * 20 {
+            let _ = client.send(frame.clone());
+            if i % 1000 == 0 {
+                peak = peak.max(gauges.report().total_bytes);
+            }
+        }
+        let report = gauges.report();
+        peak = peak.max(report.total_bytes);
+
+        assert!(
+            peak <= MEMORY_BUDGET_BYTES,
+            "peaked at {} bytes: {:?}",
+            peak,
+            report.buffers
+        );
+        assert!(report.buffers.iter().all(|b| b.bytes <= b.cap_bytes));
+        assert!(gauges.get(Buffer::PacketQueue) > 0);
+        assert!(gauges.get(Buffer::SampleRings) > 0);
+    }
+}
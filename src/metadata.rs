@@ -0,0 +1,84 @@
+//! What the hello packet says about the analysis
+//!
+//! Every connection starts with a `PACKET_TYPE_HELLO` packet, and the Suite
+//! can ask for it again with `request_metadata`, see `remote`. Besides the
+//! protocol version, the instance ID and the name in its display hints, it
+//! says what the instance analyses with, so the Suite can lay out its view
+//! before the first frame arrives:
+//!
+//! - `plugin_version`: the plugin's version, `0.5.0`
+//! - `sample_rate`: the rate the FFT sees, as in FFT packets
+//! - `fft_size`: samples in the FFT window; the bins of FFT packets are
+//!   `fft_size / 2 * zero_pad`, and `zero_pad` is in the hello too
+//! - `band_count`: coarse bands in FFT packets
+//! - `channel_mode`: what the two streams are, 0 left and right, 1 mid and
+//!   side
+//!
+//! The plugin keeps them current every block; a hello says what they were
+//! when it went out. All are 0 until the plugin initialises.
+
+use crate::params::ChannelMode;
+
+/// The version every hello packet carries
+pub const PLUGIN_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// `channel_mode` of each `ChannelMode`
+pub const CHANNEL_MODE_LEFT_RIGHT: u8 = 0;
+pub const CHANNEL_MODE_MID_SIDE: u8 = 1;
+
+/// The analysis as the Suite is told of it; set by the plugin, read by the
+/// connection thread
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metadata {
+    pub sample_rate: u32,
+    pub fft_size: u32,
+    pub zero_pad: u8,
+    pub band_count: u8,
+    pub channel_mode: u8,
+}
+
+/// `channel_mode` of `mode`
+pub fn channel_mode(mode: ChannelMode) -> u8 {
+    match mode {
+        ChannelMode::LeftRight => CHANNEL_MODE_LEFT_RIGHT,
+        ChannelMode::MidSide => CHANNEL_MODE_MID_SIDE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::display::DisplayHints;
+    use crate::protocol::{AudioPacket, PACKET_TYPE_HELLO, PROTOCOL_VERSION};
+
+    #[test]
+    fn test_hello_round_trip() {
+        let hints = DisplayHints {
+            instance_id: "3f2b8c1e-0000-4000-8000-000000000000".to_string(),
+            label: "Drums".to_string(),
+            color: "#ff8800".to_string(),
+            order: 2,
+            channel_labels: Some(["Kick".to_string(), "Snare".to_string()]),
+        };
+        let metadata = Metadata {
+            sample_rate: 44_100,
+            fft_size: 4096,
+            zero_pad: 4,
+            band_count: 16,
+            channel_mode: channel_mode(ChannelMode::MidSide),
+        };
+        let hello = AudioPacket::new_hello().with_display(&hints).with_metadata(&metadata);
+        let decoded = AudioPacket::from_bytes(&hello.to_bytes()).unwrap();
+        assert_eq!(decoded.packet_type, PACKET_TYPE_HELLO);
+        assert_eq!(decoded.supported_version, PROTOCOL_VERSION);
+        assert_eq!(decoded.plugin_version, PLUGIN_VERSION);
+        assert_eq!((decoded.instance_id.as_str(), decoded.display_label.as_str()), (hints.instance_id.as_str(), "Drums"));
+        assert_eq!((decoded.sample_rate, decoded.fft_size, decoded.zero_pad), (44_100, 4096, 4));
+        assert_eq!((decoded.band_count, decoded.channel_mode), (16, CHANNEL_MODE_MID_SIDE));
+        assert_eq!(decoded.channel_labels, ["Kick", "Snare"]);
+
+        // Other packets say nothing of it
+        let heartbeat = AudioPacket::from_bytes(&AudioPacket::new_heartbeat(0, 0).to_bytes()).unwrap();
+        assert_eq!((heartbeat.plugin_version.as_str(), heartbeat.fft_size, heartbeat.band_count), ("", 0, 0));
+    }
+}
@@ -0,0 +1,247 @@
+//! Standardised meter ballistics
+//!
+//! The packet's raw peak/RMS values are instantaneous per frame. These
+//! ballistics give every client the same peak-hold/release and RMS
+//! integration behaviour. State updates once per analysis frame.
+//!
+//! The coarse bands get a peak hold of their own, `BandPeaks`, for the thin
+//! line an analyser draws above the live bars.
+
+use crate::bands::MAX_COARSE_BANDS;
+use crate::units::FLOOR_DB;
+
+const MAX_BANDS: usize = MAX_COARSE_BANDS as usize;
+
+/// Peak meter with hold and a linear (dB/s) release.
+#[derive(Debug, Clone, Copy)]
+pub struct PeakBallistics {
+    value_db: f32,
+    hold_left: f32,
+}
+
+impl PeakBallistics {
+    pub fn new() -> Self {
+        Self {
+            value_db: FLOOR_DB,
+            hold_left: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// Feed the frame's peak in dB after `dt` seconds. Returns the meter value.
+    /// Non-finite input leaves the meter unchanged.
+    pub fn update(&mut self, peak_db: f32, dt: f32, hold_s: f32, release_db_per_s: f32) -> f32 {
+        if !peak_db.is_finite() || !dt.is_finite() {
+            return self.value_db;
+        }
+        if peak_db >= self.value_db {
+            self.value_db = peak_db;
+            self.hold_left = hold_s;
+        } else if self.hold_left > 0.0 {
+            self.hold_left -= dt;
+        } else {
+            self.value_db = (self.value_db - release_db_per_s * dt).max(peak_db);
+        }
+        self.value_db = self.value_db.max(FLOOR_DB);
+        self.value_db
+    }
+}
+
+impl Default for PeakBallistics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// RMS meter with separate attack and release integration times.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RmsBallistics {
+    value: f32,
+}
+
+impl RmsBallistics {
+    #[cfg(test)]
+    pub fn new() -> Self {
+        Self { value: 0.0 }
+    }
+
+    pub fn reset(&mut self) {
+        self.value = 0.0;
+    }
+
+    /// Feed the frame's linear RMS after `dt` seconds. A time constant of zero
+    /// follows the input instantly. Non-finite input leaves the meter
+    /// unchanged. Returns the meter value.
+    pub fn update(&mut self, rms: f32, dt: f32, attack_s: f32, release_s: f32) -> f32 {
+        if !rms.is_finite() || !dt.is_finite() {
+            return self.value;
+        }
+        let tau = if rms > self.value { attack_s } else { release_s };
+        if tau <= 0.0 {
+            self.value = rms;
+        } else {
+            let coeff = 1.0 - (-dt / tau).exp();
+            self.value += (rms - self.value) * coeff;
+        }
+        self.value
+    }
+}
+
+/// Peak and RMS ballistics for one channel
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelMeter {
+    pub peak: PeakBallistics,
+    pub rms: RmsBallistics,
+}
+
+impl ChannelMeter {
+    pub fn reset(&mut self) {
+        self.peak.reset();
+        self.rms.reset();
+    }
+}
+
+/// Peak hold of each coarse band of both channels, in dB
+#[derive(Debug, Clone)]
+pub struct BandPeaks {
+    left: [PeakBallistics; MAX_BANDS],
+    right: [PeakBallistics; MAX_BANDS],
+    /// Bands being held; 0 until the first frame
+    bands: usize,
+}
+
+impl Default for BandPeaks {
+    fn default() -> Self {
+        Self { left: [PeakBallistics::new(); MAX_BANDS], right: [PeakBallistics::new(); MAX_BANDS], bands: 0 }
+    }
+}
+
+impl BandPeaks {
+    /// Forget the peaks
+    pub fn clear(&mut self) {
+        self.bands = 0;
+    }
+
+    /// Feed one frame's raw coarse bands, in dB, `dt` seconds after the
+    /// previous one, and return the held peaks of the left and right. A
+    /// different number of bands starts over.
+    pub fn update(
+        &mut self,
+        left_db: &[f32],
+        right_db: &[f32],
+        dt: f32,
+        hold_s: f32,
+        decay_db_per_s: f32,
+    ) -> (Vec<f32>, Vec<f32>) {
+        let bands = left_db.len().min(right_db.len()).min(MAX_BANDS);
+        if bands != self.bands {
+            self.bands = bands;
+            self.left.iter_mut().chain(&mut self.right).for_each(PeakBallistics::reset);
+        }
+        let hold = |peaks: &mut [PeakBallistics; MAX_BANDS], levels: &[f32]| -> Vec<f32> {
+            peaks.iter_mut().zip(levels).map(|(peak, &db)| peak.update(db, dt, hold_s, decay_db_per_s)).collect()
+        };
+        (hold(&mut self.left, &left_db[..bands]), hold(&mut self.right, &right_db[..bands]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.05;
+
+    #[test]
+    fn test_peak_release_slope() {
+        let mut meter = PeakBallistics::new();
+        meter.update(0.0, DT, 0.0, 20.0);
+        let mut value = 0.0;
+        for _ in 0..20 {
+            value = meter.update(FLOOR_DB, DT, 0.0, 20.0);
+        }
+        // One second at 20 dB/s
+        assert!((value - (-20.0)).abs() < 0.5, "read {} dB", value);
+    }
+
+    #[test]
+    fn test_peak_hold_duration() {
+        let mut meter = PeakBallistics::new();
+        meter.update(-6.0, DT, 1.0, 20.0);
+        let mut elapsed = 0.0;
+        while elapsed < 0.95 {
+            assert_eq!(meter.update(FLOOR_DB, DT, 1.0, 20.0), -6.0, "dropped at {} s", elapsed);
+            elapsed += DT;
+        }
+        for _ in 0..4 {
+            meter.update(FLOOR_DB, DT, 1.0, 20.0);
+        }
+        assert!(meter.update(FLOOR_DB, DT, 1.0, 20.0) < -6.0, "still holding after hold time");
+    }
+
+    #[test]
+    fn test_peak_never_below_input_or_floor() {
+        let mut meter = PeakBallistics::new();
+        meter.update(0.0, DT, 0.0, 1000.0);
+        assert_eq!(meter.update(-10.0, DT, 0.0, 1000.0), -10.0);
+        assert_eq!(meter.update(-200.0, 10.0, 0.0, 1000.0), FLOOR_DB);
+    }
+
+    #[test]
+    fn test_non_finite_updates_are_ignored() {
+        let mut peak = PeakBallistics::new();
+        peak.update(-6.0, DT, 1.0, 20.0);
+        for bad in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+            assert_eq!(peak.update(bad, DT, 1.0, 20.0), -6.0);
+        }
+
+        let mut rms = RmsBallistics::new();
+        rms.update(0.5, DT, 0.0, 0.0);
+        for bad in [f32::NAN, f32::INFINITY] {
+            assert_eq!(rms.update(bad, DT, 0.3, 0.3), 0.5);
+        }
+        assert_eq!(rms.update(0.25, f32::NAN, 0.3, 0.3), 0.5);
+    }
+
+    #[test]
+    fn test_band_peaks_hold_then_decay() {
+        let mut peaks = BandPeaks::default();
+        let (left, right) = peaks.update(&[-10.0, -30.0], &[-20.0, -40.0], DT, 1.0, 20.0);
+        assert_eq!((left, right), (vec![-10.0, -30.0], vec![-20.0, -40.0]));
+
+        // Held for a second, then 20 dB/s down towards the live bands
+        let quiet = ([-60.0, -25.0], [-60.0, -60.0]);
+        for frame in 0..19 {
+            let (left, right) = peaks.update(&quiet.0, &quiet.1, DT, 1.0, 20.0);
+            assert_eq!(left, [-10.0, -25.0], "frame {}", frame);
+            assert_eq!(right, [-20.0, -40.0], "frame {}", frame);
+        }
+        let mut held = (Vec::new(), Vec::new());
+        for _ in 0..21 {
+            held = peaks.update(&quiet.0, &quiet.1, DT, 1.0, 20.0);
+        }
+        assert!((held.0[0] - (-30.0)).abs() < 1.5, "after decaying about a second: {:?}", held);
+        assert_eq!(held.0[1], -25.0);
+
+        // A new layout, or a clear, starts from the next frame
+        assert_eq!(peaks.update(&[-70.0; 3], &[-70.0; 3], DT, 1.0, 20.0).0, [-70.0; 3]);
+        peaks.clear();
+        assert_eq!(peaks.update(&[-80.0; 3], &[-80.0; 3], DT, 1.0, 20.0).0, [-80.0; 3]);
+    }
+
+    #[test]
+    fn test_rms_integration() {
+        let mut instant = RmsBallistics::new();
+        assert_eq!(instant.update(0.5, DT, 0.0, 0.0), 0.5);
+
+        // ~63% of a step after one time constant
+        let mut vu = RmsBallistics::new();
+        let mut value = 0.0;
+        for _ in 0..6 {
+            value = vu.update(1.0, DT, 0.3, 0.3);
+        }
+        assert!((value - 0.632).abs() < 0.01, "read {}", value);
+    }
+}
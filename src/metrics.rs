@@ -0,0 +1,225 @@
+//! Opt-in usage metrics for the Suite
+//!
+//! When the user opts in, one heartbeat per minute carries a `UsageMetrics`
+//! blob summarising that minute. It only ever travels over the existing Suite
+//! connection; nothing here contacts anything else. The blob carries no
+//! identifiers, only rates and counts.
+
+use nih_plug::prelude::PluginApi;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// How often a metrics blob is attached to a heartbeat
+pub const METRICS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Summary of one metrics interval
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct UsageMetrics {
+    /// Length of the interval this covers, in seconds
+    pub interval_s: u32,
+
+    /// FFT frames actually sent per second
+    pub update_rate_hz: f32,
+
+    /// Frames dropped because the network queue was full, as a percentage of
+    /// frames produced
+    pub drop_percent: f32,
+
+    /// Successful reconnects during the interval
+    pub reconnects: u32,
+
+    /// Plugin format the host loaded: 0 = unknown, 1 = CLAP, 2 = VST3,
+    /// 3 = standalone
+    pub plugin_api: u8,
+
+    /// 95th percentile audio-thread-to-TCP latency, in µs
+    pub latency_p95_us: u32,
+}
+
+/// Wire id of a plugin format, for `UsageMetrics::plugin_api`
+pub fn plugin_api_id(api: PluginApi) -> u8 {
+    match api {
+        PluginApi::Clap => 1,
+        PluginApi::Vst3 => 2,
+        PluginApi::Standalone => 3,
+    }
+}
+
+/// Running totals, bumped from the audio and network threads
+#[derive(Debug, Default)]
+pub struct UsageCounters {
+    produced: AtomicU64,
+    dropped: AtomicU64,
+    sent: AtomicU64,
+    reconnects: AtomicU32,
+}
+
+impl UsageCounters {
+    /// A frame was queued for sending, or dropped because the queue was full
+    pub fn frame_produced(&self, dropped: bool) {
+        self.produced.fetch_add(1, Ordering::Relaxed);
+        if dropped {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn frame_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn reconnected(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> UsageTotals {
+        UsageTotals {
+            produced: self.produced.load(Ordering::Relaxed),
+            dropped: self.dropped.load(Ordering::Relaxed),
+            sent: self.sent.load(Ordering::Relaxed),
+            reconnects: self.reconnects.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Snapshot of `UsageCounters`, or the difference between two
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UsageTotals {
+    pub produced: u64,
+    pub dropped: u64,
+    pub sent: u64,
+    pub reconnects: u32,
+}
+
+impl UsageTotals {
+    fn since(&self, base: &UsageTotals) -> UsageTotals {
+        UsageTotals {
+            produced: self.produced.saturating_sub(base.produced),
+            dropped: self.dropped.saturating_sub(base.dropped),
+            sent: self.sent.saturating_sub(base.sent),
+            reconnects: self.reconnects.saturating_sub(base.reconnects),
+        }
+    }
+}
+
+/// Decides when a metrics blob is due and what it covers. Owned by the
+/// network thread.
+///
+/// While opted out the window keeps restarting, so turning metrics on never
+/// reports activity from before the opt-in, and the first blob follows a full
+/// interval later.
+#[derive(Debug)]
+pub struct MetricsWindow {
+    started: Instant,
+    base: UsageTotals,
+}
+
+impl MetricsWindow {
+    pub fn new(now: Instant, totals: UsageTotals) -> Self {
+        Self { started: now, base: totals }
+    }
+
+    /// Called whenever a heartbeat is about to go out. Returns the activity
+    /// since the last blob and how long that covers, once per interval while
+    /// opted in.
+    pub fn poll(&mut self, now: Instant, opted_in: bool, totals: UsageTotals) -> Option<(UsageTotals, Duration)> {
+        let elapsed = now.saturating_duration_since(self.started);
+        if opted_in && elapsed < METRICS_INTERVAL {
+            return None;
+        }
+
+        let delta = totals.since(&self.base);
+        self.started = now;
+        self.base = totals;
+        opted_in.then_some((delta, elapsed))
+    }
+}
+
+impl UsageMetrics {
+    pub fn new(delta: UsageTotals, elapsed: Duration, plugin_api: u8, latency_p95_us: u32) -> Self {
+        let secs = elapsed.as_secs_f32().max(1e-3);
+        let drop_percent = if delta.produced == 0 {
+            0.0
+        } else {
+            delta.dropped as f32 * 100.0 / delta.produced as f32
+        };
+        Self {
+            interval_s: elapsed.as_secs().min(u32::MAX as u64) as u32,
+            update_rate_hz: delta.sent as f32 / secs,
+            drop_percent,
+            reconnects: delta.reconnects,
+            plugin_api,
+            latency_p95_us,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Drive a window with a heartbeat every second for `secs` seconds,
+    /// producing 20 frames per second of which one is dropped.
+    fn run(window: &mut MetricsWindow, counters: &UsageCounters, start: Instant, secs: u64, opted_in: bool) -> Vec<(u64, UsageTotals, Duration)> {
+        let mut blobs = Vec::new();
+        for s in 1..=secs {
+            for i in 0..20 {
+                counters.frame_produced(i == 0);
+                if i != 0 {
+                    counters.frame_sent();
+                }
+            }
+            if let Some((delta, elapsed)) = window.poll(start + Duration::from_secs(s), opted_in, counters.totals()) {
+                blobs.push((s, delta, elapsed));
+            }
+        }
+        blobs
+    }
+
+    #[test]
+    fn test_nothing_when_opted_out() {
+        let counters = UsageCounters::default();
+        let start = Instant::now();
+        let mut window = MetricsWindow::new(start, counters.totals());
+        assert!(run(&mut window, &counters, start, 600, false).is_empty());
+    }
+
+    #[test]
+    fn test_once_per_minute_when_opted_in() {
+        let counters = UsageCounters::default();
+        let start = Instant::now();
+        let mut window = MetricsWindow::new(start, counters.totals());
+
+        // Time spent opted out doesn't count toward the first blob
+        run(&mut window, &counters, start, 30, false);
+        let blobs = run(&mut window, &counters, start + Duration::from_secs(30), 600, true);
+
+        assert_eq!(blobs.len(), 10);
+        let at: Vec<u64> = blobs.iter().map(|(s, _, _)| *s).collect();
+        assert_eq!(at, (1..=10).map(|m| m * 60).collect::<Vec<_>>());
+        for (_, delta, elapsed) in &blobs {
+            assert_eq!(*elapsed, METRICS_INTERVAL);
+            assert_eq!(delta.produced, 60 * 20);
+            assert_eq!(delta.dropped, 60);
+
+            let metrics = UsageMetrics::new(*delta, *elapsed, 2, 800);
+            assert!((metrics.update_rate_hz - 19.0).abs() < 1e-3);
+            assert!((metrics.drop_percent - 5.0).abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn test_metrics_serialization() {
+        let metrics = UsageMetrics {
+            interval_s: 60,
+            update_rate_hz: 19.5,
+            drop_percent: 0.25,
+            reconnects: 2,
+            plugin_api: plugin_api_id(PluginApi::Clap),
+            latency_p95_us: 1_200,
+        };
+        let bytes = bincode::serialize(&metrics).unwrap();
+        assert_eq!(bytes.len(), 4 + 4 + 4 + 4 + 1 + 4);
+        assert_eq!(bincode::deserialize::<UsageMetrics>(&bytes).unwrap(), metrics);
+    }
+}
@@ -0,0 +1,380 @@
+//! One Suite connection for every instance in the process
+//!
+//! A project with 40 analysers used to open 40 WebSockets. When the plugin
+//! initializes, each instance joins the `SharedConnection` kept by the runtime
+//! context. The first to stream opens it, asking the Suite to multiplex with
+//! `X-Hardwave-Multiplex: 1`, and says hello with every instance on it:
+//! `{"type":"hello","streams":[{"stream":1,"instance_id":…,"label":…}]}`.
+//! Instances that join or leave later are announced with `stream_added` and
+//! `stream_removed`, and the last one out closes the connection.
+//!
+//! On a shared connection each binary frame starts with the stream ID of its
+//! instance, as a little-endian u32, followed by the packet as it would go
+//! out on a connection of its own. Text from the Suite with a `"stream"`
+//! field goes to that instance only, anything else to every instance.
+//!
+//! Each instance keeps its own connection thread, queue, state, heartbeats
+//! and stats; only the socket is shared, and its pings, see `keepalive`.
+//! The connection is authenticated once, in its own handshake, with the
+//! token of the instance that opened it; instances with the same token join
+//! it. Instances still connect on their own when the Suite doesn't answer
+//! with the header (asked again after `RETRY_AFTER`), when they stream to
+//! another port than the connection already open or to a Suite on another
+//! host, when their token is another, and when they use a packet key, which
+//! is agreed in the handshake of their own connection.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocket};
+use tungstenite::Message;
+
+use crate::discovery::SuiteAddress;
+use crate::error::TransportError;
+use crate::keepalive::Keepalive;
+use crate::tls::Stream;
+use crate::websocket;
+
+/// Handshake header asking for, and confirming, a multiplexed connection
+pub const HANDSHAKE_HEADER: &str = "X-Hardwave-Multiplex";
+
+/// Version of the multiplexed framing
+const VERSION: &str = "1";
+
+/// Bytes of the stream ID before each packet
+pub const TAG_LEN: usize = 4;
+
+/// How long a Suite that didn't agree to multiplex is left alone
+pub const RETRY_AFTER: Duration = Duration::from_secs(60);
+
+/// Texts kept for an instance that hasn't read them
+const INBOX_LEN: usize = 64;
+
+static NEXT_STREAM: AtomicU32 = AtomicU32::new(1);
+
+/// A stream ID no other instance in the process has
+pub fn next_stream_id() -> u32 {
+    NEXT_STREAM.fetch_add(1, Ordering::Relaxed)
+}
+
+pub fn request_header() -> String {
+    format!("{}: {}\r\n", HANDSHAKE_HEADER, VERSION)
+}
+
+/// Whether the handshake response `response` agreed to multiplex
+pub fn accepted(response: &str) -> bool {
+    let name = HANDSHAKE_HEADER.to_ascii_lowercase();
+    response.lines().any(|line| {
+        line.split_once(':')
+            .is_some_and(|(field, value)| field.trim().to_ascii_lowercase() == name && value.trim() == VERSION)
+    })
+}
+
+/// `packet` as a frame of stream `stream`
+pub fn tag(stream: u32, packet: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(TAG_LEN + packet.len());
+    frame.extend_from_slice(&stream.to_le_bytes());
+    frame.extend_from_slice(packet);
+    frame
+}
+
+/// What a stream whose socket is gone fails with: another stream's
+/// failure closed it, or the stream's own did
+fn closed() -> TransportError {
+    TransportError::Lost("shared connection closed".to_string())
+}
+
+/// The stream ID and packet of a frame
+#[cfg(test)]
+pub fn untag(frame: &[u8]) -> Option<(u32, &[u8])> {
+    if frame.len() < TAG_LEN {
+        return None;
+    }
+    let (id, packet) = frame.split_at(TAG_LEN);
+    Some((u32::from_le_bytes(id.try_into().ok()?), packet))
+}
+
+/// An instance on the shared connection, as the Suite is told of it
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct StreamInfo {
+    pub stream: u32,
+    pub instance_id: String,
+    pub label: String,
+}
+
+/// Control messages on a shared connection
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Control<'a> {
+    Hello { streams: &'a [StreamInfo] },
+    StreamAdded(&'a StreamInfo),
+    StreamRemoved { stream: u32 },
+}
+
+impl Control<'_> {
+    fn to_text(&self) -> String {
+        serde_json::to_string(self).expect("control messages serialize")
+    }
+}
+
+/// The stream a text from the Suite is for, if it names one
+#[derive(Deserialize)]
+struct Addressed {
+    stream: Option<u32>,
+}
+
+/// The connection every instance in the process streams over
+#[derive(Default)]
+pub struct SharedConnection {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    socket: Option<WebSocket<Stream>>,
+    port: u16,
+    /// The token the socket was opened with
+    bearer: Option<String>,
+    /// Counts sockets, so streams of a closed one notice
+    generation: u64,
+    /// Instances on the connection, in the order they joined
+    streams: Vec<StreamInfo>,
+    inboxes: HashMap<u32, VecDeque<String>>,
+    /// Pings on the socket
+    keepalive: Keepalive,
+    /// The port whose Suite didn't agree to multiplex, and when
+    refused: Option<(u16, Instant)>,
+}
+
+impl SharedConnection {
+    /// Join the connection to the Suite on `port` as `info`, opening it
+    /// with `bearer` if nobody has. `None` if this instance has to connect on
+    /// its own: the connection is to another port or with another token, or
+    /// the Suite there doesn't multiplex.
+    pub fn join(
+        self: &Arc<Self>,
+        port: u16,
+        bearer: Option<&str>,
+        info: StreamInfo,
+    ) -> Result<Option<SharedStream>, TransportError> {
+        let stream = info.stream;
+        let mut inner = self.inner.lock();
+        if inner.refused.is_some_and(|(refused, at)| refused == port && at.elapsed() < RETRY_AFTER) {
+            inner.leave(stream);
+            return Ok(None);
+        }
+        if inner.socket.is_some() && (inner.port != port || inner.bearer.as_deref() != bearer) {
+            if inner.streams.iter().any(|other| other.stream != stream) {
+                inner.leave(stream);
+                return Ok(None);
+            }
+            inner.close();
+        }
+        inner.add(info);
+        if inner.socket.is_none() && !inner.connect(port, bearer)? {
+            inner.leave(stream);
+            return Ok(None);
+        }
+        Ok(Some(SharedStream {
+            connection: Arc::clone(self),
+            stream,
+            generation: inner.generation,
+        }))
+    }
+
+    /// Take `stream` off the connection. The Suite is told, and the last
+    /// stream to leave closes the connection.
+    pub fn leave(&self, stream: u32) {
+        self.inner.lock().leave(stream);
+    }
+}
+
+impl Inner {
+    /// Open the connection, authenticated with `bearer` if there is one,
+    /// and say hello. False if the Suite doesn't multiplex.
+    fn connect(&mut self, port: u16, bearer: Option<&str>) -> Result<bool, TransportError> {
+        let headers = websocket::authorization_header(bearer) + &request_header();
+        let (mut socket, response) = websocket::open_socket(&SuiteAddress::local(port), "/", &headers, None)?;
+        if !accepted(&response) {
+            self.refused = Some((port, Instant::now()));
+            let _ = socket.close(None);
+            let _ = socket.flush();
+            return Ok(false);
+        }
+        self.refused = None;
+        let hello = Control::Hello { streams: &self.streams }.to_text();
+        if socket.send(Message::Text(hello)).is_err() || socket.flush().is_err() {
+            return Err(TransportError::Handshake("hello not sent"));
+        }
+        self.socket = Some(socket);
+        self.port = port;
+        self.bearer = bearer.map(str::to_string);
+        self.generation += 1;
+        self.keepalive = Keepalive::default();
+        Ok(true)
+    }
+
+    fn add(&mut self, info: StreamInfo) {
+        if self.streams.iter().any(|known| known.stream == info.stream) {
+            return;
+        }
+        self.inboxes.insert(info.stream, VecDeque::with_capacity(INBOX_LEN));
+        self.announce(&Control::StreamAdded(&info));
+        self.streams.push(info);
+    }
+
+    fn leave(&mut self, stream: u32) {
+        if let Some(index) = self.streams.iter().position(|known| known.stream == stream) {
+            self.streams.remove(index);
+            self.inboxes.remove(&stream);
+            self.announce(&Control::StreamRemoved { stream });
+        }
+        if self.streams.is_empty() {
+            self.close();
+        }
+    }
+
+    /// Send a control message if the connection is open
+    fn announce(&mut self, control: &Control) {
+        let Some(socket) = self.socket.as_mut() else {
+            return;
+        };
+        if socket.send(Message::Text(control.to_text())).is_err() || socket.flush().is_err() {
+            self.close();
+        }
+    }
+
+    /// Close the connection with a normal close frame, without waiting for
+    /// the Suite's
+    fn close(&mut self) {
+        if let Some(mut socket) = self.socket.take() {
+            let _ = socket.close(Some(CloseFrame { code: CloseCode::Normal, reason: "goodbye".into() }));
+            let _ = socket.flush();
+        }
+    }
+
+    /// The socket, if it is still the one of `generation`
+    fn socket(&mut self, generation: u64) -> Option<&mut WebSocket<Stream>> {
+        let current = self.generation == generation;
+        self.socket.as_mut().filter(|_| current)
+    }
+
+    /// Read whatever the Suite has sent into the inboxes, without waiting.
+    /// An error if the connection is dead, and closed now.
+    fn poll(&mut self, generation: u64) -> Result<(), TransportError> {
+        // Not through `socket`, so the keepalive can be borrowed with it
+        let current = self.generation == generation;
+        let Some(socket) = self.socket.as_mut().filter(|_| current) else {
+            return Err(closed());
+        };
+        let mut texts = Vec::new();
+        let now = Instant::now();
+        let keepalive = &mut self.keepalive;
+        let read = websocket::read_available(
+            socket,
+            |payload| {
+                keepalive.pong(payload, now);
+            },
+            |text| texts.push(text.to_string()),
+        );
+        for text in texts {
+            let to = serde_json::from_str::<Addressed>(&text).ok().and_then(|addressed| addressed.stream);
+            for (stream, inbox) in self.inboxes.iter_mut() {
+                if to.is_some_and(|to| to != *stream) {
+                    continue;
+                }
+                if inbox.len() == INBOX_LEN {
+                    inbox.pop_front();
+                }
+                inbox.push_back(text.clone());
+            }
+        }
+        let alive = read.and_then(|()| self.ping(now));
+        if alive.is_err() {
+            self.close();
+        }
+        alive
+    }
+
+    /// Ping the Suite if one is due. An error if it left the last one
+    /// unanswered too long, or this one couldn't be sent.
+    fn ping(&mut self, now: Instant) -> Result<(), TransportError> {
+        if self.keepalive.timed_out(now) {
+            return Err(TransportError::Lost("no pong".to_string()));
+        }
+        match (self.keepalive.ping_due(now), self.socket.as_mut()) {
+            (Some(payload), Some(socket)) => websocket::send_message(socket, Message::Ping(payload)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// One instance's stream on the shared connection. It goes dead with the
+/// socket it was opened on; joining again opens a new one.
+pub struct SharedStream {
+    connection: Arc<SharedConnection>,
+    stream: u32,
+    generation: u64,
+}
+
+impl SharedStream {
+    /// Send `packet` tagged with this stream's ID. An error if the
+    /// connection is dead.
+    pub fn send(&self, packet: &[u8]) -> Result<(), TransportError> {
+        let mut inner = self.connection.inner.lock();
+        let Some(socket) = inner.socket(self.generation) else {
+            return Err(closed());
+        };
+        let sent = websocket::send_message(socket, Message::Binary(tag(self.stream, packet)));
+        if sent.is_err() {
+            inner.close();
+        }
+        sent
+    }
+
+    /// Pass each text the Suite sent to this stream, or to all of them, to
+    /// `f`. An error if the connection is dead.
+    pub fn read_texts(&self, mut f: impl FnMut(&str)) -> Result<(), TransportError> {
+        let mut inner = self.connection.inner.lock();
+        inner.poll(self.generation)?;
+        if let Some(inbox) = inner.inboxes.get_mut(&self.stream) {
+            for text in inbox.drain(..) {
+                f(&text);
+            }
+        }
+        Ok(())
+    }
+
+    /// The latest round trip of the shared socket
+    pub fn round_trip(&self) -> Option<Duration> {
+        self.connection.inner.lock().keepalive.round_trip()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_frames_and_control_messages() {
+        let frame = tag(0x0102_0304, &[9, 8]);
+        assert_eq!(frame, vec![4, 3, 2, 1, 9, 8]);
+        assert_eq!(untag(&frame), Some((0x0102_0304, &[9u8, 8][..])));
+        assert_eq!(untag(&[1, 2]), None);
+
+        let info = StreamInfo { stream: 2, instance_id: "id".to_string(), label: "Kick".to_string() };
+        assert_eq!(
+            Control::StreamAdded(&info).to_text(),
+            r#"{"type":"stream_added","stream":2,"instance_id":"id","label":"Kick"}"#
+        );
+        assert_eq!(Control::StreamRemoved { stream: 2 }.to_text(), r#"{"type":"stream_removed","stream":2}"#);
+        assert!(Control::Hello { streams: &[info] }.to_text().starts_with(r#"{"type":"hello","streams":[{"stream":2"#));
+
+        assert!(accepted("HTTP/1.1 101 Switching Protocols\r\nx-hardwave-multiplex: 1\r\n\r\n"));
+        assert!(!accepted("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n"));
+    }
+}
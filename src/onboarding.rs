@@ -0,0 +1,387 @@
+//! First-run setup, as the plugin window's onboarding sees it
+//!
+//! New users open the plugin and don't know that the Suite has to be
+//! running, on the same port, or that logging in turns on the cloud
+//! features. The page draws the onboarding; this is what it draws it from.
+//! `SetupState` puts together what the plugin knows (the connection to the
+//! Suite, the account token, whether the window's own transport came up,
+//! whether this machine ever connected) and picks the `step` to show. The
+//! editor pushes it to the page as `onSetupState` whenever it changes, and
+//! answers `setupState` with it.
+//!
+//! The page can ask for three things with `setupAction:<name>`:
+//!
+//! - `retryConnect` cuts the reconnect backoff short
+//! - `openDocs` opens the setup guide in the browser
+//! - `applyRecommendedPort` sets the Port parameter to `ports::recommended()`
+//!
+//! Nothing else is relayed, and the page can't pass a URL or a port.
+//!
+//! The first run ends with the first connection to the Suite from any
+//! instance. That is recorded as `onboarded` in the machine config, so it is
+//! over for every instance, project and plugin format on the machine.
+
+use parking_lot::{Condvar, Mutex};
+#[cfg(any(feature = "gui", test))]
+use serde::Serialize;
+#[cfg(any(feature = "gui", test))]
+use std::fmt;
+#[cfg(any(feature = "gui", test))]
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::Duration;
+
+use crate::config;
+use crate::error::HardwaveError;
+#[cfg(feature = "gui")]
+use crate::threads::{self, Priority};
+
+/// The setup guide `openDocs` opens
+#[cfg(any(feature = "gui", test))]
+pub const DOCS_URL: &str = "https://hardwavestudios.com/docs/analyser/setup";
+
+/// What the page should walk the user through next
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SetupStep {
+    /// The window can't get packets from the plugin; nothing else shows
+    /// until it can
+    EditorTransport,
+    /// Start the Suite, or point the Port parameter at it
+    StartSuite,
+    /// Log in for the cloud features
+    LogIn,
+    Done,
+}
+
+/// The `setup_state` the page is sent
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct SetupState {
+    pub step: SetupStep,
+    pub suite_connected: bool,
+    pub logged_in: bool,
+    pub editor_transport_ok: bool,
+    /// No instance on this machine has connected to the Suite yet
+    pub first_run: bool,
+}
+
+#[cfg(any(feature = "gui", test))]
+impl SetupState {
+    pub fn compose(suite_connected: bool, logged_in: bool, editor_transport_ok: bool, first_run: bool) -> Self {
+        let step = if !editor_transport_ok {
+            SetupStep::EditorTransport
+        } else if !suite_connected {
+            SetupStep::StartSuite
+        } else if !logged_in {
+            SetupStep::LogIn
+        } else {
+            SetupStep::Done
+        };
+        Self {
+            step,
+            suite_connected,
+            logged_in,
+            editor_transport_ok,
+            first_run,
+        }
+    }
+}
+
+/// Something the page can ask for
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SetupAction {
+    RetryConnect,
+    OpenDocs,
+    ApplyRecommendedPort,
+}
+
+#[cfg(any(feature = "gui", test))]
+impl SetupAction {
+    pub const ALL: [SetupAction; 3] = [
+        SetupAction::RetryConnect,
+        SetupAction::OpenDocs,
+        SetupAction::ApplyRecommendedPort,
+    ];
+
+    /// Name in `setupAction:` messages
+    pub fn name(self) -> &'static str {
+        match self {
+            SetupAction::RetryConnect => "retryConnect",
+            SetupAction::OpenDocs => "openDocs",
+            SetupAction::ApplyRecommendedPort => "applyRecommendedPort",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|action| action.name() == name.trim())
+    }
+}
+
+/// What the actions act on; the editor's is the connection thread, the
+/// browser and the host
+#[cfg(any(feature = "gui", test))]
+pub trait SetupEffects {
+    fn retry_connect(&self);
+    fn open_external(&self, url: &str) -> io::Result<()>;
+    fn set_port(&self, port: u16);
+}
+
+/// Why an action wasn't carried out
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug)]
+pub enum ActionError {
+    /// Not one of `SetupAction::ALL`
+    Unknown(String),
+    /// The browser couldn't be started
+    Open(io::Error),
+}
+
+#[cfg(any(feature = "gui", test))]
+impl fmt::Display for ActionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ActionError::Unknown(name) => write!(f, "unknown setup action {:?}", name),
+            ActionError::Open(e) => write!(f, "couldn't open the setup guide: {}", e),
+        }
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+impl std::error::Error for ActionError {}
+
+/// Carry out the action the page asked for by `name`
+#[cfg(any(feature = "gui", test))]
+pub fn relay(name: &str, effects: &dyn SetupEffects) -> Result<SetupAction, ActionError> {
+    let action = SetupAction::parse(name).ok_or_else(|| ActionError::Unknown(name.to_string()))?;
+    match action {
+        SetupAction::RetryConnect => effects.retry_connect(),
+        SetupAction::OpenDocs => effects.open_external(DOCS_URL).map_err(ActionError::Open)?,
+        SetupAction::ApplyRecommendedPort => effects.set_port(crate::ports::recommended()),
+    }
+    Ok(action)
+}
+
+/// Open `url` in the default browser, without waiting for it
+#[cfg(feature = "gui")]
+pub fn open_external(url: &str) -> io::Result<()> {
+    #[cfg(target_os = "windows")]
+    let mut command = {
+        let mut command = std::process::Command::new("rundll32");
+        command.args(["url.dll,FileProtocolHandler", url]);
+        command
+    };
+    #[cfg(target_os = "macos")]
+    let mut command = {
+        let mut command = std::process::Command::new("open");
+        command.arg(url);
+        command
+    };
+    #[cfg(not(any(target_os = "windows", target_os = "macos")))]
+    let mut command = {
+        let mut command = std::process::Command::new("xdg-open");
+        command.arg(url);
+        command
+    };
+    let mut child = command.spawn()?;
+    // Reaped in the background; the opener exits as soon as it hands over
+    let _ = threads::spawn("hardwave-open", Priority::Background, move || {
+        let _ = child.wait();
+    });
+    Ok(())
+}
+
+const FIRST_RUN_UNKNOWN: u8 = 0;
+const FIRST_RUN: u8 = 1;
+const ONBOARDED: u8 = 2;
+
+/// What the connection thread and the plugin window share of the setup:
+/// the first-run flag and the page's requests to reconnect at once
+pub struct Onboarding {
+    /// Machine config the flag is kept in; none keeps it in memory
+    config_path: Option<PathBuf>,
+    /// `FIRST_RUN_UNKNOWN` until the config is first read
+    first_run: AtomicU8,
+    retry: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Onboarding {
+    /// The flag in this machine's config
+    pub fn machine() -> Self {
+        Self::at(config::config_path())
+    }
+
+    pub fn at(config_path: Option<PathBuf>) -> Self {
+        Self {
+            config_path,
+            first_run: AtomicU8::new(FIRST_RUN_UNKNOWN),
+            retry: Mutex::new(false),
+            wake: Condvar::new(),
+        }
+    }
+
+    /// Whether no instance on this machine has connected to the Suite. The
+    /// first call reads the config; one that can't be read counts as a
+    /// first run.
+    pub fn first_run(&self) -> bool {
+        match self.first_run.load(Ordering::Relaxed) {
+            FIRST_RUN_UNKNOWN => {
+                let onboarded = self
+                    .config_path
+                    .as_deref()
+                    .is_some_and(|path| config::load_from(path).is_ok_and(|config| config.onboarded));
+                let state = if onboarded { ONBOARDED } else { FIRST_RUN };
+                // Another thread may have connected in the meantime
+                let _ = self
+                    .first_run
+                    .compare_exchange(FIRST_RUN_UNKNOWN, state, Ordering::Relaxed, Ordering::Relaxed);
+                self.first_run.load(Ordering::Relaxed) == FIRST_RUN
+            }
+            state => state == FIRST_RUN,
+        }
+    }
+
+    /// Called by the connection thread on every connection to the Suite.
+    /// The first one ends the first run and records it in the config.
+    pub fn connected(&self) -> Result<(), HardwaveError> {
+        if !self.first_run() || self.first_run.swap(ONBOARDED, Ordering::Relaxed) == ONBOARDED {
+            return Ok(());
+        }
+        match &self.config_path {
+            Some(path) => config::mark_onboarded(path),
+            None => Ok(()),
+        }
+    }
+
+    /// Have the connection thread try again without waiting out its backoff
+    pub fn request_retry(&self) {
+        *self.retry.lock() = true;
+        self.wake.notify_all();
+    }
+
+    /// Called by the connection thread as it tries to connect: a retry
+    /// asked for before then, as by setting the port before the thread
+    /// starts, is this attempt and doesn't cut the next wait short
+    pub fn attempting(&self) {
+        *self.retry.lock() = false;
+    }
+
+    /// Wait out `backoff`, or until a retry is asked for. True if one was.
+    pub fn wait_for_retry(&self, backoff: Duration) -> bool {
+        let mut retry = self.retry.lock();
+        if !*retry {
+            self.wake.wait_for(&mut retry, backoff);
+        }
+        std::mem::take(&mut *retry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[derive(Default)]
+    struct Recorded {
+        effects: RefCell<Vec<String>>,
+        browser_fails: bool,
+    }
+
+    impl SetupEffects for Recorded {
+        fn retry_connect(&self) {
+            self.effects.borrow_mut().push("retry".to_string());
+        }
+
+        fn open_external(&self, url: &str) -> io::Result<()> {
+            if self.browser_fails {
+                return Err(io::Error::new(io::ErrorKind::NotFound, "no browser"));
+            }
+            self.effects.borrow_mut().push(format!("open {}", url));
+            Ok(())
+        }
+
+        fn set_port(&self, port: u16) {
+            self.effects.borrow_mut().push(format!("port {}", port));
+        }
+    }
+
+    #[test]
+    fn test_only_listed_actions_are_relayed() {
+        let recorded = Recorded::default();
+        assert_eq!(relay("retryConnect", &recorded).unwrap(), SetupAction::RetryConnect);
+        assert_eq!(relay("openDocs", &recorded).unwrap(), SetupAction::OpenDocs);
+        assert_eq!(relay(" applyRecommendedPort", &recorded).unwrap(), SetupAction::ApplyRecommendedPort);
+        assert_eq!(
+            *recorded.effects.borrow(),
+            ["retry".to_string(), format!("open {}", DOCS_URL), format!("port {}", crate::ports::recommended())]
+        );
+
+        for name in ["openUrl:https://example.com", "OpenDocs", "", "setPort:80"] {
+            assert!(matches!(relay(name, &recorded), Err(ActionError::Unknown(_))), "{:?}", name);
+        }
+        assert_eq!(recorded.effects.borrow().len(), 3);
+
+        let no_browser = Recorded { browser_fails: true, ..Recorded::default() };
+        assert!(matches!(relay("openDocs", &no_browser), Err(ActionError::Open(_))));
+    }
+
+    #[test]
+    fn test_fresh_install_to_connected_and_logged_in() {
+        let dir = std::env::temp_dir().join(format!("hardwave-onboarding-{}", std::process::id()));
+        let path = dir.join("config.json");
+        let _ = std::fs::remove_file(&path);
+        let onboarding = Onboarding::at(Some(path.clone()));
+        let state = |connected, logged_in| SetupState::compose(connected, logged_in, true, onboarding.first_run());
+
+        // Fresh install: no window transport yet, then no Suite
+        assert_eq!(SetupState::compose(false, false, false, onboarding.first_run()).step, SetupStep::EditorTransport);
+        let fresh = state(false, false);
+        assert_eq!((fresh.step, fresh.first_run), (SetupStep::StartSuite, true));
+
+        // The first connection ends the first run, machine-wide
+        onboarding.connected().unwrap();
+        let connected = state(true, false);
+        assert_eq!((connected.step, connected.first_run), (SetupStep::LogIn, false));
+        assert!(config::load_from(&path).unwrap().onboarded);
+        assert!(!Onboarding::at(Some(path.clone())).first_run(), "another instance still sees a first run");
+
+        let done = state(true, true);
+        assert_eq!((done.step, done.first_run), (SetupStep::Done, false));
+        // Losing the Suite later is a step back, not a first run
+        assert_eq!(state(false, true), SetupState::compose(false, true, true, false));
+        assert_eq!(state(false, true).step, SetupStep::StartSuite);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_retry_cuts_the_backoff_short() {
+        let onboarding = Arc::new(Onboarding::at(None));
+        assert!(!onboarding.wait_for_retry(Duration::from_millis(1)));
+
+        let started = Instant::now();
+        let waiter = {
+            let onboarding = Arc::clone(&onboarding);
+            std::thread::spawn(move || onboarding.wait_for_retry(Duration::from_secs(10)))
+        };
+        std::thread::sleep(Duration::from_millis(20));
+        onboarding.request_retry();
+        assert!(waiter.join().unwrap());
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        // Asked for before the wait: that wait ends at once, the next doesn't
+        onboarding.request_retry();
+        assert!(onboarding.wait_for_retry(Duration::from_secs(10)));
+        assert!(!onboarding.wait_for_retry(Duration::from_millis(1)));
+
+        // and an attempt made since answers it
+        onboarding.request_retry();
+        onboarding.attempting();
+        assert!(!onboarding.wait_for_retry(Duration::from_millis(1)));
+    }
+}
@@ -0,0 +1,560 @@
+//! Optional encryption of the packets sent to the Suite
+//!
+//! Spectra and levels give away the structure of unreleased music, so on a
+//! network the user doesn't trust they can set a pre-shared key, in the
+//! plugin window and the Suite alike. The key is 32 bytes, entered as 64
+//! hex digits or as base64, and saved at `~/.hardwave/packet-key`.
+//!
+//! With a key set the handshake asks for encryption with
+//!
+//! ```text
+//! X-Hardwave-Encryption: xchacha20poly1305; key-id=2a
+//! ```
+//!
+//! and only a Suite that answers with the same header, the same key ID
+//! included, gets packets. Any other answer leaves the connection in the
+//! error state with `TransportError::Encryption`, and it is retried like a
+//! failed connect; nothing goes out in the clear once a key is set.
+//!
+//! Every binary packet is then sealed with XChaCha20-Poly1305:
+//!
+//! ```text
+//! offset  size  field
+//!      0     1  PACKET_TYPE_SEALED (0x80), where a plain packet has its type
+//!      1     1  flags, bit 0 FLAG_ENCRYPTED
+//!      2     1  key ID
+//!      3    16  nonce prefix, random per connection
+//!     19     8  sequence number, u64 little-endian, from 0 per connection
+//!     27     n  the plain packet, encrypted
+//!   27+n    16  Poly1305 tag
+//! ```
+//!
+//! The nonce is the prefix followed by the sequence number, so it is never
+//! repeated for one key. The header is authenticated along with the packet.
+//! The key ID is the first byte of the tag of an empty message under the
+//! key, which names the key without saying anything about it. Text messages
+//! (token exchange, command acknowledgements) are not sealed.
+//!
+//! Key material is wiped when it is dropped.
+
+use chacha20poly1305::aead::{AeadCore, AeadInPlace, KeyInit, OsRng};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use parking_lot::Mutex;
+use std::fmt;
+#[cfg(any(feature = "gui", test))]
+use std::fmt::Write as _;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU32, Ordering};
+use zeroize::{Zeroize, ZeroizeOnDrop, Zeroizing};
+
+use crate::base64;
+use crate::error::TransportError;
+
+/// Bytes of a key
+pub const KEY_LEN: usize = 32;
+
+/// First byte of a sealed packet. Plain packets start with their
+/// `PACKET_TYPE_*`, all far below it.
+pub const PACKET_TYPE_SEALED: u8 = 0x80;
+
+/// Flags bit saying the rest of the packet is encrypted
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+
+/// Bytes before the ciphertext
+pub const HEADER_LEN: usize = 3 + NONCE_PREFIX_LEN + 8;
+
+/// Bytes of the Poly1305 tag after the ciphertext
+pub const TAG_LEN: usize = 16;
+
+const NONCE_PREFIX_LEN: usize = 16;
+
+/// Handshake header asking for, and confirming, encryption
+pub const HANDSHAKE_HEADER: &str = "X-Hardwave-Encryption";
+
+const CIPHER_NAME: &str = "xchacha20poly1305";
+
+/// Associated data of the message whose tag names the key
+const KEY_ID_CONTEXT: &[u8] = b"hardwave packet key id";
+
+/// Errors parsing, reading or writing a key
+#[derive(Debug)]
+pub enum KeyError {
+    /// Nothing was entered
+    Empty,
+    /// Neither hex nor base64
+    Encoding,
+    /// Well-formed, but not `KEY_LEN` bytes
+    Length(usize),
+    /// No home directory to keep the key in
+    #[cfg(feature = "gui")]
+    NoHomeDir,
+    /// The key file couldn't be read, written or removed
+    Io(io::Error),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyError::Empty => write!(f, "no key entered"),
+            KeyError::Encoding => write!(f, "key is neither hex nor base64"),
+            KeyError::Length(len) => write!(
+                f,
+                "key is {} bytes, it must be {} (64 hex digits or 44 base64 characters)",
+                len, KEY_LEN
+            ),
+            #[cfg(feature = "gui")]
+            KeyError::NoHomeDir => write!(f, "no home directory to store the key in"),
+            KeyError::Io(e) => write!(f, "key file I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            KeyError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A pre-shared key; wiped on drop and never printed
+#[derive(Clone, Zeroize, ZeroizeOnDrop)]
+pub struct PacketKey {
+    bytes: [u8; KEY_LEN],
+}
+
+impl PacketKey {
+    /// Parse 64 hex digits, or standard or URL-safe base64 with or without
+    /// padding. Surrounding whitespace is ignored.
+    pub fn parse(text: &str) -> Result<Self, KeyError> {
+        let text = text.trim();
+        if text.is_empty() {
+            return Err(KeyError::Empty);
+        }
+        let is_hex = text.len().is_multiple_of(2) && text.bytes().all(|c| c.is_ascii_hexdigit());
+        let decoded = if is_hex { hex_decode(text) } else { base64::decode(text).map(Zeroizing::new) }
+            .ok_or(KeyError::Encoding)?;
+        let bytes: [u8; KEY_LEN] = decoded.as_slice().try_into().map_err(|_| KeyError::Length(decoded.len()))?;
+        Ok(Self { bytes })
+    }
+
+    /// The key as lower-case hex, as it is saved
+    #[cfg(any(feature = "gui", test))]
+    pub fn to_hex(&self) -> Zeroizing<String> {
+        let mut hex = Zeroizing::new(String::with_capacity(2 * KEY_LEN));
+        for byte in &self.bytes {
+            let _ = write!(hex, "{:02x}", byte);
+        }
+        hex
+    }
+
+    /// The byte the header names this key by
+    pub fn id(&self) -> u8 {
+        let mut empty = [0u8; 0];
+        self.cipher()
+            .encrypt_in_place_detached(&XNonce::default(), KEY_ID_CONTEXT, &mut empty)
+            .map_or(0, |tag| tag[0])
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.bytes))
+    }
+}
+
+impl fmt::Debug for PacketKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PacketKey({:02x})", self.id())
+    }
+}
+
+fn hex_decode(text: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let digits = text.as_bytes();
+    let mut out = Zeroizing::new(Vec::with_capacity(digits.len() / 2));
+    for pair in digits.chunks(2) {
+        let pair = std::str::from_utf8(pair).ok()?;
+        out.push(u8::from_str_radix(pair, 16).ok()?);
+    }
+    Some(out)
+}
+
+/// Status of the key for the plugin window
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyStatus {
+    pub enabled: bool,
+    /// For the page to show as two hex digits, to compare with the Suite's
+    pub key_id: Option<u8>,
+}
+
+/// The key in use, shared by the plugin window, which sets it, and the
+/// connection thread, which reconnects whenever it changes
+#[derive(Debug, Default)]
+pub struct PacketEncryption {
+    key: Mutex<Option<PacketKey>>,
+    generation: AtomicU32,
+}
+
+impl PacketEncryption {
+    /// Use `key` from the next connection on; `None` turns encryption off
+    pub fn set(&self, key: Option<PacketKey>) {
+        let mut current = self.key.lock();
+        *current = key;
+        self.generation.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts the calls to `set`
+    pub fn generation(&self) -> u32 {
+        self.generation.load(Ordering::Relaxed)
+    }
+
+    #[cfg(any(feature = "gui", test))]
+    pub fn status(&self) -> KeyStatus {
+        let key_id = self.key.lock().as_ref().map(PacketKey::id);
+        KeyStatus { enabled: key_id.is_some(), key_id }
+    }
+
+    /// A sealer for a new connection with the current key
+    pub fn sealer(&self) -> PacketSealer {
+        let key = self.key.lock();
+        PacketSealer::new(key.as_ref(), self.generation())
+    }
+}
+
+/// Seals the packets of one connection, or passes them through without a
+/// key
+pub struct PacketSealer {
+    /// The cipher and the key's ID
+    key: Option<(XChaCha20Poly1305, u8)>,
+    prefix: [u8; NONCE_PREFIX_LEN],
+    next_seq: u64,
+    /// `PacketEncryption::generation` the key was taken at
+    generation: u32,
+}
+
+impl PacketSealer {
+    /// A sealer with a fresh random nonce prefix
+    pub fn new(key: Option<&PacketKey>, generation: u32) -> Self {
+        let mut prefix = [0u8; NONCE_PREFIX_LEN];
+        if key.is_some() {
+            prefix.copy_from_slice(&XChaCha20Poly1305::generate_nonce(&mut OsRng)[..NONCE_PREFIX_LEN]);
+        }
+        Self {
+            key: key.map(|key| (key.cipher(), key.id())),
+            prefix,
+            next_seq: 0,
+            generation,
+        }
+    }
+
+    /// The ID of the key, if packets are sealed
+    pub fn key_id(&self) -> Option<u8> {
+        self.key.as_ref().map(|(_, id)| *id)
+    }
+
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// What goes on the wire for the plain packet `packet`: the packet
+    /// itself without a key, else the header and the sealed packet
+    pub fn seal(&mut self, packet: Vec<u8>) -> Result<Vec<u8>, TransportError> {
+        let Some((cipher, key_id)) = &self.key else {
+            return Ok(packet);
+        };
+        let seq = self.next_seq;
+        self.next_seq += 1;
+
+        let mut sealed = Vec::with_capacity(HEADER_LEN + packet.len() + TAG_LEN);
+        sealed.extend_from_slice(&[PACKET_TYPE_SEALED, FLAG_ENCRYPTED, *key_id]);
+        sealed.extend_from_slice(&self.prefix);
+        sealed.extend_from_slice(&seq.to_le_bytes());
+        sealed.extend_from_slice(&packet);
+
+        let (header, body) = sealed.split_at_mut(HEADER_LEN);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce(&self.prefix, seq), header, body)
+            .map_err(|_| TransportError::Seal(packet.len()))?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+}
+
+fn nonce(prefix: &[u8; NONCE_PREFIX_LEN], seq: u64) -> XNonce {
+    let mut nonce = XNonce::default();
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&seq.to_le_bytes());
+    nonce
+}
+
+/// Why a sealed packet was turned down
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpenError {
+    /// Too short, or not marked as sealed and encrypted
+    NotSealed,
+    /// Sealed with another key
+    UnknownKey(u8),
+    /// The tag doesn't match: altered, truncated or the wrong key
+    Tampered,
+}
+
+/// The Suite's side: check and decrypt a sealed packet, returning its
+/// sequence number and the plain packet
+#[cfg(test)]
+pub fn open(key: &PacketKey, sealed: &[u8]) -> Result<(u64, Vec<u8>), OpenError> {
+    if sealed.len() < HEADER_LEN + TAG_LEN || sealed[0] != PACKET_TYPE_SEALED || sealed[1] & FLAG_ENCRYPTED == 0 {
+        return Err(OpenError::NotSealed);
+    }
+    if sealed[2] != key.id() {
+        return Err(OpenError::UnknownKey(sealed[2]));
+    }
+    let (header, rest) = sealed.split_at(HEADER_LEN);
+    let (body, tag) = rest.split_at(rest.len() - TAG_LEN);
+    let prefix: [u8; NONCE_PREFIX_LEN] = header[3..3 + NONCE_PREFIX_LEN].try_into().unwrap();
+    let seq = u64::from_le_bytes(header[3 + NONCE_PREFIX_LEN..].try_into().unwrap());
+
+    let mut packet = body.to_vec();
+    key.cipher()
+        .decrypt_in_place_detached(&nonce(&prefix, seq), header, &mut packet, chacha20poly1305::Tag::from_slice(tag))
+        .map_err(|_| OpenError::Tampered)?;
+    Ok((seq, packet))
+}
+
+/// The handshake header line asking for encryption with the key `key_id`
+pub fn request_header(key_id: u8) -> String {
+    format!("{}: {}; key-id={:02x}\r\n", HANDSHAKE_HEADER, CIPHER_NAME, key_id)
+}
+
+/// Check that the handshake response `response` agreed to encrypt with the
+/// key `key_id`
+pub fn check_response(response: &str, key_id: u8) -> Result<(), &'static str> {
+    let name = HANDSHAKE_HEADER.to_ascii_lowercase();
+    let value = response.lines().find_map(|line| {
+        let (field, value) = line.split_once(':')?;
+        (field.trim().to_ascii_lowercase() == name).then(|| value.trim().to_ascii_lowercase())
+    });
+    let Some(value) = value else {
+        return Err("the Suite doesn't support packet encryption");
+    };
+    let mut parts = value.split(';').map(str::trim);
+    if parts.next() != Some(CIPHER_NAME) {
+        return Err("the Suite offered another cipher");
+    }
+    let agreed = parts
+        .filter_map(|part| part.strip_prefix("key-id="))
+        .any(|id| u8::from_str_radix(id, 16) == Ok(key_id));
+    if !agreed {
+        return Err("the Suite has a different key");
+    }
+    Ok(())
+}
+
+/// Path to the key file
+fn key_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".hardwave").join("packet-key"))
+}
+
+/// Load the saved key. No file means no key; a file that can't be read or
+/// doesn't hold a valid key is an error.
+pub fn load_key() -> Result<Option<PacketKey>, KeyError> {
+    key_path().map_or(Ok(None), |p| load_from(&p))
+}
+
+/// Save `key`, readable by the user only where that can be said
+#[cfg(feature = "gui")]
+pub fn save_key(key: &PacketKey) -> Result<PathBuf, KeyError> {
+    let path = key_path().ok_or(KeyError::NoHomeDir)?;
+    save_to(&path, key)?;
+    Ok(path)
+}
+
+/// Remove the saved key
+#[cfg(feature = "gui")]
+pub fn delete_key() -> Result<(), KeyError> {
+    let path = key_path().ok_or(KeyError::NoHomeDir)?;
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(KeyError::Io(e)),
+        _ => Ok(()),
+    }
+}
+
+fn load_from(path: &Path) -> Result<Option<PacketKey>, KeyError> {
+    match fs::read_to_string(path) {
+        Ok(text) => PacketKey::parse(&Zeroizing::new(text)).map(Some),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(KeyError::Io(e)),
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+fn save_to(path: &Path, key: &PacketKey) -> Result<(), KeyError> {
+    use std::io::Write;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(KeyError::Io)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(KeyError::Io)?;
+    file.write_all(key.to_hex().as_bytes()).map_err(KeyError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::AudioPacket;
+    use std::collections::HashSet;
+
+    const HEX: &str = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f";
+
+    fn key() -> PacketKey {
+        PacketKey::parse(HEX).unwrap()
+    }
+
+    #[test]
+    fn test_key_parsing() {
+        let bytes: [u8; KEY_LEN] = std::array::from_fn(|i| i as u8);
+        assert_eq!(key().bytes, bytes);
+        assert_eq!(*key().to_hex(), HEX);
+        assert_eq!(PacketKey::parse(&format!("  {}\n", HEX.to_uppercase())).unwrap().bytes, bytes);
+        // The same key in standard and URL-safe base64, padded or not
+        for text in [
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8=",
+            "AAECAwQFBgcICQoLDA0ODxAREhMUFRYXGBkaGxwdHh8",
+        ] {
+            assert_eq!(PacketKey::parse(text).unwrap().bytes, bytes);
+        }
+        let spread: [u8; KEY_LEN] = std::array::from_fn(|i| (i * 8 + 3) as u8);
+        assert_eq!(PacketKey::parse("AwsTGyMrMztDS1NbY2tze4OLk5ujq7O7w8vT2+Pr8/s=").unwrap().bytes, spread);
+        assert_eq!(PacketKey::parse("AwsTGyMrMztDS1NbY2tze4OLk5ujq7O7w8vT2-Pr8_s").unwrap().bytes, spread);
+
+        assert!(matches!(PacketKey::parse(" \t"), Err(KeyError::Empty)));
+        assert!(matches!(PacketKey::parse("not a key!"), Err(KeyError::Encoding)));
+        assert!(matches!(PacketKey::parse(&HEX[..62]), Err(KeyError::Length(31))));
+        assert!(matches!(PacketKey::parse("c2hvcnQ="), Err(KeyError::Length(5))));
+        assert_eq!(
+            KeyError::Length(31).to_string(),
+            "key is 31 bytes, it must be 32 (64 hex digits or 44 base64 characters)"
+        );
+        // The ID names the key without printing it
+        assert_eq!(format!("{:?}", key()), format!("PacketKey({:02x})", key().id()));
+        assert_ne!(key().id(), PacketKey { bytes: [7; KEY_LEN] }.id());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let packet = AudioPacket::fft(48000, 1234).with_bins(vec![-20.0; 8], vec![-30.0; 8]).to_bytes();
+        let mut sealer = PacketSealer::new(Some(&key()), 0);
+        let sealed = sealer.seal(packet.clone()).unwrap();
+        assert_eq!(sealed.len(), HEADER_LEN + packet.len() + TAG_LEN);
+        assert_eq!(&sealed[..3], &[PACKET_TYPE_SEALED, FLAG_ENCRYPTED, key().id()]);
+        assert!(!sealed.windows(8).any(|w| w == &packet[..8]), "the plain packet shows through");
+
+        let (seq, opened) = open(&key(), &sealed).unwrap();
+        assert_eq!(seq, 0);
+        assert_eq!(opened, packet);
+        assert_eq!(AudioPacket::from_bytes(&opened).unwrap().timestamp_ms, 1234);
+        assert_eq!(open(&key(), &sealer.seal(packet.clone()).unwrap()).unwrap().0, 1);
+
+        // Without a key the packet goes out as it is
+        assert_eq!(PacketSealer::new(None, 0).seal(packet.clone()).unwrap(), packet);
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let packet = AudioPacket::new_heartbeat(48000, 99).to_bytes();
+        let sealed = PacketSealer::new(Some(&key()), 0).seal(packet.clone()).unwrap();
+
+        // Every bit after the key ID is covered: header, body and tag
+        for byte in 3..sealed.len() {
+            let mut altered = sealed.clone();
+            altered[byte] ^= 0x10;
+            assert_eq!(open(&key(), &altered), Err(OpenError::Tampered), "byte {}", byte);
+        }
+        assert_eq!(open(&key(), &sealed[..sealed.len() - 1]), Err(OpenError::Tampered));
+        assert_eq!(open(&key(), &sealed[..HEADER_LEN]), Err(OpenError::NotSealed));
+        assert_eq!(open(&key(), &packet), Err(OpenError::NotSealed));
+
+        let other = PacketKey { bytes: [0xaa; KEY_LEN] };
+        assert_eq!(open(&other, &sealed), Err(OpenError::UnknownKey(key().id())));
+        // Relabelled to the other key's ID, it still doesn't open
+        let mut relabelled = sealed.clone();
+        relabelled[2] = other.id();
+        assert_eq!(open(&other, &relabelled), Err(OpenError::Tampered));
+    }
+
+    #[test]
+    fn test_nonces_are_never_reused() {
+        let mut nonces = HashSet::new();
+        for _ in 0..4 {
+            // Each connection draws its own prefix and counts from zero
+            let mut sealer = PacketSealer::new(Some(&key()), 0);
+            for expected in 0..1000u64 {
+                let sealed = sealer.seal(b"x".to_vec()).unwrap();
+                let seq = u64::from_le_bytes(sealed[19..27].try_into().unwrap());
+                assert_eq!(seq, expected);
+                assert!(nonces.insert(sealed[3..HEADER_LEN].to_vec()), "nonce repeated at {}", seq);
+            }
+        }
+        assert_eq!(nonces.len(), 4000);
+    }
+
+    #[test]
+    fn test_negotiation() {
+        let id = key().id();
+        let header = request_header(id);
+        assert_eq!(header, format!("X-Hardwave-Encryption: xchacha20poly1305; key-id={:02x}\r\n", id));
+
+        let agreed = format!("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n{}\r\n", header);
+        assert_eq!(check_response(&agreed, id), Ok(()));
+        assert_eq!(check_response(&agreed.to_lowercase(), id), Ok(()));
+        assert_eq!(
+            check_response("HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n\r\n", id),
+            Err("the Suite doesn't support packet encryption")
+        );
+        assert_eq!(check_response(&agreed, id.wrapping_add(1)), Err("the Suite has a different key"));
+        assert_eq!(
+            check_response("X-Hardwave-Encryption: aes-gcm; key-id=00\r\n", 0),
+            Err("the Suite offered another cipher")
+        );
+    }
+
+    #[test]
+    fn test_setting_a_key_starts_a_new_generation() {
+        let encryption = PacketEncryption::default();
+        assert_eq!(encryption.sealer().key_id(), None);
+        assert_eq!(encryption.status(), KeyStatus { enabled: false, key_id: None });
+
+        encryption.set(Some(key()));
+        let sealer = encryption.sealer();
+        assert_eq!((sealer.key_id(), sealer.generation()), (Some(key().id()), 1));
+        assert_eq!(encryption.status(), KeyStatus { enabled: true, key_id: Some(key().id()) });
+
+        encryption.set(None);
+        assert_eq!(encryption.generation(), 2);
+        assert_eq!(encryption.sealer().key_id(), None);
+    }
+
+    #[test]
+    fn test_key_file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("hardwave-packet-key-{}", std::process::id()));
+        let path = dir.join("packet-key");
+        assert!(load_from(&path).unwrap().is_none());
+
+        save_to(&path, &key()).unwrap();
+        assert_eq!(load_from(&path).unwrap().unwrap().bytes, key().bytes);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+
+        fs::write(&path, "not a key").unwrap();
+        assert!(matches!(load_from(&path), Err(KeyError::Encoding)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
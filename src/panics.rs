@@ -0,0 +1,318 @@
+//! Keeping panics out of the host
+//!
+//! The hot paths are written not to panic, but the code around them keeps
+//! growing (JSON for the editor, PNG rendering, packet construction), and a
+//! panic that unwinds out of `process` or the editor's IPC handler crosses
+//! the FFI boundary into the host, which takes the whole DAW down. A panic
+//! in one of the plugin's own threads is quieter and not much better: the
+//! thread is gone, and a dead drainer only shows as meters that stopped.
+//!
+//! - `process` runs the analysis of each block in `contain`. After a panic
+//!   the analysis starts over, and the audio passes through as always.
+//! - The IPC handler and each packet server request run in `contain` too.
+//!   A panic answers nothing (or a 500) and is counted in `ErrorStats`.
+//! - The connection thread, the packet drainer and both config threads
+//!   start through `threads::spawn_restarting`, which runs their loop again
+//!   after a panic, at most `MAX_RESTARTS` times.
+//!
+//! Every panic is counted per region, for the whole process; the editor's
+//! `errors` command and `/errors` on the packet server include the counts.
+//! A region logs at most one line per `LOG_INTERVAL`, so a panic on every
+//! block doesn't flood the log, or the disk from the audio thread.
+//!
+//! None of this works with `panic = "abort"`, which turns every panic into
+//! a host crash. The release profile keeps the default, unwinding, and a
+//! build with abort fails to compile rather than ship.
+
+#[cfg(any(feature = "gui", test))]
+use serde::Serialize;
+use std::any::Any;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crate::latency;
+use crate::runtime;
+
+#[cfg(panic = "abort")]
+compile_error!("hardwave-analyser must unwind on panic; see src/panics.rs");
+
+/// Times a thread's loop is run again after a panic before it is left dead
+pub const MAX_RESTARTS: u32 = 5;
+
+/// Pause before each restart, so a loop that panics at once doesn't spin
+pub const RESTART_DELAY: Duration = Duration::from_millis(100);
+
+/// Shortest time between two log lines about one region
+pub const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Code a panic is contained in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    /// The analysis of a block, on the audio thread
+    Process,
+    /// The plugin window's IPC handler
+    Ipc,
+    /// A request to the plugin window's packet server
+    PacketServer,
+    /// The connection thread's loop
+    Connection,
+    /// The packet server's drainer
+    Drainer,
+    /// The worker that builds analysis states
+    ConfigBuilder,
+    /// The thread that reloads the machine config
+    ConfigWatcher,
+    /// The packet bus's thread, handing frames to consumers
+    Bus,
+}
+
+impl Region {
+    pub const ALL: [Region; 8] = [
+        Region::Process,
+        Region::Ipc,
+        Region::PacketServer,
+        Region::Connection,
+        Region::Drainer,
+        Region::ConfigBuilder,
+        Region::ConfigWatcher,
+        Region::Bus,
+    ];
+
+    /// Name in logs and reports
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Process => "process",
+            Region::Ipc => "ipc",
+            Region::PacketServer => "packet_server",
+            Region::Connection => "connection",
+            Region::Drainer => "drainer",
+            Region::ConfigBuilder => "config_builder",
+            Region::ConfigWatcher => "config_watcher",
+            Region::Bus => "bus",
+        }
+    }
+}
+
+/// A panic `contain` caught
+#[derive(Debug)]
+pub struct Panicked {
+    pub region: Region,
+    pub message: String,
+}
+
+impl fmt::Display for Panicked {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} panicked: {}", self.region.name(), self.message)
+    }
+}
+
+impl std::error::Error for Panicked {}
+
+struct Counters {
+    panics: AtomicU32,
+    restarts: AtomicU32,
+    /// `latency::now_us` of the last log line, 0 before the first
+    logged_at_us: AtomicU64,
+    /// Panics since the last log line that didn't get one
+    unlogged: AtomicU32,
+}
+
+static COUNTERS: [Counters; Region::ALL.len()] = [const {
+    Counters {
+        panics: AtomicU32::new(0),
+        restarts: AtomicU32::new(0),
+        logged_at_us: AtomicU64::new(0),
+        unlogged: AtomicU32::new(0),
+    }
+}; Region::ALL.len()];
+
+/// Run `f`, catching a panic in it. The panic is counted for `region` and
+/// logged, at most once per `LOG_INTERVAL`.
+pub fn contain<T>(region: Region, f: impl FnOnce() -> T) -> Result<T, Panicked> {
+    panic::catch_unwind(AssertUnwindSafe(|| {
+        checkpoint(region);
+        f()
+    }))
+    .map_err(|payload| {
+        let message = payload_message(&*payload);
+        count(region, &message);
+        Panicked { region, message }
+    })
+}
+
+/// Run a thread's loop, and run it again after each panic, at most
+/// `MAX_RESTARTS` times. Returns when `body` does, or when it has panicked
+/// once more than that.
+pub fn run_restarting(region: Region, mut body: impl FnMut()) {
+    for restart in 0..=MAX_RESTARTS {
+        if restart > 0 {
+            COUNTERS[region as usize].restarts.fetch_add(1, Ordering::Relaxed);
+            thread::sleep(RESTART_DELAY);
+        }
+        if contain(region, &mut body).is_ok() {
+            return;
+        }
+    }
+    runtime::debug_log(&format!(
+        "[panics] {} gave up after {} restarts",
+        region.name(),
+        MAX_RESTARTS
+    ));
+}
+
+/// Called once per iteration of a guarded loop. Does nothing, except in
+/// tests, where it is where injected panics go off.
+#[inline]
+pub fn checkpoint(_region: Region) {
+    #[cfg(test)]
+    hooks::fire(_region);
+}
+
+/// Panics and restarts of one region
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PanicCount {
+    pub region: &'static str,
+    pub panics: u32,
+    pub restarts: u32,
+}
+
+/// Every region that has panicked since the process started
+#[cfg(any(feature = "gui", test))]
+pub fn report() -> Vec<PanicCount> {
+    Region::ALL
+        .into_iter()
+        .map(|region| PanicCount {
+            region: region.name(),
+            panics: panics(region),
+            restarts: restarts(region),
+        })
+        .filter(|count| count.panics > 0)
+        .collect()
+}
+
+#[cfg(any(feature = "gui", test))]
+pub fn panics(region: Region) -> u32 {
+    COUNTERS[region as usize].panics.load(Ordering::Relaxed)
+}
+
+#[cfg(any(feature = "gui", test))]
+pub fn restarts(region: Region) -> u32 {
+    COUNTERS[region as usize].restarts.load(Ordering::Relaxed)
+}
+
+fn count(region: Region, message: &str) {
+    let counters = &COUNTERS[region as usize];
+    counters.panics.fetch_add(1, Ordering::Relaxed);
+
+    let now = latency::now_us().max(1);
+    let last = counters.logged_at_us.load(Ordering::Relaxed);
+    let due = last == 0 || now.saturating_sub(last) >= LOG_INTERVAL.as_micros() as u64;
+    if !due || counters.logged_at_us.compare_exchange(last, now, Ordering::Relaxed, Ordering::Relaxed).is_err() {
+        counters.unlogged.fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    let unlogged = counters.unlogged.swap(0, Ordering::Relaxed);
+    let more = if unlogged > 0 { format!(" ({} more since the last line)", unlogged) } else { String::new() };
+    runtime::debug_log(&format!("[panics] {} panicked: {}{}", region.name(), message, more));
+}
+
+/// What `panic!` was given, if it was a string
+fn payload_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "non-string payload".to_string()
+    }
+}
+
+/// Panics on demand, for the tests of each guarded region
+#[cfg(test)]
+pub mod hooks {
+    use super::Region;
+    use std::sync::Mutex;
+    use std::thread::{self, ThreadId};
+
+    static INJECTED: Mutex<Vec<(Region, Option<ThreadId>)>> = Mutex::new(Vec::new());
+
+    /// Make the next checkpoint of `region` panic, on `thread` or on
+    /// whichever thread gets there first
+    pub fn inject(region: Region, thread: Option<ThreadId>) {
+        INJECTED.lock().unwrap().push((region, thread));
+    }
+
+    pub(super) fn fire(region: Region) {
+        let current = thread::current().id();
+        let mut injected = INJECTED.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let Some(index) = injected
+            .iter()
+            .position(|&(r, thread)| r == region && thread.is_none_or(|thread| thread == current))
+        else {
+            return;
+        };
+        injected.remove(index);
+        drop(injected);
+        panic!("injected panic in {}", region.name());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::Arc;
+    use std::time::Instant;
+
+    #[test]
+    fn test_contain_catches_and_counts() {
+        assert_eq!(contain(Region::Process, || 7).unwrap(), 7);
+
+        let before = panics(Region::Process);
+        let panicked = contain(Region::Process, || -> u32 { panic!("bad bin {}", 3) }).unwrap_err();
+        assert_eq!(panicked.to_string(), "process panicked: bad bin 3");
+        assert!(panics(Region::Process) > before);
+        assert!(report().iter().any(|count| count.region == "process" && count.panics > 0));
+    }
+
+    #[test]
+    fn test_loop_restarts_until_it_returns() {
+        // The checkpoint goes off twice, then the loop finishes
+        let done = Arc::new(AtomicBool::new(false));
+        let before = restarts(Region::ConfigWatcher);
+        let (go, armed) = std::sync::mpsc::channel::<()>();
+        let handle = {
+            let done = Arc::clone(&done);
+            thread::spawn(move || {
+                let _ = armed.recv();
+                run_restarting(Region::ConfigWatcher, || {
+                    checkpoint(Region::ConfigWatcher);
+                    done.store(true, Ordering::Relaxed);
+                })
+            })
+        };
+        let id = Some(handle.thread().id());
+        hooks::inject(Region::ConfigWatcher, id);
+        hooks::inject(Region::ConfigWatcher, id);
+        go.send(()).unwrap();
+        handle.join().unwrap();
+        assert!(done.load(Ordering::Relaxed));
+        assert!(restarts(Region::ConfigWatcher) >= before + 2);
+    }
+
+    #[test]
+    fn test_loop_that_always_panics_gives_up() {
+        let started = Instant::now();
+        let mut runs = 0;
+        run_restarting(Region::Drainer, || {
+            runs += 1;
+            panic!("always");
+        });
+        assert_eq!(runs, MAX_RESTARTS + 1);
+        assert!(started.elapsed() >= RESTART_DELAY * MAX_RESTARTS);
+    }
+}
@@ -1,27 +1,378 @@
 //! Plugin parameters for Hardwave Analyser
+//!
+//! Generic UIs show them in three sections: Network, Analysis and Display.
+//! Every number has a unit and every choice formats and parses by name.
+//! Hosts may automate everything except:
+//!
+//! - `port`: changing it reconnects
+//! - `timing_packets`: a diagnostic, also hidden from generic UIs
+//! - `raw_spectrum`, `raw_spectrum_rate`, `waveform_packets`,
+//!   `compact_levels`: what the Suite is sent, set up for the session
+//! - `full_bandwidth`, `normalized_rate`, `zero_pad`, `fft_window`,
+//!   `reference_analysis`: each change rebuilds the analysis state off the audio thread and drops the
+//!   frame in progress
+//! - `loop_policy`, `delay_check`: choices for the session, not for a passage
 
 use nih_plug::prelude::*;
-use std::sync::Arc;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU8, Ordering};
+use std::sync::{Arc, RwLock};
 
-/// Plugin parameters
+use crate::bands;
+use crate::destinations::{self, Destination};
+use crate::display::{self, DisplayHints};
+use crate::engine::EngineSettings;
+use crate::ports;
+use crate::raw_spectrum;
+use crate::slots::AnalysisSlots;
+use crate::state;
+use crate::track_info::TrackInfo;
+
+/// Maximum length of a user-supplied label, in characters
+#[cfg(any(feature = "gui", test))]
+pub const MAX_LABEL_LEN: usize = 32;
+
+/// How the two analysis streams are derived from the input
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChannelMode {
+    #[id = "left_right"]
+    #[name = "Left/Right"]
+    LeftRight,
+
+    #[id = "mid_side"]
+    #[name = "Mid/Side"]
+    MidSide,
+}
+
+impl ChannelMode {
+    /// Labels used for the two streams until the user renames them
+    #[cfg(any(feature = "gui", test))]
+    pub fn default_labels(self) -> [&'static str; 2] {
+        match self {
+            ChannelMode::LeftRight => ["Left", "Right"],
+            ChannelMode::MidSide => ["Mid", "Side"],
+        }
+    }
+}
+
+/// RMS meter integration
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RmsIntegration {
+    #[id = "instant"]
+    #[name = "Instant"]
+    Instant,
+
+    #[id = "vu"]
+    #[name = "VU (300 ms)"]
+    Vu,
+
+    #[id = "ppm"]
+    #[name = "PPM"]
+    Ppm,
+}
+
+impl RmsIntegration {
+    /// (attack, release) time constants in seconds
+    pub fn time_constants(self) -> (f32, f32) {
+        match self {
+            RmsIntegration::Instant => (0.0, 0.0),
+            RmsIntegration::Vu => (0.3, 0.3),
+            RmsIntegration::Ppm => (0.01, 1.5),
+        }
+    }
+}
+
+/// Where the analysed window sits relative to each send interval
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AnalysisAlignment {
+    /// Window ends at the send; later content in the interval dominates
+    #[id = "window_ends_at_send"]
+    #[name = "Window Ends at Send"]
+    WindowEndsAtSend,
+
+    /// Window centred on the interval; frames describe slightly older audio
+    #[id = "window_centred"]
+    #[name = "Centred on Interval"]
+    WindowCentredOnInterval,
+
+    /// Power average of an FFT every hop, close to offline STFT averaging
+    #[id = "average_of_interval"]
+    #[name = "Average of Interval"]
+    AverageOfInterval,
+}
+
+impl AnalysisAlignment {
+    /// Value of the packet's `analysis_alignment` field
+    pub fn wire_id(self) -> u8 {
+        match self {
+            AnalysisAlignment::WindowEndsAtSend => 0,
+            AnalysisAlignment::WindowCentredOnInterval => 1,
+            AnalysisAlignment::AverageOfInterval => 2,
+        }
+    }
+}
+
+/// How far the FFT input is zero-padded past the window
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ZeroPad {
+    #[id = "off"]
+    #[name = "Off"]
+    Off,
+
+    #[id = "x2"]
+    #[name = "2x"]
+    X2,
+
+    #[id = "x4"]
+    #[name = "4x"]
+    X4,
+}
+
+impl ZeroPad {
+    /// Largest `factor` of any setting
+    pub const MAX_FACTOR: usize = 4;
+
+    /// FFT length over window length
+    pub fn factor(self) -> usize {
+        match self {
+            ZeroPad::Off => 1,
+            ZeroPad::X2 => 2,
+            ZeroPad::X4 => 4,
+        }
+    }
+}
+
+/// The window each FFT multiplies its input by
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WindowType {
+    /// A good all-rounder, and what the analysis always used
+    #[id = "hann"]
+    #[name = "Hann"]
+    Hann,
+
+    /// Low leakage, for tones close together in level or frequency
+    #[id = "blackman_harris"]
+    #[name = "Blackman-Harris"]
+    BlackmanHarris,
+
+    /// Wide peaks with an accurate amplitude, for calibration
+    #[id = "flat_top"]
+    #[name = "Flat Top"]
+    FlatTop,
+
+    #[id = "kaiser"]
+    #[name = "Kaiser"]
+    Kaiser,
+}
+
+/// Frequency weighting of the analysed signal, see `weighting`
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weighting {
+    /// Flat, the signal as it is
+    #[id = "z"]
+    #[name = "Z (Flat)"]
+    Z,
+
+    /// IEC 61672 A, roughly the ear's at low levels
+    #[id = "a"]
+    #[name = "A"]
+    A,
+
+    /// IEC 61672 C, for loud material
+    #[id = "c"]
+    #[name = "C"]
+    C,
+
+    /// ITU-R BS.1770 K, what loudness is measured with
+    #[id = "k"]
+    #[name = "K"]
+    K,
+}
+
+/// How the coarse bands divide the spectrum
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CoarseMapping {
+    /// `coarse_bands` bands of equal width on a log scale, 20 Hz to 20 kHz
+    #[id = "equal_log_width"]
+    #[name = "Equal Log Width"]
+    EqualLogWidth,
+
+    /// Sub, bass, low-mid, mid, high-mid, presence and air; ignores the count
+    #[id = "perceptual"]
+    #[name = "Perceptual"]
+    Perceptual,
+}
+
+/// What the Suite's integrated measurements (LUFS-I, session max) do while
+/// the host loops a section, see `transport_loop`
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LoopPolicy {
+    /// Keep accumulating every pass, as if there were no loop
+    #[id = "continue"]
+    #[name = "Continue"]
+    Continue,
+
+    /// Start over on every pass, so they describe one pass
+    #[id = "reset"]
+    #[name = "Reset on Loop"]
+    Reset,
+
+    /// Hold while looping and carry on once the loop ends
+    #[id = "pause"]
+    #[name = "Pause While Looping"]
+    Pause,
+}
+
+impl LoopPolicy {
+    /// Value of the packet's `loop_policy` field
+    pub fn wire_id(self) -> u8 {
+        match self {
+            LoopPolicy::Continue => 0,
+            LoopPolicy::Reset => 1,
+            LoopPolicy::Pause => 2,
+        }
+    }
+}
+
+/// Which of the two analysis configurations is live, see `slots`
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AnalysisSlot {
+    #[id = "a"]
+    #[name = "A"]
+    A,
+
+    #[id = "b"]
+    #[name = "B"]
+    B,
+}
+
+impl AnalysisSlot {
+    /// Value of the packet's `analysis_slot` field
+    pub fn wire_id(self) -> u8 {
+        match self {
+            AnalysisSlot::A => 0,
+            AnalysisSlot::B => 1,
+        }
+    }
+
+    /// `A` or `B`, either case
+    #[cfg(any(feature = "gui", test))]
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.trim() {
+            "A" | "a" => Some(AnalysisSlot::A),
+            "B" | "b" => Some(AnalysisSlot::B),
+            _ => None,
+        }
+    }
+}
+
+/// User-chosen names for the two analysis streams. While `custom` is `None`
+/// the labels follow the channel mode.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ChannelLabels {
+    pub custom: Option<[String; 2]>,
+}
+
+impl ChannelLabels {
+    /// Labels to display for the given mode
+    #[cfg(any(feature = "gui", test))]
+    pub fn effective(&self, mode: ChannelMode) -> [String; 2] {
+        match &self.custom {
+            Some(labels) => labels.clone(),
+            None => mode.default_labels().map(str::to_string),
+        }
+    }
+
+    #[cfg(test)]
+    pub fn is_customized(&self) -> bool {
+        self.custom.is_some()
+    }
+
+    /// Set custom labels. Labels that are empty after sanitizing fall back to
+    /// the mode default; two empty labels clear the customization.
+    #[cfg(any(feature = "gui", test))]
+    pub fn set(&mut self, first: &str, second: &str, mode: ChannelMode) {
+        let first = sanitize_label(first);
+        let second = sanitize_label(second);
+        if first.is_empty() && second.is_empty() {
+            self.custom = None;
+            return;
+        }
+        let [default_first, default_second] = mode.default_labels();
+        self.custom = Some([
+            if first.is_empty() { default_first.to_string() } else { first },
+            if second.is_empty() { default_second.to_string() } else { second },
+        ]);
+    }
+}
+
+/// Strip control characters and surrounding whitespace, and cap the length.
+#[cfg(any(feature = "gui", test))]
+pub fn sanitize_label(label: &str) -> String {
+    label
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect::<String>()
+        .trim()
+        .chars()
+        .take(MAX_LABEL_LEN)
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+/// Streaming to the Suite
 #[derive(Params)]
-pub struct HardwaveAnalyserParams {
+pub struct NetworkParams {
     /// Enable/disable streaming
     #[id = "enabled"]
     pub enabled: BoolParam,
 
-    /// WebSocket server port
+    /// WebSocket server port. Changing it reconnects, so it isn't automatable.
     #[id = "port"]
     pub port: IntParam,
+
+    /// Send a latency report to the Suite every 10 s; a diagnostic, so not
+    /// in generic UIs
+    #[id = "timing_packets"]
+    pub timing_packets: BoolParam,
+
+    /// Also send every bin, quantised to bytes, for the Suite's spectrogram
+    /// (see `raw_spectrum`); off by default to keep the bandwidth down
+    #[id = "raw_spectrum"]
+    pub raw_spectrum: BoolParam,
+
+    /// Raw spectrum packets per second, at most one per frame
+    #[id = "raw_spectrum_rate"]
+    pub raw_spectrum_rate: IntParam,
+
+    /// Follow every frame with the min/max envelope of the input since the
+    /// last, for a scrolling oscilloscope (see `envelope`)
+    #[id = "waveform_packets"]
+    pub waveform_packets: BoolParam,
+
+    /// Send the levels of FFT packets in 0.25 dB steps rather than as
+    /// floats, for about 40% less bandwidth (see `protocol`); off by default
+    /// for Suites from before it
+    #[id = "compact_levels"]
+    pub compact_levels: BoolParam,
 }
 
-impl Default for HardwaveAnalyserParams {
+impl Default for NetworkParams {
     fn default() -> Self {
         Self {
             enabled: BoolParam::new("Enabled", true),
             port: IntParam::new(
                 "Port",
-                9847,
+                i32::from(ports::DEFAULT_PORT),
                 IntRange::Linear {
                     min: 1024,
                     max: 65535,
@@ -29,7 +380,611 @@ impl Default for HardwaveAnalyserParams {
             )
             .with_unit(" ")
             .with_value_to_string(Arc::new(|value| format!("{}", value)))
-            .with_string_to_value(Arc::new(|string: &str| string.parse().ok())),
+            .with_string_to_value(Arc::new(|string: &str| string.trim().parse().ok()))
+            .non_automatable(),
+            timing_packets: BoolParam::new("Timing Packets", false)
+                .non_automatable()
+                .hide_in_generic_ui(),
+            raw_spectrum: BoolParam::new("Raw Spectrum Packets", false).non_automatable(),
+            raw_spectrum_rate: IntParam::new(
+                "Raw Spectrum Rate",
+                raw_spectrum::DEFAULT_RATE_HZ,
+                IntRange::Linear {
+                    min: 1,
+                    max: raw_spectrum::MAX_RATE_HZ,
+                },
+            )
+            .with_unit(" Hz")
+            .non_automatable(),
+            waveform_packets: BoolParam::new("Waveform Packets", true).non_automatable(),
+            compact_levels: BoolParam::new("Compact Levels", false).non_automatable(),
+        }
+    }
+}
+
+/// How the audio is analysed. The bandwidth, rate, padding, window and
+/// reference rebuild the analysis state, so they aren't automatable; eco mode and the slot are,
+/// and swap a prepared state in.
+#[derive(Params)]
+pub struct AnalysisParams {
+    /// Analyse up to Nyquist instead of decimating high sample rates to 48 kHz
+    #[id = "full_bandwidth"]
+    pub full_bandwidth: BoolParam,
+
+    /// Resample the analysis feed to 48 kHz so bins and bands read the same
+    /// at every project rate; overrides full bandwidth
+    #[id = "normalized_rate"]
+    pub normalized_rate: BoolParam,
+
+    /// Analyse the sidechain too and send its spectrum after every frame,
+    /// see `reference`; twice the FFT work, so off by default. Applies to
+    /// both slots
+    #[id = "reference_analysis"]
+    pub reference_analysis: BoolParam,
+
+    /// Cut the analysis down for low-power machines: half the analysis rate,
+    /// a 2048-point FFT and editor updates at 30 fps. Applies to both slots;
+    /// overrides full bandwidth and the normalised rate
+    #[id = "eco_mode"]
+    pub eco_mode: BoolParam,
+
+    /// Interpolate the spectrum by transforming a zero-padded window
+    #[id = "zero_pad"]
+    pub zero_pad: EnumParam<ZeroPad>,
+
+    /// The FFT's window function, and its coherent gain correction
+    #[id = "fft_window"]
+    pub fft_window: EnumParam<WindowType>,
+
+    /// Left/right or mid/side analysis
+    #[id = "channel_mode"]
+    pub channel_mode: EnumParam<ChannelMode>,
+
+    /// Frequency weighting of the spectrum and levels; the audio passes
+    /// through unweighted
+    #[id = "weighting"]
+    pub weighting: EnumParam<Weighting>,
+
+    /// Which audio each frame's spectrum describes
+    #[id = "analysis_alignment"]
+    pub analysis_alignment: EnumParam<AnalysisAlignment>,
+
+    /// Which analysis slot the analysis parameters edit and the engine uses
+    #[id = "active_slot"]
+    pub active_slot: EnumParam<AnalysisSlot>,
+
+    /// What integrated measurements do when the host loops a section; a
+    /// set-up choice, not automatable
+    #[id = "loop_policy"]
+    pub loop_policy: EnumParam<LoopPolicy>,
+
+    /// Warn when one channel is a delayed copy of the other; an extra
+    /// correlation pass, so off by default
+    #[id = "delay_check"]
+    pub delay_check: BoolParam,
+}
+
+impl Default for AnalysisParams {
+    fn default() -> Self {
+        Self {
+            full_bandwidth: BoolParam::new("Full Bandwidth", false).non_automatable(),
+            normalized_rate: BoolParam::new("Normalized Analysis Rate", false).non_automatable(),
+            reference_analysis: BoolParam::new("Reference Analysis", false).non_automatable(),
+            eco_mode: BoolParam::new("Eco Mode", false),
+            zero_pad: EnumParam::new("Zero Padding", ZeroPad::Off).non_automatable(),
+            fft_window: EnumParam::new("FFT Window", WindowType::Hann).non_automatable(),
+            channel_mode: EnumParam::new("Channel Mode", ChannelMode::LeftRight),
+            weighting: EnumParam::new("Weighting", Weighting::Z),
+            analysis_alignment: EnumParam::new("Analysis Alignment", AnalysisAlignment::WindowEndsAtSend),
+            active_slot: EnumParam::new("Active Slot", AnalysisSlot::A),
+            loop_policy: EnumParam::new("Integrated on Loop", LoopPolicy::Continue).non_automatable(),
+            delay_check: BoolParam::new("Delayed Copy Check", false).non_automatable(),
+        }
+    }
+}
+
+/// Meter ballistics and the coarse bands, how the analysis is shown
+#[derive(Params)]
+pub struct DisplayParams {
+    /// How long the peak meter holds its maximum
+    #[id = "peak_hold"]
+    pub peak_hold: FloatParam,
+
+    /// Peak meter fall rate after the hold expires
+    #[id = "peak_release"]
+    pub peak_release: FloatParam,
+
+    /// RMS meter integration time
+    #[id = "rms_integration"]
+    pub rms_integration: EnumParam<RmsIntegration>,
+
+    /// Number of coarse bands for hardware visualisers
+    #[id = "coarse_bands"]
+    pub coarse_bands: IntParam,
+
+    /// How the coarse bands are laid out
+    #[id = "coarse_mapping"]
+    pub coarse_mapping: EnumParam<CoarseMapping>,
+
+    /// Send how much each coarse band is moving
+    #[id = "band_activity"]
+    pub band_activity: BoolParam,
+
+    /// How fast a rising coarse band follows; 0 doesn't smooth
+    #[id = "band_attack"]
+    pub band_attack: FloatParam,
+
+    /// How fast a falling coarse band follows; 0 doesn't smooth
+    #[id = "band_release"]
+    pub band_release: FloatParam,
+
+    /// How long each coarse band's peak line holds its maximum
+    #[id = "band_peak_hold"]
+    pub band_peak_hold: FloatParam,
+
+    /// Band peak line fall rate after the hold expires
+    #[id = "band_peak_decay"]
+    pub band_peak_decay: FloatParam,
+
+    /// Hold the spectrum and levels sent, as `history.freeze` does; the
+    /// analysis carries on underneath
+    #[id = "freeze"]
+    pub freeze: BoolParam,
+}
+
+impl Default for DisplayParams {
+    fn default() -> Self {
+        Self {
+            peak_hold: FloatParam::new("Peak Hold", 1.5, FloatRange::Linear { min: 0.0, max: 5.0 })
+                .with_unit(" s")
+                .with_step_size(0.1)
+                .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            peak_release: FloatParam::new(
+                "Peak Release",
+                20.0,
+                FloatRange::Linear {
+                    min: 1.0,
+                    max: 60.0,
+                },
+            )
+            .with_unit(" dB/s")
+            .with_step_size(0.5)
+            .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            rms_integration: EnumParam::new("RMS Integration", RmsIntegration::Vu),
+            coarse_bands: IntParam::new(
+                "Coarse Bands",
+                8,
+                IntRange::Linear {
+                    min: bands::MIN_COARSE_BANDS,
+                    max: bands::MAX_COARSE_BANDS,
+                },
+            )
+            .with_unit(" bands"),
+            coarse_mapping: EnumParam::new("Coarse Mapping", CoarseMapping::EqualLogWidth),
+            band_activity: BoolParam::new("Band Activity", false),
+            band_attack: FloatParam::new("Band Attack", 0.0, FloatRange::Linear { min: 0.0, max: 1000.0 })
+                .with_unit(" ms")
+                .with_step_size(1.0)
+                .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            band_release: FloatParam::new("Band Release", 0.0, FloatRange::Linear { min: 0.0, max: 5000.0 })
+                .with_unit(" ms")
+                .with_step_size(1.0)
+                .with_value_to_string(formatters::v2s_f32_rounded(0)),
+            band_peak_hold: FloatParam::new("Band Peak Hold", 1.5, FloatRange::Linear { min: 0.0, max: 5.0 })
+                .with_unit(" s")
+                .with_step_size(0.1)
+                .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            band_peak_decay: FloatParam::new("Band Peak Decay", 20.0, FloatRange::Linear { min: 1.0, max: 60.0 })
+                .with_unit(" dB/s")
+                .with_step_size(0.5)
+                .with_value_to_string(formatters::v2s_f32_rounded(1)),
+            freeze: BoolParam::new("Freeze", false),
+        }
+    }
+}
+
+/// Plugin parameters. The automatable ones are grouped into sections for
+/// generic UIs; their IDs are the same as before the grouping, so older
+/// projects load unchanged.
+#[derive(Params)]
+pub struct HardwaveAnalyserParams {
+    #[nested(group = "Network")]
+    pub network: NetworkParams,
+
+    #[nested(group = "Analysis")]
+    pub analysis: AnalysisParams,
+
+    #[nested(group = "Display")]
+    pub display: DisplayParams,
+
+    /// Layout of the fields saved with the project, see `state`
+    #[persist = "state_version"]
+    pub state_version: AtomicU32,
+
+    /// Both analysis slots
+    #[persist = "analysis_slots"]
+    pub analysis_slots: RwLock<AnalysisSlots>,
+
+    /// Names for the two analysis streams
+    #[persist = "channel_labels"]
+    pub channel_labels: RwLock<ChannelLabels>,
+
+    /// Share anonymous usage metrics with the connected Suite
+    #[persist = "metrics_opt_in"]
+    pub metrics_opt_in: AtomicBool,
+
+    /// Identifies this instance to the Suite; saved with the project
+    #[persist = "instance_id"]
+    pub instance_id: RwLock<String>,
+
+    /// Colour the Suite shows this instance in, `#rrggbb`. `None` derives one
+    /// from `instance_id`.
+    #[persist = "display_color"]
+    pub display_color: RwLock<Option<String>>,
+
+    /// Position of this instance in the Suite, 0 first
+    #[persist = "display_order"]
+    pub display_order: AtomicU8,
+
+    /// Name the Suite shows this instance under. `None` uses the host's
+    /// track name, if it reports one.
+    #[persist = "display_label"]
+    pub display_label: RwLock<Option<String>>,
+
+    /// Suites streamed to besides the one at the Port parameter, see
+    /// `destinations`
+    #[persist = "destinations"]
+    pub destinations: RwLock<Vec<Destination>>,
+
+    /// Track name and colour from the host; not saved, since the host
+    /// reports them again on load
+    pub host_track: RwLock<Option<TrackInfo>>,
+}
+
+impl Default for HardwaveAnalyserParams {
+    fn default() -> Self {
+        Self {
+            network: NetworkParams::default(),
+            analysis: AnalysisParams::default(),
+            display: DisplayParams::default(),
+            state_version: AtomicU32::new(state::STATE_VERSION),
+            analysis_slots: RwLock::new(AnalysisSlots::default()),
+            channel_labels: RwLock::new(ChannelLabels::default()),
+            metrics_opt_in: AtomicBool::new(false),
+            instance_id: RwLock::new(display::new_instance_id()),
+            display_color: RwLock::new(None),
+            display_order: AtomicU8::new(0),
+            display_label: RwLock::new(None),
+            destinations: RwLock::new(Vec::new()),
+            host_track: RwLock::new(None),
         }
     }
 }
+
+impl HardwaveAnalyserParams {
+    /// The analysis settings the parameters read right now
+    pub fn engine_settings(&self) -> EngineSettings {
+        let (analysis, display) = (&self.analysis, &self.display);
+        EngineSettings {
+            full_bandwidth: analysis.full_bandwidth.value(),
+            zero_pad: analysis.zero_pad.value(),
+            window: analysis.fft_window.value(),
+            normalized_rate: analysis.normalized_rate.value(),
+            eco_mode: analysis.eco_mode.value(),
+            channel_mode: analysis.channel_mode.value(),
+            weighting: analysis.weighting.value(),
+            alignment: analysis.analysis_alignment.value(),
+            coarse_mapping: display.coarse_mapping.value(),
+            coarse_bands: display.coarse_bands.value() as usize,
+            band_activity: display.band_activity.value(),
+            delay_check: analysis.delay_check.value(),
+            band_attack: display.band_attack.value(),
+            band_release: display.band_release.value(),
+            band_peak_hold: display.band_peak_hold.value(),
+            band_peak_decay: display.band_peak_decay.value(),
+            peak_hold: display.peak_hold.value(),
+            peak_release: display.peak_release.value(),
+            rms_integration: display.rms_integration.value(),
+        }
+    }
+
+    /// Labels for the two analysis streams in the current channel mode
+    #[cfg(any(feature = "gui", test))]
+    pub fn channel_labels(&self) -> [String; 2] {
+        let mode = self.analysis.channel_mode.value();
+        match self.channel_labels.read() {
+            Ok(labels) => labels.effective(mode),
+            Err(_) => mode.default_labels().map(str::to_string),
+        }
+    }
+
+    /// Set the display colour; an empty string returns to the derived one.
+    /// Returns false, changing nothing, if `color` isn't a colour.
+    #[cfg(any(feature = "gui", test))]
+    pub fn set_display_color(&self, color: &str) -> bool {
+        let color = if color.trim().is_empty() {
+            None
+        } else {
+            match display::parse_color(color) {
+                Some(color) => Some(color),
+                None => return false,
+            }
+        };
+        if let Ok(mut current) = self.display_color.write() {
+            *current = color;
+        }
+        true
+    }
+
+    /// Set the label; an empty one returns to the host's track name, or to
+    /// the derived label
+    pub fn set_display_label(&self, label: &str) {
+        if let Ok(mut current) = self.display_label.write() {
+            *current = display::clean_label(label);
+        }
+    }
+
+    /// The extra destinations, cleaned again, since a project file may
+    /// have been edited by hand
+    pub fn destinations(&self) -> Vec<Destination> {
+        self.destinations.read().map(|listed| destinations::clean(&listed)).unwrap_or_default()
+    }
+
+    /// Set the extra destinations, cleaned; returns them as kept
+    #[cfg(feature = "gui")]
+    pub fn set_destinations(&self, listed: &[Destination]) -> Vec<Destination> {
+        let cleaned = destinations::clean(listed);
+        if let Ok(mut current) = self.destinations.write() {
+            *current = cleaned.clone();
+        }
+        cleaned
+    }
+
+    /// Set the display order, clamped to `MAX_DISPLAY_ORDER`
+    #[cfg(any(feature = "gui", test))]
+    pub fn set_display_order(&self, order: i64) {
+        let order = order.clamp(0, display::MAX_DISPLAY_ORDER as i64) as u8;
+        self.display_order.store(order, Ordering::Relaxed);
+    }
+
+    /// Display hints with defaults filled in: what the user set, else what
+    /// the host reports for the track, else the derived colour and label.
+    /// Saved values are validated again, since a project file may have been
+    /// edited by hand.
+    pub fn display_hints(&self) -> DisplayHints {
+        let instance_id = self.instance_id.read().map(|id| id.clone()).unwrap_or_default();
+        let host = self.host_track.read().ok().and_then(|host| host.clone()).unwrap_or_default();
+        let color = self
+            .display_color
+            .read()
+            .ok()
+            .and_then(|color| color.as_deref().and_then(display::parse_color))
+            .or(host.color)
+            .unwrap_or_else(|| display::default_color(&instance_id));
+        let label = self
+            .display_label
+            .read()
+            .ok()
+            .and_then(|label| label.as_deref().and_then(display::clean_label))
+            .or(host.name)
+            .unwrap_or_else(|| display::default_label(&instance_id));
+        DisplayHints {
+            instance_id,
+            label,
+            color,
+            order: self.display_order.load(Ordering::Relaxed).min(display::MAX_DISPLAY_ORDER),
+            channel_labels: self.channel_labels.read().ok().and_then(|labels| labels.custom.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The list in the module docs
+    const NON_AUTOMATABLE: [&str; 13] = [
+        "compact_levels", "delay_check", "fft_window", "full_bandwidth", "loop_policy", "normalized_rate", "port",
+        "raw_spectrum", "raw_spectrum_rate", "reference_analysis", "timing_packets", "waveform_packets", "zero_pad",
+    ];
+
+    #[test]
+    fn test_every_param_has_a_unit_or_formatter() {
+        let params = HardwaveAnalyserParams::default();
+        for (id, ptr, _) in params.param_map() {
+            // SAFETY: `params` outlives the pointers
+            unsafe {
+                if matches!(ptr, ParamPtr::FloatParam(_) | ParamPtr::IntParam(_)) {
+                    assert!(!ptr.unit().is_empty(), "{} has no unit", id);
+                }
+                let values: Vec<f32> = match ptr.step_count() {
+                    Some(steps) => (0..=steps).map(|step| step as f32 / steps as f32).collect(),
+                    None => vec![0.0, 0.37, 1.0, ptr.default_normalized_value()],
+                };
+                for value in values {
+                    let shown = ptr.normalized_value_to_string(value, true);
+                    assert!(!shown.trim().is_empty(), "{} shows {} as nothing", id, value);
+                    let parsed = ptr
+                        .string_to_normalized_value(&shown)
+                        .unwrap_or_else(|| panic!("{} doesn't parse its own {:?}", id, shown));
+                    assert_eq!(ptr.normalized_value_to_string(parsed, true), shown, "{}", id);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_non_automatable_params_match_the_docs() {
+        let params = HardwaveAnalyserParams::default();
+        let mut fixed: Vec<String> = params
+            .param_map()
+            .into_iter()
+            // SAFETY: `params` outlives the pointers
+            .filter(|(_, ptr, _)| unsafe { ptr.flags() }.contains(ParamFlags::NON_AUTOMATABLE))
+            .map(|(id, _, _)| id)
+            .collect();
+        fixed.sort_unstable();
+        assert_eq!(fixed, NON_AUTOMATABLE);
+
+        let hidden: Vec<String> = params
+            .param_map()
+            .into_iter()
+            // SAFETY: as above
+            .filter(|(_, ptr, _)| unsafe { ptr.flags() }.contains(ParamFlags::HIDE_IN_GENERIC_UI))
+            .map(|(id, _, _)| id)
+            .collect();
+        assert_eq!(hidden, ["timing_packets"]);
+    }
+
+    #[test]
+    fn test_params_are_grouped_under_their_old_ids() {
+        let params = HardwaveAnalyserParams::default();
+        let map: Vec<(String, String)> =
+            params.param_map().into_iter().map(|(id, _, group)| (id, group)).collect();
+        let group = |name: &str| {
+            let mut ids: Vec<&str> =
+                map.iter().filter(|(_, g)| g == name).map(|(id, _)| id.as_str()).collect();
+            ids.sort_unstable();
+            ids
+        };
+        assert_eq!(
+            group("Network"),
+            [
+                "compact_levels", "enabled", "port", "raw_spectrum", "raw_spectrum_rate", "timing_packets",
+                "waveform_packets",
+            ]
+        );
+        assert_eq!(
+            group("Analysis"),
+            [
+                "active_slot", "analysis_alignment", "channel_mode", "delay_check", "eco_mode", "fft_window",
+                "full_bandwidth", "loop_policy", "normalized_rate", "reference_analysis", "weighting", "zero_pad",
+            ]
+        );
+        assert_eq!(
+            group("Display"),
+            [
+                "band_activity", "band_attack", "band_peak_decay", "band_peak_hold", "band_release", "coarse_bands",
+                "coarse_mapping", "freeze", "peak_hold", "peak_release", "rms_integration",
+            ]
+        );
+        assert_eq!(map.len(), 30, "every parameter is in a section");
+    }
+
+    #[test]
+    fn test_default_labels_follow_mode() {
+        let labels = ChannelLabels::default();
+        assert!(!labels.is_customized());
+        assert_eq!(labels.effective(ChannelMode::LeftRight), ["Left", "Right"]);
+        assert_eq!(labels.effective(ChannelMode::MidSide), ["Mid", "Side"]);
+    }
+
+    #[test]
+    fn test_custom_labels_stick_across_mode_changes() {
+        let mut labels = ChannelLabels::default();
+        labels.set("Mix", "Reference", ChannelMode::LeftRight);
+        assert!(labels.is_customized());
+        assert_eq!(labels.effective(ChannelMode::MidSide), ["Mix", "Reference"]);
+
+        // Clearing both returns to the mode defaults.
+        labels.set("", "  ", ChannelMode::MidSide);
+        assert!(!labels.is_customized());
+        assert_eq!(labels.effective(ChannelMode::MidSide), ["Mid", "Side"]);
+    }
+
+    #[test]
+    fn test_partial_custom_labels_use_defaults() {
+        let mut labels = ChannelLabels::default();
+        labels.set("Mix", "", ChannelMode::LeftRight);
+        assert_eq!(labels.effective(ChannelMode::LeftRight), ["Mix", "Right"]);
+    }
+
+    #[test]
+    fn test_sanitize_label() {
+        assert_eq!(sanitize_label("  Drum\nBus\t "), "DrumBus");
+        assert_eq!(sanitize_label(&"x".repeat(100)).len(), MAX_LABEL_LEN);
+        assert_eq!(sanitize_label("\u{7}"), "");
+    }
+
+    #[test]
+    fn test_labels_persist_roundtrip() {
+        let mut labels = ChannelLabels::default();
+        labels.set("Mix", "Reference", ChannelMode::LeftRight);
+        let json = serde_json::to_string(&labels).unwrap();
+        let restored: ChannelLabels = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored, labels);
+
+        let params = HardwaveAnalyserParams::default();
+        *params.channel_labels.write().unwrap() = restored;
+        assert_eq!(params.channel_labels(), ["Mix", "Reference"]);
+    }
+
+    #[test]
+    fn test_display_hint_validation() {
+        let params = HardwaveAnalyserParams::default();
+        let derived = params.display_hints().color;
+        assert_eq!(derived, display::default_color(&params.display_hints().instance_id));
+
+        assert!(params.set_display_color(" #FF8800 "));
+        assert_eq!(params.display_hints().color, "#ff8800");
+        assert!(!params.set_display_color("orange"));
+        assert_eq!(params.display_hints().color, "#ff8800");
+        assert!(params.set_display_color(""));
+        assert_eq!(params.display_hints().color, derived);
+
+        params.set_display_order(-3);
+        assert_eq!(params.display_hints().order, 0);
+        params.set_display_order(1000);
+        assert_eq!(params.display_hints().order, display::MAX_DISPLAY_ORDER);
+
+        // A hand-edited project can't smuggle in a bad colour
+        *params.display_color.write().unwrap() = Some("javascript:".to_string());
+        assert_eq!(params.display_hints().color, derived);
+    }
+
+    #[test]
+    fn test_user_display_hints_beat_host_ones() {
+        let params = HardwaveAnalyserParams::default();
+        *params.host_track.write().unwrap() = Some(TrackInfo {
+            name: Some("Drums".to_string()),
+            color: Some("#336699".to_string()),
+        });
+        assert_eq!(params.display_hints().label, "Drums");
+        assert_eq!(params.display_hints().color, "#336699");
+
+        params.set_display_label("  Overheads ");
+        assert!(params.set_display_color("#f80"));
+        assert_eq!(params.display_hints().label, "Overheads");
+        assert_eq!(params.display_hints().color, "#ff8800");
+
+        // Clearing the overrides goes back to the host's values, not the
+        // derived ones
+        params.set_display_label("");
+        assert!(params.set_display_color(""));
+        assert_eq!(params.display_hints().label, "Drums");
+        assert_eq!(params.display_hints().color, "#336699");
+    }
+
+    #[test]
+    fn test_display_hints_persist_roundtrip() {
+        let params = HardwaveAnalyserParams::default();
+        params.set_display_color("#123abc");
+        params.set_display_order(4);
+        params.set_display_label("Bus 2");
+        let saved = params.serialize_fields();
+
+        let restored = HardwaveAnalyserParams::default();
+        assert_ne!(restored.display_hints().instance_id, params.display_hints().instance_id);
+        restored.deserialize_fields(&saved);
+        assert_eq!(restored.display_hints(), params.display_hints());
+
+        // An unconfigured instance keeps its derived colour and label across
+        // save/load
+        let fresh = HardwaveAnalyserParams::default();
+        let reloaded = HardwaveAnalyserParams::default();
+        reloaded.deserialize_fields(&fresh.serialize_fields());
+        assert_eq!(reloaded.display_hints().color, fresh.display_hints().color);
+        assert_eq!(reloaded.display_hints().label, fresh.display_hints().label);
+        assert!(fresh.display_hints().label.starts_with("Hardwave Analyser #"));
+    }
+}
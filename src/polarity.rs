@@ -0,0 +1,601 @@
+//! Warnings about a polarity-flipped channel, and a delayed copy of one
+//!
+//! A channel inverted somewhere in the chain leaves the correlation between
+//! left and right strongly negative for as long as the music plays, which
+//! program material on its own almost never is. The engine sums both
+//! channels' products over each frame's interval at the host rate, before
+//! any mid/side conversion, and a correlation under `FLIP_BELOW` (−0.8) for
+//! `RAISE_AFTER_S` of audio raises the polarity warning. It stays up until
+//! the correlation has been over `RESTORED_ABOVE` (−0.5) for
+//! `CLEAR_AFTER_S`, so a passage hovering around the threshold doesn't
+//! flicker it.
+//!
+//! Intervals where either channel's RMS is under `GATE_DB` count neither
+//! way: silence, a fade tail or a signal on one side only says nothing
+//! about polarity. A mono input never warns, and neither does a stereo one
+//! carrying the same signal on both sides, whose correlation is +1.
+//!
+//! With the Delayed Copy Check parameter on, the engine also keeps the last
+//! samples of each channel and, every `DELAY_EVERY` frames, looks for the
+//! strongest cross-correlation within `MAX_LAG` samples either way. The
+//! same signal matching better than `COPY_MATCH` at a lag other than zero
+//! is most often a routing error: one side through an uncompensated plugin
+//! or a send returned to it late. That is an extra correlation pass, hence
+//! the parameter. The same timing and gate apply.
+//!
+//! Packets also carry the correlation of each FFT window, from
+//! `window_correlation`, for a correlation meter: the same sum over the
+//! analysis-rate samples the spectrum saw, converted back to left and right
+//! in mid/side mode. It isn't gated, only 0 once either channel is under
+//! `WINDOW_FLOOR_DB`, and a mono input reads +1.
+//!
+//! Each warning is logged once per occurrence. The Suite can dismiss the
+//! current ones with `{"type":"clear_warnings"}`; a dismissed warning comes
+//! back only once the condition has cleared and occurred again.
+
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+
+/// Bit of `AudioPacket::stereo_warnings`: one channel is polarity-inverted
+pub const POLARITY_FLIP: u8 = 1;
+
+/// Bit of `AudioPacket::stereo_warnings`: one channel is a delayed copy of
+/// the other
+pub const DELAYED_COPY: u8 = 2;
+
+/// Correlation under which a channel looks inverted
+pub const FLIP_BELOW: f32 = -0.8;
+
+/// Correlation over which a raised polarity warning starts to clear
+pub const RESTORED_ABOVE: f32 = -0.5;
+
+/// Seconds of gated audio a condition has to last to raise its warning
+pub const RAISE_AFTER_S: f32 = 4.0;
+
+/// Seconds of gated audio without the condition that clear its warning
+pub const CLEAR_AFTER_S: f32 = 2.0;
+
+/// RMS both channels need for an interval to count, dBFS
+pub const GATE_DB: f32 = -50.0;
+
+/// RMS under which `window_correlation` takes a channel as silent, dBFS
+pub const WINDOW_FLOOR_DB: f32 = -100.0;
+
+/// Samples of each channel the delayed-copy check compares
+pub const DELAY_WINDOW: usize = 2048;
+
+/// Largest lag the delayed-copy check looks at, host samples either way
+pub const MAX_LAG: usize = 240;
+
+/// Normalised cross-correlation at which the channels are the same signal
+pub const COPY_MATCH: f32 = 0.9;
+
+/// Frames per delayed-copy check
+const DELAY_EVERY: u32 = 4;
+
+const HISTORY_LEN: usize = DELAY_WINDOW + 2 * MAX_LAG;
+
+/// Products of one interval's samples
+#[derive(Debug, Default, Clone, Copy)]
+struct Sums {
+    lr: f64,
+    ll: f64,
+    rr: f64,
+    samples: u32,
+}
+
+impl Sums {
+    fn add(&mut self, left: f32, right: f32) {
+        let (left, right) = (f64::from(left), f64::from(right));
+        self.lr += left * right;
+        self.ll += left * left;
+        self.rr += right * right;
+        self.samples += 1;
+    }
+
+    /// The correlation, -1..=1; `None` if either channel's RMS is under
+    /// `gate_db`. Audio has no DC worth removing, so the means are taken as 0.
+    fn correlation(&self, gate_db: f32) -> Option<f32> {
+        let floor = f64::from(10f32.powf(gate_db / 10.0)) * f64::from(self.samples);
+        if self.samples == 0 || self.ll < floor || self.rr < floor {
+            return None;
+        }
+        Some((self.lr / (self.ll * self.rr).sqrt()).clamp(-1.0, 1.0) as f32)
+    }
+}
+
+/// Correlation of two channels over one analysis window, -1..=1; 0 when
+/// either is under `WINDOW_FLOOR_DB`, denormal levels included. A
+/// `mid_side` window holds mid and side, which are summed back to left and
+/// right first.
+pub fn window_correlation(first: &[f32], second: &[f32], mid_side: bool) -> f32 {
+    let mut sums = Sums::default();
+    for (&first, &second) in first.iter().zip(second) {
+        if mid_side {
+            sums.add(first + second, first - second);
+        } else {
+            sums.add(first, second);
+        }
+    }
+    sums.correlation(WINDOW_FLOOR_DB).unwrap_or(0.0)
+}
+
+/// One warning's hysteresis
+#[derive(Debug, Default, Clone, Copy)]
+struct Latch {
+    raised: bool,
+    /// The Suite dismissed this occurrence
+    dismissed: bool,
+    /// Seconds the condition has been heading the other way
+    held_s: f32,
+}
+
+impl Latch {
+    /// Account `seconds` of gated audio in which the condition was `present`
+    /// or, clearly, `absent` (neither in between). True when this raised it.
+    fn update(&mut self, present: bool, absent: bool, seconds: f32) -> bool {
+        let heading = if self.raised { absent } else { present };
+        if !heading {
+            self.held_s = 0.0;
+            return false;
+        }
+        self.held_s += seconds;
+        if self.raised {
+            if self.held_s >= CLEAR_AFTER_S {
+                *self = Latch::default();
+            }
+            false
+        } else if self.held_s >= RAISE_AFTER_S {
+            *self = Latch { raised: true, ..Latch::default() };
+            true
+        } else {
+            false
+        }
+    }
+
+    fn warning(&self) -> bool {
+        self.raised && !self.dismissed
+    }
+}
+
+/// The last `HISTORY_LEN` samples of each channel, each written twice so
+/// the newest `HISTORY_LEN` are always contiguous
+struct History {
+    left: Vec<f32>,
+    right: Vec<f32>,
+    next: usize,
+    filled: usize,
+}
+
+impl History {
+    fn new() -> Self {
+        Self {
+            left: vec![0.0; 2 * HISTORY_LEN],
+            right: vec![0.0; 2 * HISTORY_LEN],
+            next: 0,
+            filled: 0,
+        }
+    }
+
+    fn push(&mut self, left: f32, right: f32) {
+        self.left[self.next] = left;
+        self.left[self.next + HISTORY_LEN] = left;
+        self.right[self.next] = right;
+        self.right[self.next + HISTORY_LEN] = right;
+        self.next = (self.next + 1) % HISTORY_LEN;
+        self.filled = (self.filled + 1).min(HISTORY_LEN);
+    }
+
+    /// The history, oldest first; `None` until it is full
+    fn samples(&self) -> Option<(&[f32], &[f32])> {
+        let range = self.next..self.next + HISTORY_LEN;
+        (self.filled == HISTORY_LEN).then(|| (&self.left[range.clone()], &self.right[range]))
+    }
+}
+
+/// The lag, in samples the right channel is behind the left (negative when
+/// it is ahead), at which one channel is a copy of the other; `None` if the
+/// best match is at no lag or isn't close enough
+fn delayed_copy(left: &[f32], right: &[f32]) -> Option<i32> {
+    let window = &left[MAX_LAG..MAX_LAG + DELAY_WINDOW];
+    let left_energy: f32 = window.iter().map(|x| x * x).sum();
+    let mut best = (0i32, 0.0f32);
+    for start in 0..=2 * MAX_LAG {
+        let shifted = &right[start..start + DELAY_WINDOW];
+        let (mut product, mut energy) = (0.0f32, 0.0f32);
+        for (l, r) in window.iter().zip(shifted) {
+            product += l * r;
+            energy += r * r;
+        }
+        if energy <= 0.0 {
+            continue;
+        }
+        let matched = (product / (left_energy * energy).sqrt()).abs();
+        if matched > best.1 {
+            best = (start as i32 - MAX_LAG as i32, matched);
+        }
+    }
+    let (lag, matched) = best;
+    (lag != 0 && matched >= COPY_MATCH).then_some(lag)
+}
+
+/// What the engine found about the channels over one interval
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct StereoReport {
+    /// Left/right correlation; `None` under the gate or for a mono input
+    pub correlation: Option<f32>,
+    /// Warnings this interval raised, `POLARITY_FLIP` and `DELAYED_COPY`
+    /// bits
+    pub raised: u8,
+    /// The delayed copy's lag when `raised` has `DELAYED_COPY`, host samples
+    pub lag: i32,
+}
+
+/// Correlation, hysteresis and the delayed-copy history; owned by the
+/// engine on the audio thread
+pub struct StereoWatch {
+    sums: Sums,
+    polarity: Latch,
+    delay: Latch,
+    history: Box<History>,
+    /// Frames since the last delayed-copy check, and their gated seconds
+    frames_since_check: u32,
+    unchecked_s: f32,
+}
+
+impl StereoWatch {
+    /// Allocates the history; call this off the audio thread
+    pub fn new() -> Self {
+        Self {
+            sums: Sums::default(),
+            polarity: Latch::default(),
+            delay: Latch::default(),
+            history: Box::new(History::new()),
+            frames_since_check: 0,
+            unchecked_s: 0.0,
+        }
+    }
+
+    /// One host sample of each channel. Not called for mono inputs.
+    pub fn push(&mut self, left: f32, right: f32, delay_check: bool) {
+        self.sums.add(left, right);
+        if delay_check {
+            self.history.push(left, right);
+        }
+    }
+
+    /// At the end of each frame's interval, at host rate `sample_rate`
+    pub fn finish_interval(&mut self, sample_rate: f32, delay_check: bool) -> StereoReport {
+        let sums = std::mem::take(&mut self.sums);
+        let seconds = sums.samples as f32 / sample_rate;
+        let correlation = sums.correlation(GATE_DB);
+        let mut report = StereoReport { correlation, ..StereoReport::default() };
+
+        if !delay_check {
+            // Turned back on, the check starts from fresh audio
+            self.delay = Latch::default();
+            self.history.filled = 0;
+            self.frames_since_check = 0;
+            self.unchecked_s = 0.0;
+        }
+        let Some(correlation) = correlation else {
+            return report;
+        };
+
+        if self.polarity.update(correlation < FLIP_BELOW, correlation > RESTORED_ABOVE, seconds) {
+            report.raised |= POLARITY_FLIP;
+        }
+
+        if delay_check {
+            self.frames_since_check += 1;
+            self.unchecked_s += seconds;
+            if self.frames_since_check >= DELAY_EVERY {
+                if let Some((left, right)) = self.history.samples() {
+                    let lag = delayed_copy(left, right);
+                    if self.delay.update(lag.is_some(), lag.is_none(), self.unchecked_s) {
+                        report.raised |= DELAYED_COPY;
+                        report.lag = lag.unwrap_or(0);
+                    }
+                }
+                self.frames_since_check = 0;
+                self.unchecked_s = 0.0;
+            }
+        }
+        report
+    }
+
+    /// Forget the audio, as after a transport jump, but not the warnings
+    pub fn reset(&mut self) {
+        self.sums = Sums::default();
+        self.history.filled = 0;
+        self.frames_since_check = 0;
+        self.unchecked_s = 0.0;
+    }
+
+    /// The warnings up now, `POLARITY_FLIP` and `DELAYED_COPY` bits
+    pub fn warnings(&self) -> u8 {
+        let mut warnings = 0;
+        if self.polarity.warning() {
+            warnings |= POLARITY_FLIP;
+        }
+        if self.delay.warning() {
+            warnings |= DELAYED_COPY;
+        }
+        warnings
+    }
+
+    /// Dismiss the warnings up now until their conditions clear
+    pub fn dismiss(&mut self) {
+        self.polarity.dismissed = self.polarity.raised;
+        self.delay.dismissed = self.delay.raised;
+    }
+}
+
+impl Default for StereoWatch {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The warnings, shared by the plugin and the connection thread
+#[derive(Debug, Default)]
+pub struct StereoWarnings {
+    warnings: AtomicU8,
+    /// The Suite dismissed them; taken by the plugin
+    clear: AtomicBool,
+}
+
+impl StereoWarnings {
+    /// Audio thread, after every frame
+    pub fn set(&self, warnings: u8) {
+        self.warnings.store(warnings, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u8 {
+        self.warnings.load(Ordering::Relaxed)
+    }
+
+    /// Connection thread, when the Suite dismisses the warnings. They read
+    /// as clear at once; the plugin dismisses them in the watch next block.
+    pub fn request_clear(&self) {
+        self.warnings.store(0, Ordering::Relaxed);
+        self.clear.store(true, Ordering::Relaxed);
+    }
+
+    /// Audio thread: whether the Suite dismissed the warnings since the
+    /// last call
+    pub fn take_clear(&self) -> bool {
+        self.clear.swap(false, Ordering::Relaxed)
+    }
+}
+
+#[derive(Deserialize)]
+struct ClearMessage {
+    #[serde(rename = "type")]
+    kind: String,
+}
+
+/// Whether a text message from the Suite dismisses the warnings
+pub fn parse_clear(text: &str) -> bool {
+    serde_json::from_str::<ClearMessage>(text).is_ok_and(|message| message.kind == "clear_warnings")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: f32 = 48_000.0;
+    /// Samples per frame at 20 Hz
+    const INTERVAL: usize = 2400;
+
+    /// Same xorshift32 as the test vectors, in -1..1
+    struct Noise(u32);
+
+    impl Noise {
+        fn next(&mut self) -> f32 {
+            let mut x = self.0;
+            x ^= x << 13;
+            x ^= x >> 17;
+            x ^= x << 5;
+            self.0 = x;
+            (x as f64 / 4_294_967_296.0 * 2.0 - 1.0) as f32
+        }
+    }
+
+    /// Music-ish: three tones and some noise
+    fn program(noise: &mut Noise, n: usize) -> f32 {
+        let t = n as f32 / RATE;
+        0.3 * (2.0 * PI * 110.0 * t).sin() + 0.2 * (2.0 * PI * 440.0 * t).sin() + 0.1 * (2.0 * PI * 1250.0 * t).sin()
+            + 0.1 * noise.next()
+    }
+
+    /// Feed `seconds` of `stereo(n)` a frame at a time; the warnings after
+    /// each frame
+    fn run(
+        watch: &mut StereoWatch,
+        seconds: f32,
+        delay_check: bool,
+        mut stereo: impl FnMut(usize) -> (f32, f32),
+    ) -> Vec<(StereoReport, u8)> {
+        let frames = (seconds * RATE) as usize / INTERVAL;
+        (0..frames)
+            .map(|frame| {
+                for i in 0..INTERVAL {
+                    let (left, right) = stereo(frame * INTERVAL + i);
+                    watch.push(left, right, delay_check);
+                }
+                let report = watch.finish_interval(RATE, delay_check);
+                (report, watch.warnings())
+            })
+            .collect()
+    }
+
+    fn raised(reports: &[(StereoReport, u8)], bit: u8) -> usize {
+        reports.iter().filter(|(report, _)| report.raised & bit != 0).count()
+    }
+
+    #[test]
+    fn test_an_inverted_copy_warns_once() {
+        let mut watch = StereoWatch::new();
+        let mut noise = Noise(0x2545_f491);
+        let reports = run(&mut watch, 10.0, false, |n| {
+            let x = program(&mut noise, n);
+            (x, -x)
+        });
+        assert!(reports.iter().all(|(report, _)| report.correlation.unwrap() < -0.99));
+        assert_eq!(raised(&reports, POLARITY_FLIP), 1, "logged once per occurrence");
+        // Not before it has lasted long enough
+        let first = reports.iter().position(|(_, warnings)| *warnings & POLARITY_FLIP != 0).unwrap();
+        let due = (RAISE_AFTER_S * RATE) as usize / INTERVAL;
+        assert!((first + 1).abs_diff(due) <= 1, "raised after {} frames", first + 1);
+        assert!(reports[first..].iter().all(|(_, warnings)| *warnings == POLARITY_FLIP));
+    }
+
+    #[test]
+    fn test_wide_stereo_silence_and_mono_dont_warn() {
+        // Independent material on each side: wide, but not inverted
+        let mut watch = StereoWatch::new();
+        let (mut left_noise, mut right_noise) = (Noise(0x2545_f491), Noise(0x1234_5678));
+        let reports = run(&mut watch, 10.0, true, |n| (program(&mut left_noise, n), 0.5 * right_noise.next()));
+        assert!(reports.iter().all(|(report, _)| report.correlation.unwrap().abs() < 0.5));
+        assert!(reports.iter().all(|(_, warnings)| *warnings == 0));
+
+        // A quiet inverted copy, under the gate, says nothing
+        let mut noise = Noise(0x2545_f491);
+        let reports = run(&mut watch, 10.0, false, |n| {
+            let x = 0.001 * program(&mut noise, n);
+            (x, -x)
+        });
+        assert!(reports.iter().all(|(report, warnings)| report.correlation.is_none() && *warnings == 0));
+
+        // The same signal on both sides is as correlated as it gets
+        let reports = run(&mut watch, 10.0, true, |n| {
+            let x = program(&mut noise, n);
+            (x, x)
+        });
+        assert!(reports.iter().all(|(_, warnings)| *warnings == 0));
+
+        // Neither is one side alone
+        let reports = run(&mut watch, 10.0, false, |n| (program(&mut noise, n), 0.0));
+        assert!(reports.iter().all(|(report, warnings)| report.correlation.is_none() && *warnings == 0));
+    }
+
+    #[test]
+    fn test_hysteresis() {
+        let mut watch = StereoWatch::new();
+        let mut noise = Noise(0x2545_f491);
+        // Inverted, with `mix` of an unrelated signal on the inverted side
+        let inverted = |mix: f32| {
+            let (mut noise, mut other) = (Noise(0x2545_f491), Noise(0x1234_5678));
+            move |n: usize| {
+                let x = program(&mut noise, n);
+                (x, -x + mix * other.next())
+            }
+        };
+
+        // A short inverted passage doesn't raise it
+        run(&mut watch, RAISE_AFTER_S - 1.0, false, inverted(0.0));
+        run(&mut watch, 1.0, false, |n| {
+            let x = program(&mut noise, n);
+            (x, x)
+        });
+        assert_eq!(watch.warnings(), 0);
+        assert_eq!(raised(&run(&mut watch, RAISE_AFTER_S - 1.0, false, inverted(0.0)), POLARITY_FLIP), 0);
+
+        // Raised, a correlation between the thresholds keeps it up
+        run(&mut watch, 2.0, false, inverted(0.0));
+        assert_eq!(watch.warnings(), POLARITY_FLIP);
+        let reports = run(&mut watch, 5.0, false, inverted(0.6));
+        let correlation = reports[0].0.correlation.unwrap();
+        assert!(correlation > FLIP_BELOW && correlation < RESTORED_ABOVE, "{}", correlation);
+        assert!(reports.iter().all(|(_, warnings)| *warnings == POLARITY_FLIP));
+
+        // Silence holds it too
+        run(&mut watch, 5.0, false, |_| (0.0, 0.0));
+        assert_eq!(watch.warnings(), POLARITY_FLIP);
+
+        // Dismissed, it stays down while the flip goes on
+        watch.dismiss();
+        assert_eq!(raised(&run(&mut watch, 10.0, false, inverted(0.0)), POLARITY_FLIP), 0);
+        assert_eq!(watch.warnings(), 0);
+
+        // Cleared by correlated audio, the next flip is a new occurrence
+        let reports = run(&mut watch, CLEAR_AFTER_S + 0.5, false, |n| {
+            let x = program(&mut noise, n);
+            (x, x)
+        });
+        assert!(reports.iter().all(|(_, warnings)| *warnings == 0));
+        assert_eq!(raised(&run(&mut watch, 10.0, false, inverted(0.0)), POLARITY_FLIP), 1);
+        assert_eq!(watch.warnings(), POLARITY_FLIP);
+    }
+
+    #[test]
+    fn test_delayed_copy_needs_the_check() {
+        const LAG: usize = 96;
+        let mut noise = Noise(0x2545_f491);
+        let source: Vec<f32> = (0..(10.0 * RATE) as usize + LAG).map(|n| program(&mut noise, n)).collect();
+        let delayed = |n: usize| (source[n + LAG], source[n]);
+
+        let mut watch = StereoWatch::new();
+        assert!(run(&mut watch, 10.0, false, delayed).iter().all(|(_, warnings)| *warnings == 0));
+
+        let mut watch = StereoWatch::new();
+        let reports = run(&mut watch, 10.0, true, delayed);
+        assert_eq!(raised(&reports, DELAYED_COPY), 1);
+        let report = reports.iter().find(|(report, _)| report.raised & DELAYED_COPY != 0).unwrap().0;
+        assert_eq!(report.lag, LAG as i32, "the right channel is behind");
+        assert_eq!(watch.warnings(), DELAYED_COPY);
+
+        // Off again, so is the warning
+        run(&mut watch, 0.1, false, delayed);
+        assert_eq!(watch.warnings(), 0);
+    }
+
+    #[test]
+    fn test_clear_message_and_shared_flags() {
+        assert!(parse_clear(r#"{"type":"clear_warnings"}"#));
+        assert!(!parse_clear(r#"{"type":"subscribe","types":["fft"]}"#));
+        assert!(!parse_clear("not json"));
+
+        let shared = StereoWarnings::default();
+        shared.set(POLARITY_FLIP | DELAYED_COPY);
+        assert_eq!(shared.get(), 3);
+        shared.request_clear();
+        assert_eq!(shared.get(), 0);
+        assert!(shared.take_clear());
+        assert!(!shared.take_clear());
+    }
+
+    #[test]
+    fn test_window_correlation() {
+        let mut noise = Noise(0x2545_f491);
+        let left: Vec<f32> = (0..4096).map(|n| program(&mut noise, n)).collect();
+        let inverted: Vec<f32> = left.iter().map(|x| -x).collect();
+        let mut other = Noise(0x1234_5678);
+        let independent: Vec<f32> = (0..4096).map(|_| other.next()).collect();
+
+        assert!((window_correlation(&left, &left, false) - 1.0).abs() < 1e-6);
+        assert!((window_correlation(&left, &inverted, false) + 1.0).abs() < 1e-6);
+        assert!(window_correlation(&left, &independent, false).abs() < 0.05);
+
+        // Mid and side of the same pairs read the same
+        let mid_side = |right: &[f32]| {
+            let mid: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l + r) * 0.5).collect();
+            let side: Vec<f32> = left.iter().zip(right).map(|(l, r)| (l - r) * 0.5).collect();
+            window_correlation(&mid, &side, true)
+        };
+        assert!((mid_side(&left) - 1.0).abs() < 1e-6);
+        assert!((mid_side(&inverted) + 1.0).abs() < 1e-6);
+        assert!(mid_side(&independent).abs() < 0.05);
+
+        // Silence, denormals and one silent side read 0
+        let silence = vec![0.0; 4096];
+        let denormal = vec![1e-40_f32; 4096];
+        assert_eq!(window_correlation(&silence, &silence, false), 0.0);
+        assert_eq!(window_correlation(&denormal, &denormal, false), 0.0);
+        assert_eq!(window_correlation(&denormal, &silence, true), 0.0);
+        assert_eq!(window_correlation(&left, &silence, false), 0.0);
+        assert_eq!(window_correlation(&[], &[], false), 0.0);
+    }
+}
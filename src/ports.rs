@@ -0,0 +1,144 @@
+//! Sanity checks for the Port parameter
+//!
+//! Ports in the OS ephemeral range may be handed to other applications at any
+//! time, which shows up as connection flapping, and the editor's packet
+//! servers bind ephemeral ports of their own. The connection thread checks
+//! the port before every attempt, and the warning goes out with the
+//! connection's status. An ephemeral port is still tried; a packet
+//! server's isn't, since what answers there is never the Suite.
+
+use parking_lot::{Mutex, MutexGuard};
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// The port the Suite listens on unless told otherwise
+pub const DEFAULT_PORT: u16 = 9847;
+
+/// Ports currently bound by editor packet servers in this process
+static PACKET_SERVER_PORTS: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+
+/// Why a port choice may cause trouble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PortWarning {
+    /// The OS may assign this port to outgoing connections of other apps
+    Ephemeral,
+    /// An editor packet server in this process is listening on it
+    PacketServer,
+}
+
+impl fmt::Display for PortWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PortWarning::Ephemeral => {
+                let range = ephemeral_range();
+                write!(
+                    f,
+                    "port is in the OS ephemeral range ({}-{}) and may be taken by other apps",
+                    range.start(),
+                    range.end()
+                )
+            }
+            PortWarning::PacketServer => write!(f, "port is used by the plugin window's packet server"),
+        }
+    }
+}
+
+/// Default ephemeral port range of the current OS.
+pub fn ephemeral_range() -> RangeInclusive<u16> {
+    if cfg!(target_os = "linux") {
+        32768..=60999
+    } else {
+        // Windows Vista+ and macOS follow the IANA range
+        49152..=65535
+    }
+}
+
+fn packet_server_ports() -> MutexGuard<'static, Vec<u16>> {
+    PACKET_SERVER_PORTS.lock()
+}
+
+/// Record a packet server port so the Port parameter can be checked against it.
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+pub fn register_packet_server(port: u16) {
+    packet_server_ports().push(port);
+}
+
+/// Forget a packet server port once the server stops.
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+pub fn unregister_packet_server(port: u16) {
+    let mut ports = packet_server_ports();
+    if let Some(i) = ports.iter().position(|&p| p == port) {
+        ports.swap_remove(i);
+    }
+}
+
+/// Classify a port choice against the given packet server ports.
+pub fn classify(port: u16, packet_server_ports: &[u16]) -> Option<PortWarning> {
+    if packet_server_ports.contains(&port) {
+        Some(PortWarning::PacketServer)
+    } else if ephemeral_range().contains(&port) {
+        Some(PortWarning::Ephemeral)
+    } else {
+        None
+    }
+}
+
+/// The port to suggest to a user setting up: the Suite's default, or the
+/// first clean one above it if something in this process took that.
+#[cfg(any(feature = "gui", test))]
+pub fn recommended() -> u16 {
+    recommend(&packet_server_ports())
+}
+
+#[cfg(any(feature = "gui", test))]
+fn recommend(packet_server_ports: &[u16]) -> u16 {
+    (DEFAULT_PORT..=u16::MAX)
+        .find(|&port| classify(port, packet_server_ports).is_none())
+        .unwrap_or(DEFAULT_PORT)
+}
+
+/// Check a port against the live packet servers in this process.
+pub fn check(port: i32) -> Option<PortWarning> {
+    let port = u16::try_from(port).ok()?;
+    classify(port, &packet_server_ports())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_port_is_clean() {
+        assert_eq!(classify(9847, &[]), None);
+        assert_eq!(classify(1024, &[]), None);
+    }
+
+    #[test]
+    fn test_ephemeral_classification() {
+        assert_eq!(classify(50000, &[]), Some(PortWarning::Ephemeral));
+        assert_eq!(classify(65535, &[]), if cfg!(target_os = "linux") { None } else { Some(PortWarning::Ephemeral) });
+        assert_eq!(classify(40000, &[]), if cfg!(target_os = "linux") { Some(PortWarning::Ephemeral) } else { None });
+        assert_eq!(classify(32767, &[]), None);
+    }
+
+    #[test]
+    fn test_packet_server_collision_wins() {
+        assert_eq!(classify(50000, &[50000]), Some(PortWarning::PacketServer));
+        assert_eq!(classify(9847, &[9847]), Some(PortWarning::PacketServer));
+    }
+
+    #[test]
+    fn test_recommended_port_avoids_packet_servers() {
+        assert_eq!(recommend(&[]), DEFAULT_PORT);
+        assert_eq!(recommend(&[DEFAULT_PORT, DEFAULT_PORT + 1]), DEFAULT_PORT + 2);
+    }
+
+    #[test]
+    fn test_registry() {
+        register_packet_server(12345);
+        assert_eq!(check(12345), Some(PortWarning::PacketServer));
+        unregister_packet_server(12345);
+        assert_eq!(check(12345), None);
+        assert_eq!(check(-1), None);
+    }
+}
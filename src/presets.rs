@@ -0,0 +1,426 @@
+//! Named analyser presets.
+//!
+//! Built-in presets are compiled in; user presets are JSON files under
+//! `~/.hardwave/presets/`. A preset only covers the analysis settings — not
+//! the port, streaming switch or channel labels — and is applied on request
+//! only. Restoring a project never reapplies a preset, so project state always
+//! wins over whatever preset was last chosen.
+
+use nih_plug::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::params::{
+    AnalysisAlignment, ChannelMode, CoarseMapping, HardwaveAnalyserParams, RmsIntegration,
+    Weighting, WindowType, ZeroPad,
+};
+
+/// Maximum length of a preset name, in characters
+pub const MAX_NAME_LEN: usize = 32;
+
+/// Errors from listing, loading or saving presets.
+#[derive(Debug)]
+pub enum PresetError {
+    /// The name is empty after sanitising.
+    InvalidName,
+    /// The name belongs to a built-in preset.
+    Reserved(String),
+    /// No preset with this name exists.
+    NotFound(String),
+    /// No home directory to store user presets in.
+    NoHomeDir,
+    Io(io::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for PresetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PresetError::InvalidName => write!(f, "preset name is empty"),
+            PresetError::Reserved(name) => write!(f, "\"{}\" is a built-in preset", name),
+            PresetError::NotFound(name) => write!(f, "no preset named \"{}\"", name),
+            PresetError::NoHomeDir => write!(f, "no home directory to store presets in"),
+            PresetError::Io(e) => write!(f, "preset file I/O error: {}", e),
+            PresetError::Json(e) => write!(f, "malformed preset file: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for PresetError {}
+
+/// Analysis settings captured by a preset. Missing fields in a user preset
+/// file fall back to the parameter defaults.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Preset {
+    pub full_bandwidth: bool,
+    pub zero_pad: ZeroPad,
+    pub channel_mode: ChannelMode,
+    pub analysis_alignment: AnalysisAlignment,
+    pub peak_hold: f32,
+    pub peak_release: f32,
+    pub rms_integration: RmsIntegration,
+    pub coarse_bands: i32,
+    pub coarse_mapping: CoarseMapping,
+    pub normalized_rate: bool,
+    pub band_activity: bool,
+    pub fft_window: WindowType,
+    pub band_attack: f32,
+    pub band_release: f32,
+    pub band_peak_hold: f32,
+    pub band_peak_decay: f32,
+    pub weighting: Weighting,
+}
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self {
+            full_bandwidth: false,
+            zero_pad: ZeroPad::Off,
+            channel_mode: ChannelMode::LeftRight,
+            analysis_alignment: AnalysisAlignment::WindowEndsAtSend,
+            peak_hold: 1.5,
+            peak_release: 20.0,
+            rms_integration: RmsIntegration::Vu,
+            coarse_bands: 8,
+            coarse_mapping: CoarseMapping::EqualLogWidth,
+            normalized_rate: false,
+            band_activity: false,
+            fft_window: WindowType::Hann,
+            band_attack: 0.0,
+            band_release: 0.0,
+            band_peak_hold: 1.5,
+            band_peak_decay: 20.0,
+            weighting: Weighting::Z,
+        }
+    }
+}
+
+impl Preset {
+    /// The current parameter values as a preset
+    pub fn capture(params: &HardwaveAnalyserParams) -> Self {
+        Self {
+            full_bandwidth: params.analysis.full_bandwidth.value(),
+            zero_pad: params.analysis.zero_pad.value(),
+            channel_mode: params.analysis.channel_mode.value(),
+            analysis_alignment: params.analysis.analysis_alignment.value(),
+            peak_hold: params.display.peak_hold.value(),
+            peak_release: params.display.peak_release.value(),
+            rms_integration: params.display.rms_integration.value(),
+            coarse_bands: params.display.coarse_bands.value(),
+            coarse_mapping: params.display.coarse_mapping.value(),
+            normalized_rate: params.analysis.normalized_rate.value(),
+            band_activity: params.display.band_activity.value(),
+            fft_window: params.analysis.fft_window.value(),
+            band_attack: params.display.band_attack.value(),
+            band_release: params.display.band_release.value(),
+            band_peak_hold: params.display.band_peak_hold.value(),
+            band_peak_decay: params.display.band_peak_decay.value(),
+            weighting: params.analysis.weighting.value(),
+        }
+    }
+
+    /// Set every covered parameter through the host. All gestures are opened
+    /// before the first value changes and closed after the last, so the host
+    /// sees the preset as one automation/undo step.
+    pub fn apply(&self, params: &HardwaveAnalyserParams, setter: &ParamSetter) {
+        setter.begin_set_parameter(&params.analysis.full_bandwidth);
+        setter.begin_set_parameter(&params.analysis.zero_pad);
+        setter.begin_set_parameter(&params.analysis.channel_mode);
+        setter.begin_set_parameter(&params.analysis.analysis_alignment);
+        setter.begin_set_parameter(&params.display.peak_hold);
+        setter.begin_set_parameter(&params.display.peak_release);
+        setter.begin_set_parameter(&params.display.rms_integration);
+        setter.begin_set_parameter(&params.display.coarse_bands);
+        setter.begin_set_parameter(&params.display.coarse_mapping);
+        setter.begin_set_parameter(&params.analysis.normalized_rate);
+        setter.begin_set_parameter(&params.display.band_activity);
+        setter.begin_set_parameter(&params.analysis.fft_window);
+        setter.begin_set_parameter(&params.display.band_attack);
+        setter.begin_set_parameter(&params.display.band_release);
+        setter.begin_set_parameter(&params.display.band_peak_hold);
+        setter.begin_set_parameter(&params.display.band_peak_decay);
+        setter.begin_set_parameter(&params.analysis.weighting);
+
+        setter.set_parameter(&params.analysis.full_bandwidth, self.full_bandwidth);
+        setter.set_parameter(&params.analysis.zero_pad, self.zero_pad);
+        setter.set_parameter(&params.analysis.channel_mode, self.channel_mode);
+        setter.set_parameter(&params.analysis.analysis_alignment, self.analysis_alignment);
+        setter.set_parameter(&params.display.peak_hold, self.peak_hold);
+        setter.set_parameter(&params.display.peak_release, self.peak_release);
+        setter.set_parameter(&params.display.rms_integration, self.rms_integration);
+        setter.set_parameter(&params.display.coarse_bands, self.coarse_bands);
+        setter.set_parameter(&params.display.coarse_mapping, self.coarse_mapping);
+        setter.set_parameter(&params.analysis.normalized_rate, self.normalized_rate);
+        setter.set_parameter(&params.display.band_activity, self.band_activity);
+        setter.set_parameter(&params.analysis.fft_window, self.fft_window);
+        setter.set_parameter(&params.display.band_attack, self.band_attack);
+        setter.set_parameter(&params.display.band_release, self.band_release);
+        setter.set_parameter(&params.display.band_peak_hold, self.band_peak_hold);
+        setter.set_parameter(&params.display.band_peak_decay, self.band_peak_decay);
+        setter.set_parameter(&params.analysis.weighting, self.weighting);
+
+        setter.end_set_parameter(&params.analysis.full_bandwidth);
+        setter.end_set_parameter(&params.analysis.zero_pad);
+        setter.end_set_parameter(&params.analysis.channel_mode);
+        setter.end_set_parameter(&params.analysis.analysis_alignment);
+        setter.end_set_parameter(&params.display.peak_hold);
+        setter.end_set_parameter(&params.display.peak_release);
+        setter.end_set_parameter(&params.display.rms_integration);
+        setter.end_set_parameter(&params.display.coarse_bands);
+        setter.end_set_parameter(&params.display.coarse_mapping);
+        setter.end_set_parameter(&params.analysis.normalized_rate);
+        setter.end_set_parameter(&params.display.band_activity);
+        setter.end_set_parameter(&params.analysis.fft_window);
+        setter.end_set_parameter(&params.display.band_attack);
+        setter.end_set_parameter(&params.display.band_release);
+        setter.end_set_parameter(&params.display.band_peak_hold);
+        setter.end_set_parameter(&params.display.band_peak_decay);
+        setter.end_set_parameter(&params.analysis.weighting);
+    }
+}
+
+/// Presets shipped with the plugin
+pub fn built_in() -> Vec<(&'static str, Preset)> {
+    vec![
+        ("Default", Preset::default()),
+        (
+            "Mastering",
+            Preset {
+                full_bandwidth: true,
+                analysis_alignment: AnalysisAlignment::AverageOfInterval,
+                peak_hold: 3.0,
+                peak_release: 10.0,
+                ..Preset::default()
+            },
+        ),
+        (
+            "Live",
+            Preset {
+                peak_hold: 0.5,
+                peak_release: 40.0,
+                rms_integration: RmsIntegration::Ppm,
+                ..Preset::default()
+            },
+        ),
+        (
+            "Bass check",
+            Preset {
+                channel_mode: ChannelMode::MidSide,
+                peak_release: 10.0,
+                ..Preset::default()
+            },
+        ),
+    ]
+}
+
+/// Directory holding user presets.
+pub fn user_dir() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".hardwave").join("presets"))
+}
+
+/// Make a user-supplied name safe to use as a file name: letters, digits,
+/// spaces, `-` and `_`, trimmed and capped at `MAX_NAME_LEN`.
+pub fn sanitize_name(name: &str) -> Option<String> {
+    let cleaned: String = name
+        .chars()
+        .filter(|c| c.is_alphanumeric() || matches!(c, ' ' | '-' | '_'))
+        .take(MAX_NAME_LEN)
+        .collect();
+    let cleaned = cleaned.trim().to_string();
+    (!cleaned.is_empty()).then_some(cleaned)
+}
+
+/// Built-in names first, then user presets sorted by name.
+pub fn list(dir: Option<&Path>) -> Vec<String> {
+    let mut user: Vec<String> = dir
+        .and_then(|d| fs::read_dir(d).ok())
+        .into_iter()
+        .flatten()
+        .flatten()
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter_map(|path| path.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .filter(|name| !is_built_in(name))
+        .collect();
+    user.sort();
+
+    built_in()
+        .into_iter()
+        .map(|(name, _)| name.to_string())
+        .chain(user)
+        .collect()
+}
+
+fn is_built_in(name: &str) -> bool {
+    built_in().iter().any(|(n, _)| n.eq_ignore_ascii_case(name))
+}
+
+/// Look up a preset by name. Built-ins take precedence over a user file of
+/// the same name.
+pub fn find(dir: Option<&Path>, name: &str) -> Result<Preset, PresetError> {
+    if let Some((_, preset)) = built_in().into_iter().find(|(n, _)| n.eq_ignore_ascii_case(name)) {
+        return Ok(preset);
+    }
+    let name = sanitize_name(name).ok_or(PresetError::InvalidName)?;
+    let dir = dir.ok_or(PresetError::NoHomeDir)?;
+    let path = dir.join(format!("{}.json", name));
+    let json = fs::read_to_string(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => PresetError::NotFound(name.clone()),
+        _ => PresetError::Io(e),
+    })?;
+    serde_json::from_str(&json).map_err(PresetError::Json)
+}
+
+/// Save `preset` as a user preset, replacing any existing one of the same
+/// name. Returns the sanitised name.
+pub fn save(dir: Option<&Path>, name: &str, preset: &Preset) -> Result<String, PresetError> {
+    let name = sanitize_name(name).ok_or(PresetError::InvalidName)?;
+    if is_built_in(&name) {
+        return Err(PresetError::Reserved(name));
+    }
+    let dir = dir.ok_or(PresetError::NoHomeDir)?;
+    fs::create_dir_all(dir).map_err(PresetError::Io)?;
+    let json = serde_json::to_string_pretty(preset).map_err(PresetError::Json)?;
+    fs::write(dir.join(format!("{}.json", name)), json).map_err(PresetError::Io)?;
+    Ok(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use nih_plug::wrapper::state::PluginState;
+    use std::sync::{Arc, Mutex};
+
+    /// Records the order of the host-facing parameter calls
+    #[derive(Default)]
+    struct RecordingContext {
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl GuiContext for RecordingContext {
+        fn plugin_api(&self) -> PluginApi {
+            PluginApi::Clap
+        }
+
+        fn request_resize(&self) -> bool {
+            false
+        }
+
+        unsafe fn raw_begin_set_parameter(&self, _param: ParamPtr) {
+            self.calls.lock().unwrap().push("begin");
+        }
+
+        unsafe fn raw_set_parameter_normalized(&self, _param: ParamPtr, _normalized: f32) {
+            self.calls.lock().unwrap().push("set");
+        }
+
+        unsafe fn raw_end_set_parameter(&self, _param: ParamPtr) {
+            self.calls.lock().unwrap().push("end");
+        }
+
+        fn get_state(&self) -> PluginState {
+            unimplemented!()
+        }
+
+        fn set_state(&self, _state: PluginState) {}
+    }
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hardwave-presets-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    #[test]
+    fn test_apply_is_one_gesture() {
+        let params = HardwaveAnalyserParams::default();
+        let context = Arc::new(RecordingContext::default());
+        let setter = ParamSetter::new(context.as_ref());
+        built_in()[1].1.apply(&params, &setter);
+
+        let calls = context.calls.lock().unwrap().clone();
+        let mut expected = vec!["begin"; 17];
+        expected.extend(["set"; 17]);
+        expected.extend(["end"; 17]);
+        assert_eq!(calls, expected);
+    }
+
+    #[test]
+    fn test_saved_preset_roundtrip() {
+        let dir = scratch_dir("roundtrip");
+        let preset = Preset {
+            full_bandwidth: true,
+            zero_pad: ZeroPad::X2,
+            channel_mode: ChannelMode::MidSide,
+            analysis_alignment: AnalysisAlignment::WindowCentredOnInterval,
+            peak_hold: 0.7,
+            peak_release: 33.0,
+            rms_integration: RmsIntegration::Instant,
+            coarse_bands: 24,
+            coarse_mapping: CoarseMapping::Perceptual,
+            normalized_rate: true,
+            band_activity: true,
+            fft_window: WindowType::BlackmanHarris,
+            band_attack: 120.0,
+            band_release: 800.0,
+            band_peak_hold: 3.0,
+            band_peak_decay: 40.0,
+            weighting: Weighting::A,
+        };
+
+        let name = save(Some(&dir), "  My/..Preset!  ", &preset).unwrap();
+        assert_eq!(name, "MyPreset");
+        assert_eq!(find(Some(&dir), "MyPreset").unwrap(), preset);
+        assert!(list(Some(&dir)).ends_with(&["MyPreset".to_string()]));
+
+        // Saving again overwrites
+        let updated = Preset { peak_hold: 2.0, ..preset };
+        save(Some(&dir), "MyPreset", &updated).unwrap();
+        assert_eq!(find(Some(&dir), "MyPreset").unwrap(), updated);
+
+        assert!(matches!(find(Some(&dir), "Missing"), Err(PresetError::NotFound(_))));
+        assert!(matches!(save(Some(&dir), "///", &preset), Err(PresetError::InvalidName)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_window_roundtrips_and_older_files_get_hann() {
+        let dir = scratch_dir("window");
+        let preset = Preset { fft_window: WindowType::FlatTop, ..Preset::default() };
+        save(Some(&dir), "Flat", &preset).unwrap();
+        assert_eq!(find(Some(&dir), "Flat").unwrap().fft_window, WindowType::FlatTop);
+
+        // Saved before the window was part of a preset
+        fs::write(dir.join("Older.json"), r#"{"peak_hold": 0.7}"#).unwrap();
+        let older = find(Some(&dir), "Older").unwrap();
+        assert_eq!(older.fft_window, WindowType::Hann);
+        assert_eq!(older.peak_hold, 0.7);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_built_ins_take_precedence() {
+        let dir = scratch_dir("precedence");
+        assert!(matches!(
+            save(Some(&dir), "mastering", &Preset::default()),
+            Err(PresetError::Reserved(_))
+        ));
+
+        // A hand-written file can't shadow a built-in either
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Live.json"), r#"{"peak_hold": 4.0}"#).unwrap();
+        assert_eq!(find(Some(&dir), "Live").unwrap(), built_in()[2].1);
+        assert_eq!(list(Some(&dir)).iter().filter(|n| *n == "Live").count(), 1);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_default_preset_matches_param_defaults() {
+        let params = HardwaveAnalyserParams::default();
+        assert_eq!(Preset::capture(&params), Preset::default());
+    }
+}
@@ -1,33 +1,364 @@
 //! Binary protocol for audio data transmission
+//!
+//! A packet is `AudioPacket` in the layout below, written and read by hand
+//! so that it stays what this says whatever the Rust types do. Every
+//! number is little-endian and nothing is padded: integers at their width,
+//! `f32` and `f64` as IEEE 754, a bool as one byte, 1 or 0. There are four
+//! parts, the header, the scalars, the arrays and the checksum:
+//!
+//! ```text
+//! offset  field                      type
+//! 0       packet_type                u8
+//! 1       protocol_version           u16   LAYOUT_VERSION, 2
+//! 3       scalar_bytes               u16   length of the scalars, SCALAR_BYTES
+//! 5       sample_rate                u32
+//! 9       timestamp_ms               u64
+//! 17      left_peak                  f32
+//! 21      right_peak                 f32
+//! 25      left_rms                   f32
+//! 29      right_rms                  f32
+//! 33      left_peak_meter            f32
+//! 37      right_peak_meter           f32
+//! 41      left_rms_meter             f32
+//! 45      right_rms_meter            f32
+//! 49      editor_open                bool
+//! 50      editor_seq                 u32
+//! 54      captured_us                u64
+//! 62      timing                     bool  whether the four below are set
+//! 63        count                    u32
+//! 67        p50_us                   u32
+//! 71        p95_us                   u32
+//! 75        max_us                   u32
+//! 79      streaming_suspended        bool
+//! 80      analysis_alignment         u8
+//! 81      non_finite_samples         u32
+//! 85      metrics_opt_in             bool
+//! 86      metrics                    bool  whether the six below are set
+//! 87        interval_s               u32
+//! 91        update_rate_hz           f32
+//! 95        drop_percent             f32
+//! 99        reconnects               u32
+//! 103       plugin_api               u8
+//! 104       latency_p95_us           u32
+//! 108     display_order              u8
+//! 109     zero_pad                   u8
+//! 110     stale_ms                   u32
+//! 114     analysis_slot              u8
+//! 115     command_seq                u32
+//! 119     eco_mode                   bool
+//! 120     loop_start_samples         i64
+//! 128     loop_end_samples           i64
+//! 136     looping                    bool
+//! 137     loop_wraps                 u32
+//! 141     loop_policy                u8
+//! 142     clicks                     bool  whether the three below are set
+//! 143       count                    u32
+//! 147       worst_timestamp_ms       u64
+//! 155       worst_severity_db        f32
+//! 159     resumed_after_ms           u64
+//! 167     beat_phase                 f32
+//! 171     bar_phase                  f32
+//! 175     beat_phase_valid           bool
+//! 176     stereo_warnings            u8
+//! 177     historical                 bool
+//! 178     simulated                  bool
+//! 179     correlation                f32
+//! 183     momentary_lufs             f32
+//! 187     short_term_lufs            f32
+//! 191     left_true_peak             f32
+//! 195     right_true_peak            f32
+//! 199     envelope_samples           u32
+//! 203     tempo_bpm                  f64
+//! 211     time_sig_numerator         u8
+//! 212     time_sig_denominator       u8
+//! 213     pos_beats                  f64
+//! 221     bar_start_beats            f64
+//! 229     pos_samples                i64
+//! 237     playing                    bool
+//! 238     recording                  bool
+//! 239     supported_version          u32
+//! 243     sequence                   u32
+//! 247     compact_levels             bool
+//! 248     instance_tag               u32
+//! 252     fft_size                   u32
+//! 256     band_count                 u8
+//! 257     channel_mode               u8
+//! 258     link                       bool  whether the four below are set
+//! 259       packets_sent             u32
+//! 263       packets_dropped          u32
+//! 267       reconnects               u32
+//! 271       bytes_written            u64
+//! 279     disabled                   bool
+//! 280     state_transitions          u32
+//! 284     rejected_transitions       u32
+//! 288     the arrays, in this order, each a u32 count and then its elements:
+//!         left_bins, right_bins, left_wave, right_wave, left_coarse,
+//!         right_coarse, coarse_edges_hz (f32); instance_id, display_color,
+//!         display_label, command (UTF-8, the count in bytes); band_activity,
+//!         left_peak_bands, right_peak_bands (f32); left_spectrum_raw,
+//!         right_spectrum_raw (u8); left_envelope, right_envelope (f32);
+//!         the compact left_bins, right_bins, left_coarse, right_coarse,
+//!         left_peak_bands and right_peak_bands (u16); channel_bins,
+//!         channel_peaks, channel_rms (f32); plugin_version, the two
+//!         channel_labels (UTF-8)
+//! last 4  checksum                   u32   CRC-32 of all the bytes before it
+//! ```
+//!
+//! An unset `timing`, `metrics`, `clicks` or `link` still takes its bytes,
+//! zeroed.
+//! Fields are only ever appended: a scalar at the end of the scalars, which
+//! makes `scalar_bytes` larger, an array after the last one. A decoder
+//! reads the scalars it knows, skips to `5 + scalar_bytes` for the arrays,
+//! and ignores whatever follows the last array it knows, so older ones read
+//! newer packets; `PROTOCOL_VERSION` counts the fields there are. A packet
+//! with fewer scalars or arrays than the decoder knows is
+//! `ProtocolError::Malformed`.
+//!
+//! The levels in dB, the bins, the coarse bands and their peaks, go once:
+//! as floats, or with `compact_levels` set as u16 steps of
+//! `COMPACT_STEP_DB` (0.25 dB) up from `COMPACT_FLOOR_DB` (-120 dB), see
+//! `compact_level`, in the compact arrays at the end. The float arrays are
+//! empty then, and the compact ones otherwise. Compact FFT packets are
+//! about 40% smaller and within 0.125 dB of the floats; a decoder from
+//! before `PROTOCOL_VERSION` 12 reads them as packets without levels.
+//!
+//! `protocol_version` is the version of the layout, and changes only when a
+//! field moves or changes type. `from_bytes` reads it first and rejects a
+//! layout it doesn't know with `ProtocolError::UnknownVersion`, rather than
+//! reading floats out of the wrong bytes, and then a packet changed on the
+//! way with `ProtocolError::Checksum`; see `checksum`. Layout 1 was bincode
+//! 1 of the struct, with u64 counts and no `scalar_bytes`, every field in
+//! declaration order; `from_bytes` still reads it, with
+//! `from_bytes_legacy`, while Suites and recordings move to layout 2.
+//!
+//! Every connection starts with a `PACKET_TYPE_HELLO` packet whose
+//! `supported_version` is the plugin's `PROTOCOL_VERSION`, the same as the
+//! handshake header, for a Suite behind something that doesn't pass headers
+//! on. Its instance ID and display hints say which instance connected, and
+//! its `plugin_version`, `sample_rate`, `fft_size`, `zero_pad`,
+//! `band_count` and `channel_mode` what it analyses with; see `metadata`.
+//! Its `channel_labels` are the names the user gave the two streams, both
+//! empty while they follow `channel_mode`; heartbeats carry them too. A
+//! connection the plugin ends itself, because the instance is removed, the
+//! host quits or the port changes, ends with a `PACKET_TYPE_GOODBYE` packet
+//! with the same instance ID, before the close frame.
 
 use serde::{Deserialize, Serialize};
+#[cfg(test)]
+use std::io::Read;
+use std::mem::size_of;
+
+use crate::bands::MAX_COARSE_BANDS;
+use crate::checksum::{self, CHECKSUM_BYTES};
+use crate::beat_phase::{BeatPhase, TransportSnapshot};
+use crate::clicks::ClickReport;
+use crate::display::{DisplayHints, MAX_LABEL_CHARS};
+#[cfg(test)]
+use crate::error::{HardwaveError, ProtocolError};
+use crate::latency::LatencyStats;
+use crate::link_stats::LinkSummary;
+use crate::memory::vec_bytes;
+use crate::metadata::{Metadata, PLUGIN_VERSION};
+use crate::metrics::UsageMetrics;
+use crate::params::ZeroPad;
+use crate::suite_commands::MAX_COMMAND_BYTES;
+use crate::transport_loop::LoopEvent;
+use crate::units::FLOOR_DB;
 
-/// Number of raw FFT magnitude bins (FFT_SIZE / 2)
+/// Number of raw FFT magnitude bins (FFT_SIZE / 2) without zero padding
 pub const NUM_BINS: usize = 2048;
 
 /// Number of time-domain samples sent per packet for the oscilloscope
 pub const WAVE_SIZE: usize = 512;
 
+/// Most heap memory one packet holds: an FFT packet's bins at the largest
+/// padding, waveforms, the largest set of coarse bands, their peaks, edges
+/// and activity, and room for the instance ID, colour and label strings (a
+/// label character is at most four bytes) and a user command. Raw spectrum
+/// and waveform packets carry none of those floats and hold less, and
+/// channels packets no more bins, unpadded for up to `surround::MAX_CHANNELS`.
+pub const MAX_PACKET_HEAP_BYTES: usize =
+    (2 * NUM_BINS * ZeroPad::MAX_FACTOR + 2 * WAVE_SIZE + 6 * (MAX_COARSE_BANDS as usize + 1)) * size_of::<f32>()
+        + 64
+        + 4 * MAX_LABEL_CHARS
+        + MAX_COMMAND_BYTES;
+
+/// Version of the packet fields, sent with every handshake as
+/// `X-Hardwave-Protocol` and in the hello packet so the Suite knows which
+/// fields to expect. 21 added `channel_labels`, 20 the state transition
+/// counts, 19 `disabled`, 18 `link`, 17 the hello's metadata, 16 goodbye
+/// packets, 15 channels packets, 14 reference packets,
+/// 13 `instance_tag`, 12 `compact_levels`, 11 moved to layout 2, 10 ended
+/// packets in a checksum, 9 added `sequence`; 8 put `protocol_version` after `packet_type`, the
+/// one field not appended, and its packets can't be read by decoders from
+/// before it; 7 added transport packets, 6 waveform packets, 5 raw spectrum
+/// packets, 4 the true peaks, 3 the loudness, 2 `correlation`, and 1 is a
+/// plugin that sends no version.
+pub const PROTOCOL_VERSION: u32 = 21;
+
+/// Version of the packet layout, the `protocol_version` of every packet;
+/// see the module docs
+pub const LAYOUT_VERSION: u16 = 2;
+
+/// The bincode layout of `PROTOCOL_VERSION` 8 to 10, which
+/// `AudioPacket::from_bytes_legacy` reads
+#[cfg(test)]
+pub const LEGACY_LAYOUT_VERSION: u16 = 1;
+
+/// Bincode of the fields added since layout 1, at their defaults, read
+/// after a legacy packet's own: `compact_levels`, `instance_tag`, the u64
+/// counts of the three empty channel arrays, `fft_size`, `band_count`,
+/// `channel_mode`, the u64 count of the empty `plugin_version`, an unset
+/// `link`, `disabled`, the state transition counts and the u64 counts of
+/// the two empty `channel_labels`
+#[cfg(test)]
+const LEGACY_DEFAULTS: &[u8] = &[0; 5 + 3 * 8 + 6 + 8 + 1 + 1 + 2 * 4 + 2 * 8];
+
+/// Bytes before the scalars: `packet_type`, `protocol_version` and
+/// `scalar_bytes`
+const HEADER_BYTES: usize = 5;
+
+/// Length of the scalars this build writes, its `scalar_bytes`
+pub const SCALAR_BYTES: usize = 283;
+
+/// Level of compact level 0, dB
+pub const COMPACT_FLOOR_DB: f32 = -120.0;
+
+/// dB per compact level step
+pub const COMPACT_STEP_DB: f32 = 0.25;
+
+/// Compact level of 0 dB, the loudest sent
+pub const MAX_COMPACT_LEVEL: u16 = 480;
+
+/// Handshake header carrying `PROTOCOL_VERSION`
+pub const VERSION_HEADER: &str = "X-Hardwave-Protocol";
+
+/// `pos_beats` and `bar_start_beats` of a host that doesn't report them
+pub const UNKNOWN_BEATS: f64 = f64::MIN;
+
+/// `pos_samples` of a host that doesn't report it
+pub const UNKNOWN_SAMPLES: i64 = i64::MIN;
+
 /// Packet type identifiers
 pub const PACKET_TYPE_FFT: u8 = 0;
 pub const PACKET_TYPE_HEARTBEAT: u8 = 1;
+pub const PACKET_TYPE_EDITOR: u8 = 2;
+pub const PACKET_TYPE_TIMING: u8 = 3;
+pub const PACKET_TYPE_STATUS: u8 = 4;
+pub const PACKET_TYPE_USER_COMMAND: u8 = 5;
+pub const PACKET_TYPE_LOOP_DETECTED: u8 = 6;
+pub const PACKET_TYPE_SPECTRUM_RAW: u8 = 7;
+pub const PACKET_TYPE_WAVEFORM: u8 = 8;
+pub const PACKET_TYPE_TRANSPORT: u8 = 9;
+pub const PACKET_TYPE_HELLO: u8 = 10;
+pub const PACKET_TYPE_REFERENCE: u8 = 11;
+pub const PACKET_TYPE_CHANNELS: u8 = 12;
+pub const PACKET_TYPE_GOODBYE: u8 = 13;
+
+/// Level in dB, sanitised to `FLOOR..=0`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct Db(f32);
+
+impl Db {
+    pub const FLOOR: Db = Db(FLOOR_DB);
+
+    /// NaN reads as the floor; everything else is clamped.
+    pub fn new(value: f32) -> Self {
+        if value.is_nan() {
+            Self::FLOOR
+        } else {
+            Db(value.clamp(Self::FLOOR.0, 0.0))
+        }
+    }
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+/// Linear level, sanitised to `0..=1`
+#[derive(Debug, Clone, Copy, PartialEq, PartialOrd)]
+pub struct LinearLevel(f32);
+
+impl LinearLevel {
+    pub const SILENT: LinearLevel = LinearLevel(0.0);
+
+    /// NaN reads as silence; everything else is clamped.
+    pub fn new(value: f32) -> Self {
+        if value.is_nan() {
+            Self::SILENT
+        } else {
+            LinearLevel(value.clamp(0.0, 1.0))
+        }
+    }
+
+    pub fn get(self) -> f32 {
+        self.0
+    }
+}
+
+/// Peak and RMS of one channel
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelLevels {
+    pub peak: Db,
+    pub rms: LinearLevel,
+}
+
+impl ChannelLevels {
+    pub const SILENCE: ChannelLevels = ChannelLevels {
+        peak: Db::FLOOR,
+        rms: LinearLevel::SILENT,
+    };
+
+    pub fn new(peak_db: f32, rms: f32) -> Self {
+        Self {
+            peak: Db::new(peak_db),
+            rms: LinearLevel::new(rms),
+        }
+    }
+}
+
+/// `db` as a compact level, the nearest step; NaN reads as the floor and
+/// anything above 0 dB as 0 dB
+pub fn compact_level(db: f32) -> u16 {
+    if db.is_nan() {
+        return 0;
+    }
+    ((db - COMPACT_FLOOR_DB) / COMPACT_STEP_DB).round().clamp(0.0, f32::from(MAX_COMPACT_LEVEL)) as u16
+}
+
+/// The level a compact level stands for, dB
+#[cfg(test)]
+pub fn compact_level_db(level: u16) -> f32 {
+    COMPACT_FLOOR_DB + f32::from(level.min(MAX_COMPACT_LEVEL)) * COMPACT_STEP_DB
+}
 
 /// Audio packet sent from VST to Hardwave Suite
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct AudioPacket {
-    /// Packet type (0=FFT, 1=Heartbeat)
+    /// Packet type (0=FFT, 1=Heartbeat, 2=Editor opened/closed, 3=Timing,
+    /// 4=Status, 5=User command, 6=Loop detected, 7=Raw spectrum,
+    /// 8=Waveform, 9=Transport, 10=Hello, 11=Reference spectrum)
     pub packet_type: u8,
 
-    /// Sample rate of the audio context
+    /// `LAYOUT_VERSION` of the build that sent it (every packet, from
+    /// `PROTOCOL_VERSION` 8); `to_bytes` writes its own whatever this says
+    pub protocol_version: u16,
+
+    /// Sample rate of the analysed signal (the host rate, or the decimated rate
+    /// when the host runs above 48 kHz). Bin `i` is
+    /// `i * sample_rate / (FFT_SIZE * zero_pad)` Hz.
     pub sample_rate: u32,
 
     /// Timestamp in milliseconds since plugin start
     pub timestamp_ms: u64,
 
-    /// Left channel raw FFT magnitude bins in dB (-100 to 0), length = NUM_BINS
+    /// Left channel raw FFT magnitude bins in dB (-100 to 0), length =
+    /// NUM_BINS * zero_pad, half that in eco mode
     pub left_bins: Vec<f32>,
 
-    /// Right channel raw FFT magnitude bins in dB (-100 to 0), length = NUM_BINS
+    /// Right channel raw FFT magnitude bins in dB (-100 to 0), length =
+    /// NUM_BINS * zero_pad, half that in eco mode
     pub right_bins: Vec<f32>,
 
     /// Left channel peak level in dB
@@ -47,10 +378,377 @@ pub struct AudioPacket {
 
     /// Right channel oscilloscope waveform samples, linear amplitude -1..1, length = WAVE_SIZE
     pub right_wave: Vec<f32>,
+
+    /// Left channel peak meter in dB, with the plugin's hold/release ballistics
+    pub left_peak_meter: f32,
+
+    /// Right channel peak meter in dB, with the plugin's hold/release ballistics
+    pub right_peak_meter: f32,
+
+    /// Left channel RMS meter (linear, 0-1), with the plugin's integration time
+    pub left_rms_meter: f32,
+
+    /// Right channel RMS meter (linear, 0-1), with the plugin's integration time
+    pub right_rms_meter: f32,
+
+    /// Whether the plugin window is open (editor and heartbeat packets)
+    pub editor_open: bool,
+
+    /// Editor transition counter; orders editor packets after rapid open/close
+    pub editor_seq: u32,
+
+    /// When the last sample of the analysis window was buffered, in µs on the
+    /// plugin's monotonic clock (FFT packets; 0 otherwise). Only differences
+    /// between packets are meaningful outside the plugin.
+    pub captured_us: u64,
+
+    /// Audio-thread-to-TCP latency over recent frames (timing packets)
+    pub timing: Option<LatencyStats>,
+
+    /// Streaming is suspended while the host renders offline (status and
+    /// heartbeat packets)
+    pub streaming_suspended: bool,
+
+    /// How the spectrum relates to the send interval (FFT packets):
+    /// 0 = window ends at send, 1 = window centred on the interval,
+    /// 2 = power average over the interval
+    pub analysis_alignment: u8,
+
+    /// NaN/Inf input samples replaced with silence since the previous frame
+    /// (FFT packets). Non-zero means something upstream is misbehaving.
+    pub non_finite_samples: u32,
+
+    /// The user has opted in to usage metrics (heartbeat packets)
+    pub metrics_opt_in: bool,
+
+    /// Usage over the last minute; on one heartbeat per minute while opted in
+    pub metrics: Option<UsageMetrics>,
+
+    /// Left channel coarse band levels in dB, for hardware visualisers (FFT
+    /// packets)
+    pub left_coarse: Vec<f32>,
+
+    /// Right channel coarse band levels in dB (FFT packets)
+    pub right_coarse: Vec<f32>,
+
+    /// Coarse band edges in Hz, one more than the number of bands (FFT
+    /// packets)
+    pub coarse_edges_hz: Vec<f32>,
+
+    /// Stable ID of the sending instance, saved with the project (heartbeat
+    /// packets)
+    pub instance_id: String,
+
+    /// Colour the user picked for this instance, `#rrggbb` (heartbeat
+    /// packets)
+    pub display_color: String,
+
+    /// Where the Suite should list this instance, 0 first (heartbeat packets)
+    pub display_order: u8,
+
+    /// Name to show this instance under: the user's, else the host's track
+    /// name; empty if neither is known (heartbeat packets)
+    pub display_label: String,
+
+    /// FFT length over window length (FFT packets). Above 1 the bins are
+    /// interpolated: they are closer together than the window can resolve,
+    /// which is still `sample_rate / FFT_SIZE` Hz.
+    pub zero_pad: u8,
+
+    /// How long the host has gone without processing audio, once that is
+    /// over `watchdog::STALE_AFTER_MS`; 0 while audio is flowing (heartbeat
+    /// packets). Non-zero means the last frame is stale.
+    pub stale_ms: u32,
+
+    /// Which analysis slot made the frame, 0 = A, 1 = B (FFT packets). The
+    /// rest of the packet describes the frame in full, so nothing else is
+    /// sent when the slot changes.
+    pub analysis_slot: u8,
+
+    /// Sequence number the Suite's `command_ack` refers to (user command
+    /// packets)
+    pub command_seq: u32,
+
+    /// `{"command": ..., "args": ...}` from the plugin window, checked
+    /// against `suite_commands::ALLOWED` (user command packets)
+    pub command: String,
+
+    /// The frame was made in eco mode: at half the usual analysis rate with
+    /// a `fft::ECO_FFT_SIZE` window, so bins are twice as wide and half as
+    /// many (FFT packets)
+    pub eco_mode: bool,
+
+    /// First sample of the looped section, at `sample_rate` from the
+    /// project start (loop packets)
+    pub loop_start_samples: i64,
+
+    /// First sample after the looped section (loop packets)
+    pub loop_end_samples: i64,
+
+    /// The host is still looping; false on the packet that ends a loop
+    /// (loop packets)
+    pub looping: bool,
+
+    /// Backward jumps to the loop start so far, 2 when the loop is first
+    /// detected (loop packets)
+    pub loop_wraps: u32,
+
+    /// What integrated measurements should do with the loop: 0 = continue,
+    /// 1 = reset on every pass, 2 = pause until it ends (loop packets)
+    pub loop_policy: u8,
+
+    /// Clicks in the input since the previous heartbeat, whether or not
+    /// they clipped; `None` without any (heartbeat packets)
+    pub clicks: Option<ClickReport>,
+
+    /// How long the machine slept, on the first packet after it woke up; 0
+    /// otherwise (status packets)
+    pub resumed_after_ms: u64,
+
+    /// How much each coarse band is moving, 0 (static) to 1, with the Band
+    /// Activity parameter on; empty otherwise (FFT packets). The mean change
+    /// of the band's level from frame to frame over the last
+    /// `activity::ACTIVITY_FRAMES` frames (2 s), both channels' power summed,
+    /// over `activity::FULL_SCALE_DB` (6 dB) and capped at 1.
+    pub band_activity: Vec<f32>,
+
+    /// Where in the beat the end of the analysis window fell, 0 on the beat
+    /// to just under 1, from the host's tempo and position (FFT packets).
+    /// Beats are quarter notes; see `beat_phase`.
+    pub beat_phase: f32,
+
+    /// Where in the bar the end of the window fell, 0..1 (FFT packets)
+    pub bar_phase: f32,
+
+    /// The host was playing and reported a tempo and position; otherwise
+    /// both phases are 0 and mean nothing (FFT packets)
+    pub beat_phase_valid: bool,
+
+    /// Stereo warnings up now, bits: 1 = one channel polarity-inverted, 2 =
+    /// one channel a delayed copy of the other (status and heartbeat
+    /// packets); see `polarity`
+    pub stereo_warnings: u8,
+
+    /// A frame from the frozen history, with its original timestamps,
+    /// served in place of a live one while the Suite or the plugin window
+    /// scrubs it (FFT packets); see `history`
+    pub historical: bool,
+
+    /// Made from input the plugin synthesised after silence, never from real
+    /// audio; a developer setting (FFT packets); see `simulate`
+    pub simulated: bool,
+
+    /// The highest each coarse band has been lately, in dB, held for the
+    /// Band Peak Hold time and then falling at the Band Peak Decay rate, as
+    /// many as `left_coarse` (FFT packets); see `meter::BandPeaks`
+    pub left_peak_bands: Vec<f32>,
+    pub right_peak_bands: Vec<f32>,
+
+    /// Correlation of left and right over the FFT window, -1..=1: +1 the
+    /// same signal on both sides, -1 one inverted, 0 unrelated or silent;
+    /// +1 for a mono input (FFT packets, from `PROTOCOL_VERSION` 2); see
+    /// `polarity::window_correlation`
+    pub correlation: f32,
+
+    /// ITU-R BS.1770 loudness of the input over the last 400 ms and 3 s, in
+    /// LUFS, floored like every level (FFT packets, from `PROTOCOL_VERSION`
+    /// 3); see `loudness`
+    pub momentary_lufs: f32,
+    pub short_term_lufs: f32,
+
+    /// The largest BS.1770 true peak of each channel since the previous
+    /// packet, 4x oversampled, in dBTP; `left_peak` and `right_peak` stay the
+    /// sample peaks of the window (FFT packets, from `PROTOCOL_VERSION` 4);
+    /// see `true_peak`
+    pub left_true_peak: f32,
+    pub right_true_peak: f32,
+
+    /// Every bin of the frame, one byte each: 0.5 dB steps up from -120 dB,
+    /// 240 being 0 dB (raw spectrum packets, from `PROTOCOL_VERSION` 5); see
+    /// `raw_spectrum`
+    pub left_spectrum_raw: Vec<u8>,
+    pub right_spectrum_raw: Vec<u8>,
+
+    /// Min/max envelope of the input since the previous waveform packet,
+    /// `[min, max, ...]` of at most 256 points, each `envelope_samples` host
+    /// samples long (waveform packets, from `PROTOCOL_VERSION` 6); see
+    /// `envelope`
+    pub left_envelope: Vec<f32>,
+    pub right_envelope: Vec<f32>,
+    pub envelope_samples: u32,
+
+    /// Host tempo in BPM, 0 if it doesn't report one (transport packets,
+    /// from `PROTOCOL_VERSION` 7); see `transport_report`
+    pub tempo_bpm: f64,
+
+    /// Host time signature, 0/0 if it doesn't report one (transport packets)
+    pub time_sig_numerator: u8,
+    pub time_sig_denominator: u8,
+
+    /// Quarter notes from the project start to the block, and to the start
+    /// of its bar; `UNKNOWN_BEATS` if the host doesn't report them
+    /// (transport packets)
+    pub pos_beats: f64,
+    pub bar_start_beats: f64,
+
+    /// Samples at `sample_rate` from the project start to the block;
+    /// `UNKNOWN_SAMPLES` if the host doesn't report it (transport packets)
+    pub pos_samples: i64,
+
+    /// Host play and record state (transport packets)
+    pub playing: bool,
+    pub recording: bool,
+
+    /// The sender's `PROTOCOL_VERSION`, the newest fields it knows (hello
+    /// packets)
+    pub supported_version: u32,
+
+    /// One more than the packet the instance built before it, wrapping at
+    /// `u32::MAX`; a gap is packets the Suite didn't get (every packet,
+    /// from `PROTOCOL_VERSION` 9); see `sequence`
+    pub sequence: u32,
+
+    /// The levels in dB go out as u16 steps of 0.25 dB, not floats, and
+    /// came in so; set from the Compact Levels parameter (FFT packets, from
+    /// `PROTOCOL_VERSION` 12). Protocol Buffers packets carry floats either
+    /// way.
+    pub compact_levels: bool,
+
+    /// The instance that sent the packet, `display::instance_tag` of its
+    /// `instance_id`; 0 until the plugin initialises (every packet, from
+    /// `PROTOCOL_VERSION` 13); see `display`
+    pub instance_tag: u32,
+
+    /// Every channel of a surround input over the frame's window, in the
+    /// input's order: each channel's bins one after the other, the peak in
+    /// dB and the RMS, linear, one per channel (channels packets, from
+    /// `PROTOCOL_VERSION` 15); see `surround`
+    pub channel_bins: Vec<f32>,
+    pub channel_peaks: Vec<f32>,
+    pub channel_rms: Vec<f32>,
+
+    /// What the instance analyses with: samples in the FFT window, coarse
+    /// bands, `metadata::CHANNEL_MODE_LEFT_RIGHT` or `CHANNEL_MODE_MID_SIDE`,
+    /// and the plugin's version (hello packets, from `PROTOCOL_VERSION`
+    /// 17); see `metadata`
+    pub fft_size: u32,
+    pub band_count: u8,
+    pub channel_mode: u8,
+    pub plugin_version: String,
+
+    /// What the connection has sent and lost, see `link_stats`; `None`
+    /// but in heartbeats (heartbeat packets, from `PROTOCOL_VERSION` 18)
+    pub link: Option<LinkSummary>,
+    /// The Enabled parameter is off: the host is running the instance but
+    /// it analyses and sends nothing but heartbeats (heartbeat packets,
+    /// from `PROTOCOL_VERSION` 19)
+    pub disabled: bool,
+    /// Connection state changes since the client was made, and changes it
+    /// refused as not allowed from the state it was in; non-zero refusals
+    /// are a bug (heartbeat packets, from `PROTOCOL_VERSION` 20)
+    pub state_transitions: u32,
+    pub rejected_transitions: u32,
+    /// Names the user gave the two streams, both empty while they follow
+    /// `channel_mode` (hello and heartbeat packets, from `PROTOCOL_VERSION`
+    /// 21)
+    pub channel_labels: [String; 2],
 }
 
 impl AudioPacket {
+    /// Start an FFT packet. Bins and levels read as silence until set with
+    /// the `with_*` methods.
+    pub fn fft(sample_rate: u32, timestamp_ms: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_FFT,
+            left_bins: vec![Db::FLOOR.get(); NUM_BINS],
+            right_bins: vec![Db::FLOOR.get(); NUM_BINS],
+            ..Self::new_heartbeat(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Set the FFT magnitude bins (dB)
+    pub fn with_bins(mut self, left: Vec<f32>, right: Vec<f32>) -> Self {
+        self.left_bins = left;
+        self.right_bins = right;
+        self
+    }
+
+    /// Set the oscilloscope waveforms
+    pub fn with_wave(mut self, left: Vec<f32>, right: Vec<f32>) -> Self {
+        self.left_wave = left;
+        self.right_wave = right;
+        self
+    }
+
+    /// Set the coarse bands and the edges they were computed with
+    pub fn with_coarse(mut self, left: Vec<f32>, right: Vec<f32>, edges_hz: Vec<f32>) -> Self {
+        self.left_coarse = left;
+        self.right_coarse = right;
+        self.coarse_edges_hz = edges_hz;
+        self
+    }
+
+    /// Set the held peaks of the coarse bands
+    pub fn with_peak_bands(mut self, left: Vec<f32>, right: Vec<f32>) -> Self {
+        self.left_peak_bands = left;
+        self.right_peak_bands = right;
+        self
+    }
+
+    /// Set the instance's display hints
+    pub fn with_display(mut self, hints: &DisplayHints) -> Self {
+        self.instance_id = hints.instance_id.clone();
+        self.display_color = hints.color.clone();
+        self.display_order = hints.order;
+        self.display_label = hints.label.clone();
+        self.channel_labels = hints.channel_labels.clone().unwrap_or_default();
+        self
+    }
+
+    /// Set what the hello says about the analysis
+    pub fn with_metadata(mut self, metadata: &Metadata) -> Self {
+        self.plugin_version = PLUGIN_VERSION.to_string();
+        self.sample_rate = metadata.sample_rate;
+        self.fft_size = metadata.fft_size;
+        self.zero_pad = metadata.zero_pad;
+        self.band_count = metadata.band_count;
+        self.channel_mode = metadata.channel_mode;
+        self
+    }
+
+    /// Set the raw frame levels. Also sets the meters, for senders without
+    /// ballistics.
+    pub fn with_levels(mut self, left: ChannelLevels, right: ChannelLevels) -> Self {
+        self.left_peak = left.peak.get();
+        self.right_peak = right.peak.get();
+        self.left_rms = left.rms.get();
+        self.right_rms = right.rms.get();
+        self.with_meters(left, right)
+    }
+
+    /// Set where in the beat and bar the window ended; `None` leaves the
+    /// phases invalid
+    pub fn with_beat_phase(mut self, phase: Option<BeatPhase>) -> Self {
+        let BeatPhase { beat, bar } = phase.unwrap_or(BeatPhase { beat: 0.0, bar: 0.0 });
+        self.beat_phase = beat;
+        self.bar_phase = bar;
+        self.beat_phase_valid = phase.is_some();
+        self
+    }
+
+    /// Set the meter values (levels after ballistics)
+    pub fn with_meters(mut self, left: ChannelLevels, right: ChannelLevels) -> Self {
+        self.left_peak_meter = left.peak.get();
+        self.right_peak_meter = right.peak.get();
+        self.left_rms_meter = left.rms.get();
+        self.right_rms_meter = right.rms.get();
+        self
+    }
+
     /// Create a new FFT packet
+    #[cfg(test)]
+    #[deprecated(note = "argument order is easy to get wrong; use `AudioPacket::fft` and `with_levels`")]
+    #[allow(clippy::too_many_arguments)]
     pub fn new_fft(
         sample_rate: u32,
         timestamp_ms: u64,
@@ -63,46 +761,854 @@ impl AudioPacket {
         left_wave: Vec<f32>,
         right_wave: Vec<f32>,
     ) -> Self {
-        Self {
-            packet_type: PACKET_TYPE_FFT,
-            sample_rate,
-            timestamp_ms,
-            left_bins,
-            right_bins,
-            left_peak,
-            right_peak,
-            left_rms,
-            right_rms,
-            left_wave,
-            right_wave,
-        }
+        Self::fft(sample_rate, timestamp_ms)
+            .with_bins(left_bins, right_bins)
+            .with_levels(
+                ChannelLevels::new(left_peak, left_rms),
+                ChannelLevels::new(right_peak, right_rms),
+            )
+            .with_wave(left_wave, right_wave)
     }
 
     /// Create a heartbeat packet
     pub fn new_heartbeat(sample_rate: u32, timestamp_ms: u64) -> Self {
         Self {
             packet_type: PACKET_TYPE_HEARTBEAT,
+            protocol_version: LAYOUT_VERSION,
             sample_rate,
             timestamp_ms,
             left_bins: vec![0.0; NUM_BINS],
             right_bins: vec![0.0; NUM_BINS],
-            left_peak: -100.0,
-            right_peak: -100.0,
-            left_rms: 0.0,
-            right_rms: 0.0,
+            left_peak: ChannelLevels::SILENCE.peak.get(),
+            right_peak: ChannelLevels::SILENCE.peak.get(),
+            left_rms: ChannelLevels::SILENCE.rms.get(),
+            right_rms: ChannelLevels::SILENCE.rms.get(),
             left_wave: vec![0.0; WAVE_SIZE],
             right_wave: vec![0.0; WAVE_SIZE],
+            left_peak_meter: ChannelLevels::SILENCE.peak.get(),
+            right_peak_meter: ChannelLevels::SILENCE.peak.get(),
+            left_rms_meter: ChannelLevels::SILENCE.rms.get(),
+            right_rms_meter: ChannelLevels::SILENCE.rms.get(),
+            editor_open: false,
+            editor_seq: 0,
+            captured_us: 0,
+            timing: None,
+            streaming_suspended: false,
+            analysis_alignment: 0,
+            non_finite_samples: 0,
+            metrics_opt_in: false,
+            metrics: None,
+            left_coarse: Vec::new(),
+            right_coarse: Vec::new(),
+            coarse_edges_hz: Vec::new(),
+            instance_id: String::new(),
+            display_color: String::new(),
+            display_order: 0,
+            display_label: String::new(),
+            zero_pad: 1,
+            stale_ms: 0,
+            analysis_slot: 0,
+            command_seq: 0,
+            command: String::new(),
+            eco_mode: false,
+            loop_start_samples: 0,
+            loop_end_samples: 0,
+            looping: false,
+            loop_wraps: 0,
+            loop_policy: 0,
+            clicks: None,
+            resumed_after_ms: 0,
+            band_activity: Vec::new(),
+            beat_phase: 0.0,
+            bar_phase: 0.0,
+            beat_phase_valid: false,
+            stereo_warnings: 0,
+            historical: false,
+            simulated: false,
+            left_peak_bands: Vec::new(),
+            right_peak_bands: Vec::new(),
+            correlation: 0.0,
+            momentary_lufs: Db::FLOOR.get(),
+            short_term_lufs: Db::FLOOR.get(),
+            left_true_peak: Db::FLOOR.get(),
+            right_true_peak: Db::FLOOR.get(),
+            left_spectrum_raw: Vec::new(),
+            right_spectrum_raw: Vec::new(),
+            left_envelope: Vec::new(),
+            right_envelope: Vec::new(),
+            envelope_samples: 0,
+            tempo_bpm: 0.0,
+            time_sig_numerator: 0,
+            time_sig_denominator: 0,
+            pos_beats: UNKNOWN_BEATS,
+            bar_start_beats: UNKNOWN_BEATS,
+            pos_samples: UNKNOWN_SAMPLES,
+            playing: false,
+            recording: false,
+            supported_version: 0,
+            sequence: 0,
+            compact_levels: false,
+            instance_tag: 0,
+            channel_bins: Vec::new(),
+            channel_peaks: Vec::new(),
+            channel_rms: Vec::new(),
+            fft_size: 0,
+            band_count: 0,
+            channel_mode: 0,
+            plugin_version: String::new(),
+            link: None,
+            disabled: false,
+            state_transitions: 0,
+            rejected_transitions: 0,
+            channel_labels: Default::default(),
+        }
+    }
+
+    /// Create the hello packet that starts every connection. The instance ID
+    /// comes with `with_display`, and the rest with `with_metadata`.
+    pub fn new_hello() -> Self {
+        Self {
+            packet_type: PACKET_TYPE_HELLO,
+            supported_version: PROTOCOL_VERSION,
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create the goodbye packet that ends a connection the plugin closes.
+    /// The instance ID comes with `with_display`.
+    pub fn new_goodbye() -> Self {
+        Self {
+            packet_type: PACKET_TYPE_GOODBYE,
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create an editor opened/closed packet
+    pub fn new_editor(editor_open: bool, editor_seq: u32) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_EDITOR,
+            editor_open,
+            editor_seq,
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create a timing report packet
+    pub fn new_timing(stats: LatencyStats) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_TIMING,
+            timing: Some(stats),
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create a streaming suspended/resumed status packet
+    pub fn new_status(streaming_suspended: bool) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_STATUS,
+            streaming_suspended,
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create the status packet that starts the first connection after the
+    /// machine slept, with the current suspension state
+    pub fn new_resumed(resumed_after_ms: u64, streaming_suspended: bool) -> Self {
+        Self {
+            resumed_after_ms,
+            ..Self::new_status(streaming_suspended)
         }
     }
 
-    /// Serialize the packet to binary format
+    /// Create a user command packet. The instance ID comes with
+    /// `with_display`.
+    pub fn new_user_command(command_seq: u32, command: String) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_USER_COMMAND,
+            command_seq,
+            command,
+            ..Self::new_heartbeat(0, 0)
+        }
+    }
+
+    /// Create a raw spectrum packet; the bytes come from
+    /// `raw_spectrum::packet_for`. No bins or waveforms as floats.
+    pub fn new_spectrum_raw(sample_rate: u32, timestamp_ms: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_SPECTRUM_RAW,
+            left_bins: Vec::new(),
+            right_bins: Vec::new(),
+            left_wave: Vec::new(),
+            right_wave: Vec::new(),
+            ..Self::new_heartbeat(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Create a waveform packet at host rate `sample_rate`; the envelope
+    /// comes from `envelope::Envelope::into_packet`. No bins or waveforms.
+    pub fn new_waveform(sample_rate: u32, timestamp_ms: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_WAVEFORM,
+            ..Self::new_spectrum_raw(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Create a reference spectrum packet; the bins and bands come from
+    /// `reference::ReferenceFrame::into_packet`. No waveforms.
+    pub fn new_reference(sample_rate: u32, timestamp_ms: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_REFERENCE,
+            ..Self::new_spectrum_raw(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Create a channels packet for a surround input; the bins and levels
+    /// come from `surround::SurroundFrame::into_packet`. No stereo bins or
+    /// waveforms.
+    pub fn new_channels(sample_rate: u32, timestamp_ms: u64) -> Self {
+        Self {
+            packet_type: PACKET_TYPE_CHANNELS,
+            ..Self::new_spectrum_raw(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Create a transport packet from the host transport at the start of a
+    /// block, with sentinels for whatever the host left out or got wrong
+    pub fn new_transport(timestamp_ms: u64, transport: &TransportSnapshot) -> Self {
+        let beats = |beats: Option<f64>| beats.filter(|beats| beats.is_finite()).unwrap_or(UNKNOWN_BEATS);
+        let (time_sig_numerator, time_sig_denominator) = transport
+            .time_sig
+            .filter(|&(numerator, denominator)| numerator > 0 && denominator > 0)
+            .map_or((0, 0), |(numerator, denominator)| {
+                (numerator.min(i32::from(u8::MAX)) as u8, denominator.min(i32::from(u8::MAX)) as u8)
+            });
+        Self {
+            packet_type: PACKET_TYPE_TRANSPORT,
+            tempo_bpm: transport.tempo.filter(|tempo| tempo.is_finite() && *tempo > 0.0).unwrap_or(0.0),
+            time_sig_numerator,
+            time_sig_denominator,
+            pos_beats: beats(transport.pos_beats),
+            bar_start_beats: beats(transport.bar_start_pos_beats),
+            pos_samples: transport.pos_samples.unwrap_or(UNKNOWN_SAMPLES),
+            playing: transport.playing,
+            recording: transport.recording,
+            ..Self::new_spectrum_raw(transport.sample_rate as u32, timestamp_ms)
+        }
+    }
+
+    /// Create a loop packet: detected, another pass, or ended. `sample_rate`
+    /// is the host's, which the boundaries count in.
+    pub fn new_loop(sample_rate: u32, timestamp_ms: u64, event: &LoopEvent, loop_policy: u8) -> Self {
+        let range = event.range();
+        Self {
+            packet_type: PACKET_TYPE_LOOP_DETECTED,
+            loop_start_samples: range.start,
+            loop_end_samples: range.end,
+            looping: event.looping(),
+            loop_wraps: event.wraps(),
+            loop_policy,
+            ..Self::new_heartbeat(sample_rate, timestamp_ms)
+        }
+    }
+
+    /// Coalescing category for packets that carry state rather than events.
+    ///
+    /// When the connection falls behind, only the newest queued packet of each
+    /// category is sent. Packets returning `None` are events and are always
+    /// delivered in order.
+    pub fn coalesce_key(&self) -> Option<u8> {
+        match self.packet_type {
+            PACKET_TYPE_FFT
+            | PACKET_TYPE_HEARTBEAT
+            | PACKET_TYPE_HELLO
+            | PACKET_TYPE_SPECTRUM_RAW
+            | PACKET_TYPE_TRANSPORT
+            | PACKET_TYPE_REFERENCE
+            | PACKET_TYPE_CHANNELS => Some(self.packet_type),
+            _ => None,
+        }
+    }
+
+    /// Heap bytes held by the packet's vectors and strings
+    pub fn heap_bytes(&self) -> usize {
+        [
+            &self.left_bins,
+            &self.right_bins,
+            &self.left_wave,
+            &self.right_wave,
+            &self.left_coarse,
+            &self.right_coarse,
+            &self.coarse_edges_hz,
+            &self.band_activity,
+            &self.left_peak_bands,
+            &self.right_peak_bands,
+            &self.left_envelope,
+            &self.right_envelope,
+            &self.channel_bins,
+            &self.channel_peaks,
+            &self.channel_rms,
+        ]
+        .into_iter()
+        .map(vec_bytes)
+        .sum::<usize>()
+            + vec_bytes(&self.left_spectrum_raw)
+            + vec_bytes(&self.right_spectrum_raw)
+            + self.instance_id.capacity()
+            + self.display_color.capacity()
+            + self.display_label.capacity()
+            + self.command.capacity()
+            + self.plugin_version.capacity()
+            + self.channel_labels.iter().map(String::capacity).sum::<usize>()
+    }
+
+    /// The levels in dB, in the order of the compact arrays
+    fn levels(&self) -> [&Vec<f32>; 6] {
+        [
+            &self.left_bins,
+            &self.right_bins,
+            &self.left_coarse,
+            &self.right_coarse,
+            &self.left_peak_bands,
+            &self.right_peak_bands,
+        ]
+    }
+
+    /// `levels` as the packet's bytes carry them in floats: not at all if
+    /// they go compact
+    fn float_levels<'a>(&self, levels: &'a [f32]) -> &'a [f32] {
+        if self.compact_levels {
+            &[]
+        } else {
+            levels
+        }
+    }
+
+    /// Bytes `to_bytes` makes of the packet, checksum included
+    pub fn encoded_len(&self) -> usize {
+        let floats: usize = [
+            &self.left_wave,
+            &self.right_wave,
+            &self.coarse_edges_hz,
+            &self.band_activity,
+            &self.left_envelope,
+            &self.right_envelope,
+            &self.channel_bins,
+            &self.channel_peaks,
+            &self.channel_rms,
+        ]
+        .into_iter()
+        .map(|values| values.len() * size_of::<f32>())
+        .sum();
+        let level_size = if self.compact_levels { size_of::<u16>() } else { size_of::<f32>() };
+        let levels: usize = self.levels().into_iter().map(|levels| levels.len() * level_size).sum();
+        let bytes = [
+            self.instance_id.as_bytes(),
+            self.display_color.as_bytes(),
+            self.display_label.as_bytes(),
+            self.command.as_bytes(),
+            self.left_spectrum_raw.as_slice(),
+            self.right_spectrum_raw.as_slice(),
+            self.plugin_version.as_bytes(),
+            self.channel_labels[0].as_bytes(),
+            self.channel_labels[1].as_bytes(),
+        ]
+        .into_iter()
+        .map(<[u8]>::len)
+        .sum::<usize>();
+        // Fifteen float arrays, nine of bytes and six compact, each after
+        // its count
+        HEADER_BYTES + SCALAR_BYTES + 30 * size_of::<u32>() + floats + levels + bytes + CHECKSUM_BYTES
+    }
+
+    /// Serialize the packet in this build's layout, checksum last
     pub fn to_bytes(&self) -> Vec<u8> {
-        bincode::serialize(self).expect("Failed to serialize packet")
+        // One allocation, with room for the checksum, which is computed
+        // over the bytes in place
+        let mut out = Writer(Vec::with_capacity(self.encoded_len()));
+        out.u8(self.packet_type);
+        out.u16(LAYOUT_VERSION);
+        out.u16(SCALAR_BYTES as u16);
+
+        // The scalars, in declaration order
+        out.u32(self.sample_rate);
+        out.u64(self.timestamp_ms);
+        out.f32(self.left_peak);
+        out.f32(self.right_peak);
+        out.f32(self.left_rms);
+        out.f32(self.right_rms);
+        out.f32(self.left_peak_meter);
+        out.f32(self.right_peak_meter);
+        out.f32(self.left_rms_meter);
+        out.f32(self.right_rms_meter);
+        out.bool(self.editor_open);
+        out.u32(self.editor_seq);
+        out.u64(self.captured_us);
+        let timing = self.timing.unwrap_or_default();
+        out.bool(self.timing.is_some());
+        out.u32(timing.count);
+        out.u32(timing.p50_us);
+        out.u32(timing.p95_us);
+        out.u32(timing.max_us);
+        out.bool(self.streaming_suspended);
+        out.u8(self.analysis_alignment);
+        out.u32(self.non_finite_samples);
+        out.bool(self.metrics_opt_in);
+        let metrics = self.metrics.unwrap_or_default();
+        out.bool(self.metrics.is_some());
+        out.u32(metrics.interval_s);
+        out.f32(metrics.update_rate_hz);
+        out.f32(metrics.drop_percent);
+        out.u32(metrics.reconnects);
+        out.u8(metrics.plugin_api);
+        out.u32(metrics.latency_p95_us);
+        out.u8(self.display_order);
+        out.u8(self.zero_pad);
+        out.u32(self.stale_ms);
+        out.u8(self.analysis_slot);
+        out.u32(self.command_seq);
+        out.bool(self.eco_mode);
+        out.i64(self.loop_start_samples);
+        out.i64(self.loop_end_samples);
+        out.bool(self.looping);
+        out.u32(self.loop_wraps);
+        out.u8(self.loop_policy);
+        let clicks = self.clicks.unwrap_or_default();
+        out.bool(self.clicks.is_some());
+        out.u32(clicks.count);
+        out.u64(clicks.worst_timestamp_ms);
+        out.f32(clicks.worst_severity_db);
+        out.u64(self.resumed_after_ms);
+        out.f32(self.beat_phase);
+        out.f32(self.bar_phase);
+        out.bool(self.beat_phase_valid);
+        out.u8(self.stereo_warnings);
+        out.bool(self.historical);
+        out.bool(self.simulated);
+        out.f32(self.correlation);
+        out.f32(self.momentary_lufs);
+        out.f32(self.short_term_lufs);
+        out.f32(self.left_true_peak);
+        out.f32(self.right_true_peak);
+        out.u32(self.envelope_samples);
+        out.f64(self.tempo_bpm);
+        out.u8(self.time_sig_numerator);
+        out.u8(self.time_sig_denominator);
+        out.f64(self.pos_beats);
+        out.f64(self.bar_start_beats);
+        out.i64(self.pos_samples);
+        out.bool(self.playing);
+        out.bool(self.recording);
+        out.u32(self.supported_version);
+        out.u32(self.sequence);
+        out.bool(self.compact_levels);
+        out.u32(self.instance_tag);
+        out.u32(self.fft_size);
+        out.u8(self.band_count);
+        out.u8(self.channel_mode);
+        let link = self.link.unwrap_or_default();
+        out.bool(self.link.is_some());
+        out.u32(link.packets_sent);
+        out.u32(link.packets_dropped);
+        out.u32(link.reconnects);
+        out.u64(link.bytes_written);
+        out.bool(self.disabled);
+        out.u32(self.state_transitions);
+        out.u32(self.rejected_transitions);
+        debug_assert_eq!(out.0.len(), HEADER_BYTES + SCALAR_BYTES);
+
+        // The arrays, in declaration order
+        out.f32s(self.float_levels(&self.left_bins));
+        out.f32s(self.float_levels(&self.right_bins));
+        out.f32s(&self.left_wave);
+        out.f32s(&self.right_wave);
+        out.f32s(self.float_levels(&self.left_coarse));
+        out.f32s(self.float_levels(&self.right_coarse));
+        out.f32s(&self.coarse_edges_hz);
+        out.bytes(self.instance_id.as_bytes());
+        out.bytes(self.display_color.as_bytes());
+        out.bytes(self.display_label.as_bytes());
+        out.bytes(self.command.as_bytes());
+        out.f32s(&self.band_activity);
+        out.f32s(self.float_levels(&self.left_peak_bands));
+        out.f32s(self.float_levels(&self.right_peak_bands));
+        out.bytes(&self.left_spectrum_raw);
+        out.bytes(&self.right_spectrum_raw);
+        out.f32s(&self.left_envelope);
+        out.f32s(&self.right_envelope);
+        for levels in self.levels() {
+            out.compact(if self.compact_levels { levels } else { &[] });
+        }
+        out.f32s(&self.channel_bins);
+        out.f32s(&self.channel_peaks);
+        out.f32s(&self.channel_rms);
+        out.bytes(self.plugin_version.as_bytes());
+        for label in &self.channel_labels {
+            out.bytes(label.as_bytes());
+        }
+
+        let Writer(mut bytes) = out;
+        let checksum = checksum::crc32(&bytes);
+        bytes.extend_from_slice(&checksum.to_le_bytes());
+        bytes
+    }
+
+    /// Deserialize a packet of this build's layout or the legacy one; a
+    /// packet of another layout is `ProtocolError::UnknownVersion`, one
+    /// whose checksum doesn't match `ProtocolError::Checksum`, and one cut
+    /// short or holding nonsense `ProtocolError::Malformed`
+    #[cfg(test)]
+    pub fn from_bytes(data: &[u8]) -> Result<Self, HardwaveError> {
+        match Self::layout_of(data)? {
+            LAYOUT_VERSION => Ok(Self::decode(verified(data)?)?),
+            LEGACY_LAYOUT_VERSION => Self::from_bytes_legacy(data),
+            version => Err(ProtocolError::UnknownVersion(version).into()),
+        }
+    }
+
+    /// Deserialize a packet of `LEGACY_LAYOUT_VERSION`: bincode 1 of the
+    /// struct, checksum last, as `PROTOCOL_VERSION` 10 sent it. Kept while
+    /// Suites and recordings move to layout 2; a packet of any other layout
+    /// is `ProtocolError::UnknownVersion`.
+    #[cfg(test)]
+    pub fn from_bytes_legacy(data: &[u8]) -> Result<Self, HardwaveError> {
+        match Self::layout_of(data)? {
+            LEGACY_LAYOUT_VERSION => Ok(bincode::deserialize_from(verified(data)?.chain(LEGACY_DEFAULTS))?),
+            version => Err(ProtocolError::UnknownVersion(version).into()),
+        }
+    }
+
+    /// The `protocol_version` of a packet's bytes
+    #[cfg(test)]
+    fn layout_of(data: &[u8]) -> Result<u16, ProtocolError> {
+        Reader(data.get(1..).unwrap_or_default()).u16("protocol_version")
+    }
+
+    /// Read the bytes of a layout 2 packet, checksum removed
+    #[cfg(test)]
+    fn decode(payload: &[u8]) -> Result<Self, ProtocolError> {
+        let mut header = Reader(payload);
+        let packet_type = header.u8("packet_type")?;
+        let protocol_version = header.u16("protocol_version")?;
+        let scalar_bytes = header.u16("scalar_bytes")?;
+        // Scalars after the ones this build knows are skipped, and so is
+        // anything after its arrays
+        let mut scalars = Reader(header.take(usize::from(scalar_bytes), "scalars")?);
+        let mut arrays = header;
+
+        // Fields are read in the order they're written here, each part's in
+        // declaration order
+        let mut packet = Self {
+            packet_type,
+            protocol_version,
+            sample_rate: scalars.u32("sample_rate")?,
+            timestamp_ms: scalars.u64("timestamp_ms")?,
+            left_bins: arrays.f32s("left_bins")?,
+            right_bins: arrays.f32s("right_bins")?,
+            left_peak: scalars.f32("left_peak")?,
+            right_peak: scalars.f32("right_peak")?,
+            left_rms: scalars.f32("left_rms")?,
+            right_rms: scalars.f32("right_rms")?,
+            left_wave: arrays.f32s("left_wave")?,
+            right_wave: arrays.f32s("right_wave")?,
+            left_peak_meter: scalars.f32("left_peak_meter")?,
+            right_peak_meter: scalars.f32("right_peak_meter")?,
+            left_rms_meter: scalars.f32("left_rms_meter")?,
+            right_rms_meter: scalars.f32("right_rms_meter")?,
+            editor_open: scalars.bool("editor_open")?,
+            editor_seq: scalars.u32("editor_seq")?,
+            captured_us: scalars.u64("captured_us")?,
+            timing: scalars.option("timing", |s| {
+                Ok(LatencyStats {
+                    count: s.u32("timing")?,
+                    p50_us: s.u32("timing")?,
+                    p95_us: s.u32("timing")?,
+                    max_us: s.u32("timing")?,
+                })
+            })?,
+            streaming_suspended: scalars.bool("streaming_suspended")?,
+            analysis_alignment: scalars.u8("analysis_alignment")?,
+            non_finite_samples: scalars.u32("non_finite_samples")?,
+            metrics_opt_in: scalars.bool("metrics_opt_in")?,
+            metrics: scalars.option("metrics", |s| {
+                Ok(UsageMetrics {
+                    interval_s: s.u32("metrics")?,
+                    update_rate_hz: s.f32("metrics")?,
+                    drop_percent: s.f32("metrics")?,
+                    reconnects: s.u32("metrics")?,
+                    plugin_api: s.u8("metrics")?,
+                    latency_p95_us: s.u32("metrics")?,
+                })
+            })?,
+            left_coarse: arrays.f32s("left_coarse")?,
+            right_coarse: arrays.f32s("right_coarse")?,
+            coarse_edges_hz: arrays.f32s("coarse_edges_hz")?,
+            instance_id: arrays.string("instance_id")?,
+            display_color: arrays.string("display_color")?,
+            display_order: scalars.u8("display_order")?,
+            display_label: arrays.string("display_label")?,
+            zero_pad: scalars.u8("zero_pad")?,
+            stale_ms: scalars.u32("stale_ms")?,
+            analysis_slot: scalars.u8("analysis_slot")?,
+            command_seq: scalars.u32("command_seq")?,
+            command: arrays.string("command")?,
+            eco_mode: scalars.bool("eco_mode")?,
+            loop_start_samples: scalars.i64("loop_start_samples")?,
+            loop_end_samples: scalars.i64("loop_end_samples")?,
+            looping: scalars.bool("looping")?,
+            loop_wraps: scalars.u32("loop_wraps")?,
+            loop_policy: scalars.u8("loop_policy")?,
+            clicks: scalars.option("clicks", |s| {
+                Ok(ClickReport {
+                    count: s.u32("clicks")?,
+                    worst_timestamp_ms: s.u64("clicks")?,
+                    worst_severity_db: s.f32("clicks")?,
+                })
+            })?,
+            resumed_after_ms: scalars.u64("resumed_after_ms")?,
+            band_activity: arrays.f32s("band_activity")?,
+            beat_phase: scalars.f32("beat_phase")?,
+            bar_phase: scalars.f32("bar_phase")?,
+            beat_phase_valid: scalars.bool("beat_phase_valid")?,
+            stereo_warnings: scalars.u8("stereo_warnings")?,
+            historical: scalars.bool("historical")?,
+            simulated: scalars.bool("simulated")?,
+            left_peak_bands: arrays.f32s("left_peak_bands")?,
+            right_peak_bands: arrays.f32s("right_peak_bands")?,
+            correlation: scalars.f32("correlation")?,
+            momentary_lufs: scalars.f32("momentary_lufs")?,
+            short_term_lufs: scalars.f32("short_term_lufs")?,
+            left_true_peak: scalars.f32("left_true_peak")?,
+            right_true_peak: scalars.f32("right_true_peak")?,
+            left_spectrum_raw: arrays.bytes("left_spectrum_raw")?,
+            right_spectrum_raw: arrays.bytes("right_spectrum_raw")?,
+            left_envelope: arrays.f32s("left_envelope")?,
+            right_envelope: arrays.f32s("right_envelope")?,
+            envelope_samples: scalars.u32("envelope_samples")?,
+            tempo_bpm: scalars.f64("tempo_bpm")?,
+            time_sig_numerator: scalars.u8("time_sig_numerator")?,
+            time_sig_denominator: scalars.u8("time_sig_denominator")?,
+            pos_beats: scalars.f64("pos_beats")?,
+            bar_start_beats: scalars.f64("bar_start_beats")?,
+            pos_samples: scalars.i64("pos_samples")?,
+            playing: scalars.bool("playing")?,
+            recording: scalars.bool("recording")?,
+            supported_version: scalars.u32("supported_version")?,
+            sequence: scalars.u32("sequence")?,
+            compact_levels: scalars.bool("compact_levels")?,
+            instance_tag: scalars.u32("instance_tag")?,
+            channel_bins: Vec::new(),
+            channel_peaks: Vec::new(),
+            channel_rms: Vec::new(),
+            fft_size: scalars.u32("fft_size")?,
+            band_count: scalars.u8("band_count")?,
+            channel_mode: scalars.u8("channel_mode")?,
+            plugin_version: String::new(),
+            link: scalars.option("link", |s| {
+                Ok(LinkSummary {
+                    packets_sent: s.u32("link")?,
+                    packets_dropped: s.u32("link")?,
+                    reconnects: s.u32("link")?,
+                    bytes_written: s.u64("link")?,
+                })
+            })?,
+            disabled: scalars.bool("disabled")?,
+            state_transitions: scalars.u32("state_transitions")?,
+            rejected_transitions: scalars.u32("rejected_transitions")?,
+            channel_labels: Default::default(),
+        };
+        let compact = [
+            arrays.compact("compact left_bins")?,
+            arrays.compact("compact right_bins")?,
+            arrays.compact("compact left_coarse")?,
+            arrays.compact("compact right_coarse")?,
+            arrays.compact("compact left_peak_bands")?,
+            arrays.compact("compact right_peak_bands")?,
+        ];
+        packet.channel_bins = arrays.f32s("channel_bins")?;
+        packet.channel_peaks = arrays.f32s("channel_peaks")?;
+        packet.channel_rms = arrays.f32s("channel_rms")?;
+        packet.plugin_version = arrays.string("plugin_version")?;
+        packet.channel_labels = [arrays.string("channel_labels")?, arrays.string("channel_labels")?];
+        if packet.compact_levels {
+            [
+                packet.left_bins,
+                packet.right_bins,
+                packet.left_coarse,
+                packet.right_coarse,
+                packet.left_peak_bands,
+                packet.right_peak_bands,
+            ] = compact;
+        }
+        Ok(packet)
+    }
+}
+
+/// The bytes of a packet before its checksum, if the checksum is theirs
+#[cfg(test)]
+fn verified(data: &[u8]) -> Result<&[u8], ProtocolError> {
+    let split = data.len().checked_sub(CHECKSUM_BYTES).ok_or(ProtocolError::Malformed("checksum"))?;
+    let (payload, sent) = data.split_at(split);
+    let sent = u32::from_le_bytes([sent[0], sent[1], sent[2], sent[3]]);
+    let computed = checksum::crc32(payload);
+    if sent != computed {
+        return Err(ProtocolError::Checksum { sent, computed });
+    }
+    Ok(payload)
+}
+
+/// Appends the fields of a packet to its bytes
+struct Writer(Vec<u8>);
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn bool(&mut self, value: bool) {
+        self.u8(u8::from(value));
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn i64(&mut self, value: i64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f32(&mut self, value: f32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn f64(&mut self, value: f64) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A count and the floats
+    fn f32s(&mut self, values: &[f32]) {
+        self.u32(values.len() as u32);
+        for &value in values {
+            self.f32(value);
+        }
+    }
+
+    /// A count and the levels as compact levels
+    fn compact(&mut self, levels: &[f32]) {
+        self.u32(levels.len() as u32);
+        for &level in levels {
+            self.u16(compact_level(level));
+        }
+    }
+
+    /// A count and the bytes
+    fn bytes(&mut self, values: &[u8]) {
+        self.u32(values.len() as u32);
+        self.0.extend_from_slice(values);
+    }
+}
+
+/// Takes the fields of a packet off the front of its bytes; a field that
+/// isn't all there, or isn't valid, is `ProtocolError::Malformed` with its
+/// name
+#[cfg(test)]
+struct Reader<'a>(&'a [u8]);
+
+#[cfg(test)]
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize, field: &'static str) -> Result<&'a [u8], ProtocolError> {
+        if len > self.0.len() {
+            return Err(ProtocolError::Malformed(field));
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(taken)
+    }
+
+    fn le<const N: usize>(&mut self, field: &'static str) -> Result<[u8; N], ProtocolError> {
+        let mut bytes = [0; N];
+        bytes.copy_from_slice(self.take(N, field)?);
+        Ok(bytes)
+    }
+
+    fn u8(&mut self, field: &'static str) -> Result<u8, ProtocolError> {
+        Ok(u8::from_le_bytes(self.le(field)?))
+    }
+
+    fn bool(&mut self, field: &'static str) -> Result<bool, ProtocolError> {
+        match self.u8(field)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(ProtocolError::Malformed(field)),
+        }
+    }
+
+    fn u16(&mut self, field: &'static str) -> Result<u16, ProtocolError> {
+        Ok(u16::from_le_bytes(self.le(field)?))
+    }
+
+    fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError> {
+        Ok(u32::from_le_bytes(self.le(field)?))
+    }
+
+    fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+        Ok(u64::from_le_bytes(self.le(field)?))
+    }
+
+    fn i64(&mut self, field: &'static str) -> Result<i64, ProtocolError> {
+        Ok(i64::from_le_bytes(self.le(field)?))
+    }
+
+    fn f32(&mut self, field: &'static str) -> Result<f32, ProtocolError> {
+        Ok(f32::from_le_bytes(self.le(field)?))
+    }
+
+    fn f64(&mut self, field: &'static str) -> Result<f64, ProtocolError> {
+        Ok(f64::from_le_bytes(self.le(field)?))
+    }
+
+    /// A set flag and the value, whose bytes are there either way
+    fn option<T>(
+        &mut self,
+        field: &'static str,
+        read: impl FnOnce(&mut Self) -> Result<T, ProtocolError>,
+    ) -> Result<Option<T>, ProtocolError> {
+        let set = self.bool(field)?;
+        let value = read(self)?;
+        Ok(set.then_some(value))
+    }
+
+    /// The elements of a counted array, `size` bytes each
+    fn array(&mut self, size: usize, field: &'static str) -> Result<&'a [u8], ProtocolError> {
+        let count = self.u32(field)? as usize;
+        // Checked before anything is allocated for it
+        self.take(count.checked_mul(size).ok_or(ProtocolError::Malformed(field))?, field)
+    }
+
+    fn f32s(&mut self, field: &'static str) -> Result<Vec<f32>, ProtocolError> {
+        let bytes = self.array(size_of::<f32>(), field)?;
+        Ok(bytes.chunks_exact(4).map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])).collect())
+    }
+
+    /// Compact levels, as the levels in dB they stand for
+    fn compact(&mut self, field: &'static str) -> Result<Vec<f32>, ProtocolError> {
+        let bytes = self.array(size_of::<u16>(), field)?;
+        Ok(bytes.chunks_exact(2).map(|b| compact_level_db(u16::from_le_bytes([b[0], b[1]]))).collect())
+    }
+
+    fn bytes(&mut self, field: &'static str) -> Result<Vec<u8>, ProtocolError> {
+        Ok(self.array(1, field)?.to_vec())
     }
 
-    /// Deserialize a packet from binary format
-    pub fn from_bytes(data: &[u8]) -> Result<Self, bincode::Error> {
-        bincode::deserialize(data)
+    fn string(&mut self, field: &'static str) -> Result<String, ProtocolError> {
+        String::from_utf8(self.bytes(field)?).map_err(|_| ProtocolError::Malformed(field))
     }
 }
 
@@ -110,20 +1616,360 @@ impl AudioPacket {
 mod tests {
     use super::*;
 
+    fn test_frame(timestamp_ms: u64) -> AudioPacket {
+        AudioPacket::fft(48000, timestamp_ms)
+            .with_bins(vec![-60.0; NUM_BINS], vec![-60.0; NUM_BINS])
+            .with_levels(ChannelLevels::new(-3.0, 0.5), ChannelLevels::new(-3.0, 0.5))
+            .with_wave(vec![0.0; WAVE_SIZE], vec![0.0; WAVE_SIZE])
+    }
+
+    /// A packet with every field set, no two scalars alike
+    fn every_field() -> AudioPacket {
+        AudioPacket {
+            packet_type: PACKET_TYPE_HEARTBEAT,
+            protocol_version: LAYOUT_VERSION,
+            sample_rate: 44_100,
+            timestamp_ms: 0x0102_0304_0506_0708,
+            left_bins: vec![-1.0, -2.0, -3.0],
+            right_bins: vec![-4.0],
+            left_peak: -1.5,
+            right_peak: -2.5,
+            left_rms: 0.125,
+            right_rms: 0.375,
+            left_wave: vec![0.5, -0.5],
+            right_wave: vec![0.25],
+            left_peak_meter: -3.5,
+            right_peak_meter: -4.5,
+            left_rms_meter: 0.0625,
+            right_rms_meter: 0.1875,
+            editor_open: true,
+            editor_seq: 11,
+            captured_us: 12_000_000_000,
+            timing: Some(LatencyStats { count: 13, p50_us: 14, p95_us: 15, max_us: 16 }),
+            streaming_suspended: true,
+            analysis_alignment: 2,
+            non_finite_samples: 17,
+            metrics_opt_in: true,
+            metrics: Some(UsageMetrics {
+                interval_s: 60,
+                update_rate_hz: 29.5,
+                drop_percent: 0.75,
+                reconnects: 18,
+                plugin_api: 3,
+                latency_p95_us: 19,
+            }),
+            left_coarse: vec![-10.0, -11.0],
+            right_coarse: vec![-12.0, -13.0],
+            coarse_edges_hz: vec![20.0, 200.0, 20_000.0],
+            instance_id: "abc".to_string(),
+            display_color: "#ff8800".to_string(),
+            display_order: 4,
+            display_label: "Bass é".to_string(),
+            zero_pad: 5,
+            stale_ms: 20,
+            analysis_slot: 6,
+            command_seq: 21,
+            command: "{}".to_string(),
+            eco_mode: true,
+            loop_start_samples: -22,
+            loop_end_samples: 23_000_000_000,
+            looping: true,
+            loop_wraps: 24,
+            loop_policy: 7,
+            clicks: Some(ClickReport { count: 25, worst_timestamp_ms: 26, worst_severity_db: 27.5 }),
+            resumed_after_ms: 28,
+            band_activity: vec![0.1, 0.9],
+            beat_phase: 0.3125,
+            bar_phase: 0.4375,
+            beat_phase_valid: true,
+            stereo_warnings: 8,
+            historical: true,
+            simulated: true,
+            left_peak_bands: vec![-5.0, -6.0],
+            right_peak_bands: vec![-7.0, -8.0],
+            correlation: -0.8125,
+            momentary_lufs: -14.5,
+            short_term_lufs: -15.5,
+            left_true_peak: -0.5,
+            right_true_peak: -0.75,
+            left_spectrum_raw: vec![1, 2, 3, 4],
+            right_spectrum_raw: vec![5],
+            left_envelope: vec![-0.9, 0.9],
+            right_envelope: vec![-0.8, 0.8, -0.7, 0.7],
+            envelope_samples: 29,
+            tempo_bpm: 128.25,
+            time_sig_numerator: 9,
+            time_sig_denominator: 10,
+            pos_beats: 30.5,
+            bar_start_beats: 28.0,
+            pos_samples: 31_000_000_000,
+            playing: true,
+            recording: true,
+            supported_version: 32,
+            sequence: 33,
+            compact_levels: false,
+            instance_tag: 34,
+            channel_bins: vec![-5.0, -6.0, -7.0, -8.0],
+            channel_peaks: vec![-9.0, -10.0],
+            channel_rms: vec![0.25, 0.125],
+            fft_size: 35,
+            band_count: 36,
+            channel_mode: 37,
+            plugin_version: "0.5.0".to_string(),
+            link: Some(LinkSummary { packets_sent: 38, packets_dropped: 39, reconnects: 40, bytes_written: 41 }),
+            disabled: true,
+            state_transitions: 42,
+            rejected_transitions: 43,
+            channel_labels: ["Kick".to_string(), "Bass é".to_string()],
+        }
+    }
+
+    /// Little-endian bytes of each float
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|value| value.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_every_field_is_at_its_documented_offset() {
+        let p = every_field();
+        let bytes = p.to_bytes();
+        assert_eq!(bytes.len(), p.encoded_len());
+        let at = |offset: usize, value: &[u8], field: &str| {
+            assert_eq!(&bytes[offset..offset + value.len()], value, "{} at {}", field, offset)
+        };
+        at(0, &[PACKET_TYPE_HEARTBEAT], "packet_type");
+        at(1, &[2, 0], "protocol_version");
+        at(3, &[27, 1], "scalar_bytes");
+        at(5, &p.sample_rate.to_le_bytes(), "sample_rate");
+        at(9, &p.timestamp_ms.to_le_bytes(), "timestamp_ms");
+        at(17, &p.left_peak.to_le_bytes(), "left_peak");
+        at(21, &p.right_peak.to_le_bytes(), "right_peak");
+        at(25, &p.left_rms.to_le_bytes(), "left_rms");
+        at(29, &p.right_rms.to_le_bytes(), "right_rms");
+        at(33, &p.left_peak_meter.to_le_bytes(), "left_peak_meter");
+        at(37, &p.right_peak_meter.to_le_bytes(), "right_peak_meter");
+        at(41, &p.left_rms_meter.to_le_bytes(), "left_rms_meter");
+        at(45, &p.right_rms_meter.to_le_bytes(), "right_rms_meter");
+        at(49, &[1], "editor_open");
+        at(50, &p.editor_seq.to_le_bytes(), "editor_seq");
+        at(54, &p.captured_us.to_le_bytes(), "captured_us");
+        let timing = p.timing.unwrap();
+        at(62, &[1], "timing");
+        at(63, &timing.count.to_le_bytes(), "timing.count");
+        at(67, &timing.p50_us.to_le_bytes(), "timing.p50_us");
+        at(71, &timing.p95_us.to_le_bytes(), "timing.p95_us");
+        at(75, &timing.max_us.to_le_bytes(), "timing.max_us");
+        at(79, &[1], "streaming_suspended");
+        at(80, &[p.analysis_alignment], "analysis_alignment");
+        at(81, &p.non_finite_samples.to_le_bytes(), "non_finite_samples");
+        at(85, &[1], "metrics_opt_in");
+        let metrics = p.metrics.unwrap();
+        at(86, &[1], "metrics");
+        at(87, &metrics.interval_s.to_le_bytes(), "metrics.interval_s");
+        at(91, &metrics.update_rate_hz.to_le_bytes(), "metrics.update_rate_hz");
+        at(95, &metrics.drop_percent.to_le_bytes(), "metrics.drop_percent");
+        at(99, &metrics.reconnects.to_le_bytes(), "metrics.reconnects");
+        at(103, &[metrics.plugin_api], "metrics.plugin_api");
+        at(104, &metrics.latency_p95_us.to_le_bytes(), "metrics.latency_p95_us");
+        at(108, &[p.display_order], "display_order");
+        at(109, &[p.zero_pad], "zero_pad");
+        at(110, &p.stale_ms.to_le_bytes(), "stale_ms");
+        at(114, &[p.analysis_slot], "analysis_slot");
+        at(115, &p.command_seq.to_le_bytes(), "command_seq");
+        at(119, &[1], "eco_mode");
+        at(120, &p.loop_start_samples.to_le_bytes(), "loop_start_samples");
+        at(128, &p.loop_end_samples.to_le_bytes(), "loop_end_samples");
+        at(136, &[1], "looping");
+        at(137, &p.loop_wraps.to_le_bytes(), "loop_wraps");
+        at(141, &[p.loop_policy], "loop_policy");
+        let clicks = p.clicks.unwrap();
+        at(142, &[1], "clicks");
+        at(143, &clicks.count.to_le_bytes(), "clicks.count");
+        at(147, &clicks.worst_timestamp_ms.to_le_bytes(), "clicks.worst_timestamp_ms");
+        at(155, &clicks.worst_severity_db.to_le_bytes(), "clicks.worst_severity_db");
+        at(159, &p.resumed_after_ms.to_le_bytes(), "resumed_after_ms");
+        at(167, &p.beat_phase.to_le_bytes(), "beat_phase");
+        at(171, &p.bar_phase.to_le_bytes(), "bar_phase");
+        at(175, &[1], "beat_phase_valid");
+        at(176, &[p.stereo_warnings], "stereo_warnings");
+        at(177, &[1], "historical");
+        at(178, &[1], "simulated");
+        at(179, &p.correlation.to_le_bytes(), "correlation");
+        at(183, &p.momentary_lufs.to_le_bytes(), "momentary_lufs");
+        at(187, &p.short_term_lufs.to_le_bytes(), "short_term_lufs");
+        at(191, &p.left_true_peak.to_le_bytes(), "left_true_peak");
+        at(195, &p.right_true_peak.to_le_bytes(), "right_true_peak");
+        at(199, &p.envelope_samples.to_le_bytes(), "envelope_samples");
+        at(203, &p.tempo_bpm.to_le_bytes(), "tempo_bpm");
+        at(211, &[p.time_sig_numerator], "time_sig_numerator");
+        at(212, &[p.time_sig_denominator], "time_sig_denominator");
+        at(213, &p.pos_beats.to_le_bytes(), "pos_beats");
+        at(221, &p.bar_start_beats.to_le_bytes(), "bar_start_beats");
+        at(229, &p.pos_samples.to_le_bytes(), "pos_samples");
+        at(237, &[1], "playing");
+        at(238, &[1], "recording");
+        at(239, &p.supported_version.to_le_bytes(), "supported_version");
+        at(243, &p.sequence.to_le_bytes(), "sequence");
+        at(247, &[0], "compact_levels");
+        at(248, &p.instance_tag.to_le_bytes(), "instance_tag");
+        at(252, &p.fft_size.to_le_bytes(), "fft_size");
+        at(256, &[p.band_count], "band_count");
+        at(257, &[p.channel_mode], "channel_mode");
+        let link = p.link.unwrap();
+        at(258, &[1], "link");
+        at(259, &link.packets_sent.to_le_bytes(), "link.packets_sent");
+        at(263, &link.packets_dropped.to_le_bytes(), "link.packets_dropped");
+        at(267, &link.reconnects.to_le_bytes(), "link.reconnects");
+        at(271, &link.bytes_written.to_le_bytes(), "link.bytes_written");
+        at(279, &[1], "disabled");
+        at(280, &p.state_transitions.to_le_bytes(), "state_transitions");
+        at(284, &p.rejected_transitions.to_le_bytes(), "rejected_transitions");
+        assert_eq!(HEADER_BYTES + SCALAR_BYTES, 288);
+
+        let arrays: [(&str, usize, Vec<u8>); 30] = [
+            ("left_bins", p.left_bins.len(), floats(&p.left_bins)),
+            ("right_bins", p.right_bins.len(), floats(&p.right_bins)),
+            ("left_wave", p.left_wave.len(), floats(&p.left_wave)),
+            ("right_wave", p.right_wave.len(), floats(&p.right_wave)),
+            ("left_coarse", p.left_coarse.len(), floats(&p.left_coarse)),
+            ("right_coarse", p.right_coarse.len(), floats(&p.right_coarse)),
+            ("coarse_edges_hz", p.coarse_edges_hz.len(), floats(&p.coarse_edges_hz)),
+            ("instance_id", 3, b"abc".to_vec()),
+            ("display_color", 7, b"#ff8800".to_vec()),
+            ("display_label", 7, "Bass é".as_bytes().to_vec()),
+            ("command", 2, b"{}".to_vec()),
+            ("band_activity", p.band_activity.len(), floats(&p.band_activity)),
+            ("left_peak_bands", p.left_peak_bands.len(), floats(&p.left_peak_bands)),
+            ("right_peak_bands", p.right_peak_bands.len(), floats(&p.right_peak_bands)),
+            ("left_spectrum_raw", 4, p.left_spectrum_raw.clone()),
+            ("right_spectrum_raw", 1, p.right_spectrum_raw.clone()),
+            ("left_envelope", p.left_envelope.len(), floats(&p.left_envelope)),
+            ("right_envelope", p.right_envelope.len(), floats(&p.right_envelope)),
+            // Compact levels, none without `compact_levels`
+            ("compact left_bins", 0, Vec::new()),
+            ("compact right_bins", 0, Vec::new()),
+            ("compact left_coarse", 0, Vec::new()),
+            ("compact right_coarse", 0, Vec::new()),
+            ("compact left_peak_bands", 0, Vec::new()),
+            ("compact right_peak_bands", 0, Vec::new()),
+            ("channel_bins", p.channel_bins.len(), floats(&p.channel_bins)),
+            ("channel_peaks", p.channel_peaks.len(), floats(&p.channel_peaks)),
+            ("channel_rms", p.channel_rms.len(), floats(&p.channel_rms)),
+            ("plugin_version", 5, b"0.5.0".to_vec()),
+            ("channel_labels", 4, b"Kick".to_vec()),
+            ("channel_labels", 7, "Bass é".as_bytes().to_vec()),
+        ];
+        let mut offset = 288;
+        for (field, count, elements) in arrays {
+            at(offset, &(count as u32).to_le_bytes(), field);
+            at(offset + 4, &elements, field);
+            offset += 4 + elements.len();
+        }
+        assert_eq!(offset + CHECKSUM_BYTES, bytes.len(), "the checksum right after the arrays");
+        at(offset, &checksum::crc32(&bytes[..offset]).to_le_bytes(), "checksum");
+
+        let decoded = AudioPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!((decoded.timing, decoded.metrics, decoded.clicks), (p.timing, p.metrics, p.clicks));
+        assert_eq!(decoded.link, p.link);
+        assert_eq!(decoded.display_label, "Bass é");
+
+        // Unset, the optional fields keep their bytes, zeroed
+        let unset = AudioPacket { timing: None, metrics: None, clicks: None, link: None, ..p };
+        let bytes = unset.to_bytes();
+        assert!(bytes[62..79]
+            .iter()
+            .chain(&bytes[86..108])
+            .chain(&bytes[142..159])
+            .chain(&bytes[258..279])
+            .all(|&b| b == 0));
+        let decoded = AudioPacket::from_bytes(&bytes).unwrap();
+        assert!(decoded.timing.is_none() && decoded.metrics.is_none() && decoded.clicks.is_none());
+        assert!(decoded.link.is_none());
+    }
+
+    #[test]
+    fn test_malformed_fields_are_named() {
+        let resealed = |payload: &[u8]| {
+            let mut bytes = payload.to_vec();
+            bytes.extend_from_slice(&checksum::crc32(payload).to_le_bytes());
+            AudioPacket::from_bytes(&bytes)
+        };
+        let malformed = |result: Result<AudioPacket, HardwaveError>| match result {
+            Err(HardwaveError::Protocol(ProtocolError::Malformed(field))) => field,
+            other => panic!("not malformed: {:?}", other.map(|p| p.packet_type)),
+        };
+        let bytes = every_field().to_bytes();
+        let payload = &bytes[..bytes.len() - CHECKSUM_BYTES];
+
+        // A bool that isn't one, a label that isn't UTF-8, a count past the
+        // end, and arrays cut short
+        let mut odd = payload.to_vec();
+        odd[49] = 2;
+        assert_eq!(malformed(resealed(&odd)), "editor_open");
+        let label = payload.windows(7).position(|w| w == "Bass é".as_bytes()).unwrap();
+        let mut odd = payload.to_vec();
+        odd[label + 5] = 0xFF;
+        assert_eq!(malformed(resealed(&odd)), "display_label");
+        let mut odd = payload.to_vec();
+        odd[288..292].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(malformed(resealed(&odd)), "left_bins");
+        assert_eq!(malformed(resealed(&payload[..payload.len() - 1])), "channel_labels");
+        assert_eq!(malformed(resealed(&payload[..100])), "scalars");
+        assert_eq!(malformed(AudioPacket::from_bytes(&bytes[..2])), "protocol_version");
+        assert_eq!(malformed(AudioPacket::from_bytes(&bytes[..3])), "checksum");
+    }
+
+    #[test]
+    fn test_legacy_bincode_packets_still_decode() {
+        // A packet as protocol version 10 sent it: bincode of the fields it
+        // had, all but `compact_levels`, `instance_tag`, the channel arrays,
+        // the hello's metadata, `link`, `disabled`, the transition counts
+        // and `channel_labels`, then the checksum; those read as their
+        // defaults
+        let packet = AudioPacket {
+            instance_tag: 0,
+            channel_bins: Vec::new(),
+            channel_peaks: Vec::new(),
+            channel_rms: Vec::new(),
+            fft_size: 0,
+            band_count: 0,
+            channel_mode: 0,
+            plugin_version: String::new(),
+            link: None,
+            disabled: false,
+            state_transitions: 0,
+            rejected_transitions: 0,
+            channel_labels: Default::default(),
+            ..every_field()
+        };
+        let old = AudioPacket { protocol_version: LEGACY_LAYOUT_VERSION, ..packet.clone() };
+        let mut legacy = bincode::serialize(&old).unwrap();
+        legacy.truncate(legacy.len() - LEGACY_DEFAULTS.len());
+        legacy.extend_from_slice(&checksum::crc32(&legacy).to_le_bytes());
+        assert_eq!(legacy[1..3], LEGACY_LAYOUT_VERSION.to_le_bytes());
+
+        let decoded = AudioPacket::from_bytes(&legacy).unwrap();
+        assert_eq!(decoded.protocol_version, LEGACY_LAYOUT_VERSION);
+        assert_eq!(decoded.to_bytes(), packet.to_bytes(), "sent on in the new layout");
+        assert_eq!(AudioPacket::from_bytes_legacy(&legacy).unwrap().to_bytes(), packet.to_bytes());
+
+        // The legacy reader reads nothing else, and checks the checksum too
+        assert!(matches!(
+            AudioPacket::from_bytes_legacy(&packet.to_bytes()),
+            Err(HardwaveError::Protocol(ProtocolError::UnknownVersion(LAYOUT_VERSION)))
+        ));
+        let last = legacy.len() - 1;
+        legacy[last] ^= 1;
+        assert!(matches!(
+            AudioPacket::from_bytes(&legacy),
+            Err(HardwaveError::Protocol(ProtocolError::Checksum { .. }))
+        ));
+    }
+
     #[test]
     fn test_packet_roundtrip() {
-        let packet = AudioPacket::new_fft(
-            48000,
-            12345,
-            vec![-60.0; NUM_BINS],
-            vec![-60.0; NUM_BINS],
-            -3.0,
-            -3.0,
-            0.5,
-            0.5,
-            vec![0.0; WAVE_SIZE],
-            vec![0.0; WAVE_SIZE],
-        );
+        let packet = test_frame(12345);
 
         let bytes = packet.to_bytes();
         let decoded = AudioPacket::from_bytes(&bytes).unwrap();
@@ -134,24 +1980,238 @@ mod tests {
         assert_eq!(decoded.left_bins.len(), NUM_BINS);
     }
 
+    #[test]
+    fn test_fields_added_later_dont_stop_older_decoders() {
+        // A decoder skips the scalars after the ones it knows and ignores
+        // what follows its arrays, so to one from before the band peaks
+        // these bytes are a packet with two arrays more, just as this packet
+        // with a scalar and an array more is to this build
+        let packet = test_frame(7).with_peak_bands(vec![-3.0; 8], vec![-4.0; 8]);
+        let bytes = packet.to_bytes();
+        let scalars_end = HEADER_BYTES + SCALAR_BYTES;
+        let mut newer = bytes[..scalars_end].to_vec();
+        newer[3..5].copy_from_slice(&(SCALAR_BYTES as u16 + 3).to_le_bytes());
+        newer.extend_from_slice(&[0xAB; 3]);
+        newer.extend_from_slice(&bytes[scalars_end..bytes.len() - CHECKSUM_BYTES]);
+        newer.extend_from_slice(&[2, 0, 0, 0, 0xCD, 0xCD]);
+        // The checksum stays last, over the fields this build doesn't know
+        newer.extend_from_slice(&checksum::crc32(&newer).to_le_bytes());
+        let decoded = AudioPacket::from_bytes(&newer).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.right_peak_bands, vec![-4.0; 8]);
+
+        // Fewer scalars than this build knows is a broken packet, not zeros
+        let mut older = bytes[..scalars_end - 4].to_vec();
+        older[3..5].copy_from_slice(&(SCALAR_BYTES as u16 - 4).to_le_bytes());
+        older.extend_from_slice(&bytes[scalars_end..bytes.len() - CHECKSUM_BYTES]);
+        older.extend_from_slice(&checksum::crc32(&older).to_le_bytes());
+        assert!(matches!(
+            AudioPacket::from_bytes(&older),
+            Err(HardwaveError::Protocol(ProtocolError::Malformed("rejected_transitions")))
+        ));
+    }
+
+    #[test]
+    fn test_other_layout_versions_are_rejected() {
+        let bytes = test_frame(7).to_bytes();
+        assert_eq!(bytes[0], PACKET_TYPE_FFT);
+        assert_eq!(u16::from_le_bytes([bytes[1], bytes[2]]), LAYOUT_VERSION);
+
+        // A future layout, whatever its fields: an error, not a packet
+        // read from the wrong offsets
+        let mut future = bytes.clone();
+        future[1..3].copy_from_slice(&(LAYOUT_VERSION + 1).to_le_bytes());
+        future.truncate(40);
+        match AudioPacket::from_bytes(&future) {
+            Err(HardwaveError::Protocol(ProtocolError::UnknownVersion(version))) => {
+                assert_eq!(version, LAYOUT_VERSION + 1)
+            }
+            other => panic!("decoded a future layout: {:?}", other.map(|p| p.packet_type)),
+        }
+        // and the layout of a plugin from before the field, whose sample
+        // rate begins where the version is now
+        let mut unversioned = vec![PACKET_TYPE_FFT];
+        unversioned.extend_from_slice(&48_000u32.to_le_bytes());
+        unversioned.extend_from_slice(&bytes[7..]);
+        assert!(matches!(
+            AudioPacket::from_bytes(&unversioned),
+            Err(HardwaveError::Protocol(ProtocolError::UnknownVersion(0xBB80)))
+        ));
+
+        // Too short to say is a malformed packet
+        assert!(matches!(
+            AudioPacket::from_bytes(&bytes[..2]),
+            Err(HardwaveError::Protocol(ProtocolError::Malformed(_)))
+        ));
+    }
+
+    #[test]
+    fn test_compact_levels_stay_within_a_quarter_db() {
+        // xorshift32 levels over the compact range and a little past it
+        let mut state = 0x9E37_79B9_u32;
+        let mut random_levels = |len: usize| -> Vec<f32> {
+            (0..len)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 17;
+                    state ^= state << 5;
+                    (state as f32 / u32::MAX as f32) * -125.0 + 2.0
+                })
+                .collect()
+        };
+        for _ in 0..50 {
+            let edges = vec![100.0; 17];
+            let packet = AudioPacket { compact_levels: true, ..test_frame(7) }
+                .with_bins(random_levels(NUM_BINS), random_levels(NUM_BINS))
+                .with_coarse(random_levels(16), random_levels(16), edges.clone())
+                .with_peak_bands(random_levels(16), random_levels(16));
+            let bytes = packet.to_bytes();
+            assert_eq!(bytes.len(), packet.encoded_len());
+            let decoded = AudioPacket::from_bytes(&bytes).unwrap();
+            assert!(decoded.compact_levels);
+            for (sent, read) in packet.levels().into_iter().zip(decoded.levels()) {
+                assert_eq!(sent.len(), read.len());
+                for (&sent, &read) in sent.iter().zip(read) {
+                    let expected = sent.clamp(COMPACT_FLOOR_DB, 0.0);
+                    assert!((read - expected).abs() <= COMPACT_STEP_DB / 2.0 + 1e-4, "{} read as {}", sent, read);
+                }
+            }
+            // Only the levels are compact, and they stay as they came
+            assert_eq!((&decoded.coarse_edges_hz, decoded.left_wave.len()), (&edges, WAVE_SIZE));
+            assert_eq!(decoded.to_bytes(), bytes);
+        }
+
+        assert_eq!(compact_level(f32::NAN), 0);
+        assert_eq!(compact_level(-200.0), 0);
+        assert_eq!(compact_level(3.0), MAX_COMPACT_LEVEL);
+        assert_eq!(compact_level_db(compact_level(-37.3)), -37.25);
+        assert_eq!(compact_level_db(u16::MAX), 0.0);
+    }
+
+    #[test]
+    fn test_compact_levels_shrink_fft_packets() {
+        let full = test_frame(7).to_bytes();
+        let compact = AudioPacket { compact_levels: true, ..test_frame(7) }.to_bytes();
+        assert!(compact.len() * 10 < full.len() * 7, "{} bytes against {}", compact.len(), full.len());
+        // The float levels are empty; a decoder that doesn't know the flag
+        // reads a frame without bins
+        assert_eq!(compact[HEADER_BYTES + SCALAR_BYTES..][..4], [0u8; 4]);
+        assert!(!AudioPacket::from_bytes(&full).unwrap().compact_levels);
+    }
+
+    #[test]
+    fn test_checksum_catches_a_changed_byte() {
+        let packet = test_frame(7);
+        let bytes = packet.to_bytes();
+        assert_eq!(bytes.capacity(), bytes.len(), "sized exactly, never grown");
+        let decoded = AudioPacket::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.to_bytes(), bytes);
+
+        let rejected = |data: &[u8]| {
+            matches!(AudioPacket::from_bytes(data), Err(HardwaveError::Protocol(ProtocolError::Checksum { .. })))
+        };
+        // A level a proxy garbled, anywhere after the version; and the
+        // checksum itself
+        for at in [3, 40, bytes.len() / 2, bytes.len() - CHECKSUM_BYTES - 1, bytes.len() - 1] {
+            let mut corrupt = bytes.clone();
+            corrupt[at] ^= 0x10;
+            assert!(rejected(&corrupt), "byte {} changed unnoticed", at);
+        }
+        // and a packet cut short
+        assert!(rejected(&bytes[..bytes.len() - 100]));
+    }
+
+    #[test]
+    fn test_hello_states_the_versions() {
+        let hints = DisplayHints { instance_id: "abc".to_string(), ..DisplayHints::default() };
+        let decoded = AudioPacket::from_bytes(&AudioPacket::new_hello().with_display(&hints).to_bytes()).unwrap();
+        assert_eq!(decoded.packet_type, PACKET_TYPE_HELLO);
+        assert_eq!((decoded.protocol_version, decoded.supported_version), (LAYOUT_VERSION, PROTOCOL_VERSION));
+        assert_eq!(decoded.instance_id, "abc");
+        assert_eq!(decoded.coalesce_key(), Some(PACKET_TYPE_HELLO), "only the newest metadata matters");
+    }
+
+    #[test]
+    fn test_loop_packet_roundtrip() {
+        use crate::transport_loop::LoopRange;
+
+        let range = LoopRange { start: 96_000, end: 480_100 };
+        let packet = AudioPacket::new_loop(48000, 9000, &LoopEvent::Detected { range, wraps: 2 }, 1);
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(decoded.packet_type, PACKET_TYPE_LOOP_DETECTED);
+        assert_eq!(decoded.coalesce_key(), None, "an event, never coalesced");
+        assert_eq!((decoded.loop_start_samples, decoded.loop_end_samples), (96_000, 480_100));
+        assert_eq!((decoded.looping, decoded.loop_wraps, decoded.loop_policy), (true, 2, 1));
+
+        let ended = AudioPacket::new_loop(48000, 9500, &LoopEvent::Ended { range, wraps: 4 }, 1);
+        assert!(!ended.looping);
+    }
+
+    #[test]
+    fn test_every_packet_type_fits_the_heap_cap() {
+        let bins = vec![0.0; NUM_BINS * ZeroPad::MAX_FACTOR];
+        let largest = AudioPacket::fft(48000, 0)
+            .with_bins(bins.clone(), bins)
+            .with_wave(vec![0.0; WAVE_SIZE], vec![0.0; WAVE_SIZE]);
+        assert!(largest.heap_bytes() <= MAX_PACKET_HEAP_BYTES);
+
+        let raw = crate::raw_spectrum::packet_for(&largest);
+        assert!(raw.heap_bytes() < largest.heap_bytes());
+        let mut waveform = AudioPacket::new_waveform(48000, 0);
+        waveform.left_envelope = vec![0.0; 2 * crate::envelope::POINTS];
+        waveform.right_envelope = vec![0.0; 2 * crate::envelope::POINTS];
+        assert!(waveform.heap_bytes() < largest.heap_bytes());
+        assert_eq!(waveform.coalesce_key(), None, "every one covers its own samples");
+    }
+
     #[test]
     fn test_packet_size() {
-        let packet = AudioPacket::new_fft(
-            48000,
-            0,
-            vec![-60.0; NUM_BINS],
-            vec![-60.0; NUM_BINS],
-            -3.0,
-            -3.0,
-            0.5,
-            0.5,
-            vec![0.0; WAVE_SIZE],
-            vec![0.0; WAVE_SIZE],
-        );
+        let packet = test_frame(0);
 
         let bytes = packet.to_bytes();
         // 2048 bins × 2 channels × 4 bytes + 512 wave × 2 channels × 4 bytes + overhead ≈ 20.5 KB
         assert!(bytes.len() < 24_000, "Packet too large: {} bytes", bytes.len());
         println!("Packet size: {} bytes", bytes.len());
     }
+
+    #[test]
+    fn test_level_sanitization() {
+        assert_eq!(Db::new(f32::NAN), Db::FLOOR);
+        assert_eq!(Db::new(f32::NEG_INFINITY), Db::FLOOR);
+        assert_eq!(Db::new(f32::INFINITY).get(), 0.0);
+        assert_eq!(Db::new(-12.5).get(), -12.5);
+
+        assert_eq!(LinearLevel::new(f32::NAN), LinearLevel::SILENT);
+        assert_eq!(LinearLevel::new(-0.5), LinearLevel::SILENT);
+        assert_eq!(LinearLevel::new(f32::INFINITY).get(), 1.0);
+        assert_eq!(LinearLevel::new(0.25).get(), 0.25);
+
+        let packet = AudioPacket::fft(48000, 0)
+            .with_levels(ChannelLevels::new(f32::NAN, f32::NAN), ChannelLevels::new(3.0, 2.0));
+        assert_eq!((packet.left_peak, packet.left_rms), (-100.0, 0.0));
+        assert_eq!((packet.right_peak, packet.right_rms), (0.0, 1.0));
+        assert_eq!(packet.right_peak_meter, 0.0);
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_deprecated_constructor_matches_builder() {
+        let legacy = AudioPacket::new_fft(
+            48000,
+            777,
+            vec![-60.0; NUM_BINS],
+            vec![-50.0; NUM_BINS],
+            -3.0,
+            -6.0,
+            0.5,
+            0.25,
+            vec![0.1; WAVE_SIZE],
+            vec![-0.1; WAVE_SIZE],
+        );
+        let built = AudioPacket::fft(48000, 777)
+            .with_bins(vec![-60.0; NUM_BINS], vec![-50.0; NUM_BINS])
+            .with_levels(ChannelLevels::new(-3.0, 0.5), ChannelLevels::new(-6.0, 0.25))
+            .with_wave(vec![0.1; WAVE_SIZE], vec![-0.1; WAVE_SIZE]);
+        assert_eq!(legacy.to_bytes(), built.to_bytes());
+    }
 }
@@ -0,0 +1,144 @@
+//! Raw-bin spectrum packets for high-resolution displays
+//!
+//! The coarse bands are too few for the Suite's spectrogram view, and full
+//! FFT packets carry every bin as an `f32`. With Raw Spectrum Packets on,
+//! the plugin also sends `PACKET_TYPE_SPECTRUM_RAW` packets at the Raw
+//! Spectrum Rate: every bin of the frame, `FFT_SIZE / 2` per channel times
+//! the zero padding (half that in eco mode), one byte each, in
+//! `left_spectrum_raw` and `right_spectrum_raw`. A quarter of the size.
+//!
+//! A byte is the level in `STEP_DB` (0.5 dB) steps up from `FLOOR_DB`
+//! (-120 dB): 0 is -120 dB or lower, 240 is 0 dB, and 241..=255 are never
+//! sent. Decoded, a level is within half a step, 0.25 dB, of what the FFT
+//! read. Packets follow the frames, so the rate is at most the frame rate,
+//! and none go out while the history is frozen or nobody subscribes to
+//! `fft`.
+
+use crate::protocol::AudioPacket;
+
+/// Level of byte 0, dB
+pub const FLOOR_DB: f32 = -120.0;
+
+/// dB per byte step
+pub const STEP_DB: f32 = 0.5;
+
+/// Byte of 0 dB, the loudest sent
+pub const MAX_BYTE: u8 = 240;
+
+/// Highest and default Raw Spectrum Rate, Hz
+pub const MAX_RATE_HZ: i32 = 20;
+pub const DEFAULT_RATE_HZ: i32 = 10;
+
+/// `db` as a byte; NaN reads as the floor
+pub fn quantize(db: f32) -> u8 {
+    if db.is_nan() {
+        return 0;
+    }
+    ((db - FLOOR_DB) / STEP_DB).round().clamp(0.0, f32::from(MAX_BYTE)) as u8
+}
+
+/// The level a byte stands for, dB
+#[cfg(test)]
+pub fn dequantize(byte: u8) -> f32 {
+    FLOOR_DB + f32::from(byte.min(MAX_BYTE)) * STEP_DB
+}
+
+/// Decides which frames a raw spectrum packet goes out with
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RawSpectrumPacer {
+    /// Packets owed, at most one; a packet goes with the frame nearest
+    /// each due time, so the credit can be half one in the red
+    credit: f32,
+    last_ms: Option<u64>,
+}
+
+impl RawSpectrumPacer {
+    /// Whether the frame stamped `timestamp_ms` takes a packet, at
+    /// `rate_hz`. The first frame does; after that they follow the rate on
+    /// average, whatever the frame interval.
+    pub fn due(&mut self, timestamp_ms: u64, rate_hz: f32) -> bool {
+        let elapsed_s = self.last_ms.map_or(1.0, |last| timestamp_ms.saturating_sub(last) as f32 / 1000.0);
+        self.last_ms = Some(timestamp_ms);
+        self.credit = (self.credit + elapsed_s * rate_hz).min(1.0);
+        if self.credit < 0.5 {
+            return false;
+        }
+        self.credit -= 1.0;
+        true
+    }
+
+    /// Start over, as for a fresh stream
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+/// The raw spectrum packet of the FFT packet `frame`, with its bins,
+/// timestamps, padding and slot
+pub fn packet_for(frame: &AudioPacket) -> AudioPacket {
+    let quantized = |bins: &[f32]| bins.iter().map(|&db| quantize(db)).collect();
+    let mut packet = AudioPacket::new_spectrum_raw(frame.sample_rate, frame.timestamp_ms);
+    packet.left_spectrum_raw = quantized(&frame.left_bins);
+    packet.right_spectrum_raw = quantized(&frame.right_bins);
+    packet.zero_pad = frame.zero_pad;
+    packet.eco_mode = frame.eco_mode;
+    packet.captured_us = frame.captured_us;
+    packet.analysis_slot = frame.analysis_slot;
+    packet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{NUM_BINS, PACKET_TYPE_SPECTRUM_RAW};
+
+    #[test]
+    fn test_quantization_round_trip() {
+        for tenth in -1300..=50 {
+            let db = tenth as f32 / 10.0;
+            let decoded = dequantize(quantize(db));
+            let expected = db.clamp(FLOOR_DB, 0.0);
+            assert!((decoded - expected).abs() <= STEP_DB / 2.0 + 1e-4, "{} dB read back as {}", db, decoded);
+        }
+        assert_eq!(quantize(0.0), MAX_BYTE);
+        assert_eq!(quantize(-120.0), 0);
+        assert_eq!(quantize(f32::NAN), 0);
+        assert_eq!(quantize(f32::NEG_INFINITY), 0);
+        assert_eq!(dequantize(255), 0.0);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let bins: Vec<f32> = (0..NUM_BINS).map(|i| -(i as f32) * 0.05).collect();
+        let frame = AudioPacket::fft(48_000, 1234).with_bins(bins.clone(), bins.iter().map(|db| db - 6.0).collect());
+        let packet = packet_for(&frame);
+        assert_eq!(packet.packet_type, PACKET_TYPE_SPECTRUM_RAW);
+        assert!(packet.left_bins.is_empty() && packet.left_wave.is_empty(), "only the bytes are sent");
+        assert!(packet.to_bytes().len() < frame.to_bytes().len() / 3);
+
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(decoded.timestamp_ms, 1234);
+        assert_eq!(decoded.left_spectrum_raw.len(), NUM_BINS);
+        for (i, (&left, &right)) in decoded.left_spectrum_raw.iter().zip(&decoded.right_spectrum_raw).enumerate() {
+            assert!((dequantize(left) - bins[i].max(FLOOR_DB)).abs() <= 0.25 + 1e-4);
+            assert!((dequantize(right) - (bins[i] - 6.0).max(FLOOR_DB)).abs() <= 0.25 + 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_pacer_follows_the_rate() {
+        // 20 frames a second, 50 ms apart give or take a few
+        let mut pacer = RawSpectrumPacer::default();
+        let stamps = (0..200u64).map(|i| i * 50 + (i * 7) % 5);
+        let sent = stamps.clone().filter(|&ms| pacer.due(ms, 10.0)).count();
+        assert!((99..=101).contains(&sent), "{} packets in 10 s at 10 Hz", sent);
+
+        let mut pacer = RawSpectrumPacer::default();
+        assert_eq!(stamps.filter(|&ms| pacer.due(ms, 20.0)).count(), 200, "at the frame rate, every frame");
+
+        // The first after a reset goes at once
+        pacer.reset();
+        assert!(pacer.due(1_000_000, 1.0));
+        assert!(!pacer.due(1_000_050, 1.0));
+    }
+}
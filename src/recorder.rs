@@ -0,0 +1,402 @@
+//! Recording of the outgoing packet stream, and replay of recordings.
+//!
+//! A recording is a sequence of files under the Hardwave data dir. Each file
+//! starts with `MAGIC`, followed by records of
+//!
+//! ```text
+//! wall_ms: u64 LE    Unix time the packet was sent
+//! stream_us: u64 LE  plugin monotonic clock at send
+//! len: u32 LE
+//! payload: [u8; len] the packet exactly as sent
+//! ```
+//!
+//! Each instance writes its own files, named for the process and the
+//! instance's tag (`packets-<pid>-<tag>.hwrec`), so instances in one host,
+//! or the same project open in two, don't write over each other. Files are
+//! capped at a size and rotated (`packets-<pid>-<tag>.hwrec` is the newest,
+//! `packets-<pid>-<tag>.1.hwrec` the one before, ...), so a forgotten
+//! recording can't fill the disk.
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tungstenite::protocol::WebSocket;
+use tungstenite::Message;
+
+/// File header identifying the format version
+pub const MAGIC: &[u8; 8] = b"HWREC\x01\0\0";
+
+/// Size at which the current file is rotated
+pub const DEFAULT_MAX_FILE_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Write buffer of an active recording
+pub const RECORDER_BUFFER_BYTES: usize = 8 * 1024;
+
+/// Rotated files kept besides the current one
+pub const KEEP_ROTATED: usize = 3;
+
+const FILE_STEM: &str = "packets";
+const FILE_EXT: &str = "hwrec";
+
+/// Where recordings are written by default.
+pub fn default_dir() -> Option<PathBuf> {
+    dirs::data_local_dir().map(|d| d.join("Hardwave").join("recordings"))
+}
+
+/// Name of the recording files of the instance tagged `instance_tag` in
+/// this process
+pub fn file_stem(instance_tag: u32) -> String {
+    format!("{}-{}-{:08x}", FILE_STEM, std::process::id(), instance_tag)
+}
+
+/// Path of the current (`index` 0) or a rotated recording file.
+pub fn file_path(dir: &Path, stem: &str, index: usize) -> PathBuf {
+    if index == 0 {
+        dir.join(format!("{}.{}", stem, FILE_EXT))
+    } else {
+        dir.join(format!("{}.{}.{}", stem, index, FILE_EXT))
+    }
+}
+
+/// One recorded packet
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub wall_ms: u64,
+    pub stream_us: u64,
+    pub payload: Vec<u8>,
+}
+
+/// Writes packets to size-capped, rotating recording files while enabled.
+pub struct PacketRecorder {
+    dir: Option<PathBuf>,
+    /// See `file_stem`
+    stem: String,
+    max_file_bytes: u64,
+    writer: Option<BufWriter<File>>,
+    written: u64,
+}
+
+impl PacketRecorder {
+    pub fn new(dir: Option<PathBuf>, max_file_bytes: u64) -> Self {
+        Self {
+            dir,
+            stem: file_stem(0),
+            max_file_bytes,
+            writer: None,
+            written: 0,
+        }
+    }
+
+    /// Write the files of the instance tagged `instance_tag`, from the next
+    /// start
+    pub fn set_instance(&mut self, instance_tag: u32) {
+        self.stem = file_stem(instance_tag);
+    }
+
+    /// Path of the current (`index` 0) or a rotated file of this recorder's;
+    /// None without a data directory
+    #[cfg(test)]
+    pub fn file_path(&self, index: usize) -> Option<PathBuf> {
+        self.dir.as_deref().map(|dir| file_path(dir, &self.stem, index))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.writer.is_some()
+    }
+
+    /// Heap bytes of the write buffer; 0 while not recording
+    pub fn buffer_bytes(&self) -> usize {
+        self.writer.as_ref().map_or(0, BufWriter::capacity)
+    }
+
+    /// Start or stop recording. Each start begins a new file; the previous
+    /// session moves to the rotated slots.
+    pub fn set_enabled(&mut self, enabled: bool) -> io::Result<()> {
+        match (enabled, self.writer.take()) {
+            (true, None) => self.open(),
+            (true, Some(writer)) => {
+                self.writer = Some(writer);
+                Ok(())
+            }
+            (false, Some(mut writer)) => writer.flush(),
+            (false, None) => Ok(()),
+        }
+    }
+
+    /// Append a packet if recording. A failed write stops the recording.
+    pub fn record(&mut self, stream_us: u64, payload: &[u8]) -> io::Result<()> {
+        if self.writer.is_none() {
+            return Ok(());
+        }
+        let len = 20 + payload.len() as u64;
+        if self.written + len > self.max_file_bytes && self.written > MAGIC.len() as u64 {
+            self.writer = None;
+            self.open()?;
+        }
+
+        let wall_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis() as u64;
+        let Some(writer) = self.writer.as_mut() else {
+            return Ok(());
+        };
+        let result = writer
+            .write_all(&wall_ms.to_le_bytes())
+            .and_then(|_| writer.write_all(&stream_us.to_le_bytes()))
+            .and_then(|_| writer.write_all(&(payload.len() as u32).to_le_bytes()))
+            .and_then(|_| writer.write_all(payload));
+        match result {
+            Ok(()) => {
+                self.written += len;
+                Ok(())
+            }
+            Err(e) => {
+                self.writer = None;
+                Err(e)
+            }
+        }
+    }
+
+    fn open(&mut self) -> io::Result<()> {
+        let dir = self
+            .dir
+            .as_deref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no data directory for recordings"))?;
+        fs::create_dir_all(dir)?;
+        rotate(dir, &self.stem)?;
+
+        let mut writer = BufWriter::with_capacity(RECORDER_BUFFER_BYTES, File::create(file_path(dir, &self.stem, 0))?);
+        writer.write_all(MAGIC)?;
+        self.writer = Some(writer);
+        self.written = MAGIC.len() as u64;
+        Ok(())
+    }
+}
+
+impl Drop for PacketRecorder {
+    fn drop(&mut self) {
+        if let Some(writer) = self.writer.as_mut() {
+            let _ = writer.flush();
+        }
+    }
+}
+
+/// Shift every file named `stem` one slot older, dropping the oldest.
+fn rotate(dir: &Path, stem: &str) -> io::Result<()> {
+    let _ = fs::remove_file(file_path(dir, stem, KEEP_ROTATED));
+    for index in (0..KEEP_ROTATED).rev() {
+        let from = file_path(dir, stem, index);
+        if from.exists() {
+            fs::rename(&from, file_path(dir, stem, index + 1))?;
+        }
+    }
+    Ok(())
+}
+
+/// Read every record of one recording file.
+pub fn read_file(path: &Path) -> io::Result<Vec<Record>> {
+    read_records(BufReader::new(File::open(path)?))
+}
+
+/// Read records from a recording stream. A record cut short at the end (the
+/// plugin was killed mid-write) is dropped.
+pub fn read_records(mut reader: impl Read) -> io::Result<Vec<Record>> {
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if magic != *MAGIC {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not a Hardwave packet recording"));
+    }
+
+    let mut records = Vec::new();
+    let mut header = [0u8; 20];
+    loop {
+        match reader.read_exact(&mut header) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+        let wall_ms = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let stream_us = u64::from_le_bytes(header[8..16].try_into().unwrap());
+        let len = u32::from_le_bytes(header[16..20].try_into().unwrap()) as usize;
+
+        let mut payload = vec![0u8; len];
+        match reader.read_exact(&mut payload) {
+            Ok(()) => records.push(Record {
+                wall_ms,
+                stream_us,
+                payload,
+            }),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(records)
+}
+
+/// Connect to a Suite (or anything else listening) on `port` like the
+/// plugin would.
+pub fn connect(port: u16) -> io::Result<WebSocket<TcpStream>> {
+    let stream = TcpStream::connect(("127.0.0.1", port))?;
+    let url = format!("ws://127.0.0.1:{}/", port);
+    tungstenite::client(url.as_str(), stream)
+        .map(|(socket, _)| socket)
+        .map_err(|e| io::Error::other(e.to_string()))
+}
+
+/// Send recorded packets over `socket`, sleeping between them to reproduce
+/// the original timing when `realtime` is set.
+pub fn replay(records: &[Record], socket: &mut WebSocket<TcpStream>, realtime: bool) -> io::Result<()> {
+    let mut previous_us = None;
+    for record in records {
+        if let (true, Some(prev)) = (realtime, previous_us) {
+            thread::sleep(Duration::from_micros(record.stream_us.saturating_sub(prev)));
+        }
+        previous_us = Some(record.stream_us);
+        socket
+            .send(Message::Binary(record.payload.clone()))
+            .map_err(|e| io::Error::other(e.to_string()))?;
+    }
+    socket.flush().map_err(|e| io::Error::other(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::AudioPacket;
+    use std::net::TcpListener;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hardwave-rec-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn session() -> Vec<(u64, Vec<u8>)> {
+        (0..20)
+            .map(|i| {
+                let packet = if i % 5 == 0 {
+                    AudioPacket::new_heartbeat(0, i)
+                } else {
+                    AudioPacket::fft(48000, i * 50)
+                };
+                (1_000 + i * 2_000, packet.to_bytes())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_record_read_and_replay() {
+        let dir = scratch_dir("roundtrip");
+        let mut recorder = PacketRecorder::new(Some(dir.clone()), DEFAULT_MAX_FILE_BYTES);
+        // Nothing is written until enabled
+        recorder.record(0, b"ignored").unwrap();
+        recorder.set_enabled(true).unwrap();
+        for (stream_us, bytes) in session() {
+            recorder.record(stream_us, &bytes).unwrap();
+        }
+        recorder.set_enabled(false).unwrap();
+
+        let records = read_file(&recorder.file_path(0).unwrap()).unwrap();
+        let payloads: Vec<Vec<u8>> = records.iter().map(|r| r.payload.clone()).collect();
+        let expected: Vec<Vec<u8>> = session().into_iter().map(|(_, b)| b).collect();
+        assert_eq!(payloads, expected);
+        assert!(records.windows(2).all(|w| w[0].stream_us < w[1].stream_us));
+        assert!(records.windows(2).all(|w| w[0].wall_ms <= w[1].wall_ms));
+
+        // Replay to a mock Suite and compare what arrives
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            let mut received = Vec::new();
+            while let Ok(msg) = ws.read() {
+                if let Message::Binary(data) = msg {
+                    received.push(data);
+                }
+            }
+            received
+        });
+        let mut socket = connect(port).unwrap();
+        let start = std::time::Instant::now();
+        replay(&records, &mut socket, true).unwrap();
+        assert!(start.elapsed() >= Duration::from_micros(19 * 2_000), "replay ignored timing");
+        let _ = socket.close(None);
+        let _ = socket.flush();
+        drop(socket);
+
+        assert_eq!(server.join().unwrap(), expected);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_rotation_caps_size() {
+        let dir = scratch_dir("rotation");
+        let payload = vec![7u8; 1000];
+        let mut recorder = PacketRecorder::new(Some(dir.clone()), 4_000);
+        recorder.set_enabled(true).unwrap();
+        for i in 0..40 {
+            recorder.record(i, &payload).unwrap();
+        }
+        recorder.set_enabled(false).unwrap();
+
+        for index in 0..=KEEP_ROTATED {
+            let size = fs::metadata(recorder.file_path(index).unwrap()).unwrap().len();
+            assert!(size <= 4_000, "file {} is {} bytes", index, size);
+        }
+        assert!(!recorder.file_path(KEEP_ROTATED + 1).unwrap().exists());
+
+        // The newest file holds the newest packets
+        let newest = read_file(&recorder.file_path(0).unwrap()).unwrap();
+        assert_eq!(newest.last().unwrap().stream_us, 39);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_instances_record_to_their_own_files() {
+        let dir = scratch_dir("instances");
+        let mut recorders = [1, 2].map(|tag| {
+            let mut recorder = PacketRecorder::new(Some(dir.clone()), DEFAULT_MAX_FILE_BYTES);
+            recorder.set_instance(tag);
+            recorder.set_enabled(true).unwrap();
+            recorder
+        });
+        for i in 0..10 {
+            recorders[0].record(i, b"first").unwrap();
+            recorders[1].record(i, b"second").unwrap();
+        }
+        for recorder in &mut recorders {
+            recorder.set_enabled(false).unwrap();
+        }
+
+        // Neither took over the other's file or rotated it away
+        let [first, second] = recorders.map(|recorder| read_file(&recorder.file_path(0).unwrap()).unwrap());
+        assert_eq!(first.len(), 10);
+        assert_eq!(second.len(), 10);
+        assert!(first.iter().all(|r| r.payload == b"first"));
+        assert!(second.iter().all(|r| r.payload == b"second"));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 2);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_truncated_and_foreign_files() {
+        let mut bytes = MAGIC.to_vec();
+        for (stream_us, payload) in [(1u64, b"abc".to_vec()), (2, b"defg".to_vec())] {
+            bytes.extend(0u64.to_le_bytes());
+            bytes.extend(stream_us.to_le_bytes());
+            bytes.extend((payload.len() as u32).to_le_bytes());
+            bytes.extend(payload);
+        }
+        bytes.truncate(bytes.len() - 2);
+        let records = read_records(bytes.as_slice()).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].payload, b"abc");
+
+        assert!(read_records(&b"not a recording"[..]).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! A reference track's spectrum next to the main input's
+//!
+//! To compare a mix with a reference, route the reference to the plugin's
+//! sidechain, its auxiliary stereo input, and turn on Reference Analysis
+//! (off by default: it doubles the FFT work). Every frame is then followed
+//! by a `PACKET_TYPE_REFERENCE` packet with the spectrum of the sidechain
+//! over the same window: `left_bins` and `right_bins`, and the coarse bands
+//! in `left_coarse`, `right_coarse` and `coarse_edges_hz`. The sidechain
+//! goes through its own decimators, resampler, weighting, ring buffers and
+//! FFTs, built like the main input's, in the same channel mode, so the
+//! Suite can draw one spectrum over the other bin for bin.
+//!
+//! The reference has no levels, meters, smoothing, peaks or activity, and
+//! with the interval average alignment it is the window at the send, not an
+//! average. A block with nothing routed to the sidechain empties its
+//! history, and a frame without a full window of it reads `FLOOR_DB`
+//! (-100 dB) in every bin and band, never what was routed before.
+//!
+//! Packets take the frame's timestamp, slot and padding, coalesce like FFT
+//! packets and go to a Suite subscribed to `fft`; the plugin window doesn't
+//! draw them. None go out while the history is frozen.
+
+use crate::bands;
+use crate::decimator::Decimator;
+use crate::fft::{FftProcessor, RingBuffer};
+use crate::protocol::AudioPacket;
+use crate::resampler::Resampler;
+use crate::units::FLOOR_DB;
+
+/// Everything the sidechain is analysed with, built by
+/// `AnalysisState::build` with the main input's when Reference Analysis is
+/// on
+pub struct ReferenceState {
+    pub fft_left: FftProcessor,
+    pub fft_right: FftProcessor,
+
+    pub decimator_left: Decimator,
+    pub decimator_right: Decimator,
+    pub resampler: Resampler,
+
+    /// The last `history_len` analysis-rate samples, empty while nothing is
+    /// routed
+    pub buffer_left: RingBuffer,
+    pub buffer_right: RingBuffer,
+}
+
+impl ReferenceState {
+    /// Append one analysis-rate sample per channel
+    pub fn buffer(&mut self, left: f32, right: f32) {
+        self.buffer_left.push(left);
+        self.buffer_right.push(right);
+    }
+
+    /// Drop buffered audio
+    pub fn clear(&mut self) {
+        self.buffer_left.clear();
+        self.buffer_right.clear();
+    }
+
+    /// Drop buffered audio and filter histories
+    pub fn reset(&mut self) {
+        self.clear();
+        self.decimator_left.reset();
+        self.decimator_right.reset();
+        self.resampler.reset();
+    }
+
+    /// The spectrum of the window `start..end` of the history, banded
+    /// over `edges`; the floor if the history doesn't reach back that far
+    pub fn frame(&mut self, start: usize, end: usize, rate: f32, zero_pad: usize, edges: &[f32]) -> ReferenceFrame {
+        if start >= end || self.buffer_left.len() < end {
+            let bins = vec![FLOOR_DB; self.fft_left.num_bins()];
+            let bands = vec![FLOOR_DB; edges.len().saturating_sub(1)];
+            return ReferenceFrame {
+                left_bins: bins.clone(),
+                right_bins: bins,
+                left_coarse: bands.clone(),
+                right_coarse: bands,
+            };
+        }
+        let left_bins = self.fft_left.process(&self.buffer_left.as_slice()[start..end], rate);
+        let right_bins = self.fft_right.process(&self.buffer_right.as_slice()[start..end], rate);
+        let bin_hz = rate / ((end - start) * zero_pad) as f32;
+        ReferenceFrame {
+            left_coarse: bands::coarse_db(&left_bins, bin_hz, zero_pad, edges),
+            right_coarse: bands::coarse_db(&right_bins, bin_hz, zero_pad, edges),
+            left_bins,
+            right_bins,
+        }
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.buffer_left.heap_bytes()
+            + self.buffer_right.heap_bytes()
+            + self.fft_left.heap_bytes()
+            + self.fft_right.heap_bytes()
+            + self.decimator_left.heap_bytes()
+            + self.decimator_right.heap_bytes()
+            + self.resampler.heap_bytes()
+    }
+}
+
+/// The sidechain's part of a frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReferenceFrame {
+    pub left_bins: Vec<f32>,
+    pub right_bins: Vec<f32>,
+    /// Over the frame's band edges
+    pub left_coarse: Vec<f32>,
+    pub right_coarse: Vec<f32>,
+}
+
+impl ReferenceFrame {
+    /// The reference packet following the FFT packet `frame`, with its
+    /// timestamps, rate, padding, slot and band edges
+    pub fn into_packet(self, frame: &AudioPacket) -> AudioPacket {
+        let mut packet = AudioPacket::new_reference(frame.sample_rate, frame.timestamp_ms)
+            .with_bins(self.left_bins, self.right_bins)
+            .with_coarse(self.left_coarse, self.right_coarse, frame.coarse_edges_hz.clone());
+        packet.zero_pad = frame.zero_pad;
+        packet.eco_mode = frame.eco_mode;
+        packet.captured_us = frame.captured_us;
+        packet.analysis_slot = frame.analysis_slot;
+        packet.compact_levels = frame.compact_levels;
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{NUM_BINS, PACKET_TYPE_REFERENCE};
+
+    #[test]
+    fn test_packet_round_trip() {
+        let mut frame = AudioPacket::fft(48_000, 1234).with_coarse(vec![-20.0; 8], vec![-21.0; 8], vec![100.0; 9]);
+        frame.zero_pad = 2;
+        frame.captured_us = 99;
+        frame.analysis_slot = 1;
+        let reference = ReferenceFrame {
+            left_bins: vec![-30.0; 2 * NUM_BINS],
+            right_bins: vec![-40.0; 2 * NUM_BINS],
+            left_coarse: vec![-50.0; 8],
+            right_coarse: vec![-60.0; 8],
+        };
+        let packet = reference.clone().into_packet(&frame);
+        assert_eq!(packet.packet_type, PACKET_TYPE_REFERENCE);
+        assert_eq!(packet.coalesce_key(), Some(PACKET_TYPE_REFERENCE), "only the newest matters");
+        assert!(packet.left_wave.is_empty(), "no waveform");
+
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.timestamp_ms, decoded.captured_us), (48_000, 1234, 99));
+        assert_eq!((decoded.zero_pad, decoded.analysis_slot), (2, 1));
+        assert_eq!(decoded.left_bins, reference.left_bins);
+        assert_eq!(decoded.right_bins, reference.right_bins);
+        assert_eq!(decoded.left_coarse, reference.left_coarse);
+        assert_eq!(decoded.right_coarse, reference.right_coarse);
+        assert_eq!(decoded.coarse_edges_hz, frame.coarse_edges_hz);
+    }
+}
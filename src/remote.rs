@@ -0,0 +1,203 @@
+//! Commands the Suite sends to steer the plugin
+//!
+//! The Suite's text messages are read on the connection thread between
+//! packets. Besides acknowledgements, subscriptions, dismissed warnings and
+//! history commands, it can send:
+//!
+//! - `{"type":"set_update_rate","hz":30}`: frames 30 times a second
+//!   instead of 20, clamped to `DEFAULT_UPDATE_RATE_HZ` to
+//!   `MAX_UPDATE_RATE_HZ`; the buffers are sized for 20, so never slower.
+//!   Like a bandwidth change it rebuilds the analysis state. Anything
+//!   counted in frames, band activity and the frame history, spans less
+//!   time. Every new connection starts at 20 again.
+//! - `{"type":"freeze"}` and `{"type":"freeze","frozen":false}`: the same
+//!   as `history.freeze` and `history.resume`, see `history`.
+//! - `{"type":"request_metadata"}`: the hello packet again, with the
+//!   display hints as they are now, for a Suite that lost track of them.
+//! - `{"type":"set_instance_name","name":"Drums"}`: renamed as from the
+//!   plugin window, and saved with the project; an empty name goes back to
+//!   the host's track name or the derived label. The plugin's next block
+//!   hands it to a background thread, which saves it, and the heartbeat
+//!   announcing it goes out at once.
+//!
+//! Messages of any other type, or whose arguments don't parse, are ignored.
+
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::history::HistoryCommand;
+
+/// Frames a second unless the Suite asks otherwise, and the slowest it can
+pub const DEFAULT_UPDATE_RATE_HZ: u32 = 20;
+
+/// The fastest the Suite can ask for
+pub const MAX_UPDATE_RATE_HZ: u32 = 60;
+
+/// A command in a text message from the Suite
+#[derive(Debug, Clone, PartialEq)]
+pub enum RemoteCommand {
+    /// Frames a second, clamped
+    SetUpdateRate(u32),
+    History(HistoryCommand),
+    RequestMetadata,
+    SetInstanceName(String),
+}
+
+#[derive(Deserialize)]
+struct CommandMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    hz: Option<f64>,
+    #[serde(default)]
+    frozen: Option<bool>,
+    #[serde(default)]
+    name: Option<String>,
+}
+
+/// The command in a text message from the Suite, if it is one
+pub fn parse_command(text: &str) -> Option<RemoteCommand> {
+    let message: CommandMessage = serde_json::from_str(text).ok()?;
+    match message.kind.as_str() {
+        "set_update_rate" => message.hz.filter(|hz| hz.is_finite()).map(|hz| {
+            let hz = hz.round().clamp(f64::from(DEFAULT_UPDATE_RATE_HZ), f64::from(MAX_UPDATE_RATE_HZ));
+            RemoteCommand::SetUpdateRate(hz as u32)
+        }),
+        "freeze" => Some(RemoteCommand::History(if message.frozen.unwrap_or(true) {
+            HistoryCommand::Freeze
+        } else {
+            HistoryCommand::Resume
+        })),
+        "request_metadata" => Some(RemoteCommand::RequestMetadata),
+        "set_instance_name" => message.name.map(RemoteCommand::SetInstanceName),
+        _ => None,
+    }
+}
+
+/// What the Suite last asked for; set by the connection thread, taken by
+/// the plugin and the connection thread
+#[derive(Debug)]
+pub struct RemoteControl {
+    update_rate: AtomicU32,
+    metadata_requested: AtomicBool,
+    instance_name: Mutex<Option<String>>,
+    /// Set with `instance_name`, so the audio thread can tell without the
+    /// lock
+    named: AtomicBool,
+}
+
+impl Default for RemoteControl {
+    fn default() -> Self {
+        Self {
+            update_rate: AtomicU32::new(DEFAULT_UPDATE_RATE_HZ),
+            metadata_requested: AtomicBool::new(false),
+            instance_name: Mutex::new(None),
+            named: AtomicBool::new(false),
+        }
+    }
+}
+
+impl RemoteControl {
+    /// A new connection starts at the default rate, with nothing pending
+    pub fn connected(&self) {
+        self.update_rate.store(DEFAULT_UPDATE_RATE_HZ, Ordering::Relaxed);
+        self.metadata_requested.store(false, Ordering::Relaxed);
+    }
+
+    /// Keep a command for whoever takes it; history commands are for the
+    /// `HistoryControl` and ignored here
+    pub fn apply(&self, command: RemoteCommand) {
+        match command {
+            RemoteCommand::SetUpdateRate(hz) => self.update_rate.store(hz, Ordering::Relaxed),
+            RemoteCommand::History(_) => {}
+            RemoteCommand::RequestMetadata => self.metadata_requested.store(true, Ordering::Relaxed),
+            // A newer name replaces one not taken yet
+            RemoteCommand::SetInstanceName(name) => {
+                *self.instance_name.lock() = Some(name);
+                self.named.store(true, Ordering::Release);
+            }
+        }
+    }
+
+    /// Frames a second the Suite wants
+    pub fn update_rate(&self) -> u32 {
+        self.update_rate.load(Ordering::Relaxed)
+    }
+
+    /// Connection thread: whether the metadata was asked for since the
+    /// last call
+    pub fn take_metadata_request(&self) -> bool {
+        self.metadata_requested.swap(false, Ordering::Relaxed)
+    }
+
+    /// Audio thread: whether the Suite named the instance since the last
+    /// call. The name is for `take_instance_name`, off the audio thread.
+    pub fn take_naming(&self) -> bool {
+        self.named.swap(false, Ordering::Acquire)
+    }
+
+    /// The name the Suite gave the instance since the last call. Waits for
+    /// the connection thread; not for the audio thread.
+    pub fn take_instance_name(&self) -> Option<String> {
+        self.instance_name.lock().take()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command(r#"{"type":"set_update_rate","hz":30}"#), Some(RemoteCommand::SetUpdateRate(30)));
+        assert_eq!(parse_command(r#"{"type":"set_update_rate","hz":29.6}"#), Some(RemoteCommand::SetUpdateRate(30)));
+        assert_eq!(parse_command(r#"{"type":"set_update_rate","hz":500}"#), Some(RemoteCommand::SetUpdateRate(60)));
+        assert_eq!(parse_command(r#"{"type":"set_update_rate","hz":1}"#), Some(RemoteCommand::SetUpdateRate(20)));
+        assert_eq!(parse_command(r#"{"type":"set_update_rate"}"#), None);
+        assert_eq!(parse_command(r#"{"type":"set_update_rate","hz":"fast"}"#), None);
+
+        assert_eq!(parse_command(r#"{"type":"freeze"}"#), Some(RemoteCommand::History(HistoryCommand::Freeze)));
+        assert_eq!(
+            parse_command(r#"{"type":"freeze","frozen":false}"#),
+            Some(RemoteCommand::History(HistoryCommand::Resume))
+        );
+        assert_eq!(parse_command(r#"{"type":"request_metadata"}"#), Some(RemoteCommand::RequestMetadata));
+        assert_eq!(
+            parse_command(r#"{"type":"set_instance_name","name":"Drums"}"#),
+            Some(RemoteCommand::SetInstanceName("Drums".to_string()))
+        );
+        assert_eq!(parse_command(r#"{"type":"set_instance_name"}"#), None);
+
+        // Unknown commands and anything else the Suite sends
+        assert_eq!(parse_command(r#"{"type":"self_destruct"}"#), None);
+        assert_eq!(parse_command(r#"{"type":"history.freeze"}"#), None);
+        assert_eq!(parse_command("not json"), None);
+    }
+
+    #[test]
+    fn test_commands_wait_to_be_taken() {
+        let control = RemoteControl::default();
+        assert_eq!(control.update_rate(), DEFAULT_UPDATE_RATE_HZ);
+        assert!(!control.take_metadata_request());
+        assert!(!control.take_naming());
+        assert_eq!(control.take_instance_name(), None);
+
+        control.apply(RemoteCommand::SetUpdateRate(40));
+        control.apply(RemoteCommand::RequestMetadata);
+        control.apply(RemoteCommand::SetInstanceName("Bass".to_string()));
+        control.apply(RemoteCommand::SetInstanceName("Drums".to_string()));
+        assert_eq!(control.update_rate(), 40);
+        assert!(control.take_metadata_request());
+        assert!(!control.take_metadata_request(), "taken once");
+        assert!(control.take_naming());
+        assert!(!control.take_naming(), "one task for both names");
+        assert_eq!(control.take_instance_name().as_deref(), Some("Drums"));
+        assert_eq!(control.take_instance_name(), None);
+
+        control.apply(RemoteCommand::RequestMetadata);
+        control.connected();
+        assert_eq!(control.update_rate(), DEFAULT_UPDATE_RATE_HZ);
+        assert!(!control.take_metadata_request());
+    }
+}
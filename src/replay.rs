@@ -0,0 +1,569 @@
+//! Deterministic replay of the analysis, against golden output
+//!
+//! Calibration work (power summing, window correction, band edges) changes
+//! what the engine emits on purpose; these tests say exactly what changed,
+//! and catch what wasn't meant to. Each case in `cases()` synthesises stereo
+//! input from a `Signal`, feeds it to a fresh `AnalysisEngine` with fixed
+//! settings in fixed-size blocks, and keeps what every frame carries except
+//! the wall clock. `compare` checks that against the golden file of the case
+//! in `replay_goldens/` at the crate root.
+//!
+//! A deliberate change blesses new goldens with
+//! `HARDWAVE_BLESS=1 cargo test replay` and commits them with it; the diff
+//! of the JSON is the review of the change.
+//!
+//! # Tolerances
+//!
+//! - Counters and shapes (the block a frame came out after, rates, sizes,
+//!   delays, bad samples, frame count) are exact
+//! - Bands, sampled bins and peaks within `DB_TOLERANCE`; bins near the
+//!   floor, where float noise dominates, within `AMPLITUDE_TOLERANCE` linear
+//! - RMS levels and band activity within `LEVEL_TOLERANCE`
+//! - Band edges within `EDGE_TOLERANCE`, relative
+
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::analysis::AnalysisConfig;
+use crate::engine::{AnalysisEngine, AnalysisFrame, EngineSettings};
+use crate::params::{AnalysisAlignment, ChannelMode, CoarseMapping, RmsIntegration, ZeroPad};
+use crate::remote::DEFAULT_UPDATE_RATE_HZ;
+use crate::units::db_to_linear;
+
+/// Set to write the goldens from this build before comparing
+pub const BLESS_ENV: &str = "HARDWAVE_BLESS";
+
+pub const DB_TOLERANCE: f32 = 0.1;
+pub const AMPLITUDE_TOLERANCE: f32 = 1e-5;
+pub const LEVEL_TOLERANCE: f32 = 1e-3;
+pub const EDGE_TOLERANCE: f32 = 1e-4;
+
+/// Bins kept per channel and frame; a whole spectrum per frame would make
+/// goldens nobody can review
+pub const SAMPLED_BINS: usize = 64;
+
+/// How a channel's input is made. Samples are computed in f64 from the
+/// sample index `i` and rate `sr`, and rounded to f32.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Signal {
+    Silence,
+
+    /// `amplitude * sin(2π * frequency_hz * i / sr)`
+    Sine { frequency_hz: f64, amplitude: f64 },
+
+    /// Exponential sweep over `seconds`, then held at `to_hz`: phase
+    /// `2π * from_hz * T / ln(k) * (k^(t/T) - 1)` with `t = i / sr`,
+    /// `T = seconds` and `k = to_hz / from_hz`
+    Sweep { from_hz: f64, to_hz: f64, seconds: f64, amplitude: f64 },
+
+    /// xorshift32 (`x ^= x << 13; x ^= x >> 17; x ^= x << 5`) from `seed`;
+    /// each sample is `((x >> 8) / 2^24 * 2 - 1) * amplitude`
+    Noise { seed: u32, amplitude: f64 },
+
+    /// `amplitude` at `offset`, `offset + interval`, ..., 0 elsewhere
+    Clicks { interval: usize, offset: usize, amplitude: f64 },
+
+    /// The sum of the signals
+    Mix { signals: Vec<Signal> },
+
+    /// `signal`, with NaN at `offset`, `offset + interval`, ...
+    Dropouts { signal: Box<Signal>, interval: usize, offset: usize },
+}
+
+impl Signal {
+    pub fn samples(&self, sample_rate: u32, len: usize) -> Vec<f32> {
+        self.samples_f64(f64::from(sample_rate), len).into_iter().map(|s| s as f32).collect()
+    }
+
+    fn samples_f64(&self, sr: f64, len: usize) -> Vec<f64> {
+        let every = |interval: usize, offset: usize, i: usize| i >= offset && (i - offset).is_multiple_of(interval);
+        match self {
+            Signal::Silence => vec![0.0; len],
+            Signal::Sine { frequency_hz, amplitude } => {
+                (0..len).map(|i| amplitude * (2.0 * PI * frequency_hz * i as f64 / sr).sin()).collect()
+            }
+            Signal::Sweep { from_hz, to_hz, seconds, amplitude } => {
+                let k = to_hz / from_hz;
+                let scale = 2.0 * PI * from_hz * seconds / k.ln();
+                let phase = |t: f64| scale * (k.powf(t / seconds) - 1.0);
+                (0..len)
+                    .map(|i| {
+                        let t = i as f64 / sr;
+                        let p = if t <= *seconds {
+                            phase(t)
+                        } else {
+                            phase(*seconds) + 2.0 * PI * to_hz * (t - seconds)
+                        };
+                        amplitude * p.sin()
+                    })
+                    .collect()
+            }
+            Signal::Noise { seed, amplitude } => {
+                let mut x = *seed;
+                (0..len)
+                    .map(|_| {
+                        x ^= x << 13;
+                        x ^= x >> 17;
+                        x ^= x << 5;
+                        (f64::from(x >> 8) / f64::from(1u32 << 24) * 2.0 - 1.0) * amplitude
+                    })
+                    .collect()
+            }
+            Signal::Clicks { interval, offset, amplitude } => {
+                (0..len).map(|i| if every(*interval, *offset, i) { *amplitude } else { 0.0 }).collect()
+            }
+            Signal::Mix { signals } => signals.iter().fold(vec![0.0; len], |mut sum, signal| {
+                for (sum, s) in sum.iter_mut().zip(signal.samples_f64(sr, len)) {
+                    *sum += s;
+                }
+                sum
+            }),
+            Signal::Dropouts { signal, interval, offset } => {
+                let mut samples = signal.samples_f64(sr, len);
+                for (i, sample) in samples.iter_mut().enumerate() {
+                    if every(*interval, *offset, i) {
+                        *sample = f64::NAN;
+                    }
+                }
+                samples
+            }
+        }
+    }
+}
+
+/// One replayed configuration
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Case {
+    pub name: String,
+    pub description: String,
+    /// Host rate, Hz
+    pub sample_rate: u32,
+    /// Samples per host block
+    pub block: usize,
+    pub blocks: usize,
+    /// Kept apart from `settings`, which don't save it
+    pub eco_mode: bool,
+    pub settings: EngineSettings,
+    pub left: Signal,
+    pub right: Signal,
+}
+
+impl Case {
+    pub fn engine_settings(&self) -> EngineSettings {
+        EngineSettings { eco_mode: self.eco_mode, ..self.settings }
+    }
+}
+
+/// What a frame carries, minus the wall clock
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct GoldenFrame {
+    /// Index of the block the frame came out after
+    pub block: usize,
+    pub analysis_rate: f32,
+    pub fft_size: usize,
+    pub zero_pad: usize,
+    pub num_bins: usize,
+    pub delay_ms: u64,
+    pub non_finite_samples: u32,
+    /// `SAMPLED_BINS` bins spread evenly over the spectrum, dB
+    pub left_bins: Vec<f32>,
+    pub right_bins: Vec<f32>,
+    pub left_coarse: Vec<f32>,
+    pub right_coarse: Vec<f32>,
+    pub coarse_edges: Vec<f32>,
+    pub band_activity: Vec<f32>,
+    /// dB
+    pub left_peak: f32,
+    pub right_peak: f32,
+    /// Linear
+    pub left_rms: f32,
+    pub right_rms: f32,
+    pub left_meter_peak: f32,
+    pub right_meter_peak: f32,
+    pub left_meter_rms: f32,
+    pub right_meter_rms: f32,
+}
+
+impl GoldenFrame {
+    pub fn new(block: usize, frame: &AnalysisFrame) -> Self {
+        Self {
+            block,
+            analysis_rate: frame.analysis_rate,
+            fft_size: frame.fft_size,
+            zero_pad: frame.zero_pad,
+            num_bins: frame.left_bins.len(),
+            delay_ms: frame.delay_ms,
+            non_finite_samples: frame.non_finite_samples,
+            left_bins: sample_bins(&frame.left_bins),
+            right_bins: sample_bins(&frame.right_bins),
+            left_coarse: frame.left_coarse.clone(),
+            right_coarse: frame.right_coarse.clone(),
+            coarse_edges: frame.coarse_edges.clone(),
+            band_activity: frame.band_activity.clone(),
+            left_peak: frame.left_levels.peak.get(),
+            right_peak: frame.right_levels.peak.get(),
+            left_rms: frame.left_levels.rms.get(),
+            right_rms: frame.right_levels.rms.get(),
+            left_meter_peak: frame.left_meters.peak.get(),
+            right_meter_peak: frame.right_meters.peak.get(),
+            left_meter_rms: frame.left_meters.rms.get(),
+            right_meter_rms: frame.right_meters.rms.get(),
+        }
+    }
+
+    /// The linear levels, in the order `frame_differences` reports them
+    fn levels(&self) -> Vec<f32> {
+        vec![self.left_rms, self.right_rms, self.left_meter_rms, self.right_meter_rms]
+    }
+}
+
+/// Every `len / SAMPLED_BINS`th bin, from bin 0
+fn sample_bins(bins: &[f32]) -> Vec<f32> {
+    let stride = (bins.len() / SAMPLED_BINS).max(1);
+    bins.iter().step_by(stride).take(SAMPLED_BINS).copied().collect()
+}
+
+/// One golden file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Golden {
+    pub case: Case,
+    pub frames: Vec<GoldenFrame>,
+}
+
+/// The configurations replayed by `cargo test`
+pub fn cases() -> Vec<Case> {
+    let case = |name: &str, description: &str, sample_rate, settings, left, right| Case {
+        name: name.to_string(),
+        description: description.to_string(),
+        sample_rate,
+        block: 512,
+        // A second and a bit at every rate here
+        blocks: (sample_rate as usize * 5 / 4).div_ceil(512),
+        eco_mode: false,
+        settings,
+        left,
+        right,
+    };
+    let tone = |frequency_hz, amplitude| Signal::Sine { frequency_hz, amplitude };
+    vec![
+        case(
+            "default_sine",
+            "Default settings; 1 kHz at -6 dBFS left, -12 dBFS right",
+            48_000,
+            EngineSettings::default(),
+            tone(1000.0, 0.5),
+            tone(1000.0, 0.25),
+        ),
+        case(
+            "zero_pad_noise",
+            "4x zero padding, perceptual bands, PPM integration; white noise left, a 440 Hz tone in it right",
+            48_000,
+            EngineSettings {
+                zero_pad: ZeroPad::X4,
+                coarse_mapping: CoarseMapping::Perceptual,
+                coarse_bands: 16,
+                rms_integration: RmsIntegration::Ppm,
+                ..EngineSettings::default()
+            },
+            Signal::Noise { seed: 0x1234_5678, amplitude: 0.5 },
+            Signal::Mix {
+                signals: vec![Signal::Noise { seed: 0x0bad_cafe, amplitude: 0.1 }, tone(440.0, 0.5)],
+            },
+        ),
+        case(
+            "mid_side_average",
+            "Mid/side, average of interval; two tones panned apart",
+            48_000,
+            EngineSettings {
+                channel_mode: ChannelMode::MidSide,
+                alignment: AnalysisAlignment::AverageOfInterval,
+                ..EngineSettings::default()
+            },
+            Signal::Mix { signals: vec![tone(200.0, 0.5), tone(5000.0, 0.1)] },
+            Signal::Mix { signals: vec![tone(200.0, 0.5), tone(5000.0, -0.1)] },
+        ),
+        case(
+            "full_bandwidth_sweep",
+            "Full bandwidth at 96 kHz, window centred on the interval; 20 Hz to 40 kHz sweep over a second",
+            96_000,
+            EngineSettings {
+                full_bandwidth: true,
+                alignment: AnalysisAlignment::WindowCentredOnInterval,
+                coarse_bands: 32,
+                ..EngineSettings::default()
+            },
+            Signal::Sweep { from_hz: 20.0, to_hz: 40_000.0, seconds: 1.0, amplitude: 0.5 },
+            Signal::Sweep { from_hz: 40_000.0, to_hz: 20.0, seconds: 1.0, amplitude: 0.5 },
+        ),
+        case(
+            "normalized_44k1",
+            "44.1 kHz normalised to 48 kHz, 2x padding; 3141.5 Hz between bins",
+            44_100,
+            EngineSettings {
+                normalized_rate: true,
+                zero_pad: ZeroPad::X2,
+                ..EngineSettings::default()
+            },
+            tone(3141.5, 0.25),
+            tone(3141.5, 0.125),
+        ),
+        Case {
+            eco_mode: true,
+            ..case(
+                "eco_clicks_activity",
+                "Eco mode with band activity, instant integration; clicks every 1000 samples left, silence right",
+                48_000,
+                EngineSettings {
+                    band_activity: true,
+                    rms_integration: RmsIntegration::Instant,
+                    ..EngineSettings::default()
+                },
+                Signal::Clicks { interval: 1000, offset: 500, amplitude: 1.0 },
+                Signal::Silence,
+            )
+        },
+        case(
+            "dropouts",
+            "Default settings; a 100 Hz tone with a NaN every 5000 samples on the left",
+            48_000,
+            EngineSettings::default(),
+            Signal::Dropouts { signal: Box::new(tone(100.0, 0.5)), interval: 5000, offset: 1234 },
+            tone(100.0, 0.5),
+        ),
+    ]
+}
+
+/// Directory holding the goldens
+pub fn golden_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("replay_goldens")
+}
+
+/// Feed `case` to a fresh engine and keep every frame
+pub fn run(case: &Case) -> Vec<GoldenFrame> {
+    let settings = case.engine_settings();
+    let mut engine = AnalysisEngine::new(AnalysisConfig {
+        sample_rate: case.sample_rate as f32,
+        full_bandwidth: settings.full_bandwidth,
+        zero_pad: settings.zero_pad,
+        window: settings.window,
+        normalized_rate: settings.normalized_rate,
+        eco_mode: settings.eco_mode,
+        reference: false,
+        channels: 2,
+        update_rate: DEFAULT_UPDATE_RATE_HZ,
+    });
+    // Built for these settings already, so nothing waits on the config worker
+    engine.set_settings(settings);
+
+    let len = case.block * case.blocks;
+    let left = case.left.samples(case.sample_rate, len);
+    let right = case.right.samples(case.sample_rate, len);
+    let mut frames = Vec::new();
+    for (block, (left, right)) in left.chunks(case.block).zip(right.chunks(case.block)).enumerate() {
+        engine.push_samples(left, right);
+        if let Some(frame) = engine.poll_frame() {
+            frames.push(GoldenFrame::new(block, &frame));
+        }
+    }
+    frames
+}
+
+/// Write the goldens of every case from this build, replacing the old ones.
+pub fn bless(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for case in cases() {
+        let golden = Golden { frames: run(&case), case };
+        let json = serde_json::to_string_pretty(&golden).map_err(io::Error::other)?;
+        fs::write(dir.join(format!("{}.json", golden.case.name)), json + "\n")?;
+    }
+    Ok(())
+}
+
+/// Whether an actual value is close enough to the expected one
+type Matches = fn(f32, f32) -> bool;
+
+fn db_matches(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= DB_TOLERANCE
+        || (db_to_linear(actual) - db_to_linear(expected)).abs() <= AMPLITUDE_TOLERANCE
+}
+
+fn level_matches(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= LEVEL_TOLERANCE
+}
+
+fn edge_matches(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= EDGE_TOLERANCE * expected.abs().max(1.0)
+}
+
+/// Every difference between two frames beyond the tolerances
+pub fn frame_differences(actual: &GoldenFrame, expected: &GoldenFrame) -> Vec<String> {
+    let mut differences = Vec::new();
+    for (field, a, e) in [
+        ("block", actual.block as u64, expected.block as u64),
+        ("fft_size", actual.fft_size as u64, expected.fft_size as u64),
+        ("zero_pad", actual.zero_pad as u64, expected.zero_pad as u64),
+        ("num_bins", actual.num_bins as u64, expected.num_bins as u64),
+        ("delay_ms", actual.delay_ms, expected.delay_ms),
+        ("non_finite_samples", u64::from(actual.non_finite_samples), u64::from(expected.non_finite_samples)),
+    ] {
+        if a != e {
+            differences.push(format!("{}: {}, expected {}", field, a, e));
+        }
+    }
+    if actual.analysis_rate != expected.analysis_rate {
+        differences.push(format!("analysis_rate: {}, expected {}", actual.analysis_rate, expected.analysis_rate));
+    }
+
+    let series: [(&str, &[f32], &[f32], Matches); 7] = [
+        ("left_bins", &actual.left_bins, &expected.left_bins, db_matches),
+        ("right_bins", &actual.right_bins, &expected.right_bins, db_matches),
+        ("left_coarse", &actual.left_coarse, &expected.left_coarse, db_matches),
+        ("right_coarse", &actual.right_coarse, &expected.right_coarse, db_matches),
+        ("coarse_edges", &actual.coarse_edges, &expected.coarse_edges, edge_matches),
+        ("band_activity", &actual.band_activity, &expected.band_activity, level_matches),
+        ("levels", &actual.levels(), &expected.levels(), level_matches),
+    ];
+    for (field, a, e, matches) in series {
+        if a.len() != e.len() {
+            differences.push(format!("{}: {} values, expected {}", field, a.len(), e.len()));
+        } else if let Some((i, (a, e))) = a.iter().zip(e).enumerate().find(|(_, (a, e))| !matches(**a, **e)) {
+            differences.push(format!("{}[{}]: {}, expected {}", field, i, a, e));
+        }
+    }
+    for (field, a, e) in [
+        ("left_peak", actual.left_peak, expected.left_peak),
+        ("right_peak", actual.right_peak, expected.right_peak),
+        ("left_meter_peak", actual.left_meter_peak, expected.left_meter_peak),
+        ("right_meter_peak", actual.right_meter_peak, expected.right_meter_peak),
+    ] {
+        if !db_matches(a, e) {
+            differences.push(format!("{}: {} dB, expected {} dB", field, a, e));
+        }
+    }
+    differences
+}
+
+/// Replay every case and compare it with its golden in `dir`. Returns every
+/// frame that differs, with the first difference in each field.
+pub fn compare(dir: &Path) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    for case in cases() {
+        let path = dir.join(format!("{}.json", case.name));
+        let expected: Golden = match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        {
+            Ok(golden) => golden,
+            Err(e) => {
+                problems.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let name = &case.name;
+        if expected.case != case {
+            problems.push(format!("{}: case differs from cases(); bless it", name));
+            continue;
+        }
+
+        let actual = run(&case);
+        if actual.len() != expected.frames.len() {
+            problems.push(format!("{}: {} frames, expected {}", name, actual.len(), expected.frames.len()));
+        }
+        for (index, (a, e)) in actual.iter().zip(&expected.frames).enumerate() {
+            for difference in frame_differences(a, e) {
+                problems.push(format!("{}: frame {}: {}", name, index, difference));
+            }
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay_matches_goldens() {
+        let dir = golden_dir();
+        if std::env::var_os(BLESS_ENV).is_some() {
+            bless(&dir).unwrap();
+        }
+        if let Err(problems) = compare(&dir) {
+            panic!(
+                "replay drifted from replay_goldens/ (bless with {}=1 if that was the intent):\n{}",
+                BLESS_ENV,
+                problems.join("\n")
+            );
+        }
+    }
+
+    #[test]
+    fn test_replay_is_deterministic() {
+        let cases = cases();
+        assert!(cases.len() >= 5);
+        for case in &cases {
+            let frames = run(case);
+            assert!(frames.len() > 10, "{}: {} frames", case.name, frames.len());
+            assert_eq!(frames, run(case), "{}", case.name);
+        }
+        // The dropout case counts every NaN it put in up to the last frame
+        let dropouts = cases.iter().find(|case| case.name == "dropouts").unwrap();
+        let frames = run(dropouts);
+        let counted: u32 = frames.iter().map(|frame| frame.non_finite_samples).sum();
+        let end = (frames.last().unwrap().block + 1) * dropouts.block;
+        let put_in = dropouts.left.samples(dropouts.sample_rate, end).iter().filter(|s| s.is_nan()).count();
+        assert!(put_in > 0);
+        assert_eq!(counted as usize, put_in);
+    }
+
+    #[test]
+    fn test_tolerances() {
+        let case = &cases()[0];
+        let frame = run(case).pop().unwrap();
+        assert!(frame_differences(&frame, &frame).is_empty());
+
+        let mut nudged = frame.clone();
+        nudged.left_coarse[3] += 0.05;
+        nudged.left_rms += 0.0005;
+        assert!(frame_differences(&nudged, &frame).is_empty(), "within tolerance");
+
+        nudged.left_coarse[3] += 0.1;
+        let differences = frame_differences(&nudged, &frame);
+        assert_eq!(differences.len(), 1);
+        assert!(differences[0].starts_with("left_coarse[3]"), "{:?}", differences);
+
+        let mut off_by_one = frame.clone();
+        off_by_one.non_finite_samples += 1;
+        off_by_one.block += 1;
+        assert_eq!(frame_differences(&off_by_one, &frame).len(), 2, "counters are exact");
+    }
+
+    #[test]
+    fn test_signals() {
+        let sweep = Signal::Sweep { from_hz: 20.0, to_hz: 20_000.0, seconds: 0.1, amplitude: 0.5 };
+        let samples = sweep.samples(48_000, 9600);
+        assert!(samples.iter().all(|s| s.abs() <= 0.5));
+        assert_eq!(samples[0], 0.0);
+
+        let dropouts = Signal::Dropouts { signal: Box::new(Signal::Silence), interval: 10, offset: 3 };
+        let samples = dropouts.samples(48_000, 30);
+        let nan: Vec<usize> = (0..samples.len()).filter(|&i| samples[i].is_nan()).collect();
+        assert_eq!(nan, [3, 13, 23]);
+
+        let mix = Signal::Mix {
+            signals: vec![
+                Signal::Clicks { interval: 4, offset: 0, amplitude: 0.5 },
+                Signal::Clicks { interval: 4, offset: 0, amplitude: 0.25 },
+            ],
+        };
+        assert_eq!(mix.samples(48_000, 5), [0.75, 0.0, 0.0, 0.0, 0.75]);
+    }
+}
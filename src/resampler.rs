@@ -0,0 +1,285 @@
+//! Resampling of the analysis feed to one fixed rate
+//!
+//! Bin widths follow the sample rate, so the same material reads slightly
+//! differently in a 44.1 kHz and a 96 kHz project. With the normalised
+//! analysis rate on, the decimators bring the feed down to between
+//! `NORMALIZED_RATE` and twice that, and a `Resampler` takes it the rest of
+//! the way before it reaches the ring. Bins, coarse bands and the calibration
+//! are then the same at every project rate. The passthrough audio is never
+//! touched.
+//!
+//! The filter is a Kaiser-windowed sinc cut off just below the lower of the
+//! two Nyquist frequencies, stored as `PHASES` polyphase rows and
+//! interpolated linearly between neighbouring rows for the fractional delay.
+
+use std::f32::consts::PI;
+use std::mem::size_of;
+
+use crate::decimator::bessel_i0;
+use crate::memory;
+
+/// Rate the FFT sees with the normalised analysis rate on
+pub const NORMALIZED_RATE: f32 = 48_000.0;
+
+/// Most output samples one input sample can make
+pub const MAX_OUTPUTS: usize = 8;
+
+/// Lowest input rate that is resampled; slower feeds are analysed as they
+/// are
+pub const MIN_INPUT_RATE: f32 = NORMALIZED_RATE / MAX_OUTPUTS as f32;
+
+/// Highest input rate over `NORMALIZED_RATE`; the decimators stay within it
+const MAX_RATIO: f32 = 2.0;
+
+/// Rows of the polyphase bank, one more is stored for interpolation
+const PHASES: usize = 128;
+
+/// Taps per row when upsampling; downsampling stretches the kernel by the
+/// rate ratio
+const BASE_TAPS: usize = 64;
+
+const MAX_TAPS: usize = BASE_TAPS * MAX_RATIO as usize;
+
+/// Cutoff as a fraction of the lower Nyquist frequency
+const CUTOFF: f32 = 0.95;
+
+/// Kaiser window beta for ~80 dB stopband attenuation
+const KAISER_BETA: f32 = 7.86;
+
+/// The stereo samples one input made, oldest first
+#[derive(Debug, Clone, Copy)]
+pub struct Resampled {
+    samples: [(f32, f32); MAX_OUTPUTS],
+    len: usize,
+    next: usize,
+}
+
+impl Iterator for Resampled {
+    type Item = (f32, f32);
+
+    fn next(&mut self) -> Option<(f32, f32)> {
+        let sample = *self.samples[..self.len].get(self.next)?;
+        self.next += 1;
+        Some(sample)
+    }
+}
+
+/// Stereo resampler to `NORMALIZED_RATE`; both channels share the timing
+pub struct Resampler {
+    /// `PHASES + 1` rows of `taps` coefficients; row `p` is the kernel for an
+    /// output `p / PHASES` of an input past the centre tap
+    coeffs: Vec<f32>,
+    /// 0 passes the feed through
+    taps: usize,
+    /// Input samples per output sample
+    step: f64,
+    /// The newest `taps` inputs, stored twice so they are always contiguous
+    history_left: Vec<f32>,
+    history_right: Vec<f32>,
+    pos: usize,
+    /// Where the next output falls past the centre tap, in input samples
+    frac: f64,
+}
+
+impl Resampler {
+    /// What `heap_bytes` reports at most: the largest bank and its histories
+    pub const MAX_HEAP_BYTES: usize = ((PHASES + 1) * MAX_TAPS + 4 * MAX_TAPS) * size_of::<f32>();
+
+    /// A resampler from `input_rate`, or one that passes the feed through if
+    /// it is at `NORMALIZED_RATE` already or out of range. Allocates.
+    pub fn new(input_rate: f32) -> Self {
+        let ratio = input_rate / NORMALIZED_RATE;
+        if (input_rate - NORMALIZED_RATE).abs() < 1.0 || !(MIN_INPUT_RATE..=MAX_RATIO * NORMALIZED_RATE).contains(&input_rate) {
+            return Self::passthrough();
+        }
+
+        let taps = ((BASE_TAPS as f32 * ratio.max(1.0)).ceil() as usize + 1) & !1;
+        let half = taps as f32 / 2.0;
+        // Cycles per input sample
+        let cutoff = CUTOFF * 0.5 * input_rate.min(NORMALIZED_RATE) / input_rate;
+        let norm = bessel_i0(KAISER_BETA);
+        let mut coeffs = Vec::with_capacity((PHASES + 1) * taps);
+        for phase in 0..=PHASES {
+            let frac = phase as f32 / PHASES as f32;
+            let row_start = coeffs.len();
+            coeffs.extend((0..taps).map(|j| {
+                let t = frac + half - 1.0 - j as f32;
+                let x = 2.0 * cutoff * t;
+                let sinc = if x.abs() < 1e-6 { 1.0 } else { (PI * x).sin() / (PI * x) };
+                let r = t / half;
+                let window = bessel_i0(KAISER_BETA * (1.0 - r * r).max(0.0).sqrt()) / norm;
+                sinc * window
+            }));
+            // Unity DC gain at every phase
+            let row = &mut coeffs[row_start..];
+            let sum: f32 = row.iter().sum();
+            row.iter_mut().for_each(|c| *c /= sum);
+        }
+
+        Self {
+            coeffs,
+            taps,
+            step: f64::from(input_rate) / f64::from(NORMALIZED_RATE),
+            history_left: vec![0.0; 2 * taps],
+            history_right: vec![0.0; 2 * taps],
+            pos: 0,
+            frac: 0.0,
+        }
+    }
+
+    /// A resampler that hands every input straight back
+    pub fn passthrough() -> Self {
+        Self {
+            coeffs: Vec::new(),
+            taps: 0,
+            step: 1.0,
+            history_left: Vec::new(),
+            history_right: Vec::new(),
+            pos: 0,
+            frac: 0.0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.taps > 0
+    }
+
+    /// Clear the filter history, as the ring is
+    pub fn reset(&mut self) {
+        self.history_left.fill(0.0);
+        self.history_right.fill(0.0);
+        self.pos = 0;
+        self.frac = 0.0;
+    }
+
+    /// Heap bytes of the bank and histories
+    pub fn heap_bytes(&self) -> usize {
+        memory::vec_bytes(&self.coeffs) + memory::vec_bytes(&self.history_left) + memory::vec_bytes(&self.history_right)
+    }
+
+    /// Push one stereo input; returns the outputs it completes, none to
+    /// `MAX_OUTPUTS`. Never allocates.
+    pub fn process(&mut self, left: f32, right: f32) -> Resampled {
+        let mut out = Resampled {
+            samples: [(0.0, 0.0); MAX_OUTPUTS],
+            len: 0,
+            next: 0,
+        };
+        let taps = self.taps;
+        if taps == 0 {
+            out.samples[0] = (left, right);
+            out.len = 1;
+            return out;
+        }
+
+        self.history_left[self.pos] = left;
+        self.history_left[self.pos + taps] = left;
+        self.history_right[self.pos] = right;
+        self.history_right[self.pos + taps] = right;
+        self.pos = (self.pos + 1) % taps;
+        let history_left = &self.history_left[self.pos..self.pos + taps];
+        let history_right = &self.history_right[self.pos..self.pos + taps];
+
+        while self.frac < 1.0 && out.len < MAX_OUTPUTS {
+            let phase = self.frac * PHASES as f64;
+            let row = (phase as usize).min(PHASES - 1);
+            let t = (phase - row as f64) as f32;
+            let below = &self.coeffs[row * taps..(row + 1) * taps];
+            let above = &self.coeffs[(row + 1) * taps..(row + 2) * taps];
+
+            let (mut left_below, mut left_above, mut right_below, mut right_above) = (0.0, 0.0, 0.0, 0.0);
+            for (((&l, &r), &b), &a) in history_left.iter().zip(history_right).zip(below).zip(above) {
+                left_below += l * b;
+                left_above += l * a;
+                right_below += r * b;
+                right_above += r * a;
+            }
+            out.samples[out.len] = (
+                left_below + (left_above - left_below) * t,
+                right_below + (right_above - right_below) * t,
+            );
+            out.len += 1;
+            self.frac += self.step;
+        }
+        self.frac -= 1.0;
+        out
+    }
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self::passthrough()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Resample a sine at `hz` from `rate` and return the outputs after the
+    /// filter has filled
+    fn resample_tone(rate: f32, hz: f32, inputs: usize) -> Vec<f32> {
+        let mut resampler = Resampler::new(rate);
+        let mut out = Vec::new();
+        for i in 0..inputs {
+            let x = (2.0 * std::f64::consts::PI * f64::from(hz) * i as f64 / f64::from(rate)).sin() as f32;
+            out.extend(resampler.process(x, x).map(|(left, _)| left));
+        }
+        out.split_off(MAX_TAPS)
+    }
+
+    /// Amplitude of the `hz` component of `samples` at `NORMALIZED_RATE`
+    fn amplitude(samples: &[f32], hz: f32) -> f32 {
+        let (mut re, mut im) = (0.0_f64, 0.0_f64);
+        for (i, &s) in samples.iter().enumerate() {
+            let phase = 2.0 * std::f64::consts::PI * f64::from(hz) * i as f64 / f64::from(NORMALIZED_RATE);
+            re += f64::from(s) * phase.cos();
+            im += f64::from(s) * phase.sin();
+        }
+        (2.0 * (re * re + im * im).sqrt() / samples.len() as f64) as f32
+    }
+
+    #[test]
+    fn test_output_rate() {
+        for rate in [44_100.0, 88_200.0, 32_000.0, 96_000.0] {
+            let mut resampler = Resampler::new(rate);
+            let outputs: usize = (0..rate as usize).map(|_| resampler.process(0.0, 0.0).count()).sum();
+            assert!((outputs as f32 - NORMALIZED_RATE).abs() <= 1.0, "{} Hz made {} samples a second", rate, outputs);
+        }
+        assert!(!Resampler::new(NORMALIZED_RATE).is_active());
+        assert!(!Resampler::new(1_000.0).is_active());
+        assert_eq!(Resampler::new(NORMALIZED_RATE).process(0.25, -0.5).collect::<Vec<_>>(), [(0.25, -0.5)]);
+    }
+
+    #[test]
+    fn test_passband_is_flat() {
+        for rate in [44_100.0, 88_200.0] {
+            for hz in [100.0, 1_000.0, 10_000.0, 16_000.0] {
+                let out = resample_tone(rate, hz, rate as usize / 2);
+                let gain = amplitude(&out, hz);
+                assert!((gain - 1.0).abs() < 0.01, "{} Hz from {} Hz: gain {}", hz, rate, gain);
+            }
+        }
+    }
+
+    #[test]
+    fn test_no_images_or_aliases() {
+        // Upsampling 44.1 kHz: a 15 kHz tone mustn't image to 48 - (44.1 - 15) kHz
+        let out = resample_tone(44_100.0, 15_000.0, 22_050);
+        assert!(amplitude(&out, 18_900.0) < 1e-3);
+        // Downsampling 88.2 kHz: 30 kHz mustn't alias to 18 kHz
+        let out = resample_tone(88_200.0, 30_000.0, 44_100);
+        assert!(amplitude(&out, 18_000.0) < 1e-3);
+    }
+
+    #[test]
+    fn test_reset_forgets_history() {
+        let mut resampler = Resampler::new(44_100.0);
+        for _ in 0..100 {
+            resampler.process(1.0, 1.0).count();
+        }
+        resampler.reset();
+        assert!(resampler.process(0.0, 0.0).all(|(left, right)| left == 0.0 && right == 0.0));
+        assert!(resampler.heap_bytes() <= Resampler::MAX_HEAP_BYTES);
+    }
+}
@@ -0,0 +1,166 @@
+//! Noticing that the machine slept
+//!
+//! After a laptop resumes, the socket to the Suite is usually dead without
+//! knowing it, the connection loop may be deep into its backoff, and a
+//! listener can be left bound to interface state from before. None of that
+//! recovers quickly by itself, so the loops that own sockets look for the
+//! sleep instead: an iteration that should take milliseconds and took longer
+//! than `RESUME_GAP`. On Windows the monotonic clock keeps counting through
+//! sleep; on Linux and macOS it stops, so the wall clock is compared as
+//! well. A wall clock set forward by hand looks the same, and costs nothing
+//! worse than a reconnect.
+//!
+//! The connection thread then drops its socket and reconnects at once with
+//! the backoff reset, and the first packet on the new connection is a status
+//! packet with `resumed_after_ms`. Packet timestamps leave out the sleep
+//! where the monotonic clock counted it (`ResumeState::slept`), so they go on
+//! from where they were. A Suite on another host is looked up again in
+//! `open_socket`, and browsed for again where it was discovered, as on
+//! every attempt. The editor's packet server binds its port again, and the
+//! audio side needs nothing: the watchdog gap already restarts the analysis.
+
+use parking_lot::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Longer than any loop iteration takes awake: the reconnect backoff tops
+/// out at 5 s and a connect attempt times out after 2 s
+pub const RESUME_GAP: Duration = Duration::from_secs(15);
+
+/// A sleep, as seen from one loop
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Resume {
+    /// How long the machine was away, by whichever clock saw more of it
+    pub slept: Duration,
+    /// How much of that the monotonic clock counted
+    pub monotonic: Duration,
+}
+
+/// Compares each iteration of a loop with the one before
+#[derive(Debug, Clone, Copy)]
+pub struct ResumeDetector {
+    instant: Instant,
+    wall: SystemTime,
+}
+
+impl ResumeDetector {
+    pub fn new() -> Self {
+        Self::starting_at(Instant::now(), SystemTime::now())
+    }
+
+    fn starting_at(instant: Instant, wall: SystemTime) -> Self {
+        Self { instant, wall }
+    }
+
+    /// Once per iteration: whether the machine slept since the last call
+    pub fn check(&mut self) -> Option<Resume> {
+        self.check_at(Instant::now(), SystemTime::now())
+    }
+
+    fn check_at(&mut self, instant: Instant, wall: SystemTime) -> Option<Resume> {
+        let monotonic = instant.saturating_duration_since(self.instant);
+        // A wall clock set back reads as no time at all
+        let wall_elapsed = wall.duration_since(self.wall).unwrap_or_default();
+        self.instant = instant;
+        self.wall = wall;
+        let slept = monotonic.max(wall_elapsed);
+        (slept > RESUME_GAP).then_some(Resume { slept, monotonic })
+    }
+
+    /// Pretend the last iteration was `ago` earlier, as if the machine had
+    /// slept since
+    #[cfg(test)]
+    pub fn backdate(&mut self, ago: Duration) {
+        self.instant = self.instant.checked_sub(ago).unwrap_or(self.instant);
+        self.wall -= ago;
+    }
+}
+
+impl Default for ResumeDetector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The connection thread's view of sleeps, shared with the plugin for its
+/// timestamps
+#[derive(Debug, Default)]
+pub struct ResumeState {
+    detector: Mutex<ResumeDetector>,
+    /// Sleep the monotonic clock counted, in total
+    slept_ms: AtomicU64,
+    /// Resumes seen since the plugin started
+    resumes: AtomicU64,
+}
+
+impl ResumeState {
+    /// Connection thread, once per loop iteration
+    pub fn check(&self) -> Option<Resume> {
+        let resume = self.detector.lock().check()?;
+        self.slept_ms.fetch_add(resume.monotonic.as_millis() as u64, Ordering::Relaxed);
+        self.resumes.fetch_add(1, Ordering::Relaxed);
+        Some(resume)
+    }
+
+    /// Sleep to leave out of `Instant`-based timestamps
+    pub fn slept(&self) -> Duration {
+        Duration::from_millis(self.slept_ms.load(Ordering::Relaxed))
+    }
+
+    #[cfg(test)]
+    pub fn resumes(&self) -> u64 {
+        self.resumes.load(Ordering::Relaxed)
+    }
+
+    #[cfg(test)]
+    pub fn simulate_sleep(&self, slept: Duration) {
+        self.detector.lock().backdate(slept);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_a_gap_between_iterations_is_a_resume() {
+        let (start, wall) = (Instant::now(), SystemTime::now());
+        let mut detector = ResumeDetector::starting_at(start, wall);
+        let ms = Duration::from_millis;
+
+        // Awake, including a long reconnect wait
+        assert_eq!(detector.check_at(start + ms(1), wall + ms(1)), None);
+        assert_eq!(detector.check_at(start + ms(7001), wall + ms(7001)), None);
+
+        // Windows: both clocks counted the hour away
+        let (start, wall) = (start + ms(7001), wall + ms(7001));
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            detector.check_at(start + hour, wall + hour),
+            Some(Resume { slept: hour, monotonic: hour })
+        );
+
+        // Linux and macOS: only the wall clock did
+        let (start, wall) = (start + hour, wall + hour);
+        assert_eq!(
+            detector.check_at(start + ms(2), wall + hour),
+            Some(Resume { slept: hour, monotonic: ms(2) })
+        );
+
+        // The wall clock set back an hour is no resume
+        let (start, wall) = (start + ms(2), wall + hour);
+        assert_eq!(detector.check_at(start + ms(1), wall - hour), None);
+    }
+
+    #[test]
+    fn test_state_counts_the_sleep_the_monotonic_clock_saw() {
+        let state = ResumeState::default();
+        assert_eq!(state.check(), None);
+        state.simulate_sleep(Duration::from_secs(60));
+        let resume = state.check().unwrap();
+        assert!(resume.slept >= Duration::from_secs(60));
+        assert_eq!(state.slept().as_millis(), resume.monotonic.as_millis());
+        assert_eq!(state.resumes(), 1);
+        assert_eq!(state.check(), None);
+    }
+}
@@ -0,0 +1,590 @@
+//! Process-wide resources shared by every instance
+//!
+//! A host loads the library once per process and makes instances as it
+//! pleases: a plugin scan creates and destroys several in a row, the VST3
+//! and CLAP wrappers create theirs independently, and instances may be
+//! created and dropped on different threads. Anything there should only be
+//! one of per process lives in the `RuntimeContext`. Each instance holds a
+//! `RuntimeHandle`, taken in `Default::default()`: the first handle builds
+//! the context, the last one dropped tears it down, so nothing is started
+//! twice and nothing outlives the instances. A scan that only creates and
+//! drops instances costs a lock and an allocation; the config watcher
+//! thread starts on the first `config()`, from `initialize()`.
+//!
+//! The context holds the instance registry, the machine config with the
+//! thread that reloads it when the file changes, the debug log, and the
+//! connection to the Suite the instances share (see `multiplex`). The
+//! editor's packet servers stay per window, since one can outlive its
+//! instance when a host loses the editor handle; they register their ports
+//! in `ports` themselves. For the same reason `debug_log` still writes when
+//! no instance is left, opening the file for each line.
+//!
+//! The audio thread logs too, so `debug_log` never waits: it queues the
+//! line for the log's thread, which keeps the file open and rotates it at
+//! `LOG_MAX_BYTES`. That thread starts with `start_log`, from
+//! `initialize()`; lines from before wait in the queue, and teardown writes
+//! whatever is left. A line that finds the queue full, or an instance
+//! being created or dropped, is counted and not written, and the count is
+//! the next line the log's thread writes.
+//!
+//! Teardown happens under the context lock, so an instance created while
+//! the last one is going waits and then builds a fresh context. Nothing in
+//! the teardown, including the watcher thread it joins, may log.
+
+use parking_lot::Mutex;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use crossbeam_channel::{Receiver, Sender as LineSender};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, OnceLock};
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+use crate::config::{self, MachineConfig};
+use crate::error::HardwaveError;
+use crate::multiplex::SharedConnection;
+use crate::panics::{self, Region};
+use crate::threads::{self, Priority};
+
+/// The debug log, in the temp directory
+const LOG_FILE: &str = "hardwave-debug.log";
+
+/// Size at which the debug log moves to `hardwave-debug.log.1`, replacing
+/// the previous one
+pub const LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+/// Lines waiting for the log's thread; more are dropped
+const LOG_QUEUE_LEN: usize = 256;
+
+/// How often the log's thread writes what was queued
+const LOG_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Lines `debug_log` dropped rather than wait for an instance being
+/// created or dropped, not yet reported
+static DROPPED_LINES: AtomicU64 = AtomicU64::new(0);
+
+/// How often the config watcher looks at the file's modification time
+const CONFIG_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// A machine config load; errors are shared so every instance can record
+/// the same one
+pub type ConfigResult = Result<MachineConfig, Arc<HardwaveError>>;
+
+/// Who holds the context
+#[derive(Default)]
+struct Slot {
+    context: Option<Arc<RuntimeContext>>,
+    next_serial: u64,
+    /// Contexts built since the process started
+    builds: u64,
+}
+
+static RUNTIME: OnceLock<Mutex<Slot>> = OnceLock::new();
+
+fn slot() -> &'static Mutex<Slot> {
+    RUNTIME.get_or_init(|| Mutex::new(Slot::default()))
+}
+
+/// Everything there is one of per process
+pub struct RuntimeContext {
+    /// Serial numbers of the live instances
+    instances: Mutex<Vec<u64>>,
+    config: ConfigWatcher,
+    log: Logger,
+    suite: Arc<SharedConnection>,
+}
+
+impl RuntimeContext {
+    fn new() -> Self {
+        Self {
+            instances: Mutex::new(Vec::new()),
+            config: ConfigWatcher::new(),
+            log: Logger::new(log_path()),
+            suite: Arc::new(SharedConnection::default()),
+        }
+    }
+
+    /// Instances alive in this process, of any format
+    pub fn instance_count(&self) -> usize {
+        self.instances.lock().len()
+    }
+
+    /// The machine config as of its last change on disk. The first call
+    /// loads it and starts the watcher. Not for the audio thread.
+    pub fn config(&self) -> ConfigResult {
+        self.config.current()
+    }
+
+    /// Start the thread that writes the debug log. Not for the audio thread.
+    pub fn start_log(&self) {
+        self.log.start();
+    }
+
+    /// The connection to the Suite for every instance's packets. It opens
+    /// when the first instance streams and closes when the last one leaves.
+    pub fn suite_connection(&self) -> Arc<SharedConnection> {
+        Arc::clone(&self.suite)
+    }
+
+    fn shutdown(&self) {
+        self.config.stop();
+        self.log.close();
+    }
+}
+
+/// One instance's share of the `RuntimeContext`
+pub struct RuntimeHandle {
+    context: Arc<RuntimeContext>,
+    serial: u64,
+}
+
+impl RuntimeHandle {
+    /// Join the context, building it if this is the only instance
+    pub fn acquire() -> Self {
+        let mut slot = slot().lock();
+        let Slot { context, next_serial, builds } = &mut *slot;
+        let serial = *next_serial;
+        *next_serial += 1;
+        let context = Arc::clone(context.get_or_insert_with(|| {
+            *builds += 1;
+            Arc::new(RuntimeContext::new())
+        }));
+        context.instances.lock().push(serial);
+        Self { context, serial }
+    }
+
+    pub fn context(&self) -> &RuntimeContext {
+        &self.context
+    }
+}
+
+impl Drop for RuntimeHandle {
+    fn drop(&mut self) {
+        let mut slot = slot().lock();
+        let mut instances = self.context.instances.lock();
+        instances.retain(|&serial| serial != self.serial);
+        let last = instances.is_empty();
+        drop(instances);
+        let current = slot.context.as_ref().is_some_and(|context| Arc::ptr_eq(context, &self.context));
+        if last && current {
+            slot.context = None;
+            self.context.shutdown();
+        }
+    }
+}
+
+/// Write a line to the debug log: queued for the log's thread while any
+/// instance is alive, written at once when none is. Never waits for
+/// another thread, so a line that finds an instance being created or
+/// dropped is counted as dropped.
+pub fn debug_log(msg: &str) {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let line = format!("[{}] {}", now, msg);
+    let Some(slot) = slot().try_lock() else {
+        DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    match &slot.context {
+        Some(context) => context.log.write(line),
+        None => {
+            drop(slot);
+            let mut file = LogFile::new(log_path());
+            file.write(&line);
+        }
+    }
+}
+
+fn log_path() -> PathBuf {
+    std::env::temp_dir().join(LOG_FILE)
+}
+
+fn open_log(path: &Path) -> std::io::Result<File> {
+    OpenOptions::new().create(true).append(true).open(path)
+}
+
+/// Move a full log aside
+fn rotate_if_full(path: &Path) {
+    if fs::metadata(path).is_ok_and(|meta| meta.len() >= LOG_MAX_BYTES) {
+        let _ = fs::rename(path, path.with_extension("log.1"));
+    }
+}
+
+/// The debug log file, opened on the first line and rotated when full
+struct LogFile {
+    path: PathBuf,
+    file: Option<File>,
+}
+
+impl LogFile {
+    fn new(path: PathBuf) -> Self {
+        Self { path, file: None }
+    }
+
+    fn write(&mut self, line: &str) {
+        let len = match self.file.as_ref() {
+            Some(file) => file.metadata().map(|meta| meta.len()),
+            None => fs::metadata(&self.path).map(|meta| meta.len()),
+        };
+        if len.is_ok_and(|len| len >= LOG_MAX_BYTES) {
+            // Closed first, since Windows won't rename an open file
+            self.file = None;
+            rotate_if_full(&self.path);
+        }
+        if self.file.is_none() {
+            self.file = open_log(&self.path).ok();
+        }
+        if let Some(file) = self.file.as_mut() {
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+
+    /// Write the lines waiting in `lines`, after a count of the `dropped`
+    /// ones if there are any
+    fn write_queued(&mut self, lines: &Receiver<String>, dropped: u64) {
+        if dropped > 0 {
+            self.write(&format!("[runtime] {} lines dropped, the log was busy", dropped));
+        }
+        for line in lines.try_iter() {
+            self.write(&line);
+        }
+    }
+}
+
+/// The shared debug log: a queue that takes lines from any thread without
+/// waiting, and the thread that writes them
+struct Logger {
+    lines: (LineSender<String>, Receiver<String>),
+    /// Lines the full queue turned away, not yet reported
+    dropped: Arc<AtomicU64>,
+    /// The thread's while it runs, and teardown's after
+    file: Arc<Mutex<LogFile>>,
+    /// Dropping the sender stops the thread at once
+    thread: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+}
+
+impl Logger {
+    fn new(path: PathBuf) -> Self {
+        Self {
+            lines: crossbeam_channel::bounded(LOG_QUEUE_LEN),
+            dropped: Arc::new(AtomicU64::new(0)),
+            file: Arc::new(Mutex::new(LogFile::new(path))),
+            thread: Mutex::new(None),
+        }
+    }
+
+    /// Queue a line; with the queue full it is counted and dropped
+    fn write(&self, line: String) {
+        if self.lines.0.try_send(line).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Start the writing thread unless it runs. Without one, lines wait
+    /// for `close`, as many as the queue holds.
+    fn start(&self) {
+        let mut thread = self.thread.lock();
+        if thread.is_some() {
+            return;
+        }
+        let (stop, stopped) = mpsc::channel::<()>();
+        let lines = self.lines.1.clone();
+        let dropped = Arc::clone(&self.dropped);
+        let file = Arc::clone(&self.file);
+        *thread = threads::spawn("hardwave-log", Priority::Background, move || loop {
+            // Lines `debug_log` dropped for any context are this one's to
+            // report
+            let count = dropped.swap(0, Ordering::Relaxed) + DROPPED_LINES.swap(0, Ordering::Relaxed);
+            file.lock().write_queued(&lines, count);
+            match stopped.recv_timeout(LOG_POLL_INTERVAL) {
+                Err(RecvTimeoutError::Timeout) => {}
+                _ => break,
+            }
+        })
+        .ok()
+        .map(|handle| (stop, handle));
+    }
+
+    /// Stop the thread and write what is left
+    fn close(&self) {
+        if let Some((stop, handle)) = self.thread.lock().take() {
+            drop(stop);
+            let _ = handle.join();
+        }
+        let mut file = self.file.lock();
+        file.write_queued(&self.lines.1, self.dropped.swap(0, Ordering::Relaxed));
+        file.file = None;
+    }
+}
+
+/// The machine config and when the file was last changed
+struct Loaded {
+    config: ConfigResult,
+    modified: Option<SystemTime>,
+}
+
+impl Loaded {
+    fn load() -> Self {
+        Self {
+            modified: config_modified(),
+            config: config::load().map_err(Arc::new),
+        }
+    }
+}
+
+fn config_modified() -> Option<SystemTime> {
+    config::config_path()
+        .and_then(|path| fs::metadata(path).ok())
+        .and_then(|meta| meta.modified().ok())
+}
+
+/// Keeps the machine config current, from a thread that starts on the
+/// first read
+struct ConfigWatcher {
+    loaded: Arc<Mutex<Option<Loaded>>>,
+    /// Dropping the sender stops the thread at once
+    thread: Mutex<Option<(Sender<()>, JoinHandle<()>)>>,
+}
+
+impl ConfigWatcher {
+    fn new() -> Self {
+        Self {
+            loaded: Arc::new(Mutex::new(None)),
+            thread: Mutex::new(None),
+        }
+    }
+
+    fn current(&self) -> ConfigResult {
+        let mut thread = self.thread.lock();
+        if thread.is_none() {
+            *self.loaded.lock() = Some(Loaded::load());
+            *thread = self.spawn();
+        }
+        drop(thread);
+        let loaded = self.loaded.lock();
+        loaded.as_ref().map_or_else(|| Ok(MachineConfig::default()), |loaded| loaded.config.clone())
+    }
+
+    /// Without a thread the config is still read once, just not reloaded
+    fn spawn(&self) -> Option<(Sender<()>, JoinHandle<()>)> {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let loaded = Arc::clone(&self.loaded);
+        let handle = threads::spawn_restarting("hardwave-config", Priority::Background, Region::ConfigWatcher, move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(CONFIG_POLL_INTERVAL) {
+                panics::checkpoint(Region::ConfigWatcher);
+                let modified = config_modified();
+                let changed = loaded.lock().as_ref().is_some_and(|loaded| loaded.modified != modified);
+                if changed {
+                    // Read outside the lock; the file is small but the disk may be slow
+                    let fresh = Loaded::load();
+                    *loaded.lock() = Some(fresh);
+                }
+            }
+        })
+        .ok()?;
+        Some((stop, handle))
+    }
+
+    #[cfg(test)]
+    fn is_watching(&self) -> bool {
+        self.thread.lock().is_some()
+    }
+
+    fn stop(&self) {
+        if let Some((stop, handle)) = self.thread.lock().take() {
+            drop(stop);
+            let _ = handle.join();
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::sync::{Barrier, Weak};
+    use std::thread;
+
+    /// The context is process-wide, so tests that count builds, and any test
+    /// that makes a plugin, take turns
+    pub(crate) static SERIAL: Mutex<()> = Mutex::new(());
+
+    fn builds() -> u64 {
+        slot().lock().builds
+    }
+
+    fn running() -> bool {
+        slot().lock().context.is_some()
+    }
+
+    #[test]
+    fn test_instances_share_one_context() {
+        let _serial = SERIAL.lock();
+        let before = builds();
+        let first = RuntimeHandle::acquire();
+        let second = RuntimeHandle::acquire();
+        assert!(Arc::ptr_eq(&first.context, &second.context));
+        assert_eq!(first.context().instance_count(), 2);
+        assert_eq!(builds(), before + 1);
+
+        drop(first);
+        assert!(running(), "survives until the last instance goes");
+        assert_eq!(second.context().instance_count(), 1);
+        let context = Arc::downgrade(&second.context);
+        drop(second);
+        assert!(!running());
+        assert!(context.upgrade().is_none());
+
+        // Built afresh for the next instance
+        let next = RuntimeHandle::acquire();
+        assert_eq!(builds(), before + 2);
+        assert!(context.upgrade().is_none() && next.context().instance_count() == 1);
+    }
+
+    #[test]
+    fn test_concurrent_plugin_instances_build_the_context_once() {
+        let _serial = SERIAL.lock();
+        let before = builds();
+        // A project's instance holds the context while a scan churns
+        let project = crate::HardwaveAnalyser::default();
+        let project_context = Arc::downgrade(&project.runtime.context);
+
+        let threads = 8;
+        let barrier = Arc::new(Barrier::new(threads));
+        let scans: Vec<_> = (0..threads)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                let project_context = project_context.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    for _ in 0..10 {
+                        let scanned = crate::HardwaveAnalyser::default();
+                        assert!(Weak::ptr_eq(&Arc::downgrade(&scanned.runtime.context), &project_context));
+                    }
+                })
+            })
+            .collect();
+        for scan in scans {
+            scan.join().unwrap();
+        }
+        assert_eq!(builds(), before + 1);
+        assert_eq!(project.runtime.context().instance_count(), 1);
+
+        drop(project);
+        assert!(!running());
+        assert!(project_context.upgrade().is_none(), "nothing holds the context after the last drop");
+    }
+
+    #[test]
+    fn test_churn_without_a_long_lived_instance_cleans_up() {
+        let _serial = SERIAL.lock();
+        let threads = 8;
+        let barrier = Arc::new(Barrier::new(threads));
+        let scans: Vec<_> = (0..threads)
+            .map(|_| {
+                let barrier = Arc::clone(&barrier);
+                thread::spawn(move || {
+                    barrier.wait();
+                    (0..50)
+                        .map(|_| {
+                            let handle = RuntimeHandle::acquire();
+                            // Some instances get as far as initialising
+                            let _ = handle.context().config();
+                            Arc::downgrade(&handle.context)
+                        })
+                        .collect::<Vec<Weak<RuntimeContext>>>()
+                })
+            })
+            .collect();
+        let contexts: Vec<_> = scans.into_iter().map(|scan| scan.join().unwrap()).collect();
+
+        assert!(!running());
+        assert!(contexts.iter().flatten().all(|context| context.upgrade().is_none()));
+    }
+
+    #[test]
+    fn test_config_watcher_starts_on_first_read_and_stops_with_the_context() {
+        let _serial = SERIAL.lock();
+        let handle = RuntimeHandle::acquire();
+        assert!(!handle.context().config.is_watching(), "not for instances that are only scanned");
+        let first = handle.context().config();
+        assert_eq!(first.is_ok(), handle.context().config().is_ok());
+        assert!(handle.context().config.is_watching());
+
+        let loaded = Arc::downgrade(&handle.context().config.loaded);
+        drop(handle);
+        assert!(loaded.upgrade().is_none(), "the watcher thread has let go");
+    }
+
+    #[test]
+    fn test_config_watcher_restarts_after_a_panic() {
+        use crate::panics::hooks;
+
+        let _serial = SERIAL.lock();
+        let handle = RuntimeHandle::acquire();
+        let _ = handle.context().config();
+        let watcher = handle.context().config.thread.lock().as_ref().map(|(_, thread)| thread.thread().id());
+        let restarts = panics::restarts(Region::ConfigWatcher);
+        hooks::inject(Region::ConfigWatcher, watcher);
+
+        let deadline = std::time::Instant::now() + CONFIG_POLL_INTERVAL * 3;
+        while panics::restarts(Region::ConfigWatcher) == restarts {
+            assert!(std::time::Instant::now() < deadline, "watcher never restarted");
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(handle.context().config.is_watching());
+        let loaded = Arc::downgrade(&handle.context().config.loaded);
+        drop(handle);
+        assert!(loaded.upgrade().is_none(), "the restarted watcher still stops");
+    }
+
+    #[test]
+    fn test_log_rotates_when_full() {
+        let dir = std::env::temp_dir().join(format!("hardwave-log-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOG_FILE);
+        fs::write(&path, vec![b'x'; LOG_MAX_BYTES as usize]).unwrap();
+
+        let log = Logger::new(path.clone());
+        log.write("first".to_string());
+        log.write("second".to_string());
+        log.close();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "first\nsecond\n");
+        assert_eq!(fs::metadata(path.with_extension("log.1")).unwrap().len(), LOG_MAX_BYTES);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_log_thread_writes_what_was_queued_and_counts_the_rest() {
+        let dir = std::env::temp_dir().join(format!("hardwave-log-queue-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(LOG_FILE);
+
+        // Nothing is written on the thread that logs; a full queue drops
+        let log = Logger::new(path.clone());
+        for i in 0..LOG_QUEUE_LEN + 2 {
+            log.write(format!("line {}", i));
+        }
+        assert!(!path.exists());
+
+        log.start();
+        let last = format!("line {}\n", LOG_QUEUE_LEN - 1);
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while !fs::read_to_string(&path).is_ok_and(|log| log.ends_with(&last)) {
+            assert!(std::time::Instant::now() < deadline, "the queued lines were never written");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let written = fs::read_to_string(&path).unwrap();
+        assert!(written.lines().next().unwrap().ends_with("lines dropped, the log was busy"), "{}", written);
+        assert_eq!(written.lines().filter(|line| line.starts_with("line ")).count(), LOG_QUEUE_LEN);
+
+        // and teardown writes what came since
+        log.write("last".to_string());
+        log.close();
+        assert!(fs::read_to_string(&path).unwrap().ends_with("last\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,241 @@
+//! Consistency checks of the analysis state, for the `paranoid` setting
+//!
+//! The two channels run through identical decimators, rings and FFTs, so
+//! anything that tells them apart — a sample pushed to one ring only, a
+//! filter stage set on one side, an FFT planned differently — is a bug that
+//! shows as drift between left and right. With `paranoid` on in the machine
+//! config, and always in debug builds, the engine checks after every block
+//! that both rings hold the same number of samples in the same capacity and
+//! both decimators run the same stages, and every `FFT_CHECK_BLOCKS` blocks
+//! feeds both FFTs one fixed synthetic frame and compares their outputs bit
+//! for bit. A mismatch is returned as an `Inconsistency`; the engine then
+//! resets and has the state rebuilt.
+//!
+//! Off, no checker exists and nothing is checked.
+
+use std::fmt;
+use std::mem::size_of;
+
+use crate::analysis::AnalysisState;
+use crate::fft::FFT_SIZE;
+use crate::memory;
+use crate::params::ZeroPad;
+use crate::protocol::NUM_BINS;
+
+/// Blocks between FFT comparisons, a few seconds at usual block sizes
+pub const FFT_CHECK_BLOCKS: u32 = 500;
+
+/// How the two channels of an `AnalysisState` were found to differ
+#[derive(Debug, Clone, PartialEq)]
+pub enum Inconsistency {
+    /// The rings hold different numbers of samples
+    RingFill { left: usize, right: usize },
+    /// The rings were allocated for different lengths
+    RingCapacity { left: usize, right: usize },
+    /// The decimators divide the rate by different factors
+    Decimation { left: usize, right: usize },
+    /// The FFTs window or transform different lengths
+    FftShape { left: (usize, usize), right: (usize, usize) },
+    /// The FFTs disagree on the synthetic frame; the first differing bin
+    FftOutput { bin: usize, left: f32, right: f32 },
+}
+
+impl fmt::Display for Inconsistency {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Inconsistency::RingFill { left, right } => {
+                write!(f, "rings hold {} left and {} right samples", left, right)
+            }
+            Inconsistency::RingCapacity { left, right } => {
+                write!(f, "rings have room for {} left and {} right samples", left, right)
+            }
+            Inconsistency::Decimation { left, right } => {
+                write!(f, "decimators divide by {} left and {} right", left, right)
+            }
+            Inconsistency::FftShape { left, right } => write!(
+                f,
+                "FFTs window {} samples into {} bins left and {} into {} right",
+                left.0, left.1, right.0, right.1
+            ),
+            Inconsistency::FftOutput { bin, left, right } => write!(
+                f,
+                "FFTs differ at bin {} of the test frame: {:e} ({:#010x}) left, {:e} ({:#010x}) right",
+                bin,
+                left,
+                left.to_bits(),
+                right,
+                right.to_bits()
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Inconsistency {}
+
+/// The synthetic frame and the power sums the two FFTs write it into
+pub struct SelfCheck {
+    frame: Vec<f32>,
+    power_left: Vec<f32>,
+    power_right: Vec<f32>,
+    /// Blocks since the last FFT comparison
+    blocks: u32,
+}
+
+impl SelfCheck {
+    /// What `heap_bytes` reports: the frame and two power sums at the largest
+    /// padding
+    pub const HEAP_BYTES: usize = (FFT_SIZE + 2 * NUM_BINS * ZeroPad::MAX_FACTOR) * size_of::<f32>();
+
+    /// Allocates; call this off the audio thread
+    pub fn new() -> Self {
+        // Two tones off the bin centres over a little deterministic noise,
+        // so every bin of every padding gets something to disagree on
+        let mut noise = 0x2545_f491_u32;
+        let frame = (0..FFT_SIZE)
+            .map(|i| {
+                noise = noise.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let t = i as f32 / FFT_SIZE as f32;
+                let hiss = (noise >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                0.5 * (2.0 * std::f32::consts::PI * 37.3 * t).sin()
+                    + 0.25 * (2.0 * std::f32::consts::PI * 611.7 * t).sin()
+                    + 0.01 * hiss
+            })
+            .collect();
+        Self {
+            frame,
+            power_left: vec![0.0; NUM_BINS * ZeroPad::MAX_FACTOR],
+            power_right: vec![0.0; NUM_BINS * ZeroPad::MAX_FACTOR],
+            blocks: 0,
+        }
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        memory::vec_bytes(&self.frame) + memory::vec_bytes(&self.power_left) + memory::vec_bytes(&self.power_right)
+    }
+
+    /// Audio thread, after each block: the rings and decimators, and every
+    /// `FFT_CHECK_BLOCKS` blocks the FFTs. Never allocates.
+    pub fn after_block(&mut self, state: &mut AnalysisState) -> Result<(), Inconsistency> {
+        check_rings(state)?;
+        self.blocks += 1;
+        if self.blocks < FFT_CHECK_BLOCKS {
+            return Ok(());
+        }
+        self.blocks = 0;
+        self.check_ffts(state)
+    }
+
+    /// Transform the synthetic frame with both FFTs and compare bit for bit.
+    /// The FFTs keep no state between transforms, so the analysis isn't
+    /// disturbed. Never allocates.
+    pub fn check_ffts(&mut self, state: &mut AnalysisState) -> Result<(), Inconsistency> {
+        let shape = |fft: &crate::fft::FftProcessor| (fft.window_len(), fft.num_bins());
+        let (left, right) = (shape(&state.fft_left), shape(&state.fft_right));
+        if left != right {
+            return Err(Inconsistency::FftShape { left, right });
+        }
+        let power_left = &mut self.power_left[..left.1];
+        let power_right = &mut self.power_right[..right.1];
+        power_left.fill(0.0);
+        power_right.fill(0.0);
+        state.fft_left.accumulate_power(&self.frame, power_left);
+        state.fft_right.accumulate_power(&self.frame, power_right);
+
+        match power_left.iter().zip(power_right.iter()).position(|(l, r)| l.to_bits() != r.to_bits()) {
+            Some(bin) => Err(Inconsistency::FftOutput {
+                bin,
+                left: power_left[bin],
+                right: power_right[bin],
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Default for SelfCheck {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Both rings at the same fill and capacity, both decimators at the same
+/// factor
+pub fn check_rings(state: &AnalysisState) -> Result<(), Inconsistency> {
+    let (left, right) = (state.buffer_left.len(), state.buffer_right.len());
+    if left != right {
+        return Err(Inconsistency::RingFill { left, right });
+    }
+    let (left, right) = (state.buffer_left.capacity(), state.buffer_right.capacity());
+    if left != right {
+        return Err(Inconsistency::RingCapacity { left, right });
+    }
+    let (left, right) = (state.decimator_left.factor(), state.decimator_right.factor());
+    if left != right {
+        return Err(Inconsistency::Decimation { left, right });
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::fft::FftProcessor;
+
+    #[test]
+    fn test_identical_channels_pass() {
+        let mut check = SelfCheck::new();
+        for zero_pad in [ZeroPad::Off, ZeroPad::X4] {
+            for eco_mode in [false, true] {
+                let mut state = AnalysisState::build(AnalysisConfig { zero_pad, eco_mode, ..AnalysisConfig::default() });
+                for i in 0..100 {
+                    state.buffer(i as f32, -(i as f32));
+                }
+                assert_eq!(check.check_ffts(&mut state), Ok(()));
+                assert_eq!(check_rings(&state), Ok(()));
+            }
+        }
+        assert!(check.heap_bytes() <= SelfCheck::HEAP_BYTES);
+    }
+
+    #[test]
+    fn test_desynchronised_copies_are_caught() {
+        let config = AnalysisConfig::default();
+
+        let mut state = AnalysisState::build(config);
+        state.buffer_right.push(0.0);
+        assert_eq!(check_rings(&state), Err(Inconsistency::RingFill { left: 0, right: 1 }));
+
+        let mut state = AnalysisState::build(config);
+        state.decimator_right.set_stages(1);
+        assert_eq!(check_rings(&state), Err(Inconsistency::Decimation { left: 1, right: 2 }));
+
+        let mut check = SelfCheck::new();
+        let mut state = AnalysisState::build(config);
+        state.fft_right = FftProcessor::with_zero_pad(2);
+        assert!(matches!(check.check_ffts(&mut state), Err(Inconsistency::FftShape { .. })));
+
+        // A fresh processor planned the same way agrees bit for bit
+        let mut state = AnalysisState::build(config);
+        state.fft_right = FftProcessor::new();
+        assert_eq!(check.check_ffts(&mut state), Ok(()));
+
+        let err = Inconsistency::FftOutput { bin: 3, left: 1.0, right: 1.5 };
+        assert_eq!(
+            err.to_string(),
+            "FFTs differ at bin 3 of the test frame: 1e0 (0x3f800000) left, 1.5e0 (0x3fc00000) right"
+        );
+    }
+
+    #[test]
+    fn test_ffts_are_compared_every_interval() {
+        let mut check = SelfCheck::new();
+        let mut state = AnalysisState::build(AnalysisConfig::default());
+        state.fft_right = FftProcessor::with_zero_pad(2);
+        for _ in 1..FFT_CHECK_BLOCKS {
+            assert_eq!(check.after_block(&mut state), Ok(()));
+        }
+        assert!(check.after_block(&mut state).is_err());
+        assert_eq!(check.after_block(&mut state), Ok(()), "and not again until the next interval");
+    }
+}
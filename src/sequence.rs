@@ -0,0 +1,106 @@
+//! Sequence numbers, so the Suite can count the packets it didn't get
+//!
+//! `WebSocketClient::send` drops a packet rather than block the audio
+//! thread when the queue is full, and a Suite that hears nothing for a while
+//! can't tell a quiet plugin from one whose packets are being dropped. Every
+//! packet carries a `sequence` from one counter per instance, taken when the
+//! packet is built: frames and everything sent after them on the audio
+//! thread, heartbeats and everything else the connection thread makes. A
+//! gap is packets the Suite didn't get: dropped on a full queue, coalesced
+//! while the connection was behind, or not subscribed to. The two threads
+//! number independently of the order the packets go out in, so a heartbeat
+//! can arrive just ahead of the frame numbered before it.
+//!
+//! The counter starts at 0 when the plugin initialises and wraps from
+//! `u32::MAX` to 0. A frame served from the frozen history keeps the number
+//! it had when it was live, and live frames take none while they aren't
+//! sent, so freezing the history leaves no gap.
+//!
+//! Stamping a packet also tags it with the instance it comes from, see
+//! `display`, so the counter is per instance in the Suite as well.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::protocol::AudioPacket;
+
+/// The next sequence number, shared by the plugin, the connection thread
+/// and whatever else builds packets
+#[derive(Debug, Default)]
+pub struct PacketSequence {
+    next: AtomicU32,
+    /// The `instance_tag` of this instance
+    instance: AtomicU32,
+}
+
+impl PacketSequence {
+    #[cfg(test)]
+    fn starting_at(next: u32) -> Self {
+        Self { next: AtomicU32::new(next), ..Self::default() }
+    }
+
+    /// Number `packet`, the next after the last one numbered, and tag it
+    pub fn stamp(&self, packet: &mut AudioPacket) {
+        // Wraps at u32::MAX
+        packet.sequence = self.next.fetch_add(1, Ordering::Relaxed);
+        packet.instance_tag = self.instance.load(Ordering::Relaxed);
+    }
+
+    /// Tag the packets stamped from now on with `instance_tag`
+    pub fn set_instance(&self, instance_tag: u32) {
+        self.instance.store(instance_tag, Ordering::Relaxed);
+    }
+
+    /// Start again from 0
+    pub fn reset(&self) {
+        self.next.store(0, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stamped(sequence: &PacketSequence, packet: AudioPacket) -> u32 {
+        let mut packet = packet;
+        sequence.stamp(&mut packet);
+        AudioPacket::from_bytes(&packet.to_bytes()).unwrap().sequence
+    }
+
+    #[test]
+    fn test_packets_are_numbered_without_gaps() {
+        let sequence = PacketSequence::default();
+        let numbers: Vec<u32> = (0..10)
+            .map(|i| {
+                let packet = if i % 3 == 0 { AudioPacket::new_heartbeat(0, 0) } else { AudioPacket::fft(48000, i) };
+                stamped(&sequence, packet)
+            })
+            .collect();
+        assert_eq!(numbers, (0..10).collect::<Vec<u32>>(), "frames and heartbeats share the counter");
+
+        sequence.reset();
+        assert_eq!(stamped(&sequence, AudioPacket::fft(48000, 0)), 0);
+    }
+
+    #[test]
+    fn test_packets_are_tagged_with_the_instance() {
+        let sequence = PacketSequence::default();
+        let mut packet = AudioPacket::fft(48000, 0);
+        sequence.stamp(&mut packet);
+        assert_eq!(packet.instance_tag, 0, "untagged until set");
+
+        sequence.set_instance(0x8577_0bfd);
+        sequence.stamp(&mut packet);
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!((decoded.sequence, decoded.instance_tag), (1, 0x8577_0bfd));
+        sequence.reset();
+        sequence.stamp(&mut packet);
+        assert_eq!(packet.instance_tag, 0x8577_0bfd, "kept across a reset");
+    }
+
+    #[test]
+    fn test_sequence_wraps() {
+        let sequence = PacketSequence::starting_at(u32::MAX - 1);
+        let numbers: Vec<u32> = (0..4).map(|_| stamped(&sequence, AudioPacket::new_heartbeat(0, 0))).collect();
+        assert_eq!(numbers, [u32::MAX - 1, u32::MAX, 0, 1]);
+    }
+}
@@ -0,0 +1,88 @@
+//! State shared between the plugin, its editor and the network thread
+
+use std::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+
+/// Tracks whether a plugin window is open.
+///
+/// Hosts may spawn a new editor before dropping the old one, so this counts
+/// live editor handles and only treats 0 ↔ 1 as a transition. Every
+/// transition bumps `seq`, which lets the network thread detect changes and
+/// report only the latest state after rapid open/close cycles.
+#[derive(Debug, Default)]
+pub struct EditorLifecycle {
+    open_handles: AtomicUsize,
+    seq: AtomicU32,
+}
+
+impl EditorLifecycle {
+    /// Called from `Editor::spawn`.
+    #[cfg(any(feature = "gui", test))]
+    pub fn opened(&self) {
+        if self.open_handles.fetch_add(1, Ordering::AcqRel) == 0 {
+            self.seq.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    /// Called when an editor handle is dropped.
+    #[cfg(any(feature = "gui", test))]
+    pub fn closed(&self) {
+        let prev = self
+            .open_handles
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if prev == Ok(1) {
+            self.seq.fetch_add(1, Ordering::AcqRel);
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        self.open_handles.load(Ordering::Acquire) > 0
+    }
+
+    /// Transition counter; changes whenever `is_open` does
+    pub fn seq(&self) -> u32 {
+        self.seq.load(Ordering::Acquire)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_close_sequence() {
+        let editor = EditorLifecycle::default();
+        assert!(!editor.is_open());
+        assert_eq!(editor.seq(), 0);
+
+        editor.opened();
+        assert!(editor.is_open());
+        assert_eq!(editor.seq(), 1);
+
+        editor.closed();
+        assert!(!editor.is_open());
+        assert_eq!(editor.seq(), 2);
+    }
+
+    #[test]
+    fn test_reopen_before_old_handle_drops() {
+        let editor = EditorLifecycle::default();
+        editor.opened();
+        // New window spawned before the old handle is dropped
+        editor.opened();
+        editor.closed();
+        assert!(editor.is_open());
+        assert_eq!(editor.seq(), 1, "no transition while a window stays open");
+
+        editor.closed();
+        assert!(!editor.is_open());
+        assert_eq!(editor.seq(), 2);
+    }
+
+    #[test]
+    fn test_extra_close_is_ignored() {
+        let editor = EditorLifecycle::default();
+        editor.closed();
+        assert!(!editor.is_open());
+        assert_eq!(editor.seq(), 0);
+    }
+}
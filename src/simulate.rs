@@ -0,0 +1,245 @@
+//! Synthesised input for working on the plugin window without audio
+//!
+//! Designing the web analyser inside a DAW needs signal, and a silent track
+//! leaves most of what the page can show unreachable. With `SIMULATE_ENV`
+//! set in the host's environment, an engine whose input has been silent for
+//! `ENGAGE_AFTER_S` analyses program-like material of its own instead:
+//! low-passed noise, a kick on every beat at `TEMPO_BPM` with a hat on the
+//! off-beat, and a tone sweeping from `SWEEP_LOW_HZ` to `SWEEP_HIGH_HZ`. The
+//! first block with a sample above `SILENCE` is analysed as it is again, and
+//! every frame with a synthesised sample in its window says `simulated`.
+//!
+//! It is a developer setting, never a host parameter, so no project can turn
+//! it on. The variable's value seeds the noise (a number; anything else is
+//! `DEFAULT_SEED`), and each engagement starts over from the seed, so a seed
+//! gives the same frames every time for UI tests. Synthesised samples skip
+//! the click and stereo checks.
+
+use std::f32::consts::TAU;
+
+/// Env var that turns simulated input on, with the seed as its value
+pub const SIMULATE_ENV: &str = "HARDWAVE_SIMULATE_INPUT";
+
+/// Seconds of silent input before the simulation takes over
+pub const ENGAGE_AFTER_S: f32 = 3.0;
+
+/// Input samples at or under this (-80 dBFS) are silence; NaN and Inf
+/// aren't
+pub const SILENCE: f32 = 1e-4;
+
+/// Seed for a value that isn't a number, or is 0
+pub const DEFAULT_SEED: u32 = 0x5eed_cafe;
+
+const TEMPO_BPM: f32 = 120.0;
+const SWEEP_LOW_HZ: f32 = 40.0;
+const SWEEP_HIGH_HZ: f32 = 12_000.0;
+const SWEEP_S: f32 = 8.0;
+
+/// Corner of the noise's one-pole low pass
+const NOISE_CUTOFF_HZ: f32 = 2_000.0;
+
+/// The seed from `SIMULATE_ENV`, if it is set
+pub fn seed_from_env() -> Option<u32> {
+    std::env::var_os(SIMULATE_ENV).map(|value| parse_seed(&value.to_string_lossy()))
+}
+
+fn parse_seed(value: &str) -> u32 {
+    value.trim().parse().ok().filter(|&seed| seed != 0).unwrap_or(DEFAULT_SEED)
+}
+
+fn is_silent(sample: f32) -> bool {
+    sample.is_finite() && sample.abs() <= SILENCE
+}
+
+/// Decides block by block whether the input is replaced, and makes what
+/// replaces it. Audio thread only; never allocates.
+#[derive(Debug, Clone)]
+pub struct InputSimulator {
+    seed: u32,
+    /// Host samples of silence before the current block
+    silent_samples: u64,
+    engaged: bool,
+    generator: Generator,
+}
+
+impl InputSimulator {
+    pub fn new(seed: u32) -> Self {
+        let seed = if seed == 0 { DEFAULT_SEED } else { seed };
+        Self { seed, silent_samples: 0, engaged: false, generator: Generator::new(seed) }
+    }
+
+    /// Once per block, before it is analysed: whether the block is replaced
+    /// with `next` samples. Any sample above `SILENCE` disengages at once.
+    pub fn observe(&mut self, left: &[f32], right: &[f32], sample_rate: f32) -> bool {
+        if !left.iter().chain(right).all(|&sample| is_silent(sample)) {
+            self.silent_samples = 0;
+            self.engaged = false;
+            return false;
+        }
+        if !self.engaged && self.silent_samples as f32 >= ENGAGE_AFTER_S * sample_rate {
+            self.engaged = true;
+            self.generator = Generator::new(self.seed);
+        }
+        self.silent_samples += left.len() as u64;
+        self.engaged
+    }
+
+    #[cfg(test)]
+    pub fn engaged(&self) -> bool {
+        self.engaged
+    }
+
+    /// The next synthesised left and right sample, at `sample_rate`
+    pub fn next(&mut self, sample_rate: f32) -> (f32, f32) {
+        self.generator.next(sample_rate)
+    }
+}
+
+/// The synthesised signal, from its start
+#[derive(Debug, Clone)]
+struct Generator {
+    /// xorshift32 state, never 0
+    noise: u32,
+    /// Samples since the start
+    samples: u64,
+    /// The low passes of each channel's noise
+    low_left: f32,
+    low_right: f32,
+    /// The previous noise sample, for the high-passed hat
+    last_noise: f32,
+    kick_phase: f32,
+    sweep_phase: f32,
+}
+
+impl Generator {
+    fn new(seed: u32) -> Self {
+        Self {
+            noise: seed,
+            samples: 0,
+            low_left: 0.0,
+            low_right: 0.0,
+            last_noise: 0.0,
+            kick_phase: 0.0,
+            sweep_phase: 0.0,
+        }
+    }
+
+    /// Same xorshift32 as the test vectors, in -1..1
+    fn noise(&mut self) -> f32 {
+        let mut x = self.noise;
+        x ^= x << 13;
+        x ^= x >> 17;
+        x ^= x << 5;
+        self.noise = x;
+        x as f32 / u32::MAX as f32 * 2.0 - 1.0
+    }
+
+    fn next(&mut self, sample_rate: f32) -> (f32, f32) {
+        let t = (self.samples as f64 / f64::from(sample_rate)) as f32;
+        self.samples += 1;
+
+        // Noise, partly correlated between the channels
+        let (a, b) = (self.noise(), self.noise());
+        let low = 1.0 - (-TAU * NOISE_CUTOFF_HZ / sample_rate).exp();
+        self.low_left += low * (0.7 * a + 0.3 * b - self.low_left);
+        self.low_right += low * (0.3 * a + 0.7 * b - self.low_right);
+
+        // A kick falling from 150 to 50 Hz on the beat, a hat between
+        let beat_s = 60.0 / TEMPO_BPM;
+        let since_beat = t % beat_s;
+        let since_off_beat = (t + beat_s / 2.0) % beat_s;
+        self.kick_phase = (self.kick_phase + TAU * (50.0 + 100.0 * (-30.0 * since_beat).exp()) / sample_rate) % TAU;
+        let kick = (-12.0 * since_beat).exp() * self.kick_phase.sin();
+        let hat = (-60.0 * since_off_beat).exp() * (a - self.last_noise) * 0.5;
+        self.last_noise = a;
+
+        // A tone rising evenly in pitch, over and over
+        let sweep_hz = SWEEP_LOW_HZ * (SWEEP_HIGH_HZ / SWEEP_LOW_HZ).powf(t % SWEEP_S / SWEEP_S);
+        self.sweep_phase = (self.sweep_phase + TAU * sweep_hz / sample_rate) % TAU;
+        let sweep = self.sweep_phase.sin();
+
+        let common = 0.45 * kick + 0.1 * hat;
+        (common + 0.3 * self.low_left + 0.15 * sweep, common + 0.3 * self.low_right + 0.12 * sweep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: f32 = 48_000.0;
+    const BLOCK: usize = 480;
+
+    #[test]
+    fn test_engages_after_silence_and_disengages_at_once() {
+        let mut simulator = InputSimulator::new(7);
+        let silence = [0.0; BLOCK];
+        // 3 s of silence are analysed as they are, and noise under -80 dBFS
+        // counts as silence
+        let quiet = [SILENCE; BLOCK];
+        for block in 0..300 {
+            let input = if block % 2 == 0 { &silence } else { &quiet };
+            assert!(!simulator.observe(input, input, RATE), "engaged after block {}", block);
+        }
+        assert!(simulator.observe(&silence, &silence, RATE));
+        assert!(simulator.engaged());
+        assert!(simulator.observe(&silence, &silence, RATE));
+
+        // One sample of signal in either channel, or a bad one, ends it
+        let mut signal = [0.0; BLOCK];
+        signal[BLOCK - 1] = 0.001;
+        assert!(!simulator.observe(&silence, &signal, RATE));
+        assert!(!simulator.engaged());
+        // And the silence counts from zero again
+        assert!(!simulator.observe(&silence, &silence, RATE));
+        for _ in 0..300 {
+            simulator.observe(&silence, &silence, RATE);
+        }
+        assert!(simulator.engaged());
+        let mut bad = [0.0; BLOCK];
+        bad[0] = f32::NAN;
+        assert!(!simulator.observe(&bad, &bad, RATE));
+    }
+
+    #[test]
+    fn test_same_seed_same_signal() {
+        let render = |seed: u32| {
+            let mut simulator = InputSimulator::new(seed);
+            (0..RATE as usize).map(|_| simulator.next(RATE)).collect::<Vec<_>>()
+        };
+        let signal = render(7);
+        assert_eq!(signal, render(7));
+        assert_ne!(signal, render(8));
+
+        // Moving, in range, and not one channel copied into the other
+        assert!(signal.iter().all(|&(left, right)| left.abs() <= 1.0 && right.abs() <= 1.0));
+        let peak = signal.iter().fold(0.0_f32, |peak, &(left, _)| peak.max(left.abs()));
+        assert!(peak > 0.3, "peak {}", peak);
+        assert!(signal.iter().any(|&(left, right)| left != right));
+
+        // Each engagement starts over from the seed
+        let mut simulator = InputSimulator::new(7);
+        let silence = [0.0; BLOCK];
+        for _ in 0..=300 {
+            simulator.observe(&silence, &silence, RATE);
+        }
+        let first: Vec<_> = (0..BLOCK).map(|_| simulator.next(RATE)).collect();
+        simulator.observe(&[1.0], &[1.0], RATE);
+        for _ in 0..=300 {
+            simulator.observe(&silence, &silence, RATE);
+        }
+        let again: Vec<_> = (0..BLOCK).map(|_| simulator.next(RATE)).collect();
+        assert_eq!(first, again);
+        assert_eq!(first[..], signal[..BLOCK]);
+    }
+
+    #[test]
+    fn test_seed_from_the_variable() {
+        assert_eq!(parse_seed("1234"), 1234);
+        assert_eq!(parse_seed(" 42\n"), 42);
+        assert_eq!(parse_seed("1"), 1);
+        assert_eq!(parse_seed("yes"), DEFAULT_SEED);
+        assert_eq!(parse_seed(""), DEFAULT_SEED);
+        assert_eq!(parse_seed("0"), DEFAULT_SEED);
+    }
+}
@@ -0,0 +1,349 @@
+//! Two complete analysis configurations to flip between
+//!
+//! The `active_slot` parameter picks slot A or B. The analysis parameters
+//! always edit the active slot: a changed parameter is written into it, one
+//! setting at a time. A slot nobody has left yet follows the parameters
+//! exactly, so a project from before slots analyses as it always did, and
+//! the first visit to B starts from where A was left.
+//!
+//! Switching doesn't touch the parameters, which would take a host gesture
+//! per setting and fill the undo history; the host keeps showing the values
+//! last set, and only the changed ones are taken into the new slot. The
+//! engine gets the whole slot in one `set_settings`, so no frame mixes the
+//! two, and only the frame in progress is lost, if any is.
+//!
+//! Eco mode is the exception: it is about the machine, not the analysis, so
+//! it isn't kept in a slot and always follows its parameter.
+
+use serde::{Deserialize, Serialize};
+use std::sync::RwLock;
+
+use crate::engine::EngineSettings;
+use crate::params::AnalysisSlot;
+
+/// The saved slots. `None` is a slot that follows the parameters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct AnalysisSlots {
+    a: Option<EngineSettings>,
+    b: Option<EngineSettings>,
+}
+
+impl AnalysisSlots {
+    fn slot_mut(&mut self, slot: AnalysisSlot) -> &mut Option<EngineSettings> {
+        match slot {
+            AnalysisSlot::A => &mut self.a,
+            AnalysisSlot::B => &mut self.b,
+        }
+    }
+
+    /// What `slot` analyses with while the parameters read `params`
+    pub fn settings(&self, slot: AnalysisSlot, params: EngineSettings) -> EngineSettings {
+        match slot {
+            AnalysisSlot::A => self.a,
+            AnalysisSlot::B => self.b,
+        }
+        .unwrap_or(params)
+    }
+
+    /// Make `to` a copy of `from`
+    #[cfg(any(feature = "gui", test))]
+    pub fn copy(&mut self, from: AnalysisSlot, to: AnalysisSlot, params: EngineSettings) {
+        let settings = self.settings(from, params);
+        *self.slot_mut(to) = Some(settings);
+    }
+
+    /// Keep `params` in `slot` if it was following them, as it is left
+    fn freeze(&mut self, slot: AnalysisSlot, params: EngineSettings) {
+        self.slot_mut(slot).get_or_insert(params);
+    }
+
+    /// Take the settings that changed from `before` to `after` into `slot`
+    fn edit(&mut self, slot: AnalysisSlot, before: &EngineSettings, after: &EngineSettings) {
+        let Some(s) = self.slot_mut(slot) else {
+            return;
+        };
+        if before.full_bandwidth != after.full_bandwidth {
+            s.full_bandwidth = after.full_bandwidth;
+        }
+        if before.zero_pad != after.zero_pad {
+            s.zero_pad = after.zero_pad;
+        }
+        if before.window != after.window {
+            s.window = after.window;
+        }
+        if before.normalized_rate != after.normalized_rate {
+            s.normalized_rate = after.normalized_rate;
+        }
+        if before.channel_mode != after.channel_mode {
+            s.channel_mode = after.channel_mode;
+        }
+        if before.weighting != after.weighting {
+            s.weighting = after.weighting;
+        }
+        if before.alignment != after.alignment {
+            s.alignment = after.alignment;
+        }
+        if before.coarse_mapping != after.coarse_mapping {
+            s.coarse_mapping = after.coarse_mapping;
+        }
+        if before.coarse_bands != after.coarse_bands {
+            s.coarse_bands = after.coarse_bands;
+        }
+        if before.band_activity != after.band_activity {
+            s.band_activity = after.band_activity;
+        }
+        if before.delay_check != after.delay_check {
+            s.delay_check = after.delay_check;
+        }
+        if before.band_attack != after.band_attack {
+            s.band_attack = after.band_attack;
+        }
+        if before.band_release != after.band_release {
+            s.band_release = after.band_release;
+        }
+        if before.band_peak_hold != after.band_peak_hold {
+            s.band_peak_hold = after.band_peak_hold;
+        }
+        if before.band_peak_decay != after.band_peak_decay {
+            s.band_peak_decay = after.band_peak_decay;
+        }
+        if before.peak_hold != after.peak_hold {
+            s.peak_hold = after.peak_hold;
+        }
+        if before.peak_release != after.peak_release {
+            s.peak_release = after.peak_release;
+        }
+        if before.rms_integration != after.rms_integration {
+            s.rms_integration = after.rms_integration;
+        }
+    }
+}
+
+/// The slots of a `copySlot` request, `"A>B"` copying A into B
+#[cfg(any(feature = "gui", test))]
+pub fn parse_copy(request: &str) -> Option<(AnalysisSlot, AnalysisSlot)> {
+    let (from, to) = request.split_once('>')?;
+    Some((AnalysisSlot::parse(from)?, AnalysisSlot::parse(to)?))
+}
+
+/// Audio-thread side of the slots: notices switches and edits, and keeps the
+/// settings of the active slot
+#[derive(Debug, Clone, Copy)]
+pub struct SlotTracker {
+    active: AnalysisSlot,
+    /// Parameter values as of the last update
+    params: EngineSettings,
+    settings: EngineSettings,
+}
+
+impl SlotTracker {
+    /// Start from the parameters as they are, e.g. just after a project was
+    /// restored, without counting them as edits
+    pub fn new(active: AnalysisSlot, params: EngineSettings, slots: &AnalysisSlots) -> Self {
+        Self {
+            active,
+            params,
+            settings: Self::with_eco_mode(slots.settings(active, params), params),
+        }
+    }
+
+    /// `settings` with eco mode as the parameters have it
+    fn with_eco_mode(settings: EngineSettings, params: EngineSettings) -> EngineSettings {
+        EngineSettings {
+            eco_mode: params.eco_mode,
+            ..settings
+        }
+    }
+
+    pub fn active(&self) -> AnalysisSlot {
+        self.active
+    }
+
+    /// Audio thread, once per block, with the parameters' values: the
+    /// settings to analyse with. Only ever tries the lock; while someone
+    /// else holds it (saving the project, copying a slot) nothing changes
+    /// until a later block gets it.
+    pub fn update(&mut self, active: AnalysisSlot, params: EngineSettings, shared: &RwLock<AnalysisSlots>) -> EngineSettings {
+        if let Ok(mut slots) = shared.try_write() {
+            if active != self.active {
+                slots.freeze(self.active, self.params);
+            }
+            slots.edit(active, &self.params, &params);
+            self.active = active;
+            self.params = params;
+            self.settings = Self::with_eco_mode(slots.settings(active, params), params);
+        }
+        self.settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::analysis::AnalysisConfig;
+    use crate::engine::AnalysisEngine;
+    use crate::params::{AnalysisAlignment, HardwaveAnalyserParams, RmsIntegration};
+    use nih_plug::prelude::*;
+
+    const BLOCK: usize = 480;
+
+    /// "Fast, peak detector, no smoothing"
+    fn fast() -> EngineSettings {
+        EngineSettings {
+            peak_hold: 0.0,
+            peak_release: 60.0,
+            rms_integration: RmsIntegration::Instant,
+            ..EngineSettings::default()
+        }
+    }
+
+    /// "Slow, averaged"
+    fn slow() -> EngineSettings {
+        EngineSettings {
+            alignment: AnalysisAlignment::AverageOfInterval,
+            peak_hold: 3.0,
+            peak_release: 5.0,
+            coarse_bands: 16,
+            ..EngineSettings::default()
+        }
+    }
+
+    #[test]
+    fn test_edits_go_to_the_active_slot() {
+        let shared = RwLock::new(AnalysisSlots::default());
+        let mut tracker = SlotTracker::new(AnalysisSlot::A, fast(), &shared.read().unwrap());
+        assert_eq!(tracker.update(AnalysisSlot::A, fast(), &shared), fast());
+
+        // B starts as a copy of A and follows the parameters from there
+        assert_eq!(tracker.update(AnalysisSlot::B, fast(), &shared), fast());
+        assert_eq!(tracker.update(AnalysisSlot::B, slow(), &shared), slow());
+
+        // Back on A, the parameters still read B's values, but A is as left
+        assert_eq!(tracker.update(AnalysisSlot::A, slow(), &shared), fast());
+
+        // Editing one parameter edits only that setting of A
+        let edited = EngineSettings { peak_hold: 1.0, ..slow() };
+        assert_eq!(tracker.update(AnalysisSlot::A, edited, &shared), EngineSettings { peak_hold: 1.0, ..fast() });
+        assert_eq!(tracker.update(AnalysisSlot::B, edited, &shared), slow());
+    }
+
+    #[test]
+    fn test_copy_request() {
+        assert_eq!(parse_copy("A>B"), Some((AnalysisSlot::A, AnalysisSlot::B)));
+        assert_eq!(parse_copy(" b > a "), Some((AnalysisSlot::B, AnalysisSlot::A)));
+        assert_eq!(parse_copy("A"), None);
+        assert_eq!(parse_copy("A>C"), None);
+
+        // Copying a slot that follows the parameters copies their values
+        let mut slots = AnalysisSlots::default();
+        slots.copy(AnalysisSlot::A, AnalysisSlot::B, slow());
+        assert_eq!(slots.settings(AnalysisSlot::B, fast()), slow());
+        assert_eq!(slots.settings(AnalysisSlot::A, fast()), fast());
+    }
+
+    #[test]
+    fn test_busy_lock_defers_the_switch() {
+        let shared = RwLock::new(AnalysisSlots::default());
+        let mut tracker = SlotTracker::new(AnalysisSlot::A, fast(), &shared.read().unwrap());
+        shared.write().unwrap().copy(AnalysisSlot::A, AnalysisSlot::B, slow());
+
+        let saving = shared.read().unwrap();
+        assert_eq!(tracker.update(AnalysisSlot::B, fast(), &shared), fast());
+        assert_eq!(tracker.active(), AnalysisSlot::A);
+        drop(saving);
+        assert_eq!(tracker.update(AnalysisSlot::B, fast(), &shared), slow());
+        assert_eq!(tracker.active(), AnalysisSlot::B);
+    }
+
+    #[test]
+    fn test_slots_persist_with_the_project() {
+        let params = HardwaveAnalyserParams::default();
+        {
+            let mut slots = params.analysis_slots.write().unwrap();
+            slots.freeze(AnalysisSlot::A, fast());
+            slots.freeze(AnalysisSlot::B, slow());
+            slots.edit(AnalysisSlot::B, &slow(), &EngineSettings { coarse_bands: 24, ..slow() });
+        }
+        let saved = params.serialize_fields();
+
+        let restored = HardwaveAnalyserParams::default();
+        restored.deserialize_fields(&saved);
+        let slots = *restored.analysis_slots.read().unwrap();
+        assert_eq!(slots, *params.analysis_slots.read().unwrap());
+        let defaults = EngineSettings::default();
+        assert_eq!(slots.settings(AnalysisSlot::A, defaults), fast());
+        assert_eq!(slots.settings(AnalysisSlot::B, defaults).coarse_bands, 24);
+
+        // A project from before slots follows its parameters
+        let old = HardwaveAnalyserParams::default();
+        old.deserialize_fields(&Default::default());
+        assert_eq!(old.analysis_slots.read().unwrap().settings(AnalysisSlot::B, slow()), slow());
+    }
+
+    #[test]
+    fn test_active_slot_is_an_automatable_parameter() {
+        let params = HardwaveAnalyserParams::default();
+        assert!(params.param_map().iter().any(|(id, _, _)| id == "active_slot"));
+        assert!(!params.analysis.active_slot.flags().contains(ParamFlags::NON_AUTOMATABLE));
+        assert_eq!(params.analysis.active_slot.value(), AnalysisSlot::A);
+        assert_eq!(params.analysis.active_slot.preview_normalized(AnalysisSlot::B), 1.0);
+    }
+
+    #[test]
+    fn test_eco_mode_follows_its_parameter_in_both_slots() {
+        let params = HardwaveAnalyserParams::default();
+        assert!(params.param_map().iter().any(|(id, _, _)| id == "eco_mode"));
+        assert!(!params.analysis.eco_mode.flags().contains(ParamFlags::NON_AUTOMATABLE));
+
+        let shared = RwLock::new(AnalysisSlots::default());
+        let mut tracker = SlotTracker::new(AnalysisSlot::A, fast(), &shared.read().unwrap());
+        tracker.update(AnalysisSlot::B, slow(), &shared);
+
+        // Automated on in B, it stays on switching to A, and off again there
+        let eco = |settings| EngineSettings { eco_mode: true, ..settings };
+        assert_eq!(tracker.update(AnalysisSlot::B, eco(slow()), &shared), eco(slow()));
+        assert_eq!(tracker.update(AnalysisSlot::A, eco(slow()), &shared), eco(fast()));
+        assert_eq!(tracker.update(AnalysisSlot::A, slow(), &shared), fast());
+        assert_eq!(tracker.update(AnalysisSlot::B, slow(), &shared), slow());
+
+        // Nor is it saved with a slot
+        shared.write().unwrap().copy(AnalysisSlot::B, AnalysisSlot::A, eco(slow()));
+        let saved = serde_json::to_string(&*shared.read().unwrap()).unwrap();
+        assert!(!saved.contains("eco_mode"), "{}", saved);
+    }
+
+    #[test]
+    fn test_switching_mid_playback_loses_at_most_one_frame() {
+        let shared = RwLock::new(AnalysisSlots::default());
+        shared.write().unwrap().copy(AnalysisSlot::A, AnalysisSlot::B, slow());
+        let mut tracker = SlotTracker::new(AnalysisSlot::A, fast(), &shared.read().unwrap());
+        let mut engine = AnalysisEngine::new(AnalysisConfig::default());
+        engine.set_settings(tracker.update(AnalysisSlot::A, fast(), &shared));
+
+        // Automation flips the slot every seven blocks, out of step with the
+        // five-block frame interval
+        let mut frames = Vec::new();
+        for block in 0..200 {
+            let slot = if (block / 7) % 2 == 0 { AnalysisSlot::A } else { AnalysisSlot::B };
+            engine.set_settings(tracker.update(slot, fast(), &shared));
+            engine.push_samples(&[0.0; BLOCK], &[0.0; BLOCK]);
+            if let Some(frame) = engine.poll_frame() {
+                frames.push((block, slot, frame));
+            }
+        }
+
+        for window in frames.windows(2) {
+            let ((earlier, _, _), (later, _, _)) = (&window[0], &window[1]);
+            assert!(later - earlier <= 10, "frames after blocks {} and {}", earlier, later);
+        }
+        // Every frame is wholly one slot's
+        for (block, slot, frame) in &frames {
+            let (alignment, bands) = match slot {
+                AnalysisSlot::A => (fast().alignment, fast().coarse_bands),
+                AnalysisSlot::B => (slow().alignment, slow().coarse_bands),
+            };
+            assert_eq!(frame.alignment, alignment, "block {}", block);
+            assert_eq!(frame.left_coarse.len(), bands, "block {}", block);
+        }
+    }
+}
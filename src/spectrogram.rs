@@ -0,0 +1,285 @@
+//! Spectrogram of the last half minute, for sharing as an image
+//!
+//! The editor keeps one column per FFT packet it receives, reduced to
+//! `BANDS` log-spaced bands, in a fixed ring of `COLUMNS`. Rendering to PNG
+//! happens on whichever thread asks for the image (the packet server's
+//! `/spectrogram.png` route or the `saveSpectrogram:` IPC command), never on
+//! the audio thread.
+//!
+//! The image has time on X, oldest on the left, and frequency on Y, lowest
+//! at the bottom. Columns the history hasn't filled yet are drawn at the
+//! floor colour.
+
+use std::fs;
+use std::io;
+use std::mem::size_of;
+use std::path::Path;
+
+use crate::bands::{HIGH_HZ, LOW_HZ};
+use crate::fft::FFT_SIZE;
+use crate::protocol::{AudioPacket, PACKET_TYPE_FFT};
+use crate::units::FLOOR_DB;
+
+/// Columns kept: 30 s at the ~20 Hz frame rate
+pub const COLUMNS: usize = 600;
+
+/// Log-spaced bands between `LOW_HZ` and `HIGH_HZ`
+pub const BANDS: usize = 128;
+
+/// Pixel rows per band in the rendered image
+pub const ROW_PIXELS: usize = 2;
+
+/// Size of the ring, allocated once
+pub const HISTORY_BYTES: usize = COLUMNS * BANDS * size_of::<f32>();
+
+/// Colour map from the floor (0.0) to 0 dB (1.0)
+const COLOR_STOPS: [(f32, [u8; 3]); 5] = [
+    (0.0, [0, 0, 0]),
+    (0.25, [40, 0, 90]),
+    (0.5, [180, 20, 90]),
+    (0.75, [250, 140, 20]),
+    (1.0, [255, 255, 210]),
+];
+
+/// Band levels of the most recent `COLUMNS` frames
+#[derive(Debug, Clone)]
+pub struct SpectrogramHistory {
+    /// `COLUMNS` columns of `BANDS` levels in dB, a ring starting at `next`
+    levels: Vec<f32>,
+    next: usize,
+    filled: usize,
+    /// `BANDS + 1` band edges in Hz
+    edges: Vec<f32>,
+}
+
+impl SpectrogramHistory {
+    pub fn new() -> Self {
+        let ratio = HIGH_HZ / LOW_HZ;
+        Self {
+            levels: vec![FLOOR_DB; COLUMNS * BANDS],
+            next: 0,
+            filled: 0,
+            edges: (0..=BANDS)
+                .map(|i| LOW_HZ * ratio.powf(i as f32 / BANDS as f32))
+                .collect(),
+        }
+    }
+
+    /// Columns recorded so far, at most `COLUMNS`
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.filled
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.filled == 0
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        (self.levels.capacity() + self.edges.capacity()) * size_of::<f32>()
+    }
+
+    /// Add a column for a live FFT packet; other packets, and frames served
+    /// from the frozen history, are ignored. Each band reads the loudest bin
+    /// it covers in either channel, or the bin nearest its centre if it is
+    /// narrower than a bin.
+    pub fn push(&mut self, packet: &AudioPacket) {
+        if packet.packet_type != PACKET_TYPE_FFT
+            || packet.historical
+            || packet.left_bins.is_empty()
+            || packet.sample_rate == 0
+        {
+            return;
+        }
+        let bin_hz = packet.sample_rate as f32 / (FFT_SIZE * packet.zero_pad.max(1) as usize) as f32;
+        let start = self.next * BANDS;
+        let column = &mut self.levels[start..start + BANDS];
+        for (band, level) in column.iter_mut().enumerate() {
+            let (low, high) = (self.edges[band], self.edges[band + 1]);
+            let first = (low / bin_hz).ceil() as usize;
+            let last = (high / bin_hz).floor() as usize;
+            let bins = if first <= last {
+                first..=last
+            } else {
+                let centre = ((low * high).sqrt() / bin_hz).round() as usize;
+                centre..=centre
+            };
+            *level = bins
+                .flat_map(|k| [packet.left_bins.get(k), packet.right_bins.get(k)])
+                .flatten()
+                .fold(FLOOR_DB, |loudest, &db| loudest.max(db));
+        }
+        self.next = (self.next + 1) % COLUMNS;
+        self.filled = (self.filled + 1).min(COLUMNS);
+    }
+
+    /// Image size in pixels
+    pub fn dimensions() -> (u32, u32) {
+        (COLUMNS as u32, (BANDS * ROW_PIXELS) as u32)
+    }
+
+    /// RGB pixels, row by row from the top
+    fn pixels(&self) -> Vec<u8> {
+        let (width, height) = Self::dimensions();
+        let mut rgb = Vec::with_capacity(width as usize * height as usize * 3);
+        for y in 0..height as usize {
+            let band = BANDS - 1 - y / ROW_PIXELS;
+            for x in 0..COLUMNS {
+                let db = if x < COLUMNS - self.filled {
+                    FLOOR_DB
+                } else {
+                    self.levels[(self.next + x) % COLUMNS * BANDS + band]
+                };
+                rgb.extend_from_slice(&color(db));
+            }
+        }
+        rgb
+    }
+
+    /// Encode the history as a PNG
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let (width, height) = Self::dimensions();
+        let mut out = Vec::new();
+        let mut encoder = png::Encoder::new(&mut out, width, height);
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        encoder.set_compression(png::Compression::Fast);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&self.pixels())?;
+        writer.finish()?;
+        Ok(out)
+    }
+
+    /// Write the PNG to `path`, replacing any file there
+    pub fn save_png(&self, path: &Path) -> io::Result<()> {
+        fs::write(path, self.to_png()?)
+    }
+}
+
+impl Default for SpectrogramHistory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Colour of a level between the floor and 0 dB
+fn color(db: f32) -> [u8; 3] {
+    let t = ((db - FLOOR_DB) / -FLOOR_DB).clamp(0.0, 1.0);
+    let upper = COLOR_STOPS.iter().position(|&(at, _)| at >= t).unwrap_or(COLOR_STOPS.len() - 1).max(1);
+    let (a_at, a) = COLOR_STOPS[upper - 1];
+    let (b_at, b) = COLOR_STOPS[upper];
+    let f = (t - a_at) / (b_at - a_at);
+    let mix = |i: usize| (a[i] as f32 + (b[i] as f32 - a[i] as f32) * f).round() as u8;
+    [mix(0), mix(1), mix(2)]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::NUM_BINS;
+
+    const BIN_HZ: f32 = 48_000.0 / FFT_SIZE as f32;
+
+    /// A 48 kHz frame with the bin nearest `hz` at 0 dB and everything else
+    /// at the floor
+    fn tone(hz: f32) -> AudioPacket {
+        let mut bins = vec![FLOOR_DB; NUM_BINS];
+        bins[(hz / BIN_HZ).round() as usize] = 0.0;
+        AudioPacket::fft(48_000, 0).with_bins(bins.clone(), bins)
+    }
+
+    /// Top pixel row of the band holding `tone(hz)`
+    fn row_of(history: &SpectrogramHistory, hz: f32) -> usize {
+        let bin_hz = (hz / BIN_HZ).round() * BIN_HZ;
+        let band = history.edges.windows(2).position(|e| e[0] <= bin_hz && bin_hz < e[1]).unwrap();
+        (BANDS - 1 - band) * ROW_PIXELS
+    }
+
+    fn decode(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        let decoder = png::Decoder::new(png);
+        let mut reader = decoder.read_info().unwrap();
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).unwrap();
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        buf.truncate(info.buffer_size());
+        (info.width, info.height, buf)
+    }
+
+    fn pixel(rgb: &[u8], x: usize, y: usize) -> [u8; 3] {
+        let i = (y * COLUMNS + x) * 3;
+        [rgb[i], rgb[i + 1], rgb[i + 2]]
+    }
+
+    #[test]
+    fn test_tone_paints_its_row() {
+        let mut history = SpectrogramHistory::new();
+        for _ in 0..50 {
+            history.push(&tone(1000.0));
+        }
+        let (width, height, rgb) = decode(&history.to_png().unwrap());
+        assert_eq!((width, height), (COLUMNS as u32, (BANDS * ROW_PIXELS) as u32));
+
+        let row = row_of(&history, 1000.0);
+        let newest = COLUMNS - 1;
+        assert_eq!(pixel(&rgb, newest, row), color(0.0));
+        assert_eq!(pixel(&rgb, newest, row + 1), color(0.0));
+        assert_eq!(pixel(&rgb, newest, row - 1), color(FLOOR_DB));
+        assert_eq!(pixel(&rgb, newest, row + ROW_PIXELS), color(FLOOR_DB));
+
+        // Only 50 columns so far; the rest of the image is empty
+        assert_eq!(pixel(&rgb, COLUMNS - 50, row), color(0.0));
+        assert_eq!(pixel(&rgb, COLUMNS - 51, row), color(FLOOR_DB));
+    }
+
+    #[test]
+    fn test_history_is_bounded() {
+        let mut history = SpectrogramHistory::new();
+        let bytes = history.heap_bytes();
+        for _ in 0..COLUMNS {
+            history.push(&tone(100.0));
+        }
+        for _ in 0..10 {
+            history.push(&tone(5000.0));
+        }
+        assert_eq!(history.len(), COLUMNS);
+        assert_eq!(history.heap_bytes(), bytes);
+
+        // The ten newest columns are on the right
+        let (_, _, rgb) = decode(&history.to_png().unwrap());
+        let row = row_of(&history, 5000.0);
+        assert_eq!(pixel(&rgb, COLUMNS - 10, row), color(0.0));
+        assert_eq!(pixel(&rgb, COLUMNS - 11, row), color(FLOOR_DB));
+    }
+
+    #[test]
+    fn test_other_packets_are_ignored() {
+        let mut history = SpectrogramHistory::new();
+        history.push(&AudioPacket::new_heartbeat(0, 0));
+        history.push(&AudioPacket::fft(48_000, 0));
+        let mut frozen = AudioPacket::fft(48_000, 0).with_bins(vec![0.0; 2048], vec![0.0; 2048]);
+        frozen.historical = true;
+        history.push(&frozen);
+        assert!(history.is_empty());
+    }
+
+    #[test]
+    fn test_color_map() {
+        assert_eq!(color(FLOOR_DB), [0, 0, 0]);
+        assert_eq!(color(-200.0), [0, 0, 0]);
+        assert_eq!(color(0.0), [255, 255, 210]);
+        assert_eq!(color(-50.0), [180, 20, 90]);
+    }
+
+    #[test]
+    #[cfg(not(debug_assertions))]
+    fn test_render_within_budget() {
+        let mut history = SpectrogramHistory::new();
+        for i in 0..COLUMNS {
+            history.push(&tone(100.0 + i as f32 * 30.0));
+        }
+        let start = std::time::Instant::now();
+        history.to_png().unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_millis(100), "{:?}", start.elapsed());
+    }
+}
@@ -0,0 +1,178 @@
+//! Versions of the state saved with a project
+//!
+//! nih-plug saves every parameter by ID and every `#[persist]` field as a
+//! JSON string of its own. Loading takes the fields it knows and leaves the
+//! rest at their defaults, and the field types ignore keys they don't know,
+//! so adding a field is safe both ways. Changing what a saved value means
+//! isn't, and that is what the version is for: `state_version`, saved with
+//! the other fields, says which layout a project was written in, and
+//! `migrate` brings an older one up to date before the host's state is
+//! applied. A project without it is from before versioning, layout 0.
+//!
+//! To change the layout, bump `STATE_VERSION`, add the step to `MIGRATIONS`
+//! and save a project with the new version as a fixture in
+//! `state_fixtures/`. The tests here fail until all three are done: every
+//! fixture is loaded and checked against the values it expects, and the
+//! fixtures of the current version must hold exactly the fields the
+//! parameters save.
+
+use nih_plug::prelude::PluginState;
+
+/// Layout the plugin writes
+pub const STATE_VERSION: u32 = 2;
+
+/// Key of the version among the persisted fields
+pub const VERSION_KEY: &str = "state_version";
+
+/// `MIGRATIONS[n]` turns a layout `n` state into layout `n + 1`
+const MIGRATIONS: [fn(&mut PluginState); STATE_VERSION as usize] = [from_unversioned, from_v1];
+
+/// Layout of a saved state; 0 if it has no version or one that doesn't parse
+pub fn saved_version(state: &PluginState) -> u32 {
+    state
+        .fields
+        .get(VERSION_KEY)
+        .and_then(|version| serde_json::from_str(version).ok())
+        .unwrap_or(0)
+}
+
+/// Bring `state` up to `STATE_VERSION`, from the host's thread before the
+/// state is applied. Returns the layout it was saved in. A state from a
+/// newer plugin is left alone: what this one knows of it loads, the rest
+/// is ignored.
+pub fn migrate(state: &mut PluginState) -> u32 {
+    let saved = saved_version(state);
+    if saved > STATE_VERSION {
+        return saved;
+    }
+    for step in &MIGRATIONS[saved as usize..] {
+        step(state);
+    }
+    state.fields.insert(VERSION_KEY.to_string(), STATE_VERSION.to_string());
+    saved
+}
+
+/// Layout 0 to 1. Every layout before versioning only added fields: channel
+/// labels, the metrics opt-in, the instance ID, colour and order, the
+/// display label and last the A/B slots. Missing ones load as defaults, and
+/// a project from before slots gets slots that follow its parameters,
+/// which is how it analysed, so nothing needs translating.
+fn from_unversioned(_state: &mut PluginState) {}
+
+/// Layout 1 to 2, which added the extra destinations. A project without
+/// them streams to the Port parameter's Suite alone, as it did.
+fn from_v1(_state: &mut PluginState) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::params::HardwaveAnalyserParams;
+    use nih_plug::prelude::Params;
+    use serde_json::Value;
+    use std::collections::BTreeSet;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    /// A project saved in one released layout, and what it loads as
+    struct Fixture {
+        name: String,
+        state: PluginState,
+        /// Persisted field to its value after loading
+        expect: serde_json::Map<String, Value>,
+    }
+
+    fn fixture_dir() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("state_fixtures")
+    }
+
+    fn fixtures() -> Vec<Fixture> {
+        let mut paths: Vec<PathBuf> = fs::read_dir(fixture_dir())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect();
+        paths.sort();
+        paths
+            .into_iter()
+            .map(|path| {
+                let mut json: Value = serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+                Fixture {
+                    name: path.file_name().unwrap().to_string_lossy().into_owned(),
+                    state: serde_json::from_value(json["state"].take()).unwrap(),
+                    expect: json["expect"].as_object().cloned().unwrap(),
+                }
+            })
+            .collect()
+    }
+
+    /// Migrate and load like the host would; the fields as they save again
+    fn load(mut state: PluginState) -> (u32, serde_json::Map<String, Value>) {
+        let saved = migrate(&mut state);
+        let params = HardwaveAnalyserParams::default();
+        params.deserialize_fields(&state.fields);
+        let fields = params
+            .serialize_fields()
+            .into_iter()
+            .map(|(key, json)| (key, serde_json::from_str(&json).unwrap()))
+            .collect();
+        (saved, fields)
+    }
+
+    fn current_keys() -> BTreeSet<String> {
+        HardwaveAnalyserParams::default().serialize_fields().into_keys().collect()
+    }
+
+    #[test]
+    fn test_every_released_layout_loads() {
+        let fixtures = fixtures();
+        assert!(!fixtures.is_empty());
+        for fixture in fixtures {
+            let (_, fields) = load(fixture.state);
+            assert_eq!(fields.keys().cloned().collect::<BTreeSet<_>>(), current_keys(), "{}", fixture.name);
+            assert_eq!(fields[VERSION_KEY], STATE_VERSION, "{}", fixture.name);
+            for (key, expected) in &fixture.expect {
+                assert_eq!(&fields[key], expected, "{}: {}", fixture.name, key);
+            }
+        }
+    }
+
+    #[test]
+    fn test_every_layout_has_a_fixture_and_a_migration() {
+        assert_eq!(MIGRATIONS.len(), STATE_VERSION as usize);
+        let fixtures = fixtures();
+        for version in 0..=STATE_VERSION {
+            assert!(
+                fixtures.iter().any(|fixture| saved_version(&fixture.state) == version),
+                "no fixture saved in layout {}",
+                version
+            );
+        }
+        // A field added without a new layout shows up here
+        for fixture in fixtures.iter().filter(|fixture| saved_version(&fixture.state) == STATE_VERSION) {
+            let keys: BTreeSet<String> = fixture.state.fields.keys().cloned().collect();
+            assert_eq!(keys, current_keys(), "{} isn't what the plugin saves now; bump STATE_VERSION", fixture.name);
+        }
+    }
+
+    #[test]
+    fn test_state_from_a_newer_plugin_loads_what_it_can() {
+        let mut state = PluginState {
+            version: "9.0.0".to_string(),
+            params: Default::default(),
+            fields: [
+                (VERSION_KEY.to_string(), "7".to_string()),
+                ("display_order".to_string(), "3".to_string()),
+                ("channel_labels".to_string(), r#"{"custom":["Kick","Snare"],"colours":["red","blue"]}"#.to_string()),
+                ("profiles".to_string(), "[]".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+        };
+        assert_eq!(migrate(&mut state), 7);
+        assert_eq!(state.fields[VERSION_KEY], "7", "left as it was");
+
+        let (_, fields) = load(state);
+        assert_eq!(fields["display_order"], 3);
+        assert_eq!(fields["channel_labels"]["custom"], serde_json::json!(["Kick", "Snare"]));
+    }
+}
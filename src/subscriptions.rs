@@ -0,0 +1,261 @@
+//! Which kinds of data the Suite wants, per connection
+//!
+//! Most Suite views only need the spectrum and the levels, so the Suite can
+//! say what it wants with a text message, `{"type":"subscribe","types":
+//! ["waveform"]}` or `{"type":"unsubscribe","types":["fft"]}`. Every
+//! connection starts with everything on, which is what a Suite that never
+//! sends either gets. Heartbeats can't be turned off and unknown types are
+//! ignored, so a newer Suite can ask for data this plugin doesn't have.
+//!
+//! What nobody wants isn't computed at all: the engine skips the FFTs when
+//! neither the Suite nor the plugin window wants the spectrum, and doesn't
+//! copy out the oscilloscope waveform when the Suite doesn't want it. The
+//! window only draws the spectrum and levels, so it counts as a subscriber
+//! to those. Frames made for the window anyway lose what the Suite didn't
+//! ask for on the way out, and frames it wants nothing of aren't sent. Raw
+//! spectrum and reference packets go only to a Suite that wants `fft`,
+//! waveform packets to one that wants `waveform`, and channels packets to
+//! one that wants either `fft` or `levels`, without their bins unless it
+//! wants `fft`.
+
+use serde::Deserialize;
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::protocol::{
+    AudioPacket, PACKET_TYPE_CHANNELS, PACKET_TYPE_FFT, PACKET_TYPE_REFERENCE, PACKET_TYPE_SPECTRUM_RAW,
+    PACKET_TYPE_WAVEFORM,
+};
+
+/// A kind of data the Suite can subscribe to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Category {
+    /// Bins and coarse bands of FFT packets
+    Fft,
+    /// Levels and meters of FFT packets
+    Levels,
+    /// Oscilloscope waveform of FFT packets
+    Waveform,
+    /// Heartbeats; always on
+    Heartbeat,
+}
+
+impl Category {
+    pub const ALL: [Category; 4] = [Category::Fft, Category::Levels, Category::Waveform, Category::Heartbeat];
+
+    /// Name in subscribe messages
+    pub fn name(self) -> &'static str {
+        match self {
+            Category::Fft => "fft",
+            Category::Levels => "levels",
+            Category::Waveform => "waveform",
+            Category::Heartbeat => "heartbeat",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|category| category.name() == name)
+    }
+
+    fn bit(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+/// A set of categories
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CategorySet(u8);
+
+impl CategorySet {
+    #[cfg(test)]
+    pub const NONE: Self = Self(0);
+
+    /// What every connection starts with, and what the plugin did before
+    /// subscriptions
+    pub const ALL: Self = Self(0b1111);
+
+    pub fn contains(self, category: Category) -> bool {
+        self.0 & category.bit() != 0
+    }
+
+    pub fn with(self, category: Category) -> Self {
+        Self(self.0 | category.bit())
+    }
+
+    pub fn without(self, category: Category) -> Self {
+        match category {
+            Category::Heartbeat => self,
+            _ => Self(self.0 & !category.bit()),
+        }
+    }
+}
+
+impl Default for CategorySet {
+    fn default() -> Self {
+        Self::ALL
+    }
+}
+
+/// A subscribe or unsubscribe message from the Suite
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SubscriptionChange {
+    pub subscribe: bool,
+    pub categories: Vec<Category>,
+}
+
+#[derive(Deserialize)]
+struct ChangeMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    types: Vec<String>,
+}
+
+/// The subscription change in a text message from the Suite, if it is one
+pub fn parse_change(text: &str) -> Option<SubscriptionChange> {
+    let message: ChangeMessage = serde_json::from_str(text).ok()?;
+    let subscribe = match message.kind.as_str() {
+        "subscribe" => true,
+        "unsubscribe" => false,
+        _ => return None,
+    };
+    Some(SubscriptionChange {
+        subscribe,
+        categories: message.types.iter().filter_map(|name| Category::from_name(name)).collect(),
+    })
+}
+
+/// The current connection's subscriptions; set by the connection thread,
+/// read by the plugin every block
+#[derive(Debug)]
+pub struct Subscriptions {
+    suite: AtomicU8,
+}
+
+impl Default for Subscriptions {
+    fn default() -> Self {
+        Self { suite: AtomicU8::new(CategorySet::ALL.0) }
+    }
+}
+
+impl Subscriptions {
+    /// A new connection starts with everything on
+    pub fn connected(&self) {
+        self.suite.store(CategorySet::ALL.0, Ordering::Relaxed);
+    }
+
+    pub fn apply(&self, change: &SubscriptionChange) {
+        let mut set = self.suite();
+        for &category in &change.categories {
+            set = if change.subscribe { set.with(category) } else { set.without(category) };
+        }
+        self.suite.store(set.0, Ordering::Relaxed);
+    }
+
+    /// What the Suite subscribed to
+    pub fn suite(&self) -> CategorySet {
+        CategorySet(self.suite.load(Ordering::Relaxed))
+    }
+
+    /// What the engine has to compute: the Suite's subscriptions, plus the
+    /// spectrum and levels while the plugin window is open
+    pub fn wanted(&self, editor_open: bool) -> CategorySet {
+        let suite = self.suite();
+        if editor_open {
+            suite.with(Category::Fft).with(Category::Levels)
+        } else {
+            suite
+        }
+    }
+
+    /// Take out of `packet` what the Suite didn't subscribe to. Returns
+    /// false if nothing it wants is left; raw spectrum and reference packets
+    /// are all spectrum, and waveform packets all waveform.
+    pub fn filter(&self, packet: &mut AudioPacket) -> bool {
+        let suite = self.suite();
+        match packet.packet_type {
+            PACKET_TYPE_FFT | PACKET_TYPE_CHANNELS => {}
+            PACKET_TYPE_SPECTRUM_RAW | PACKET_TYPE_REFERENCE => return suite.contains(Category::Fft),
+            PACKET_TYPE_WAVEFORM => return suite.contains(Category::Waveform),
+            _ => return true,
+        }
+        if !suite.contains(Category::Fft) && !suite.contains(Category::Levels) {
+            return false;
+        }
+        if !suite.contains(Category::Fft) {
+            packet.left_bins = Vec::new();
+            packet.right_bins = Vec::new();
+            packet.left_coarse = Vec::new();
+            packet.right_coarse = Vec::new();
+            packet.coarse_edges_hz = Vec::new();
+            packet.band_activity = Vec::new();
+            packet.channel_bins = Vec::new();
+        }
+        if !suite.contains(Category::Waveform) {
+            packet.left_wave = Vec::new();
+            packet.right_wave = Vec::new();
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{ChannelLevels, NUM_BINS, WAVE_SIZE};
+
+    fn frame() -> AudioPacket {
+        AudioPacket::fft(48000, 0)
+            .with_bins(vec![-60.0; NUM_BINS], vec![-60.0; NUM_BINS])
+            .with_levels(ChannelLevels::new(-6.0, 0.2), ChannelLevels::new(-6.0, 0.2))
+            .with_wave(vec![0.1; WAVE_SIZE], vec![0.1; WAVE_SIZE])
+    }
+
+    #[test]
+    fn test_parse_change() {
+        assert_eq!(
+            parse_change(r#"{"type":"subscribe","types":["waveform","goniometer"]}"#),
+            Some(SubscriptionChange { subscribe: true, categories: vec![Category::Waveform] })
+        );
+        assert_eq!(
+            parse_change(r#"{"type":"unsubscribe","types":["fft","levels"]}"#),
+            Some(SubscriptionChange { subscribe: false, categories: vec![Category::Fft, Category::Levels] })
+        );
+        assert_eq!(parse_change(r#"{"type":"command_ack","seq":3,"ok":true}"#), None);
+        assert_eq!(parse_change("not json"), None);
+    }
+
+    #[test]
+    fn test_heartbeats_stay_on() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.apply(&SubscriptionChange { subscribe: false, categories: Category::ALL.to_vec() });
+        assert_eq!(subscriptions.suite(), CategorySet::NONE.with(Category::Heartbeat));
+        subscriptions.connected();
+        assert_eq!(subscriptions.suite(), CategorySet::ALL);
+    }
+
+    #[test]
+    fn test_the_window_keeps_the_spectrum_but_the_suite_doesnt_get_it() {
+        let subscriptions = Subscriptions::default();
+        subscriptions.apply(&SubscriptionChange { subscribe: false, categories: vec![Category::Fft, Category::Waveform] });
+        assert!(!subscriptions.wanted(false).contains(Category::Fft));
+        assert!(subscriptions.wanted(true).contains(Category::Fft));
+        assert!(!subscriptions.wanted(true).contains(Category::Waveform));
+
+        let mut packet = frame();
+        assert!(subscriptions.filter(&mut packet));
+        assert!(packet.left_bins.is_empty() && packet.left_wave.is_empty());
+        assert_eq!(packet.left_peak, frame().left_peak, "levels are still wanted");
+        assert!(!subscriptions.filter(&mut AudioPacket::new_spectrum_raw(48000, 0)));
+        assert!(!subscriptions.filter(&mut AudioPacket::new_waveform(48000, 0)));
+        assert!(!subscriptions.filter(&mut AudioPacket::new_reference(48000, 0)));
+        let mut channels = AudioPacket::new_channels(48000, 0);
+        channels.channel_bins = vec![-20.0; 6 * NUM_BINS];
+        channels.channel_peaks = vec![-6.0; 6];
+        assert!(subscriptions.filter(&mut channels));
+        assert!(channels.channel_bins.is_empty() && channels.channel_peaks.len() == 6);
+
+        subscriptions.apply(&SubscriptionChange { subscribe: false, categories: vec![Category::Levels] });
+        assert!(!subscriptions.filter(&mut frame()), "nothing the Suite wants");
+        assert!(subscriptions.filter(&mut AudioPacket::new_heartbeat(0, 0)));
+    }
+}
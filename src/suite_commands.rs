@@ -0,0 +1,250 @@
+//! Commands the plugin window relays to the Suite
+//!
+//! The page posts `suiteCommand:{"command":"startCapture","args":{...}}`. Only
+//! the commands in `ALLOWED`, and none over `MAX_COMMAND_BYTES`, go any
+//! further; the rest are turned down here and never reach the Suite. An
+//! accepted command waits in `SuiteCommands` until the connection thread has
+//! sent it as a `PACKET_TYPE_USER_COMMAND` packet carrying the instance ID,
+//! so one submitted while disconnected goes out on the next connection.
+//!
+//! The Suite may answer with a text message, `{"type":"command_ack",
+//! "seq":1,"ok":true}` or `"ok":false,"error":"..."`, which the editor
+//! passes on to the page.
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+#[cfg(any(feature = "gui", test))]
+use std::fmt;
+
+/// Commands the Suite accepts from a plugin window
+#[cfg(any(feature = "gui", test))]
+pub const ALLOWED: &[&str] = &["startCapture", "stopCapture", "markCapture"];
+
+/// Longest command, arguments included, as posted by the page
+pub const MAX_COMMAND_BYTES: usize = 1024;
+
+/// Commands waiting for a connection; more are turned down
+#[cfg(any(feature = "gui", test))]
+pub const MAX_PENDING: usize = 16;
+
+/// Acknowledgements waiting for the editor; the oldest go first
+pub const MAX_ACKS: usize = 16;
+
+/// Why a command was turned down before reaching the Suite
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// Longer than `MAX_COMMAND_BYTES`
+    TooLarge(usize),
+    /// Not a `{"command": ..., "args": ...}` object
+    Malformed(String),
+    /// Not in `ALLOWED`
+    NotAllowed(String),
+    /// `MAX_PENDING` commands are already waiting to be sent
+    QueueFull,
+}
+
+#[cfg(any(feature = "gui", test))]
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::TooLarge(len) => write!(f, "command is {} bytes, over {}", len, MAX_COMMAND_BYTES),
+            CommandError::Malformed(why) => write!(f, "malformed command: {}", why),
+            CommandError::NotAllowed(name) => write!(f, "unknown command {:?}", name),
+            CommandError::QueueFull => write!(f, "too many commands waiting for the Suite"),
+        }
+    }
+}
+
+#[cfg(any(feature = "gui", test))]
+impl std::error::Error for CommandError {}
+
+#[cfg(any(feature = "gui", test))]
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Command {
+    command: String,
+    #[serde(default)]
+    args: serde_json::Value,
+}
+
+/// Check a command from the page, returning it as sent to the Suite
+#[cfg(any(feature = "gui", test))]
+pub fn validate(json: &str) -> Result<String, CommandError> {
+    if json.len() > MAX_COMMAND_BYTES {
+        return Err(CommandError::TooLarge(json.len()));
+    }
+    let command: Command = serde_json::from_str(json).map_err(|e| CommandError::Malformed(e.to_string()))?;
+    if !ALLOWED.contains(&command.command.as_str()) {
+        return Err(CommandError::NotAllowed(command.command));
+    }
+    serde_json::to_string(&command).map_err(|e| CommandError::Malformed(e.to_string()))
+}
+
+/// The Suite's answer to the command sent as `seq`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandAck {
+    pub seq: u32,
+    pub ok: bool,
+    pub error: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct AckMessage {
+    #[serde(rename = "type")]
+    kind: String,
+    seq: u32,
+    ok: bool,
+    #[serde(default)]
+    error: Option<String>,
+}
+
+/// The acknowledgement in a text message from the Suite, if it is one
+pub fn parse_ack(text: &str) -> Option<CommandAck> {
+    let message: AckMessage = serde_json::from_str(text).ok()?;
+    (message.kind == "command_ack").then_some(CommandAck {
+        seq: message.seq,
+        ok: message.ok,
+        error: message.error,
+    })
+}
+
+#[derive(Debug, Default)]
+struct Queues {
+    #[cfg(any(feature = "gui", test))]
+    last_seq: u32,
+    pending: VecDeque<(u32, String)>,
+    acks: VecDeque<CommandAck>,
+}
+
+/// Commands on their way to the Suite and answers on their way back; shared
+/// by the editor and the connection thread
+#[derive(Debug, Default)]
+pub struct SuiteCommands {
+    queues: Mutex<Queues>,
+}
+
+impl SuiteCommands {
+    /// Editor: check and queue a command from the page. Returns the sequence
+    /// number its acknowledgement will carry.
+    #[cfg(any(feature = "gui", test))]
+    pub fn submit(&self, json: &str) -> Result<u32, CommandError> {
+        let command = validate(json)?;
+        let mut queues = self.queues.lock();
+        if queues.pending.len() >= MAX_PENDING {
+            return Err(CommandError::QueueFull);
+        }
+        queues.last_seq = queues.last_seq.wrapping_add(1).max(1);
+        let seq = queues.last_seq;
+        queues.pending.push_back((seq, command));
+        Ok(seq)
+    }
+
+    /// Connection thread: the oldest command not sent yet. It stays queued
+    /// until `sent`.
+    pub fn next(&self) -> Option<(u32, String)> {
+        self.queues.lock().pending.front().cloned()
+    }
+
+    /// Connection thread: the command `seq` is out
+    pub fn sent(&self, seq: u32) {
+        let mut queues = self.queues.lock();
+        if queues.pending.front().is_some_and(|(front, _)| *front == seq) {
+            queues.pending.pop_front();
+        }
+    }
+
+    /// Connection thread: keep an answer from the Suite for the editor
+    pub fn acknowledged(&self, ack: CommandAck) {
+        let mut queues = self.queues.lock();
+        if queues.acks.len() >= MAX_ACKS {
+            queues.acks.pop_front();
+        }
+        queues.acks.push_back(ack);
+    }
+
+    /// Editor: the answers since the last call
+    #[cfg(any(feature = "gui", test))]
+    pub fn take_acks(&self) -> Vec<CommandAck> {
+        self.queues.lock().acks.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allow_list() {
+        assert_eq!(
+            validate(r#"{"command":"startCapture","args":{"seconds":30}}"#).unwrap(),
+            r#"{"command":"startCapture","args":{"seconds":30}}"#
+        );
+        assert_eq!(validate(r#"{"command":"stopCapture"}"#).unwrap(), r#"{"command":"stopCapture","args":null}"#);
+
+        assert_eq!(
+            validate(r#"{"command":"deleteProject"}"#),
+            Err(CommandError::NotAllowed("deleteProject".to_string()))
+        );
+        assert!(matches!(validate("startCapture"), Err(CommandError::Malformed(_))));
+        assert!(matches!(validate(r#"{"command":"startCapture","token":"x"}"#), Err(CommandError::Malformed(_))));
+    }
+
+    #[test]
+    fn test_size_caps() {
+        let args = "x".repeat(MAX_COMMAND_BYTES);
+        let json = format!(r#"{{"command":"markCapture","args":"{}"}}"#, args);
+        assert_eq!(validate(&json), Err(CommandError::TooLarge(json.len())));
+
+        let fits = format!(r#"{{"command":"markCapture","args":"{}"}}"#, &args[..MAX_COMMAND_BYTES - 40]);
+        assert!(validate(&fits).is_ok());
+
+        let commands = SuiteCommands::default();
+        for _ in 0..MAX_PENDING {
+            commands.submit(r#"{"command":"markCapture"}"#).unwrap();
+        }
+        assert_eq!(commands.submit(r#"{"command":"markCapture"}"#), Err(CommandError::QueueFull));
+    }
+
+    #[test]
+    fn test_commands_stay_queued_until_sent() {
+        let commands = SuiteCommands::default();
+        assert_eq!(commands.submit(r#"{"command":"startCapture"}"#), Ok(1));
+        assert_eq!(commands.submit(r#"{"command":"stopCapture"}"#), Ok(2));
+        assert!(commands.submit(r#"{"command":"format"}"#).is_err());
+
+        // A send that failed leaves the command for the next connection
+        let (seq, _) = commands.next().unwrap();
+        assert_eq!(commands.next().unwrap().0, seq);
+        commands.sent(seq);
+        assert_eq!(commands.next().unwrap().0, 2);
+        commands.sent(1);
+        assert_eq!(commands.next().unwrap().0, 2);
+        commands.sent(2);
+        assert_eq!(commands.next(), None);
+    }
+
+    #[test]
+    fn test_acks() {
+        assert_eq!(
+            parse_ack(r#"{"type":"command_ack","seq":3,"ok":true}"#),
+            Some(CommandAck { seq: 3, ok: true, error: None })
+        );
+        assert_eq!(
+            parse_ack(r#"{"type":"command_ack","seq":4,"ok":false,"error":"no capture running"}"#),
+            Some(CommandAck { seq: 4, ok: false, error: Some("no capture running".to_string()) })
+        );
+        assert_eq!(parse_ack(r#"{"type":"stream_token","token":"t","expires_in":60}"#), None);
+        assert_eq!(parse_ack("ok"), None);
+
+        let commands = SuiteCommands::default();
+        for seq in 0..MAX_ACKS as u32 + 2 {
+            commands.acknowledged(CommandAck { seq, ok: true, error: None });
+        }
+        let acks = commands.take_acks();
+        assert_eq!(acks.len(), MAX_ACKS);
+        assert_eq!(acks[0].seq, 2, "oldest dropped first");
+        assert!(commands.take_acks().is_empty());
+    }
+}
@@ -0,0 +1,207 @@
+//! Surround layouts: a stereo downmix for the FFT packet and every channel
+//! on its own
+//!
+//! Besides mono and stereo the plugin takes 5.1 and 7.1, for film and game
+//! stems. The channels come in the order the plugin APIs use, `L R C LFE Ls
+//! Rs` and `L R C LFE Ls Rs Lrs Rrs`. Everything a stereo input gets, the
+//! FFT packet, waveforms, loudness, clicks and the stereo warnings, comes
+//! from a downmix as ITU-R BS.775 has it: each side plus the centre and
+//! that side's surrounds at -3 dB, without the LFE. A Suite that knows
+//! nothing of surround draws the downmix as it draws a stereo input.
+//!
+//! Every frame from a surround input is also followed by a
+//! `PACKET_TYPE_CHANNELS` packet with the window of each channel on its
+//! own, in the same order: the bins of every channel one after the other in
+//! `channel_bins`, `fft_size / 2` each, and the window's peak in dB in
+//! `channel_peaks` and its RMS, linear, in `channel_rms`. The number of
+//! channels is the length of `channel_peaks`. The channels go through
+//! their own decimators, resamplers, weighting and ring buffers, built like
+//! the main input's but never zero-padded, so a 7.1 packet holds no more
+//! bins than a padded FFT packet; the channel mode doesn't apply to them.
+//! Their levels are always floats, whatever Compact Levels says.
+//!
+//! Channels packets take the frame's timestamp and slot, coalesce like FFT
+//! packets and go to a Suite subscribed to `fft` or `levels`, the bins only
+//! with `fft`. None go out while the history is frozen, and the sidechain
+//! is only offered with mono and stereo.
+
+use crate::decimator::Decimator;
+use crate::fft::{FftProcessor, RingBuffer};
+use crate::protocol::{AudioPacket, ChannelLevels};
+use crate::resampler::Resampler;
+use crate::units::FLOOR_DB;
+
+/// Channels of the largest layout, 7.1
+pub const MAX_CHANNELS: usize = 8;
+
+/// BS.775's gain for the centre and surrounds in the downmix, -3 dB
+const DOWNMIX_GAIN: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// The channel after L and R that is the LFE, left out of the downmix
+const LFE: usize = 3;
+
+/// Sum the channels of a surround block into `left` and `right`, as long as
+/// the shorter of the two and the block. Left channels are the even ones
+/// after the LFE, right ones the odd ones; the centre goes to both.
+pub fn downmix<C: AsRef<[f32]>>(channels: &[C], left: &mut [f32], right: &mut [f32]) {
+    left.fill(0.0);
+    right.fill(0.0);
+    for (index, channel) in channels.iter().enumerate() {
+        let (to_left, to_right) = match index {
+            0 => (1.0, 0.0),
+            1 => (0.0, 1.0),
+            2 => (DOWNMIX_GAIN, DOWNMIX_GAIN),
+            LFE => continue,
+            _ if index % 2 == 0 => (DOWNMIX_GAIN, 0.0),
+            _ => (0.0, DOWNMIX_GAIN),
+        };
+        for ((left, right), &sample) in left.iter_mut().zip(right.iter_mut()).zip(channel.as_ref()) {
+            *left += to_left * sample;
+            *right += to_right * sample;
+        }
+    }
+}
+
+/// Everything the channels of a surround input are analysed with, built by
+/// `AnalysisState::build` for a config of more than two channels
+pub struct SurroundState {
+    pub ffts: Vec<FftProcessor>,
+    pub decimators: Vec<Decimator>,
+    /// One per pair of channels, in order
+    pub resamplers: Vec<Resampler>,
+    /// The last `history_len` analysis-rate samples of each channel
+    pub buffers: Vec<RingBuffer>,
+}
+
+impl SurroundState {
+    /// Channels analysed
+    pub fn channels(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Take one host-rate sample of every channel, already weighted
+    pub fn push(&mut self, samples: &[f32]) {
+        for (pair, resampler) in self.resamplers.iter_mut().enumerate() {
+            let (left, right) = (2 * pair, 2 * pair + 1);
+            let (Some(left_sample), Some(right_sample)) = (
+                self.decimators[left].process(samples[left]),
+                self.decimators[right].process(samples[right]),
+            ) else {
+                continue;
+            };
+            for (left_sample, right_sample) in resampler.process(left_sample, right_sample) {
+                self.buffers[left].push(left_sample);
+                self.buffers[right].push(right_sample);
+            }
+        }
+    }
+
+    /// Drop buffered audio
+    pub fn clear(&mut self) {
+        self.buffers.iter_mut().for_each(RingBuffer::clear);
+    }
+
+    /// Drop buffered audio and filter histories
+    pub fn reset(&mut self) {
+        self.clear();
+        self.decimators.iter_mut().for_each(Decimator::reset);
+        self.resamplers.iter_mut().for_each(Resampler::reset);
+    }
+
+    /// Every channel's spectrum and levels over the window `start..end` of
+    /// the history; the floor if it doesn't reach back that far
+    pub fn frame(&mut self, start: usize, end: usize) -> SurroundFrame {
+        let bins_per_channel = self.ffts.first().map_or(0, FftProcessor::num_bins);
+        let mut bins = vec![FLOOR_DB; self.channels() * bins_per_channel];
+        let mut levels = vec![ChannelLevels::SILENCE; self.channels()];
+        for (((fft, buffer), bins), levels) in
+            self.ffts.iter_mut().zip(&self.buffers).zip(bins.chunks_mut(bins_per_channel)).zip(&mut levels)
+        {
+            if start >= end || buffer.len() < end {
+                continue;
+            }
+            let window = &buffer.as_slice()[start..end];
+            fft.process_into(window, bins);
+            let (peak, rms) = FftProcessor::calculate_levels(window);
+            *levels = ChannelLevels::new(peak, rms);
+        }
+        SurroundFrame { bins, levels }
+    }
+
+    pub fn heap_bytes(&self) -> usize {
+        self.ffts.iter().map(FftProcessor::heap_bytes).sum::<usize>()
+            + self.decimators.iter().map(Decimator::heap_bytes).sum::<usize>()
+            + self.resamplers.iter().map(Resampler::heap_bytes).sum::<usize>()
+            + self.buffers.iter().map(RingBuffer::heap_bytes).sum::<usize>()
+    }
+}
+
+/// The channels' part of a frame
+#[derive(Debug, Clone, PartialEq)]
+pub struct SurroundFrame {
+    /// Every channel's bins, one after the other
+    pub bins: Vec<f32>,
+    pub levels: Vec<ChannelLevels>,
+}
+
+impl SurroundFrame {
+    /// The channels packet following the FFT packet `frame`, with its
+    /// timestamps, rate, slot and eco mode
+    pub fn into_packet(self, frame: &AudioPacket) -> AudioPacket {
+        let mut packet = AudioPacket::new_channels(frame.sample_rate, frame.timestamp_ms);
+        packet.channel_bins = self.bins;
+        packet.channel_peaks = self.levels.iter().map(|levels| levels.peak.get()).collect();
+        packet.channel_rms = self.levels.iter().map(|levels| levels.rms.get()).collect();
+        packet.eco_mode = frame.eco_mode;
+        packet.captured_us = frame.captured_us;
+        packet.analysis_slot = frame.analysis_slot;
+        packet
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::PACKET_TYPE_CHANNELS;
+
+    #[test]
+    fn test_downmix() {
+        // L R C LFE Ls Rs, each a different constant
+        let channels: Vec<Vec<f32>> = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6].iter().map(|&x| vec![x; 4]).collect();
+        let (mut left, mut right) = (vec![1.0; 4], vec![1.0; 4]);
+        downmix(&channels, &mut left, &mut right);
+        let gain = DOWNMIX_GAIN;
+        assert!(left.iter().all(|&x| (x - (0.1 + gain * 0.3 + gain * 0.5)).abs() < 1e-6), "{:?}", left);
+        assert!(right.iter().all(|&x| (x - (0.2 + gain * 0.3 + gain * 0.6)).abs() < 1e-6), "{:?}", right);
+
+        // 7.1: the rear pair joins the sides
+        let mut channels = channels;
+        channels.extend([vec![1.0; 4], vec![-1.0; 4]]);
+        downmix(&channels, &mut left, &mut right);
+        assert!((left[0] - (0.1 + gain * 1.8)).abs() < 1e-6);
+        assert!((right[0] - (0.2 + gain * -0.1)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_packet_round_trip() {
+        let mut frame = AudioPacket::fft(48_000, 1234);
+        frame.captured_us = 99;
+        frame.analysis_slot = 1;
+        let surround = SurroundFrame {
+            bins: (0..6 * 16).map(|i| -(i as f32)).collect(),
+            levels: (0..6).map(|i| ChannelLevels::new(-3.0 * i as f32, 0.1 * i as f32)).collect(),
+        };
+        let packet = surround.clone().into_packet(&frame);
+        assert_eq!(packet.packet_type, PACKET_TYPE_CHANNELS);
+        assert_eq!(packet.coalesce_key(), Some(PACKET_TYPE_CHANNELS), "only the newest matters");
+        assert!(packet.left_bins.is_empty(), "no stereo bins");
+
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.timestamp_ms, decoded.captured_us), (48_000, 1234, 99));
+        assert_eq!(decoded.analysis_slot, 1);
+        assert_eq!(decoded.channel_bins, surround.bins);
+        assert_eq!(decoded.channel_peaks, [0.0, -3.0, -6.0, -9.0, -12.0, -15.0]);
+        assert_eq!(decoded.channel_rms.len(), 6);
+        assert!((decoded.channel_rms[5] - 0.5).abs() < 1e-6);
+    }
+}
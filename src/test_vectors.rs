@@ -0,0 +1,320 @@
+//! Protocol conformance test vectors
+//!
+//! The golden files in `test_vectors/` at the crate root say: given this
+//! input audio, the analysis produces these bins and levels, and a packet
+//! carrying them encodes to exactly these bytes. They are for anyone
+//! implementing the protocol or the analysis outside this crate, and they
+//! pin the plugin's own calibration and packet layout: `cargo test` fails if
+//! either drifts. A deliberate change regenerates them with
+//! `HARDWAVE_REGENERATE_VECTORS=1 cargo test test_vectors` and commits the
+//! result.
+//!
+//! # Fixture format
+//!
+//! One JSON file per case:
+//!
+//! - `name`, `description`
+//! - `sample_rate`: Hz; only affects the sine input
+//! - `input`: how to synthesise the `FFT_SIZE` (4096) input samples, see
+//!   `Input`. The left channel is the input, the right channel the input
+//!   times 0.5 (-6.02 dB).
+//! - `left_bins`, `right_bins`: `NUM_BINS` (2048) magnitudes in dB from one
+//!   Hann-windowed FFT of the whole input, scaled so a full-scale sine on a
+//!   bin reads 0 dB, clamped to -100..0
+//! - `left_peak`, `right_peak`: sample peak in dB; `left_rms`, `right_rms`:
+//!   linear RMS of the input
+//! - `packet_len`, `packet_fnv1a64`: length and FNV-1a 64 hash (lower-case
+//!   hex) of the FFT packet's bytes, checksum included, built from the
+//!   fixture's own stored values with `sample_rate`, `timestamp_ms` 0,
+//!   zeroed waveforms, meters equal to the levels and every other field at
+//!   its default
+//!
+//! # Tolerances
+//!
+//! Stored values are rounded; implementations match when every bin is within
+//! `BIN_TOLERANCE_DB` or, near the floor where float noise dominates, within
+//! `BIN_TOLERANCE_AMPLITUDE` linear; levels within `LEVEL_TOLERANCE`. The
+//! packet hash is exact.
+
+use serde::{Deserialize, Serialize};
+use std::f64::consts::PI;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::fft::{FftProcessor, FFT_SIZE};
+use crate::protocol::{AudioPacket, ChannelLevels};
+use crate::units::db_to_linear;
+
+pub const BIN_TOLERANCE_DB: f32 = 0.01;
+pub const BIN_TOLERANCE_AMPLITUDE: f32 = 1e-5;
+pub const LEVEL_TOLERANCE: f32 = 1e-3;
+
+/// How the input samples of a fixture are made
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Input {
+    /// `amplitude * sin(2π * frequency_hz * i / sample_rate)`, computed in
+    /// f64 and rounded to f32
+    Sine { frequency_hz: f64, amplitude: f64 },
+
+    /// xorshift32 (`x ^= x << 13; x ^= x >> 17; x ^= x << 5`) from `seed`;
+    /// each sample is `((x >> 8) / 2^24 * 2 - 1) * amplitude`
+    Noise { seed: u32, amplitude: f32 },
+
+    /// `amplitude` at `offset`, `offset + interval`, ..., 0 elsewhere
+    Clicks { interval: usize, offset: usize, amplitude: f32 },
+}
+
+impl Input {
+    pub fn samples(&self, sample_rate: u32) -> Vec<f32> {
+        match *self {
+            Input::Sine { frequency_hz, amplitude } => (0..FFT_SIZE)
+                .map(|i| (amplitude * (2.0 * PI * frequency_hz * i as f64 / sample_rate as f64).sin()) as f32)
+                .collect(),
+            Input::Noise { seed, amplitude } => {
+                let mut x = seed;
+                (0..FFT_SIZE)
+                    .map(|_| {
+                        x ^= x << 13;
+                        x ^= x >> 17;
+                        x ^= x << 5;
+                        ((x >> 8) as f32 / (1u32 << 24) as f32 * 2.0 - 1.0) * amplitude
+                    })
+                    .collect()
+            }
+            Input::Clicks { interval, offset, amplitude } => (0..FFT_SIZE)
+                .map(|i| {
+                    if i >= offset && (i - offset) % interval == 0 {
+                        amplitude
+                    } else {
+                        0.0
+                    }
+                })
+                .collect(),
+        }
+    }
+}
+
+/// One golden file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Fixture {
+    pub name: String,
+    pub description: String,
+    pub sample_rate: u32,
+    pub input: Input,
+    pub left_bins: Vec<f32>,
+    pub right_bins: Vec<f32>,
+    pub left_peak: f32,
+    pub left_rms: f32,
+    pub right_peak: f32,
+    pub right_rms: f32,
+    pub packet_len: usize,
+    pub packet_fnv1a64: String,
+}
+
+impl Fixture {
+    /// The packet described in the module docs
+    pub fn packet(&self) -> AudioPacket {
+        AudioPacket::fft(self.sample_rate, 0)
+            .with_bins(self.left_bins.clone(), self.right_bins.clone())
+            .with_levels(
+                ChannelLevels::new(self.left_peak, self.left_rms),
+                ChannelLevels::new(self.right_peak, self.right_rms),
+            )
+    }
+}
+
+/// The cases shipped in `test_vectors/`
+pub fn cases() -> Vec<(&'static str, &'static str, u32, Input)> {
+    vec![
+        (
+            "sine_1k",
+            "1 kHz sine at -6 dBFS (amplitude 0.5), phase 0",
+            48000,
+            Input::Sine { frequency_hz: 1000.0, amplitude: 0.5 },
+        ),
+        (
+            "sine_off_bin",
+            "3141.5 Hz sine at -12 dBFS, between bins",
+            44100,
+            Input::Sine { frequency_hz: 3141.5, amplitude: 0.25 },
+        ),
+        (
+            "noise",
+            "Uniform white noise from xorshift32, seed 0x12345678, amplitude 0.5",
+            48000,
+            Input::Noise { seed: 0x1234_5678, amplitude: 0.5 },
+        ),
+        (
+            "clicks",
+            "Unit impulses every 1000 samples starting at sample 500",
+            48000,
+            Input::Clicks { interval: 1000, offset: 500, amplitude: 1.0 },
+        ),
+    ]
+}
+
+/// Directory holding the golden files
+pub fn fixture_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("test_vectors")
+}
+
+/// FNV-1a, 64 bit
+pub fn fnv1a64(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+fn round_to(value: f32, places: i32) -> f32 {
+    let scale = 10f64.powi(places);
+    ((value as f64 * scale).round() / scale) as f32
+}
+
+/// Run every case through this crate's analysis and encoder.
+pub fn generate() -> Vec<Fixture> {
+    let mut fft = FftProcessor::new();
+    cases()
+        .into_iter()
+        .map(|(name, description, sample_rate, input)| {
+            let left = input.samples(sample_rate);
+            let right: Vec<f32> = left.iter().map(|s| s * 0.5).collect();
+            let bins = |fft: &mut FftProcessor, samples: &[f32]| -> Vec<f32> {
+                fft.process(samples, sample_rate as f32)
+                    .into_iter()
+                    .map(|db| round_to(db, 4))
+                    .collect()
+            };
+            let (left_peak, left_rms) = FftProcessor::calculate_levels(&left);
+            let (right_peak, right_rms) = FftProcessor::calculate_levels(&right);
+
+            let mut fixture = Fixture {
+                name: name.to_string(),
+                description: description.to_string(),
+                sample_rate,
+                input,
+                left_bins: bins(&mut fft, &left),
+                right_bins: bins(&mut fft, &right),
+                left_peak: round_to(left_peak, 4),
+                left_rms: round_to(left_rms, 6),
+                right_peak: round_to(right_peak, 4),
+                right_rms: round_to(right_rms, 6),
+                packet_len: 0,
+                packet_fnv1a64: String::new(),
+            };
+            let bytes = fixture.packet().to_bytes();
+            fixture.packet_len = bytes.len();
+            fixture.packet_fnv1a64 = format!("{:016x}", fnv1a64(&bytes));
+            fixture
+        })
+        .collect()
+}
+
+/// Write freshly generated fixtures to `dir`, replacing the old ones.
+pub fn write(dir: &Path) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    for fixture in generate() {
+        let json = serde_json::to_string_pretty(&fixture).map_err(io::Error::other)?;
+        fs::write(dir.join(format!("{}.json", fixture.name)), json + "\n")?;
+    }
+    Ok(())
+}
+
+fn bin_matches(actual: f32, expected: f32) -> bool {
+    (actual - expected).abs() <= BIN_TOLERANCE_DB
+        || (db_to_linear(actual) - db_to_linear(expected)).abs() <= BIN_TOLERANCE_AMPLITUDE
+}
+
+/// Compare this crate's output with the fixtures in `dir`. Returns every
+/// mismatch found.
+pub fn compare(dir: &Path) -> Result<(), Vec<String>> {
+    let mut problems = Vec::new();
+    for actual in generate() {
+        let path = dir.join(format!("{}.json", actual.name));
+        let expected: Fixture = match fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|json| serde_json::from_str(&json).map_err(|e| e.to_string()))
+        {
+            Ok(fixture) => fixture,
+            Err(e) => {
+                problems.push(format!("{}: {}", path.display(), e));
+                continue;
+            }
+        };
+        let name = &actual.name;
+
+        if expected.input != actual.input || expected.sample_rate != actual.sample_rate {
+            problems.push(format!("{}: input differs from cases()", name));
+        }
+        for (channel, a, e) in [
+            ("left", &actual.left_bins, &expected.left_bins),
+            ("right", &actual.right_bins, &expected.right_bins),
+        ] {
+            if a.len() != e.len() {
+                problems.push(format!("{}: {} bins: {} vs {}", name, channel, a.len(), e.len()));
+                continue;
+            }
+            if let Some((bin, (a, e))) = a.iter().zip(e).enumerate().find(|(_, (a, e))| !bin_matches(**a, **e)) {
+                problems.push(format!("{}: {} bin {}: {} dB, expected {} dB", name, channel, bin, a, e));
+            }
+        }
+        for (level, a, e) in [
+            ("left_peak", actual.left_peak, expected.left_peak),
+            ("left_rms", actual.left_rms, expected.left_rms),
+            ("right_peak", actual.right_peak, expected.right_peak),
+            ("right_rms", actual.right_rms, expected.right_rms),
+        ] {
+            if (a - e).abs() > LEVEL_TOLERANCE {
+                problems.push(format!("{}: {}: {}, expected {}", name, level, a, e));
+            }
+        }
+
+        // Layout: encode the expected values and compare bytes
+        let bytes = expected.packet().to_bytes();
+        let hash = format!("{:016x}", fnv1a64(&bytes));
+        if bytes.len() != expected.packet_len || hash != expected.packet_fnv1a64 {
+            problems.push(format!(
+                "{}: packet is {} bytes / {}, expected {} bytes / {}",
+                name, bytes.len(), hash, expected.packet_len, expected.packet_fnv1a64
+            ));
+        }
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixtures_match() {
+        let dir = fixture_dir();
+        if std::env::var_os("HARDWAVE_REGENERATE_VECTORS").is_some() {
+            write(&dir).unwrap();
+        }
+        if let Err(problems) = compare(&dir) {
+            panic!("test vectors drifted:\n{}", problems.join("\n"));
+        }
+    }
+
+    #[test]
+    fn test_fnv1a64() {
+        assert_eq!(fnv1a64(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a64(b"a"), 0xaf63_dc4c_8601_ec8c);
+    }
+
+    #[test]
+    fn test_inputs_are_deterministic() {
+        for (_, _, sample_rate, input) in cases() {
+            let samples = input.samples(sample_rate);
+            assert_eq!(samples.len(), FFT_SIZE);
+            assert_eq!(samples, input.samples(sample_rate));
+            assert!(samples.iter().all(|s| s.is_finite() && s.abs() <= 1.0));
+        }
+    }
+}
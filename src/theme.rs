@@ -0,0 +1,205 @@
+//! OS theme detection and the environment hints passed to the analyser page
+//!
+//! The page gets the theme and the host's UI scale both as query parameters
+//! on the first load and as `window.__hardwaveEnv`, which the editor updates
+//! whenever either changes. When the theme can't be determined it reads as
+//! dark, the page's long-standing look.
+
+use serde::Serialize;
+
+/// OS colour scheme
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Theme {
+    Dark,
+    Light,
+}
+
+impl Theme {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Theme::Dark => "dark",
+            Theme::Light => "light",
+        }
+    }
+}
+
+/// What the page is told about its surroundings
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct HardwaveEnv {
+    pub theme: Theme,
+    /// Host UI scale factor (1.0 = 96 DPI)
+    pub scale: f32,
+}
+
+impl HardwaveEnv {
+    /// Query string form, without the leading `?`/`&`
+    pub fn query(&self) -> String {
+        format!("theme={}&scale={}", self.theme.as_str(), self.scale)
+    }
+}
+
+/// Append the env query parameters to `url`.
+pub fn with_env(url: &str, env: &HardwaveEnv) -> String {
+    let separator = if url.contains('?') { '&' } else { '?' };
+    format!("{}{}{}", url, separator, env.query())
+}
+
+/// Current OS theme. May run a short-lived helper process on Linux and
+/// macOS, so call it at most every few seconds.
+#[cfg(target_os = "windows")]
+pub fn detect() -> Theme {
+    use std::ffi::c_void;
+
+    #[link(name = "advapi32")]
+    extern "system" {
+        fn RegGetValueW(
+            hkey: isize,
+            subkey: *const u16,
+            value: *const u16,
+            flags: u32,
+            value_type: *mut u32,
+            data: *mut c_void,
+            data_len: *mut u32,
+        ) -> i32;
+    }
+    const HKEY_CURRENT_USER: isize = 0x8000_0001_u32 as i32 as isize;
+    const RRF_RT_REG_DWORD: u32 = 0x10;
+
+    let wide = |s: &str| s.encode_utf16().chain(Some(0)).collect::<Vec<u16>>();
+    let subkey = wide(r"Software\Microsoft\Windows\CurrentVersion\Themes\Personalize");
+    let value = wide("AppsUseLightTheme");
+    let mut data: u32 = 0;
+    let mut len = std::mem::size_of::<u32>() as u32;
+    let status = unsafe {
+        RegGetValueW(
+            HKEY_CURRENT_USER,
+            subkey.as_ptr(),
+            value.as_ptr(),
+            RRF_RT_REG_DWORD,
+            std::ptr::null_mut(),
+            &mut data as *mut u32 as *mut c_void,
+            &mut len,
+        )
+    };
+    parse_apps_use_light_theme((status == 0).then_some(data))
+}
+
+#[cfg(target_os = "macos")]
+pub fn detect() -> Theme {
+    // Only set in dark mode; the command fails in light mode
+    let output = std::process::Command::new("defaults")
+        .args(["read", "-g", "AppleInterfaceStyle"])
+        .output();
+    match output {
+        Ok(out) => {
+            let style = out
+                .status
+                .success()
+                .then(|| String::from_utf8_lossy(&out.stdout).into_owned());
+            parse_apple_interface_style(style.as_deref())
+        }
+        Err(_) => Theme::Dark,
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub fn detect() -> Theme {
+    let gsettings = |key: &str| {
+        std::process::Command::new("gsettings")
+            .args(["get", "org.gnome.desktop.interface", key])
+            .output()
+            .ok()
+            .filter(|out| out.status.success())
+            .map(|out| String::from_utf8_lossy(&out.stdout).into_owned())
+    };
+    match gsettings("color-scheme") {
+        Some(scheme) => parse_color_scheme(&scheme, gsettings("gtk-theme").as_deref()),
+        None => Theme::Dark,
+    }
+}
+
+#[cfg(not(any(target_os = "windows", target_os = "macos", target_os = "linux")))]
+pub fn detect() -> Theme {
+    Theme::Dark
+}
+
+/// `AppsUseLightTheme` registry value: 0 = dark, anything else = light
+#[cfg(any(target_os = "windows", test))]
+fn parse_apps_use_light_theme(value: Option<u32>) -> Theme {
+    match value {
+        Some(0) | None => Theme::Dark,
+        Some(_) => Theme::Light,
+    }
+}
+
+/// Output of `defaults read -g AppleInterfaceStyle`, `None` if it failed
+#[cfg(any(target_os = "macos", test))]
+fn parse_apple_interface_style(output: Option<&str>) -> Theme {
+    match output {
+        Some(style) if style.trim().eq_ignore_ascii_case("dark") => Theme::Dark,
+        Some(_) => Theme::Light,
+        // The key is absent in light mode
+        None => Theme::Light,
+    }
+}
+
+/// freedesktop `color-scheme` (`'prefer-dark'`, `'prefer-light'`,
+/// `'default'`); with no preference, a `-dark` GTK theme decides.
+#[cfg(any(target_os = "linux", test))]
+fn parse_color_scheme(scheme: &str, gtk_theme: Option<&str>) -> Theme {
+    match scheme.trim().trim_matches('\'') {
+        "prefer-dark" => Theme::Dark,
+        "prefer-light" => Theme::Light,
+        _ => match gtk_theme {
+            Some(name) if name.to_ascii_lowercase().contains("dark") => Theme::Dark,
+            _ => Theme::Light,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_env_serialization() {
+        let env = HardwaveEnv {
+            theme: Theme::Light,
+            scale: 1.5,
+        };
+        assert_eq!(serde_json::to_string(&env).unwrap(), r#"{"theme":"light","scale":1.5}"#);
+        assert_eq!(env.query(), "theme=light&scale=1.5");
+
+        assert_eq!(with_env("https://x/a", &env), "https://x/a?theme=light&scale=1.5");
+        assert_eq!(with_env("https://x/a?token=t", &env), "https://x/a?token=t&theme=light&scale=1.5");
+    }
+
+    #[test]
+    fn test_windows_registry_value() {
+        assert_eq!(parse_apps_use_light_theme(Some(0)), Theme::Dark);
+        assert_eq!(parse_apps_use_light_theme(Some(1)), Theme::Light);
+        assert_eq!(parse_apps_use_light_theme(None), Theme::Dark);
+    }
+
+    #[test]
+    fn test_macos_interface_style() {
+        assert_eq!(parse_apple_interface_style(Some("Dark\n")), Theme::Dark);
+        assert_eq!(parse_apple_interface_style(None), Theme::Light);
+    }
+
+    #[test]
+    fn test_freedesktop_color_scheme() {
+        assert_eq!(parse_color_scheme("'prefer-dark'\n", None), Theme::Dark);
+        assert_eq!(parse_color_scheme("'prefer-light'\n", Some("'Adwaita-dark'")), Theme::Light);
+        assert_eq!(parse_color_scheme("'default'\n", Some("'Adwaita-dark'\n")), Theme::Dark);
+        assert_eq!(parse_color_scheme("'default'\n", Some("'Adwaita'\n")), Theme::Light);
+        assert_eq!(parse_color_scheme("'default'\n", None), Theme::Light);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_detect_does_not_panic() {
+        let _ = detect();
+    }
+}
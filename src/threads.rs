@@ -0,0 +1,151 @@
+//! Spawning of the plugin's background threads
+//!
+//! Every thread the plugin starts goes through `spawn`, so each one shows up
+//! named in debuggers and crash dumps and runs at a priority that suits its
+//! job. Priorities are best effort: the platform may refuse a raise, in which
+//! case the thread simply runs at normal priority. Nothing here ever competes
+//! with the host's audio threads. Threads that loop for as long as the
+//! plugin runs start through `spawn_restarting`, so a panic doesn't leave
+//! them dead; see `panics`.
+
+use std::io;
+use std::thread::{self, JoinHandle};
+
+use crate::panics::{self, Region};
+
+/// How urgently a thread needs CPU time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Feeds the meters (connection loop, packet server, drainer, editor):
+    /// above normal threads, below audio
+    Streaming,
+
+    /// Housekeeping that can wait
+    Background,
+}
+
+/// Spawn a named thread at `priority`. Names should start with `hardwave-`
+/// and stay within 15 bytes, the Linux limit.
+pub fn spawn<F, T>(name: &str, priority: Priority, f: F) -> io::Result<JoinHandle<T>>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    thread::Builder::new().name(name.to_string()).spawn(move || {
+        set_current_priority(priority);
+        f()
+    })
+}
+
+/// `spawn` for a thread's main loop: after a panic `body` runs again, at
+/// most `panics::MAX_RESTARTS` times, each panic counted for `region`
+pub fn spawn_restarting<F>(name: &str, priority: Priority, region: Region, body: F) -> io::Result<JoinHandle<()>>
+where
+    F: FnMut() + Send + 'static,
+{
+    spawn(name, priority, move || panics::run_restarting(region, body))
+}
+
+#[cfg(target_os = "linux")]
+fn set_current_priority(priority: Priority) {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        fn setpriority(which: c_int, who: u32, prio: c_int) -> c_int;
+    }
+    const PRIO_PROCESS: c_int = 0;
+
+    // On Linux the nice value is per thread and `who = 0` means the calling
+    // thread. Raising priority usually needs privileges and then fails
+    // harmlessly.
+    let nice = match priority {
+        Priority::Streaming => -5,
+        Priority::Background => 10,
+    };
+    unsafe {
+        setpriority(PRIO_PROCESS, 0, nice);
+    }
+}
+
+#[cfg(target_os = "macos")]
+fn set_current_priority(priority: Priority) {
+    use std::os::raw::c_int;
+
+    extern "C" {
+        fn pthread_set_qos_class_self_np(qos_class: u32, relative_priority: c_int) -> c_int;
+    }
+    const QOS_CLASS_USER_INITIATED: u32 = 0x19;
+    const QOS_CLASS_UTILITY: u32 = 0x11;
+
+    let class = match priority {
+        Priority::Streaming => QOS_CLASS_USER_INITIATED,
+        Priority::Background => QOS_CLASS_UTILITY,
+    };
+    unsafe {
+        pthread_set_qos_class_self_np(class, 0);
+    }
+}
+
+#[cfg(target_os = "windows")]
+fn set_current_priority(priority: Priority) {
+    use std::ffi::c_void;
+
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetCurrentThread() -> *mut c_void;
+        fn SetThreadPriority(thread: *mut c_void, priority: i32) -> i32;
+    }
+    const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+    const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+
+    let level = match priority {
+        Priority::Streaming => THREAD_PRIORITY_ABOVE_NORMAL,
+        Priority::Background => THREAD_PRIORITY_BELOW_NORMAL,
+    };
+    unsafe {
+        SetThreadPriority(GetCurrentThread(), level);
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "windows")))]
+fn set_current_priority(_priority: Priority) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn_names_thread() {
+        let handle = spawn("hardwave-test", Priority::Streaming, || {
+            thread::current().name().map(str::to_string)
+        })
+        .unwrap();
+        assert_eq!(handle.thread().name(), Some("hardwave-test"));
+        assert_eq!(handle.join().unwrap().as_deref(), Some("hardwave-test"));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_name_and_priority_visible_to_os() {
+        let (comm, stat) = spawn("hardwave-bg", Priority::Background, || {
+            (
+                std::fs::read_to_string("/proc/thread-self/comm").unwrap(),
+                std::fs::read_to_string("/proc/thread-self/stat").unwrap(),
+            )
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+        assert_eq!(comm.trim(), "hardwave-bg");
+
+        // Field 19 of stat is the nice value; the comm field before it may
+        // contain spaces, so count from the closing parenthesis
+        let nice: i32 = stat[stat.rfind(')').unwrap() + 2..]
+            .split_whitespace()
+            .nth(16)
+            .unwrap()
+            .parse()
+            .unwrap();
+        assert!(nice >= 10, "background thread nice = {}", nice);
+    }
+}
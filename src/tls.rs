@@ -0,0 +1,447 @@
+//! TLS for the connection to the Suite, `wss://`
+//!
+//! The account token travels in the handshake and the packets give away the
+//! structure of the music, so where the connection crosses a network the
+//! user doesn't trust it can go over TLS: `"use_tls": true` in the machine
+//! config (see `config`), in builds with the `tls` feature. A build without
+//! it that is asked for TLS doesn't connect at all and stays in the error
+//! state with `TlsError::Unsupported`; nothing goes out in the clear once
+//! TLS is asked for. A connection over TLS is never the shared one.
+//!
+//! The Suite's certificate is checked against the webpki roots, for the
+//! address the plugin connects to. One no root vouches for, self-signed
+//! say, is turned down with `TlsError::Untrusted`, which carries the
+//! SHA-256 fingerprint of the certificate for the plugin window to show.
+//! Once the user accepts it the fingerprint is pinned for that host, and a
+//! certificate with that fingerprint is taken for the host from then on,
+//! root or not. A pin stands in for the chain and the name only: the
+//! handshake's signatures are checked all the same.
+//!
+//! Pins are saved at `~/.hardwave/tls-pins`, next to the account token, a
+//! line per host: the host, a space and the fingerprint as 64 hex digits.
+
+use parking_lot::Mutex;
+use std::fmt;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "tls")]
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "tls")]
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+#[cfg(feature = "tls")]
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+#[cfg(feature = "tls")]
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+/// How long the TLS handshake may take, on top of the TCP connect
+#[cfg(feature = "tls")]
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Errors setting up TLS, or reading and writing the pins
+#[derive(Debug)]
+pub enum TlsError {
+    /// TLS is asked for and this build has none
+    #[cfg(not(feature = "tls"))]
+    Unsupported,
+    /// No root vouches for the certificate and it isn't pinned for the host
+    #[cfg(any(feature = "tls", test))]
+    Untrusted { host: String, fingerprint: String },
+    /// The handshake failed for any other reason
+    #[cfg(feature = "tls")]
+    Handshake(String),
+    /// The certificate to accept isn't the one last turned down
+    #[cfg(any(feature = "gui", test))]
+    NotOffered,
+    /// No home directory to keep the pins in
+    #[cfg(any(feature = "gui", test))]
+    NoHomeDir,
+    /// The pins file couldn't be read or written
+    Io(io::Error),
+}
+
+impl fmt::Display for TlsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            #[cfg(not(feature = "tls"))]
+            TlsError::Unsupported => write!(f, "TLS is on but this build has no TLS"),
+            #[cfg(any(feature = "tls", test))]
+            TlsError::Untrusted { host, fingerprint } => {
+                write!(f, "certificate of {} is not trusted, SHA-256 {}", host, fingerprint)
+            }
+            #[cfg(feature = "tls")]
+            TlsError::Handshake(why) => write!(f, "TLS handshake failed: {}", why),
+            #[cfg(any(feature = "gui", test))]
+            TlsError::NotOffered => write!(f, "that certificate was not offered"),
+            #[cfg(any(feature = "gui", test))]
+            TlsError::NoHomeDir => write!(f, "no home directory to store the pins in"),
+            TlsError::Io(e) => write!(f, "pins file I/O error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TlsError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+fn pins_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|h| h.join(".hardwave").join("tls-pins"))
+}
+
+fn is_fingerprint(text: &str) -> bool {
+    text.len() == 64 && text.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+/// Certificates accepted for a host although no root vouches for them
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TlsPins {
+    /// Where they are saved; none keeps them in memory only
+    path: Option<PathBuf>,
+    /// Host and fingerprint, lowercase
+    pins: Vec<(String, String)>,
+}
+
+impl TlsPins {
+    /// The saved pins. No file, or no home directory, means none; a file
+    /// that can't be read is an error.
+    pub fn load() -> Result<Self, TlsError> {
+        pins_path().map_or(Ok(Self::default()), Self::load_from)
+    }
+
+    /// The pins saved at `path`; lines that aren't a host and a fingerprint
+    /// are skipped
+    pub fn load_from(path: PathBuf) -> Result<Self, TlsError> {
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(TlsError::Io(e)),
+        };
+        let pins = text
+            .lines()
+            .filter_map(|line| {
+                let (host, fingerprint) = line.trim().split_once(' ')?;
+                let fingerprint = fingerprint.trim().to_ascii_lowercase();
+                is_fingerprint(&fingerprint).then(|| (host.to_string(), fingerprint))
+            })
+            .collect();
+        Ok(Self { path: Some(path), pins })
+    }
+
+    /// Whether the pins have been loaded from, or go to, a file
+    pub fn is_saved(&self) -> bool {
+        self.path.is_some()
+    }
+
+    /// The fingerprint pinned for `host`
+    #[cfg(any(feature = "tls", test))]
+    pub fn fingerprint(&self, host: &str) -> Option<&str> {
+        self.pins.iter().find(|(pinned, _)| pinned == host).map(|(_, fingerprint)| fingerprint.as_str())
+    }
+
+    /// Pin `fingerprint` for `host` in place of any pin it had, and save.
+    /// Without a file to save to it is pinned until the plugin unloads.
+    #[cfg(any(feature = "gui", test))]
+    pub fn pin(&mut self, host: &str, fingerprint: &str) -> Result<(), TlsError> {
+        self.pins.retain(|(pinned, _)| pinned != host);
+        self.pins.push((host.to_string(), fingerprint.to_ascii_lowercase()));
+        let path = self.path.as_ref().ok_or(TlsError::NoHomeDir)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).map_err(TlsError::Io)?;
+        }
+        let text: String = self.pins.iter().map(|(host, fingerprint)| format!("{} {}\n", host, fingerprint)).collect();
+        fs::write(path, text).map_err(TlsError::Io)
+    }
+}
+
+/// Whether connections use TLS, the pins, and the certificate last turned
+/// down; shared by the connection thread and the plugin window
+#[derive(Debug, Default)]
+pub struct TlsControl {
+    enabled: AtomicBool,
+    pins: Mutex<TlsPins>,
+    /// Host and fingerprint
+    untrusted: Mutex<Option<(String, String)>>,
+}
+
+impl TlsControl {
+    pub fn enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+
+    /// From the next connection
+    pub fn set_enabled(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    pub fn pins(&self) -> TlsPins {
+        self.pins.lock().clone()
+    }
+
+    pub fn set_pins(&self, pins: TlsPins) {
+        *self.pins.lock() = pins;
+    }
+
+    /// How the last connection attempt went: a certificate turned down is
+    /// kept for the plugin window to offer, anything else forgets it
+    pub fn attempted(&self, error: Option<&TlsError>) {
+        *self.untrusted.lock() = match error {
+            #[cfg(any(feature = "tls", test))]
+            Some(TlsError::Untrusted { host, fingerprint }) => Some((host.clone(), fingerprint.clone())),
+            _ => None,
+        };
+    }
+
+    /// Fingerprint of the certificate last turned down, for the user to
+    /// accept
+    #[cfg(any(feature = "gui", test))]
+    pub fn untrusted(&self) -> Option<String> {
+        self.untrusted.lock().as_ref().map(|(_, fingerprint)| fingerprint.clone())
+    }
+
+    /// Pin the certificate last turned down for its host, if it is the one
+    /// with `fingerprint`: the user accepted what they were shown, not
+    /// whatever the Suite offers next
+    #[cfg(any(feature = "gui", test))]
+    pub fn trust(&self, fingerprint: &str) -> Result<(), TlsError> {
+        let mut untrusted = self.untrusted.lock();
+        match untrusted.take() {
+            Some((host, offered)) if offered.eq_ignore_ascii_case(fingerprint) => self.pins.lock().pin(&host, &offered),
+            offered => {
+                *untrusted = offered;
+                Err(TlsError::NotOffered)
+            }
+        }
+    }
+}
+
+/// The SHA-256 of a certificate's DER, as 64 lowercase hex digits
+#[cfg(feature = "tls")]
+pub fn fingerprint(der: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, der);
+    digest.as_ref().iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// What a WebSocket to the Suite runs over
+pub enum Stream {
+    Plain(TcpStream),
+    #[cfg(feature = "tls")]
+    Tls(Box<rustls::StreamOwned<rustls::ClientConnection, TcpStream>>),
+}
+
+impl Stream {
+    /// The TCP socket underneath, for its options
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(tcp) => tcp,
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => &tls.sock,
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.read(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(tcp) => tcp.write(buf),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(tcp) => tcp.flush(),
+            #[cfg(feature = "tls")]
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+/// Shake hands over `tcp` with the Suite at `host`, taking its certificate
+/// if a root vouches for it or it is pinned for the host
+#[cfg(feature = "tls")]
+pub fn connect(mut tcp: TcpStream, host: &str, pins: &TlsPins) -> Result<Stream, TlsError> {
+    use std::sync::Arc;
+
+    let handshake = |e: &dyn fmt::Display| TlsError::Handshake(e.to_string());
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let roots = rustls::RootCertStore { roots: webpki_roots::TLS_SERVER_ROOTS.to_vec() };
+    let roots = rustls::client::WebPkiServerVerifier::builder_with_provider(Arc::new(roots), Arc::clone(&provider))
+        .build()
+        .map_err(|e| handshake(&e))?;
+    let verifier = Arc::new(PinningVerifier {
+        roots,
+        pin: pins.fingerprint(host).map(str::to_string),
+        offered: Mutex::new(None),
+    });
+    let config = rustls::ClientConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .map_err(|e| handshake(&e))?
+        .dangerous()
+        .with_custom_certificate_verifier(verifier.clone())
+        .with_no_client_auth();
+    let name = ServerName::try_from(host).map_err(|e| handshake(&e))?.to_owned();
+    let mut connection = rustls::ClientConnection::new(Arc::new(config), name).map_err(|e| handshake(&e))?;
+
+    let deadline = Instant::now() + HANDSHAKE_TIMEOUT;
+    while connection.is_handshaking() {
+        match connection.complete_io(&mut tcp) {
+            Ok(_) => {}
+            // The socket's read timeout is shorter than a handshake may take
+            Err(e)
+                if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut)
+                    && Instant::now() < deadline => {}
+            Err(e) => {
+                return Err(match verifier.offered.lock().take() {
+                    Some(fingerprint) => TlsError::Untrusted { host: host.to_string(), fingerprint },
+                    None => handshake(&e),
+                })
+            }
+        }
+    }
+    Ok(Stream::Tls(Box::new(rustls::StreamOwned::new(connection, tcp))))
+}
+
+/// Without the `tls` feature there is no TLS to connect with
+#[cfg(not(feature = "tls"))]
+pub fn connect(_tcp: TcpStream, _host: &str, _pins: &TlsPins) -> Result<Stream, TlsError> {
+    Err(TlsError::Unsupported)
+}
+
+/// The webpki roots, or the pin for the host in their place; keeps the
+/// fingerprint of a certificate it turned down
+#[cfg(feature = "tls")]
+#[derive(Debug)]
+struct PinningVerifier {
+    roots: std::sync::Arc<rustls::client::WebPkiServerVerifier>,
+    pin: Option<String>,
+    offered: Mutex<Option<String>>,
+}
+
+#[cfg(feature = "tls")]
+impl ServerCertVerifier for PinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let fingerprint = fingerprint(end_entity.as_ref());
+        if self.pin.as_deref() == Some(fingerprint.as_str()) {
+            return Ok(ServerCertVerified::assertion());
+        }
+        let verified = self.roots.verify_server_cert(end_entity, intermediates, server_name, ocsp_response, now);
+        if let Err(rustls::Error::InvalidCertificate(_)) = &verified {
+            *self.offered.lock() = Some(fingerprint);
+        }
+        verified
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.roots.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.roots.supported_verify_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FINGERPRINT: &str = "b562da0dbd2408761951cbcf1ed5b3fd7851aad8af467692cb5c6579b505b4de";
+
+    fn scratch_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hardwave-tls-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join("tls-pins")
+    }
+
+    #[test]
+    fn test_pins_are_saved_per_host() {
+        let path = scratch_path("pins");
+        let mut pins = TlsPins::load_from(path.clone()).unwrap();
+        assert_eq!(pins.fingerprint("127.0.0.1"), None);
+        pins.pin("127.0.0.1", &"AB".repeat(32)).unwrap();
+        pins.pin("127.0.0.1", FINGERPRINT).unwrap();
+        pins.pin("studio.local", &"cd".repeat(32)).unwrap();
+
+        // A newer pin replaces the host's old one
+        let loaded = TlsPins::load_from(path.clone()).unwrap();
+        assert_eq!(loaded, pins);
+        assert_eq!(loaded.fingerprint("127.0.0.1"), Some(FINGERPRINT));
+        assert_eq!(loaded.fingerprint("studio.local"), Some("cd".repeat(32).as_str()));
+
+        // Lines that aren't pins are skipped
+        fs::write(&path, format!("garbage\n127.0.0.1 not-hex\nstudio.local {}\n", FINGERPRINT.to_uppercase())).unwrap();
+        let loaded = TlsPins::load_from(path).unwrap();
+        assert_eq!(loaded.fingerprint("127.0.0.1"), None);
+        assert_eq!(loaded.fingerprint("studio.local"), Some(FINGERPRINT));
+
+        let mut memory = TlsPins::default();
+        assert!(matches!(memory.pin("127.0.0.1", FINGERPRINT), Err(TlsError::NoHomeDir)));
+        assert_eq!(memory.fingerprint("127.0.0.1"), Some(FINGERPRINT), "pinned until unloaded");
+    }
+
+    #[test]
+    fn test_only_the_certificate_offered_is_trusted() {
+        let control = TlsControl::default();
+        control.set_pins(TlsPins::load_from(scratch_path("trust")).unwrap());
+        assert!(matches!(control.trust(FINGERPRINT), Err(TlsError::NotOffered)));
+
+        let untrusted = TlsError::Untrusted { host: "127.0.0.1".to_string(), fingerprint: FINGERPRINT.to_string() };
+        control.attempted(Some(&untrusted));
+        assert_eq!(control.untrusted().as_deref(), Some(FINGERPRINT));
+        assert!(matches!(control.trust(&"00".repeat(32)), Err(TlsError::NotOffered)));
+        assert_eq!(control.untrusted().as_deref(), Some(FINGERPRINT), "still on offer");
+        control.trust(&FINGERPRINT.to_uppercase()).unwrap();
+        assert_eq!(control.untrusted(), None);
+        assert_eq!(control.pins().fingerprint("127.0.0.1"), Some(FINGERPRINT));
+
+        // A connection that got further forgets what was turned down
+        control.attempted(Some(&untrusted));
+        control.attempted(None);
+        assert_eq!(control.untrusted(), None);
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint(include_bytes!("../tls_fixtures/suite-cert.der")), FINGERPRINT);
+    }
+}
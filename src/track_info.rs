@@ -0,0 +1,137 @@
+//! Track name and colour from the host
+//!
+//! CLAP's track-info extension and VST3's `IInfoListener` tell a plugin the
+//! name and colour of the track it sits on. They become the default label and
+//! colour the Suite shows the instance with; a label or colour set in the
+//! plugin window still wins. nih-plug surfaces neither interface yet, so the
+//! plugin reads them through `TrackInfoSource`, with `NoTrackInfo` standing
+//! in until a host-backed source exists.
+
+use crate::display;
+use crate::params::HardwaveAnalyserParams;
+
+/// What the host says about the track this instance is on
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TrackInfo {
+    pub name: Option<String>,
+    /// `#rrggbb`
+    pub color: Option<String>,
+}
+
+/// Where track info comes from. Only called on the main thread, as both
+/// plugin APIs require.
+pub trait TrackInfoSource: Send {
+    /// Current track info, or `None` if the host doesn't report any
+    fn track_info(&self) -> Option<TrackInfo>;
+}
+
+/// A host that reports nothing
+pub struct NoTrackInfo;
+
+impl TrackInfoSource for NoTrackInfo {
+    fn track_info(&self) -> Option<TrackInfo> {
+        None
+    }
+}
+
+/// Copies the host's track info into the parameters when it changes
+pub struct TrackInfoWatcher {
+    source: Box<dyn TrackInfoSource>,
+}
+
+impl TrackInfoWatcher {
+    pub fn new(source: Box<dyn TrackInfoSource>) -> Self {
+        Self { source }
+    }
+
+    /// Re-read the track info, e.g. when the host signals a change. Returns
+    /// whether it changed, in which case the display hints need republishing.
+    pub fn refresh(&self, params: &HardwaveAnalyserParams) -> bool {
+        // Hosts pass whatever the user typed; hold it to the same rules as ours
+        let info = self.source.track_info().map(|info| TrackInfo {
+            name: info.name.as_deref().and_then(display::clean_label),
+            color: info.color.as_deref().and_then(display::parse_color),
+        });
+        let Ok(mut current) = params.host_track.write() else {
+            return false;
+        };
+        if *current == info {
+            return false;
+        }
+        *current = info;
+        true
+    }
+}
+
+impl Default for TrackInfoWatcher {
+    fn default() -> Self {
+        Self::new(Box::new(NoTrackInfo))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::AudioPacket;
+    use std::sync::{Arc, Mutex};
+
+    /// A host whose track the test renames and recolours
+    struct MockHost(Arc<Mutex<Option<TrackInfo>>>);
+
+    impl TrackInfoSource for MockHost {
+        fn track_info(&self) -> Option<TrackInfo> {
+            self.0.lock().unwrap().clone()
+        }
+    }
+
+    fn track(name: &str, color: &str) -> Option<TrackInfo> {
+        Some(TrackInfo {
+            name: Some(name.to_string()),
+            color: Some(color.to_string()),
+        })
+    }
+
+    #[test]
+    fn test_refresh_on_change() {
+        let host = Arc::new(Mutex::new(track("Kick", "#FF0000")));
+        let watcher = TrackInfoWatcher::new(Box::new(MockHost(Arc::clone(&host))));
+        let params = HardwaveAnalyserParams::default();
+
+        assert!(watcher.refresh(&params));
+        assert!(!watcher.refresh(&params), "unchanged info is not a change");
+        let heartbeat = AudioPacket::new_heartbeat(0, 0).with_display(&params.display_hints());
+        assert_eq!(heartbeat.display_label, "Kick");
+        assert_eq!(heartbeat.display_color, "#ff0000");
+
+        *host.lock().unwrap() = track("Kick Sub", "#00ff00");
+        assert!(watcher.refresh(&params));
+        let heartbeat = AudioPacket::new_heartbeat(0, 0).with_display(&params.display_hints());
+        assert_eq!(heartbeat.display_label, "Kick Sub");
+        assert_eq!(heartbeat.display_color, "#00ff00");
+
+        // The track info going away falls back to the derived defaults
+        *host.lock().unwrap() = None;
+        assert!(watcher.refresh(&params));
+        let hints = params.display_hints();
+        assert_eq!(hints.label, display::default_label(&hints.instance_id));
+        assert_eq!(hints.color, display::default_color(&hints.instance_id));
+    }
+
+    #[test]
+    fn test_host_values_are_cleaned() {
+        let host = Arc::new(Mutex::new(track("  Vox\u{7}  ", "not a colour")));
+        let watcher = TrackInfoWatcher::new(Box::new(MockHost(host)));
+        let params = HardwaveAnalyserParams::default();
+        watcher.refresh(&params);
+        let hints = params.display_hints();
+        assert_eq!(hints.label, "Vox");
+        assert_eq!(hints.color, display::default_color(&hints.instance_id));
+    }
+
+    #[test]
+    fn test_default_watcher_reports_nothing() {
+        let params = HardwaveAnalyserParams::default();
+        assert!(!TrackInfoWatcher::default().refresh(&params));
+        assert_eq!(*params.host_track.read().unwrap(), None);
+    }
+}
@@ -0,0 +1,331 @@
+//! Spots the host looping a section from its transport position
+//!
+//! Not every plugin API reports the host's loop range, but every host
+//! reports where the playhead is at the start of each block. While playing,
+//! the next block should start one block later; a block that starts earlier
+//! is a backward jump. One backward jump is a seek. Two in a row that land
+//! in the same place after covering the same length are a loop: the user
+//! doesn't click back to the same spot after exactly the same amount of
+//! playing twice, the host does it on every pass.
+//!
+//! The loop is confirmed on its second wrap, so one repeat has already gone
+//! by. Boundaries are exact in length and good to within a block in
+//! position, since the host only says where a block starts, not where in it
+//! the wrap fell.
+//!
+//! The Suite owns the integrated measurements (LUFS-I, session max); it
+//! gets a `loop_detected` packet for every event here and applies the loop
+//! policy the packet carries, as `LoopEvent::action` describes.
+
+#[cfg(test)]
+use crate::params::LoopPolicy;
+
+/// Positions within this many samples of each other count as the same,
+/// for hosts that round the playhead
+const POSITION_SLACK: i64 = 2;
+
+/// A looped section, in samples at the host rate from the project start
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoopRange {
+    pub start: i64,
+    pub end: i64,
+}
+
+impl LoopRange {
+    #[cfg(test)]
+    pub fn len_samples(&self) -> i64 {
+        self.end - self.start
+    }
+}
+
+/// What the transport did this block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LoopEvent {
+    /// The same backward jump came twice: the host is looping
+    Detected { range: LoopRange, wraps: u32 },
+    /// Another pass of a detected loop began
+    Wrapped { range: LoopRange, wraps: u32 },
+    /// The transport stopped, played past the end or jumped elsewhere
+    Ended { range: LoopRange, wraps: u32 },
+}
+
+/// What an integrated measurement does on a loop event
+#[cfg(test)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccumulatorAction {
+    /// Start over, so it covers the current pass only
+    Reset,
+    /// Stop taking audio in
+    Pause,
+    /// Take audio in again after a pause
+    Resume,
+}
+
+impl LoopEvent {
+    pub fn range(&self) -> LoopRange {
+        match *self {
+            LoopEvent::Detected { range, .. } | LoopEvent::Wrapped { range, .. } | LoopEvent::Ended { range, .. } => {
+                range
+            }
+        }
+    }
+
+    /// Backward jumps seen so far, counting the first one before detection
+    pub fn wraps(&self) -> u32 {
+        match *self {
+            LoopEvent::Detected { wraps, .. } | LoopEvent::Wrapped { wraps, .. } | LoopEvent::Ended { wraps, .. } => {
+                wraps
+            }
+        }
+    }
+
+    /// Whether the loop is still going after this event
+    pub fn looping(&self) -> bool {
+        !matches!(self, LoopEvent::Ended { .. })
+    }
+
+    /// What integrated measurements do on this event under `policy`; `None`
+    /// carries on as if there weren't a loop
+    #[cfg(test)]
+    pub fn action(&self, policy: LoopPolicy) -> Option<AccumulatorAction> {
+        match (policy, self) {
+            (LoopPolicy::Continue, _) => None,
+            (LoopPolicy::Reset, LoopEvent::Ended { .. }) => None,
+            (LoopPolicy::Reset, _) => Some(AccumulatorAction::Reset),
+            (LoopPolicy::Pause, LoopEvent::Detected { .. }) => Some(AccumulatorAction::Pause),
+            (LoopPolicy::Pause, LoopEvent::Wrapped { .. }) => None,
+            (LoopPolicy::Pause, LoopEvent::Ended { .. }) => Some(AccumulatorAction::Resume),
+        }
+    }
+}
+
+/// A backward jump: where the block should have started, and where it did
+#[derive(Debug, Clone, Copy)]
+struct Jump {
+    from: i64,
+    to: i64,
+}
+
+impl Jump {
+    /// The same wrap of the same loop. The wrap can fall anywhere in a
+    /// block, so both ends move by up to a block between passes; the length
+    /// only does if the host doesn't wrap sample-accurately.
+    fn matches(&self, other: &Jump, block_len: i64) -> bool {
+        let tolerance = block_len + POSITION_SLACK;
+        (self.to - other.to).abs() <= tolerance
+            && ((self.from - self.to) - (other.from - other.to)).abs() <= tolerance
+    }
+}
+
+/// Per-block state of the playhead. Audio thread only; never allocates.
+#[derive(Debug, Default)]
+pub struct LoopDetector {
+    /// Where the next block should start if nothing jumps
+    expected: Option<i64>,
+    /// The last backward jump, not yet repeated
+    candidate: Option<Jump>,
+    /// The confirmed loop and the jump that wraps it
+    active: Option<(LoopRange, Jump)>,
+    /// Backward jumps since the candidate, for the current loop
+    wraps: u32,
+}
+
+impl LoopDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Whether a loop is confirmed and still going
+    #[cfg(test)]
+    pub fn looping(&self) -> bool {
+        self.active.is_some()
+    }
+
+    /// Audio thread, once per block, with the host's transport: whether it
+    /// plays, where the block starts (`None` if the host doesn't say) and
+    /// how long the block is
+    pub fn observe(&mut self, playing: bool, pos_samples: Option<i64>, block_len: usize) -> Option<LoopEvent> {
+        let (true, Some(pos)) = (playing, pos_samples) else {
+            self.expected = None;
+            self.candidate = None;
+            return self.end();
+        };
+        let block_len = block_len as i64;
+        let expected = self.expected.replace(pos + block_len)?;
+
+        if (pos - expected).abs() <= POSITION_SLACK {
+            // Playing on; past the end plus the block the wrap could fall in
+            // means the host stopped looping
+            let past_end = self.active.is_some_and(|(range, _)| pos > range.end + block_len + POSITION_SLACK);
+            return if past_end { self.end() } else { None };
+        }
+
+        if pos > expected {
+            self.candidate = None;
+            return self.end();
+        }
+
+        let jump = Jump { from: expected, to: pos };
+        if let Some((range, wrap)) = self.active {
+            if wrap.matches(&jump, block_len) {
+                self.wraps += 1;
+                return Some(LoopEvent::Wrapped { range, wraps: self.wraps });
+            }
+            // Somewhere else: this may be the first wrap of another loop
+            let ended = self.end();
+            self.candidate = Some(jump);
+            self.wraps = 1;
+            return ended;
+        }
+
+        match self.candidate.take() {
+            Some(first) if first.matches(&jump, block_len) => {
+                let start = first.to.min(jump.to);
+                let range = LoopRange { start, end: start + (jump.from - jump.to) };
+                self.active = Some((range, jump));
+                self.wraps += 1;
+                Some(LoopEvent::Detected { range, wraps: self.wraps })
+            }
+            _ => {
+                self.candidate = Some(jump);
+                self.wraps = 1;
+                None
+            }
+        }
+    }
+
+    /// Forget the playhead, as after the plugin is reset
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+
+    fn end(&mut self) -> Option<LoopEvent> {
+        let (range, _) = self.active.take()?;
+        Some(LoopEvent::Ended { range, wraps: std::mem::take(&mut self.wraps) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BLOCK: usize = 512;
+
+    /// Play `blocks` blocks from `start`, wrapping from `end` back to
+    /// `loop_start` mid-block the way hosts do
+    fn play_looped(detector: &mut LoopDetector, loop_start: i64, end: i64, blocks: usize) -> Vec<LoopEvent> {
+        let mut pos = loop_start;
+        let mut events = Vec::new();
+        for _ in 0..blocks {
+            events.extend(detector.observe(true, Some(pos), BLOCK));
+            pos += BLOCK as i64;
+            if pos >= end {
+                pos = loop_start + (pos - end);
+            }
+        }
+        events
+    }
+
+    #[test]
+    fn test_loop_is_detected_on_the_second_wrap() {
+        let mut detector = LoopDetector::new();
+        // Four bars at 120 bpm and 48 kHz, not a whole number of blocks
+        let (start, end) = (96_000, 96_000 + 384_100);
+        let blocks_per_pass = 384_100 / BLOCK + 1;
+
+        let events = play_looped(&mut detector, start, end, blocks_per_pass * 3);
+        assert_eq!(events.len(), 2, "{:?}", events);
+        let LoopEvent::Detected { range, wraps: 2 } = events[0] else {
+            panic!("{:?}", events);
+        };
+        assert_eq!(range.len_samples(), 384_100);
+        assert!((range.start - start).abs() <= BLOCK as i64, "{:?}", range);
+        assert_eq!(events[1], LoopEvent::Wrapped { range, wraps: 3 });
+        assert!(detector.looping());
+
+        assert!(matches!(detector.observe(false, Some(0), BLOCK), Some(LoopEvent::Ended { wraps: 3, .. })));
+        assert!(!detector.looping());
+    }
+
+    #[test]
+    fn test_a_single_seek_is_not_a_loop() {
+        let mut detector = LoopDetector::new();
+        let mut events = Vec::new();
+        let mut pos = 200_000;
+        for block in 0..2000 {
+            if block == 500 {
+                pos = 10_000;
+            }
+            events.extend(detector.observe(true, Some(pos), BLOCK));
+            pos += BLOCK as i64;
+        }
+        assert_eq!(events, []);
+
+        // Nor is going back to the same spot twice after playing for different
+        // lengths, like returning to the top of a section
+        let mut detector = LoopDetector::new();
+        let mut pos = 300_000;
+        for block in 0..300 {
+            if block == 10 || block == 100 {
+                pos = 10_000;
+            }
+            assert_eq!(detector.observe(true, Some(pos), BLOCK), None);
+            pos += BLOCK as i64;
+        }
+        assert!(!detector.looping());
+    }
+
+    #[test]
+    fn test_loop_ends_when_the_playhead_leaves_it() {
+        let mut detector = LoopDetector::new();
+        let events = play_looped(&mut detector, 0, 51_200, 250);
+        assert!(matches!(events[0], LoopEvent::Detected { .. }));
+
+        // The host turns looping off and plays on through the end
+        let mut pos = 250 % 100 * BLOCK as i64;
+        let mut after = Vec::new();
+        for _ in 0..110 {
+            after.extend(detector.observe(true, Some(pos), BLOCK));
+            pos += BLOCK as i64;
+        }
+        assert!(matches!(after[..], [LoopEvent::Ended { wraps: 2, .. }]), "{:?}", after);
+        assert!(!detector.looping());
+    }
+
+    #[test]
+    fn test_accumulator_policies() {
+        // A running maximum, the simplest session measurement
+        fn session_max(policy: LoopPolicy) -> f32 {
+            let range = LoopRange { start: 0, end: 1000 };
+            let feed: [(f32, Option<LoopEvent>); 6] = [
+                (-3.0, None),
+                (-6.0, None),
+                (-1.0, Some(LoopEvent::Detected { range, wraps: 2 })),
+                (-2.0, Some(LoopEvent::Wrapped { range, wraps: 3 })),
+                (-12.0, Some(LoopEvent::Ended { range, wraps: 3 })),
+                (-10.0, None),
+            ];
+            let (mut max, mut paused) = (f32::NEG_INFINITY, false);
+            for (level, event) in feed {
+                match event.and_then(|event| event.action(policy)) {
+                    Some(AccumulatorAction::Reset) => max = f32::NEG_INFINITY,
+                    Some(AccumulatorAction::Pause) => paused = true,
+                    Some(AccumulatorAction::Resume) => paused = false,
+                    None => {}
+                }
+                if !paused {
+                    max = max.max(level);
+                }
+            }
+            max
+        }
+        assert_eq!(session_max(LoopPolicy::Continue), -1.0);
+        assert_eq!(session_max(LoopPolicy::Reset), -2.0, "from the last pass on");
+        assert_eq!(session_max(LoopPolicy::Pause), -3.0, "without the loop");
+
+        let range = LoopRange { start: 0, end: 1000 };
+        let ended = LoopEvent::Ended { range, wraps: 3 };
+        assert_eq!(ended.action(LoopPolicy::Pause), Some(AccumulatorAction::Resume));
+        assert!(!ended.looping());
+    }
+}
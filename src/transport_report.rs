@@ -0,0 +1,198 @@
+//! Transport packets: tempo, time signature, position and play state
+//!
+//! The Suite lines its history view up with bars and stops the waterfall
+//! scrolling while the host is stopped, so it needs the host transport
+//! itself, not only the beat phase of each frame. A `PACKET_TYPE_TRANSPORT`
+//! packet goes out on the first block, whenever the tempo, the time
+//! signature or the play or record state changes or the position jumps,
+//! and every `REPORT_INTERVAL_S` while playing. Time is counted in host
+//! samples, as everywhere the transport is followed, so rendering faster or
+//! slower than real time reports just as often per second of audio.
+//!
+//! A tempo ramp is reported each time it has moved `TEMPO_SLACK_BPM` from
+//! the last report, not every block. A jump is a block that doesn't start
+//! where the one before it ended, give or take `POSITION_SLACK` samples;
+//! while stopped, the playhead should stay put.
+//!
+//! Hosts leave out what they don't know, and the packet carries sentinels
+//! for those: a tempo of 0, a time signature of 0/0, and
+//! `protocol::UNKNOWN_BEATS` or `protocol::UNKNOWN_SAMPLES` for a position.
+//! The packets coalesce like FFT packets; a Suite that falls behind only
+//! needs the newest.
+
+use crate::beat_phase::TransportSnapshot;
+
+/// Reports while playing, at least this often in seconds of audio
+pub const REPORT_INTERVAL_S: f32 = 0.5;
+
+/// Tempo that moved less than this since the last report isn't a change
+const TEMPO_SLACK_BPM: f64 = 0.01;
+
+/// Positions within this many samples of where they should be aren't a jump
+const POSITION_SLACK: i64 = 2;
+
+/// Decides which blocks send a transport packet; owned by the audio thread
+#[derive(Debug, Default)]
+pub struct TransportReporter {
+    /// The transport last reported
+    reported: Option<TransportSnapshot>,
+    /// Where the next block should start, if the host reports positions
+    expected_samples: Option<i64>,
+    /// Host samples since the last report
+    since_report: u64,
+}
+
+impl TransportReporter {
+    /// Whether the block of `len` samples starting at `transport` sends a
+    /// transport packet
+    pub fn observe(&mut self, transport: &TransportSnapshot, len: usize) -> bool {
+        let jumped = match (self.expected_samples, transport.pos_samples) {
+            (Some(expected), Some(pos)) => (pos - expected).abs() > POSITION_SLACK,
+            _ => false,
+        };
+        let changed = self.reported.is_none_or(|reported| {
+            reported.playing != transport.playing
+                || reported.recording != transport.recording
+                || reported.time_sig != transport.time_sig
+                || match (reported.tempo, transport.tempo) {
+                    (Some(before), Some(now)) => (now - before).abs() >= TEMPO_SLACK_BPM,
+                    (before, now) => before.is_some() != now.is_some(),
+                }
+        });
+        let due = transport.playing
+            && self.since_report as f32 >= REPORT_INTERVAL_S * transport.sample_rate.max(1.0);
+
+        let advance = if transport.playing { len as i64 } else { 0 };
+        self.expected_samples = transport.pos_samples.map(|pos| pos + advance);
+        let report = jumped || changed || due;
+        if report {
+            self.reported = Some(*transport);
+            self.since_report = 0;
+        }
+        self.since_report += len as u64;
+        report
+    }
+
+    /// Start over: the next block reports
+    pub fn reset(&mut self) {
+        *self = Self::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{AudioPacket, PACKET_TYPE_TRANSPORT, UNKNOWN_BEATS, UNKNOWN_SAMPLES};
+
+    const RATE: f32 = 48_000.0;
+    const BLOCK: usize = 480;
+
+    /// Playing at 120 BPM in 4/4, `block` blocks in
+    fn playing(block: i64) -> TransportSnapshot {
+        let pos_samples = block * BLOCK as i64;
+        let pos_beats = pos_samples as f64 / f64::from(RATE) * 2.0;
+        TransportSnapshot {
+            playing: true,
+            recording: false,
+            sample_rate: RATE,
+            pos_samples: Some(pos_samples),
+            pos_beats: Some(pos_beats),
+            bar_start_pos_beats: Some((pos_beats / 4.0).floor() * 4.0),
+            tempo: Some(120.0),
+            time_sig: Some((4, 4)),
+        }
+    }
+
+    /// The blocks of `transports` that report
+    fn reports(
+        reporter: &mut TransportReporter,
+        transports: impl IntoIterator<Item = TransportSnapshot>,
+    ) -> Vec<usize> {
+        transports
+            .into_iter()
+            .enumerate()
+            .filter(|(_, transport)| reporter.observe(transport, BLOCK))
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    #[test]
+    fn test_reports_on_start_and_at_2_hz_while_playing() {
+        let mut reporter = TransportReporter::default();
+        // Two seconds, 50 blocks a 0.5 s
+        assert_eq!(reports(&mut reporter, (0..200).map(playing)), [0, 50, 100, 150]);
+
+        // Stopped where it was: nothing more to say after the stop itself
+        let stopped = TransportSnapshot { playing: false, ..playing(200) };
+        assert_eq!(reports(&mut reporter, std::iter::repeat_n(stopped, 100)), [0]);
+    }
+
+    #[test]
+    fn test_changes_and_jumps_report_at_once() {
+        let mut reporter = TransportReporter::default();
+        reports(&mut reporter, (0..10).map(playing));
+
+        let tempo = |tempo| TransportSnapshot { tempo: Some(tempo), ..playing(10) };
+        assert!(!reporter.observe(&tempo(120.004), BLOCK), "under the slack");
+        let mut reporter = TransportReporter::default();
+        reports(&mut reporter, (0..10).map(playing));
+        assert!(reporter.observe(&tempo(128.0), BLOCK));
+
+        let mut reporter = TransportReporter::default();
+        reports(&mut reporter, (0..10).map(playing));
+        let recording = TransportSnapshot { recording: true, ..playing(10) };
+        let waltz = TransportSnapshot { time_sig: Some((3, 4)), ..playing(11) };
+        let no_tempo = TransportSnapshot { tempo: None, ..playing(12) };
+        // Back to bar 1, then on from there, then a rounded position
+        let looped = playing(0);
+        let after = playing(1);
+        let rounded = TransportSnapshot { pos_samples: Some(2 * BLOCK as i64 + 1), ..playing(2) };
+        assert_eq!(
+            reports(&mut reporter, [recording, waltz, no_tempo, looped, after, rounded]),
+            [0, 1, 2, 3],
+            "a change each and a jump, and then nothing new"
+        );
+
+        // Moving the playhead while stopped is a jump too
+        let mut reporter = TransportReporter::default();
+        let stopped = |pos| TransportSnapshot { playing: false, pos_samples: Some(pos), ..playing(0) };
+        assert_eq!(reports(&mut reporter, [stopped(0), stopped(0), stopped(96_000), stopped(96_000)]), [0, 2]);
+
+        reporter.reset();
+        assert!(reporter.observe(&stopped(96_000), BLOCK), "the first block after a reset");
+    }
+
+    #[test]
+    fn test_packet_round_trip_and_sentinels() {
+        let transport = TransportSnapshot { recording: true, time_sig: Some((7, 8)), ..playing(100) };
+        let packet = AudioPacket::new_transport(4321, &transport);
+        let decoded = AudioPacket::from_bytes(&packet.to_bytes()).unwrap();
+        assert_eq!(decoded.packet_type, PACKET_TYPE_TRANSPORT);
+        assert_eq!((decoded.sample_rate, decoded.timestamp_ms), (48_000, 4321));
+        assert_eq!(decoded.tempo_bpm, 120.0);
+        assert_eq!((decoded.time_sig_numerator, decoded.time_sig_denominator), (7, 8));
+        assert_eq!(decoded.pos_samples, 48_000);
+        assert_eq!((decoded.pos_beats, decoded.bar_start_beats), (2.0, 0.0));
+        assert!(decoded.playing && decoded.recording);
+        assert_eq!(decoded.coalesce_key(), Some(PACKET_TYPE_TRANSPORT), "state, only the newest matters");
+        assert!(decoded.left_bins.is_empty() && decoded.left_wave.is_empty());
+
+        // A host that reports nothing but the play state
+        let bare = TransportSnapshot { playing: true, sample_rate: RATE, ..TransportSnapshot::default() };
+        let decoded = AudioPacket::from_bytes(&AudioPacket::new_transport(0, &bare).to_bytes()).unwrap();
+        assert_eq!(decoded.tempo_bpm, 0.0);
+        assert_eq!((decoded.time_sig_numerator, decoded.time_sig_denominator), (0, 0));
+        assert_eq!((decoded.pos_beats, decoded.bar_start_beats), (UNKNOWN_BEATS, UNKNOWN_BEATS));
+        assert_eq!(decoded.pos_samples, UNKNOWN_SAMPLES);
+
+        // Nonsense from the host reads as unknown
+        let odd = TransportSnapshot {
+            tempo: Some(f64::NAN),
+            time_sig: Some((4, 0)),
+            pos_beats: Some(f64::INFINITY),
+            ..bare
+        };
+        let packet = AudioPacket::new_transport(0, &odd);
+        assert_eq!((packet.tempo_bpm, packet.time_sig_numerator, packet.pos_beats), (0.0, 0, UNKNOWN_BEATS));
+    }
+}
@@ -0,0 +1,187 @@
+//! Datagrams to the Suite, for the least latency
+//!
+//! A WebSocket runs over TCP: a lost segment holds up every packet behind
+//! it, and a write can wait out the socket's 100 ms timeout. For a Suite on
+//! the same machine that cares more about how soon a frame arrives than
+//! whether each one does, `"transport": "udp"` in the machine config (see
+//! `config`) sends the packets to the Suite's host and port as UDP datagrams
+//! instead: the bytes the WebSocket would have carried, encoded as
+//! `stream_format` asks and sealed with the packet key if one is set.
+//!
+//! There is no handshake and no connection. The connection reads
+//! `Connected` once the socket is bound, nothing is read back, and nothing
+//! pings; the Suite tells a plugin still there from one that left by the
+//! heartbeats, which go out as on a WebSocket, and by the goodbye. No token
+//! goes with the packets and the instance never shares a connection. A
+//! machine config asking for TLS as well gets a WebSocket: datagrams are
+//! never secured.
+//!
+//! A packet takes one datagram or more, each with a header of
+//! `HEADER_BYTES`, little-endian:
+//!
+//! - u32 `message`: counts the packets of the socket, the same in every
+//!   fragment of one
+//! - u16 `index`: of the fragment, from 0
+//! - u16 `count`: fragments of the packet
+//!
+//! then up to `MAX_FRAGMENT_BYTES` of the packet, so every datagram fits an
+//! Ethernet frame. The Suite has the packet once it has every fragment of
+//! the message; one missing a fragment is lost. A packet needing more than
+//! `u16::MAX` fragments isn't sent and is recorded as
+//! `TransportError::Oversized`.
+
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::discovery::SuiteAddress;
+use crate::error::{ErrorStats, TransportError};
+
+/// Fragment header: message, index and count
+pub const HEADER_BYTES: usize = 8;
+
+/// Largest datagram sent: an Ethernet frame less the IPv6 and UDP headers
+pub const MAX_DATAGRAM_BYTES: usize = 1452;
+
+/// Bytes of the packet in each datagram
+pub const MAX_FRAGMENT_BYTES: usize = MAX_DATAGRAM_BYTES - HEADER_BYTES;
+
+/// How packets get to the Suite
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    #[default]
+    WebSocket,
+    Udp,
+}
+
+/// Fragments a packet of `len` bytes takes; none if more than a header can
+/// count
+pub fn fragment_count(len: usize) -> Option<u16> {
+    // An empty packet still takes a datagram
+    u16::try_from(len.div_ceil(MAX_FRAGMENT_BYTES).max(1)).ok()
+}
+
+/// The datagrams of `data`, the packet numbered `message`; none if it needs
+/// too many
+pub fn fragments(message: u32, data: &[u8]) -> Option<impl Iterator<Item = Vec<u8>> + '_> {
+    let count = fragment_count(data.len())?;
+    let chunks = data.chunks(MAX_FRAGMENT_BYTES).chain(data.is_empty().then_some(&data[..0]));
+    Some(chunks.enumerate().map(move |(index, chunk)| {
+        let mut datagram = Vec::with_capacity(HEADER_BYTES + chunk.len());
+        datagram.extend_from_slice(&message.to_le_bytes());
+        datagram.extend_from_slice(&(index as u16).to_le_bytes());
+        datagram.extend_from_slice(&count.to_le_bytes());
+        datagram.extend_from_slice(chunk);
+        datagram
+    }))
+}
+
+/// A socket sending an instance's packets to the Suite
+pub struct DatagramSocket {
+    socket: UdpSocket,
+    /// Number of the next packet
+    message: u32,
+    /// Where packets too large to send are recorded
+    errors: Arc<ErrorStats>,
+}
+
+impl DatagramSocket {
+    /// Bind a socket for datagrams to `address`
+    pub fn open(address: &SuiteAddress, errors: Arc<ErrorStats>) -> Result<Self, TransportError> {
+        let target = address.resolve()?;
+        let any: SocketAddr = match target {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(any).map_err(TransportError::Bind)?;
+        socket.connect(target).map_err(TransportError::Connect)?;
+        socket.set_write_timeout(Some(Duration::from_millis(100))).ok();
+        Ok(Self { socket, message: 0, errors })
+    }
+
+    /// Send one packet, in as many datagrams as it takes. An error if the
+    /// socket failed; a packet too large is recorded and dropped, and a
+    /// Suite not listening is no failure.
+    pub fn send(&mut self, data: &[u8]) -> Result<(), TransportError> {
+        let message = self.message;
+        self.message = self.message.wrapping_add(1);
+        let Some(fragments) = fragments(message, data) else {
+            self.errors.record(&TransportError::Oversized(data.len()).into());
+            return Ok(());
+        };
+        for datagram in fragments {
+            match self.socket.send(&datagram) {
+                Ok(_) => {}
+                // The port answered unreachable to an earlier datagram
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {}
+                Err(e) => return Err(TransportError::Lost(e.to_string())),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The packets in `datagrams`, in order, as the Suite puts them back
+/// together; ones missing a fragment are left out
+#[cfg(test)]
+pub fn reassemble(datagrams: &[Vec<u8>]) -> Vec<Vec<u8>> {
+    let mut messages = std::collections::BTreeMap::<u32, Vec<Option<Vec<u8>>>>::new();
+    for datagram in datagrams {
+        let message = u32::from_le_bytes(datagram[0..4].try_into().unwrap());
+        let index = u16::from_le_bytes(datagram[4..6].try_into().unwrap()) as usize;
+        let count = u16::from_le_bytes(datagram[6..8].try_into().unwrap()) as usize;
+        messages.entry(message).or_insert_with(|| vec![None; count])[index] = Some(datagram[8..].to_vec());
+    }
+    messages
+        .into_values()
+        .filter_map(|parts| parts.into_iter().collect::<Option<Vec<_>>>().map(|parts| parts.concat()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fragments() {
+        let packet: Vec<u8> = (0..3 * MAX_FRAGMENT_BYTES + 17).map(|i| i as u8).collect();
+        let datagrams: Vec<Vec<u8>> = fragments(7, &packet).unwrap().collect();
+        assert_eq!(datagrams.len(), 4);
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= MAX_DATAGRAM_BYTES));
+        assert_eq!(&datagrams[3][..HEADER_BYTES], &[7, 0, 0, 0, 3, 0, 4, 0]);
+        assert_eq!(reassemble(&datagrams), vec![packet.clone()]);
+
+        // A missing fragment loses the packet, not the ones around it
+        let small: Vec<Vec<u8>> = fragments(8, b"heartbeat").unwrap().collect();
+        assert_eq!(small.len(), 1);
+        let mut lossy = datagrams[1..].to_vec();
+        lossy.extend(small);
+        assert_eq!(reassemble(&lossy), vec![b"heartbeat".to_vec()]);
+
+        assert_eq!(fragments(0, b"").unwrap().collect::<Vec<_>>(), vec![vec![0, 0, 0, 0, 0, 0, 1, 0]]);
+        assert_eq!(fragment_count(u16::MAX as usize * MAX_FRAGMENT_BYTES), Some(u16::MAX));
+        assert_eq!(fragment_count(u16::MAX as usize * MAX_FRAGMENT_BYTES + 1), None);
+    }
+
+    #[test]
+    fn test_datagrams_reach_a_listener() {
+        let listener = UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let address = SuiteAddress::local(listener.local_addr().unwrap().port());
+        let mut socket = DatagramSocket::open(&address, Arc::new(ErrorStats::default())).unwrap();
+        let packet = vec![42; 2 * MAX_FRAGMENT_BYTES];
+        socket.send(&packet).unwrap();
+        socket.send(b"next").unwrap();
+
+        let mut datagrams = Vec::new();
+        let mut buf = [0u8; MAX_DATAGRAM_BYTES];
+        for _ in 0..3 {
+            let n = listener.recv(&mut buf).unwrap();
+            datagrams.push(buf[..n].to_vec());
+        }
+        assert_eq!(reassemble(&datagrams), vec![packet, b"next".to_vec()]);
+    }
+}
@@ -0,0 +1,127 @@
+//! dB conversions shared by every reading the plugin produces, and the
+//! input guard that keeps non-finite samples out of them
+//!
+//! All levels bottom out at `FLOOR_DB`. Silence, negative input and NaN read
+//! as the floor rather than `-inf`/NaN, so packets never carry non-finite
+//! levels. There is no ceiling here: readings above 0 dBFS are the caller's
+//! business to clamp.
+
+/// Lowest level any reading reports, in dB
+pub const FLOOR_DB: f32 = -100.0;
+
+/// Added to amplitudes before taking the log (-200 dB, well below the floor)
+const AMPLITUDE_EPSILON: f32 = 1e-10;
+
+/// Added to powers before taking the log; the square of `AMPLITUDE_EPSILON`
+const POWER_EPSILON: f32 = 1e-20;
+
+/// Replaces NaN/Inf input samples with silence and counts them, so one bad
+/// upstream buffer can't poison the filters and FFT history.
+#[derive(Debug, Default)]
+pub struct SampleGuard {
+    replaced: u32,
+}
+
+impl SampleGuard {
+    pub fn clean(&mut self, sample: f32) -> f32 {
+        if sample.is_finite() {
+            sample
+        } else {
+            self.replaced = self.replaced.saturating_add(1);
+            0.0
+        }
+    }
+
+    /// Samples replaced since the last call
+    pub fn take(&mut self) -> u32 {
+        std::mem::take(&mut self.replaced)
+    }
+}
+
+/// Amplitude (linear, e.g. peak or FFT magnitude) to dB.
+///
+/// Zero, negative and NaN input read as `FLOOR_DB`; `+inf` stays `+inf`.
+pub fn linear_to_db(amplitude: f32) -> f32 {
+    if amplitude.is_nan() || amplitude <= 0.0 {
+        return FLOOR_DB;
+    }
+    (20.0 * (amplitude + AMPLITUDE_EPSILON).log10()).max(FLOOR_DB)
+}
+
+/// Power (amplitude squared, e.g. mean square) to dB.
+///
+/// Zero, negative and NaN input read as `FLOOR_DB`; `+inf` stays `+inf`.
+pub fn power_to_db(power: f32) -> f32 {
+    if power.is_nan() || power <= 0.0 {
+        return FLOOR_DB;
+    }
+    (10.0 * (power + POWER_EPSILON).log10()).max(FLOOR_DB)
+}
+
+/// dB to amplitude.
+///
+/// Anything at or below `FLOOR_DB` (including `-inf`) and NaN read as 0, so
+/// the floor round-trips to silence.
+pub fn db_to_linear(db: f32) -> f32 {
+    if db.is_nan() || db <= FLOOR_DB {
+        return 0.0;
+    }
+    10.0_f32.powf(db / 20.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Log-spaced amplitudes from -100 dB to +20 dB
+    fn sweep() -> impl Iterator<Item = f32> {
+        (0..=1200).map(|i| 10.0_f32.powf((i as f32 / 10.0 - 100.0) / 20.0))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for x in sweep().filter(|&x| x > 1e-4) {
+            let back = db_to_linear(linear_to_db(x));
+            assert!((back - x).abs() <= x * 1e-4, "{} -> {}", x, back);
+
+            let db = linear_to_db(x);
+            assert!((linear_to_db(db_to_linear(db)) - db).abs() < 1e-3, "{} dB", db);
+            assert!((power_to_db(x * x) - db).abs() < 1e-3, "power/amplitude disagree at {}", x);
+        }
+    }
+
+    #[test]
+    fn test_monotonic() {
+        let readings: Vec<f32> = sweep().map(linear_to_db).collect();
+        assert!(readings.windows(2).all(|w| w[0] <= w[1]));
+        let powers: Vec<f32> = sweep().map(|x| power_to_db(x * x)).collect();
+        assert!(powers.windows(2).all(|w| w[0] <= w[1]));
+    }
+
+    #[test]
+    fn test_sample_guard() {
+        let mut guard = SampleGuard::default();
+        let cleaned: Vec<f32> = [0.5, f32::NAN, -0.25, f32::INFINITY, f32::NEG_INFINITY]
+            .into_iter()
+            .map(|x| guard.clean(x))
+            .collect();
+        assert_eq!(cleaned, vec![0.5, 0.0, -0.25, 0.0, 0.0]);
+        assert_eq!(guard.take(), 3);
+        assert_eq!(guard.take(), 0);
+    }
+
+    #[test]
+    fn test_floor_behaviour() {
+        for x in [0.0, -0.0, -1.0, f32::NAN, f32::NEG_INFINITY, 1e-30] {
+            assert_eq!(linear_to_db(x), FLOOR_DB, "linear_to_db({})", x);
+            assert_eq!(power_to_db(x), FLOOR_DB, "power_to_db({})", x);
+        }
+        assert_eq!(linear_to_db(f32::INFINITY), f32::INFINITY);
+
+        assert_eq!(db_to_linear(FLOOR_DB), 0.0);
+        assert_eq!(db_to_linear(f32::NEG_INFINITY), 0.0);
+        assert_eq!(db_to_linear(f32::NAN), 0.0);
+        assert!((db_to_linear(0.0) - 1.0).abs() < 1e-6);
+        assert!((linear_to_db(0.5) - (-6.0206)).abs() < 1e-3);
+    }
+}
@@ -0,0 +1,109 @@
+//! Notices when the host stops calling `process()`
+//!
+//! A frozen track, a suspended plugin or removed routing stops the blocks
+//! while the connection and the editor carry on, so the Suite would keep
+//! showing the last frame as if it were live. The audio thread stamps every
+//! block; the connection thread reads the stamp for each heartbeat and the
+//! editor for its status line. Both use the plugin's monotonic clock, in
+//! milliseconds.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::latency;
+
+/// How long without a block before the data counts as stale
+pub const STALE_AFTER_MS: u64 = 500;
+
+/// Now on the plugin's monotonic clock, in ms
+pub fn now_ms() -> u64 {
+    latency::now_us() / 1000
+}
+
+/// When the host last processed a block, shared by the plugin, the connection
+/// thread and the editor
+#[derive(Debug)]
+pub struct ProcessWatchdog {
+    last_process_ms: AtomicU64,
+}
+
+impl ProcessWatchdog {
+    /// Counts from now, so a host that never processes goes stale too
+    pub fn new() -> Self {
+        Self::starting_at(now_ms())
+    }
+
+    fn starting_at(now_ms: u64) -> Self {
+        Self {
+            last_process_ms: AtomicU64::new(now_ms),
+        }
+    }
+
+    /// Audio thread, once per block. Returns the ms since the previous block.
+    pub fn beat(&self) -> u64 {
+        self.beat_at(now_ms())
+    }
+
+    fn beat_at(&self, now_ms: u64) -> u64 {
+        let last = self.last_process_ms.swap(now_ms, Ordering::Relaxed);
+        now_ms.saturating_sub(last)
+    }
+
+    /// How long the host has gone without processing, or 0 while that is
+    /// under `STALE_AFTER_MS`
+    pub fn stale_ms(&self) -> u32 {
+        self.stale_ms_at(now_ms())
+    }
+
+    fn stale_ms_at(&self, now_ms: u64) -> u32 {
+        let gap = now_ms.saturating_sub(self.last_process_ms.load(Ordering::Relaxed));
+        if gap > STALE_AFTER_MS {
+            gap.min(u32::MAX as u64) as u32
+        } else {
+            0
+        }
+    }
+}
+
+impl Default for ProcessWatchdog {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_staleness_rises_during_a_gap() {
+        let watchdog = ProcessWatchdog::starting_at(1_000);
+        // Blocks every 10 ms
+        for t in (1_010..=2_000).step_by(10) {
+            assert_eq!(watchdog.beat_at(t), 10);
+            assert_eq!(watchdog.stale_ms_at(t + 5), 0);
+        }
+
+        // The host stops
+        assert_eq!(watchdog.stale_ms_at(2_000 + STALE_AFTER_MS), 0);
+        assert_eq!(watchdog.stale_ms_at(2_600), 600);
+        assert_eq!(watchdog.stale_ms_at(5_000), 3_000);
+
+        // And resumes: the first block reports the gap, then all is well
+        assert_eq!(watchdog.beat_at(5_000), 3_000);
+        assert_eq!(watchdog.stale_ms_at(5_010), 0);
+    }
+
+    #[test]
+    fn test_never_processing_goes_stale() {
+        let watchdog = ProcessWatchdog::starting_at(1_000);
+        assert_eq!(watchdog.stale_ms_at(1_400), 0);
+        assert_eq!(watchdog.stale_ms_at(2_000), 1_000);
+    }
+
+    #[test]
+    fn test_clock_going_backwards_is_not_a_gap() {
+        let watchdog = ProcessWatchdog::starting_at(1_000);
+        assert_eq!(watchdog.stale_ms_at(900), 0);
+        assert_eq!(watchdog.beat_at(900), 0);
+    }
+}
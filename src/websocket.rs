@@ -1,267 +1,4259 @@
 //! WebSocket client for streaming audio data to Hardwave Suite
 
-use crossbeam_channel::{bounded, Receiver, Sender, TryRecvError};
+use crossbeam_channel::{Receiver, TryRecvError};
+use nih_plug::prelude::PluginApi;
 use parking_lot::Mutex;
+use serde::Serialize;
 use std::io::{Read, Write};
-use std::net::TcpStream;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+use std::collections::VecDeque;
 use std::sync::Arc;
 use std::thread::{self, JoinHandle};
-use std::time::Duration;
-use tungstenite::protocol::WebSocket;
-use tungstenite::{Message, client::IntoClientRequest, handshake::client::generate_key};
+use std::time::{Duration, Instant};
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::{CloseFrame, WebSocket};
+use tungstenite::{Message, handshake::client::generate_key};
 
-use crate::protocol::AudioPacket;
+use crate::auth::{self, AccountToken, AuthError, StreamAuth, StreamToken};
+use crate::bus::{Overflow, PacketBus, Route, RouteStats, Subscription};
+use crate::clicks::ClickStats;
+use crate::clock::Clock;
+use crate::destinations::{self, Destination};
+use crate::discovery::{self, Discovery, SuiteAddress};
+use crate::display::{self, DisplayHints};
+use crate::error::{ErrorStats, HardwaveError, TransportError};
+use crate::latency::{self, LatencyHistogram};
+#[cfg(any(feature = "gui", test))]
+use crate::latency::LatencyStats;
+#[cfg(all(feature = "gui", any(target_os = "windows", test)))]
+use crate::link_stats::LinkReport;
+use crate::link_stats::LinkStats;
+use crate::memory::{Buffer, MemoryGauges};
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+use crate::memory;
+use crate::metadata::Metadata;
+use crate::metrics::{self, MetricsWindow, UsageCounters, UsageMetrics};
+use crate::multiplex::{self, SharedConnection, SharedStream, StreamInfo};
+use crate::onboarding::Onboarding;
+use crate::packet_crypto::{self, PacketEncryption, PacketSealer};
+use crate::panics::{self, Region};
+use crate::history::{self, HistoryControl};
+use crate::keepalive::{Keepalive, PingTimes, RoundTrip};
+use crate::polarity::{self, StereoWarnings};
+use crate::ports::{self, PortWarning};
+use crate::protocol::{
+    AudioPacket, PACKET_TYPE_CHANNELS, PACKET_TYPE_FFT, PACKET_TYPE_HEARTBEAT, PACKET_TYPE_HELLO, PACKET_TYPE_REFERENCE,
+    PACKET_TYPE_SPECTRUM_RAW, PACKET_TYPE_TRANSPORT, PROTOCOL_VERSION, VERSION_HEADER,
+};
+use crate::recorder::{self, PacketRecorder};
+use crate::remote::{self, RemoteCommand, RemoteControl};
+use crate::resume::{Resume, ResumeState};
+use crate::sequence::PacketSequence;
+use crate::shared::EditorLifecycle;
+use crate::subscriptions::{self, CategorySet, Subscriptions};
+use crate::suite_commands::{self, SuiteCommands};
+use crate::threads::{self, Priority};
+use crate::tls::{self, Stream, TlsControl, TlsPins};
+use crate::udp::{DatagramSocket, Transport};
+use crate::watchdog::ProcessWatchdog;
+use crate::wire_format::{self, WireFormat};
 
 /// Connection state
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum ConnectionState {
+    #[default]
     Disconnected,
+    /// Browsing for the Suite, see `discovery`
+    Discovering,
     Connecting,
     Connected,
     Error,
+    /// The Suite turned the token down, see `auth`
+    Unauthorized,
+}
+
+/// Packets the audio thread can queue for the connection thread: the
+/// newest of each kind in `STATE_KINDS` and the rest events, see
+/// `SuiteSubscription`
+pub const PACKET_QUEUE_LEN: usize = 32;
+
+/// Kinds of packet only the newest of which matters, the `coalesce_key`s:
+/// frames first
+const STATE_KINDS: [u8; 7] = [
+    PACKET_TYPE_FFT,
+    PACKET_TYPE_HEARTBEAT,
+    PACKET_TYPE_HELLO,
+    PACKET_TYPE_TRANSPORT,
+    PACKET_TYPE_SPECTRUM_RAW,
+    PACKET_TYPE_REFERENCE,
+    PACKET_TYPE_CHANNELS,
+];
+
+/// Events that can wait for the connection thread: the room the state
+/// kinds' slots leave them
+const EVENT_QUEUE_LEN: usize = PACKET_QUEUE_LEN - STATE_KINDS.len();
+
+/// Number of state transitions kept for diagnostics
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+pub const STATE_HISTORY_LEN: usize = 50;
+
+/// A recorded change of connection state
+#[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+#[derive(Debug, Clone)]
+pub struct StateTransition {
+    pub from: ConnectionState,
+    pub to: ConnectionState,
+    pub at: Instant,
+    pub reason: &'static str,
+}
+
+/// The connection to the Suite as the editor reports it
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ConnectionStatus {
+    pub connection: ConnectionState,
+    /// `host:port` of the Suite it is connected to or last tried; `None`
+    /// before the first attempt
+    pub address: Option<String>,
+    /// Why the last connection failed or ended, see `link_stats`
+    pub last_error: Option<String>,
+    pub latency: LatencyStats,
+    /// The latest ping's round trip, see `keepalive`
+    pub round_trip_us: Option<u32>,
+    /// Why the Port parameter may not work, see `ports`
+    pub port_warning: Option<String>,
+}
+
+/// Read-only view of a client's connection state and send latency, for the
+/// editor
+#[cfg(any(feature = "gui", test))]
+#[derive(Clone)]
+pub struct ConnectionStats {
+    state: Arc<Mutex<StateMachine>>,
+    latency: Arc<Mutex<LatencyHistogram>>,
+    round_trip: Arc<RoundTrip>,
+    link: Arc<LinkStats>,
+    address: Arc<Mutex<Option<SuiteAddress>>>,
+    port_warning: Arc<Mutex<Option<PortWarning>>>,
+}
+
+#[cfg(any(feature = "gui", test))]
+impl ConnectionStats {
+    pub fn status(&self) -> ConnectionStatus {
+        ConnectionStatus {
+            connection: self.state.lock().current,
+            address: self.address.lock().as_ref().map(ToString::to_string),
+            last_error: self.link.last_error(),
+            latency: self.latency.lock().stats(),
+            round_trip_us: self.round_trip.get(),
+            port_warning: self.port_warning.lock().map(|warning| warning.to_string()),
+        }
+    }
+
+    /// What the connection sent and lost, see `link_stats`
+    #[cfg(all(feature = "gui", any(target_os = "windows", test)))]
+    pub fn link(&self) -> LinkReport {
+        self.link.report()
+    }
+
+    /// Recent state transitions, oldest first
+    #[cfg(all(feature = "gui", any(target_os = "windows", test)))]
+    pub fn history(&self) -> Vec<StateTransition> {
+        self.state.lock().history.iter().cloned().collect()
+    }
+}
+
+/// An extra destination and its connection, see `destinations`
+#[cfg(any(feature = "gui", test))]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct DestinationStatus {
+    #[serde(flatten)]
+    pub destination: Destination,
+    /// `Disconnected` while it is off
+    pub connection: ConnectionState,
+}
+
+/// Connection state plus a bounded history of how it got there.
+///
+/// Only the transitions in `is_allowed` can be recorded, so e.g. a live
+/// connection can never read `Connecting` without first passing through
+/// `Disconnected`. Asking for another is a bug in the connection thread: it
+/// is counted, and stops debug builds.
+struct StateMachine {
+    current: ConnectionState,
+    /// Only the Windows packet server's `/status` and tests look back
+    #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+    history: VecDeque<StateTransition>,
+    /// Transitions recorded and rejected since the client was made, for
+    /// heartbeats
+    recorded: u32,
+    rejected: u32,
+}
+
+impl StateMachine {
+    fn new() -> Self {
+        Self {
+            current: ConnectionState::Disconnected,
+            #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+            history: VecDeque::with_capacity(STATE_HISTORY_LEN),
+            recorded: 0,
+            rejected: 0,
+        }
+    }
+
+    fn is_allowed(from: ConnectionState, to: ConnectionState) -> bool {
+        use ConnectionState::*;
+        matches!(
+            (from, to),
+            (Disconnected, Connecting)
+                | (Disconnected, Discovering)
+                | (Error, Discovering)
+                | (Unauthorized, Discovering)
+                | (Discovering, Connecting)
+                | (Discovering, Disconnected)
+                | (Error, Connecting)
+                | (Connecting, Connected)
+                | (Connecting, Disconnected)
+                | (Connecting, Error)
+                | (Connecting, Unauthorized)
+                | (Unauthorized, Connecting)
+                | (Connected, Disconnected)
+                | (Connected, Error)
+                // Shutting down, or the thread restarting, after a refusal
+                | (Error, Disconnected)
+                | (Unauthorized, Disconnected)
+        )
+    }
+
+    /// Move to `to`. Staying in the current state is a no-op.
+    fn transition(&mut self, to: ConnectionState, reason: &'static str) {
+        let from = self.current;
+        let allowed = self.try_transition(to, reason);
+        debug_assert!(allowed, "{:?} -> {:?} ({}) is not a connection state transition", from, to, reason);
+    }
+
+    /// `transition`, returning false and counting it rather than stopping
+    /// when `to` isn't allowed from here
+    fn try_transition(&mut self, to: ConnectionState, reason: &'static str) -> bool {
+        if self.current == to {
+            return true;
+        }
+        if !Self::is_allowed(self.current, to) {
+            crate::runtime::debug_log(&format!(
+                "[websocket] {:?} -> {:?} ({}) is not a connection state transition",
+                self.current, to, reason
+            ));
+            self.rejected = self.rejected.saturating_add(1);
+            return false;
+        }
+
+        #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+        {
+            if self.history.len() == STATE_HISTORY_LEN {
+                self.history.pop_front();
+            }
+            self.history.push_back(StateTransition {
+                from: self.current,
+                to,
+                at: Instant::now(),
+                reason,
+            });
+        }
+        self.current = to;
+        self.recorded = self.recorded.saturating_add(1);
+        true
+    }
+}
+
+/// Heartbeat interval while streaming
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Heartbeat interval while streaming is suspended (offline render)
+pub const SUSPENDED_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How long a closing socket waits for the Suite's close frame
+pub const CLOSE_TIMEOUT: Duration = Duration::from_millis(250);
+
+/// How long dropping the client waits for the connection thread to say
+/// goodbye; a thread still connecting then is left to finish on its own,
+/// so a host quitting never hangs on a Suite that isn't answering
+pub const DROP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// What the instance is doing between frames, kept current by the audio
+/// thread for heartbeats to say
+#[derive(Debug)]
+struct Session {
+    /// The Enabled parameter
+    enabled: AtomicBool,
+    /// Timestamp of the last block the host processed, on the frames' clock
+    timestamp_ms: AtomicU64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            enabled: AtomicBool::new(true),
+            timestamp_ms: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Settings the plugin changes at runtime, read by the connection thread.
+/// Each concern's are kept together; a clone of one is what a connection
+/// to another destination starts with, see `for_destination`.
+struct Controls {
+    auth: AuthControls,
+    encryption: EncryptionControls,
+    transport: TransportControls,
+    telemetry: TelemetryControls,
+    suite: SuiteControls,
+}
+
+/// The tokens handshakes carry, see `auth`
+struct AuthControls {
+    /// The account token handshakes carry, and the editor saving or
+    /// clearing it; shared with the editor
+    account_token: Arc<AccountToken>,
+
+    /// Stream token from the Suite; only touched by the connection thread
+    stream_auth: Mutex<StreamAuth>,
+}
+
+/// The same account token, exchanged for stream tokens of its own
+impl Clone for AuthControls {
+    fn clone(&self) -> Self {
+        Self {
+            account_token: Arc::clone(&self.account_token),
+            stream_auth: Mutex::new(StreamAuth::new(Arc::clone(&self.account_token))),
+        }
+    }
+}
+
+/// What packets and connections are secured with, see `packet_crypto` and
+/// `tls`
+struct EncryptionControls {
+    /// Pre-shared key packets are encrypted with; shared with the editor
+    key: Arc<PacketEncryption>,
+
+    /// Seals the packets of the current connection; only touched by the
+    /// connection thread
+    sealer: Mutex<PacketSealer>,
+
+    /// Whether connections go over TLS, and the certificates pinned;
+    /// shared with the editor
+    tls: Arc<TlsControl>,
+}
+
+/// The same key and pins, with a sealer of its own
+impl Clone for EncryptionControls {
+    fn clone(&self) -> Self {
+        Self {
+            key: Arc::clone(&self.key),
+            sealer: Mutex::new(PacketSealer::new(None, 0)),
+            tls: Arc::clone(&self.tls),
+        }
+    }
+}
+
+/// Where packets go and how
+struct TransportControls {
+    /// Current server port
+    port: Mutex<u16>,
+
+    /// How the connection finds the Suite, from the machine config
+    discovery: Mutex<Discovery>,
+
+    /// Whether packets go over a WebSocket or as datagrams, from the
+    /// machine config
+    kind: Mutex<Transport>,
+
+    /// Encoding the machine config asks for on the packet stream
+    stream_format: Mutex<WireFormat>,
+
+    /// This instance's stream ID on a shared connection
+    stream: u32,
+
+    /// The connection shared by the instances of the process, if this one
+    /// streams over it
+    shared: Mutex<Option<Arc<SharedConnection>>>,
+
+    /// How often a connection of its own pings the Suite
+    ping_times: Mutex<PingTimes>,
+
+    /// The current connection's latest round trip; shared with the editor
+    round_trip: Arc<RoundTrip>,
+
+    /// Whether to send periodic timing packets
+    timing_packets: AtomicBool,
+
+    /// Streaming suspended: hold the connection but stay quiet
+    suspended: AtomicBool,
+
+    /// What the current connection's Suite subscribed to
+    subscriptions: Subscriptions,
+
+    /// Sleeps the connection thread noticed
+    resume: ResumeState,
+
+    /// Where the Suite was last looked for; shared with the editor
+    address: Arc<Mutex<Option<SuiteAddress>>>,
+
+    /// What is wrong with the port, checked by the connection thread before
+    /// every attempt; shared with the editor
+    port_warning: Arc<Mutex<Option<PortWarning>>>,
+
+    /// Where the plugin publishes packets for the connection thread, the
+    /// editor and any other consumer
+    bus: Arc<PacketBus>,
+
+    /// Numbers every packet; shared with the plugin, which resets it
+    sequence: Arc<PacketSequence>,
+}
+
+/// The same packets and settings as they are now, under a stream ID of its
+/// own; what the connection learns of its Suite starts over
+impl Clone for TransportControls {
+    fn clone(&self) -> Self {
+        Self {
+            port: Mutex::new(*self.port.lock()),
+            discovery: Mutex::new(self.discovery.lock().clone()),
+            kind: Mutex::new(*self.kind.lock()),
+            stream_format: Mutex::new(*self.stream_format.lock()),
+            stream: multiplex::next_stream_id(),
+            shared: Mutex::new(None),
+            ping_times: Mutex::new(*self.ping_times.lock()),
+            round_trip: Arc::new(RoundTrip::default()),
+            timing_packets: AtomicBool::new(self.timing_packets.load(Ordering::Relaxed)),
+            suspended: AtomicBool::new(self.suspended.load(Ordering::Relaxed)),
+            subscriptions: Subscriptions::default(),
+            resume: ResumeState::default(),
+            address: Arc::new(Mutex::new(None)),
+            port_warning: Arc::new(Mutex::new(None)),
+            bus: Arc::clone(&self.bus),
+            sequence: Arc::clone(&self.sequence),
+        }
+    }
+}
+
+/// What the connection counts and reports, in heartbeats and to the editor
+struct TelemetryControls {
+    /// What heartbeats, timing reports and metrics are timed by, see `clock`
+    clock: Clock,
+
+    /// For heartbeats, with the sample rate in `metadata`; the same for
+    /// every destination
+    session: Arc<Session>,
+
+    /// What the instance analyses with, for the hello packet; set by the
+    /// plugin every block
+    metadata: Mutex<Metadata>,
+
+    /// Attach usage metrics to one heartbeat per minute
+    metrics_opt_in: AtomicBool,
+
+    /// `metrics::plugin_api_id` of the host's plugin format
+    plugin_api: AtomicU8,
+
+    /// Counts behind the usage metrics
+    usage: UsageCounters,
+
+    /// Only touched by the connection thread
+    metrics_window: Mutex<MetricsWindow>,
+
+    /// What the connection sent and lost; shared with the editor
+    link: Arc<LinkStats>,
+
+    /// Per-buffer memory use of this instance; shared with the plugin and
+    /// the editor
+    memory: Arc<MemoryGauges>,
+
+    /// When the host last processed a block; stamped by the plugin, shared
+    /// with the editor
+    watchdog: Arc<ProcessWatchdog>,
+
+    /// Errors nothing could be done about; shared with the plugin and the
+    /// editor
+    errors: Arc<ErrorStats>,
+
+    /// Clicks in the input; recorded by the plugin, shared with the editor
+    clicks: Arc<ClickStats>,
+
+    /// Record outgoing packets to disk; shared with the editor
+    recording: Arc<AtomicBool>,
+
+    /// Only touched by the connection thread
+    recorder: Mutex<PacketRecorder>,
+}
+
+/// The same session, watchdog and errors, with counts, gauges and a
+/// recorder of its own
+impl Clone for TelemetryControls {
+    fn clone(&self) -> Self {
+        Self {
+            clock: self.clock.clone(),
+            session: Arc::clone(&self.session),
+            metadata: Mutex::new(*self.metadata.lock()),
+            metrics_opt_in: AtomicBool::new(self.metrics_opt_in.load(Ordering::Relaxed)),
+            plugin_api: AtomicU8::new(self.plugin_api.load(Ordering::Relaxed)),
+            usage: UsageCounters::default(),
+            metrics_window: Mutex::new(MetricsWindow::new(self.clock.now(), Default::default())),
+            link: Arc::new(LinkStats::default()),
+            // Or it would overwrite the instance's gauges
+            memory: Arc::new(MemoryGauges::default()),
+            watchdog: Arc::clone(&self.watchdog),
+            errors: Arc::clone(&self.errors),
+            // Heartbeats take the clicks since the last one, so only the
+            // Port parameter's Suite hears of them
+            clicks: Arc::new(ClickStats::default()),
+            // Recordings are of what the one Suite was sent
+            recording: Arc::new(AtomicBool::new(false)),
+            recorder: Mutex::new(PacketRecorder::new(recorder::default_dir(), recorder::DEFAULT_MAX_FILE_BYTES)),
+        }
+    }
+}
+
+/// What the Suite and the plugin window ask of the instance, and it of them
+struct SuiteControls {
+    /// Colour and order the Suite shows this instance with; shared with the
+    /// editor
+    display: Arc<Mutex<DisplayHints>>,
+
+    /// Polarity and delayed-copy warnings; set by the plugin, dismissed by
+    /// the Suite
+    stereo: Arc<StereoWarnings>,
+
+    /// Freeze and scrub requests for the frame history, from the Suite and
+    /// the editor; taken by the plugin
+    history: Arc<HistoryControl>,
+
+    /// The update rate, metadata requests and names the Suite sent; taken
+    /// by the plugin and the connection thread
+    remote: Arc<RemoteControl>,
+
+    /// Commands from the plugin window and the Suite's answers; shared with
+    /// the editor
+    suite_commands: Arc<SuiteCommands>,
+
+    /// The first-run flag, and the editor's requests to retry at once
+    onboarding: Arc<Onboarding>,
+}
+
+/// The same instance and warnings; the plugin and the editor only take
+/// requests from the Port parameter's Suite
+impl Clone for SuiteControls {
+    fn clone(&self) -> Self {
+        Self {
+            display: Arc::clone(&self.display),
+            stereo: Arc::clone(&self.stereo),
+            history: Arc::new(HistoryControl::default()),
+            remote: Arc::new(RemoteControl::default()),
+            suite_commands: Arc::new(SuiteCommands::default()),
+            // Only the Port parameter's Suite ends the first run
+            onboarding: Arc::new(Onboarding::at(None)),
+        }
+    }
+}
+
+impl Controls {
+    /// Write `data` to the recording if enabled, opening or closing the file
+    /// when the switch has changed. A recording that fails switches itself off.
+    fn record(&self, data: &[u8]) {
+        let mut recorder = self.telemetry.recorder.lock();
+        let enabled = self.telemetry.recording.load(Ordering::Relaxed);
+        let result = if enabled == recorder.is_enabled() {
+            Ok(())
+        } else {
+            // Into this instance's files, under the tag its packets carry
+            if enabled {
+                recorder.set_instance(display::instance_tag(&self.suite.display.lock().instance_id));
+            }
+            recorder.set_enabled(enabled)
+        }
+        .and_then(|_| recorder.record(latency::now_us(), data));
+        if result.is_err() {
+            let _ = recorder.set_enabled(false);
+            self.telemetry.recording.store(false, Ordering::Relaxed);
+        }
+        self.telemetry.memory.set(Buffer::Recorder, recorder.buffer_bytes());
+    }
+
+    /// How the Suite is told of this instance on a shared connection
+    fn stream_info(&self) -> StreamInfo {
+        let display = self.suite.display.lock();
+        StreamInfo {
+            stream: self.transport.stream,
+            instance_id: display.instance_id.clone(),
+            label: display.label.clone(),
+        }
+    }
+
+    /// The controls of a connection to `destination`, see `destinations`.
+    /// It streams what this one's plugin publishes, under the same instance,
+    /// key and pins, with settings the plugin changes starting as they are
+    /// now; what the connection keeps of its own Suite, and everything the
+    /// plugin and the editor only take from the Port parameter's, is its
+    /// own.
+    fn for_destination(&self, destination: &Destination) -> Controls {
+        Controls {
+            auth: self.auth.clone(),
+            encryption: self.encryption.clone(),
+            transport: TransportControls {
+                port: Mutex::new(destination.port),
+                discovery: Mutex::new(Discovery::Host(destination.host.clone())),
+                ..self.transport.clone()
+            },
+            telemetry: self.telemetry.clone(),
+            suite: self.suite.clone(),
+        }
+    }
+
+    /// The metrics blob for the heartbeat about to be sent, if one is due
+    fn usage_metrics(&self, latency: &Mutex<LatencyHistogram>) -> Option<UsageMetrics> {
+        let opted_in = self.telemetry.metrics_opt_in.load(Ordering::Relaxed);
+        let (delta, elapsed) = self
+            .telemetry.metrics_window
+            .lock()
+            .poll(self.telemetry.clock.now(), opted_in, self.telemetry.usage.totals())?;
+        Some(UsageMetrics::new(
+            delta,
+            elapsed,
+            self.telemetry.plugin_api.load(Ordering::Relaxed),
+            latency.lock().stats().p95_us,
+        ))
+    }
+}
+
+/// Connections to the extra destinations, see `destinations`; shared with
+/// the editor, which changes them
+pub struct Destinations {
+    /// The instance's, which each connection's are made from
+    controls: Arc<Controls>,
+    editor: Arc<EditorLifecycle>,
+    links: Mutex<DestinationLinks>,
+}
+
+struct DestinationLinks {
+    listed: Vec<Destination>,
+    /// Whether the client started, so connections may too
+    started: bool,
+    /// One per enabled destination, once started
+    running: Vec<DestinationLink>,
+}
+
+/// A connection thread streaming to one destination
+struct DestinationLink {
+    destination: Destination,
+    /// For `Destinations::statuses`
+    #[cfg(any(feature = "gui", test))]
+    state: Arc<Mutex<StateMachine>>,
+    shutdown: Arc<AtomicBool>,
+    controls: Arc<Controls>,
+    thread: JoinHandle<()>,
+}
+
+impl Destinations {
+    fn new(controls: Arc<Controls>, editor: Arc<EditorLifecycle>) -> Self {
+        Self {
+            controls,
+            editor,
+            links: Mutex::new(DestinationLinks { listed: Vec::new(), started: false, running: Vec::new() }),
+        }
+    }
+
+    /// Stream to `listed`, cleaned, from now on. A destination listed and
+    /// enabled before keeps its connection; the connections of the others
+    /// say goodbye and end, waiting up to `DROP_TIMEOUT` for them.
+    pub fn set(&self, listed: &[Destination]) {
+        let listed = destinations::clean(listed);
+        let mut links = self.links.lock();
+        let (kept, stopped): (Vec<_>, Vec<_>) = links.running.drain(..).partition(|link| listed.contains(&link.destination));
+        stop(stopped);
+        links.running = kept;
+        links.listed = listed;
+        if links.started {
+            self.connect(&mut links);
+        }
+    }
+
+    /// Each listed destination and its connection state
+    #[cfg(any(feature = "gui", test))]
+    pub fn statuses(&self) -> Vec<DestinationStatus> {
+        let links = self.links.lock();
+        links
+            .listed
+            .iter()
+            .map(|destination| DestinationStatus {
+                destination: destination.clone(),
+                connection: links
+                    .running
+                    .iter()
+                    .find(|link| link.destination == *destination)
+                    .map_or(ConnectionState::Disconnected, |link| link.state.lock().current),
+            })
+            .collect()
+    }
+
+    /// Connect to the enabled destinations, now and when they change
+    fn start(&self) {
+        let mut links = self.links.lock();
+        links.started = true;
+        self.connect(&mut links);
+    }
+
+    /// Start a connection to each enabled destination without one
+    fn connect(&self, links: &mut DestinationLinks) {
+        for destination in links.listed.iter().filter(|destination| destination.enabled) {
+            if links.running.iter().any(|link| link.destination == *destination) {
+                continue;
+            }
+            match DestinationLink::start(destination, &self.controls, &self.editor) {
+                Ok(link) => links.running.push(link),
+                Err(e) => self.controls.telemetry.errors.record(&e.into()),
+            }
+        }
+    }
+
+    /// Apply `f` to each connection's controls, for a setting the plugin
+    /// changed. The audio thread never waits: while the editor changes the
+    /// destinations it waits for the next call, which for the settings set
+    /// every block is the next block.
+    fn each(&self, f: impl Fn(&Controls)) {
+        if let Some(links) = self.links.try_lock() {
+            links.running.iter().for_each(|link| f(&link.controls));
+        }
+    }
+
+    /// Tell every connection to end, for dropping the client to wait for
+    /// them with its own
+    fn shut_down(&self) -> Vec<DestinationLink> {
+        let mut links = self.links.lock();
+        links.started = false;
+        let stopping: Vec<DestinationLink> = links.running.drain(..).collect();
+        stopping.iter().for_each(DestinationLink::shut_down);
+        stopping
+    }
+}
+
+impl DestinationLink {
+    fn start(destination: &Destination, primary: &Controls, editor: &Arc<EditorLifecycle>) -> Result<Self, TransportError> {
+        let controls = Arc::new(primary.for_destination(destination));
+        let subscription = SuiteSubscription::new("destination", "destination frame", &controls);
+        let state = Arc::new(Mutex::new(StateMachine::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let latency = Arc::new(Mutex::new(LatencyHistogram::new()));
+        let thread = spawn_connection("hardwave-ws-dest", subscription, &state, &shutdown, &controls, editor, &latency)?;
+        Ok(Self {
+            destination: destination.clone(),
+            #[cfg(any(feature = "gui", test))]
+            state,
+            shutdown,
+            controls,
+            thread,
+        })
+    }
+
+    /// Tell the thread to say goodbye and end, out of a reconnect wait at
+    /// once
+    fn shut_down(&self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        self.controls.suite.onboarding.request_retry();
+    }
+}
+
+/// Stop `links` at once, and wait up to `DROP_TIMEOUT` for them all
+fn stop(links: Vec<DestinationLink>) {
+    links.iter().for_each(DestinationLink::shut_down);
+    let deadline = Instant::now() + DROP_TIMEOUT;
+    for link in links {
+        join_by(link.thread, deadline);
+    }
+}
+
+/// Join `handle` if its thread ends by `deadline`; otherwise it is
+/// detached
+fn join_by(handle: JoinHandle<()>, deadline: Instant) {
+    while !handle.is_finished() && Instant::now() < deadline {
+        thread::sleep(Duration::from_millis(1));
+    }
+    if handle.is_finished() {
+        let _ = handle.join();
+    }
+}
+
+/// A connection thread's place on the bus, taking whatever isn't
+/// published while suspended. Each kind of packet in `STATE_KINDS`
+/// overwrites a slot of its own, so a connection that fell behind sends the
+/// newest frame, heartbeat, metadata or transport once it catches up rather
+/// than the ones that went stale while it was stuck. Events wait their turn
+/// in a queue of their own that none of those can fill, for the Suite needs
+/// every one; only more of them than `EVENT_QUEUE_LEN` at once are dropped,
+/// and the connection thread logs how many. Frames count towards the drop
+/// rate as they are queued or overwritten, and every packet dropped in the
+/// link's stats. Dropping it leaves the bus.
+struct SuiteSubscription {
+    events: Subscription<AudioPacket>,
+    /// One slot per kind, in `STATE_KINDS`' order
+    latest: Vec<Subscription<AudioPacket>>,
+    /// Events dropped and not yet logged
+    events_dropped: Arc<AtomicU64>,
+}
+
+impl SuiteSubscription {
+    /// Subscribe as `name`, the slots as `latest_name`
+    fn new(name: &'static str, latest_name: &'static str, controls: &Arc<Controls>) -> Self {
+        // What each queue holds, for the memory gauge to add up
+        let queued: Arc<[AtomicUsize]> = (0..=STATE_KINDS.len()).map(|_| AtomicUsize::new(0)).collect();
+        let events_dropped = Arc::new(AtomicU64::new(0));
+        let route = |name, kind| suite_route(name, kind, controls, &queued, &events_dropped);
+        Self {
+            events: controls.transport.bus.subscribe(route(name, None)),
+            latest: STATE_KINDS.iter().map(|&kind| controls.transport.bus.subscribe(route(latest_name, Some(kind)))).collect(),
+            events_dropped: Arc::clone(&events_dropped),
+        }
+    }
+
+    /// Whether the connection thread still takes packets; every queue goes
+    /// together
+    fn stats(&self) -> Arc<RouteStats> {
+        self.events.stats()
+    }
+
+    fn queues(&self) -> SuiteQueues {
+        SuiteQueues {
+            events: self.events.receiver().clone(),
+            latest: self.latest.iter().map(|slot| slot.receiver().clone()).collect(),
+            events_dropped: Arc::clone(&self.events_dropped),
+        }
+    }
+}
+
+/// The slot of a `SuiteSubscription` for packets whose `coalesce_key` is
+/// `kind`, or with None its event queue, which counts what it drops in
+/// `events_dropped`. `queued` holds the bytes in each, the event queue's
+/// first.
+fn suite_route(
+    name: &'static str,
+    kind: Option<u8>,
+    controls: &Arc<Controls>,
+    queued: &Arc<[AtomicUsize]>,
+    events_dropped: &Arc<AtomicU64>,
+) -> Route<AudioPacket> {
+    let (route, index) = match kind {
+        Some(kind) => (
+            Route::new(name, 1, Overflow::DropOldest),
+            1 + STATE_KINDS.iter().position(|&state| state == kind).unwrap_or_default(),
+        ),
+        None => (Route::new(name, EVENT_QUEUE_LEN, Overflow::DropNewest), 0),
+    };
+    let accepting = Arc::clone(controls);
+    let observing = Arc::clone(controls);
+    let queued = Arc::clone(queued);
+    let events_dropped = Arc::clone(events_dropped);
+    route
+        .accepting(move |packet: &AudioPacket| {
+            !accepting.transport.suspended.load(Ordering::Relaxed) && packet.coalesce_key() == kind
+        })
+        .observed(move |packet: &AudioPacket, delivery| {
+            // Queued packets are the same shape, so this one stands for
+            // them all
+            queued[index].store(delivery.queued * packet.heap_bytes(), Ordering::Relaxed);
+            let bytes = queued.iter().map(|bytes| bytes.load(Ordering::Relaxed)).sum();
+            observing.telemetry.memory.set(Buffer::PacketQueue, bytes);
+            if delivery.dropped {
+                observing.telemetry.link.dropped();
+                if kind.is_none() {
+                    events_dropped.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            if packet.packet_type == PACKET_TYPE_FFT {
+                observing.telemetry.usage.frame_produced(delivery.dropped);
+            }
+        })
+}
+
+/// What a connection thread reads its `SuiteSubscription` with
+struct SuiteQueues {
+    events: Receiver<Arc<AudioPacket>>,
+    latest: Vec<Receiver<Arc<AudioPacket>>>,
+    events_dropped: Arc<AtomicU64>,
+}
+
+impl SuiteQueues {
+    /// Move everything queued to `pending`: the events in order, then the
+    /// newest of each state kind, the latest frame last. `Empty` if there
+    /// was nothing, `Disconnected` once the subscription or the bus is
+    /// gone. Events dropped since the last call are logged.
+    fn take(&self, pending: &mut Vec<Arc<AudioPacket>>) -> Result<(), TryRecvError> {
+        let dropped = self.events_dropped.swap(0, Ordering::Relaxed);
+        if dropped > 0 {
+            crate::runtime::debug_log(&format!("[websocket] {} events dropped, the Suite fell behind", dropped));
+        }
+        let disconnected = match self.events.try_recv() {
+            Ok(event) => {
+                pending.push(event);
+                pending.extend(self.events.try_iter());
+                false
+            }
+            Err(e) => e == TryRecvError::Disconnected,
+        };
+        // Frames, first of the kinds, go out last
+        for slot in self.latest.iter().skip(1).chain(self.latest.first()) {
+            if let Ok(packet) = slot.try_recv() {
+                pending.push(packet);
+            }
+        }
+        match pending.is_empty() {
+            true if disconnected => Err(TryRecvError::Disconnected),
+            true => Err(TryRecvError::Empty),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Start a connection thread named `name` reading `subscription`. Joined
+/// by whoever holds the handle. After a panic the loop starts over from
+/// Disconnected; the socket went with the unwinding.
+fn spawn_connection(
+    name: &str,
+    subscription: SuiteSubscription,
+    state: &Arc<Mutex<StateMachine>>,
+    shutdown: &Arc<AtomicBool>,
+    controls: &Arc<Controls>,
+    editor: &Arc<EditorLifecycle>,
+    latency: &Arc<Mutex<LatencyHistogram>>,
+) -> Result<JoinHandle<()>, TransportError> {
+    let state = Arc::clone(state);
+    let shutdown = Arc::clone(shutdown);
+    let controls = Arc::clone(controls);
+    let editor = Arc::clone(editor);
+    let latency = Arc::clone(latency);
+    // Held by the thread, so the bus stops queueing once it ends
+    threads::spawn_restarting(name, Priority::Streaming, Region::Connection, move || {
+        state.lock().transition(ConnectionState::Disconnected, "connection thread restarted");
+        WebSocketClient::connection_loop(
+            subscription.queues(),
+            Arc::clone(&state),
+            Arc::clone(&shutdown),
+            Arc::clone(&controls),
+            Arc::clone(&editor),
+            Arc::clone(&latency),
+        );
+    })
+    .map_err(TransportError::Spawn)
 }
 
 /// WebSocket client that runs in a background thread
 pub struct WebSocketClient {
-    /// Sender for audio packets
-    packet_sender: Sender<AudioPacket>,
+    /// The connection thread's subscription to the bus, once started
+    suite_route: Option<Arc<RouteStats>>,
+
+    /// Current connection state and transition history
+    state: Arc<Mutex<StateMachine>>,
+
+    /// Flag to signal shutdown
+    shutdown: Arc<AtomicBool>,
+
+    /// Background thread handle
+    thread_handle: Option<JoinHandle<()>>,
+
+    /// Runtime settings, recorder and usage counters
+    controls: Arc<Controls>,
+
+    /// Plugin window open/closed state, reported to the Suite
+    editor: Arc<EditorLifecycle>,
+
+    /// Audio thread → TCP stack latency of sent frames
+    latency: Arc<Mutex<LatencyHistogram>>,
+
+    /// Connections to the extra destinations
+    destinations: Arc<Destinations>,
+}
+
+impl WebSocketClient {
+    /// Create a new WebSocket client. Does NOT start the connection thread yet —
+    /// call `start()` after the plugin is initialised to avoid blocking DAW
+    /// plugin scans.
+    pub fn new() -> Self {
+        Self::with_clock(Clock::system())
+    }
+
+    /// A client whose heartbeats and reports go by `clock`
+    pub fn with_clock(clock: Clock) -> Self {
+        let state = Arc::new(Mutex::new(StateMachine::new()));
+        let shutdown = Arc::new(AtomicBool::new(false));
+        // Nor send the user's own token
+        let account_token = Arc::new(if cfg!(test) { AccountToken::at(Vec::new()) } else { AccountToken::new() });
+        let controls = Arc::new(Controls {
+            auth: AuthControls {
+                stream_auth: Mutex::new(StreamAuth::new(Arc::clone(&account_token))),
+                account_token,
+            },
+            encryption: EncryptionControls {
+                key: Arc::new(PacketEncryption::default()),
+                sealer: Mutex::new(PacketSealer::new(None, 0)),
+                tls: Arc::new(TlsControl::default()),
+            },
+            transport: TransportControls {
+                port: Mutex::new(9847u16),
+                discovery: Mutex::new(Discovery::default()),
+                kind: Mutex::new(Transport::default()),
+                stream_format: Mutex::new(WireFormat::default()),
+                stream: multiplex::next_stream_id(),
+                shared: Mutex::new(None),
+                ping_times: Mutex::new(PingTimes::default()),
+                round_trip: Arc::new(RoundTrip::default()),
+                timing_packets: AtomicBool::new(false),
+                suspended: AtomicBool::new(false),
+                subscriptions: Subscriptions::default(),
+                resume: ResumeState::default(),
+                address: Arc::new(Mutex::new(None)),
+                port_warning: Arc::new(Mutex::new(None)),
+                bus: Arc::new(PacketBus::new()),
+                sequence: Arc::new(PacketSequence::default()),
+            },
+            telemetry: TelemetryControls {
+                session: Arc::new(Session::default()),
+                metadata: Mutex::new(Metadata::default()),
+                metrics_opt_in: AtomicBool::new(false),
+                plugin_api: AtomicU8::new(0),
+                usage: UsageCounters::default(),
+                metrics_window: Mutex::new(MetricsWindow::new(clock.now(), Default::default())),
+                clock,
+                link: Arc::new(LinkStats::default()),
+                memory: Arc::new(MemoryGauges::default()),
+                watchdog: Arc::new(ProcessWatchdog::new()),
+                errors: Arc::new(ErrorStats::default()),
+                clicks: Arc::new(ClickStats::default()),
+                recording: Arc::new(AtomicBool::new(false)),
+                recorder: Mutex::new(PacketRecorder::new(
+                    recorder::default_dir(),
+                    recorder::DEFAULT_MAX_FILE_BYTES,
+                )),
+            },
+            suite: SuiteControls {
+                display: Arc::new(Mutex::new(DisplayHints::default())),
+                stereo: Arc::new(StereoWarnings::default()),
+                history: Arc::new(HistoryControl::default()),
+                remote: Arc::new(RemoteControl::default()),
+                suite_commands: Arc::new(SuiteCommands::default()),
+                // Tests never mark the user's own machine onboarded
+                onboarding: Arc::new(if cfg!(test) { Onboarding::at(None) } else { Onboarding::machine() }),
+            },
+        });
+
+        let latency = LatencyHistogram::new();
+        // Both are allocated up front and never grow
+        #[cfg(any(all(feature = "gui", target_os = "windows"), test))]
+        controls
+            .telemetry.memory
+            .set(Buffer::StateHistory, memory::deque_bytes(&state.lock().history));
+        controls.telemetry.memory.set(Buffer::LatencyHistogram, latency.heap_bytes());
+        let editor = Arc::new(EditorLifecycle::default());
+
+        Self {
+            suite_route: None,
+            state,
+            shutdown,
+            thread_handle: None,
+            destinations: Arc::new(Destinations::new(Arc::clone(&controls), Arc::clone(&editor))),
+            controls,
+            editor,
+            latency: Arc::new(Mutex::new(latency)),
+        }
+    }
+
+    /// Start the background connection thread. Safe to call multiple times —
+    /// only the first successful call spawns the thread.
+    pub fn start(&mut self) -> Result<(), HardwaveError> {
+        if self.thread_handle.is_some() {
+            return Ok(());
+        }
+
+        let subscription = SuiteSubscription::new("suite", "suite frame", &self.controls);
+        let suite_route = subscription.stats();
+
+        match crate::config::load() {
+            Ok(config) => {
+                if config.record_packets {
+                    self.controls.telemetry.recording.store(true, Ordering::Relaxed);
+                }
+                *self.controls.transport.stream_format.lock() = config.stream_format;
+                if config.use_tls {
+                    self.controls.encryption.tls.set_enabled(true);
+                }
+                // Set up from elsewhere, they stay unless the config asks
+                // for something else
+                if config.transport != Transport::default() {
+                    *self.controls.transport.kind.lock() = config.transport;
+                }
+                let (discovery, unsupported) = Discovery::from_config(&config);
+                if discovery != Discovery::Local {
+                    *self.controls.transport.discovery.lock() = discovery;
+                }
+                if let Some(e) = unsupported {
+                    self.controls.telemetry.errors.record(&e.into());
+                }
+            }
+            Err(e) => self.controls.telemetry.errors.record(&e),
+        }
+        // Pins already set up from elsewhere win over the saved ones
+        if !self.controls.encryption.tls.pins().is_saved() {
+            match TlsPins::load() {
+                Ok(pins) => self.controls.encryption.tls.set_pins(pins),
+                Err(e) => self.controls.telemetry.errors.record(&TransportError::Tls(e).into()),
+            }
+        }
+        // A key already set from the editor wins over the saved one
+        match packet_crypto::load_key() {
+            Ok(Some(key)) if self.controls.encryption.key.generation() == 0 => self.controls.encryption.key.set(Some(key)),
+            Ok(_) => {}
+            Err(e) => self.controls.telemetry.errors.record(&e.into()),
+        }
+
+        // Joined in Drop
+        let handle = spawn_connection(
+            "hardwave-ws",
+            subscription,
+            &self.state,
+            &self.shutdown,
+            &self.controls,
+            &self.editor,
+            &self.latency,
+        )?;
+        self.thread_handle = Some(handle);
+        self.suite_route = Some(suite_route);
+        // With the machine config applied, which they start from
+        self.destinations.start();
+        Ok(())
+    }
+
+    /// Stream over `shared` with the other instances of the process, where
+    /// the Suite multiplexes. Takes effect from the next connection.
+    pub fn share_connection(&self, shared: Arc<SharedConnection>) {
+        *self.controls.transport.shared.lock() = Some(shared);
+    }
+
+    /// Editor lifecycle shared with the plugin window
+    #[cfg(any(feature = "gui", test))]
+    pub fn editor_lifecycle(&self) -> Arc<EditorLifecycle> {
+        Arc::clone(&self.editor)
+    }
+
+    /// What the engine should compute for frames: what the Suite subscribed
+    /// to, and the spectrum and levels while the plugin window is open
+    pub fn wanted_outputs(&self) -> CategorySet {
+        self.controls.transport.subscriptions.wanted(self.editor.is_open())
+    }
+
+    /// How much sleep the monotonic clock counted, for `Instant`-based
+    /// timestamps to leave out
+    pub fn slept(&self) -> Duration {
+        self.controls.transport.resume.slept()
+    }
+
+    /// Update the server port
+    pub fn set_port(&self, port: i32) {
+        let mut p = self.controls.transport.port.lock();
+        if *p != port as u16 {
+            *p = port as u16;
+            // The connection to the old port ends and the new one is tried
+            // without waiting out any backoff
+            self.controls.suite.onboarding.request_retry();
+        }
+    }
+
+    /// Enable or disable the periodic timing packet
+    pub fn set_timing_packets(&self, enabled: bool) {
+        self.controls.transport.timing_packets.store(enabled, Ordering::Relaxed);
+        self.destinations.each(|controls| controls.transport.timing_packets.store(enabled, Ordering::Relaxed));
+    }
+
+    /// Opt in to or out of usage metrics for the Suite
+    pub fn set_metrics_opt_in(&self, opted_in: bool) {
+        self.controls.telemetry.metrics_opt_in.store(opted_in, Ordering::Relaxed);
+        self.destinations.each(|controls| controls.telemetry.metrics_opt_in.store(opted_in, Ordering::Relaxed));
+    }
+
+    /// Audio thread, every block: whether the Enabled parameter is on, and
+    /// the block's timestamp. Heartbeats say both, so the Suite can tell an
+    /// instance that is there but disabled from one that is gone.
+    pub fn set_session(&self, enabled: bool, timestamp_ms: u64) {
+        self.controls.telemetry.session.enabled.store(enabled, Ordering::Relaxed);
+        self.controls.telemetry.session.timestamp_ms.store(timestamp_ms, Ordering::Relaxed);
+    }
+
+    /// Set the display hints sent in heartbeats, and the instance every
+    /// packet is tagged with
+    pub fn set_display_hints(&self, hints: DisplayHints) {
+        self.controls.transport.sequence.set_instance(display::instance_tag(&hints.instance_id));
+        *self.controls.suite.display.lock() = hints;
+    }
+
+    /// Set what the hello packet says about the analysis. The audio thread
+    /// never waits: while the connection thread reads it, the update waits
+    /// for the next block.
+    pub fn set_metadata(&self, metadata: Metadata) {
+        let set = |controls: &Controls| {
+            if let Some(mut current) = controls.telemetry.metadata.try_lock() {
+                *current = metadata;
+            }
+        };
+        set(&self.controls);
+        self.destinations.each(set);
+    }
+
+    /// Display hints shared with the editor, which updates them on IPC
+    pub fn display_hints(&self) -> Arc<Mutex<DisplayHints>> {
+        Arc::clone(&self.controls.suite.display)
+    }
+
+    /// Memory gauges of this instance, for the plugin and the editor to
+    /// update and report
+    pub fn memory_gauges(&self) -> Arc<MemoryGauges> {
+        Arc::clone(&self.controls.telemetry.memory)
+    }
+
+    /// Error counts of this instance, for the plugin and the editor to add
+    /// to and report
+    pub fn error_stats(&self) -> Arc<ErrorStats> {
+        Arc::clone(&self.controls.telemetry.errors)
+    }
+
+    /// Click counts of this instance, for the plugin to record and the
+    /// editor to report
+    pub fn click_stats(&self) -> Arc<ClickStats> {
+        Arc::clone(&self.controls.telemetry.clicks)
+    }
+
+    /// The bus packets go out on, for the plugin to publish to and the
+    /// editor to subscribe to
+    #[cfg(feature = "gui")]
+    pub fn packet_bus(&self) -> Arc<PacketBus> {
+        Arc::clone(&self.controls.transport.bus)
+    }
+
+    /// The stereo warnings, for the plugin to set and to dismiss when the
+    /// Suite asks
+    pub fn stereo_warnings(&self) -> Arc<StereoWarnings> {
+        Arc::clone(&self.controls.suite.stereo)
+    }
+
+    /// Freeze and scrub requests for the frame history, for the plugin to
+    /// follow and the editor to make
+    pub fn history_control(&self) -> Arc<HistoryControl> {
+        Arc::clone(&self.controls.suite.history)
+    }
+
+    /// Ping faster than `keepalive` does, from the next connection, so a
+    /// test needn't wait out the real timeout
+    #[cfg(test)]
+    fn set_ping_times(&self, times: PingTimes) {
+        *self.controls.transport.ping_times.lock() = times;
+    }
+
+    /// The update rate and instance name the Suite asked for, for the
+    /// plugin to follow
+    pub fn remote_control(&self) -> Arc<RemoteControl> {
+        Arc::clone(&self.controls.suite.remote)
+    }
+
+    /// The first-run flag and reconnect requests, for the editor's setup
+    /// state and actions
+    #[cfg(feature = "gui")]
+    pub fn onboarding(&self) -> Arc<Onboarding> {
+        Arc::clone(&self.controls.suite.onboarding)
+    }
+
+    /// Commands for the Suite, queued by the editor, and the Suite's
+    /// answers for it to pick up
+    #[cfg(any(feature = "gui", test))]
+    pub fn suite_commands(&self) -> Arc<SuiteCommands> {
+        Arc::clone(&self.controls.suite.suite_commands)
+    }
+
+    /// The packet key, for the editor to set and report
+    #[cfg(any(feature = "gui", test))]
+    pub fn packet_encryption(&self) -> Arc<PacketEncryption> {
+        Arc::clone(&self.controls.encryption.key)
+    }
+
+    /// TLS to the Suite, for the editor to offer the certificate turned
+    /// down
+    #[cfg(feature = "gui")]
+    pub fn tls_control(&self) -> Arc<TlsControl> {
+        Arc::clone(&self.controls.encryption.tls)
+    }
+
+    /// For the editor to read, save and clear the account token
+    #[cfg(any(feature = "gui", test))]
+    pub fn account_token(&self) -> Arc<AccountToken> {
+        Arc::clone(&self.controls.auth.account_token)
+    }
+
+    /// The stamp heartbeats measure staleness against, for the plugin to
+    /// update every block and the editor to watch
+    pub fn process_watchdog(&self) -> Arc<ProcessWatchdog> {
+        Arc::clone(&self.controls.telemetry.watchdog)
+    }
+
+    /// The counter every packet's `sequence` comes from, for the plugin to
+    /// number its packets with and reset
+    pub fn packet_sequence(&self) -> Arc<PacketSequence> {
+        Arc::clone(&self.controls.transport.sequence)
+    }
+
+    /// Record which plugin format the host loaded, for usage metrics
+    pub fn set_plugin_api(&self, api: PluginApi) {
+        let id = metrics::plugin_api_id(api);
+        self.controls.telemetry.plugin_api.store(id, Ordering::Relaxed);
+        self.destinations.each(|controls| controls.telemetry.plugin_api.store(id, Ordering::Relaxed));
+    }
+
+    /// Switch for recording outgoing packets, shared with the plugin window
+    #[cfg(feature = "gui")]
+    pub fn recording_switch(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.controls.telemetry.recording)
+    }
+
+    /// Suspend or resume streaming. While suspended the connection is kept
+    /// but only a status packet and slow heartbeats are sent.
+    pub fn set_suspended(&self, suspended: bool) {
+        self.controls.transport.suspended.store(suspended, Ordering::Relaxed);
+        self.destinations.each(|controls| controls.transport.suspended.store(suspended, Ordering::Relaxed));
+    }
+
+    /// Stream to `destinations` as well as the Suite at the Port parameter,
+    /// see `destinations`; from `start` on if it hasn't been called
+    pub fn set_destinations(&self, destinations: &[Destination]) {
+        self.destinations.set(destinations);
+    }
+
+    /// The extra destinations, for the editor to change
+    #[cfg(feature = "gui")]
+    pub fn destinations(&self) -> Arc<Destinations> {
+        Arc::clone(&self.destinations)
+    }
+
+    /// Each extra destination and its connection state, as
+    /// `connection_state` is the Port parameter's
+    #[cfg(test)]
+    pub fn destination_statuses(&self) -> Vec<DestinationStatus> {
+        self.destinations.statuses()
+    }
+
+    /// Latency percentiles over recently sent frames. Not for the audio
+    /// thread, which would wait for the connection thread and sort; see
+    /// `latency_percentiles`.
+    #[cfg(test)]
+    pub fn latency_stats(&self) -> LatencyStats {
+        self.latency.lock().stats()
+    }
+
+
+    /// Connection state and latency, for the editor to report
+    #[cfg(any(feature = "gui", test))]
+    pub fn connection_stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            state: Arc::clone(&self.state),
+            latency: Arc::clone(&self.latency),
+            round_trip: Arc::clone(&self.controls.transport.round_trip),
+            link: Arc::clone(&self.controls.telemetry.link),
+            address: Arc::clone(&self.controls.transport.address),
+            port_warning: Arc::clone(&self.controls.transport.port_warning),
+        }
+    }
+
+    /// Get the current connection state
+    #[cfg(test)]
+    pub fn connection_state(&self) -> ConnectionState {
+        self.state.lock().current
+    }
+
+    /// Recent state transitions, oldest first
+    #[cfg(test)]
+    pub fn state_history(&self) -> Vec<StateTransition> {
+        self.state.lock().history.iter().cloned().collect()
+    }
+
+    /// Check if connected
+    #[cfg(test)]
+    pub fn is_connected(&self) -> bool {
+        self.connection_state() == ConnectionState::Connected
+    }
+
+    /// Publish a packet on the bus (non-blocking), for the connection thread
+    /// and whatever else subscribed. It is published even when the Suite
+    /// won't get it, and the error says why not. A frame takes the place of
+    /// one the Suite's connection hasn't sent yet, and a full queue for the
+    /// Suite drops anything else, on the bus's thread; `QueueFull` is the bus
+    /// itself falling behind. Frames count towards the drop rate once the thread is running,
+    /// and every packet dropped in `stats`.
+    pub fn send(&self, packet: impl Into<Arc<AudioPacket>>) -> Result<(), HardwaveError> {
+        let packet = packet.into();
+        let is_frame = packet.packet_type == PACKET_TYPE_FFT;
+        let published = self.controls.transport.bus.publish(packet);
+        let Some(route) = &self.suite_route else {
+            return Err(TransportError::NotStarted.into());
+        };
+        if !route.is_open() {
+            return Err(TransportError::Closed.into());
+        }
+        if published.is_err() {
+            self.controls.telemetry.link.dropped();
+            if is_frame {
+                self.controls.telemetry.usage.frame_produced(true);
+            }
+        }
+        Ok(published?)
+    }
+
+    /// Background connection loop
+    fn connection_loop(
+        queues: SuiteQueues,
+        state: Arc<Mutex<StateMachine>>,
+        shutdown: Arc<AtomicBool>,
+        controls: Arc<Controls>,
+        editor: Arc<EditorLifecycle>,
+        latency: Arc<Mutex<LatencyHistogram>>,
+    ) {
+        let initial_delay = Duration::from_millis(100);
+        let mut reconnect_delay = initial_delay;
+        let max_reconnect_delay = Duration::from_secs(5);
+        let mut connected_before = false;
+        // A sleep not yet reported to the Suite
+        let mut resumed: Option<Resume> = None;
+        let mut token_generation = controls.auth.account_token.generation();
+
+        while !shutdown.load(Ordering::Relaxed) {
+            panics::checkpoint(Region::Connection);
+
+            // Get current port, and where the Suite is
+            let port = *controls.transport.port.lock();
+            Self::check_port(&controls, port);
+            let address = Self::find_suite(&state, &shutdown, &controls, port);
+            *controls.transport.address.lock() = Some(address.clone());
+            if shutdown.load(Ordering::Relaxed) {
+                state.lock().transition(ConnectionState::Disconnected, "shutdown");
+                break;
+            }
+
+            // Try to connect
+            state.lock().transition(ConnectionState::Connecting, "connect attempt");
+            controls.suite.onboarding.attempting();
+
+            // A stream token came from the account token it was exchanged
+            // for, and a new one is exchanged again
+            if controls.auth.account_token.generation() != token_generation {
+                token_generation = controls.auth.account_token.generation();
+                controls.auth.stream_auth.lock().forget();
+            }
+            let stream_token = controls.auth.stream_auth.lock().bearer(Instant::now());
+            let bearer = stream_token.clone().or_else(|| match controls.auth.stream_auth.lock().account_token() {
+                Ok(token) => token,
+                Err(e) => {
+                    controls.telemetry.errors.record(&e.into());
+                    None
+                }
+            });
+            let mut unauthorized = false;
+            // A key set or cleared since the last connection applies from here
+            let sealer = controls.encryption.key.sealer();
+            let key_id = sealer.key_id();
+            *controls.encryption.sealer.lock() = sealer;
+            match Self::open_link(&address, bearer.as_deref(), key_id, &controls) {
+                Ok(mut link) => {
+                    if let Link::Own(socket, _) = &mut link {
+                        Self::exchange_token(socket, &controls);
+                    }
+                    state.lock().transition(ConnectionState::Connected, "handshake complete");
+                    controls.encryption.tls.attempted(None);
+                    if let Err(e) = controls.suite.onboarding.connected() {
+                        controls.telemetry.errors.record(&e);
+                    }
+                    reconnect_delay = initial_delay;
+                    if connected_before {
+                        controls.telemetry.usage.reconnected();
+                        controls.telemetry.link.reconnected();
+                    }
+                    connected_before = true;
+
+                    // Handle connection
+                    let ended = Self::handle_connection(
+                        &mut link,
+                        &queues,
+                        &state,
+                        &shutdown,
+                        &controls,
+                        &editor,
+                        &latency,
+                        port,
+                        token_generation,
+                        resumed.take(),
+                    );
+                    let (slept, failure) = match ended {
+                        Ok(slept) => (slept, None),
+                        Err(e) => (None, Some(e)),
+                    };
+
+                    // Whatever ended the connection, never leave it reading Connected
+                    let reason = if shutdown.load(Ordering::Relaxed) {
+                        "shutdown"
+                    } else if *controls.transport.port.lock() != port {
+                        "port changed"
+                    } else if slept.is_some() {
+                        "resumed from sleep"
+                    } else if controls.auth.account_token.generation() != token_generation {
+                        "account token changed"
+                    } else {
+                        "connection closed"
+                    };
+                    resumed = resumed.or(slept);
+                    state.lock().transition(ConnectionState::Disconnected, reason);
+                    controls.transport.round_trip.set(None);
+                    // The plugin didn't end it, so whatever did is the error
+                    if let Some(e) = failure {
+                        Self::link_failed(&controls, &e);
+                    }
+                }
+                Err(e) => {
+                    Self::link_failed(&controls, &e);
+                    // Refusing to stream in the clear is an error the user
+                    // has to act on, not a Suite that isn't there
+                    if matches!(e, TransportError::Encryption(_)) {
+                        state.lock().transition(ConnectionState::Error, "encryption refused");
+                    } else if matches!(e, TransportError::OwnPort(_)) {
+                        // Nothing to retry until the port changes
+                        state.lock().transition(ConnectionState::Error, "port is the packet server's");
+                    } else if let TransportError::Tls(tls) = &e {
+                        // The reason, the fingerprint of a certificate turned
+                        // down included, is the last error recorded
+                        state.lock().transition(ConnectionState::Error, "TLS refused");
+                        controls.encryption.tls.attempted(Some(tls));
+                    } else if matches!(e, TransportError::Unauthorized(_)) {
+                        state.lock().transition(ConnectionState::Unauthorized, "token refused");
+                        // Hammering the Suite with the same account token
+                        // won't change its mind
+                        unauthorized = stream_token.is_none();
+                    } else {
+                        state.lock().transition(ConnectionState::Disconnected, "connect failed");
+                    }
+                    // Perhaps the stream token was turned down; without it
+                    // the next attempt connects with the account token and
+                    // exchanges again
+                    if stream_token.is_some()
+                        && matches!(e, TransportError::Handshake(_) | TransportError::Unauthorized(_))
+                    {
+                        controls.auth.stream_auth.lock().forget();
+                    }
+                    // A refused connection only means the Suite isn't running
+                    if !matches!(&e, TransportError::Connect(io) if io.kind() == std::io::ErrorKind::ConnectionRefused) {
+                        controls.telemetry.errors.record(&e.into());
+                    }
+                }
+            }
+
+            // Wait before reconnecting, unless the machine just woke up: the
+            // Suite is likely back already and the backoff is from before.
+            // The plugin window can cut the wait short too, saving a token
+            // included.
+            if let Some(resume) = controls.transport.resume.check() {
+                resumed = Some(resume);
+            }
+            if resumed.is_some() {
+                reconnect_delay = initial_delay;
+            } else if !shutdown.load(Ordering::Relaxed) {
+                let backoff = if unauthorized { auth::UNAUTHORIZED_RETRY } else { reconnect_delay };
+                if controls.suite.onboarding.wait_for_retry(backoff) {
+                    reconnect_delay = initial_delay;
+                } else {
+                    reconnect_delay = (reconnect_delay * 2).min(max_reconnect_delay);
+                }
+            }
+        }
+    }
+
+    /// Keep why the link failed, with a line in the log and the counts so
+    /// far when the reason is a new one. Here rather than on the audio
+    /// thread, which can't wait for the reason's lock or format it.
+    fn link_failed(controls: &Controls, error: &dyn std::fmt::Display) {
+        if controls.telemetry.link.failed(error) {
+            crate::runtime::debug_log(&format!("[websocket] link: {}; so far {:?}", error, controls.telemetry.link.summary()));
+        }
+    }
+
+    /// Check the port against the OS's ephemeral range and the packet
+    /// servers in this process, logging a warning that changed. Here rather
+    /// than where the audio thread sets the port, since it takes a lock and
+    /// formats.
+    fn check_port(controls: &Controls, port: u16) {
+        let warning = ports::check(i32::from(port));
+        let mut current = controls.transport.port_warning.lock();
+        if *current != warning {
+            if let Some(warning) = warning {
+                crate::runtime::debug_log(&format!("[websocket] Port {}: {}", port, warning));
+            }
+            *current = warning;
+        }
+    }
+
+    /// Where to connect this time: the host the machine config sets, the
+    /// Suite that answers browsing, or this machine at `port`. Browsing
+    /// again for every attempt also covers a Suite that went away.
+    fn find_suite(state: &Mutex<StateMachine>, shutdown: &AtomicBool, controls: &Controls, port: u16) -> SuiteAddress {
+        let found = match controls.transport.discovery.lock().clone() {
+            Discovery::Local => None,
+            Discovery::Host(host) => return SuiteAddress { host, port },
+            Discovery::Browse => {
+                state.lock().transition(ConnectionState::Discovering, "browsing");
+                discovery::browse(discovery::SERVICE_TYPE, discovery::BROWSE_TIMEOUT, shutdown)
+                    .unwrap_or_else(|e| {
+                        controls.telemetry.errors.record(&e.into());
+                        None
+                    })
+            }
+            #[cfg(test)]
+            Discovery::Advertised(address) => {
+                state.lock().transition(ConnectionState::Discovering, "browsing");
+                Some(address)
+            }
+        };
+        found.unwrap_or_else(|| SuiteAddress::local(port))
+    }
+
+    /// Where this instance's packets go: a stream of the shared connection if
+    /// there is one that can carry them, else a socket of its own
+    fn open_link(
+        address: &SuiteAddress,
+        bearer: Option<&str>,
+        key_id: Option<u8>,
+        controls: &Controls,
+    ) -> Result<Link, TransportError> {
+        // A plugin window's packet server would answer, but it isn't the
+        // Suite
+        if address.is_local() && ports::check(i32::from(address.port)) == Some(PortWarning::PacketServer) {
+            return Err(TransportError::OwnPort(address.port));
+        }
+        let format = *controls.transport.stream_format.lock();
+        // Nothing is agreed over datagrams, and they are never secured, so
+        // TLS gets a WebSocket
+        if *controls.transport.kind.lock() == Transport::Udp && !controls.encryption.tls.enabled() {
+            if let Some(shared) = controls.transport.shared.lock().as_ref() {
+                shared.leave(controls.transport.stream);
+            }
+            let socket = DatagramSocket::open(address, Arc::clone(&controls.telemetry.errors))?;
+            return Ok(Link::Udp(Box::new(socket), WireFormat::usable(format)));
+        }
+        let shared = controls.transport.shared.lock().clone();
+        if let Some(shared) = shared {
+            // A packet key, a format or TLS is agreed in the handshake, and
+            // a token is traded on the connection, so they need a connection
+            // of their own, as does a Suite on another host. A token to
+            // stream with is the shared connection's.
+            let exchange_due = !matches!(controls.auth.stream_auth.lock().exchange_due(Instant::now()), Ok(None));
+            let own_format = WireFormat::usable(format) != WireFormat::default();
+            let agreed = key_id.is_some() || exchange_due || own_format || controls.encryption.tls.enabled();
+            if agreed || !address.is_local() {
+                shared.leave(controls.transport.stream);
+            } else if let Some(stream) = shared.join(address.port, bearer, controls.stream_info())? {
+                return Ok(Link::Shared(stream));
+            }
+        }
+        let pins = controls.encryption.tls.enabled().then(|| controls.encryption.tls.pins());
+        Self::try_connect(address, bearer, key_id, format, pins.as_ref())
+            .map(|(socket, format)| Link::Own(Box::new(socket), format))
+    }
+
+    /// Try to establish a WebSocket connection, authenticated with the
+    /// stream or account token if there is one, with the Suite agreeing to
+    /// decrypt packets sealed with the key `key_id` if one is set, and the
+    /// packet encoding agreed from `format` (see `wire_format`), over TLS
+    /// with `pins` if there are any
+    fn try_connect(
+        address: &SuiteAddress,
+        bearer: Option<&str>,
+        key_id: Option<u8>,
+        format: WireFormat,
+        tls: Option<&TlsPins>,
+    ) -> Result<(WebSocket<Stream>, WireFormat), TransportError> {
+        let authorization = authorization_header(bearer);
+        let encryption = key_id.map_or(String::new(), packet_crypto::request_header);
+        let (path, formats) = wire_format::request(format);
+        let (socket, response) = open_socket(address, &path, &(authorization + &encryption + &formats), tls)?;
+        if let Some(key_id) = key_id {
+            packet_crypto::check_response(&response, key_id).map_err(TransportError::Encryption)?;
+        }
+        Ok((socket, wire_format::agreed(format, &response)))
+    }
+
+    /// On a new connection, trade the account token for a stream token if
+    /// that is due. A failure leaves the connection streaming without one, as
+    /// before the exchange existed.
+    fn exchange_token(socket: &mut WebSocket<Stream>, controls: &Controls) {
+        let account_token = match controls.auth.stream_auth.lock().exchange_due(Instant::now()) {
+            Ok(Some(token)) => token,
+            Ok(None) => return,
+            Err(e) => {
+                controls.telemetry.errors.record(&e.into());
+                return;
+            }
+        };
+        match Self::request_stream_token(socket, &account_token) {
+            Ok(stream) => controls.auth.stream_auth.lock().exchanged(stream),
+            Err(e) => {
+                controls.auth.stream_auth.lock().exchange_failed(Instant::now());
+                controls.telemetry.errors.record(&e.into());
+            }
+        }
+    }
+
+    /// Ask the Suite for a stream token and wait up to `EXCHANGE_TIMEOUT` for
+    /// it
+    fn request_stream_token(socket: &mut WebSocket<Stream>, account_token: &str) -> Result<StreamToken, AuthError> {
+        let failed = |e: tungstenite::Error| AuthError::Exchange(e.to_string());
+        socket.send(Message::Text(auth::exchange_request(account_token))).map_err(failed)?;
+
+        let deadline = Instant::now() + auth::EXCHANGE_TIMEOUT;
+        while Instant::now() < deadline {
+            match socket.read() {
+                Ok(Message::Text(text)) => {
+                    if let Some(reply) = auth::parse_exchange_reply(&text, Instant::now()) {
+                        return reply;
+                    }
+                }
+                Ok(_) => {}
+                // The read timeout, so the deadline is checked
+                Err(tungstenite::Error::Io(e))
+                    if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => {}
+                Err(e) => return Err(failed(e)),
+            }
+        }
+        Err(AuthError::Exchange("no reply from the Suite".to_string()))
+    }
+
+    /// Send one packet in the link's encoding and flush it, recording it if
+    /// enabled, and count it. An error means the connection is dead.
+    fn send_packet(link: &mut Link, controls: &Controls, packet: &AudioPacket) -> Result<(), TransportError> {
+        let data = packet.to_bytes();
+        // Recordings stay on this machine and are kept plain, and canonical
+        controls.record(&data);
+        let data = match link.format() {
+            WireFormat::Canonical => data,
+            format => format.encode(packet),
+        };
+        // A packet that can't be sealed fails like one that can't be sent
+        let data = controls.encryption.sealer.lock().seal(data)?;
+        let bytes = data.len();
+        link.send(data)?;
+        controls.telemetry.link.sent(bytes);
+        Ok(())
+    }
+
+    /// Number a packet made here and send it, as `send_packet`
+    fn send_new(link: &mut Link, controls: &Controls, mut packet: AudioPacket) -> Result<(), TransportError> {
+        controls.transport.sequence.stamp(&mut packet);
+        Self::send_packet(link, controls, &packet)
+    }
+
+    /// Handle an active connection to `port`, first reporting `resumed` if
+    /// the machine slept since the last one. Returns the sleep if one ended
+    /// it: the socket is from before and likely dead. A change of port
+    /// closes the connection, and so does an account token saved or
+    /// cleared since `token_generation`.
+    #[allow(clippy::too_many_arguments)]
+    fn handle_connection(
+        link: &mut Link,
+        queues: &SuiteQueues,
+        state: &Arc<Mutex<StateMachine>>,
+        shutdown: &Arc<AtomicBool>,
+        controls: &Controls,
+        editor: &EditorLifecycle,
+        latency: &Mutex<LatencyHistogram>,
+        port: u16,
+        token_generation: u32,
+        resumed: Option<Resume>,
+    ) -> Result<Option<Resume>, TransportError> {
+        let mut last_heartbeat = controls.telemetry.clock.now();
+        let mut last_timing = last_heartbeat;
+        let mut pending: Vec<Arc<AudioPacket>> = Vec::with_capacity(PACKET_QUEUE_LEN);
+        // Late joiners learn the current editor, suspension and warnings
+        // from heartbeats
+        let mut last_editor_seq = editor.seq();
+        let mut suspended = false;
+        let mut stereo_warnings = 0;
+        let mut over_budget = false;
+        controls.transport.subscriptions.connected();
+        controls.suite.remote.connected();
+        let mut keepalive = Keepalive::new(Instant::now(), *controls.transport.ping_times.lock());
+
+        // Which fields the packets have, for a Suite that didn't see the
+        // handshake header, and what the instance analyses with
+        let mut announced = controls.suite.display.lock().clone();
+        let hello = AudioPacket::new_hello().with_display(&announced).with_metadata(&controls.telemetry.metadata.lock());
+        if let Err(e) = Self::send_new(link, controls, hello) {
+            state.lock().transition(ConnectionState::Disconnected, "send failed");
+            return Err(e);
+        }
+
+        if let Some(resume) = resumed {
+            suspended = controls.transport.suspended.load(Ordering::Relaxed);
+            stereo_warnings = controls.suite.stereo.get();
+            let mut packet = AudioPacket::new_resumed(resume.slept.as_millis() as u64, suspended);
+            packet.stereo_warnings = stereo_warnings;
+            if let Err(e) = Self::send_new(link, controls, packet) {
+                state.lock().transition(ConnectionState::Disconnected, "send failed");
+                return Err(e);
+            }
+        }
+
+        while !shutdown.load(Ordering::Relaxed) {
+            panics::checkpoint(Region::Connection);
+            if let Some(resume) = controls.transport.resume.check() {
+                return Ok(Some(resume));
+            }
+
+            // A new key, or none, needs a new handshake
+            if controls.encryption.sealer.lock().generation() != controls.encryption.key.generation() {
+                state.lock().transition(ConnectionState::Disconnected, "packet key changed");
+                return Ok(None);
+            }
+
+            // So does a new account token, or none
+            if controls.auth.account_token.generation() != token_generation {
+                Self::say_goodbye(link, controls);
+                return Ok(None);
+            }
+
+            // So does a new port, and the old Suite hears that this one left
+            if *controls.transport.port.lock() != port {
+                Self::say_goodbye(link, controls);
+                return Ok(None);
+            }
+
+            // Announce suspension and warning changes once; a fresh
+            // connection only announces an active suspension or warning
+            let now_suspended = controls.transport.suspended.load(Ordering::Relaxed);
+            let now_warnings = controls.suite.stereo.get();
+            if now_suspended != suspended || now_warnings != stereo_warnings {
+                let mut status = AudioPacket::new_status(now_suspended);
+                status.stereo_warnings = now_warnings;
+                if let Err(e) = Self::send_new(link, controls, status) {
+                    state.lock().transition(ConnectionState::Disconnected, "send failed");
+                    return Err(e);
+                }
+                suspended = now_suspended;
+                stereo_warnings = now_warnings;
+            }
+
+            // Report editor open/close; after rapid cycles only the latest state
+            let editor_seq = editor.seq();
+            if editor_seq != last_editor_seq {
+                let packet = AudioPacket::new_editor(editor.is_open(), editor_seq);
+                if let Err(e) = Self::send_new(link, controls, packet) {
+                    state.lock().transition(ConnectionState::Disconnected, "send failed");
+                    return Err(e);
+                }
+                last_editor_seq = editor_seq;
+            }
+
+            // The metadata again, as it is now, for a Suite that asked
+            if controls.suite.remote.take_metadata_request() {
+                announced = controls.suite.display.lock().clone();
+                let hello = AudioPacket::new_hello().with_display(&announced).with_metadata(&controls.telemetry.metadata.lock());
+                if let Err(e) = Self::send_new(link, controls, hello) {
+                    state.lock().transition(ConnectionState::Disconnected, "send failed");
+                    return Err(e);
+                }
+            }
+
+            // Commands from the plugin window; one that can't be sent stays
+            // queued for the next connection
+            while let Some((seq, command)) = controls.suite.suite_commands.next() {
+                let packet = AudioPacket::new_user_command(seq, command).with_display(&controls.suite.display.lock());
+                if let Err(e) = Self::send_new(link, controls, packet) {
+                    state.lock().transition(ConnectionState::Disconnected, "send failed");
+                    return Err(e);
+                }
+                controls.suite.suite_commands.sent(seq);
+            }
+
+            // Check for incoming packets to send: the events first, then
+            // the latest frame
+            match queues.take(&mut pending) {
+                Ok(()) => {
+                    for packet in pending.drain(..) {
+                        // Shared with the plugin window, maybe; copied only
+                        // if it still holds it
+                        let mut packet = Arc::unwrap_or_clone(packet);
+                        // Made for the plugin window as well, maybe
+                        if !controls.transport.subscriptions.filter(&mut packet) {
+                            continue;
+                        }
+                        if let Err(e) = Self::send_packet(link, controls, &packet) {
+                            state.lock().transition(ConnectionState::Disconnected, "send failed");
+                            return Err(e);
+                        }
+                        if packet.packet_type == PACKET_TYPE_FFT {
+                            controls.telemetry.usage.frame_sent();
+                            // A frozen frame's capture was long ago
+                            if !packet.historical {
+                                latency.lock().record(packet.captured_us, latency::now_us());
+                            }
+                        }
+                    }
+                }
+                Err(TryRecvError::Empty) => {
+                    let now = controls.telemetry.clock.now();
+                    if !suspended
+                        && controls.transport.timing_packets.load(Ordering::Relaxed)
+                        && now.saturating_duration_since(last_timing) >= latency::TIMING_INTERVAL
+                    {
+                        let report = AudioPacket::new_timing(latency.lock().stats());
+                        if let Err(e) = Self::send_new(link, controls, report) {
+                            state.lock().transition(ConnectionState::Disconnected, "send failed");
+                            return Err(e);
+                        }
+                        last_timing = now;
+                    }
+
+                    // No packet available, check if we need to send heartbeat
+                    let heartbeat_interval = if suspended {
+                        SUSPENDED_HEARTBEAT_INTERVAL
+                    } else {
+                        HEARTBEAT_INTERVAL
+                    };
+                    // A new label, colour or order goes out at once, with
+                    // the rest of the heartbeat
+                    let display_changed = *controls.suite.display.lock() != announced;
+                    if now.saturating_duration_since(last_heartbeat) >= heartbeat_interval || display_changed {
+                        announced = controls.suite.display.lock().clone();
+                        // The instance ID, and what analysis there is or
+                        // would be, even with nothing analysed
+                        let sample_rate = controls.telemetry.metadata.lock().sample_rate;
+                        let timestamp_ms = controls.telemetry.session.timestamp_ms.load(Ordering::Relaxed);
+                        let mut heartbeat =
+                            AudioPacket::new_heartbeat(sample_rate, timestamp_ms).with_display(&announced);
+                        heartbeat.disabled = !controls.telemetry.session.enabled.load(Ordering::Relaxed);
+                        heartbeat.editor_open = editor.is_open();
+                        heartbeat.editor_seq = last_editor_seq;
+                        heartbeat.streaming_suspended = suspended;
+                        heartbeat.metrics_opt_in = controls.telemetry.metrics_opt_in.load(Ordering::Relaxed);
+                        heartbeat.metrics = controls.usage_metrics(latency);
+                        heartbeat.stale_ms = controls.telemetry.watchdog.stale_ms();
+                        heartbeat.clicks = controls.telemetry.clicks.take_interval();
+                        heartbeat.stereo_warnings = stereo_warnings;
+                        heartbeat.link = Some(controls.telemetry.link.summary());
+                        // Logged here rather than per frame on the audio
+                        // thread, once each time the send path goes over
+                        let stats = latency.lock().stats();
+                        let over = u64::from(stats.p95_us) > latency::IN_PROCESS_BUDGET_US;
+                        if over && !over_budget {
+                            crate::runtime::debug_log(&format!(
+                                "[websocket] send latency over budget: p50 {} µs, p95 {} µs",
+                                stats.p50_us, stats.p95_us
+                            ));
+                        }
+                        over_budget = over;
+                        {
+                            let state = state.lock();
+                            heartbeat.state_transitions = state.recorded;
+                            heartbeat.rejected_transitions = state.rejected;
+                        }
+                        if let Err(e) = Self::send_new(link, controls, heartbeat) {
+                            state.lock().transition(ConnectionState::Disconnected, "heartbeat failed");
+                            return Err(e);
+                        }
+                        last_heartbeat = now;
+                    }
+                }
+                Err(TryRecvError::Disconnected) => {
+                    // Channel closed, exit
+                    return Ok(None);
+                }
+            }
+
+            if let Err(e) = Self::read_replies(link, controls, &mut keepalive) {
+                state.lock().transition(ConnectionState::Disconnected, "read failed");
+                return Err(e);
+            }
+
+            // A Suite that stopped answering is gone, however open the
+            // socket looks
+            if let Err(e) = link.keep_alive(&mut keepalive) {
+                state.lock().transition(ConnectionState::Disconnected, "no pong");
+                return Err(e);
+            }
+            controls.transport.round_trip.set(link.round_trip(&keepalive));
+
+            // Small sleep to avoid busy-waiting
+            thread::sleep(Duration::from_millis(1));
+        }
+        // The instance is going, not the connection failing
+        Self::say_goodbye(link, controls);
+        Ok(None)
+    }
+
+    /// Tell the Suite this instance is leaving, with its instance ID, and
+    /// close a socket of its own
+    fn say_goodbye(link: &mut Link, controls: &Controls) {
+        let goodbye = AudioPacket::new_goodbye().with_display(&controls.suite.display.lock());
+        let _ = Self::send_new(link, controls, goodbye);
+        link.close();
+    }
+
+    /// Take whatever the Suite has sent without waiting for more: command
+    /// acknowledgements go to the editor, subscription changes, dismissed
+    /// warnings and history commands apply at once, the commands in
+    /// `remote` wait for whoever takes them, pongs go to `keepalive`,
+    /// anything else is ignored. An error means the connection is dead.
+    fn read_replies(link: &mut Link, controls: &Controls, keepalive: &mut Keepalive) -> Result<(), TransportError> {
+        let pong = |payload: &[u8]| {
+            keepalive.pong(payload, Instant::now());
+        };
+        link.read(pong, |text| {
+            if let Some(ack) = suite_commands::parse_ack(text) {
+                controls.suite.suite_commands.acknowledged(ack);
+            } else if let Some(change) = subscriptions::parse_change(text) {
+                controls.transport.subscriptions.apply(&change);
+            } else if polarity::parse_clear(text) {
+                controls.suite.stereo.request_clear();
+            } else if let Some(command) = history::parse_command(text) {
+                controls.suite.history.apply(command);
+            } else if let Some(command) = remote::parse_command(text) {
+                match command {
+                    RemoteCommand::History(command) => controls.suite.history.apply(command),
+                    command => controls.suite.remote.apply(command),
+                }
+            }
+        })
+    }
+}
+
+/// Where a connection thread's packets go
+enum Link {
+    /// A socket of the instance's own, and the encoding agreed on it
+    Own(Box<WebSocket<Stream>>, WireFormat),
+    /// A stream of the connection shared by every instance
+    Shared(SharedStream),
+    /// Datagrams to the Suite, see `udp`, and the encoding the machine
+    /// config asks for
+    Udp(Box<DatagramSocket>, WireFormat),
+}
+
+impl Link {
+    /// Send one packet and flush it. An error means the connection is dead.
+    fn send(&mut self, data: Vec<u8>) -> Result<(), TransportError> {
+        match self {
+            Link::Own(socket, _) => send_message(socket, Message::Binary(data)),
+            Link::Shared(stream) => stream.send(&data),
+            Link::Udp(socket, _) => socket.send(&data),
+        }
+    }
+
+    /// Pass each text the Suite has sent to `f`, and each pong on a socket
+    /// of its own to `pong`, without waiting for more. An error means the
+    /// connection is dead. Nothing comes back over datagrams.
+    fn read(&mut self, pong: impl FnMut(&[u8]), f: impl FnMut(&str)) -> Result<(), TransportError> {
+        match self {
+            Link::Own(socket, _) => read_available(socket, pong, f),
+            Link::Shared(stream) => stream.read_texts(f),
+            Link::Udp(..) => Ok(()),
+        }
+    }
+
+    /// Ping a socket of the instance's own if one is due. An error if the
+    /// last ping went unanswered too long or this one couldn't be sent. The
+    /// shared connection pings for all its streams, and closes when its
+    /// Suite is gone. Datagrams go unanswered.
+    fn keep_alive(&mut self, keepalive: &mut Keepalive) -> Result<(), TransportError> {
+        let Link::Own(socket, _) = self else {
+            return Ok(());
+        };
+        let now = Instant::now();
+        if keepalive.timed_out(now) {
+            return Err(TransportError::Lost("no pong".to_string()));
+        }
+        match keepalive.ping_due(now) {
+            Some(payload) => send_message(socket, Message::Ping(payload)),
+            None => Ok(()),
+        }
+    }
+
+    /// The latest round trip of the socket the packets go over
+    fn round_trip(&self, keepalive: &Keepalive) -> Option<Duration> {
+        match self {
+            Link::Own(..) => keepalive.round_trip(),
+            Link::Shared(stream) => stream.round_trip(),
+            Link::Udp(..) => None,
+        }
+    }
+
+    /// Close a socket of the instance's own cleanly, with a normal close
+    /// frame, and wait up to `CLOSE_TIMEOUT` for the Suite's; a shared
+    /// stream is left when the instance next joins, on whichever port, or
+    /// when the client is dropped
+    fn close(&mut self) {
+        let Link::Own(socket, _) = self else {
+            return;
+        };
+        let frame = CloseFrame { code: CloseCode::Normal, reason: "goodbye".into() };
+        if socket.close(Some(frame)).is_err() || socket.flush().is_err() {
+            return;
+        }
+        // Reading fails once the Suite's close frame is in
+        let deadline = Instant::now() + CLOSE_TIMEOUT;
+        while Instant::now() < deadline && read_available(socket, |_| {}, |_| {}).is_ok() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// How packets are encoded on this link; the shared connection is always
+    /// canonical
+    fn format(&self) -> WireFormat {
+        match self {
+            Link::Own(_, format) | Link::Udp(_, format) => *format,
+            Link::Shared(_) => WireFormat::Canonical,
+        }
+    }
+}
+
+/// The handshake header authenticating with `bearer`, if there is one
+pub(crate) fn authorization_header(bearer: Option<&str>) -> String {
+    bearer.map_or(String::new(), |token| format!("Authorization: Bearer {}\r\n", token))
+}
+
+/// Send `message` on `socket` and flush it. An error means the connection
+/// is dead.
+pub(crate) fn send_message(socket: &mut WebSocket<Stream>, message: Message) -> Result<(), TransportError> {
+    let lost = |e: tungstenite::Error| TransportError::Lost(e.to_string());
+    socket.send(message).map_err(lost)?;
+    socket.flush().map_err(lost)
+}
+
+/// Pass each text message already on `socket` to `f` and each pong's
+/// payload to `pong`, and answer the Suite's pings. An error means the
+/// connection is dead.
+pub(crate) fn read_available(
+    socket: &mut WebSocket<Stream>,
+    mut pong: impl FnMut(&[u8]),
+    mut f: impl FnMut(&str),
+) -> Result<(), TransportError> {
+    let lost = |e: &dyn std::fmt::Display| TransportError::Lost(e.to_string());
+    // Non-blocking just for the read; a read timeout this short isn't
+    // honoured everywhere
+    socket.get_ref().tcp().set_nonblocking(true).map_err(|e| lost(&e))?;
+    fn would_block(e: &tungstenite::Error) -> bool {
+        matches!(e, tungstenite::Error::Io(e) if e.kind() == std::io::ErrorKind::WouldBlock)
+    }
+    let read = loop {
+        match socket.read() {
+            Ok(Message::Text(text)) => f(&text),
+            Ok(Message::Pong(payload)) => pong(&payload),
+            Ok(_) => {}
+            Err(e) if would_block(&e) => break Ok(()),
+            Err(e) => break Err(lost(&e)),
+        }
+    };
+    // Reading queued the pongs for the Suite's pings; out now, rather than
+    // with the next packet
+    let flushed = match socket.flush() {
+        Err(e) if !would_block(&e) => Err(lost(&e)),
+        _ => Ok(()),
+    };
+    let blocking = socket.get_ref().tcp().set_nonblocking(false).map_err(|e| lost(&e));
+    read.and(flushed).and(blocking)
+}
+
+/// Connect to the Suite on `port` and upgrade to a WebSocket at `path`,
+/// sending `headers` (each ending in CRLF) with the request, over TLS with
+/// `tls` pinned if it is set (see `tls`). Returns the socket and the
+/// response headers, for the caller to check what was agreed.
+pub(crate) fn open_socket(
+    address: &SuiteAddress,
+    path: &str,
+    headers: &str,
+    tls: Option<&TlsPins>,
+) -> Result<(WebSocket<Stream>, String), TransportError> {
+    let addr = address.resolve()?;
+
+    // Connect with timeout
+    let tcp = std::net::TcpStream::connect_timeout(&addr, Duration::from_secs(2)).map_err(TransportError::Connect)?;
+
+    tcp.set_nonblocking(false).ok();
+    tcp.set_read_timeout(Some(Duration::from_millis(100))).ok();
+    tcp.set_write_timeout(Some(Duration::from_millis(100))).ok();
+    let mut stream = match tls {
+        Some(pins) => tls::connect(tcp, &address.host, pins).map_err(TransportError::Tls)?,
+        None => Stream::Plain(tcp),
+    };
+
+    // Perform WebSocket handshake manually
+    let key = generate_key();
+    let request = format!(
+        "GET {} HTTP/1.1\r\n\
+         Host: {}\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Key: {}\r\n\
+         Sec-WebSocket-Version: 13\r\n\
+         {}: {}\r\n\
+         {}\
+         \r\n",
+        path, address, key, VERSION_HEADER, PROTOCOL_VERSION, headers
+    );
+
+    stream.write_all(request.as_bytes()).map_err(TransportError::Connect)?;
+
+    // Read response. The Suite may send its first messages straight after
+    // it, in the same read.
+    let mut response = [0u8; 1024];
+    let mut total_read = 0;
+    let headers_end = loop {
+        let n = stream
+            .read(&mut response[total_read..])
+            .map_err(TransportError::Connect)?;
+        if n == 0 {
+            return Err(TransportError::Handshake("connection closed during handshake"));
+        }
+        total_read += n;
+        // Check for end of headers
+        if let Some(end) = response[..total_read].windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if total_read >= response.len() {
+            return Err(TransportError::Handshake("response headers too long"));
+        }
+    };
+
+    // Verify response contains 101 Switching Protocols
+    let response_str = std::str::from_utf8(&response[..headers_end])
+        .map_err(|_| TransportError::Handshake("response is not UTF-8"))?;
+    // A Suite refusing the token is told apart from one that isn't a Suite
+    let status = response_str.split_whitespace().nth(1).and_then(|code| code.parse::<u16>().ok());
+    if let Some(status @ (401 | 403)) = status {
+        return Err(TransportError::Unauthorized(status));
+    }
+    if !response_str.contains("101") || !response_str.to_lowercase().contains("upgrade") {
+        return Err(TransportError::Handshake("no 101 Switching Protocols"));
+    }
+
+    // Create WebSocket from the stream, with whatever followed the headers
+    let socket = WebSocket::from_partially_read(
+        stream,
+        response[headers_end..total_read].to_vec(),
+        tungstenite::protocol::Role::Client,
+        None,
+    );
+    Ok((socket, response_str.to_string()))
+}
+
+impl Default for WebSocketClient {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for WebSocketClient {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Relaxed);
+        // Out of a reconnect wait at once
+        self.controls.suite.onboarding.request_retry();
+        let destinations = self.destinations.shut_down();
+        // The destinations' threads have the same time as this one
+        let deadline = Instant::now() + DROP_TIMEOUT;
+        if let Some(handle) = self.thread_handle.take() {
+            join_by(handle, deadline);
+        }
+        for link in destinations {
+            join_by(link.thread, deadline);
+        }
+        // The Suite hears that the instance is gone
+        if let Some(shared) = self.controls.transport.shared.lock().take() {
+            shared.leave(self.controls.transport.stream);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // The Suites here answer handshakes through tungstenite's callback,
+    // which has to return its whole error response
+    #![allow(clippy::result_large_err)]
+
+    use super::*;
+    use crate::error::Layer;
+    use crate::packet_crypto::PacketKey;
+    use crate::beat_phase::TransportSnapshot;
+    use crate::protocol::{ChannelLevels, NUM_BINS, PACKET_TYPE_LOOP_DETECTED, PACKET_TYPE_USER_COMMAND, WAVE_SIZE};
+    use crate::transport_loop::{LoopEvent, LoopRange};
+    use std::net::TcpListener;
+
+    /// Accept one WebSocket client and forward every decoded packet, reading
+    /// with a delay to simulate a slow Suite.
+    fn slow_mock_server(read_delay: Duration) -> (u16, Receiver<AudioPacket>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        (port, slow_mock_server_on(listener, read_delay))
+    }
+
+    fn slow_mock_server_on(listener: TcpListener, read_delay: Duration) -> Receiver<AudioPacket> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            while let Ok(msg) = ws.read() {
+                if let Message::Binary(data) = msg {
+                    if let Ok(packet) = AudioPacket::from_bytes(&data) {
+                        let _ = tx.send(packet);
+                    }
+                }
+                thread::sleep(read_delay);
+            }
+        });
+        rx
+    }
+
+    /// Take frames from `rx` into `frames` until the one at `timestamp_ms`
+    fn wait_for_frame(rx: &Receiver<AudioPacket>, frames: &mut Vec<u64>, timestamp_ms: u64) {
+        while frames.last() != Some(&timestamp_ms) {
+            let packet = rx.recv_timeout(Duration::from_secs(5)).expect("the frame never arrived");
+            if packet.packet_type == PACKET_TYPE_FFT {
+                frames.push(packet.timestamp_ms);
+            }
+        }
+    }
+
+    fn wait_connected(client: &WebSocketClient) {
+        let start = Instant::now();
+        while !client.is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(5), "client never connected");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    fn fft_frame(timestamp_ms: u64) -> AudioPacket {
+        AudioPacket::fft(48000, timestamp_ms)
+            .with_bins(vec![-60.0; NUM_BINS], vec![-60.0; NUM_BINS])
+            .with_levels(ChannelLevels::new(-3.0, 0.5), ChannelLevels::new(-3.0, 0.5))
+            .with_wave(vec![0.0; WAVE_SIZE], vec![0.0; WAVE_SIZE])
+    }
+
+    /// Event stand-in: another pass of a loop, numbered by its timestamp
+    fn event(index: u64) -> AudioPacket {
+        let range = LoopRange { start: 0, end: 48_000 };
+        AudioPacket::new_loop(48000, index, &LoopEvent::Wrapped { range, wraps: index as u32 }, 0)
+    }
+
+    /// A client on a manual clock, connected to a mock server that reads as
+    /// fast as it can. Only `step` moves its heartbeats along.
+    fn stepped_client() -> (WebSocketClient, Clock, Receiver<AudioPacket>) {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let clock = Clock::manual();
+        let mut client = WebSocketClient::with_clock(clock.clone());
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        (client, clock, rx)
+    }
+
+    /// Wait for the connection thread to read `clock` twice more, by when it
+    /// has sent whatever was due at the time it read
+    fn settle(clock: &Clock) {
+        let reads = clock.reads();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while clock.reads() < reads + 2 {
+            assert!(Instant::now() < deadline, "the connection thread stopped reading the clock");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Step `clock` by `by` and take what the connection sent for it: all
+    /// it sent up to a plugin-window command queued after the step, which
+    /// goes out even while suspended, the command included
+    fn step(client: &WebSocketClient, clock: &Clock, rx: &Receiver<AudioPacket>, by: Duration) -> Vec<AudioPacket> {
+        settle(clock);
+        clock.advance(by);
+        settle(clock);
+        let marker = client.suite_commands().submit(r#"{"command":"markCapture"}"#).unwrap();
+
+        let mut packets = Vec::new();
+        loop {
+            let packet = rx.recv_timeout(Duration::from_secs(5)).expect("the marker never arrived");
+            let done = packet.packet_type == PACKET_TYPE_USER_COMMAND && packet.command_seq == marker;
+            packets.push(packet);
+            if done {
+                return packets;
+            }
+        }
+    }
+
+    #[test]
+    fn test_state_machine_rejects_illegal_transitions() {
+        let mut machine = StateMachine::new();
+        assert!(!machine.try_transition(ConnectionState::Connected, "skip connecting"));
+        assert!(machine.try_transition(ConnectionState::Connecting, "a"));
+        assert!(machine.try_transition(ConnectionState::Connected, "b"));
+        assert!(!machine.try_transition(ConnectionState::Connecting, "reconnect while live"));
+        assert_eq!(machine.current, ConnectionState::Connected);
+        assert_eq!(machine.history.len(), 2);
+
+        // Browsing comes before connecting, never during a connection
+        assert!(!machine.try_transition(ConnectionState::Discovering, "browse while live"));
+        assert!(machine.try_transition(ConnectionState::Disconnected, "c"));
+        assert!(machine.try_transition(ConnectionState::Discovering, "d"));
+        assert!(!machine.try_transition(ConnectionState::Connected, "skip connecting"));
+        assert!(machine.try_transition(ConnectionState::Connecting, "e"));
+        assert_eq!((machine.recorded, machine.rejected), (5, 4));
+    }
+
+    #[test]
+    #[should_panic(expected = "is not a connection state transition")]
+    #[cfg(debug_assertions)]
+    fn test_an_illegal_transition_stops_debug_builds() {
+        let mut machine = StateMachine::new();
+        machine.transition(ConnectionState::Connected, "skip connecting");
+    }
+
+    #[test]
+    fn test_state_history_is_bounded() {
+        let mut machine = StateMachine::new();
+        for _ in 0..STATE_HISTORY_LEN {
+            machine.transition(ConnectionState::Connecting, "attempt");
+            machine.transition(ConnectionState::Disconnected, "failed");
+        }
+        assert_eq!(machine.history.len(), STATE_HISTORY_LEN);
+        assert_eq!(machine.history.back().unwrap().reason, "failed");
+        assert!(machine.history.iter().zip(machine.history.iter().skip(1)).all(|(a, b)| a.at <= b.at));
+    }
+
+    #[test]
+    fn test_connect_fail_reconnect_history() {
+        use ConnectionState::*;
+
+        // Reserve a port, then release it so the first attempts fail.
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let wait_for = |client: &WebSocketClient, from: ConnectionState, to: ConnectionState| {
+            let start = Instant::now();
+            while !client.state_history().iter().any(|t| t.from == from && t.to == to) {
+                assert!(start.elapsed() < Duration::from_secs(10), "never saw {:?} -> {:?}", from, to);
+                thread::sleep(Duration::from_millis(5));
+            }
+        };
+        wait_for(&client, Connecting, Disconnected);
+
+        // Bring a server up, accept once, then go away again.
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let ws = tungstenite::accept(stream).unwrap();
+            thread::sleep(Duration::from_millis(100));
+            drop(ws);
+        });
+        wait_for(&client, Connecting, Connected);
+        server.join().unwrap();
+        wait_for(&client, Connected, Disconnected);
+
+        let history: Vec<(ConnectionState, ConnectionState)> =
+            client.state_history().iter().map(|t| (t.from, t.to)).collect();
+        let expected = [
+            (Disconnected, Connecting),
+            (Connecting, Disconnected),
+            (Disconnected, Connecting),
+            (Connecting, Connected),
+            (Connected, Disconnected),
+        ];
+        let mut remaining = expected.iter().peekable();
+        for step in &history {
+            if remaining.peek() == Some(&step) {
+                remaining.next();
+            }
+        }
+        assert!(remaining.peek().is_none(), "unexpected history {:?}", history);
+        assert!(!history.contains(&(Connected, Connecting)));
+    }
+
+    #[test]
+    fn test_unanswered_pings_reconnect() {
+        use crate::keepalive::PingTimes;
+
+        // The first Suite hangs after the handshake and reads nothing, so
+        // its pongs never come; the second answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, accepted) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let mut hung = Vec::new();
+            for (connection, stream) in listener.incoming().take(2).enumerate() {
+                let mut ws = tungstenite::accept(stream.unwrap()).unwrap();
+                let _ = tx.send(Instant::now());
+                if connection == 0 {
+                    hung.push(ws);
+                } else {
+                    thread::spawn(move || while ws.read().is_ok() {});
+                }
+            }
+            thread::sleep(Duration::from_secs(30));
+        });
+
+        let times = PingTimes { interval: Duration::from_millis(50), timeout: Duration::from_millis(200) };
+        let mut client = WebSocketClient::new();
+        client.set_ping_times(times);
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let first = accepted.recv_timeout(Duration::from_secs(5)).unwrap();
+        let second = accepted.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(
+            second - first < times.interval + times.timeout + Duration::from_secs(1),
+            "reconnected after {:?}",
+            second - first
+        );
+        assert!(client.state_history().iter().any(|t| t.reason == "no pong"));
+        // The error kept is why the link broke, not the state's reason
+        assert_eq!(client.controls.telemetry.link.last_error().as_deref(), Some("connection lost: no pong"));
+
+        // The Suite that answers has its round trip measured
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.connection_stats().status().round_trip_us.is_none() {
+            assert!(Instant::now() < deadline, "no round trip measured");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(client.is_connected());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn test_self_signed_suite_needs_a_pin() {
+        use crate::protocol::PACKET_TYPE_HELLO;
+        use rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+
+        // A Suite with a self-signed certificate for 127.0.0.1, reporting the
+        // type of every packet that reaches it
+        let cert = include_bytes!("../tls_fixtures/suite-cert.der");
+        let key = include_bytes!("../tls_fixtures/suite-key.der");
+        let config = Arc::new(
+            rustls::ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+                .with_safe_default_protocol_versions()
+                .unwrap()
+                .with_no_client_auth()
+                .with_single_cert(
+                    vec![CertificateDer::from(cert.to_vec())],
+                    PrivateKeyDer::Pkcs8(PrivatePkcs8KeyDer::from(key.to_vec())),
+                )
+                .unwrap(),
+        );
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, received) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let connection = rustls::ServerConnection::new(Arc::clone(&config)).unwrap();
+                // A client that turned the certificate down fails here
+                let Ok(mut ws) = tungstenite::accept(rustls::StreamOwned::new(connection, stream.unwrap())) else {
+                    continue;
+                };
+                while let Ok(message) = ws.read() {
+                    if let Message::Binary(data) = message {
+                        let _ = tx.send(AudioPacket::from_bytes(&data).unwrap().packet_type);
+                    }
+                }
+            }
+        });
+
+        let dir = std::env::temp_dir().join(format!("hardwave-ws-tls-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let mut client = WebSocketClient::new();
+        client.controls.encryption.tls.set_pins(TlsPins::load_from(dir.join("tls-pins")).unwrap());
+        client.controls.encryption.tls.set_enabled(true);
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        // Turned down, in the error state, with the fingerprint to accept
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let last = loop {
+            if let Some(last) = client.error_stats().report().last {
+                break last;
+            }
+            assert!(Instant::now() < deadline, "the certificate was never turned down");
+            thread::sleep(Duration::from_millis(5));
+        };
+        let fingerprint = client.controls.encryption.tls.untrusted().unwrap();
+        assert_eq!(fingerprint, tls::fingerprint(cert));
+        assert!(last.contains(&fingerprint), "{}", last);
+        assert!(client.state_history().iter().any(|t| t.to == ConnectionState::Error && t.reason == "TLS refused"));
+        assert!(received.try_recv().is_err(), "sent to a Suite it didn't trust");
+
+        // Accepted, the pin is saved and the connection goes through
+        client.controls.encryption.tls.trust(&fingerprint).unwrap();
+        client.controls.suite.onboarding.request_retry();
+        wait_connected(&client);
+        assert_eq!(received.recv_timeout(Duration::from_secs(5)).unwrap(), PACKET_TYPE_HELLO);
+        client.send(fft_frame(7)).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while received.recv_deadline(deadline).unwrap() != PACKET_TYPE_FFT {}
+        let pins = TlsPins::load_from(dir.join("tls-pins")).unwrap();
+        assert_eq!(pins.fingerprint("127.0.0.1"), Some(fingerprint.as_str()));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(not(feature = "tls"))]
+    #[test]
+    fn test_tls_without_the_feature_never_connects() {
+        // What each connection sent before it closed
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut sent = Vec::new();
+                let _ = stream.unwrap().read_to_end(&mut sent);
+                let _ = tx.send(sent);
+            }
+        });
+        let mut client = WebSocketClient::new();
+        client.controls.encryption.tls.set_enabled(true);
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !client.state_history().iter().any(|t| t.to == ConnectionState::Error && t.reason == "TLS refused") {
+            assert!(Instant::now() < deadline, "never refused");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!client.is_connected());
+        assert_eq!(rx.recv_timeout(Duration::from_secs(5)).unwrap(), b"", "sent in the clear");
+    }
+
+    #[test]
+    fn test_dropping_the_client_closes_cleanly() {
+        use crate::protocol::PACKET_TYPE_GOODBYE;
+
+        // The Suite keeps what arrives, answers the close, then reports the
+        // goodbye's instance ID and the close frame
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            let mut goodbye = None;
+            loop {
+                match ws.read() {
+                    Ok(Message::Binary(data)) => {
+                        let packet = AudioPacket::from_bytes(&data).unwrap();
+                        if packet.packet_type == PACKET_TYPE_GOODBYE {
+                            goodbye = Some(packet.instance_id);
+                        }
+                    }
+                    Ok(Message::Close(frame)) => {
+                        let _ = tx.send((goodbye.take(), frame));
+                    }
+                    Ok(_) => {}
+                    Err(_) => break,
+                }
+            }
+        });
+
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        let instance_id = client.controls.suite.display.lock().instance_id.clone();
+
+        let dropped = Instant::now();
+        drop(client);
+        assert!(dropped.elapsed() < DROP_TIMEOUT + Duration::from_millis(100), "{:?}", dropped.elapsed());
+        let (goodbye, frame) = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(goodbye, Some(instance_id), "goodbye before the close frame");
+        assert_eq!(frame.map(|frame| frame.code), Some(CloseCode::Normal));
+    }
+
+    #[test]
+    fn test_dropping_the_client_never_waits_long() {
+        // A Suite that accepts the connection and never reads, so the
+        // close goes unanswered
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let _ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            thread::sleep(Duration::from_secs(30));
+        });
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+
+        let dropped = Instant::now();
+        drop(client);
+        assert!(dropped.elapsed() < DROP_TIMEOUT + Duration::from_millis(100), "{:?}", dropped.elapsed());
+    }
+
+    #[test]
+    fn test_port_change_moves_the_connection() {
+        let (old_port, old_rx) = slow_mock_server(Duration::ZERO);
+        let (new_port, new_rx) = slow_mock_server(Duration::ZERO);
+        let mut client = WebSocketClient::new();
+        client.set_port(old_port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        client.send(fft_frame(1)).unwrap();
+        let arrived = |rx: &Receiver<AudioPacket>, timestamp_ms| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while let Ok(packet) = rx.recv_deadline(deadline) {
+                if packet.packet_type == PACKET_TYPE_FFT && packet.timestamp_ms == timestamp_ms {
+                    return true;
+                }
+            }
+            false
+        };
+        assert!(arrived(&old_rx, 1));
+
+        // Within a second the new port has packets, and the old Suite saw the
+        // connection close
+        let moved = Instant::now();
+        client.set_port(new_port as i32);
+        client.send(fft_frame(2)).unwrap();
+        assert!(arrived(&new_rx, 2));
+        assert!(moved.elapsed() < Duration::from_secs(1), "moved after {:?}", moved.elapsed());
+        // Its thread ends with the connection, and drops the sender
+        let closed = loop {
+            match old_rx.recv_timeout(Duration::from_secs(5)) {
+                Ok(_) => continue,
+                Err(e) => break e.is_disconnected(),
+            }
+        };
+        assert!(closed, "the old connection is still open");
+        assert!(client.state_history().iter().any(|t| t.reason == "port changed"));
+        assert!(client.is_connected());
+    }
+
+    #[test]
+    fn test_own_packet_server_port_is_refused() {
+        // Something answers on it, but the plugin window's packet server
+        // isn't the Suite
+        let (port, _rx) = slow_mock_server(Duration::ZERO);
+        ports::register_packet_server(port);
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        let start = Instant::now();
+        while client.connection_stats().status().connection != ConnectionState::Error {
+            assert!(start.elapsed() < Duration::from_secs(5), "never refused");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let status = client.connection_stats().status();
+        assert_eq!(status.port_warning, Some(PortWarning::PacketServer.to_string()));
+        assert!(status.last_error.as_deref().unwrap().contains("packet server"));
+        assert!(!client.is_connected());
+
+        // Once the packet server is gone the port is the Suite's
+        ports::unregister_packet_server(port);
+        wait_connected(&client);
+        // An ephemeral port may still be warned about
+        assert_ne!(client.connection_stats().status().port_warning, status.port_warning);
+    }
+
+    #[test]
+    fn test_frames_and_heartbeats_are_numbered_together() {
+        let (client, clock, rx) = stepped_client();
+
+        // Frames numbered as the plugin numbers them, one per step so none
+        // is coalesced, and a heartbeat every third step
+        let sequence = client.packet_sequence();
+        let mut packets = Vec::new();
+        for timestamp_ms in 0..8 {
+            let mut frame = fft_frame(timestamp_ms);
+            sequence.stamp(&mut frame);
+            client.send(frame).unwrap();
+            packets.extend(step(&client, &clock, &rx, HEARTBEAT_INTERVAL / 3 + Duration::from_millis(1)));
+        }
+        assert_eq!(packets.first().map(|p| p.sequence), Some(0), "the hello is the first");
+        assert_eq!(count(&packets, PACKET_TYPE_HEARTBEAT), 2);
+        assert_eq!(count(&packets, PACKET_TYPE_FFT), 8);
+        // A heartbeat can overtake the frame numbered just before it
+        let mut numbers: Vec<u32> = packets.iter().map(|p| p.sequence).collect();
+        numbers.sort_unstable();
+        assert_eq!(numbers, (0..packets.len() as u32).collect::<Vec<u32>>(), "a gap without a drop");
+    }
+
+    fn local_destination(port: u16) -> Destination {
+        Destination { host: discovery::LOCAL_HOST.to_string(), port, enabled: true }
+    }
+
+    fn wait_destination_connected(client: &WebSocketClient, port: u16) {
+        let start = Instant::now();
+        while !client
+            .destination_statuses()
+            .iter()
+            .any(|status| status.destination.port == port && status.connection == ConnectionState::Connected)
+        {
+            assert!(start.elapsed() < Duration::from_secs(5), "destination never connected");
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+
+    /// The sequence numbers of the frames among `packets`
+    fn frame_numbers(packets: &[AudioPacket]) -> Vec<u32> {
+        packets.iter().filter(|p| p.packet_type == PACKET_TYPE_FFT).map(|p| p.sequence).collect()
+    }
+
+    /// Send frames numbered as the plugin numbers them, spaced so none is
+    /// coalesced
+    fn send_spaced_frames(client: &WebSocketClient, count: u64) {
+        let sequence = client.packet_sequence();
+        for timestamp_ms in 0..count {
+            let mut frame = fft_frame(timestamp_ms);
+            sequence.stamp(&mut frame);
+            client.send(frame).unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    #[test]
+    fn test_destinations_get_the_same_packets() {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let (other_port, other_rx) = slow_mock_server(Duration::ZERO);
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.set_destinations(&[local_destination(other_port)]);
+        assert_eq!(client.destination_statuses()[0].connection, ConnectionState::Disconnected, "not started");
+        client.start().unwrap();
+        wait_connected(&client);
+        wait_destination_connected(&client, other_port);
+
+        send_spaced_frames(&client, 8);
+        let numbers = frame_numbers(&collect_for(&rx, Duration::from_millis(200)));
+        assert_eq!(numbers.len(), 8);
+        assert_eq!(frame_numbers(&collect_for(&other_rx, Duration::from_millis(200))), numbers);
+
+        // Still listed, still the same connection; the mock takes no other.
+        // One switched off is listed, and not connected to.
+        let off = Destination { enabled: false, ..local_destination(other_port + 1) };
+        client.set_destinations(&[local_destination(other_port), off.clone()]);
+        let statuses = client.destination_statuses();
+        assert_eq!(statuses.len(), 2);
+        assert_eq!(statuses[0].connection, ConnectionState::Connected);
+        assert_eq!(statuses[1], DestinationStatus { destination: off, connection: ConnectionState::Disconnected });
+        let json = serde_json::to_value(&statuses[0]).unwrap();
+        assert_eq!(json["port"], other_port);
+        assert_eq!(json["connection"], "connected");
+    }
+
+    #[test]
+    fn test_a_dead_destination_holds_up_no_other() {
+        // Nothing listening at the Port parameter, and a Suite that
+        // accepts the connection and never reads from it
+        let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let stuck = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stuck_port = stuck.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (stream, _) = stuck.accept().unwrap();
+            let _ws = tungstenite::accept(stream).unwrap();
+            thread::sleep(Duration::from_secs(10));
+        });
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+
+        let mut client = WebSocketClient::new();
+        client.set_port(closed as i32);
+        client.set_destinations(&[local_destination(stuck_port), local_destination(port)]);
+        client.start().unwrap();
+        wait_destination_connected(&client, port);
+
+        send_spaced_frames(&client, 8);
+        assert_eq!(frame_numbers(&collect_for(&rx, Duration::from_millis(200))).len(), 8);
+        assert!(!client.is_connected());
+    }
+
+    #[test]
+    fn test_editor_events_and_heartbeat_state() {
+        use crate::protocol::PACKET_TYPE_EDITOR;
+
+        let (client, clock, rx) = stepped_client();
+
+        // Each seen by the connection before the next
+        let editor = client.editor_lifecycle();
+        editor.opened();
+        settle(&clock);
+        editor.closed();
+        settle(&clock);
+        // Rapid reopen: new window before the old one is dropped
+        editor.opened();
+        editor.opened();
+        editor.closed();
+
+        let packets = step(&client, &clock, &rx, HEARTBEAT_INTERVAL);
+        let events: Vec<(u32, bool)> = packets
+            .iter()
+            .filter(|p| p.packet_type == PACKET_TYPE_EDITOR)
+            .map(|p| (p.editor_seq, p.editor_open))
+            .collect();
+        let heartbeats: Vec<&AudioPacket> =
+            packets.iter().filter(|p| p.packet_type == PACKET_TYPE_HEARTBEAT).collect();
+        let [final_heartbeat] = heartbeats[..] else {
+            panic!("expected one heartbeat for the step, got {}", heartbeats.len());
+        };
+
+        assert_eq!(events, vec![(1, true), (2, false), (3, true)]);
+        assert!(final_heartbeat.editor_open);
+        assert_eq!(final_heartbeat.editor_seq, 3);
+    }
+
+    /// Everything the server receives during `window`
+    fn collect_for(rx: &Receiver<AudioPacket>, window: Duration) -> Vec<AudioPacket> {
+        let deadline = Instant::now() + window;
+        let mut packets = Vec::new();
+        while let Some(left) = deadline.checked_duration_since(Instant::now()) {
+            if let Ok(packet) = rx.recv_timeout(left) {
+                packets.push(packet);
+            }
+        }
+        packets
+    }
 
-    /// Current connection state
-    state: Arc<Mutex<ConnectionState>>,
+    fn count(packets: &[AudioPacket], packet_type: u8) -> usize {
+        packets.iter().filter(|p| p.packet_type == packet_type).count()
+    }
 
-    /// Flag to signal shutdown
-    shutdown: Arc<AtomicBool>,
+    #[test]
+    fn test_suspension_status_and_heartbeat_cadence() {
+        use crate::protocol::PACKET_TYPE_STATUS;
 
-    /// Background thread handle
-    thread_handle: Option<JoinHandle<()>>,
+        let heartbeats = |packets: &[AudioPacket]| count(packets, PACKET_TYPE_HEARTBEAT);
+        let statuses = |packets: &[AudioPacket]| -> Vec<bool> {
+            packets
+                .iter()
+                .filter(|p| p.packet_type == PACKET_TYPE_STATUS)
+                .map(|p| p.streaming_suspended)
+                .collect()
+        };
 
-    /// Current server port
-    server_port: Arc<Mutex<u16>>,
-}
+        let (client, clock, rx) = stepped_client();
 
-impl WebSocketClient {
-    /// Create a new WebSocket client. Does NOT start the connection thread yet —
-    /// call `start()` after the plugin is initialised to avoid blocking DAW
-    /// plugin scans.
-    pub fn new() -> Self {
-        let (packet_sender, _packet_receiver) = bounded::<AudioPacket>(32);
-        let state = Arc::new(Mutex::new(ConnectionState::Disconnected));
-        let shutdown = Arc::new(AtomicBool::new(false));
-        let server_port = Arc::new(Mutex::new(9847u16));
+        assert_eq!(heartbeats(&step(&client, &clock, &rx, HEARTBEAT_INTERVAL / 2)), 0);
+        let streaming = step(&client, &clock, &rx, HEARTBEAT_INTERVAL / 2);
+        assert_eq!(heartbeats(&streaming), 1, "one a second");
+        assert!(statuses(&streaming).is_empty());
 
-        Self {
-            packet_sender,
-            state,
-            shutdown,
-            thread_handle: None,
-            server_port,
-        }
+        client.set_suspended(true);
+        let suspended = step(&client, &clock, &rx, HEARTBEAT_INTERVAL * 3);
+        assert_eq!(statuses(&suspended), vec![true], "one suspension announcement");
+        assert_eq!(heartbeats(&suspended), 0, "heartbeats should slow to every 10 s");
+        let suspended = step(&client, &clock, &rx, SUSPENDED_HEARTBEAT_INTERVAL - HEARTBEAT_INTERVAL * 3);
+        assert_eq!(heartbeats(&suspended), 1);
+        assert!(client.is_connected(), "connection is held while suspended");
+
+        client.set_suspended(false);
+        let resumed = step(&client, &clock, &rx, HEARTBEAT_INTERVAL);
+        assert_eq!(statuses(&resumed), vec![false]);
+        assert_eq!(heartbeats(&resumed), 1);
     }
 
-    /// Start the background connection thread. Safe to call multiple times —
-    /// only the first call spawns the thread.
-    pub fn start(&mut self) {
-        if self.thread_handle.is_some() {
-            return;
+    #[test]
+    fn test_heartbeats_metrics_fields() {
+        let (client, clock, rx) = stepped_client();
+        let heartbeat = |by: Duration| -> AudioPacket {
+            let mut heartbeats: Vec<AudioPacket> = step(&client, &clock, &rx, by)
+                .into_iter()
+                .filter(|p| p.packet_type == PACKET_TYPE_HEARTBEAT)
+                .collect();
+            assert_eq!(heartbeats.len(), 1, "one heartbeat per step");
+            heartbeats.remove(0)
+        };
+
+        let opted_out = heartbeat(HEARTBEAT_INTERVAL);
+        assert!(!opted_out.metrics_opt_in && opted_out.metrics.is_none());
+
+        // Opting in shows up at once; the first blob waits a full interval
+        client.set_metrics_opt_in(true);
+        for _ in 0..2 {
+            let opted_in = heartbeat(HEARTBEAT_INTERVAL);
+            assert!(opted_in.metrics_opt_in && opted_in.metrics.is_none());
         }
+        let blob = heartbeat(metrics::METRICS_INTERVAL).metrics.expect("a blob a minute");
+        assert_eq!(blob.interval_s as u64, metrics::METRICS_INTERVAL.as_secs() + 2);
+    }
+
+    #[test]
+    fn test_heartbeats_carry_display_hints() {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let clock = Clock::manual();
+        let mut client = WebSocketClient::with_clock(clock.clone());
+        let hints = DisplayHints {
+            instance_id: "3f2b8c1e-9d4a-4e6b-8a7f-0c5d2e1b9a34".to_string(),
+            label: "Kick".to_string(),
+            color: "#ff8800".to_string(),
+            order: 3,
+            channel_labels: None,
+        };
+        client.set_display_hints(hints.clone());
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
 
-        let (packet_sender, packet_receiver) = bounded::<AudioPacket>(32);
-        self.packet_sender = packet_sender;
+        let heartbeat = step(&client, &clock, &rx, HEARTBEAT_INTERVAL)
+            .into_iter()
+            .find(|p| p.packet_type == PACKET_TYPE_HEARTBEAT)
+            .expect("no heartbeat");
+        assert_eq!(heartbeat.instance_id, hints.instance_id);
+        assert_eq!(heartbeat.display_color, "#ff8800");
+        assert_eq!(heartbeat.display_order, 3);
+        assert_eq!(heartbeat.display_label, "Kick");
+        assert_eq!(heartbeat.channel_labels, ["", ""], "following the channel mode");
 
-        let state_clone = Arc::clone(&self.state);
-        let shutdown_clone = Arc::clone(&self.shutdown);
-        let port_clone = Arc::clone(&self.server_port);
+        // Changes from the editor reach the next heartbeat
+        client.display_hints().lock().color = "#00aaff".to_string();
+        let later = step(&client, &clock, &rx, HEARTBEAT_INTERVAL);
+        assert!(later
+            .iter()
+            .any(|p| p.packet_type == PACKET_TYPE_HEARTBEAT && p.display_color == "#00aaff"));
 
-        self.thread_handle = Some(thread::spawn(move || {
-            Self::connection_loop(packet_receiver, state_clone, shutdown_clone, port_clone);
-        }));
+        // A new name goes out at once, not a second later with the next one
+        client.display_hints().lock().label = "Drum Bus".to_string();
+        let renamed = step(&client, &clock, &rx, Duration::ZERO);
+        assert!(renamed
+            .iter()
+            .any(|p| p.packet_type == PACKET_TYPE_HEARTBEAT && p.display_label == "Drum Bus"));
+
+        // and so do new channel labels
+        client.display_hints().lock().channel_labels = Some(["Mix".to_string(), "Reference".to_string()]);
+        let relabelled = step(&client, &clock, &rx, Duration::ZERO);
+        assert!(relabelled
+            .iter()
+            .any(|p| p.packet_type == PACKET_TYPE_HEARTBEAT && p.channel_labels == ["Mix", "Reference"]));
     }
 
-    /// Update the server port
-    pub fn set_port(&self, port: i32) {
-        let mut p = self.server_port.lock();
-        *p = port as u16;
+    #[test]
+    fn test_heartbeats_carry_the_session() {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let clock = Clock::manual();
+        let mut client = WebSocketClient::with_clock(clock.clone());
+        client.set_metadata(Metadata { sample_rate: 44_100, ..Metadata::default() });
+        client.set_session(false, 1234);
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+
+        // Disabled, so there are no frames, but the Suite still hears of it
+        let heartbeat = step(&client, &clock, &rx, HEARTBEAT_INTERVAL)
+            .into_iter()
+            .find(|p| p.packet_type == PACKET_TYPE_HEARTBEAT)
+            .expect("no heartbeat");
+        assert_eq!((heartbeat.sample_rate, heartbeat.timestamp_ms), (44_100, 1234));
+        assert!(heartbeat.disabled);
+        assert_eq!(heartbeat.instance_id, client.display_hints().lock().instance_id);
+
+        // Enabled again, at a new rate
+        client.set_metadata(Metadata { sample_rate: 96_000, ..Metadata::default() });
+        client.set_session(true, 5678);
+        let later = step(&client, &clock, &rx, HEARTBEAT_INTERVAL);
+        assert!(later.iter().any(|p| p.packet_type == PACKET_TYPE_HEARTBEAT
+            && (p.sample_rate, p.timestamp_ms, p.disabled) == (96_000, 5678, false)));
     }
 
-    /// Get the current connection state
-    pub fn connection_state(&self) -> ConnectionState {
-        *self.state.lock()
+    #[test]
+    fn test_heartbeats_count_state_transitions() {
+        let (client, clock, rx) = stepped_client();
+        let heartbeat = step(&client, &clock, &rx, HEARTBEAT_INTERVAL)
+            .into_iter()
+            .find(|p| p.packet_type == PACKET_TYPE_HEARTBEAT)
+            .expect("no heartbeat");
+        assert_eq!(heartbeat.state_transitions as usize, client.state_history().len());
+        assert!(heartbeat.state_transitions >= 2, "connecting, connected");
+        assert_eq!(heartbeat.rejected_transitions, 0);
     }
 
-    /// Check if connected
-    pub fn is_connected(&self) -> bool {
-        self.connection_state() == ConnectionState::Connected
+    #[test]
+    fn test_heartbeats_report_a_processing_gap() {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+
+        // A stand-in audio thread, processing 10 ms blocks until told to stop
+        let host = |watchdog: Arc<ProcessWatchdog>| {
+            let processing = Arc::new(AtomicBool::new(true));
+            let flag = Arc::clone(&processing);
+            let handle = thread::spawn(move || {
+                while flag.load(Ordering::Relaxed) {
+                    watchdog.beat();
+                    thread::sleep(Duration::from_millis(10));
+                }
+            });
+            (processing, handle)
+        };
+        let heartbeats = |window: Duration| -> Vec<u32> {
+            collect_for(&rx, window)
+                .iter()
+                .filter(|p| p.packet_type == PACKET_TYPE_HEARTBEAT)
+                .map(|p| p.stale_ms)
+                .collect()
+        };
+
+        let (processing, audio) = host(client.process_watchdog());
+        let live = heartbeats(Duration::from_millis(1500));
+        assert!(!live.is_empty());
+        assert!(live.iter().all(|&ms| ms == 0), "stale while processing: {:?}", live);
+
+        // The host stops calling process()
+        processing.store(false, Ordering::Relaxed);
+        audio.join().unwrap();
+        let stale = heartbeats(Duration::from_millis(3500));
+        let rising: Vec<u32> = stale.into_iter().filter(|&ms| ms > 0).collect();
+        assert!(rising.len() >= 2, "expected stale heartbeats, got {:?}", rising);
+        assert!(rising.windows(2).all(|w| w[1] > w[0]), "staleness should rise: {:?}", rising);
+        assert!(rising.iter().all(|&ms| ms as u64 > crate::watchdog::STALE_AFTER_MS));
+
+        // Processing again clears it
+        let (processing, audio) = host(client.process_watchdog());
+        let resumed = heartbeats(Duration::from_millis(1500));
+        processing.store(false, Ordering::Relaxed);
+        audio.join().unwrap();
+        assert!(!resumed.is_empty());
+        assert!(resumed.iter().all(|&ms| ms == 0), "stale after resuming: {:?}", resumed);
     }
 
-    /// Send an audio packet (non-blocking)
-    pub fn send(&self, packet: AudioPacket) {
-        // Don't block the audio thread - drop packets if queue is full
-        let _ = self.packet_sender.try_send(packet);
+    #[test]
+    fn test_send_says_why_a_packet_was_not_queued() {
+        let mut client = WebSocketClient::new();
+        assert!(matches!(
+            client.send(fft_frame(0)),
+            Err(HardwaveError::Transport(TransportError::NotStarted))
+        ));
+        assert_eq!(client.controls.telemetry.usage.totals().produced, 0, "nothing to count before start");
+
+        // Nothing listens on port 1, so nothing is sent. Each frame takes
+        // the place of the one before and the drop rate counts that; the
+        // events fill their queue, and the bus drops the one that doesn't
+        // fit.
+        client.set_port(1);
+        client.start().unwrap();
+        let sent = EVENT_QUEUE_LEN as u64 + 1;
+        for i in 0..sent {
+            client.send(fft_frame(i)).unwrap();
+            client.send(event(i)).unwrap();
+        }
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while client.controls.telemetry.usage.totals().produced < sent || client.controls.telemetry.link.report().packets_dropped < sent {
+            assert!(Instant::now() < deadline, "the bus didn't hand the packets on");
+            thread::sleep(Duration::from_millis(1));
+        }
+        let totals = client.controls.telemetry.usage.totals();
+        assert_eq!((totals.produced, totals.dropped), (sent, sent - 1));
+        assert_eq!(client.controls.telemetry.link.report().packets_dropped, sent, "the link counts both");
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while client.controls.telemetry.link.report().last_error.is_none() {
+            assert!(Instant::now() < deadline, "the failed connect wasn't kept");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(client.controls.telemetry.link.report().packets_sent, 0);
+
+        // The connection thread stops and takes its subscription with it
+        client.shutdown.store(true, Ordering::Relaxed);
+        // Within one reconnect delay
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !client.thread_handle.as_ref().unwrap().is_finished() {
+            assert!(Instant::now() < deadline, "connection thread didn't stop");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(matches!(
+            client.send(fft_frame(0)),
+            Err(HardwaveError::Transport(TransportError::Closed))
+        ));
     }
 
-    /// Background connection loop
-    fn connection_loop(
-        receiver: Receiver<AudioPacket>,
-        state: Arc<Mutex<ConnectionState>>,
-        shutdown: Arc<AtomicBool>,
-        server_port: Arc<Mutex<u16>>,
-    ) {
-        let mut reconnect_delay = Duration::from_millis(100);
-        let max_reconnect_delay = Duration::from_secs(5);
+    #[test]
+    fn test_connect_errors() {
+        // A closed port: the Suite isn't running
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        assert!(matches!(
+            WebSocketClient::try_connect(&SuiteAddress::local(port), None, None, WireFormat::Canonical, None),
+            Err(TransportError::Connect(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused
+        ));
 
-        while !shutdown.load(Ordering::Relaxed) {
-            // Get current port
-            let port = *server_port.lock();
+        // Something else on the port, answering HTTP without an upgrade
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0u8; 1024];
+            let _ = stream.read(&mut request);
+            let _ = stream.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+        });
+        assert!(matches!(
+            WebSocketClient::try_connect(&SuiteAddress::local(port), None, None, WireFormat::Canonical, None),
+            Err(TransportError::Handshake(_))
+        ));
+    }
 
-            // Try to connect
-            *state.lock() = ConnectionState::Connecting;
+    #[test]
+    fn test_browsing_falls_back_to_the_port() {
+        // No Suite advertises itself, or this build can't browse
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let mut client = WebSocketClient::new();
+        *client.controls.transport.discovery.lock() = Discovery::Browse;
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert!(client.state_history().iter().any(|t| t.to == ConnectionState::Discovering));
+    }
 
-            match Self::try_connect(port) {
-                Ok(mut socket) => {
-                    *state.lock() = ConnectionState::Connected;
-                    reconnect_delay = Duration::from_millis(100);
+    #[test]
+    fn test_udp_sends_the_same_packets_as_datagrams() {
+        use crate::protocol::PACKET_TYPE_HELLO;
+        use crate::udp;
 
-                    // Handle connection
-                    Self::handle_connection(&mut socket, &receiver, &state, &shutdown);
+        let listener = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        listener.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+        let mut client = WebSocketClient::new();
+        *client.controls.transport.kind.lock() = Transport::Udp;
+        client.set_port(listener.local_addr().unwrap().port() as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        client.send(fft_frame(42)).unwrap();
+
+        // Until a frame and a heartbeat are in: no connection, so heartbeats
+        // are how the Suite knows the plugin is there
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut datagrams = Vec::new();
+        let mut buf = [0u8; udp::MAX_DATAGRAM_BYTES];
+        let packets = loop {
+            assert!(Instant::now() < deadline, "no frame and heartbeat arrived");
+            if let Ok(n) = listener.recv(&mut buf) {
+                datagrams.push(buf[..n].to_vec());
+            }
+            let packets: Vec<AudioPacket> =
+                udp::reassemble(&datagrams).iter().map(|data| AudioPacket::from_bytes(data).unwrap()).collect();
+            let has = |packet_type| packets.iter().any(|packet| packet.packet_type == packet_type);
+            if has(PACKET_TYPE_FFT) && has(PACKET_TYPE_HEARTBEAT) {
+                break packets;
+            }
+        };
+        assert_eq!(packets[0].packet_type, PACKET_TYPE_HELLO);
+        let frame = packets.iter().find(|packet| packet.packet_type == PACKET_TYPE_FFT).unwrap();
+        assert_eq!((frame.timestamp_ms, frame.left_bins.len()), (42, NUM_BINS));
+        assert!(datagrams.len() > packets.len(), "the frame took several datagrams");
+        assert!(datagrams.iter().all(|datagram| datagram.len() <= udp::MAX_DATAGRAM_BYTES));
+    }
+
+    #[test]
+    fn test_connects_to_the_advertised_suite() {
+        // A Suite advertising a port the Port parameter doesn't say; the
+        // answer is injected, so no multicast is needed
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let wrong_port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = WebSocketClient::new();
+        *client.controls.transport.discovery.lock() = Discovery::Advertised(SuiteAddress::local(port));
+        client.set_port(wrong_port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        assert!(rx.recv_timeout(Duration::from_secs(5)).is_ok(), "nothing arrived at the advertised port");
+        let history = client.state_history();
+        let browsing = history.iter().position(|t| t.to == ConnectionState::Discovering).unwrap();
+        let connecting = history.iter().position(|t| t.to == ConnectionState::Connecting).unwrap();
+        assert!(browsing < connecting, "browsed before connecting");
+    }
+
+    /// Account token with `"stream_exchange": true`
+    const EXCHANGE_JWT: &str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCJ9.eyJzdWIiOiJ1c2VyLTEiLCJzdHJlYW1fZXhjaGFuZ2UiOnRydWV9.sig";
+
+    /// What one connection to `exchange_mock_server` brought
+    #[derive(Debug)]
+    struct Visit {
+        authorization: Option<String>,
+        texts: Vec<String>,
+        packets: usize,
+    }
+
+    /// Accept connections one after another, each held open for `hold`. The
+    /// `n`th connection answers an exchange request with `reply(n)`.
+    fn exchange_mock_server(reply: fn(usize) -> &'static str, hold: Duration) -> (u16, Receiver<Visit>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (n, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else { return };
+                let mut authorization = None;
+                let header = |request: &tungstenite::handshake::server::Request, response| {
+                    authorization = request
+                        .headers()
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string);
+                    Ok(response)
+                };
+                let Ok(mut ws) = tungstenite::accept_hdr(stream, header) else { continue };
+                ws.get_ref().set_read_timeout(Some(Duration::from_millis(10))).unwrap();
+
+                let mut visit = Visit { authorization, texts: Vec::new(), packets: 0 };
+                let until = Instant::now() + hold;
+                while Instant::now() < until {
+                    match ws.read() {
+                        Ok(Message::Text(text)) => {
+                            if text.contains("token_exchange") {
+                                let _ = ws.send(Message::Text(reply(n).to_string()));
+                            }
+                            visit.texts.push(text);
+                        }
+                        Ok(Message::Binary(_)) => visit.packets += 1,
+                        Ok(_) | Err(tungstenite::Error::Io(_)) => {}
+                        Err(_) => break,
+                    }
+                }
+                if tx.send(visit).is_err() {
+                    return;
                 }
-                Err(_) => {
-                    *state.lock() = ConnectionState::Disconnected;
+            }
+        });
+        (port, rx)
+    }
+
+    fn exchanging_client(port: u16) -> WebSocketClient {
+        let mut client = WebSocketClient::new();
+        *client.controls.auth.stream_auth.lock() = StreamAuth::with_loader(|| Ok(Some(EXCHANGE_JWT.to_string())));
+        client.set_port(port as i32);
+        client.start().unwrap();
+        client
+    }
+
+    /// Accept clients one after another, agreeing to encryption if `agree`,
+    /// and forward every binary message as it came
+    fn encrypting_mock_server(agree: bool) -> (u16, Receiver<Vec<u8>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let header = |request: &tungstenite::handshake::server::Request,
+                              mut response: tungstenite::handshake::server::Response| {
+                    match request.headers().get("x-hardwave-encryption") {
+                        Some(asked) if agree => {
+                            response.headers_mut().insert("x-hardwave-encryption", asked.clone());
+                        }
+                        _ => {}
+                    }
+                    Ok(response)
+                };
+                let Ok(mut ws) = tungstenite::accept_hdr(stream, header) else { continue };
+                while let Ok(msg) = ws.read() {
+                    if let Message::Binary(data) = msg {
+                        if tx.send(data).is_err() {
+                            return;
+                        }
+                    }
                 }
             }
+        });
+        (port, rx)
+    }
+
+    fn packet_key() -> PacketKey {
+        PacketKey::parse(&"2a".repeat(32)).unwrap()
+    }
 
-            // Wait before reconnecting
-            if !shutdown.load(Ordering::Relaxed) {
-                thread::sleep(reconnect_delay);
-                reconnect_delay = (reconnect_delay * 2).min(max_reconnect_delay);
+    #[test]
+    fn test_handshake_says_the_protocol_version() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let header = |request: &tungstenite::handshake::server::Request,
+                          response: tungstenite::handshake::server::Response| {
+                let version = request.headers().get("x-hardwave-protocol").and_then(|value| value.to_str().ok());
+                let _ = tx.send(version.map(str::to_string));
+                Ok(response)
+            };
+            let _ = tungstenite::accept_hdr(stream, header);
+        });
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        let version = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(version, Some(PROTOCOL_VERSION.to_string()));
+    }
+
+    #[test]
+    fn test_packets_are_sealed_with_the_key() {
+        let (port, rx) = encrypting_mock_server(true);
+        let mut client = WebSocketClient::new();
+        client.packet_encryption().set(Some(packet_key()));
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        client.send(fft_frame(77)).unwrap();
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let frame = loop {
+            assert!(Instant::now() < deadline, "no frame arrived");
+            let Ok(data) = rx.recv_timeout(Duration::from_millis(100)) else {
+                continue;
+            };
+            let (_, plain) = packet_crypto::open(&packet_key(), &data).expect("not sealed with the key");
+            let packet = AudioPacket::from_bytes(&plain).unwrap();
+            if packet.packet_type == PACKET_TYPE_FFT {
+                break packet;
             }
+        };
+        assert_eq!(frame.timestamp_ms, 77);
+    }
+
+    #[test]
+    fn test_suite_without_encryption_gets_nothing() {
+        let (port, rx) = encrypting_mock_server(false);
+        let mut client = WebSocketClient::new();
+        client.packet_encryption().set(Some(packet_key()));
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let start = Instant::now();
+        while client.connection_state() != ConnectionState::Error {
+            assert!(start.elapsed() < Duration::from_secs(5), "never refused");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let _ = client.send(fft_frame(0));
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err(), "a packet went out");
+        assert!(client.error_stats().count(Layer::Transport) > 0);
+        let last = client.error_stats().report().last.unwrap();
+        assert!(last.contains("doesn't support packet encryption"), "{}", last);
+
+        // Without the key it streams as before
+        client.packet_encryption().set(None);
+        wait_connected(&client);
+        let data = rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(AudioPacket::from_bytes(&data).is_ok());
+    }
+
+    #[test]
+    fn test_stream_token_is_exchanged_once_and_reused() {
+        let (port, visits) = exchange_mock_server(
+            |_| r#"{"type":"stream_token","token":"stream-1","expires_in":3600}"#,
+            Duration::from_millis(300),
+        );
+        let _client = exchanging_client(port);
+
+        let first = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(first.authorization, Some(format!("Bearer {}", EXCHANGE_JWT)));
+        assert_eq!(first.texts.len(), 1);
+        assert!(first.texts[0].contains(EXCHANGE_JWT));
+
+        // Reconnects use the stream token instead
+        for _ in 0..2 {
+            let visit = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+            assert_eq!(visit.authorization.as_deref(), Some("Bearer stream-1"));
+            assert!(visit.texts.is_empty(), "{:?}", visit.texts);
         }
     }
 
-    /// Try to establish a WebSocket connection
-    fn try_connect(port: u16) -> Result<WebSocket<TcpStream>, ()> {
-        let addr = format!("127.0.0.1:{}", port);
+    #[test]
+    fn test_expired_stream_token_is_exchanged_again() {
+        let (port, visits) = exchange_mock_server(
+            |n| match n {
+                0 => r#"{"type":"stream_token","token":"stream-1","expires_in":0}"#,
+                _ => r#"{"type":"stream_token","token":"stream-2","expires_in":3600}"#,
+            },
+            Duration::from_millis(300),
+        );
+        let _client = exchanging_client(port);
 
-        // Connect with timeout
-        let stream = TcpStream::connect_timeout(
-            &addr.parse().map_err(|_| ())?,
-            Duration::from_secs(2),
-        )
-        .map_err(|_| ())?;
-
-        stream.set_nonblocking(false).ok();
-        stream.set_read_timeout(Some(Duration::from_millis(100))).ok();
-        stream.set_write_timeout(Some(Duration::from_millis(100))).ok();
-
-        // Perform WebSocket handshake manually
-        let key = generate_key();
-        let request = format!(
-            "GET / HTTP/1.1\r\n\
-             Host: 127.0.0.1:{}\r\n\
-             Upgrade: websocket\r\n\
-             Connection: Upgrade\r\n\
-             Sec-WebSocket-Key: {}\r\n\
-             Sec-WebSocket-Version: 13\r\n\
-             \r\n",
-            port, key
+        assert_eq!(visits.recv_timeout(Duration::from_secs(5)).unwrap().texts.len(), 1);
+        let renewal = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(renewal.authorization, Some(format!("Bearer {}", EXCHANGE_JWT)), "expired token used");
+        assert_eq!(renewal.texts.len(), 1);
+        let renewed = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(renewed.authorization.as_deref(), Some("Bearer stream-2"));
+    }
+
+    #[test]
+    fn test_failed_exchange_falls_back_to_streaming_without_a_token() {
+        let (port, visits) = exchange_mock_server(
+            |_| r#"{"type":"token_exchange_error","reason":"scopes not enabled"}"#,
+            Duration::from_millis(1300),
         );
+        let client = exchanging_client(port);
 
-        let mut stream_clone = stream.try_clone().map_err(|_| ())?;
-        stream_clone.write_all(request.as_bytes()).map_err(|_| ())?;
+        let refused = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(refused.texts.len(), 1);
+        assert!(refused.packets > 0, "nothing streamed after the refusal");
+        assert_eq!(client.error_stats().count(Layer::Auth), 1);
 
-        // Read response
-        let mut response = [0u8; 1024];
-        let mut total_read = 0;
-        loop {
-            let n = stream_clone.read(&mut response[total_read..]).map_err(|_| ())?;
-            if n == 0 {
-                return Err(());
+        // No second try until EXCHANGE_RETRY has passed
+        let next = visits.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(next.authorization, Some(format!("Bearer {}", EXCHANGE_JWT)));
+        assert!(next.texts.is_empty(), "{:?}", next.texts);
+        assert!(next.packets > 0);
+    }
+
+    /// The account token `refused_token_client` loads
+    static ACCOUNT_TOKEN: std::sync::Mutex<Option<&str>> = std::sync::Mutex::new(None);
+
+    #[test]
+    fn test_refused_token_waits_for_a_new_one() {
+        // Only the new token gets in, and the connection stays open
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, visits) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else { return };
+                let mut authorization = None;
+                let header = |request: &tungstenite::handshake::server::Request, response| {
+                    authorization = request
+                        .headers()
+                        .get("authorization")
+                        .and_then(|value| value.to_str().ok())
+                        .map(str::to_string);
+                    let status = match authorization.as_deref() {
+                        Some("Bearer new-token") => return Ok(response),
+                        Some(_) => 403,
+                        None => 401,
+                    };
+                    Err(tungstenite::http::Response::builder().status(status).body(None).unwrap())
+                };
+                let accepted = tungstenite::accept_hdr(stream, header).ok();
+                if tx.send(authorization).is_err() {
+                    return;
+                }
+                if let Some(mut ws) = accepted {
+                    while ws.read().is_ok() {}
+                }
             }
-            total_read += n;
-            // Check for end of headers
-            if total_read >= 4 && &response[total_read - 4..total_read] == b"\r\n\r\n" {
-                break;
+        });
+        let mut client = WebSocketClient::new();
+        *client.controls.auth.stream_auth.lock() =
+            StreamAuth::with_loader(|| Ok(ACCOUNT_TOKEN.lock().unwrap().map(str::to_string)));
+        client.set_port(port as i32);
+        client.start().unwrap();
+        // As the editor saving or clearing a token
+        let save = |token| {
+            *ACCOUNT_TOKEN.lock().unwrap() = token;
+            client.account_token().changed();
+            client.controls.suite.onboarding.request_retry();
+        };
+        let wait_for_state = |to| {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while client.connection_state() != to {
+                assert!(Instant::now() < deadline, "never {:?}", to);
+                thread::sleep(Duration::from_millis(5));
             }
-            if total_read >= response.len() {
-                return Err(());
+        };
+
+        // Without a token, then with the old one, it waits rather than
+        // retrying at full speed
+        assert_eq!(visits.recv_timeout(Duration::from_secs(5)).unwrap(), None);
+        wait_for_state(ConnectionState::Unauthorized);
+        assert!(visits.recv_timeout(Duration::from_millis(500)).is_err(), "retried at once");
+        let last = client.error_stats().report().last.unwrap();
+        assert!(last.contains("refused the token (401)"), "{}", last);
+        save(Some("old-token"));
+        assert_eq!(visits.recv_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("Bearer old-token"));
+        assert!(visits.recv_timeout(Duration::from_millis(500)).is_err(), "retried at once");
+        assert_eq!(client.connection_state(), ConnectionState::Unauthorized);
+
+        save(Some("new-token"));
+        assert_eq!(visits.recv_timeout(Duration::from_secs(5)).unwrap().as_deref(), Some("Bearer new-token"));
+        wait_connected(&client);
+
+        // Clearing it drops the live connection
+        save(None);
+        assert_eq!(visits.recv_timeout(Duration::from_secs(5)).unwrap(), None);
+        wait_for_state(ConnectionState::Unauthorized);
+        assert!(client.state_history().iter().any(|t| t.reason == "account token changed"));
+    }
+
+    #[test]
+    fn test_suite_command_round_trip() {
+        // Acknowledge every user command, then pass it on
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, commands) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            while let Ok(message) = ws.read() {
+                let Message::Binary(data) = message else { continue };
+                let Ok(packet) = AudioPacket::from_bytes(&data) else { continue };
+                if packet.packet_type == PACKET_TYPE_USER_COMMAND {
+                    let ack = format!(
+                        r#"{{"type":"command_ack","seq":{},"ok":{},"error":null}}"#,
+                        packet.command_seq,
+                        packet.command.contains("startCapture")
+                    );
+                    if ws.send(Message::Text(ack)).is_err() || tx.send(packet).is_err() {
+                        return;
+                    }
+                }
             }
-        }
+        });
 
-        // Verify response contains 101 Switching Protocols
-        let response_str = std::str::from_utf8(&response[..total_read]).map_err(|_| ())?;
-        if !response_str.contains("101") || !response_str.to_lowercase().contains("upgrade") {
-            return Err(());
-        }
+        let mut client = WebSocketClient::new();
+        client.set_display_hints(DisplayHints { instance_id: "instance-1".to_string(), ..Default::default() });
+        let relay = client.suite_commands();
+        // Queued before there is a connection
+        let start = relay.submit(r#"{"command":"startCapture","args":{"seconds":10}}"#).unwrap();
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let sent = commands.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(sent.command_seq, start);
+        assert_eq!(sent.command, r#"{"command":"startCapture","args":{"seconds":10}}"#);
+        assert_eq!(sent.instance_id, "instance-1");
 
-        // Create WebSocket from the stream
-        let socket = WebSocket::from_raw_socket(stream_clone, tungstenite::protocol::Role::Client, None);
-        Ok(socket)
+        let stop = relay.submit(r#"{"command":"stopCapture"}"#).unwrap();
+        assert_eq!(commands.recv_timeout(Duration::from_secs(5)).unwrap().command_seq, stop);
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut acks = Vec::new();
+        while acks.len() < 2 {
+            assert!(Instant::now() < deadline, "acks so far {:?}", acks);
+            acks.extend(relay.take_acks());
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!((acks[0].seq, acks[0].ok), (start, true));
+        assert_eq!((acks[1].seq, acks[1].ok), (stop, false));
+        assert!(client.is_connected(), "replies from the Suite don't end the connection");
     }
 
-    /// Handle an active connection
-    fn handle_connection(
-        socket: &mut WebSocket<TcpStream>,
-        receiver: &Receiver<AudioPacket>,
-        state: &Arc<Mutex<ConnectionState>>,
-        shutdown: &Arc<AtomicBool>,
-    ) {
-        let mut last_heartbeat = std::time::Instant::now();
-        let heartbeat_interval = Duration::from_secs(1);
+    #[test]
+    fn test_subscriptions_decide_what_is_computed_and_sent() {
+        use crate::analysis::AnalysisConfig;
+        use crate::engine::AnalysisEngine;
+        use crate::subscriptions::Category;
 
-        while !shutdown.load(Ordering::Relaxed) {
-            // Check for incoming packets to send
-            match receiver.try_recv() {
-                Ok(packet) => {
-                    let data = packet.to_bytes();
-                    if socket.send(Message::Binary(data)).is_err() {
-                        *state.lock() = ConnectionState::Disconnected;
+        // The Suite turns the spectrum and waveform off as soon as it
+        // connects, and the spectrum back on after five frames without it
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, packets) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            let unsubscribe = r#"{"type":"unsubscribe","types":["fft","waveform"]}"#;
+            if ws.send(Message::Text(unsubscribe.to_string())).is_err() {
+                return;
+            }
+            let mut without_spectrum = 0;
+            while let Ok(message) = ws.read() {
+                let Message::Binary(data) = message else { continue };
+                let Ok(packet) = AudioPacket::from_bytes(&data) else { continue };
+                if packet.packet_type == PACKET_TYPE_FFT && packet.left_bins.is_empty() {
+                    without_spectrum += 1;
+                    let subscribe = r#"{"type":"subscribe","types":["fft"]}"#;
+                    if without_spectrum == 5 && ws.send(Message::Text(subscribe.to_string())).is_err() {
                         return;
                     }
-                    // Flush to ensure data is sent
-                    if socket.flush().is_err() {
-                        *state.lock() = ConnectionState::Disconnected;
-                        return;
+                }
+                if tx.send(packet).is_err() {
+                    return;
+                }
+            }
+        });
+
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        let mut engine = AnalysisEngine::new(AnalysisConfig::default());
+        let tone: Vec<f32> = (0..480).map(|i| 0.5 * (i as f32 * 0.1).sin()).collect();
+
+        let (mut levels_only, mut spectrum_only) = (0, 0);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while spectrum_only < 5 {
+            assert!(Instant::now() < deadline, "{} frames without the spectrum", levels_only);
+            let wanted = client.wanted_outputs();
+            engine.set_outputs(wanted);
+            let before = engine.work_counts();
+            engine.push_samples(&tone, &tone);
+            if let Some(frame) = engine.poll_frame() {
+                let work = engine.work_counts();
+                assert_eq!(work.spectra > before.spectra, wanted.contains(Category::Fft));
+                assert_eq!(work.waveforms > before.waveforms, wanted.contains(Category::Waveform));
+                let _ = client.send(frame.into_packet(0));
+            }
+            for packet in packets.try_iter().filter(|packet| packet.packet_type == PACKET_TYPE_FFT) {
+                assert!(packet.left_peak > -10.0, "levels are always sent");
+                match (packet.left_bins.is_empty(), packet.left_wave.is_empty()) {
+                    // Made before the Suite's first message arrived
+                    (false, false) => assert_eq!(levels_only, 0),
+                    (true, true) => levels_only += 1,
+                    (false, true) => {
+                        assert!(levels_only >= 5);
+                        assert!(!packet.left_coarse.is_empty());
+                        spectrum_only += 1;
                     }
+                    (true, false) => panic!("waveform without the Suite asking for it"),
                 }
-                Err(TryRecvError::Empty) => {
-                    // No packet available, check if we need to send heartbeat
-                    if last_heartbeat.elapsed() >= heartbeat_interval {
-                        let heartbeat = AudioPacket::new_heartbeat(0, 0);
-                        let data = heartbeat.to_bytes();
-                        if socket.send(Message::Binary(data)).is_err() {
-                            *state.lock() = ConnectionState::Disconnected;
-                            return;
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+    }
+
+    #[test]
+    fn test_resume_reconnects_at_once_and_says_so() {
+        use crate::protocol::{PACKET_TYPE_HELLO, PACKET_TYPE_STATUS};
+
+        // Each connection's first two packets, and which connection
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, firsts) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().take(2).enumerate() {
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut ws = tungstenite::accept(stream.unwrap()).unwrap();
+                    let mut seen = 0;
+                    while let Ok(message) = ws.read() {
+                        let Message::Binary(data) = message else { continue };
+                        if seen < 2 {
+                            seen += 1;
+                            let _ = tx.send((connection, AudioPacket::from_bytes(&data).unwrap()));
+                        }
+                    }
+                });
+            }
+        });
+
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        let (_, hello) = firsts.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(hello.packet_type, PACKET_TYPE_HELLO, "every connection says hello first");
+        assert_eq!(hello.resumed_after_ms, 0);
+
+        let slept_at = Instant::now();
+        client.controls.transport.resume.simulate_sleep(Duration::from_secs(60));
+        let mut second = Vec::new();
+        while second.len() < 2 {
+            let (connection, packet) = firsts.recv_timeout(Duration::from_secs(5)).expect("no second connection");
+            if connection == 1 {
+                second.push(packet);
+            }
+        }
+        assert!(slept_at.elapsed() < Duration::from_secs(1), "reconnect waited for the backoff");
+        assert_eq!(second[0].packet_type, PACKET_TYPE_HELLO);
+        let after = &second[1];
+        assert_eq!(after.packet_type, PACKET_TYPE_STATUS);
+        assert!(after.resumed_after_ms >= 60_000, "{}", after.resumed_after_ms);
+        assert!(client.state_history().iter().any(|t| t.reason == "resumed from sleep"));
+        assert_eq!(client.controls.transport.resume.resumes(), 1);
+    }
+
+    #[test]
+    fn test_metadata_comes_first() {
+        use crate::metadata::{CHANNEL_MODE_MID_SIDE, PLUGIN_VERSION};
+        use crate::protocol::PACKET_TYPE_HELLO;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, first) = crossbeam_channel::bounded(1);
+        thread::spawn(move || {
+            let mut ws = tungstenite::accept(listener.accept().unwrap().0).unwrap();
+            while let Ok(message) = ws.read() {
+                if let Message::Binary(data) = message {
+                    let _ = tx.send(data);
+                    break;
+                }
+            }
+        });
+
+        let mut client = WebSocketClient::new();
+        client.set_display_hints(DisplayHints {
+            label: "Drums".to_string(),
+            channel_labels: Some(["Kick".to_string(), "Snare".to_string()]),
+            ..Default::default()
+        });
+        client.set_metadata(Metadata {
+            sample_rate: 48_000,
+            fft_size: 4096,
+            zero_pad: 2,
+            band_count: 7,
+            channel_mode: CHANNEL_MODE_MID_SIDE,
+        });
+        client.set_port(port as i32);
+        client.start().unwrap();
+        let hello = AudioPacket::from_bytes(&first.recv_timeout(Duration::from_secs(5)).unwrap()).unwrap();
+        assert_eq!(hello.packet_type, PACKET_TYPE_HELLO);
+        assert_eq!((hello.plugin_version.as_str(), hello.display_label.as_str()), (PLUGIN_VERSION, "Drums"));
+        assert_eq!((hello.sample_rate, hello.fft_size, hello.zero_pad), (48_000, 4096, 2));
+        assert_eq!((hello.band_count, hello.channel_mode), (7, CHANNEL_MODE_MID_SIDE));
+        assert_eq!(hello.channel_labels, ["Kick", "Snare"]);
+    }
+
+    #[test]
+    fn test_connection_thread_restarts_after_a_panic() {
+        use crate::panics::hooks;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, accepted) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for stream in listener.incoming().take(2) {
+                let mut ws = tungstenite::accept(stream.unwrap()).unwrap();
+                let _ = tx.send(());
+                thread::spawn(move || while ws.read().is_ok() {});
+            }
+        });
+
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        accepted.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let restarts = panics::restarts(Region::Connection);
+        let thread = client.thread_handle.as_ref().unwrap().thread().id();
+        hooks::inject(Region::Connection, Some(thread));
+        accepted.recv_timeout(Duration::from_secs(5)).expect("no connection after the restart");
+        assert!(panics::restarts(Region::Connection) > restarts);
+        wait_connected(&client);
+        assert!(client.state_history().iter().any(|t| t.reason == "connection thread restarted"));
+        assert!(client.send(fft_frame(0)).is_ok(), "the new loop takes packets");
+    }
+
+    /// What a Suite saw on a connection
+    #[derive(Debug)]
+    enum Seen {
+        /// The handshake, with its `Authorization` header if it had one
+        Handshake(Option<String>),
+        Text(String),
+        /// A packet and its stream; 0 if it came untagged
+        Packet(u32, Box<AudioPacket>),
+    }
+
+    /// Accept clients, agreeing to multiplex if `agree` and they ask, and
+    /// forward what each sends with the index of its connection
+    fn multiplexing_mock_server(agree: bool) -> (u16, Receiver<(usize, Seen)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            for (connection, stream) in listener.incoming().enumerate() {
+                let Ok(stream) = stream else { return };
+                let tx = tx.clone();
+                thread::spawn(move || {
+                    let mut authorization = None;
+                    let mut tagged = false;
+                    let header = |request: &tungstenite::handshake::server::Request,
+                                  mut response: tungstenite::handshake::server::Response| {
+                        authorization = request
+                            .headers()
+                            .get("authorization")
+                            .and_then(|value| value.to_str().ok())
+                            .map(str::to_string);
+                        match request.headers().get("x-hardwave-multiplex") {
+                            Some(asked) if agree => {
+                                response.headers_mut().insert("x-hardwave-multiplex", asked.clone());
+                                tagged = true;
+                            }
+                            _ => {}
                         }
-                        if socket.flush().is_err() {
-                            *state.lock() = ConnectionState::Disconnected;
+                        Ok(response)
+                    };
+                    let Ok(mut ws) = tungstenite::accept_hdr(stream, header) else { return };
+                    if tx.send((connection, Seen::Handshake(authorization))).is_err() {
+                        return;
+                    }
+                    while let Ok(msg) = ws.read() {
+                        let seen = match msg {
+                            Message::Text(text) => Seen::Text(text),
+                            Message::Binary(data) if tagged => match multiplex::untag(&data) {
+                                Some((stream, packet)) => match AudioPacket::from_bytes(packet) {
+                                    Ok(packet) => Seen::Packet(stream, Box::new(packet)),
+                                    Err(_) => continue,
+                                },
+                                None => continue,
+                            },
+                            Message::Binary(data) => match AudioPacket::from_bytes(&data) {
+                                Ok(packet) => Seen::Packet(0, Box::new(packet)),
+                                Err(_) => continue,
+                            },
+                            _ => continue,
+                        };
+                        if tx.send((connection, seen)).is_err() {
                             return;
                         }
-                        last_heartbeat = std::time::Instant::now();
+                    }
+                });
+            }
+        });
+        (port, rx)
+    }
+
+    fn sharing_client(port: u16, shared: &Arc<SharedConnection>) -> WebSocketClient {
+        sharing_client_with(port, shared, || Ok(None))
+    }
+
+    /// A `sharing_client` whose account token is what `load` returns
+    fn sharing_client_with(
+        port: u16,
+        shared: &Arc<SharedConnection>,
+        load: fn() -> Result<Option<String>, AuthError>,
+    ) -> WebSocketClient {
+        let mut client = WebSocketClient::new();
+        *client.controls.auth.stream_auth.lock() = StreamAuth::with_loader(load);
+        client.share_connection(Arc::clone(shared));
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+        client
+    }
+
+    /// The connection and stream of each frame with one of `timestamps_ms`,
+    /// in that order, and the texts seen on the way. The frames come from
+    /// different instances, so in any order.
+    fn find_frames<const N: usize>(
+        rx: &Receiver<(usize, Seen)>,
+        timestamps_ms: [u64; N],
+        texts: &mut Vec<(usize, String)>,
+    ) -> [(usize, u32); N] {
+        let mut found = [None; N];
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while found.contains(&None) {
+            assert!(Instant::now() < deadline, "frames {:?} never arrived, found {:?}", timestamps_ms, found);
+            let Ok((connection, seen)) = rx.recv_timeout(Duration::from_millis(100)) else {
+                continue;
+            };
+            match seen {
+                Seen::Packet(stream, packet) if packet.packet_type == PACKET_TYPE_FFT => {
+                    if let Some(index) = timestamps_ms.iter().position(|&ms| ms == packet.timestamp_ms) {
+                        found[index] = Some((connection, stream));
                     }
                 }
-                Err(TryRecvError::Disconnected) => {
-                    // Channel closed, exit
-                    return;
+                Seen::Text(text) => texts.push((connection, text)),
+                Seen::Packet(..) | Seen::Handshake(_) => {}
+            }
+        }
+        found.map(Option::unwrap)
+    }
+
+    #[test]
+    fn test_instances_stream_over_one_connection() {
+        let (port, rx) = multiplexing_mock_server(true);
+        let shared = Arc::new(SharedConnection::default());
+        let first = sharing_client(port, &shared);
+        let second = sharing_client(port, &shared);
+        let (first_id, second_id) = (first.controls.transport.stream, second.controls.transport.stream);
+
+        first.send(fft_frame(100)).unwrap();
+        second.send(fft_frame(200)).unwrap();
+        let mut texts = Vec::new();
+        assert_eq!(find_frames(&rx, [100, 200], &mut texts), [(0, first_id), (0, second_id)]);
+
+        // One hello, and whoever joined after it was announced
+        assert!(texts[0].1.starts_with(r#"{"type":"hello","streams":[{"stream":"#), "{:?}", texts);
+        assert_eq!(texts.iter().filter(|(_, text)| text.contains(r#""type":"hello""#)).count(), 1);
+        for id in [first_id, second_id] {
+            let named = format!(r#"{{"stream":{},"#, id);
+            let added = format!(r#"{{"type":"stream_added","stream":{},"#, id);
+            assert!(texts.iter().any(|(_, text)| text.contains(&named) || text.starts_with(&added)), "{:?}", texts);
+        }
+
+        // Unloading one instance takes only its stream off
+        drop(second);
+        let removed = format!(r#"{{"type":"stream_removed","stream":{}}}"#, second_id);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "no removal event");
+            if let Ok((connection, Seen::Text(text))) = rx.recv_timeout(Duration::from_millis(100)) {
+                if text == removed {
+                    assert_eq!(connection, 0);
+                    break;
                 }
             }
+        }
+        first.send(fft_frame(300)).unwrap();
+        assert_eq!(find_frames(&rx, [300], &mut texts), [(0, first_id)]);
+        assert!(first.is_connected());
+    }
 
-            // Small sleep to avoid busy-waiting
+    #[test]
+    fn test_instances_with_a_token_share_one_authenticated_connection() {
+        let (port, rx) = multiplexing_mock_server(true);
+        let shared = Arc::new(SharedConnection::default());
+        let token = || Ok(Some("account-token".to_string()));
+        let first = sharing_client_with(port, &shared, token);
+        let second = sharing_client_with(port, &shared, token);
+        let (first_id, second_id) = (first.controls.transport.stream, second.controls.transport.stream);
+
+        first.send(fft_frame(100)).unwrap();
+        second.send(fft_frame(200)).unwrap();
+        let mut handshakes = Vec::new();
+        let mut frames = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while frames.len() < 2 {
+            assert!(Instant::now() < deadline, "frames never arrived, found {:?}", frames);
+            match rx.recv_timeout(Duration::from_millis(100)) {
+                Ok((connection, Seen::Handshake(authorization))) => handshakes.push((connection, authorization)),
+                Ok((connection, Seen::Packet(stream, packet))) if packet.packet_type == PACKET_TYPE_FFT => {
+                    frames.push((packet.timestamp_ms, connection, stream));
+                }
+                _ => {}
+            }
+        }
+        frames.sort_unstable();
+        assert_eq!(frames, [(100, 0, first_id), (200, 0, second_id)]);
+        // The token went once, in the shared connection's handshake
+        assert_eq!(handshakes, [(0, Some("Bearer account-token".to_string()))]);
+
+        // An instance with another token connects on its own
+        let other = sharing_client_with(port, &shared, || Ok(Some("other-token".to_string())));
+        other.send(fft_frame(300)).unwrap();
+        assert_eq!(find_frames(&rx, [300], &mut Vec::new()), [(1, 0)]);
+    }
+
+    #[test]
+    fn test_old_suite_gets_a_connection_per_instance() {
+        let (port, rx) = multiplexing_mock_server(false);
+        let shared = Arc::new(SharedConnection::default());
+        let first = sharing_client(port, &shared);
+        let second = sharing_client(port, &shared);
+
+        first.send(fft_frame(100)).unwrap();
+        second.send(fft_frame(200)).unwrap();
+        let mut texts = Vec::new();
+        let [(first_connection, first_stream), (second_connection, second_stream)] =
+            find_frames(&rx, [100, 200], &mut texts);
+        assert_eq!((first_stream, second_stream), (0, 0), "packets were tagged");
+        assert_ne!(first_connection, second_connection);
+        assert!(texts.iter().all(|(_, text)| !text.contains("hello")), "{:?}", texts);
+    }
+
+    #[test]
+    fn test_connection_thread_is_named_and_joined() {
+        let mut client = WebSocketClient::new();
+        client.set_port(1);
+        client.start().unwrap();
+        let handle = client.thread_handle.as_ref().unwrap();
+        assert_eq!(handle.thread().name(), Some("hardwave-ws"));
+
+        // The thread holds a reference to the controls until it exits
+        let controls = Arc::clone(&client.controls);
+        drop(client);
+        assert_eq!(Arc::strong_count(&controls), 1, "connection thread outlived the client");
+    }
+
+    #[test]
+    fn test_a_stuck_connection_takes_events_then_the_latest_of_each_kind() {
+        // Not started, so nothing but the test reads
+        let client = WebSocketClient::new();
+        let controls = &client.controls;
+        let subscription = SuiteSubscription::new("suite", "suite latest", controls);
+        let queues = subscription.queues();
+        let mut pending = Vec::new();
+        assert_eq!(queues.take(&mut pending), Err(TryRecvError::Empty));
+        // Every packet gets to the queues, if the bus's thread is behind
+        let publish = |packet: AudioPacket| {
+            let packet = Arc::new(packet);
+            while let Err(TransportError::QueueFull) = controls.transport.bus.publish(Arc::clone(&packet)) {
+                thread::yield_now();
+            }
+        };
+
+        // Nobody reads while the socket is stuck. Frames, heartbeats and
+        // transport outnumber the room there is, and the events still fit.
+        let events = EVENT_QUEUE_LEN as u64;
+        for i in 0..events {
+            publish(fft_frame(i));
+            publish(AudioPacket::new_heartbeat(48000, i));
+            publish(event(i));
+            let transport = TransportSnapshot { sample_rate: 48000.0, ..TransportSnapshot::default() };
+            publish(AudioPacket::new_transport(i, &transport));
+        }
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while controls.telemetry.usage.totals().produced < events || queues.events.len() < EVENT_QUEUE_LEN {
+            assert!(Instant::now() < deadline, "the bus didn't hand the packets on");
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Once it unblocks, every event in order, then the newest of each
+        // kind, the frame last
+        queues.take(&mut pending).unwrap();
+        let taken: Vec<(u8, u64)> = pending.iter().map(|p| (p.packet_type, p.timestamp_ms)).collect();
+        let mut expected: Vec<(u8, u64)> = (0..events).map(|i| (PACKET_TYPE_LOOP_DETECTED, i)).collect();
+        expected.extend([(PACKET_TYPE_HEARTBEAT, events - 1), (PACKET_TYPE_TRANSPORT, events - 1), (PACKET_TYPE_FFT, events - 1)]);
+        assert_eq!(taken, expected);
+        assert_eq!(controls.telemetry.usage.totals().dropped, events - 1, "overwritten frames count as dropped");
+        assert_eq!(controls.telemetry.link.report().packets_dropped, 3 * (events - 1), "no event was");
+        assert_eq!(queues.events_dropped.load(Ordering::Relaxed), 0);
+
+        // One event more than there is room for is counted, and logged at
+        // the next take
+        pending.clear();
+        for i in 0..=events {
+            publish(event(i));
+        }
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while queues.events_dropped.load(Ordering::Relaxed) < 1 {
+            assert!(Instant::now() < deadline, "the dropped event wasn't counted");
             thread::sleep(Duration::from_millis(1));
         }
+        queues.take(&mut pending).unwrap();
+        assert_eq!(pending.len(), EVENT_QUEUE_LEN);
+        assert_eq!(queues.events_dropped.load(Ordering::Relaxed), 0, "taken to be logged");
+
+        pending.clear();
+        assert_eq!(queues.take(&mut pending), Err(TryRecvError::Empty));
+        drop(subscription);
+        assert_eq!(queues.take(&mut pending), Err(TryRecvError::Disconnected));
     }
-}
 
-impl Default for WebSocketClient {
-    fn default() -> Self {
-        Self::new()
+    #[test]
+    fn test_in_process_latency_within_budget() {
+        let (port, rx) = slow_mock_server(Duration::ZERO);
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        wait_connected(&client);
+
+        // Default settings: 20 frames/s, sped up to keep the test short
+        let frames = 100;
+        for timestamp in 0..frames {
+            let mut frame = fft_frame(timestamp);
+            frame.captured_us = latency::now_us();
+            let _ = client.send(frame);
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        // Wait for the newest frame; it is never coalesced away
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            assert!(Instant::now() < deadline, "last frame never arrived");
+            if let Ok(packet) = rx.recv_timeout(Duration::from_millis(50)) {
+                if packet.packet_type == PACKET_TYPE_FFT && packet.timestamp_ms == frames - 1 {
+                    break;
+                }
+            }
+        }
+        let stats = client.latency_stats();
+        assert!(stats.count > 0 && stats.count <= frames as u32, "{:?}", stats);
+        assert!(
+            (stats.p95_us as u64) < latency::IN_PROCESS_BUDGET_US,
+            "p95 {} µs over the {} µs budget ({:?})",
+            stats.p95_us,
+            latency::IN_PROCESS_BUDGET_US,
+            stats
+        );
     }
-}
 
-impl Drop for WebSocketClient {
-    fn drop(&mut self) {
-        self.shutdown.store(true, Ordering::Relaxed);
-        if let Some(handle) = self.thread_handle.take() {
-            let _ = handle.join();
+    #[test]
+    fn test_slow_server_sees_recent_frames_and_every_event() {
+        // The bursts pile up while the Suite isn't listening yet, so each
+        // one is queued back to back, whatever the connection thread is at
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+
+        let bursts = 20;
+        let frames_per_burst = 5;
+        let mut timestamp = 0;
+        // Every packet gets to the queues, if the bus's thread is behind
+        let send = |packet: AudioPacket| {
+            let packet = Arc::new(packet);
+            while let Err(HardwaveError::Transport(TransportError::QueueFull)) = client.send(Arc::clone(&packet)) {
+                thread::yield_now();
+            }
+        };
+        let send_burst = |burst: u64, timestamp: &mut u64| {
+            for _ in 0..frames_per_burst {
+                *timestamp += 1;
+                send(fft_frame(*timestamp));
+            }
+            send(event(burst));
+        };
+        for burst in 0..bursts {
+            send_burst(burst, &mut timestamp);
+        }
+
+        let rx = slow_mock_server_on(TcpListener::bind(("127.0.0.1", port)).unwrap(), Duration::from_millis(2));
+        let mut frames = Vec::new();
+        let mut events = Vec::new();
+        let mut take_until_event = |burst: u64| loop {
+            let packet = rx.recv_timeout(Duration::from_secs(5)).expect("an event never arrived");
+            match packet.packet_type {
+                PACKET_TYPE_LOOP_DETECTED => {
+                    events.push(packet.timestamp_ms);
+                    if packet.timestamp_ms == burst {
+                        return;
+                    }
+                }
+                PACKET_TYPE_FFT => frames.push(packet.timestamp_ms),
+                _ => {}
+            }
+        };
+        take_until_event(bursts - 1);
+        // Then the newest of them, once the events are through
+        let stalled_newest = timestamp;
+
+        // Once connected, a burst at a time against the slow reader
+        for burst in bursts..bursts * 2 {
+            send_burst(burst, &mut timestamp);
+            take_until_event(burst);
+        }
+        wait_for_frame(&rx, &mut frames, timestamp);
+
+        let expected: Vec<u64> = (0..bursts * 2).collect();
+        assert_eq!(events, expected, "every event exactly once, in order");
+        assert!(frames.windows(2).all(|w| w[0] < w[1]), "frames out of order");
+        assert!(frames.contains(&stalled_newest), "the newest stalled frame must arrive");
+        assert!(!frames.iter().any(|&t| t < stalled_newest), "stale frames should be coalesced");
+        assert_eq!(frames.last().copied(), Some(timestamp), "newest frame must arrive");
+
+        let link = client.controls.telemetry.link.report();
+        assert!(link.packets_sent as usize >= frames.len() + events.len());
+        assert!(link.bytes_written > 0 && link.last_sent_ms.is_some());
+    }
+
+    #[test]
+    fn test_the_first_frame_after_a_stall_is_the_newest() {
+        // The Suite isn't there yet: the connection is stuck as it would be
+        // on a blocked socket, and nothing is sent
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let mut client = WebSocketClient::new();
+        client.set_port(port as i32);
+        client.start().unwrap();
+        for timestamp in 0..50 {
+            client.send(fft_frame(timestamp)).unwrap();
+            if timestamp % 10 == 0 {
+                client.send(event(timestamp / 10)).unwrap();
+            }
         }
+
+        let listener = TcpListener::bind(("127.0.0.1", port)).unwrap();
+        let (tx, rx) = crossbeam_channel::unbounded();
+        thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut ws = tungstenite::accept(stream).unwrap();
+            while let Ok(msg) = ws.read() {
+                if let Message::Binary(data) = msg {
+                    if let Ok(packet) = AudioPacket::from_bytes(&data) {
+                        let _ = tx.send(packet);
+                    }
+                }
+            }
+        });
+
+        let mut events = Vec::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        let first_frame = loop {
+            assert!(Instant::now() < deadline, "no frame after the stall");
+            let Ok(packet) = rx.recv_timeout(Duration::from_millis(100)) else {
+                continue;
+            };
+            match packet.packet_type {
+                PACKET_TYPE_LOOP_DETECTED => events.push(packet.timestamp_ms),
+                PACKET_TYPE_FFT => break packet.timestamp_ms,
+                _ => {}
+            }
+        };
+        assert_eq!(first_frame, 49, "the newest, not the oldest still queued");
+        assert_eq!(events, [0, 1, 2, 3, 4], "every event, before it");
     }
 }
@@ -0,0 +1,179 @@
+//! Commands for the editor webview from any thread
+//!
+//! A wry `WebView` may only be touched by the thread that created it: the
+//! DAW's UI thread on Windows, the editor thread elsewhere. Everything that
+//! wants the webview to do something pushes a `WebViewCommand` onto a
+//! `WebViewQueue`. The `WebViewPump` applies the commands, in the order they
+//! were pushed, when its owning thread gets around to it. On Windows that
+//! thread is the one running the IPC handler, which the page calls every
+//! frame. On other platforms it is the editor loop.
+
+use crossbeam_channel::{unbounded, Receiver, Sender};
+use std::thread::{self, ThreadId};
+
+/// Something for the webview to do
+#[derive(Debug, Clone)]
+// Windows lets wry follow the parent window, so nothing sets bounds there
+pub enum WebViewCommand {
+    /// Load a URL
+    Navigate(String),
+    /// Move and resize within the parent window
+    SetBounds(wry::Rect),
+    /// Run a script in the page
+    Eval(String),
+    /// Open the web inspector; debug builds only
+    OpenDevtools,
+}
+
+/// Sending end, cheap to clone and safe to use from any thread
+#[derive(Clone)]
+pub struct WebViewQueue(Sender<WebViewCommand>);
+
+impl WebViewQueue {
+    /// Queue a command. Once the pump is gone, commands are dropped.
+    pub fn push(&self, command: WebViewCommand) {
+        let _ = self.0.send(command);
+    }
+
+    pub fn eval(&self, js: String) {
+        self.push(WebViewCommand::Eval(js));
+    }
+}
+
+/// Receiving end, tied to the thread that created it
+pub struct WebViewPump {
+    owner: ThreadId,
+    commands: Receiver<WebViewCommand>,
+}
+
+impl WebViewPump {
+    /// Apply every queued command in order and return how many there were.
+    /// Off the owning thread this applies nothing and the commands wait.
+    pub fn pump(&self, mut apply: impl FnMut(WebViewCommand)) -> usize {
+        if !self.is_owner() {
+            return 0;
+        }
+        let mut applied = 0;
+        while let Ok(command) = self.commands.try_recv() {
+            apply(command);
+            applied += 1;
+        }
+        applied
+    }
+
+    pub fn is_owner(&self) -> bool {
+        thread::current().id() == self.owner
+    }
+}
+
+/// A queue and its pump, owned by the calling thread, which must be the one
+/// that creates the webview
+pub fn channel() -> (WebViewQueue, WebViewPump) {
+    let (sender, commands) = unbounded();
+    (
+        WebViewQueue(sender),
+        WebViewPump {
+            owner: thread::current().id(),
+            commands,
+        },
+    )
+}
+
+/// Apply one command. Errors are logged by wry and not worth more here: the
+/// next frame or poll repeats anything that matters.
+pub fn apply(webview: &wry::WebView, command: WebViewCommand) {
+    match command {
+        WebViewCommand::Navigate(url) => {
+            let _ = webview.load_url(&url);
+        }
+        WebViewCommand::SetBounds(bounds) => {
+            let _ = webview.set_bounds(bounds);
+        }
+        WebViewCommand::Eval(js) => {
+            let _ = webview.evaluate_script(&js);
+        }
+        #[cfg(debug_assertions)]
+        WebViewCommand::OpenDevtools => webview.open_devtools(),
+        #[cfg(not(debug_assertions))]
+        WebViewCommand::OpenDevtools => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn describe(command: WebViewCommand) -> String {
+        match command {
+            WebViewCommand::Navigate(url) => format!("navigate {}", url),
+            WebViewCommand::SetBounds(_) => "bounds".to_string(),
+            WebViewCommand::Eval(js) => format!("eval {}", js),
+            WebViewCommand::OpenDevtools => "devtools".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_commands_apply_in_order() {
+        let (queue, pump) = channel();
+        queue.eval("a()".to_string());
+        queue.push(WebViewCommand::Navigate("https://example.com".to_string()));
+        queue.eval("b()".to_string());
+        queue.push(WebViewCommand::OpenDevtools);
+
+        let mut applied = Vec::new();
+        assert_eq!(pump.pump(|c| applied.push(describe(c))), 4);
+        assert_eq!(applied, ["eval a()", "navigate https://example.com", "eval b()", "devtools"]);
+
+        // Drained: a second pump has nothing to do
+        assert_eq!(pump.pump(|_| panic!("nothing queued")), 0);
+    }
+
+    #[test]
+    fn test_other_threads_queue_for_the_owner() {
+        let (queue, pump) = channel();
+        let producers: Vec<_> = (0..4)
+            .map(|t| {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    for i in 0..100 {
+                        queue.eval(format!("{} {}", t, i));
+                    }
+                })
+            })
+            .collect();
+        for producer in producers {
+            producer.join().unwrap();
+        }
+
+        let mut applied = Vec::new();
+        assert_eq!(pump.pump(|c| applied.push(describe(c))), 400);
+        // Each producer's commands keep their order
+        for t in 0..4 {
+            let own: Vec<&String> = applied.iter().filter(|c| c.starts_with(&format!("eval {} ", t))).collect();
+            let expected: Vec<String> = (0..100).map(|i| format!("eval {} {}", t, i)).collect();
+            assert_eq!(own, expected.iter().collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn test_pump_off_owner_applies_nothing() {
+        let (queue, pump) = channel();
+        queue.eval("a()".to_string());
+        let pump = thread::spawn(move || {
+            assert!(!pump.is_owner());
+            assert_eq!(pump.pump(|_| panic!("applied off the owner thread")), 0);
+            pump
+        })
+        .join()
+        .unwrap();
+        assert!(pump.is_owner());
+        assert_eq!(pump.pump(|_| {}), 1);
+    }
+
+    #[test]
+    fn test_push_after_pump_is_gone() {
+        let (queue, pump) = channel();
+        drop(pump);
+        queue.eval("too late".to_string());
+    }
+}
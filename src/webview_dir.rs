@@ -0,0 +1,189 @@
+//! Choice of the WebView2 user data directory.
+//!
+//! WebView2 needs a writable data directory; the default (the host
+//! executable's folder) usually isn't, and LocalAppData may be redirected to a
+//! slow network share. Candidates are tried in order — env var, machine
+//! config, LocalAppData, then a per-instance temp directory — and the first
+//! one that passes a create-and-delete probe wins.
+//!
+//! Only the stale-directory cleanup runs outside Windows.
+
+use std::fs;
+use std::path::Path;
+#[cfg(any(target_os = "windows", test))]
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+#[cfg(any(target_os = "windows", test))]
+use crate::error::{EditorError, HardwaveError};
+
+/// Env var that overrides the WebView2 data directory
+#[cfg(target_os = "windows")]
+pub const DATA_DIR_ENV: &str = "HARDWAVE_WEBVIEW_DATA_DIR";
+
+/// Prefix of the per-instance temp fallback directories
+const TEMP_DIR_PREFIX: &str = "Hardwave-WebView2-";
+
+/// Per-instance temp directories older than this are removed at spawn
+pub const STALE_TEMP_DIR_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+
+/// Candidate data directories in order of preference.
+#[cfg(any(target_os = "windows", test))]
+pub fn candidates(
+    env_override: Option<PathBuf>,
+    config_override: Option<PathBuf>,
+    data_local: Option<PathBuf>,
+    temp: &Path,
+    pid: u32,
+) -> Vec<PathBuf> {
+    let mut dirs = Vec::new();
+    dirs.extend(env_override.filter(|p| !p.as_os_str().is_empty()));
+    dirs.extend(config_override);
+    dirs.extend(data_local.map(|d| d.join("Hardwave").join("WebView2")));
+    dirs.push(temp.join(format!("{}{}", TEMP_DIR_PREFIX, pid)));
+    dirs
+}
+
+/// Check that `dir` exists (creating it if needed) and that we can create and
+/// delete a file inside it.
+#[cfg(any(target_os = "windows", test))]
+pub fn is_writable(dir: &Path) -> bool {
+    if fs::create_dir_all(dir).is_err() {
+        return false;
+    }
+    let marker = dir.join(".hardwave-write-probe");
+    let ok = fs::write(&marker, b"probe").is_ok();
+    ok && fs::remove_file(&marker).is_ok()
+}
+
+/// First writable candidate, or an error listing every one that was tried.
+#[cfg(any(target_os = "windows", test))]
+pub fn choose(candidates: &[PathBuf]) -> Result<PathBuf, HardwaveError> {
+    candidates
+        .iter()
+        .find(|dir| is_writable(dir))
+        .cloned()
+        .ok_or_else(|| EditorError::NoDataDir(candidates.to_vec()).into())
+}
+
+fn is_stale(modified: SystemTime, now: SystemTime, max_age: Duration) -> bool {
+    now.duration_since(modified).map(|age| age > max_age).unwrap_or(false)
+}
+
+/// Remove per-instance temp directories older than `max_age`, except the one
+/// belonging to this process. Returns the number removed.
+pub fn cleanup_stale(temp: &Path, max_age: Duration, own_pid: u32) -> usize {
+    let Ok(entries) = fs::read_dir(temp) else {
+        return 0;
+    };
+    let own = format!("{}{}", TEMP_DIR_PREFIX, own_pid);
+    let now = SystemTime::now();
+
+    entries
+        .flatten()
+        .filter(|entry| {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            name.starts_with(TEMP_DIR_PREFIX) && name != own
+        })
+        .filter(|entry| {
+            entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .map(|modified| is_stale(modified, now, max_age))
+                .unwrap_or(false)
+        })
+        .filter(|entry| fs::remove_dir_all(entry.path()).is_ok())
+        .count()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("hardwave-wvdir-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_probe() {
+        let dir = scratch_dir("probe");
+        assert!(is_writable(&dir.join("nested")));
+        assert!(!dir.join("nested").join(".hardwave-write-probe").exists());
+
+        // A regular file where the directory should be can't be used.
+        let file = dir.join("file");
+        fs::write(&file, "x").unwrap();
+        assert!(!is_writable(&file.join("sub")));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_candidate_order_and_fallback() {
+        let dir = scratch_dir("order");
+        let temp = dir.join("temp");
+        let list = candidates(
+            Some(dir.join("env")),
+            Some(dir.join("config")),
+            Some(dir.join("local")),
+            &temp,
+            42,
+        );
+        assert_eq!(
+            list,
+            vec![
+                dir.join("env"),
+                dir.join("config"),
+                dir.join("local").join("Hardwave").join("WebView2"),
+                temp.join("Hardwave-WebView2-42"),
+            ]
+        );
+
+        // Empty env var is ignored.
+        assert_eq!(candidates(Some(PathBuf::new()), None, None, &temp, 1).len(), 1);
+
+        // An unusable first choice falls through to the next one.
+        let blocker = dir.join("blocker");
+        fs::write(&blocker, "x").unwrap();
+        let chosen = choose(&[blocker.join("wv"), dir.join("config")]);
+        assert_eq!(chosen.unwrap(), dir.join("config"));
+
+        // With nothing usable the error lists what was tried.
+        let tried = vec![blocker.join("a"), blocker.join("b")];
+        match choose(&tried) {
+            Err(HardwaveError::Editor(EditorError::NoDataDir(dirs))) => assert_eq!(dirs, tried),
+            other => panic!("expected NoDataDir, got {:?}", other),
+        }
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_stale_age_filter() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert!(is_stale(now - day * 31, now, STALE_TEMP_DIR_AGE));
+        assert!(!is_stale(now - day * 29, now, STALE_TEMP_DIR_AGE));
+        // Clock skew: modified in the future is never stale.
+        assert!(!is_stale(now + day, now, STALE_TEMP_DIR_AGE));
+    }
+
+    #[test]
+    fn test_cleanup_skips_fresh_own_and_unrelated() {
+        let temp = scratch_dir("cleanup");
+        fs::create_dir_all(temp.join("Hardwave-WebView2-1")).unwrap();
+        fs::create_dir_all(temp.join("Hardwave-WebView2-2")).unwrap();
+        fs::create_dir_all(temp.join("unrelated")).unwrap();
+
+        assert_eq!(cleanup_stale(&temp, STALE_TEMP_DIR_AGE, 2), 0);
+
+        std::thread::sleep(Duration::from_millis(20));
+        assert_eq!(cleanup_stale(&temp, Duration::from_millis(1), 2), 1);
+        assert!(!temp.join("Hardwave-WebView2-1").exists());
+        assert!(temp.join("Hardwave-WebView2-2").exists());
+        assert!(temp.join("unrelated").exists());
+        let _ = fs::remove_dir_all(&temp);
+    }
+}
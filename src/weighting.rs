@@ -0,0 +1,243 @@
+//! Frequency weighting of the analysis feed
+//!
+//! With a weighting chosen, the engine filters its own copy of the input,
+//! at the host rate and before decimation, so the spectrum, the levels and
+//! the coarse bands all describe the weighted signal; the audio the plugin
+//! passes through is never touched. Z is flat and skips the filter.
+//!
+//! A and C are the IEC 61672-1 curves: their analog poles through the
+//! bilinear transform as cascaded biquads, normalised to 0 dB at 1 kHz. The
+//! transform squeezes the top octave towards Nyquist, so the 12.2 kHz
+//! section is pre-warped at `WARP_HZ`; that keeps both curves within a few
+//! hundredths of a dB of the standard up to 8 kHz at 44.1 kHz and above.
+//! K is the ITU-R BS.1770 pre-filter, a high shelf for the head and the
+//! RLB high-pass, derived for any rate from the parameters its 48 kHz
+//! coefficients come from. It isn't normalised: 1 kHz reads about +0.7 dB,
+//! which a loudness meter takes back with its -0.691 dB offset.
+//!
+//! Filters are designed for the host rate whenever it or the weighting
+//! changes, which doesn't allocate, and start from silence when the engine
+//! resets.
+
+use std::f64::consts::PI;
+
+use crate::params::Weighting;
+
+/// Pole frequencies of the A and C curves, Hz
+const POLE_1_HZ: f64 = 20.598_997;
+const POLE_2_HZ: f64 = 107.652_65;
+const POLE_3_HZ: f64 = 737.862_23;
+const POLE_4_HZ: f64 = 12_194.217;
+
+/// Where the 12.2 kHz section's transform is exact, at most a quarter of
+/// the rate
+const WARP_HZ: f64 = 8_000.0;
+
+/// BS.1770 high shelf
+const SHELF_HZ: f64 = 1_681.974_450_955_533;
+const SHELF_GAIN_DB: f64 = 3.999_843_853_973_347;
+const SHELF_Q: f64 = 0.707_175_236_955_419_6;
+
+/// BS.1770 RLB high-pass
+const RLB_HZ: f64 = 38.135_470_876_024_44;
+const RLB_Q: f64 = 0.500_327_037_323_877_3;
+
+/// Sections the longest weighting, A, needs
+const MAX_SECTIONS: usize = 3;
+
+/// One second-order section, transposed direct form II
+#[derive(Debug, Clone, Copy, Default)]
+struct Biquad {
+    b: [f64; 3],
+    /// Denominator after the leading 1
+    a: [f64; 2],
+    state: [f64; 2],
+}
+
+impl Biquad {
+    /// The analog section `(b[0] s² + b[1] s + b[2]) / (a[0] s² + a[1] s +
+    /// a[2])` through the bilinear transform `s = k (1 - z⁻¹) / (1 + z⁻¹)`
+    fn bilinear(b: [f64; 3], a: [f64; 3], k: f64) -> Self {
+        let k2 = k * k;
+        let digital =
+            |c: [f64; 3]| [c[0] * k2 + c[1] * k + c[2], 2.0 * (c[2] - c[0] * k2), c[0] * k2 - c[1] * k + c[2]];
+        let (b, a) = (digital(b), digital(a));
+        Self {
+            b: [b[0] / a[0], b[1] / a[0], b[2] / a[0]],
+            a: [a[1] / a[0], a[2] / a[0]],
+            state: [0.0; 2],
+        }
+    }
+
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.state[0];
+        self.state[0] = self.b[1] * x - self.a[0] * y + self.state[1];
+        self.state[1] = self.b[2] * x - self.a[1] * y;
+        // A decaying tail stops short of the denormals. Both states go at
+        // once: zeroing one alone kicks a section with poles near 1 back up
+        if self.state.iter().all(|state| state.abs() < 1e-30) {
+            self.state = [0.0; 2];
+        }
+        y
+    }
+
+    /// Gain at `hz`, linear
+    fn magnitude(&self, hz: f64, sample_rate: f64) -> f64 {
+        let w = 2.0 * PI * hz / sample_rate;
+        let (cos1, sin1, cos2, sin2) = (w.cos(), w.sin(), (2.0 * w).cos(), (2.0 * w).sin());
+        let numerator = (self.b[0] + self.b[1] * cos1 + self.b[2] * cos2).hypot(self.b[1] * sin1 + self.b[2] * sin2);
+        let denominator = (1.0 + self.a[0] * cos1 + self.a[1] * cos2).hypot(self.a[0] * sin1 + self.a[1] * sin2);
+        numerator / denominator
+    }
+}
+
+/// One channel's weighting filter
+#[derive(Debug, Clone, Copy)]
+pub struct WeightingFilter {
+    sections: [Biquad; MAX_SECTIONS],
+    /// Sections in use; 0 is flat
+    len: usize,
+    gain: f64,
+}
+
+impl WeightingFilter {
+    /// `weighting` at host rate `sample_rate`, from silence
+    pub fn new(weighting: Weighting, sample_rate: f32) -> Self {
+        let fs = f64::from(sample_rate);
+        let mut filter = Self { sections: [Biquad::default(); MAX_SECTIONS], len: 0, gain: 1.0 };
+        let (w1, w2, w3, w4) = (2.0 * PI * POLE_1_HZ, 2.0 * PI * POLE_2_HZ, 2.0 * PI * POLE_3_HZ, 2.0 * PI * POLE_4_HZ);
+        let warp = 2.0 * PI * WARP_HZ.min(fs / 4.0);
+        let (k, k_warped) = (2.0 * fs, warp / (warp / (2.0 * fs)).tan());
+        // Double pole at 20.6 Hz and at 12.2 kHz, and for A the two between
+        let low = Biquad::bilinear([1.0, 0.0, 0.0], [1.0, 2.0 * w1, w1 * w1], k);
+        let high = Biquad::bilinear([0.0, 0.0, 1.0], [1.0, 2.0 * w4, w4 * w4], k_warped);
+        let sections: &[Biquad] = match weighting {
+            Weighting::Z => &[],
+            Weighting::A => &[low, Biquad::bilinear([1.0, 0.0, 0.0], [1.0, w2 + w3, w2 * w3], k), high],
+            Weighting::C => &[low, high],
+            Weighting::K => &[Self::shelf(fs), Self::rlb(fs)],
+        };
+        filter.sections[..sections.len()].copy_from_slice(sections);
+        filter.len = sections.len();
+        if matches!(weighting, Weighting::A | Weighting::C) {
+            filter.gain = 1.0 / sections.iter().map(|section| section.magnitude(1000.0, fs)).product::<f64>();
+        }
+        filter
+    }
+
+    /// The BS.1770 high shelf at `fs`
+    fn shelf(fs: f64) -> Biquad {
+        let k = (PI * SHELF_HZ / fs).tan();
+        let high = 10f64.powf(SHELF_GAIN_DB / 20.0);
+        let band = high.powf(0.499_666_774_154_541_6);
+        let a0 = 1.0 + k / SHELF_Q + k * k;
+        Biquad {
+            b: [
+                (high + band * k / SHELF_Q + k * k) / a0,
+                2.0 * (k * k - high) / a0,
+                (high - band * k / SHELF_Q + k * k) / a0,
+            ],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / SHELF_Q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+
+    /// The BS.1770 RLB high-pass at `fs`
+    fn rlb(fs: f64) -> Biquad {
+        let k = (PI * RLB_HZ / fs).tan();
+        let a0 = 1.0 + k / RLB_Q + k * k;
+        Biquad {
+            b: [1.0, -2.0, 1.0],
+            a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / RLB_Q + k * k) / a0],
+            state: [0.0; 2],
+        }
+    }
+
+    pub fn process(&mut self, sample: f32) -> f32 {
+        if self.len == 0 {
+            return sample;
+        }
+        let weighted = self.sections[..self.len]
+            .iter_mut()
+            .fold(f64::from(sample), |x, section| section.process(x));
+        (weighted * self.gain) as f32
+    }
+
+    /// Back to silence
+    pub fn reset(&mut self) {
+        for section in &mut self.sections {
+            section.state = [0.0; 2];
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gain of `weighting` for a sine at `hz`, dB: the RMS over two seconds
+    /// after one to settle, both whole numbers of cycles
+    fn gain_db(weighting: Weighting, hz: f64, sample_rate: f32) -> f64 {
+        let mut filter = WeightingFilter::new(weighting, sample_rate);
+        let fs = f64::from(sample_rate);
+        let (settle, measure) = (sample_rate as usize, 2 * sample_rate as usize);
+        let mut power = 0.0;
+        for n in 0..settle + measure {
+            let x = 0.5 * (2.0 * PI * hz * n as f64 / fs).sin();
+            let y = f64::from(filter.process(x as f32));
+            if n >= settle {
+                power += y * y;
+            }
+        }
+        10.0 * (power / measure as f64 / 0.125).log10()
+    }
+
+    #[test]
+    fn test_a_weighting_matches_iec_61672() {
+        // Nominal A-weighting, dB
+        for (hz, nominal) in [(31.5, -39.4), (1000.0, 0.0), (8000.0, -1.1)] {
+            for rate in [44_100.0, 48_000.0, 96_000.0] {
+                let measured = gain_db(Weighting::A, hz, rate);
+                assert!((measured - nominal).abs() < 0.5, "{} Hz at {}: {:.2} dB", hz, rate, measured);
+            }
+        }
+    }
+
+    #[test]
+    fn test_c_and_k_weighting() {
+        for (hz, nominal) in [(31.5, -3.0), (1000.0, 0.0), (8000.0, -3.0)] {
+            let measured = gain_db(Weighting::C, hz, 48_000.0);
+            assert!((measured - nominal).abs() < 0.5, "C at {} Hz: {:.2} dB", hz, measured);
+        }
+        // BS.1770 at 48 kHz, and its published coefficients
+        for (hz, nominal) in [(31.5, -7.79), (1000.0, 0.70), (8000.0, 4.04)] {
+            let measured = gain_db(Weighting::K, hz, 48_000.0);
+            assert!((measured - nominal).abs() < 0.05, "K at {} Hz: {:.2} dB", hz, measured);
+        }
+        let shelf = WeightingFilter::shelf(48_000.0);
+        assert!((shelf.b[0] - 1.535_124_859_586_97).abs() < 1e-9);
+        assert!((shelf.a[0] + 1.690_659_293_182_41).abs() < 1e-9);
+        let rlb = WeightingFilter::rlb(48_000.0);
+        assert!((rlb.a[1] - 0.990_072_250_366_21).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_z_is_untouched_and_reset_forgets() {
+        let mut flat = WeightingFilter::new(Weighting::Z, 48_000.0);
+        for x in [0.25, -1.0, 1e-38, 0.0] {
+            assert_eq!(flat.process(x), x);
+        }
+
+        let mut filter = WeightingFilter::new(Weighting::A, 48_000.0);
+        let first: Vec<f32> = (0..64).map(|n| filter.process(if n == 0 { 1.0 } else { 0.0 })).collect();
+        filter.reset();
+        let again: Vec<f32> = (0..64).map(|n| filter.process(if n == 0 { 1.0 } else { 0.0 })).collect();
+        assert_eq!(first, again);
+
+        // Silence after a signal settles to exact zeros
+        for _ in 0..48_000 * 20 {
+            filter.process(0.0);
+        }
+        assert_eq!(filter.process(0.0), 0.0);
+    }
+}
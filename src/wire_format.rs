@@ -0,0 +1,169 @@
+//! How packets are encoded, agreed per connection
+//!
+//! Packets go out in the layout of `protocol`: the canonical format, and
+//! what every connection gets unless it agrees otherwise. In headers and the
+//! config it is still `bincode`: it was bincode of `AudioPacket` before
+//! `PROTOCOL_VERSION` 11, and Suites ask for it by that name. Built with the `proto` feature, the
+//! plugin can send them as Protocol Buffers instead,
+//! one `AudioPacket` message of `proto/hardwave.proto` per binary message
+//! (see `proto`), for partners who generate their decoder from the schema
+//! rather than port the layout. A connection is proto when either
+//!
+//! - the handshake offers it, `X-Hardwave-Formats: bincode, proto`, and the
+//!   response picks it with `X-Hardwave-Format: proto`; or
+//! - `stream_format` in the machine config is `"proto"`, so the plugin asks
+//!   for `GET /?format=proto` and streams proto without waiting for an
+//!   answer, for servers that route on the request and can't set headers.
+//!
+//! Only an instance's own connection is agreed like this; the shared one
+//! (see `multiplex`) stays canonical, so an instance set to proto connects on
+//! its own. Packet encryption seals whichever encoding the connection has,
+//! and recordings stay canonical either way.
+
+use serde::{Deserialize, Serialize};
+
+use crate::protocol::AudioPacket;
+
+/// Handshake header listing the formats the plugin can send
+pub const OFFER_HEADER: &str = "X-Hardwave-Formats";
+
+/// Handshake response header picking one of them
+pub const CHOICE_HEADER: &str = "X-Hardwave-Format";
+
+/// An encoding of the packet stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum WireFormat {
+    /// The `protocol` layout, named for what it was before version 11
+    #[default]
+    #[serde(rename = "bincode")]
+    Canonical,
+    Proto,
+}
+
+impl WireFormat {
+    /// The formats this build can send
+    pub const SUPPORTED: &'static [WireFormat] = if cfg!(feature = "proto") {
+        &[WireFormat::Canonical, WireFormat::Proto]
+    } else {
+        &[WireFormat::Canonical]
+    };
+
+    /// Name in headers, the query and the config
+    pub fn name(self) -> &'static str {
+        match self {
+            WireFormat::Canonical => "bincode",
+            WireFormat::Proto => "proto",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::SUPPORTED.iter().copied().find(|format| format.name() == name)
+    }
+
+    /// `configured`, if this build can send it; else the default
+    pub fn usable(configured: WireFormat) -> Self {
+        if Self::SUPPORTED.contains(&configured) {
+            configured
+        } else {
+            Self::default()
+        }
+    }
+
+    pub fn encode(self, packet: &AudioPacket) -> Vec<u8> {
+        #[cfg(feature = "proto")]
+        if self == WireFormat::Proto {
+            return crate::proto::encode(packet);
+        }
+        // Proto is never agreed without the feature
+        packet.to_bytes()
+    }
+}
+
+/// The request path and the headers (each ending in CRLF) for a connection
+/// with `configured` as the machine config's format
+pub fn request(configured: WireFormat) -> (String, String) {
+    let configured = WireFormat::usable(configured);
+    if configured != WireFormat::default() {
+        return (format!("/?format={}", configured.name()), String::new());
+    }
+    if WireFormat::SUPPORTED.len() == 1 {
+        return ("/".to_string(), String::new());
+    }
+    let offered: Vec<&str> = WireFormat::SUPPORTED.iter().map(|format| format.name()).collect();
+    ("/".to_string(), format!("{}: {}\r\n", OFFER_HEADER, offered.join(", ")))
+}
+
+/// The format a connection asked for with `request(configured)` streams,
+/// given the handshake response `response`
+pub fn agreed(configured: WireFormat, response: &str) -> WireFormat {
+    let configured = WireFormat::usable(configured);
+    if configured != WireFormat::default() {
+        return configured;
+    }
+    let name = CHOICE_HEADER.to_ascii_lowercase();
+    response
+        .lines()
+        .find_map(|line| {
+            let (field, value) = line.split_once(':')?;
+            (field.trim().to_ascii_lowercase() == name).then(|| value.trim().to_ascii_lowercase())
+        })
+        .and_then(|value| WireFormat::from_name(&value))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SWITCHING: &str = "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\n";
+
+    #[test]
+    fn test_default_is_canonical() {
+        assert_eq!(agreed(WireFormat::Canonical, SWITCHING), WireFormat::Canonical);
+        // A Suite picking something it wasn't offered, or nothing known
+        let unknown = format!("{}x-hardwave-format: msgpack\r\n", SWITCHING);
+        assert_eq!(agreed(WireFormat::Canonical, &unknown), WireFormat::Canonical);
+
+        let packet = AudioPacket::new_editor(true, 1);
+        assert_eq!(WireFormat::Canonical.encode(&packet), packet.to_bytes());
+
+        // Configs written before the rename keep working
+        assert_eq!(serde_json::from_str::<WireFormat>(r#""bincode""#).unwrap(), WireFormat::Canonical);
+        assert_eq!(serde_json::to_string(&WireFormat::Canonical).unwrap(), r#""bincode""#);
+    }
+
+    #[test]
+    #[cfg(not(feature = "proto"))]
+    fn test_without_the_feature_nothing_is_offered() {
+        assert_eq!(request(WireFormat::Canonical), ("/".to_string(), String::new()));
+        // A config asking for proto is ignored
+        assert_eq!(request(WireFormat::Proto), ("/".to_string(), String::new()));
+        let picked = format!("{}X-Hardwave-Format: proto\r\n", SWITCHING);
+        assert_eq!(agreed(WireFormat::Proto, &picked), WireFormat::Canonical);
+        assert_eq!(agreed(WireFormat::Canonical, &picked), WireFormat::Canonical);
+    }
+
+    #[test]
+    #[cfg(feature = "proto")]
+    fn test_proto_by_negotiation_or_query() {
+        use prost::Message;
+
+        // Offered, and picked by the response
+        let (path, headers) = request(WireFormat::Canonical);
+        assert_eq!(path, "/");
+        assert_eq!(headers, "X-Hardwave-Formats: bincode, proto\r\n");
+        let picked = format!("{}x-hardwave-format: Proto\r\n", SWITCHING);
+        assert_eq!(agreed(WireFormat::Canonical, &picked), WireFormat::Proto);
+        assert_eq!(agreed(WireFormat::Canonical, SWITCHING), WireFormat::Canonical);
+
+        // Asked for in the query, from the config, with no answer needed
+        assert_eq!(request(WireFormat::Proto), ("/?format=proto".to_string(), String::new()));
+        assert_eq!(agreed(WireFormat::Proto, SWITCHING), WireFormat::Proto);
+
+        let packet = AudioPacket::fft(48000, 5);
+        let encoded = WireFormat::Proto.encode(&packet);
+        let decoded = crate::proto::wire::AudioPacket::decode(encoded.as_slice()).unwrap();
+        assert_eq!(AudioPacket::from(decoded).to_bytes(), packet.to_bytes());
+    }
+}